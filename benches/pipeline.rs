@@ -0,0 +1,299 @@
+//! Converts the fixture videos in `benches/fixtures` through a real server and
+//! reports wall time, CPU time and GIF size for each encoder setup.
+//!
+//! Run with `cargo bench` (add `--features native-encoder` to include the
+//! in-process encoder). ffmpeg has to be installed; setups needing gifski are
+//! skipped without it. `BENCH_ITERATIONS` (default 3) sets how many times each
+//! fixture is converted, and the median run is reported. Arguments after
+//! `--` pick the setups whose names contain one of them, e.g.
+//! `cargo bench -- relay pipe`.
+//!
+//! With gifski installed, it then runs ffmpeg and gifski on each fixture by
+//! themselves, once with gifski reading ffmpeg's stdout directly, as the
+//! server has them, and once with the frames copied from one to the other
+//! through this process, the way they were before the direct pipe. The CPU
+//! time of the second includes the copying.
+
+use fastgif_core::backend::y4m_output;
+use fastgif_core::encoder::GifskiFlags;
+use fastgif_core::GifSettings;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+struct Setup {
+    name: &'static str,
+    env: &'static [(&'static str, &'static str)],
+    needs_gifski: bool,
+}
+
+const SETUPS: &[Setup] = &[
+    Setup {
+        name: "gifski fast",
+        env: &[("ENCODER", "subprocess"), ("GIF_FAST", "true")],
+        needs_gifski: true,
+    },
+    Setup {
+        name: "gifski quality",
+        env: &[("ENCODER", "subprocess"), ("GIF_FAST", "false")],
+        needs_gifski: true,
+    },
+    #[cfg(feature = "native-encoder")]
+    Setup {
+        name: "native fast",
+        env: &[("ENCODER", "native"), ("GIF_FAST", "true")],
+        needs_gifski: false,
+    },
+    #[cfg(feature = "native-encoder")]
+    Setup {
+        name: "native quality",
+        env: &[("ENCODER", "native"), ("GIF_FAST", "false")],
+        needs_gifski: false,
+    },
+    Setup {
+        name: "ffmpeg fast",
+        env: &[("ENCODER", "ffmpeg"), ("GIF_FAST", "true")],
+        needs_gifski: false,
+    },
+    Setup {
+        name: "ffmpeg quality",
+        env: &[("ENCODER", "ffmpeg"), ("GIF_FAST", "false")],
+        needs_gifski: false,
+    },
+];
+
+struct Run {
+    wall: Duration,
+    cpu: Option<Duration>,
+    bytes: usize,
+}
+
+fn main() {
+    if !is_installed("ffmpeg") {
+        println!("ffmpeg isn't installed, skipping the pipeline benchmarks");
+        return;
+    }
+    let has_gifski = is_installed("gifski");
+    let iterations = std::env::var("BENCH_ITERATIONS")
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .unwrap_or(3)
+        .max(1);
+
+    let filters: Vec<String> = std::env::args().skip(1).filter(|arg| !arg.starts_with("--")).collect();
+    let picked = |setup: &str| filters.is_empty() || filters.iter().any(|filter| setup.contains(filter.as_str()));
+
+    let fixtures = fixtures();
+    let upstream = serve_fixtures(&fixtures);
+
+    println!(
+        "{:<16} {:<10} {:>10} {:>10} {:>10}",
+        "setup", "fixture", "wall ms", "cpu ms", "bytes"
+    );
+    for setup in SETUPS.iter().filter(|setup| picked(setup.name)) {
+        if setup.needs_gifski && !has_gifski {
+            println!("{:<16} skipped, gifski isn't installed", setup.name);
+            continue;
+        }
+        let server = Server::start(upstream, setup.env);
+        for fixture in &fixtures {
+            let name = fixture.file_stem().unwrap().to_string_lossy();
+            let mut runs: Vec<Run> = (0..iterations).map(|_| server.convert(&name)).collect();
+            runs.sort_by_key(|run| run.wall);
+            let run = &runs[runs.len() / 2];
+            print_run(setup.name, &name, run);
+        }
+    }
+
+    if !has_gifski {
+        return;
+    }
+    for (pipe, relay) in [("direct pipe", false), ("relay", true)].into_iter().filter(|(pipe, _)| picked(pipe)) {
+        for fixture in &fixtures {
+            let name = fixture.file_stem().unwrap().to_string_lossy();
+            let mut runs: Vec<Run> = (0..iterations).map(|_| encode(fixture, relay)).collect();
+            runs.sort_by_key(|run| run.wall);
+            print_run(pipe, &name, &runs[runs.len() / 2]);
+        }
+    }
+}
+
+fn print_run(setup: &str, fixture: &str, run: &Run) {
+    println!(
+        "{:<16} {:<10} {:>10} {:>10} {:>10}",
+        setup,
+        fixture,
+        run.wall.as_millis(),
+        run.cpu.map_or("n/a".to_string(), |cpu| cpu.as_millis().to_string()),
+        run.bytes
+    );
+}
+
+// ffmpeg and gifski converting `fixture` with the default settings, with the
+// frames going through this process if `relay`
+fn encode(fixture: &Path, relay: bool) -> Run {
+    let settings = GifSettings::default();
+    let cpu_before = cpu_time("self");
+    let started = Instant::now();
+    let mut ffmpeg = Command::new("ffmpeg")
+        .args(["-loglevel", "error", "-i"])
+        .arg(fixture)
+        .args(y4m_output(&settings, None))
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let frames = ffmpeg.stdout.take().unwrap();
+    let (stdin, relayed) = if relay { (Stdio::piped(), Some(frames)) } else { (Stdio::from(frames), None) };
+    let mut gifski = Command::new("gifski")
+        .args(settings.gifski_args(&GifskiFlags::default()))
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let copying = relayed.map(|mut frames| {
+        let mut stdin = gifski.stdin.take().unwrap();
+        std::thread::spawn(move || std::io::copy(&mut frames, &mut stdin).unwrap())
+    });
+    let mut gif = Vec::new();
+    gifski.stdout.take().unwrap().read_to_end(&mut gif).unwrap();
+    if let Some(copying) = copying {
+        copying.join().unwrap();
+    }
+    assert!(ffmpeg.wait().unwrap().success() && gifski.wait().unwrap().success(), "converting {:?} failed", fixture);
+    let wall = started.elapsed();
+    Run {
+        wall,
+        cpu: cpu_before.zip(cpu_time("self")).map(|(before, after)| after - before),
+        bytes: gif.len(),
+    }
+}
+
+fn is_installed(name: &str) -> bool {
+    Command::new(name)
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+fn fixtures() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/fixtures");
+    let mut fixtures: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    fixtures.sort();
+    fixtures
+}
+
+// Serves each fixture at /tweet_video/<name>.mp4, whatever its real format
+fn serve_fixtures(fixtures: &[PathBuf]) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let fixtures = fixtures.to_vec();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let mut request = [0u8; 4096];
+            let read = stream.read(&mut request).unwrap_or(0);
+            let request = String::from_utf8_lossy(&request[..read]);
+            let path = request.split_whitespace().nth(1).unwrap_or("");
+            let video = fixtures
+                .iter()
+                .find(|fixture| {
+                    let stem = fixture.file_stem().unwrap().to_string_lossy();
+                    path == format!("/tweet_video/{}.mp4", stem)
+                })
+                .and_then(|fixture| std::fs::read(fixture).ok());
+            let _ = match video {
+                Some(video) => stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            video.len()
+                        )
+                        .as_bytes(),
+                    )
+                    .and_then(|_| stream.write_all(&video)),
+                None => stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+            };
+        }
+    });
+    port
+}
+
+struct Server {
+    child: Child,
+    port: u16,
+}
+
+impl Server {
+    fn start(upstream: u16, env: &[(&str, &str)]) -> Self {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let child = Command::new(env!("CARGO_BIN_EXE_fastgif"))
+            .env("PORT", port.to_string())
+            .env("VIDEO_BASE_URL", format!("http://127.0.0.1:{}", upstream))
+            .env("MAX_INPUT_DURATION", "0")
+            .envs(env.iter().copied())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(started.elapsed() < Duration::from_secs(10), "server didn't start");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        Self { child, port }
+    }
+
+    fn convert(&self, name: &str) -> Run {
+        let cpu_before = self.cpu_time();
+        let started = Instant::now();
+        let mut stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        write!(
+            stream,
+            "GET /tweet_video/{}.gif HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            name
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let wall = started.elapsed();
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..split]);
+        assert!(head.starts_with("HTTP/1.1 200"), "conversion of {} failed: {}", name, head);
+        Run {
+            wall,
+            cpu: cpu_before.zip(self.cpu_time()).map(|(before, after)| after - before),
+            bytes: response.len() - split - 4,
+        }
+    }
+
+    // The server's CPU time plus that of its children that have been reaped,
+    // which by the time a response is complete is every ffmpeg and gifski
+    fn cpu_time(&self) -> Option<Duration> {
+        cpu_time(&self.child.id().to_string())
+    }
+}
+
+// The CPU time of the process `pid` (or `self`) and its reaped children
+fn cpu_time(pid: &str) -> Option<Duration> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // Fields after the command name, which is in parentheses and may contain spaces
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let ticks: u64 = fields.get(11..15)?.iter().filter_map(|f| f.parse::<u64>().ok()).sum();
+    // The kernel's USER_HZ is 100 on every platform we'd run this on
+    Some(Duration::from_millis(ticks * 10))
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
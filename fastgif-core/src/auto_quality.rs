@@ -0,0 +1,133 @@
+use crate::encoder::GifSettings;
+use std::fmt;
+use std::time::Duration;
+
+/// Bits a second per pixel a second at or over which a video counts as busy:
+/// about 0.13 bits a pixel a frame at 30 fps, which calm footage and screen
+/// recordings seldom need and fast motion usually does.
+pub const BUSY_BITS_PER_PIXEL: f64 = 4.0;
+
+/// How long a source video is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Length {
+    /// Up to 10 s
+    Short,
+    /// Up to 30 s
+    Medium,
+    Long,
+}
+
+/// How big a source video is, by its shorter side, so portrait videos count
+/// the same as landscape ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    /// Up to 480 px
+    Sd,
+    /// Up to 720 px
+    Hd,
+    FullHd,
+}
+
+/// What a source video is like, as far as picking its conversion settings
+/// goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bucket {
+    pub length: Length,
+    pub size: Size,
+    /// Whether it takes a lot of bits for its size, which usually means a
+    /// lot of motion (see [`BUSY_BITS_PER_PIXEL`])
+    pub busy: bool,
+}
+
+/// What a bucket's GIFs are held to. Like a [`Tier`](crate::tier::Tier),
+/// these only ever lower the configured settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Profile {
+    pub quality: u8,
+    pub fps: Option<f32>,
+    pub max_width: Option<u32>,
+}
+
+const fn profile(quality: u8, fps: Option<f32>, max_width: Option<u32>) -> Profile {
+    Profile { quality, fps, max_width }
+}
+
+// Rows are how long, columns how big. Busy videos lose another 10 quality.
+const TABLE: [[Profile; 3]; 3] = [
+    // Short
+    [profile(100, None, None), profile(90, None, Some(480)), profile(80, Some(15.0), Some(480))],
+    // Medium
+    [profile(80, Some(15.0), None), profile(70, Some(15.0), Some(400)), profile(70, Some(12.0), Some(360))],
+    // Long
+    [profile(60, Some(10.0), Some(360)), profile(50, Some(10.0), Some(320)), profile(50, Some(8.0), Some(320))],
+];
+
+impl Bucket {
+    /// The bucket of a video `duration` long, shown `width` by `height`,
+    /// at `bit_rate` bits a second if the container says.
+    pub fn of(duration: Duration, width: u32, height: u32, bit_rate: Option<u64>) -> Bucket {
+        let length = match duration.as_secs_f64() {
+            secs if secs <= 10.0 => Length::Short,
+            secs if secs <= 30.0 => Length::Medium,
+            _ => Length::Long,
+        };
+        let size = match width.min(height) {
+            side if side <= 480 => Size::Sd,
+            side if side <= 720 => Size::Hd,
+            _ => Size::FullHd,
+        };
+        let pixels = f64::from(width.max(1)) * f64::from(height.max(1));
+        let busy = bit_rate.is_some_and(|bit_rate| bit_rate as f64 / pixels >= BUSY_BITS_PER_PIXEL);
+        Bucket { length, size, busy }
+    }
+
+    pub fn profile(self) -> Profile {
+        let row = match self.length {
+            Length::Short => 0,
+            Length::Medium => 1,
+            Length::Long => 2,
+        };
+        let column = match self.size {
+            Size::Sd => 0,
+            Size::Hd => 1,
+            Size::FullHd => 2,
+        };
+        let profile = TABLE[row][column];
+        match self.busy {
+            true => Profile { quality: profile.quality - 10, ..profile },
+            false => profile,
+        }
+    }
+
+    /// `settings`, held to this bucket's profile, which never raises any of
+    /// them.
+    pub fn apply(self, settings: GifSettings) -> GifSettings {
+        let profile = self.profile();
+        GifSettings {
+            quality: settings.quality.min(profile.quality),
+            fps: profile.fps.map_or(settings.fps, |fps| settings.fps.min(fps)),
+            max_width: match (settings.max_width, profile.max_width) {
+                (Some(width), Some(max)) => Some(width.min(max)),
+                (width, max) => width.or(max),
+            },
+            ..settings
+        }
+    }
+}
+
+/// What the `X-FastGIF-Auto-Quality` header says, e.g. `medium-hd-calm`.
+impl fmt::Display for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let length = match self.length {
+            Length::Short => "short",
+            Length::Medium => "medium",
+            Length::Long => "long",
+        };
+        let size = match self.size {
+            Size::Sd => "sd",
+            Size::Hd => "hd",
+            Size::FullHd => "fullhd",
+        };
+        write!(f, "{}-{}-{}", length, size, if self.busy { "busy" } else { "calm" })
+    }
+}
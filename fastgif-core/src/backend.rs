@@ -0,0 +1,299 @@
+use crate::encoder::{Decoder, GifSettings};
+use crate::error::ConversionError;
+use crate::pipeline::{ConversionOptions, PipelineConfig, RunningEncoder};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::ChildStdout;
+use tokio_util::sync::CancellationToken;
+
+/// What comes out of an encoder, read while it's still being made.
+pub type Encoded = Box<dyn AsyncRead + Unpin + Send>;
+
+/// What a backend makes, which `?format=` picks it by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Gif,
+    Webp,
+    Webm,
+}
+
+impl Format {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::Gif => "gif",
+            Format::Webp => "webp",
+            Format::Webm => "webm",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Gif => "image/gif",
+            Format::Webp => "image/webp",
+            Format::Webm => "video/webm",
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gif" => Ok(Format::Gif),
+            "webp" => Ok(Format::Webp),
+            "webm" => Ok(Format::Webm),
+            other => Err(format!("unknown format {:?}, expected gif, webp or webm", other)),
+        }
+    }
+}
+
+/// Something that turns the frames ffmpeg decodes into the image a
+/// conversion sends back. Each built-in `Encoder` is one; others are added to
+/// `Backends`.
+pub trait Backend: Send + Sync + std::fmt::Debug {
+    /// What `X-FastGIF-Encoder` calls it
+    fn name(&self) -> &'static str;
+
+    fn format(&self) -> Format;
+
+    /// The ffmpeg options after the input that make it write what this
+    /// backend reads: yuv4mpegpipe frames on stdout, or PNGs for a GIF that
+    /// keeps the video's transparency, unless it says otherwise.
+    fn ffmpeg_output(&self, settings: &GifSettings) -> Vec<String> {
+        match settings.transparent() {
+            true => png_output(settings),
+            false => y4m_output(settings, None),
+        }
+    }
+
+    /// Start encoding `frames`, ffmpeg's stdout, returning what's running and
+    /// the stream the image comes out of. Tasks it spawns should stop once
+    /// `stop` is cancelled, which it is however the conversion ends.
+    fn start(
+        &self,
+        frames: ChildStdout,
+        config: &PipelineConfig,
+        options: &ConversionOptions,
+        stop: &CancellationToken,
+    ) -> Result<(RunningEncoder, Encoded), ConversionError>;
+}
+
+/// ffmpeg writing raw frames to stdout, scaled down to `max_width` and to
+/// even dimensions, at the GIF's frame rate, and in `pix_fmt` if the encoder
+/// is picky.
+pub fn y4m_output(settings: &GifSettings, pix_fmt: Option<&str>) -> Vec<String> {
+    // y4m has one frame rate for the whole stream, so a variable frame rate
+    // video, like a screen recording, plays at the wrong speed unless its
+    // frames are first dropped and repeated to a constant one
+    let mut args = vec!["-vf".into(), settings.filters()];
+    if let Some(pix_fmt) = pix_fmt {
+        args.extend(["-pix_fmt".into(), pix_fmt.into()]);
+    }
+    args.extend([
+        "-f".into(), "yuv4mpegpipe".into(),  // Output in yuv4mpegpipe format
+        "-".into(),  // Output to stdout
+    ]);
+    args
+}
+
+/// ffmpeg writing each frame to stdout as an RGBA PNG, one after another,
+/// filtered like [`y4m_output`]'s: y4m has nowhere to put an alpha channel.
+pub fn png_output(settings: &GifSettings) -> Vec<String> {
+    vec![
+        "-vf".into(), settings.filters(),
+        "-c:v".into(), "png".into(),
+        "-pix_fmt".into(), "rgba".into(),
+        "-f".into(), "image2pipe".into(),
+        "-".into(),
+    ]
+}
+
+/// The eight bytes every PNG starts with.
+pub(crate) const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// The next whole PNG of [`png_output`]'s on `input`, found by following its
+/// chunks to `IEND`, or `None` at the end of the stream.
+pub(crate) async fn read_png(input: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<Vec<u8>>> {
+    let mut png = vec![0; PNG_SIGNATURE.len()];
+    match input.read_exact(&mut png).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if png != PNG_SIGNATURE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not a PNG"));
+    }
+    loop {
+        // Each chunk's length, type, data and CRC
+        let mut header = [0; 8];
+        input.read_exact(&mut header).await?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        png.extend_from_slice(&header);
+        let start = png.len();
+        png.resize(start + length + 4, 0);
+        input.read_exact(&mut png[start..]).await?;
+        if &header[4..] == b"IEND" {
+            return Ok(Some(png));
+        }
+    }
+}
+
+/// Animated WebP from ffmpeg on its own, when it was built with libwebp.
+#[derive(Debug)]
+pub struct FfmpegWebp;
+
+impl Backend for FfmpegWebp {
+    fn name(&self) -> &'static str {
+        "ffmpeg-webp"
+    }
+
+    fn format(&self) -> Format {
+        Format::Webp
+    }
+
+    fn ffmpeg_output(&self, settings: &GifSettings) -> Vec<String> {
+        // The other way round from GIFs: 0 loops forever, and 1 plays once
+        let repeat = settings.repeat.map_or(0, |repeat| u32::from(repeat) + 1);
+        // WebP has an alpha channel of its own, which ffmpeg keeps
+        vec![
+            "-vf".into(), settings.filters(),
+            "-c:v".into(), "libwebp_anim".into(),
+            "-quality".into(), settings.quality.to_string(),
+            "-compression_level".into(), if settings.fast { "1" } else { "4" }.into(),
+            "-loop".into(), repeat.to_string(),
+            "-f".into(), "webp".into(),
+            "-".into(),
+        ]
+    }
+
+    fn start(
+        &self,
+        frames: ChildStdout,
+        _: &PipelineConfig,
+        _: &ConversionOptions,
+        _: &CancellationToken,
+    ) -> Result<(RunningEncoder, Encoded), ConversionError> {
+        Ok((RunningEncoder::Ffmpeg, Box::new(frames)))
+    }
+}
+
+/// The widest a WebM is made, however wide the video or `?width=`: it's for
+/// a post's preview, played on a loop, not for watching at full size.
+pub const WEBM_MAX_WIDTH: u32 = 720;
+
+/// What makes the video in a WebM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebmCodec {
+    /// libvpx
+    Vp9,
+    /// SVT-AV1, which is fast enough to convert on request where libaom isn't
+    Av1,
+}
+
+/// A silent WebM from ffmpeg on its own, which players loop with the
+/// `loop` attribute where a GIF loops by itself. Quality is a CRF, worked
+/// out from the GIF quality.
+#[derive(Debug)]
+pub struct FfmpegWebm(pub WebmCodec);
+
+impl FfmpegWebm {
+    // GIF quality 100 is CRF 15, which is close to transparent, down to 48
+    // at quality 1, which is blocky but still recognisable
+    fn crf(quality: u8) -> u8 {
+        15 + (100 - quality.min(100)) / 3
+    }
+}
+
+impl Backend for FfmpegWebm {
+    fn name(&self) -> &'static str {
+        match self.0 {
+            WebmCodec::Vp9 => "ffmpeg-vp9",
+            WebmCodec::Av1 => "ffmpeg-av1",
+        }
+    }
+
+    fn format(&self) -> Format {
+        Format::Webm
+    }
+
+    fn ffmpeg_output(&self, settings: &GifSettings) -> Vec<String> {
+        let max_width = settings.max_width.map_or(WEBM_MAX_WIDTH, |width| width.min(WEBM_MAX_WIDTH));
+        let settings = GifSettings { max_width: Some(max_width), ..*settings };
+        let crf = Self::crf(settings.quality).to_string();
+        let mut args: Vec<String> = vec!["-an".into(), "-sn".into(), "-vf".into(), settings.filters()];
+        match self.0 {
+            // CRF alone, with no bitrate to keep to as well
+            WebmCodec::Vp9 => args.extend([
+                "-c:v".into(), "libvpx-vp9".into(),
+                "-crf".into(), crf,
+                "-b:v".into(), "0".into(),
+                "-row-mt".into(), "1".into(),
+                "-deadline".into(), if settings.fast { "realtime" } else { "good" }.into(),
+                "-cpu-used".into(), if settings.fast { "8" } else { "4" }.into(),
+                // VP9 keeps an alpha channel; in a WebM, it's a side channel browsers read
+                "-pix_fmt".into(), if settings.transparent() { "yuva420p" } else { "yuv420p" }.into(),
+            ]),
+            // SVT-AV1 has no alpha, so a transparent video's is lost
+            WebmCodec::Av1 => args.extend([
+                "-c:v".into(), "libsvtav1".into(),
+                "-crf".into(), crf,
+                "-preset".into(), if settings.fast { "12" } else { "8" }.into(),
+                "-pix_fmt".into(), "yuv420p".into(),
+            ]),
+        }
+        args.extend(["-f".into(), "webm".into(), "-".into()]);
+        args
+    }
+
+    fn start(
+        &self,
+        frames: ChildStdout,
+        _: &PipelineConfig,
+        _: &ConversionOptions,
+        _: &CancellationToken,
+    ) -> Result<(RunningEncoder, Encoded), ConversionError> {
+        Ok((RunningEncoder::Ffmpeg, Box::new(frames)))
+    }
+}
+
+/// The backends registered beside the configured encoder. One takes over its
+/// format from the configured encoder and anything registered before it.
+#[derive(Debug, Clone)]
+pub struct Backends {
+    decoder: Decoder,
+    registered: Vec<Arc<dyn Backend>>,
+}
+
+impl Backends {
+    pub fn new(decoder: Decoder) -> Self {
+        Self { decoder, registered: Vec::new() }
+    }
+
+    pub fn register(&mut self, backend: Arc<dyn Backend>) {
+        self.registered.push(backend);
+    }
+
+    /// What's been registered to make `format`, if anything. libav's frames
+    /// only ever go to the native encoder, so with it there's nothing.
+    pub fn registered(&self, format: Format) -> Option<Arc<dyn Backend>> {
+        if self.decoder != Decoder::Ffmpeg {
+            return None;
+        }
+        self.registered.iter().rev().find(|backend| backend.format() == format).cloned()
+    }
+
+    /// Whether anything here makes `format`. The configured encoder makes GIFs.
+    pub fn makes(&self, format: Format) -> bool {
+        format == Format::Gif || self.registered(format).is_some()
+    }
+
+    /// Every format something here makes.
+    pub fn formats(&self) -> Vec<&'static str> {
+        [Format::Gif, Format::Webp, Format::Webm]
+            .into_iter()
+            .filter(|format| self.makes(*format))
+            .map(Format::as_str)
+            .collect()
+    }
+}
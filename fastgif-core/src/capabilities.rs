@@ -0,0 +1,214 @@
+use crate::encoder::{Decoder, Encoder, GifskiFlags};
+use crate::process::Binary;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeSet;
+use std::process::Stdio;
+use std::str::FromStr;
+
+/// A dotted version number like `6.1.1`, compared part by part.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(Vec<u64>);
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .split('.')
+            .map(|part| part.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("{:?} isn't a version like 4.4", s))?;
+        Ok(Version(parts))
+    }
+}
+
+impl Version {
+    /// The version at the start of `s`, ignoring a leading `n` and whatever
+    /// follows the numbers, as in `n6.1` or `4.4.2-0ubuntu0.22.04.1`.
+    fn leading(s: &str) -> Option<Self> {
+        let s = s.strip_prefix('n').unwrap_or(s);
+        let end = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+        s[..end].trim_end_matches('.').parse().ok()
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(u64::to_string).collect();
+        f.write_str(&parts.join("."))
+    }
+}
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// What the ffmpeg and gifski we found can do, checked once at startup, for
+/// anything that depends on it to look up per request.
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    /// `None` if there's no ffmpeg
+    pub ffmpeg: Option<Ffmpeg>,
+    /// `None` if there's no gifski
+    pub gifski: Option<Gifski>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Ffmpeg {
+    /// `None` for builds that don't say, like ones from git
+    pub version: Option<Version>,
+    pub muxers: BTreeSet<String>,
+    pub encoders: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Gifski {
+    pub version: Option<Version>,
+    /// What `--help` lists, or `null` if it didn't say
+    #[serde(serialize_with = "serialize_flags")]
+    pub flags: GifskiFlags,
+}
+
+fn serialize_flags<S: Serializer>(flags: &GifskiFlags, serializer: S) -> Result<S::Ok, S::Error> {
+    flags.known().serialize(serializer)
+}
+
+impl Capabilities {
+    /// Ask the binaries.
+    pub fn detect(ffmpeg: &Binary, gifski: &Binary) -> Self {
+        Self {
+            ffmpeg: ffmpeg.is_installed().then(|| Ffmpeg {
+                // `ffmpeg version 6.1.1-3ubuntu5 Copyright (c) ...`
+                version: ffmpeg
+                    .version("-version")
+                    .as_deref()
+                    .and_then(|line| line.split_whitespace().nth(2))
+                    .and_then(Version::leading),
+                muxers: listed(ffmpeg, "-muxers"),
+                encoders: listed(ffmpeg, "-encoders"),
+            }),
+            gifski: gifski.is_installed().then(|| Gifski {
+                // `gifski 1.32.0`
+                version: gifski
+                    .version("--version")
+                    .as_deref()
+                    .and_then(|line| line.split_whitespace().nth(1))
+                    .and_then(Version::leading),
+                flags: GifskiFlags::parse(&help(gifski)),
+            }),
+        }
+    }
+
+    /// The flags the gifski binary takes. With no gifski, or none that said,
+    /// that's all of them.
+    pub fn gifski_flags(&self) -> GifskiFlags {
+        self.gifski.as_ref().map(|gifski| gifski.flags.clone()).unwrap_or_default()
+    }
+
+    pub fn can_mux(&self, format: &str) -> bool {
+        self.ffmpeg.as_ref().is_some_and(|ffmpeg| ffmpeg.muxers.contains(format))
+    }
+
+    pub fn can_encode(&self, codec: &str) -> bool {
+        self.ffmpeg.as_ref().is_some_and(|ffmpeg| ffmpeg.encoders.contains(codec))
+    }
+}
+
+// What `gifski --help` prints, which is nothing if it can't be run
+fn help(gifski: &Binary) -> String {
+    let output = gifski
+        .std_command()
+        .and_then(|mut command| command.arg("--help").stderr(Stdio::null()).output().ok());
+    output.map_or(String::new(), |output| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// The names in `ffmpeg -muxers` or `-encoders`. Both print a legend, a line
+// of dashes, then a line per entry: flags, the name(s), and a description.
+fn listed(ffmpeg: &Binary, list: &str) -> BTreeSet<String> {
+    let Some(output) = ffmpeg
+        .std_command()
+        .and_then(|mut command| command.args(["-hide_banner", list]).stderr(Stdio::null()).output().ok())
+        .filter(|output| output.status.success())
+    else {
+        return BTreeSet::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("--"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .flat_map(|names| names.split(','))
+        .map(String::from)
+        .collect()
+}
+
+/// The oldest ffmpeg and gifski we'll run with (`FFMPEG_MIN_VERSION`,
+/// `GIFSKI_MIN_VERSION`).
+#[derive(Debug, Clone)]
+pub struct Minimums {
+    pub ffmpeg: Version,
+    pub gifski: Version,
+}
+
+impl Default for Minimums {
+    fn default() -> Self {
+        Self {
+            ffmpeg: Version(vec![4, 0]),
+            // The first that reads video frames from stdin
+            gifski: Version(vec![1, 10]),
+        }
+    }
+}
+
+/// What setting up conversions fails with when the binaries can't do what
+/// the configuration needs: everything that's wrong, as one line each.
+#[derive(Debug, Clone)]
+pub struct Problems(pub Vec<String>);
+
+impl std::fmt::Display for Problems {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0.join("; "))
+    }
+}
+
+impl std::error::Error for Problems {}
+
+/// Everything about the binaries that stops this configuration from working,
+/// as one line each. Versions that can't be told are let through.
+pub fn problems(capabilities: &Capabilities, encoder: Encoder, decoder: Decoder, minimums: &Minimums) -> Vec<String> {
+    let mut problems = Vec::new();
+    if decoder == Decoder::Ffmpeg {
+        match &capabilities.ffmpeg {
+            None => problems.push("ffmpeg isn't installed".to_string()),
+            Some(ffmpeg) => {
+                if let Some(version) = ffmpeg.version.as_ref().filter(|version| **version < minimums.ffmpeg) {
+                    problems.push(format!("ffmpeg is {}, older than the {} needed", version, minimums.ffmpeg));
+                }
+                // gifski (in or out of process) is fed frames as yuv4mpegpipe,
+                // while ffmpeg on its own writes the GIF itself
+                let missing = match encoder {
+                    Encoder::Ffmpeg => (!capabilities.can_encode("gif")).then_some("the gif encoder"),
+                    Encoder::Subprocess | Encoder::Native => {
+                        (!capabilities.can_mux("yuv4mpegpipe")).then_some("the yuv4mpegpipe muxer")
+                    }
+                };
+                if let Some(missing) = missing {
+                    problems.push(format!("ffmpeg was built without {}", missing));
+                }
+            }
+        }
+    }
+    if encoder == Encoder::Subprocess {
+        match &capabilities.gifski {
+            None => problems.push("gifski isn't installed".to_string()),
+            Some(gifski) => {
+                if let Some(version) = gifski.version.as_ref().filter(|version| **version < minimums.gifski) {
+                    problems.push(format!("gifski is {}, older than the {} needed", version, minimums.gifski));
+                }
+            }
+        }
+    }
+    problems
+}
@@ -0,0 +1,359 @@
+use crate::backend::{Backend, Backends, FfmpegWebm, FfmpegWebp, WebmCodec};
+use crate::capabilities::{self, Capabilities, Minimums, Problems};
+use crate::encoder::{self, Decoder, Encoder, GifSettings};
+use crate::error::{ConversionError, StartupError};
+use crate::fetch::{DirFetcher, FetchMode, Fetcher, FetcherConfig, SourceFetcher, SourceRef};
+use crate::hwaccel::{self, HwaccelMode};
+use crate::metrics::{ConversionMetrics, NoMetrics};
+use crate::optimize::PostOptimize;
+use crate::pipeline::{ConversionHandle, ConversionOptions, ConversionOutput, PipelineConfig};
+use crate::process::{Binaries, ChildLimits};
+use crate::spill::SpillConfig;
+use crate::temp::{self, TempManager};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// What a `Converter` is set up with. The defaults are the server's, with
+/// nothing set in the environment.
+#[derive(Debug, Clone)]
+pub struct CoreConfig {
+    /// Where the binaries are, rather than wherever `PATH` has them
+    pub ffmpeg_path: Option<PathBuf>,
+    pub ffprobe_path: Option<PathBuf>,
+    pub gifski_path: Option<PathBuf>,
+    /// What turns the frames into a GIF: the gifski binary, or ffmpeg if
+    /// there's no gifski, unless it's set
+    pub encoder: Option<Encoder>,
+    pub decoder: Decoder,
+    /// Whether ffmpeg decodes on a hardware decoder, if there's one that works
+    pub hwaccel: HwaccelMode,
+    /// Upper bound on a whole conversion, after which the children get killed
+    pub timeout: Duration,
+    /// Times a video the upstream served without its `moov` atom is asked
+    /// for again
+    pub moov_retries: u32,
+    pub child_limits: ChildLimits,
+    /// `-threads` for ffmpeg's decoder, every core if it's not set
+    pub ffmpeg_threads: Option<usize>,
+    /// `-filter_threads` for ffmpeg, if set
+    pub ffmpeg_filter_threads: Option<usize>,
+    /// Conversions producing more than this are aborted
+    pub max_output_bytes: Option<u64>,
+    /// Source videos bigger than this are refused
+    pub max_input_bytes: Option<u64>,
+    /// Whether ffmpeg fetches `Source::Url`s itself, or we feed them to it
+    pub fetch_mode: FetchMode,
+    /// How the upstream client connects
+    pub fetcher: FetcherConfig,
+    /// Read every source video from this directory rather than fetching it,
+    /// going by the name it's known by
+    pub source_dir: Option<PathBuf>,
+    /// Where big outputs go instead of memory, if anywhere
+    pub spill: Option<SpillConfig>,
+    /// What every temp file is made by, spilled GIFs' included
+    pub temp: Arc<TempManager>,
+    /// How GIFs are encoded, kept to what the encoders take
+    pub gif_settings: GifSettings,
+    /// Run every GIF through gifsicle if it's installed, not just the
+    /// conversions asking for it
+    pub post_optimize: bool,
+    /// How long gifsicle gets
+    pub post_optimize_timeout: Duration,
+    /// Split long videos into up to this many segments converted at once
+    pub parallel_segments: Option<usize>,
+    /// The oldest ffmpeg and gifski to work with
+    pub minimums: Minimums,
+    /// Set up even if the binaries can't do what this needs, rather than
+    /// failing with the `Problems`
+    pub skip_binary_check: bool,
+    /// What failures are counted by
+    pub metrics: Arc<dyn ConversionMetrics>,
+}
+
+impl Default for CoreConfig {
+    fn default() -> Self {
+        Self {
+            ffmpeg_path: None,
+            ffprobe_path: None,
+            gifski_path: None,
+            encoder: None,
+            decoder: Decoder::Ffmpeg,
+            hwaccel: HwaccelMode::Off,
+            timeout: Duration::from_secs(60),
+            moov_retries: 2,
+            child_limits: ChildLimits::default(),
+            ffmpeg_threads: None,
+            ffmpeg_filter_threads: None,
+            max_output_bytes: None,
+            max_input_bytes: None,
+            fetch_mode: FetchMode::Ffmpeg,
+            fetcher: FetcherConfig::default(),
+            source_dir: None,
+            spill: None,
+            temp: Arc::new(TempManager::new(std::env::temp_dir(), None, temp::ORPHAN_AFTER, None)),
+            gif_settings: GifSettings::default(),
+            post_optimize: false,
+            post_optimize_timeout: Duration::from_secs(10),
+            parallel_segments: None,
+            minimums: Minimums::default(),
+            skip_binary_check: false,
+            metrics: Arc::new(NoMetrics),
+        }
+    }
+}
+
+/// A video to convert.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// From a URL, fetched the way `CoreConfig::fetch_mode` says. It's known
+    /// by the last part of its path, e.g. `abc.mp4`.
+    Url(String),
+    /// From a file, which we read and feed to ffmpeg whatever the fetch mode.
+    Path(PathBuf),
+}
+
+/// Converts videos to GIFs: the binaries found, checked and set up once, and
+/// shared by every conversion. Cloning it is cheap, and the clones share it.
+#[derive(Debug, Clone)]
+pub struct Converter {
+    pipeline: Arc<PipelineConfig>,
+    capabilities: Arc<Capabilities>,
+}
+
+impl Converter {
+    /// Find the binaries and set up the pipeline `config` asks for. Fails if
+    /// a binary that's set isn't there, or, unless `skip_binary_check` is
+    /// set, with the `Problems` if they can't do what's needed.
+    pub fn new(config: CoreConfig) -> Result<Self, StartupError> {
+        info!("Conversions time out after {:?}", config.timeout);
+        if !config.child_limits.is_empty() {
+            if cfg!(unix) {
+                info!("Applying limits to child processes: {:?}", config.child_limits);
+            } else {
+                warn!("Child process limits aren't supported on this platform, ignoring them");
+            }
+        }
+
+        let default_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let ffmpeg_threads = config.ffmpeg_threads.unwrap_or(default_threads).max(1);
+        let ffmpeg_filter_threads = config.ffmpeg_filter_threads.map(|n| n.max(1));
+        info!(
+            "ffmpeg uses {} threads per conversion ({} filter threads)",
+            ffmpeg_threads,
+            ffmpeg_filter_threads.map_or("default".to_string(), |n| n.to_string())
+        );
+
+        // Only we can read them from a directory
+        let fetch_mode = if config.source_dir.is_some() { FetchMode::InProcess } else { config.fetch_mode };
+        info!("Fetching source videos with {:?}", fetch_mode);
+        if let Some(max) = config.max_input_bytes {
+            match fetch_mode {
+                FetchMode::InProcess => info!("Source videos over {} bytes are refused", max),
+                FetchMode::Ffmpeg => warn!(
+                    "Source videos over {} bytes are refused, but only when the upstream reports \
+                     their size; use UPSTREAM_FETCH=inprocess to enforce the limit while downloading",
+                    max
+                ),
+            }
+        }
+        info!("Upstream client: {:?}", config.fetcher);
+
+        if let Some(spill) = &config.spill {
+            info!("GIFs over {} bytes are spilled to {}", spill.threshold, config.temp.dir().display());
+        }
+        config.temp.sweep_orphans();
+
+        let default_settings = GifSettings::default();
+        let gif_settings = GifSettings {
+            quality: config.gif_settings.quality.clamp(1, 100),
+            fps: Some(config.gif_settings.fps)
+                .filter(|fps| fps.is_finite() && *fps > 0.0)
+                .unwrap_or(default_settings.fps)
+                .min(50.0),
+            ..config.gif_settings
+        };
+        info!("Encoding with {:?}", gif_settings);
+
+        // The programs we run, found once rather than on every spawn
+        let binaries = Binaries::resolve(
+            config.ffmpeg_path.as_deref(),
+            config.ffprobe_path.as_deref(),
+            config.gifski_path.as_deref(),
+        )?;
+
+        // What does the encoding: the gifski binary, the gifski library inside this
+        // process, or ffmpeg on its own. And what decodes the videos: the ffmpeg
+        // binary, or libav inside this process.
+        let (encoder, decoder) = encoder::choose(config.encoder, config.decoder, &binaries.gifski)?;
+        info!("Encoding GIFs with {:?}, decoding videos with {:?}", encoder, decoder);
+
+        // Find out now if the binaries can't do what this configuration needs,
+        // rather than from the first conversion failing
+        let capabilities = Arc::new(Capabilities::detect(&binaries.ffmpeg, &binaries.gifski));
+        let problems = capabilities::problems(&capabilities, encoder, decoder, &config.minimums);
+        if !problems.is_empty() {
+            if !config.skip_binary_check {
+                return Err(StartupError::Problems(Problems(problems)));
+            }
+            warn!("Starting anyway, but conversions may fail: {}", problems.join("; "));
+        }
+        // A hardware decoder, if one's asked for and decodes a test clip here.
+        // Only the ffmpeg binary has them.
+        let hwaccel = match decoder {
+            Decoder::Ffmpeg => hwaccel::detect(&binaries.ffmpeg, config.hwaccel),
+            Decoder::Libav => {
+                if config.hwaccel != HwaccelMode::Off {
+                    warn!("HWACCEL is for the ffmpeg decoder, so libav decodes videos in software");
+                }
+                None
+            }
+        };
+        // Other formats, for conversions asking for them
+        let mut backends = Backends::new(decoder);
+        if capabilities.can_encode("libwebp_anim") {
+            backends.register(Arc::new(FfmpegWebp));
+        }
+        // AV1 where there's an encoder quick enough for it, taking over from VP9
+        if capabilities.can_encode("libvpx-vp9") {
+            backends.register(Arc::new(FfmpegWebm(WebmCodec::Vp9)));
+        }
+        if capabilities.can_encode("libsvtav1") {
+            backends.register(Arc::new(FfmpegWebm(WebmCodec::Av1)));
+        }
+        info!("Making {} on request", backends.formats().join(", "));
+        if encoder == Encoder::Subprocess {
+            let unsupported = gif_settings.unsupported(&capabilities.gifski_flags());
+            if !unsupported.is_empty() {
+                warn!("This gifski has no flag for {}, so GIFs are made without", unsupported.join(", "));
+            }
+        }
+
+        // Optionally shrink GIFs further with gifsicle, for every conversion or just
+        // the ones asking for it
+        let post_optimize = if binaries.gifsicle.is_installed() {
+            let timeout = config.post_optimize_timeout;
+            if config.post_optimize {
+                info!("Optimizing every GIF with gifsicle (giving up after {:?})", timeout);
+            }
+            Some(PostOptimize {
+                always: config.post_optimize,
+                timeout,
+            })
+        } else {
+            if config.post_optimize {
+                warn!("POST_OPTIMIZE=gifsicle, but gifsicle isn't installed; GIFs won't be optimized");
+            }
+            None
+        };
+
+        // Long videos can be converted as several segments at once, using more cores
+        let parallel_segments = config.parallel_segments.filter(|n| *n > 1);
+        if let Some(segments) = parallel_segments {
+            info!("Converting long videos in up to {} segments at once", segments);
+        }
+
+        // The upstream client, and what source videos are read through
+        let fetcher = Fetcher::new(&config.fetcher, config.metrics.clone())?;
+        let source_fetcher: Arc<dyn SourceFetcher> = match &config.source_dir {
+            Some(dir) => {
+                info!("Reading source videos from {}", dir.display());
+                Arc::new(DirFetcher::new(dir.clone()))
+            }
+            None => Arc::new(fetcher.clone()),
+        };
+
+        Ok(Self {
+            pipeline: Arc::new(PipelineConfig {
+                binaries,
+                timeout: config.timeout,
+                moov_retries: config.moov_retries,
+                child_limits: config.child_limits,
+                ffmpeg_threads,
+                ffmpeg_filter_threads,
+                max_output_bytes: config.max_output_bytes,
+                max_input_bytes: config.max_input_bytes,
+                fetch_mode,
+                fetcher,
+                source_fetcher,
+                spill: config.spill,
+                temp: config.temp,
+                encoder,
+                backends,
+                decoder,
+                hwaccel,
+                gif_settings,
+                gifski_flags: capabilities.gifski_flags(),
+                post_optimize,
+                parallel_segments,
+                child_usage: Arc::default(),
+                metrics: config.metrics,
+            }),
+            capabilities,
+        })
+    }
+
+    /// What conversions go through, for running them some other way than
+    /// `convert` does, or looking at how they're set up.
+    pub fn pipeline(&self) -> &Arc<PipelineConfig> {
+        &self.pipeline
+    }
+
+    /// What the ffmpeg and gifski that were found can do.
+    pub fn capabilities(&self) -> &Arc<Capabilities> {
+        &self.capabilities
+    }
+
+    /// Read source videos through `fetcher` instead of the upstream client,
+    /// so every conversion does, whatever the fetch mode.
+    pub fn set_source_fetcher(&mut self, fetcher: Arc<dyn SourceFetcher>) {
+        let pipeline = Arc::make_mut(&mut self.pipeline);
+        pipeline.source_fetcher = fetcher;
+        pipeline.fetch_mode = FetchMode::InProcess;
+    }
+
+    /// Add a backend for conversions to ask for by its format, taking over
+    /// that format from the configured encoder or anything added before.
+    pub fn register_backend(&mut self, backend: Arc<dyn Backend>) {
+        Arc::make_mut(&mut self.pipeline).backends.register(backend);
+    }
+
+    /// Convert `source` with `options`, post-processing included.
+    pub async fn convert(&self, source: Source, options: &ConversionOptions) -> Result<ConversionOutput, ConversionError> {
+        let (pipeline, source) = self.prepare(source, options);
+        pipeline.convert(&source, options).await
+    }
+
+    /// Start converting `source` like `convert` does, in a task of its own,
+    /// returning the handle that can cancel it.
+    pub fn spawn(
+        &self,
+        source: Source,
+        options: &ConversionOptions,
+    ) -> ConversionHandle<Result<ConversionOutput, ConversionError>> {
+        let (pipeline, source) = self.prepare(source, options);
+        pipeline.spawn(&source, options)
+    }
+
+    // The pipeline to convert `source` with, and what its fetcher is asked
+    // for. A file is read from its directory, however anything else is read.
+    fn prepare(&self, source: Source, options: &ConversionOptions) -> (Arc<PipelineConfig>, SourceRef) {
+        match source {
+            Source::Url(url) => {
+                let name = url.split(['?', '#']).next().unwrap_or_default().rsplit('/').next().unwrap_or_default();
+                let source = self.pipeline.source_ref(name, url.clone(), options);
+                (self.pipeline.clone(), source)
+            }
+            Source::Path(path) => {
+                let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                let dir = path.parent().map(PathBuf::from).unwrap_or_default();
+                let mut pipeline = (*self.pipeline).clone();
+                pipeline.source_fetcher = Arc::new(DirFetcher::new(dir));
+                pipeline.fetch_mode = FetchMode::InProcess;
+                let source = pipeline.source_ref(&name, path.display().to_string(), options);
+                (Arc::new(pipeline), source)
+            }
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use lru::LruCache;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many hostnames we remember. We only ever talk to a handful.
+const CACHE_SIZE: usize = 64;
+
+/// Which of a host's addresses are connected to (`UPSTREAM_IP_PREFERENCE`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpPreference {
+    /// Both, in the order the system resolver gave them. The connector
+    /// tries the first one's family, and races the other against it once
+    /// that's taken 300 ms, so a family that's broken on the way to the
+    /// upstream only costs that rather than the whole connect timeout.
+    #[default]
+    Auto,
+    /// Only IPv4 addresses
+    V4,
+    /// Only IPv6 addresses
+    V6,
+}
+
+impl std::str::FromStr for IpPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(IpPreference::Auto),
+            "v4" => Ok(IpPreference::V4),
+            "v6" => Ok(IpPreference::V6),
+            other => Err(format!("unknown IP preference {:?}, expected auto, v4 or v6", other)),
+        }
+    }
+}
+
+impl IpPreference {
+    fn allows(self, addr: &SocketAddr) -> bool {
+        match self {
+            IpPreference::Auto => true,
+            IpPreference::V4 => family(addr.ip()) == "ipv4",
+            IpPreference::V6 => family(addr.ip()) == "ipv6",
+        }
+    }
+}
+
+/// Which family `ip` is, `ipv4` or `ipv6`, counting an IPv4-mapped IPv6
+/// address as the IPv4 one it maps.
+pub fn family(ip: IpAddr) -> &'static str {
+    match ip.to_canonical() {
+        IpAddr::V4(_) => "ipv4",
+        IpAddr::V6(_) => "ipv6",
+    }
+}
+
+/// Resolves hostnames with the system resolver, remembering answers for `ttl`
+/// and failures for `negative_ttl`, so conversions don't each pay for a lookup
+/// and a broken upstream doesn't get looked up on every request. Addresses
+/// nothing can be connected to are left out of every answer (see
+/// [`connectable`]), and so are those of a family `preference` rules out.
+#[derive(Clone)]
+pub struct CachingResolver {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    ttl: Duration,
+    negative_ttl: Duration,
+    preference: IpPreference,
+    cache: Mutex<LruCache<String, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entry {
+    expires: Instant,
+    // Only the message of a failure is kept, which is all a retry would get anyway
+    addrs: Result<Vec<SocketAddr>, String>,
+}
+
+/// How well the DNS cache is doing, as reported on the stats endpoint.
+#[derive(Debug, Serialize)]
+pub struct DnsStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub cached: usize,
+}
+
+impl CachingResolver {
+    pub fn new(ttl: Duration, negative_ttl: Duration, preference: IpPreference) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                ttl,
+                negative_ttl,
+                preference,
+                cache: Mutex::new(LruCache::new(NonZeroUsize::new(CACHE_SIZE).unwrap())),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// What `host` is at, from the cache while the answer's fresh. With
+    /// `fresh` it's looked up again regardless, e.g. once every address it
+    /// was at has stopped answering.
+    pub async fn lookup(&self, host: &str, fresh: bool) -> Result<Vec<SocketAddr>, String> {
+        let inner = &self.inner;
+        let cached = match fresh {
+            true => None,
+            false => {
+                let mut cache = inner.cache.lock().unwrap();
+                match cache.get(host) {
+                    Some(entry) if entry.expires > Instant::now() => Some(entry.addrs.clone()),
+                    _ => None,
+                }
+            }
+        };
+        if let Some(addrs) = cached {
+            inner.hits.fetch_add(1, Ordering::Relaxed);
+            return addrs;
+        }
+        inner.misses.fetch_add(1, Ordering::Relaxed);
+        // Port 0 gets replaced with the URL's port by reqwest
+        let addrs = tokio::net::lookup_host((host, 0))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|addrs| vetted(addrs.collect(), inner.preference));
+        let ttl = if addrs.is_ok() { inner.ttl } else { inner.negative_ttl };
+        inner.cache.lock().unwrap().put(
+            host.to_string(),
+            Entry {
+                expires: Instant::now() + ttl,
+                addrs: addrs.clone(),
+            },
+        );
+        addrs
+    }
+
+    pub fn stats(&self) -> DnsStats {
+        DnsStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+            cached: self.inner.cache.lock().unwrap().len(),
+        }
+    }
+}
+
+impl std::fmt::Debug for CachingResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CachingResolver")
+            .field("ttl", &self.inner.ttl)
+            .field("negative_ttl", &self.inner.negative_ttl)
+            .field("preference", &self.inner.preference)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs = resolver.lookup(&host, false).await.map_err(|e| format!("Failed to resolve {}: {}", host, e))?;
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Whether `ip` is one a connection can be made to at all: not the
+/// unspecified address (which connects to this host), a multicast group,
+/// or the broadcast address.
+pub fn connectable(ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    !ip.is_unspecified() && !ip.is_multicast() && ip != IpAddr::V4(Ipv4Addr::BROADCAST)
+}
+
+// The addresses of an answer that can be connected to, of the families
+// `preference` allows, or why there aren't any
+fn vetted(addrs: Vec<SocketAddr>, preference: IpPreference) -> Result<Vec<SocketAddr>, String> {
+    let (usable, refused): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| connectable(addr.ip()));
+    let (allowed, other): (Vec<_>, Vec<_>) = usable.into_iter().partition(|addr| preference.allows(addr));
+    match (allowed.is_empty(), other.first(), refused.first()) {
+        (false, _, _) => Ok(allowed),
+        (true, Some(other), _) => Err(format!("it's only at {}, which UPSTREAM_IP_PREFERENCE rules out", other.ip())),
+        (true, None, Some(refused)) => Err(format!("it's only at {}, which can't be connected to", refused.ip())),
+        (true, None, None) => Err("it has no addresses".to_string()),
+    }
+}
@@ -0,0 +1,334 @@
+use crate::error::StartupError;
+use crate::process::Binary;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use tracing::warn;
+
+/// What turns ffmpeg's frames into a GIF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoder {
+    /// The gifski binary, reading ffmpeg's output straight from its stdout
+    Subprocess,
+    /// The gifski library, inside our own process (needs the `native-encoder` feature)
+    Native,
+    /// ffmpeg itself, with `palettegen`/`paletteuse`. Bigger, uglier GIFs, but
+    /// it works on hosts without gifski.
+    Ffmpeg,
+}
+
+impl Encoder {
+    /// Whether this build can actually use the encoder.
+    pub fn is_available(&self) -> bool {
+        match self {
+            Encoder::Subprocess | Encoder::Ffmpeg => true,
+            Encoder::Native => cfg!(feature = "native-encoder"),
+        }
+    }
+
+    /// What to call the encoder in the `X-FastGIF-Encoder` header.
+    pub fn header_value(&self) -> &'static str {
+        match self {
+            Encoder::Subprocess => "gifski",
+            Encoder::Native => "gifski-native",
+            Encoder::Ffmpeg => "ffmpeg",
+        }
+    }
+
+    /// The pixel format ffmpeg has to produce for this encoder, if it's picky.
+    /// The native encoder only takes full-resolution chroma, so there's no
+    /// subsampling for it to undo.
+    pub fn ffmpeg_pix_fmt(&self) -> Option<&'static str> {
+        match self {
+            Encoder::Subprocess | Encoder::Ffmpeg => None,
+            Encoder::Native => Some("yuv444p"),
+        }
+    }
+}
+
+impl std::str::FromStr for Encoder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "subprocess" => Ok(Encoder::Subprocess),
+            "native" => Ok(Encoder::Native),
+            "ffmpeg" => Ok(Encoder::Ffmpeg),
+            other => Err(format!("unknown encoder {:?}, expected subprocess, native or ffmpeg", other)),
+        }
+    }
+}
+
+/// What decodes source videos into frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoder {
+    /// The ffmpeg binary
+    Ffmpeg,
+    /// libav, inside our own process (needs the `libav` feature). Its frames
+    /// always go to the native encoder.
+    Libav,
+}
+
+impl Decoder {
+    /// Whether this build can actually use the decoder.
+    pub fn is_available(&self) -> bool {
+        match self {
+            Decoder::Ffmpeg => true,
+            Decoder::Libav => cfg!(feature = "libav"),
+        }
+    }
+}
+
+impl std::str::FromStr for Decoder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ffmpeg" => Ok(Decoder::Ffmpeg),
+            "libav" => Ok(Decoder::Libav),
+            other => Err(format!("unknown decoder {:?}, expected ffmpeg or libav", other)),
+        }
+    }
+}
+
+/// What encodes and decodes, given what was asked for. Without an `ENCODER`
+/// it's the gifski binary, or ffmpeg where there's no gifski, and libav's
+/// frames only ever go to the native encoder.
+pub fn choose(encoder: Option<Encoder>, decoder: Decoder, gifski: &Binary) -> Result<(Encoder, Decoder), StartupError> {
+    let mut encoder = match encoder {
+        Some(encoder) => encoder,
+        None if !gifski.is_installed() => {
+            warn!("gifski isn't installed, falling back to encoding GIFs with ffmpeg");
+            Encoder::Ffmpeg
+        }
+        None => Encoder::Subprocess,
+    };
+    if !encoder.is_available() {
+        return Err(StartupError::Invalid("ENCODER=native needs a build with the native-encoder feature".to_string()));
+    }
+    if !decoder.is_available() {
+        return Err(StartupError::Invalid("DECODER=libav needs a build with the libav feature".to_string()));
+    }
+    if decoder == Decoder::Libav && encoder != Encoder::Native {
+        warn!("DECODER=libav always encodes in-process, ignoring ENCODER={:?}", encoder);
+        encoder = Encoder::Native;
+    }
+    Ok((encoder, decoder))
+}
+
+/// How the GIF is encoded, whichever encoder does it. The defaults are what
+/// we've always asked the gifski binary for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GifSettings {
+    /// 1-100, as in gifski's `--quality`
+    pub quality: u8,
+    /// Trade some quality for a much quicker encode (`--fast`)
+    pub fast: bool,
+    /// Frame rate of the GIF; frames are dropped or repeated to get there (`--fps`)
+    pub fps: f32,
+    /// How many times the animation plays after the first, or `None` to loop forever
+    pub repeat: Option<u16>,
+    /// Wider videos are scaled down to this, keeping their aspect ratio
+    pub max_width: Option<u32>,
+    /// Whether the video has an alpha channel, which the GIF keeps as
+    /// transparency unless there's a `background`
+    pub alpha: bool,
+    /// What a video with an alpha channel is flattened onto instead (`?bg=`)
+    pub background: Option<Background>,
+}
+
+/// A colour to flatten transparent videos onto, written `RRGGBB` in hex,
+/// with or without a `#`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Background(pub [u8; 3]);
+
+impl std::str::FromStr for Background {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        let channel = |at: usize| hex.get(at..at + 2).and_then(|channel| u8::from_str_radix(channel, 16).ok());
+        match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(r), Some(g), Some(b)) => Ok(Background([r, g, b])),
+            _ => Err(format!("{:?} isn't a colour like ff8800", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Background {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Background> for String {
+    fn from(background: Background) -> Self {
+        background.to_string()
+    }
+}
+
+impl std::fmt::Display for Background {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [r, g, b] = self.0;
+        write!(f, "{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+impl Default for GifSettings {
+    fn default() -> Self {
+        Self {
+            quality: 90,
+            fast: true,
+            fps: 20.0,
+            repeat: None,
+            max_width: None,
+            alpha: false,
+            background: None,
+        }
+    }
+}
+
+impl GifSettings {
+    /// The ffmpeg filter that brings the video down to `max_width`, if
+    /// there is one, and to even dimensions either way: yuv420p needs them,
+    /// and some videos have an odd width or height, which depending on the
+    /// ffmpeg version is an error or gets padded.
+    pub fn scale_filter(&self) -> String {
+        match self.max_width {
+            // -2 keeps the height even, in proportion to the width
+            Some(width) => format!("scale='trunc(min(iw,{})/2)*2':-2", width),
+            None => "scale=trunc(iw/2)*2:trunc(ih/2)*2".to_string(),
+        }
+    }
+
+    /// Whether the GIF keeps the video's transparency, and so has to be
+    /// handed frames that have it too.
+    pub fn transparent(&self) -> bool {
+        self.alpha && self.background.is_none()
+    }
+
+    /// Every ffmpeg filter the frames go through on their way to the
+    /// encoder: scaled, at the GIF's frame rate, and flattened onto the
+    /// `background` if there's transparency to flatten.
+    pub fn filters(&self) -> String {
+        let filters = format!("{},fps={}", self.scale_filter(), self.fps);
+        match self.background {
+            // A copy of each frame filled in opaque is what it's laid over, and
+            // what comes out is left without an alpha plane, which y4m can't carry
+            Some(background) if self.alpha => format!(
+                "{},split[fg][bg];[bg]drawbox=c=0x{}@1:replace=1:t=fill[bg];[bg][fg]overlay=format=yuv444,format=yuv444p",
+                filters, background
+            ),
+            _ => filters,
+        }
+    }
+
+    /// The gifski command line (minus the binary) for these settings, reading
+    /// frames from stdin and writing the GIF to stdout. Only flags `flags`
+    /// says the binary has are used; `unsupported` tells what that leaves out.
+    pub fn gifski_args(&self, flags: &GifskiFlags) -> Vec<String> {
+        let mut args: Vec<String> = vec![
+            "--output".into(), "-".into(),
+            "--quality".into(), self.quality.to_string(),
+            "--fps".into(), self.fps.to_string(),
+        ];
+        if self.fast && flags.has("--fast") {
+            args.push("--fast".into());
+        }
+        // gifski loops forever unless told otherwise, and counts repetitions
+        // differently: -1 plays once. Before `--repeat` there was only `--once`.
+        match self.repeat {
+            None => {}
+            Some(0) if !flags.has("--repeat") && flags.has("--once") => args.push("--once".into()),
+            Some(repeat) if flags.has("--repeat") => {
+                let repeat = if repeat == 0 { -1 } else { i32::from(repeat) };
+                args.extend(["--repeat".into(), repeat.to_string()]);
+            }
+            Some(_) => {}
+        }
+        args.push("-".into());  // Read from stdin
+        args
+    }
+
+    /// The settings the gifski binary with `flags` can't honour, which
+    /// `gifski_args` leaves out.
+    pub fn unsupported(&self, flags: &GifskiFlags) -> Vec<&'static str> {
+        let mut unsupported = Vec::new();
+        if self.fast && !flags.has("--fast") {
+            unsupported.push("fast");
+        }
+        match self.repeat {
+            None => {}
+            Some(0) if flags.has("--repeat") || flags.has("--once") => {}
+            Some(_) if flags.has("--repeat") => {}
+            Some(_) => unsupported.push("repeat"),
+        }
+        unsupported
+    }
+}
+
+/// The long flags a gifski binary takes, going by its `--help`. Releases have
+/// added and dropped them over the years (`--once` became `--repeat`,
+/// `--extra` and `--motion-quality` arrived later).
+#[derive(Debug, Clone, Default)]
+pub struct GifskiFlags(Option<BTreeSet<String>>);
+
+impl GifskiFlags {
+    /// The flags in `help`. Help that lists none, like a stand-in's, tells us
+    /// nothing, so then every flag is taken to be there.
+    pub fn parse(help: &str) -> Self {
+        let flags: BTreeSet<String> = help
+            .split(|c: char| c.is_whitespace() || c == ',' || c == '[' || c == ']')
+            .filter(|word| word.starts_with("--") && word.len() > 2)
+            .map(|word| word.split(['=', '<']).next().unwrap_or(word))
+            .filter(|flag| flag[2..].chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+            .map(String::from)
+            .collect();
+        Self((!flags.is_empty()).then_some(flags))
+    }
+
+    pub fn has(&self, flag: &str) -> bool {
+        self.0.as_ref().is_none_or(|flags| flags.contains(flag))
+    }
+
+    /// Every flag, or `None` if we couldn't tell.
+    pub fn known(&self) -> Option<&BTreeSet<String>> {
+        self.0.as_ref()
+    }
+}
+
+impl GifSettings {
+    /// The ffmpeg output options (everything after the input) that make ffmpeg
+    /// write the GIF itself.
+    ///
+    /// ffmpeg has no quality setting as such, so `quality` becomes the size of
+    /// the palette, and `fast` picks ordered dithering over error diffusion.
+    /// The palette is built from what changes between frames, like gifski does,
+    /// with a colour set aside for transparency if the GIF keeps it.
+    pub fn ffmpeg_gif_args(&self) -> Vec<String> {
+        let max_colors = (u32::from(self.quality) * 256 / 100).clamp(2, 256);
+        let dither = if self.fast { "bayer:bayer_scale=3" } else { "sierra2_4a" };
+        let (reserve, threshold) = match self.transparent() {
+            true => (":reserve_transparent=1", ":alpha_threshold=128"),
+            false => ("", ""),
+        };
+        let filter = format!(
+            "{},split[s0][s1];[s0]palettegen=max_colors={}:stats_mode=diff{}[p];[s1][p]paletteuse=dither={}:diff_mode=rectangle{}",
+            self.filters(), max_colors, reserve, dither, threshold
+        );
+        // Same convention as gifski: -1 plays once, 0 loops forever
+        let repeat = match self.repeat {
+            None => 0,
+            Some(0) => -1,
+            Some(n) => i32::from(n),
+        };
+        vec![
+            "-filter_complex".into(), filter,
+            "-loop".into(), repeat.to_string(),
+            "-f".into(), "gif".into(),
+            "-".into(),  // Output to stdout
+        ]
+    }
+}
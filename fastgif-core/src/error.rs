@@ -0,0 +1,130 @@
+use crate::capabilities::Problems;
+use crate::error_class::ErrorClass;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+/// Why a conversion failed. Everything from fetching the source to collecting
+/// the GIF fails with one of these, and `ErrorClass::of` sorts them into what
+/// the response, the access log and the metrics say.
+#[derive(Debug, thiserror::Error)]
+pub enum ConversionError {
+    /// ffmpeg, gifski or gifsicle couldn't be started at all
+    #[error("Failed to spawn {binary} process: {source}")]
+    Spawn {
+        binary: &'static str,
+        source: std::io::Error,
+    },
+    /// The upstream answered, but not with the video
+    #[error("Upstream responded with {status} for {url}")]
+    UpstreamStatus {
+        status: reqwest::StatusCode,
+        url: String,
+    },
+    /// The upstream didn't answer, or stopped partway through the video
+    #[error("Failed to download video: {0}")]
+    Upstream(#[from] reqwest::Error),
+    /// The upstream's host couldn't be looked up, or isn't anywhere that can
+    /// be connected to
+    #[error("Failed to resolve {host}: {reason}")]
+    UpstreamUnresolved {
+        host: String,
+        reason: String,
+    },
+    /// ffmpeg failed, in the way its stderr says it did
+    #[error("FFmpeg process failed with exit code: {code:?}")]
+    Ffmpeg {
+        code: Option<i32>,
+        class: ErrorClass,
+    },
+    /// libav couldn't make sense of the video
+    #[error("{0}")]
+    Decode(String),
+    /// There's nothing in the source to convert, for the reason given
+    #[error("Source has nothing to convert: {0}")]
+    UnsupportedSource(&'static str),
+    /// The upstream's video was still cut short after `MOOV_RETRIES` more
+    /// tries, so it may not have finished being posted
+    #[error("The upstream only has part of the video so far, try again shortly")]
+    SourceIncomplete,
+    /// The encoder failed. `status` is the gifski binary's, when it was that
+    /// which crashed.
+    #[error("{message}")]
+    Encode {
+        status: Option<ExitStatus>,
+        message: String,
+    },
+    /// Reading or writing one of the conversion's pipes or files failed
+    #[error("{context}: {source}")]
+    Pipe {
+        context: String,
+        source: std::io::Error,
+    },
+    /// The conversion didn't finish within `CONVERSION_TIMEOUT`
+    #[error("Conversion timed out after {0:?}")]
+    TimedOut(Duration),
+    /// The conversion couldn't finish by the deadline its request set, which
+    /// was sooner than `CONVERSION_TIMEOUT`
+    #[error("Conversion couldn't finish by the request's deadline")]
+    DeadlineExceeded,
+    /// Nobody is waiting for the conversion anymore
+    #[error("Conversion was cancelled")]
+    Cancelled,
+    /// The source video is bigger than `MAX_INPUT_BYTES`
+    #[error("Source video exceeds the {0} byte limit")]
+    InputTooLarge(u64),
+    /// The GIF grew past `MAX_OUTPUT_BYTES`
+    #[error("GIF output exceeded the {0} byte limit")]
+    OutputTooLarge(u64),
+    /// There's less than `MIN_FREE_DISK_BYTES` left where a temp file would
+    /// have been written
+    #[error("Less than {min_free} bytes are free in {dir}, so nothing more is written there")]
+    DiskFull {
+        dir: String,
+        min_free: u64,
+    },
+    /// The temp files would have taken up more than `TEMP_MAX_BYTES`
+    #[error("Temp files would take up more than the {0} bytes allowed them")]
+    TempSpaceExceeded(u64),
+    /// A child was killed for going over its `ChildLimits`
+    #[error("{0} was killed for exceeding its resource limits")]
+    ResourceLimit(&'static str),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl ConversionError {
+    /// For `map_err`, an I/O error on a pipe or file described by `context`.
+    pub fn pipe(context: impl Into<String>) -> impl FnOnce(std::io::Error) -> Self {
+        let context = context.into();
+        move |source| ConversionError::Pipe { context, source }
+    }
+
+    /// The gifski binary exiting with a signal or a failure status.
+    pub fn encoder_crashed(status: ExitStatus) -> Self {
+        ConversionError::Encode {
+            status: Some(status),
+            message: format!("gifski process failed ({})", status),
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        ConversionError::Internal(message.into())
+    }
+}
+
+/// Why a `Converter` couldn't be set up from its configuration.
+#[derive(Debug, thiserror::Error)]
+pub enum StartupError {
+    /// A setting can't be used, as the message says
+    #[error("{0}")]
+    Invalid(String),
+    /// The binaries can't do what the configuration needs
+    #[error("{0}")]
+    Problems(#[from] Problems),
+    /// The upstream client couldn't be built
+    #[error("Couldn't set up the upstream client: {0}")]
+    Client(#[from] reqwest::Error),
+}
+
+/// The result of anything a conversion does.
+pub type Result<T, E = ConversionError> = std::result::Result<T, E>;
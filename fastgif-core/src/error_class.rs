@@ -0,0 +1,247 @@
+use crate::error::ConversionError;
+use reqwest::StatusCode;
+
+/// Why a conversion failed. It decides the response's status and `error`
+/// code, the access log's `outcome` and which counter goes up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The upstream doesn't have the video (a 403, 404 or 410)
+    UpstreamNotFound,
+    UpstreamTimeout,
+    /// The upstream failed some other way: a 5xx, or it couldn't be reached
+    Upstream5xx,
+    FfmpegSpawnFailed,
+    /// ffmpeg couldn't make sense of the video
+    FfmpegDecodeError,
+    /// There's nothing in the source to convert: it has no video stream, or
+    /// isn't a video at all
+    UnsupportedSource,
+    /// The upstream only served part of the video, up to before its `moov`
+    /// atom, every time it was asked: it's most likely still being posted
+    SourceIncomplete,
+    /// gifski failed, or couldn't be started
+    GifskiEncodeError,
+    /// ffmpeg and the encoder stopped talking to each other
+    PipeBroken,
+    /// A child was killed for going over `CHILD_MAX_*`
+    ResourceLimit,
+    /// There wasn't `MIN_FREE_DISK_BYTES` left for a temp file, or it would
+    /// have gone over `TEMP_MAX_BYTES`
+    DiskFull,
+    InputTooLarge,
+    OutputTooLarge,
+    Timeout,
+    /// The request's own deadline (`X-Request-Timeout`) came first
+    DeadlineExceeded,
+    /// The client went away before the conversion was done
+    ClientAbort,
+    /// Cancelled because the server is stopping
+    Shutdown,
+    /// Cancelled with `DELETE /admin/conversions/{request_id}`
+    CancelledByAdmin,
+    Internal,
+}
+
+impl ErrorClass {
+    pub const ALL: [ErrorClass; 19] = [
+        ErrorClass::UpstreamNotFound,
+        ErrorClass::UpstreamTimeout,
+        ErrorClass::Upstream5xx,
+        ErrorClass::FfmpegSpawnFailed,
+        ErrorClass::FfmpegDecodeError,
+        ErrorClass::UnsupportedSource,
+        ErrorClass::SourceIncomplete,
+        ErrorClass::GifskiEncodeError,
+        ErrorClass::PipeBroken,
+        ErrorClass::ResourceLimit,
+        ErrorClass::DiskFull,
+        ErrorClass::InputTooLarge,
+        ErrorClass::OutputTooLarge,
+        ErrorClass::Timeout,
+        ErrorClass::DeadlineExceeded,
+        ErrorClass::ClientAbort,
+        ErrorClass::Shutdown,
+        ErrorClass::CancelledByAdmin,
+        ErrorClass::Internal,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorClass::UpstreamNotFound => "upstream_not_found",
+            ErrorClass::UpstreamTimeout => "upstream_timeout",
+            ErrorClass::Upstream5xx => "upstream_5xx",
+            ErrorClass::FfmpegSpawnFailed => "ffmpeg_spawn_failed",
+            ErrorClass::FfmpegDecodeError => "ffmpeg_decode_error",
+            ErrorClass::UnsupportedSource => "unsupported_source",
+            ErrorClass::SourceIncomplete => "source_incomplete",
+            ErrorClass::GifskiEncodeError => "gifski_encode_error",
+            ErrorClass::PipeBroken => "pipe_broken",
+            ErrorClass::ResourceLimit => "resource_limit",
+            ErrorClass::DiskFull => "disk_full",
+            ErrorClass::InputTooLarge => "input_too_large",
+            ErrorClass::OutputTooLarge => "output_too_large",
+            ErrorClass::Timeout => "timeout",
+            ErrorClass::DeadlineExceeded => "deadline_exceeded",
+            ErrorClass::ClientAbort => "client_abort",
+            ErrorClass::Shutdown => "shutdown",
+            ErrorClass::CancelledByAdmin => "cancelled_by_admin",
+            ErrorClass::Internal => "internal",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorClass::UpstreamNotFound => StatusCode::NOT_FOUND,
+            ErrorClass::UpstreamTimeout | ErrorClass::Timeout | ErrorClass::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            ErrorClass::Upstream5xx => StatusCode::BAD_GATEWAY,
+            ErrorClass::InputTooLarge | ErrorClass::OutputTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorClass::UnsupportedSource => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorClass::DiskFull => StatusCode::INSUFFICIENT_STORAGE,
+            ErrorClass::SourceIncomplete | ErrorClass::Shutdown | ErrorClass::CancelledByAdmin => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            // Nobody's there to see it, but it's what the access log says
+            ErrorClass::ClientAbort => StatusCode::from_u16(499).unwrap(),
+            ErrorClass::FfmpegSpawnFailed
+            | ErrorClass::FfmpegDecodeError
+            | ErrorClass::GifskiEncodeError
+            | ErrorClass::PipeBroken
+            | ErrorClass::ResourceLimit
+            | ErrorClass::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// What a command-line conversion exits with, so scripts can tell which
+    /// part failed: 3 for the input, 4 for decoding, 5 for encoding, 6 for
+    /// a limit, and 1 for anything else. (2 is a usage error.)
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorClass::UpstreamNotFound
+            | ErrorClass::UpstreamTimeout
+            | ErrorClass::Upstream5xx
+            | ErrorClass::UnsupportedSource
+            | ErrorClass::SourceIncomplete => 3,
+            ErrorClass::FfmpegDecodeError => 4,
+            ErrorClass::GifskiEncodeError => 5,
+            ErrorClass::ResourceLimit
+            | ErrorClass::InputTooLarge
+            | ErrorClass::OutputTooLarge
+            | ErrorClass::Timeout
+            | ErrorClass::DeadlineExceeded => 6,
+            ErrorClass::FfmpegSpawnFailed
+            | ErrorClass::PipeBroken
+            | ErrorClass::DiskFull
+            | ErrorClass::ClientAbort
+            | ErrorClass::Shutdown
+            | ErrorClass::CancelledByAdmin
+            | ErrorClass::Internal => 1,
+        }
+    }
+
+    /// Whether `FAILURE_PLACEHOLDER_PATH` is sent instead of the error: the
+    /// video isn't there, or is more than we'll convert, which nobody looking
+    /// at an embed can do anything about.
+    pub fn has_placeholder(self) -> bool {
+        matches!(
+            self,
+            ErrorClass::UpstreamNotFound
+                | ErrorClass::UpstreamTimeout
+                | ErrorClass::InputTooLarge
+                | ErrorClass::OutputTooLarge
+                | ErrorClass::Timeout
+        )
+    }
+
+    /// The class of a failed conversion, from the error it failed with.
+    /// A cancelled one is taken to be shutdown; only the conversion's own
+    /// task can tell it apart from a client going away, or an admin.
+    pub fn of(error: &ConversionError) -> Self {
+        match error {
+            ConversionError::Cancelled => ErrorClass::Shutdown,
+            ConversionError::TimedOut(_) => ErrorClass::Timeout,
+            ConversionError::DeadlineExceeded => ErrorClass::DeadlineExceeded,
+            ConversionError::InputTooLarge(_) => ErrorClass::InputTooLarge,
+            ConversionError::OutputTooLarge(_) => ErrorClass::OutputTooLarge,
+            ConversionError::ResourceLimit(_) => ErrorClass::ResourceLimit,
+            ConversionError::DiskFull { .. } | ConversionError::TempSpaceExceeded(_) => ErrorClass::DiskFull,
+            ConversionError::Encode { .. } => ErrorClass::GifskiEncodeError,
+            ConversionError::Ffmpeg { class, .. } => *class,
+            ConversionError::Decode(_) => ErrorClass::FfmpegDecodeError,
+            ConversionError::UnsupportedSource(_) => ErrorClass::UnsupportedSource,
+            ConversionError::SourceIncomplete => ErrorClass::SourceIncomplete,
+            ConversionError::Spawn { binary: "ffmpeg", .. } => ErrorClass::FfmpegSpawnFailed,
+            ConversionError::Spawn { .. } => ErrorClass::GifskiEncodeError,
+            ConversionError::UpstreamStatus { status, .. } => {
+                upstream_status(status.as_u16()).unwrap_or(ErrorClass::Internal)
+            }
+            ConversionError::Upstream(e) if e.is_timeout() => ErrorClass::UpstreamTimeout,
+            ConversionError::Upstream(_) | ConversionError::UpstreamUnresolved { .. } => ErrorClass::Upstream5xx,
+            ConversionError::Pipe { .. } | ConversionError::Internal(_) => ErrorClass::Internal,
+        }
+    }
+
+    /// What a line of ffmpeg's stderr says went wrong, if it's one we know.
+    /// The first line that matches is usually the cause, and the rest fallout.
+    pub fn from_ffmpeg_line(line: &str) -> Option<Self> {
+        for (pattern, class) in FFMPEG_PATTERNS {
+            if line.contains(pattern) {
+                return Some(*class);
+            }
+        }
+        if unsupported_source(line).is_some() {
+            return Some(ErrorClass::UnsupportedSource);
+        }
+        // ffmpeg fetching the video itself: `Server returned 404 Not Found`,
+        // `HTTP error 503 Service Unavailable`
+        let status = ["Server returned ", "HTTP error "]
+            .iter()
+            .find_map(|prefix| line.split_once(prefix))
+            .and_then(|(_, rest)| rest.get(..3)?.parse().ok())?;
+        upstream_status(status)
+    }
+}
+
+/// What ffmpeg writes to stderr when things go wrong, and what it means.
+/// Checked in order, against every line.
+const FFMPEG_PATTERNS: &[(&str, ErrorClass)] = &[
+    ("Server returned 5XX Server Error reply", ErrorClass::Upstream5xx),
+    ("Connection timed out", ErrorClass::UpstreamTimeout),
+    ("Operation timed out", ErrorClass::UpstreamTimeout),
+    ("Connection refused", ErrorClass::Upstream5xx),
+    ("Failed to resolve hostname", ErrorClass::Upstream5xx),
+    ("Broken pipe", ErrorClass::PipeBroken),
+    // `Error while decoding stream #0:0: Invalid data found when processing
+    // input` is a video that goes bad partway through, not one that can't
+    // be read at all
+    ("Error while decoding stream", ErrorClass::FfmpegDecodeError),
+    ("Could not find codec parameters", ErrorClass::FfmpegDecodeError),
+];
+
+/// What ffmpeg and ffprobe write to stderr about a source there's nothing
+/// to convert in, and why, as the client is told. Checked after
+/// [`FFMPEG_PATTERNS`], in order.
+const UNSUPPORTED_PATTERNS: &[(&str, &str)] = &[
+    ("moov atom not found", CUT_SHORT),
+    ("does not contain any stream", "it has no video stream"),
+    ("matches no streams", "it has no video stream"),
+    ("Invalid data found when processing input", "it isn't a video, or is too damaged to read"),
+];
+
+/// Why there's nothing to convert in an MP4 without its `moov` atom. From
+/// the upstream, that's often one that's only partly there yet, and worth
+/// asking for again.
+pub const CUT_SHORT: &str = "it's an MP4 that was cut short, or never finished being written";
+
+/// Why there's nothing to convert in a source, if a line of ffmpeg's or
+/// ffprobe's stderr says that's so.
+pub fn unsupported_source(line: &str) -> Option<&'static str> {
+    UNSUPPORTED_PATTERNS.iter().find(|(pattern, _)| line.contains(pattern)).map(|(_, reason)| *reason)
+}
+
+fn upstream_status(status: u16) -> Option<ErrorClass> {
+    match status {
+        403 | 404 | 410 => Some(ErrorClass::UpstreamNotFound),
+        500..=599 => Some(ErrorClass::Upstream5xx),
+        _ => None,
+    }
+}
@@ -0,0 +1,691 @@
+use crate::dns::{self, CachingResolver, DnsStats, IpPreference};
+use crate::error::{ConversionError, Result, StartupError};
+use crate::metrics::ConversionMetrics;
+use crate::timing::StageTimes;
+use crate::trace::TraceContext;
+use crate::upstream::{HostHealth, Transfer, UpstreamHealth};
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use lru::LruCache;
+use serde::Serialize;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio_util::io::ReaderStream;
+use tower_service::Service;
+use tracing::{info, warn};
+
+/// How long to wait before retrying the upstream, times the attempt.
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// How many sets of addresses we keep a client pinned to. Each has its own
+/// connection pool, and there's usually only the one.
+const PINNED_CLIENTS: usize = 16;
+
+tokio::task_local! {
+    // How long the request being sent took to connect, if it needed a
+    // connection of its own rather than one from the pool
+    static CONNECTED_IN: Cell<Option<Duration>>;
+}
+
+/// How source videos get to ffmpeg.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchMode {
+    /// ffmpeg downloads the URL itself
+    Ffmpeg,
+    /// We download it and stream the body into ffmpeg's stdin
+    InProcess,
+}
+
+impl std::str::FromStr for FetchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ffmpeg" => Ok(FetchMode::Ffmpeg),
+            "inprocess" => Ok(FetchMode::InProcess),
+            other => Err(format!("unknown fetch mode {:?}, expected ffmpeg or inprocess", other)),
+        }
+    }
+}
+
+/// How the shared upstream client connects and how long it keeps connections.
+#[derive(Debug, Clone, Copy)]
+pub struct FetcherConfig {
+    pub connect_timeout: Duration,
+    /// Longest wait for the next bit of a response, `None` for no limit
+    pub read_timeout: Option<Duration>,
+    /// Idle connections kept open per host
+    pub max_idle_per_host: usize,
+    pub idle_timeout: Duration,
+    pub dns_ttl: Duration,
+    pub dns_negative_ttl: Duration,
+    /// Times a download is tried again after no response or a 5xx
+    pub retries: u32,
+    /// Which of the upstream's addresses are connected to
+    pub ip_preference: IpPreference,
+}
+
+impl Default for FetcherConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: None,
+            max_idle_per_host: 16,
+            idle_timeout: Duration::from_secs(90),
+            dns_ttl: Duration::from_secs(60),
+            dns_negative_ttl: Duration::from_secs(5),
+            retries: 1,
+            ip_preference: IpPreference::Auto,
+        }
+    }
+}
+
+/// Talks to the upstream video host ourselves, rather than through ffmpeg.
+/// There's one for the whole server, so every request shares its connection
+/// pool (HTTP/2 where the upstream offers it) and DNS cache. A conversion's
+/// host is looked up once, and all its requests go to the addresses that
+/// found, through a client pinned to them; conversions that found the same
+/// ones share it, and its pool.
+#[derive(Debug, Clone)]
+pub struct Fetcher {
+    client: reqwest::Client,
+    /// By the host they're for and the addresses they're pinned to
+    pinned: Arc<Mutex<LruCache<Pin, reqwest::Client>>>,
+    config: FetcherConfig,
+    resolver: CachingResolver,
+    requests: Arc<AtomicU64>,
+    failures: Arc<AtomicU64>,
+    /// Requests that got a response, by whether it came over IPv4 or IPv6
+    ipv4: Arc<AtomicU64>,
+    ipv6: Arc<AtomicU64>,
+    retries: u32,
+    metrics: Arc<dyn ConversionMetrics>,
+    health: Arc<UpstreamHealth>,
+}
+
+/// What the upstream client has been up to, as reported on the stats endpoint.
+#[derive(Debug, Serialize)]
+pub struct FetchStats {
+    pub requests: u64,
+    /// Requests that didn't get a response at all
+    pub failures: u64,
+    /// Requests that got a response over IPv4, and over IPv6
+    pub ipv4: u64,
+    pub ipv6: u64,
+    pub dns: DnsStats,
+    /// How each host has been doing lately
+    pub hosts: BTreeMap<String, HostHealth>,
+}
+
+impl Fetcher {
+    pub fn new(config: &FetcherConfig, metrics: Arc<dyn ConversionMetrics>) -> Result<Self, StartupError> {
+        let resolver = CachingResolver::new(config.dns_ttl, config.dns_negative_ttl, config.ip_preference);
+        Ok(Self {
+            client: builder(config, &resolver).build()?,
+            pinned: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(PINNED_CLIENTS).unwrap()))),
+            config: *config,
+            resolver,
+            requests: Arc::default(),
+            failures: Arc::default(),
+            ipv4: Arc::default(),
+            ipv6: Arc::default(),
+            retries: config.retries,
+            metrics,
+            health: Arc::default(),
+        })
+    }
+
+    /// How each upstream host has been doing, going by every request this
+    /// fetcher has made to it.
+    pub fn health(&self) -> &Arc<UpstreamHealth> {
+        &self.health
+    }
+
+    pub fn stats(&self) -> FetchStats {
+        FetchStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            ipv4: self.ipv4.load(Ordering::Relaxed),
+            ipv6: self.ipv6.load(Ordering::Relaxed),
+            dns: self.resolver.stats(),
+            hosts: self.health.summary(),
+        }
+    }
+
+    // A request about to be made for `source`, adding to `timings` once it's
+    // over if it's for the conversion's video
+    fn exchange(&self, source: &SourceRef, timings: Option<&StageTimes>) -> Exchange {
+        Exchange {
+            host: reqwest::Url::parse(&source.url).ok().and_then(|url| url.host_str().map(str::to_string)),
+            transfer: Transfer::default(),
+            responded: None,
+            metrics: self.metrics.clone(),
+            health: self.health.clone(),
+            timings: timings.cloned(),
+        }
+    }
+
+    async fn send(
+        &self,
+        mut request: reqwest::RequestBuilder,
+        trace: Option<&TraceContext>,
+        exchange: &mut Exchange,
+    ) -> reqwest::Result<reqwest::Response> {
+        // So the CDN's logs can be joined up with ours
+        if let Some(trace) = trace {
+            request = request.header("traceparent", trace.traceparent());
+            if let Some(state) = &trace.tracestate {
+                request = request.header("tracestate", state);
+            }
+        }
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let sent = Instant::now();
+        let (response, connected_in) = CONNECTED_IN
+            .scope(Cell::new(None), async move {
+                let response = request.send().await;
+                (response, CONNECTED_IN.with(Cell::get))
+            })
+            .await;
+        exchange.transfer.connect = connected_in;
+        match &response {
+            Ok(response) => exchange.responded(response.status(), sent.elapsed()),
+            Err(_) => exchange.transfer.failed = true,
+        }
+        if let Some(addr) = response.as_ref().ok().and_then(reqwest::Response::remote_addr) {
+            let family = dns::family(addr.ip());
+            let count = if family == "ipv4" { &self.ipv4 } else { &self.ipv6 };
+            count.fetch_add(1, Ordering::Relaxed);
+            self.metrics.upstream_response(family);
+        }
+        if let Err(e) = &response {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            let kind = if e.is_timeout() {
+                "timeout"
+            } else if e.is_connect() {
+                "connect"
+            } else {
+                "other"
+            };
+            self.metrics.upstream_error(kind);
+        }
+        response
+    }
+
+    // The client for `source`'s requests, pinned to the addresses its host
+    // was found at. They're looked up the first time, and again if `fresh`,
+    // which `exchange` times.
+    async fn client_for(&self, source: &SourceRef, fresh: bool, exchange: &mut Exchange) -> Result<reqwest::Client> {
+        // An address already, or not a URL at all, which reqwest will say
+        let Some(host) = reqwest::Url::parse(&source.url).ok().and_then(|url| url.domain().map(str::to_string)) else {
+            return Ok(self.client.clone());
+        };
+        let addrs = match source.pinned.get(&host) {
+            Some(addrs) if !fresh => addrs,
+            _ => {
+                let started = Instant::now();
+                let found = self.resolver.lookup(&host, fresh).await;
+                exchange.transfer.dns = Some(started.elapsed());
+                let addrs = found.map_err(|reason| {
+                    exchange.transfer.failed = true;
+                    self.metrics.upstream_error("connect");
+                    ConversionError::UpstreamUnresolved { host: host.clone(), reason }
+                })?;
+                let listed: Vec<_> = addrs.iter().map(|addr| addr.ip().to_string()).collect();
+                info!("Pinned {} to {} for this conversion", host, listed.join(", "));
+                source.pinned.set(&host, addrs.clone());
+                addrs
+            }
+        };
+        let mut pinned = self.pinned.lock().unwrap();
+        let key = (host, addrs);
+        if let Some(client) = pinned.get(&key) {
+            return Ok(client.clone());
+        }
+        let client = builder(&self.config, &self.resolver).resolve_to_addrs(&key.0, &key.1).build()?;
+        pinned.put(key, client.clone());
+        Ok(client)
+    }
+
+    /// Start downloading `source`, refusing it up front if it says it's over
+    /// its `max_bytes`. The body still has to be checked against the limit
+    /// as it's read, since the upstream doesn't have to tell us its length.
+    /// Getting no response or a 5xx is retried, but nothing once the body
+    /// has started. When none of the addresses the source is pinned to can
+    /// be connected to, its host is looked up again, once.
+    pub async fn get(&self, source: &SourceRef) -> Result<reqwest::Response> {
+        self.start(source).await.map(|(response, _)| response)
+    }
+
+    // `get`, along with the exchange that got the response, to go on timing
+    // its body
+    async fn start(&self, source: &SourceRef) -> Result<(reqwest::Response, Exchange)> {
+        let mut attempt = 0;
+        let mut looked_again = false;
+        let mut fresh = false;
+        loop {
+            match self.get_once(source, std::mem::take(&mut fresh)).await {
+                Err(ConversionError::Upstream(e)) if e.is_connect() && !looked_again => {
+                    warn!("Couldn't connect to {} where it was, looking it up again: {}", source.url, e);
+                    looked_again = true;
+                    fresh = true;
+                }
+                Err(e) if attempt < self.retries && retryable(&e) => {
+                    attempt += 1;
+                    warn!("Retrying {} ({} of {}): {}", source.url, attempt, self.retries, e);
+                    tokio::time::sleep(RETRY_BACKOFF * attempt).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn get_once(&self, source: &SourceRef, fresh: bool) -> Result<(reqwest::Response, Exchange)> {
+        let url = &source.url;
+        let mut exchange = self.exchange(source, Some(&source.timings));
+        let client = self.client_for(source, fresh, &mut exchange).await?;
+        let response = self.send(client.get(url), source.trace.as_ref(), &mut exchange).await?;
+        let status = response.status();
+        if !status.is_success() {
+            self.metrics.upstream_error("status");
+            return Err(ConversionError::UpstreamStatus { status, url: url.to_string() });
+        }
+        let length = content_length(&response);
+        check_length(length, source.max_bytes)?;
+        match response.remote_addr() {
+            Some(addr) => info!("Fetching {} from {} over {} ({:?} bytes)", url, addr, dns::family(addr.ip()), length),
+            None => info!("Fetching {} ({:?} bytes)", url, length),
+        }
+        Ok((response, exchange))
+    }
+
+    /// Ask the upstream whether `source` is still there with a conditional
+    /// HEAD request, going by `validators`. It's only asked the once.
+    pub async fn head(&self, source: &SourceRef, validators: &Validators) -> Revalidation {
+        let mut exchange = self.exchange(source, None);
+        let client = match self.client_for(source, false, &mut exchange).await {
+            Ok(client) => client,
+            Err(e) => return Revalidation::Unknown(e.to_string()),
+        };
+        let mut request = client.head(&source.url);
+        if let Some(etag) = &validators.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+        let response = match self.send(request, source.trace.as_ref(), &mut exchange).await {
+            Ok(response) => response,
+            Err(e) => return Revalidation::Unknown(e.to_string()),
+        };
+        match response.status() {
+            reqwest::StatusCode::NOT_MODIFIED => Revalidation::Current(validators.clone()),
+            // Nothing to go by yet, so this is what's gone by from now on
+            status if status.is_success() && *validators == Validators::default() => {
+                Revalidation::Current(Validators::from(response.headers()))
+            }
+            status if status.is_success() => Revalidation::Unknown("it's changed since".to_string()),
+            status @ (reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE) => Revalidation::Gone(status.as_u16()),
+            status => Revalidation::Unknown(format!("the upstream said {}", status)),
+        }
+    }
+
+    /// Ask the upstream how big `source` is with a HEAD request, refusing
+    /// it if that's over `max_bytes`. Not knowing isn't an error.
+    pub async fn check_size(&self, source: &SourceRef, max_bytes: u64) -> Result<()> {
+        let url = &source.url;
+        let mut exchange = self.exchange(source, None);
+        let client = self.client_for(source, false, &mut exchange).await?;
+        let length = match self.send(client.head(url), source.trace.as_ref(), &mut exchange).await {
+            Ok(response) if response.status().is_success() => content_length(&response),
+            Ok(_) => {
+                self.metrics.upstream_error("status");
+                None
+            }
+            Err(e) => {
+                warn!("Couldn't check the size of {}: {}", url, e);
+                None
+            }
+        };
+        if length.is_none() {
+            warn!("Upstream didn't say how big {} is, so MAX_INPUT_BYTES can't be checked", url);
+        }
+        check_length(length, Some(max_bytes))
+    }
+}
+
+impl SourceFetcher for Fetcher {
+    fn fetch<'a>(&'a self, source: &'a SourceRef) -> BoxFuture<'a, Result<SourceStream>> {
+        Box::pin(async move {
+            let (response, exchange) = self.start(source).await?;
+            // The exchange goes with the body, so it's reported once that's
+            // all been read, or broken off, or given up on
+            let body = stream::unfold((response, exchange), |(mut response, mut exchange)| async move {
+                match response.chunk().await {
+                    Ok(Some(chunk)) => {
+                        exchange.transfer.bytes += chunk.len() as u64;
+                        Some((Ok(chunk), (response, exchange)))
+                    }
+                    Ok(None) => {
+                        exchange.finished();
+                        None
+                    }
+                    Err(e) => {
+                        exchange.metrics.upstream_error(if e.is_timeout() { "timeout" } else { "body" });
+                        exchange.transfer.failed = true;
+                        Some((Err(ConversionError::Upstream(e)), (response, exchange)))
+                    }
+                }
+            });
+            Ok(body.boxed())
+        })
+    }
+
+    fn revalidate<'a>(&'a self, source: &'a SourceRef, validators: &'a Validators) -> BoxFuture<'a, Revalidation> {
+        Box::pin(self.head(source, validators))
+    }
+}
+
+// What every client to the upstream is built from
+fn builder(config: &FetcherConfig, resolver: &CachingResolver) -> reqwest::ClientBuilder {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(concat!("fastgif/", env!("CARGO_PKG_VERSION")))
+        .connect_timeout(config.connect_timeout)
+        .pool_max_idle_per_host(config.max_idle_per_host)
+        .pool_idle_timeout(config.idle_timeout)
+        .tcp_keepalive(Duration::from_secs(60))
+        .dns_resolver(Arc::new(resolver.clone()))
+        .connector_layer(TimeConnects);
+    if let Some(read_timeout) = config.read_timeout {
+        builder = builder.read_timeout(read_timeout);
+    }
+    builder
+}
+
+// Times each connection the client makes, for the request it's made for
+#[derive(Clone)]
+struct TimeConnects;
+
+impl<S> tower_layer::Layer<S> for TimeConnects {
+    type Service = TimedConnect<S>;
+
+    fn layer(&self, inner: S) -> TimedConnect<S> {
+        TimedConnect(inner)
+    }
+}
+
+#[derive(Clone)]
+struct TimedConnect<S>(S);
+
+impl<S, R> Service<R> for TimedConnect<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+    S::Response: 'static,
+    S::Error: 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, std::result::Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let connecting = self.0.call(request);
+        Box::pin(async move {
+            let started = Instant::now();
+            let connected = connecting.await;
+            // Not there when the pool connects ahead of a request
+            let _ = CONNECTED_IN.try_with(|connected_in| connected_in.set(Some(started.elapsed())));
+            connected
+        })
+    }
+}
+
+// One request to the upstream, reported once it's over however far it got:
+// to the metrics, the host's health, and the conversion it was for if any
+struct Exchange {
+    host: Option<String>,
+    transfer: Transfer,
+    // When its response's headers came
+    responded: Option<Instant>,
+    metrics: Arc<dyn ConversionMetrics>,
+    health: Arc<UpstreamHealth>,
+    timings: Option<StageTimes>,
+}
+
+impl Exchange {
+    fn responded(&mut self, status: reqwest::StatusCode, took: Duration) {
+        self.transfer.ttfb = Some(took);
+        self.transfer.status = Some(status.as_u16());
+        self.transfer.failed = status.is_server_error();
+        self.responded = Some(Instant::now());
+    }
+
+    // Its body's been read to the end
+    fn finished(&mut self) {
+        if !self.transfer.failed {
+            self.transfer.download = self.responded.map(|responded| responded.elapsed());
+        }
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        let Some(host) = &self.host else { return };
+        self.metrics.upstream_transfer(host, &self.transfer);
+        self.health.record(host, &self.transfer);
+        if let Some(timings) = &self.timings {
+            timings.upstream_fetched(&self.transfer);
+        }
+    }
+}
+
+// Whether the upstream might do better a second time: it didn't answer at
+// all, or it failed rather than refused
+fn retryable(e: &ConversionError) -> bool {
+    match e {
+        ConversionError::UpstreamStatus { status, .. } => status.is_server_error(),
+        ConversionError::Upstream(_) => true,
+        _ => false,
+    }
+}
+
+/// The video a conversion is of, and what getting it is limited by.
+#[derive(Debug, Clone)]
+pub struct SourceRef {
+    /// What it's called, e.g. `abc.mp4`: what was asked for under
+    /// `/tweet_video/`, or a file's name
+    pub path: String,
+    /// Where that is upstream
+    pub url: String,
+    /// Sources that say they're bigger are refused before they're read
+    pub max_bytes: Option<u64>,
+    /// The trace the request is part of, passed on to the upstream
+    pub trace: Option<TraceContext>,
+    /// Where its host was found, which every request for it goes to
+    pub pinned: PinnedAddrs,
+    /// The conversion's, which the upstream's phases are added to
+    pub timings: StageTimes,
+}
+
+/// The addresses a source's host was found at, once it's been looked up.
+/// Shared between a `SourceRef`'s clones, so a retried conversion goes back
+/// to the same place, and an answer that changes partway through (DNS load
+/// balancing, or rebinding) isn't followed.
+#[derive(Debug, Clone, Default)]
+pub struct PinnedAddrs(Arc<Mutex<Option<Pin>>>);
+
+/// A host, and the addresses it's to be connected to at.
+type Pin = (String, Vec<SocketAddr>);
+
+impl PinnedAddrs {
+    /// Where `host` is pinned to, if it is.
+    pub fn get(&self, host: &str) -> Option<Vec<SocketAddr>> {
+        let pinned = self.0.lock().unwrap();
+        pinned.as_ref().filter(|(pinned, _)| pinned == host).map(|(_, addrs)| addrs.clone())
+    }
+
+    /// Pin `host` to `addrs`, in place of wherever it was.
+    pub fn set(&self, host: &str, addrs: Vec<SocketAddr>) {
+        *self.0.lock().unwrap() = Some((host.to_string(), addrs));
+    }
+}
+
+/// A source video's bytes, as they arrive.
+pub type SourceStream = BoxStream<'static, Result<Bytes>>;
+
+/// Gets source videos for the conversions that read them through us rather
+/// than leaving it to ffmpeg (`UPSTREAM_FETCH=inprocess`). What's read is
+/// counted against `max_bytes` as it goes by the pipeline, so a fetcher only
+/// has to refuse sources it knows are too big up front. A missing one is a
+/// `ConversionError::UpstreamStatus` 404, as it is from the upstream.
+pub trait SourceFetcher: Send + Sync + std::fmt::Debug {
+    fn fetch<'a>(&'a self, source: &'a SourceRef) -> BoxFuture<'a, Result<SourceStream>>;
+
+    /// Where ffprobe reads `source` from, to find out how long it is.
+    fn probe_input(&self, source: &SourceRef) -> String {
+        source.url.clone()
+    }
+
+    /// Whether `fetch` can be called again for the same source, as retrying
+    /// a conversion does. It can't when the video is read as it arrives from
+    /// somewhere that only sends it once.
+    fn refetchable(&self) -> bool {
+        true
+    }
+
+    /// Whether `source` is still there, and still what `validators` say it
+    /// was, for a GIF made from it that's been kept a while. Fetchers that
+    /// can't tell say so.
+    fn revalidate<'a>(&'a self, source: &'a SourceRef, validators: &'a Validators) -> BoxFuture<'a, Revalidation> {
+        let _ = (source, validators);
+        Box::pin(async { Revalidation::Unknown("its fetcher can't tell".to_string()) })
+    }
+}
+
+/// What the upstream last said a source's version was (`ETag` and
+/// `Last-Modified`), to ask whether it's changed since.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    fn from(headers: &reqwest::header::HeaderMap) -> Self {
+        let header = |name| headers.get(name)?.to_str().ok().map(str::to_string);
+        Self { etag: header(reqwest::header::ETAG), last_modified: header(reqwest::header::LAST_MODIFIED) }
+    }
+}
+
+/// What asking about a source again came to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Revalidation {
+    /// It's still there: unchanged (a `304`), or a `2xx` when there was
+    /// nothing to ask with, with what to ask with next time
+    Current(Validators),
+    /// It's been taken down: a `404` or `410`
+    Gone(u16),
+    /// Anything else, which says nothing either way
+    Unknown(String),
+}
+
+/// Source videos read from a directory instead of the upstream
+/// (`SOURCE_DIR`), e.g. for trying conversions out without a network.
+#[derive(Debug, Clone)]
+pub struct DirFetcher {
+    root: PathBuf,
+}
+
+impl DirFetcher {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    // Where `source` is, if it's inside the directory at all
+    fn path(&self, source: &SourceRef) -> Option<PathBuf> {
+        let relative = Path::new(&source.path);
+        relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+            .then(|| self.root.join(relative))
+    }
+}
+
+impl SourceFetcher for DirFetcher {
+    fn fetch<'a>(&'a self, source: &'a SourceRef) -> BoxFuture<'a, Result<SourceStream>> {
+        Box::pin(async move {
+            let not_found = || ConversionError::UpstreamStatus {
+                status: reqwest::StatusCode::NOT_FOUND,
+                url: source.path.clone(),
+            };
+            let path = self.path(source).ok_or_else(not_found)?;
+            let file = match tokio::fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(not_found()),
+                Err(source) => {
+                    return Err(ConversionError::Pipe { context: format!("Failed to open {}", path.display()), source })
+                }
+            };
+            let metadata = file.metadata().await.map_err(ConversionError::pipe(format!("Failed to open {}", path.display())))?;
+            if !metadata.is_file() {
+                return Err(not_found());
+            }
+            check_length(Some(metadata.len()), source.max_bytes)?;
+            info!("Reading {} ({} bytes)", path.display(), metadata.len());
+            let read_failed = format!("Failed to read {}", path.display());
+            Ok(ReaderStream::new(file).map(move |chunk| chunk.map_err(ConversionError::pipe(read_failed.clone()))).boxed())
+        })
+    }
+
+    fn probe_input(&self, source: &SourceRef) -> String {
+        self.path(source).map_or_else(|| source.url.clone(), |path| path.display().to_string())
+    }
+
+    // There's nothing to a file's version but whether it's there
+    fn revalidate<'a>(&'a self, source: &'a SourceRef, _validators: &'a Validators) -> BoxFuture<'a, Revalidation> {
+        Box::pin(async move {
+            let Some(path) = self.path(source) else {
+                return Revalidation::Gone(404);
+            };
+            match tokio::fs::metadata(&path).await {
+                Ok(metadata) if metadata.is_file() => Revalidation::Current(Validators::default()),
+                Ok(_) => Revalidation::Gone(404),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Revalidation::Gone(404),
+                Err(e) => Revalidation::Unknown(format!("couldn't look at {}: {}", path.display(), e)),
+            }
+        })
+    }
+}
+
+// From the header itself: `Response::content_length` is the body's size hint,
+// which is always zero for HEAD responses
+fn content_length(response: &reqwest::Response) -> Option<u64> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn check_length(length: Option<u64>, max_bytes: Option<u64>) -> Result<()> {
+    match (length, max_bytes) {
+        (Some(length), Some(max)) if length > max => {
+            warn!(outcome = "input_too_large", "Source is {} bytes, over the {} byte limit", length, max);
+            Err(ConversionError::InputTooLarge(max))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,238 @@
+/// Why a GIF couldn't be read, or GIFs couldn't be joined.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct Malformed(String);
+
+type Result<T> = std::result::Result<T, Malformed>;
+
+// Block introducers and the extension labels we care about
+const EXTENSION: u8 = 0x21;
+const IMAGE: u8 = 0x2C;
+const TRAILER: u8 = 0x3B;
+const APPLICATION: u8 = 0xFF;
+const COMMENT: u8 = 0xFE;
+const GRAPHIC_CONTROL: u8 = 0xF9;
+
+// Flags in the logical screen and image descriptors' packed fields
+const HAS_PALETTE: u8 = 0x80;
+const SORTED_PALETTE: u8 = 0x20;
+const PALETTE_SIZE: u8 = 0x07;
+
+/// A GIF split into its parts, borrowing from the original bytes.
+struct Parsed<'a> {
+    width: u16,
+    height: u16,
+    /// Header, logical screen descriptor and global palette
+    head: &'a [u8],
+    global_palette: Option<&'a [u8]>,
+    global_palette_bits: u8,
+    blocks: Vec<Block<'a>>,
+}
+
+enum Block<'a> {
+    Extension { label: u8, bytes: &'a [u8] },
+    Image { has_palette: bool, bytes: &'a [u8] },
+}
+
+/// Join GIFs with the same dimensions into one that plays them back to back,
+/// returning it and how many frames it has.
+///
+/// The result keeps the first GIF's header, screen and loop settings. Frames
+/// from the others that relied on their own GIF's global palette get it as a
+/// local palette instead, unless it's the same as the first GIF's.
+pub fn concat(gifs: &[impl AsRef<[u8]>]) -> Result<(Vec<u8>, usize)> {
+    let mut gifs = gifs.iter().map(|gif| parse(gif.as_ref()));
+    let first = gifs.next().ok_or_else(|| Malformed("No GIFs to join".to_string()))??;
+
+    let mut joined = first.head.to_vec();
+    let mut frames = 0;
+    for block in &first.blocks {
+        if let Block::Image { .. } = block {
+            frames += 1;
+        }
+        joined.extend_from_slice(block.bytes());
+    }
+
+    for (index, gif) in gifs.enumerate() {
+        let gif = gif.map_err(|e| Malformed(format!("GIF {}: {}", index + 2, e)))?;
+        if (gif.width, gif.height) != (first.width, first.height) {
+            return Err(Malformed(format!(
+                "GIF {} is {}x{}, but the first is {}x{}",
+                index + 2,
+                gif.width,
+                gif.height,
+                first.width,
+                first.height
+            )));
+        }
+        let needs_palette = gif.global_palette != first.global_palette;
+        for block in &gif.blocks {
+            match *block {
+                // Only the first GIF's loop count (and comments) are kept
+                Block::Extension { label: APPLICATION | COMMENT, .. } => {}
+                Block::Extension { bytes, .. } => joined.extend_from_slice(bytes),
+                Block::Image { has_palette: false, bytes } if needs_palette => {
+                    let palette = gif.global_palette.ok_or_else(|| {
+                        Malformed(format!("GIF {} has a frame without any palette", index + 2))
+                    })?;
+                    let packed = bytes[9] & !(SORTED_PALETTE | PALETTE_SIZE);
+                    joined.extend_from_slice(&bytes[..9]);
+                    joined.push(packed | HAS_PALETTE | gif.global_palette_bits);
+                    joined.extend_from_slice(palette);
+                    joined.extend_from_slice(&bytes[10..]);
+                    frames += 1;
+                }
+                Block::Image { bytes, .. } => {
+                    joined.extend_from_slice(bytes);
+                    frames += 1;
+                }
+            }
+        }
+    }
+    joined.push(TRAILER);
+    Ok((joined, frames))
+}
+
+/// How many frames a GIF has, or what's wrong with it if it doesn't parse.
+pub fn frames(gif: &[u8]) -> Result<usize> {
+    let parsed = parse(gif)?;
+    Ok(parsed.blocks.iter().filter(|block| matches!(block, Block::Image { .. })).count())
+}
+
+/// What a GIF comes to, as played back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub width: u16,
+    pub height: u16,
+    pub frames: usize,
+    /// From its `NETSCAPE2.0` extension, `0` being forever
+    pub loop_count: Option<u16>,
+    /// How many colours the biggest of its palettes has
+    pub palette: usize,
+    /// Its frames' delays added up
+    pub duration_ms: u64,
+    /// Whether its first frame has a transparent colour
+    pub transparent: bool,
+}
+
+/// What's in a GIF, or what's wrong with it if it doesn't parse.
+pub fn summary(gif: &[u8]) -> Result<Summary> {
+    let parsed = parse(gif)?;
+    let colours = |packed: u8| if packed & HAS_PALETTE != 0 { 2 << (packed & PALETTE_SIZE) } else { 0 };
+    let mut summary = Summary {
+        width: parsed.width,
+        height: parsed.height,
+        frames: 0,
+        loop_count: None,
+        palette: colours(parsed.head[10]),
+        duration_ms: 0,
+        transparent: false,
+    };
+    for block in &parsed.blocks {
+        match *block {
+            // Its packed fields, then the next frame's delay in centiseconds
+            Block::Extension { label: GRAPHIC_CONTROL, bytes } if bytes.len() >= 6 => {
+                summary.duration_ms += u64::from(u16::from_le_bytes([bytes[4], bytes[5]])) * 10;
+                if summary.frames == 0 {
+                    summary.transparent |= bytes[3] & 0x01 != 0;
+                }
+            }
+            // The 11-byte identifier, then the loop sub-block
+            Block::Extension { label: APPLICATION, bytes }
+                if bytes.len() >= 18 && &bytes[3..14] == b"NETSCAPE2.0" && bytes[15] == 1 =>
+            {
+                summary.loop_count = Some(u16::from_le_bytes([bytes[16], bytes[17]]));
+            }
+            Block::Extension { .. } => {}
+            Block::Image { bytes, .. } => {
+                summary.palette = summary.palette.max(colours(bytes[9]));
+                summary.frames += 1;
+            }
+        }
+    }
+    Ok(summary)
+}
+
+impl Block<'_> {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Block::Extension { bytes, .. } | Block::Image { bytes, .. } => bytes,
+        }
+    }
+}
+
+fn parse(gif: &[u8]) -> Result<Parsed<'_>> {
+    if !(gif.starts_with(b"GIF89a") || gif.starts_with(b"GIF87a")) || gif.len() < 13 {
+        return Err(Malformed("Not a GIF".to_string()));
+    }
+    let width = u16::from_le_bytes([gif[6], gif[7]]);
+    let height = u16::from_le_bytes([gif[8], gif[9]]);
+    let packed = gif[10];
+    let mut pos = 13;
+    let global_palette_bits = packed & PALETTE_SIZE;
+    let global_palette = if packed & HAS_PALETTE != 0 {
+        let palette = take(gif, pos, palette_len(packed))?;
+        pos += palette.len();
+        Some(palette)
+    } else {
+        None
+    };
+    let head = &gif[..pos];
+
+    let mut blocks = Vec::new();
+    loop {
+        let start = pos;
+        match gif.get(pos) {
+            Some(&EXTENSION) => {
+                let label = take(gif, pos + 1, 1)?[0];
+                pos = skip_sub_blocks(gif, pos + 2)?;
+                blocks.push(Block::Extension { label, bytes: &gif[start..pos] });
+            }
+            Some(&IMAGE) => {
+                let descriptor = take(gif, pos, 10)?;
+                let has_palette = descriptor[9] & HAS_PALETTE != 0;
+                pos += 10;
+                if has_palette {
+                    pos += palette_len(descriptor[9]);
+                }
+                // The LZW minimum code size comes before the image data
+                take(gif, pos, 1)?;
+                pos = skip_sub_blocks(gif, pos + 1)?;
+                blocks.push(Block::Image { has_palette, bytes: &gif[start..pos] });
+            }
+            Some(&TRAILER) => break,
+            Some(other) => return Err(Malformed(format!("Unexpected block 0x{:02x} at byte {}", other, pos))),
+            None => return Err(Malformed("GIF ends without a trailer".to_string())),
+        }
+    }
+    Ok(Parsed {
+        width,
+        height,
+        head,
+        global_palette,
+        global_palette_bits,
+        blocks,
+    })
+}
+
+fn palette_len(packed: u8) -> usize {
+    3 << ((packed & PALETTE_SIZE) + 1)
+}
+
+fn take(gif: &[u8], pos: usize, len: usize) -> Result<&[u8]> {
+    gif.get(pos..pos + len)
+        .ok_or_else(|| Malformed(format!("GIF is truncated at byte {}", gif.len())))
+}
+
+// Skip a run of data sub-blocks, returning where the next block starts
+fn skip_sub_blocks(gif: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len = take(gif, pos, 1)?[0] as usize;
+        pos += 1;
+        if len == 0 {
+            return Ok(pos);
+        }
+        take(gif, pos, len)?;
+        pos += len;
+    }
+}
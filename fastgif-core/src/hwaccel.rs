@@ -0,0 +1,181 @@
+use crate::process::Binary;
+use std::io::Write;
+use std::process::Stdio;
+use tracing::{info, warn};
+
+/// What ffmpeg decodes the source video with (`HWACCEL`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwaccelMode {
+    /// In software, the default
+    Off,
+    /// The first of the hardware decoders that works here
+    Auto,
+    /// That one, or software if it doesn't work here
+    Only(Hwaccel),
+}
+
+/// A hardware decoder ffmpeg can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hwaccel {
+    Vaapi,
+    Nvdec,
+    VideoToolbox,
+}
+
+impl Hwaccel {
+    /// In the order `HWACCEL=auto` tries them.
+    pub const ALL: [Hwaccel; 3] = [Hwaccel::Nvdec, Hwaccel::Vaapi, Hwaccel::VideoToolbox];
+
+    /// What the `X-FastGIF-Decode` header and metrics call it.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Hwaccel::Vaapi => "vaapi",
+            Hwaccel::Nvdec => "nvdec",
+            Hwaccel::VideoToolbox => "videotoolbox",
+        }
+    }
+
+    /// What ffmpeg's `-hwaccel` calls it, and `-hwaccels` lists.
+    pub fn ffmpeg_name(self) -> &'static str {
+        match self {
+            Hwaccel::Vaapi => "vaapi",
+            // `nvdec` is only an alias, and not one every build lists
+            Hwaccel::Nvdec => "cuda",
+            Hwaccel::VideoToolbox => "videotoolbox",
+        }
+    }
+}
+
+impl std::str::FromStr for HwaccelMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(HwaccelMode::Off),
+            "auto" => Ok(HwaccelMode::Auto),
+            "vaapi" => Ok(HwaccelMode::Only(Hwaccel::Vaapi)),
+            "nvdec" | "cuda" => Ok(HwaccelMode::Only(Hwaccel::Nvdec)),
+            "videotoolbox" => Ok(HwaccelMode::Only(Hwaccel::VideoToolbox)),
+            other => Err(format!("unknown hwaccel {:?}, expected auto, vaapi, nvdec, videotoolbox or off", other)),
+        }
+    }
+}
+
+/// What the decode path is called when it's not a hardware decoder.
+pub const SOFTWARE: &str = "software";
+
+/// Whether a line of ffmpeg's stderr says it's given up on the hardware
+/// decoder. ffmpeg carries on in software after most of these, so the
+/// conversion can still succeed, just not the way it was meant to.
+pub fn gave_up(line: &str) -> bool {
+    const LINES: [&str; 5] = [
+        "hwaccel initialisation returned error",
+        "Failed setup for format",
+        "Failed to get HW surface format",
+        "Device creation failed",
+        "No device available for decoder",
+    ];
+    LINES.iter().any(|gave_up| line.contains(gave_up))
+}
+
+/// The hardware decoder `mode` asks for, if ffmpeg lists it and can decode a
+/// tiny clip with it here. Asking for one that can't means software, with a
+/// warning, rather than failing every conversion.
+pub fn detect(ffmpeg: &Binary, mode: HwaccelMode) -> Option<Hwaccel> {
+    let candidates = match mode {
+        HwaccelMode::Off => return None,
+        HwaccelMode::Auto => Hwaccel::ALL.to_vec(),
+        HwaccelMode::Only(hwaccel) => vec![hwaccel],
+    };
+    let listed = listed(ffmpeg);
+    let Some(sample) = sample(ffmpeg) else {
+        warn!("Couldn't make a clip to test hardware decoding with, so videos are decoded in software");
+        return None;
+    };
+    for hwaccel in candidates {
+        if !listed.iter().any(|name| name == hwaccel.ffmpeg_name()) {
+            if mode != HwaccelMode::Auto {
+                warn!("This ffmpeg has no {} hwaccel, so videos are decoded in software", hwaccel.as_str());
+            }
+            continue;
+        }
+        match decodes(ffmpeg, hwaccel, &sample) {
+            Ok(()) => {
+                info!("Decoding videos with {}", hwaccel.as_str());
+                return Some(hwaccel);
+            }
+            Err(reason) => warn!("{} doesn't work here ({}), so it isn't used", hwaccel.as_str(), reason),
+        }
+    }
+    if mode == HwaccelMode::Auto {
+        info!("No hardware decoder works here, so videos are decoded in software");
+    }
+    None
+}
+
+// What `ffmpeg -hwaccels` lists: a heading, then a name a line
+fn listed(ffmpeg: &Binary) -> Vec<String> {
+    let Some(output) = ffmpeg
+        .std_command()
+        .and_then(|mut command| command.args(["-hide_banner", "-hwaccels"]).stderr(Stdio::null()).output().ok())
+        .filter(|output| output.status.success())
+    else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .skip(1)
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// A second of H.264 (or MPEG-2, where there's no libx264) in MPEG-TS, for
+// the hardware decoders to prove themselves on. It's made here rather than
+// carried along, so any ffmpeg can make it.
+fn sample(ffmpeg: &Binary) -> Option<Vec<u8>> {
+    for codec in ["libx264", "mpeg2video"] {
+        let output = ffmpeg.std_command().and_then(|mut command| {
+            command
+                .args(["-hide_banner", "-v", "error", "-f", "lavfi", "-i", "testsrc=size=128x128:rate=10:duration=1"])
+                .args(["-pix_fmt", "yuv420p", "-c:v", codec, "-f", "mpegts", "pipe:1"])
+                .stderr(Stdio::null())
+                .output()
+                .ok()
+        });
+        if let Some(output) = output.filter(|output| output.status.success() && !output.stdout.is_empty()) {
+            return Some(output.stdout);
+        }
+    }
+    None
+}
+
+// Whether ffmpeg decodes `sample` with `hwaccel` without giving up on it
+fn decodes(ffmpeg: &Binary, hwaccel: Hwaccel, sample: &[u8]) -> Result<(), String> {
+    let mut command = ffmpeg.std_command().ok_or("there's no ffmpeg")?;
+    let mut child = command
+        .args(["-hide_banner", "-v", "warning", "-hwaccel", hwaccel.ffmpeg_name()])
+        .args(["-f", "mpegts", "-i", "pipe:0", "-f", "null", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    // ffmpeg giving up early shows up in how it exits
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(sample);
+    }
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if let Some(line) = stderr.lines().find(|line| gave_up(line)) {
+        return Err(line.trim().to_string());
+    }
+    if !output.status.success() {
+        return Err(match stderr.lines().rev().find(|line| !line.trim().is_empty()) {
+            Some(line) => line.trim().to_string(),
+            None => format!("ffmpeg exited with {}", output.status),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,137 @@
+//! Checking what an encoder made is an image of the format it was meant to
+//! be, before it's sent or cached. An encoder that exits cleanly having
+//! written nothing, or half a GIF, would otherwise be served for as long as
+//! the response's `Cache-Control` says.
+
+use crate::backend::Format;
+use crate::gif;
+
+/// How much of the start of an image is enough to tell its format.
+pub const HEAD: usize = 12;
+
+/// What a WebM, like any EBML document, starts with.
+const EBML_MAGIC: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
+
+// The WebM elements `webm_frames` looks in or counts
+const SEGMENT: u64 = 0x1853_8067;
+const CLUSTER: u64 = 0x1F43_B675;
+const SIMPLE_BLOCK: u64 = 0xA3;
+const BLOCK_GROUP: u64 = 0xA0;
+
+/// The two ends of an image: all that's kept of one that's too big to hold
+/// in memory, or that's been sent on as it was made.
+#[derive(Debug, Clone, Default)]
+pub struct Ends {
+    head: Vec<u8>,
+    last: Option<u8>,
+}
+
+impl Ends {
+    pub fn new(head: &[u8], last: Option<u8>) -> Self {
+        Self { head: head[..head.len().min(HEAD)].to_vec(), last }
+    }
+
+    /// Take in the next chunk of the image.
+    pub fn feed(&mut self, chunk: &[u8]) {
+        let wanted = HEAD.saturating_sub(self.head.len()).min(chunk.len());
+        self.head.extend_from_slice(&chunk[..wanted]);
+        self.last = chunk.last().copied().or(self.last);
+    }
+
+    /// What's wrong with an image with these ends as a `format`, if
+    /// anything: that it's empty, doesn't start with the format's magic, or
+    /// is a GIF without its trailer.
+    pub fn check(&self, format: Format) -> Result<(), String> {
+        let Some(last) = self.last else {
+            return Err("it's empty".to_string());
+        };
+        match format {
+            Format::Gif if !(self.head.starts_with(b"GIF89a") || self.head.starts_with(b"GIF87a")) => {
+                Err("it doesn't start with GIF89a or GIF87a".to_string())
+            }
+            Format::Gif if last != 0x3B => Err("it doesn't end with the GIF trailer".to_string()),
+            Format::Webp if !(self.head.starts_with(b"RIFF") && self.head.get(8..12) == Some(b"WEBP")) => {
+                Err("it doesn't start with a RIFF WEBP header".to_string())
+            }
+            Format::Webm if !self.head.starts_with(&EBML_MAGIC) => Err("it doesn't start with the EBML magic".to_string()),
+            Format::Gif | Format::Webp | Format::Webm => Ok(()),
+        }
+    }
+}
+
+/// What's wrong with `image` as a `format`, if anything: what `Ends::check`
+/// looks for, and then that it's all there, with at least one frame.
+pub fn check(image: &[u8], format: Format) -> Result<(), String> {
+    Ends::new(image, image.last().copied()).check(format)?;
+    let frames = match format {
+        Format::Gif => gif::frames(image).map_err(|e| e.to_string())?,
+        Format::Webp => webp_frames(image)?,
+        Format::Webm => webm_frames(image)?,
+    };
+    match frames {
+        0 => Err("it has no frames".to_string()),
+        _ => Ok(()),
+    }
+}
+
+// Walk a WebP's chunks, counting those with a frame in them. The RIFF
+// header's own size isn't trusted, since ffmpeg can't go back to fill it in
+// when it's writing to a pipe.
+fn webp_frames(webp: &[u8]) -> Result<usize, String> {
+    let mut pos = HEAD;
+    let mut frames = 0;
+    while pos < webp.len() {
+        let header = webp
+            .get(pos..pos + 8)
+            .ok_or_else(|| format!("it's truncated at byte {}", webp.len()))?;
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        // Chunks are padded to an even length
+        let end = pos + 8 + len + len % 2;
+        if end > webp.len() + len % 2 {
+            return Err(format!("it's truncated at byte {}", webp.len()));
+        }
+        if matches!(&header[..4], b"VP8 " | b"VP8L" | b"ANMF") {
+            frames += 1;
+        }
+        pos = end;
+    }
+    Ok(frames)
+}
+
+// Walk a WebM's EBML elements, going into its segment and clusters and
+// counting the blocks of frames there. Anything else is skipped over whole.
+// ffmpeg writing to a pipe can't go back to fill in the segment's size, so
+// one that's unknown runs to the end.
+fn webm_frames(webm: &[u8]) -> Result<usize, String> {
+    let truncated = || format!("it's truncated at byte {}", webm.len());
+    let mut pos = 0;
+    let mut frames = 0;
+    while pos < webm.len() {
+        let (id, id_len) = vint(&webm[pos..]).ok_or_else(truncated)?;
+        let (size, size_len) = vint(&webm[pos + id_len..]).ok_or_else(truncated)?;
+        let body = pos + id_len + size_len;
+        // The size without its length marker, all ones being unknown
+        let marker = 1 << (7 * size_len);
+        let size = size ^ marker;
+        if id == SEGMENT || id == CLUSTER {
+            pos = body;
+            continue;
+        }
+        if size == marker - 1 {
+            return Err(format!("element {:X} at byte {} has no size", id, pos));
+        }
+        if id == SIMPLE_BLOCK || id == BLOCK_GROUP {
+            frames += 1;
+        }
+        pos = body.checked_add(size as usize).filter(|end| *end <= webm.len()).ok_or_else(truncated)?;
+    }
+    Ok(frames)
+}
+
+// An EBML variable-length integer at the start of `bytes`, with its length
+// marker, and how many bytes it takes
+fn vint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let len = bytes.first()?.leading_zeros() as usize + 1;
+    let value = bytes.get(..len).filter(|_| len <= 8)?.iter().fold(0, |value, byte| value << 8 | u64::from(*byte));
+    Some((value, len))
+}
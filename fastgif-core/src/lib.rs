@@ -0,0 +1,54 @@
+//! fastgif's conversions, without the server around them: fetching a video,
+//! decoding it with ffmpeg (or libav) and encoding the frames as a GIF with
+//! gifski (the binary or the library) or ffmpeg, with the process management,
+//! limits and post-processing that go with it.
+//!
+//! ```no_run
+//! use fastgif_core::{ConversionOptions, Converter, CoreConfig, Source};
+//!
+//! # async fn convert() -> Result<(), Box<dyn std::error::Error>> {
+//! let converter = Converter::new(CoreConfig::default())?;
+//! let source = Source::Url("https://video.twimg.com/tweet_video/abc.mp4".to_string());
+//! let gif = converter.convert(source, &ConversionOptions::default()).await?.into_bytes().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The in-process encoder is behind the `native-encoder` feature, and the
+//! libav decoder behind `libav`.
+
+pub mod auto_quality;
+pub mod backend;
+pub mod capabilities;
+mod converter;
+pub mod dns;
+pub mod encoder;
+pub mod error;
+pub mod error_class;
+pub mod fetch;
+pub mod gif;
+pub mod hwaccel;
+pub mod integrity;
+#[cfg(feature = "libav")]
+mod libav_decoder;
+pub mod metrics;
+#[cfg(feature = "native-encoder")]
+mod native_encoder;
+pub mod optimize;
+pub mod pipeline;
+pub mod process;
+pub mod progress;
+pub mod segment;
+pub mod shared_decode;
+pub mod spill;
+pub mod stderr_tail;
+pub mod temp;
+pub mod tier;
+pub mod timing;
+pub mod trace;
+pub mod upstream;
+
+pub use converter::{Converter, CoreConfig, Source};
+pub use encoder::{Decoder, Encoder, GifSettings};
+pub use error::{ConversionError, StartupError};
+pub use pipeline::{ConversionOptions, ConversionOutput, Pipeline};
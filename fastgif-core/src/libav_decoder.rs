@@ -0,0 +1,268 @@
+use crate::encoder::{Background, GifSettings};
+use crate::error::{ConversionError, Result};
+use crate::error_class::unsupported_source;
+use crate::native_encoder::FrameSelector;
+use ffmpeg_next as ffmpeg;
+use ffmpeg::codec::packet::side_data::Type as SideData;
+use ffmpeg::format::{context::StreamIo, Pixel};
+use ffmpeg::media::Type;
+use ffmpeg::software::scaling::{context::Context as Scaler, flag::Flags};
+use ffmpeg::util::frame::video::Video;
+use imgref::ImgVec;
+use rgb::RGBA8;
+use std::io::Read;
+use std::sync::Once;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// Frame rate to assume for frame durations when the container doesn't say.
+const FALLBACK_FPS: f64 = 30.0;
+
+/// Where libav reads the source video from.
+pub enum Input {
+    /// libav downloads it itself
+    Url(String),
+    /// We download it and libav reads it from here
+    Stream(Box<dyn Read + Send>),
+}
+
+/// Decode the source video with libav and hand its frames to the collector,
+/// returning how many it got. Frames are dropped to bring the video down to
+/// the settings' frame rate, turned the way the video's display matrix says
+/// as ffmpeg's autorotation would, scaled down to their `max_width` and
+/// flattened onto their `background` if they have one, and decoding starts
+/// `start` into the video and stops `trim` after that if they're set.
+/// Otherwise whatever alpha channel the video has is kept. Cancelling `stop`
+/// aborts decoding, including a read that's stuck waiting on the network. A
+/// source with nothing in it to convert fails with a
+/// [`ConversionError::UnsupportedSource`].
+pub fn decode_frames(
+    input: Input,
+    collector: gifski::Collector,
+    settings: &GifSettings,
+    start: Option<Duration>,
+    trim: Option<Duration>,
+    stop: CancellationToken,
+) -> Result<usize> {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        if let Err(e) = ffmpeg::init() {
+            tracing::error!("Failed to initialise libav: {}", e);
+        }
+    });
+
+    let interrupted = {
+        let stop = stop.clone();
+        move || stop.is_cancelled()
+    };
+    let mut context = match input {
+        Input::Url(url) => ffmpeg::format::input_with_interrupt(&url, interrupted),
+        Input::Stream(reader) => ffmpeg::format::input_from_stream_with_interrupt(
+            StreamIo::from_read(reader).map_err(failed)?,
+            None,
+            None,
+            interrupted,
+        ),
+    }
+    .map_err(|e| match unsupported_source(&e.to_string()) {
+        Some(reason) => ConversionError::UnsupportedSource(reason),
+        None => ConversionError::Decode(format!("libav couldn't open the source video: {}", e)),
+    })?;
+
+    let stream = context
+        .streams()
+        .best(Type::Video)
+        .ok_or(ConversionError::UnsupportedSource("it has no video stream"))?;
+    let stream_index = stream.index();
+    let time_base = f64::from(stream.time_base());
+    let turns = stream
+        .side_data()
+        .find(|side_data| side_data.kind() == SideData::DisplayMatrix && side_data.data().len() >= 9 * 4)
+        .map_or(0, |matrix| {
+            // In degrees anticlockwise
+            let rotation = unsafe { ffmpeg::ffi::av_display_rotation_get(matrix.data().as_ptr() as *const i32) };
+            match rotation.is_finite() {
+                true => ((-rotation / 90.0).round() as i64).rem_euclid(4) as u8,
+                false => 0,
+            }
+        });
+    let frame_rate = Some(f64::from(stream.avg_frame_rate()))
+        .filter(|fps| fps.is_finite() && *fps > 0.0)
+        .unwrap_or(FALLBACK_FPS);
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())
+        .and_then(|context| context.decoder().video())
+        .map_err(failed)?;
+
+    let mut frames = Frames {
+        collector,
+        selector: FrameSelector::new(f64::from(settings.fps)),
+        max_width: settings.max_width,
+        turns,
+        background: settings.background,
+        scaler: None,
+        time_base,
+        frame_duration: 1.0 / frame_rate,
+        start: start.map_or(0.0, |start| start.as_secs_f64()),
+        trim: trim.map(|trim| trim.as_secs_f64()),
+        first_timestamp: None,
+        count: 0,
+    };
+
+    // Packets are read by hand rather than through `packets()`, which ends the
+    // same way on a network error as on the end of the file
+    let mut packet = ffmpeg::Packet::empty();
+    loop {
+        if stop.is_cancelled() {
+            return Err(ConversionError::Decode("Decoding was stopped".to_string()));
+        }
+        match packet.read(&mut context) {
+            Ok(()) => {}
+            Err(ffmpeg::Error::Eof) => break,
+            // A corrupt packet; the demuxer can pick up again after it
+            Err(ffmpeg::Error::InvalidData) => continue,
+            Err(e) => return Err(ConversionError::Decode(format!("libav failed to read the source video: {}", e))),
+        }
+        if packet.stream() != stream_index {
+            continue;
+        }
+        decoder.send_packet(&packet).map_err(failed)?;
+        if !frames.receive(&mut decoder)? {
+            return Ok(frames.count);
+        }
+    }
+    decoder.send_eof().map_err(failed)?;
+    frames.receive(&mut decoder)?;
+    info!("Decoded {} frames with libav", frames.count);
+    Ok(frames.count)
+}
+
+// Turns decoded frames into RGBA and passes on the ones the GIF needs
+struct Frames {
+    collector: gifski::Collector,
+    selector: FrameSelector,
+    // Created for the first frame, once its pixel format is known for sure
+    scaler: Option<Scaler>,
+    max_width: Option<u32>,
+    // Quarter turns clockwise each frame needs to be shown the right way up
+    turns: u8,
+    background: Option<Background>,
+    time_base: f64,
+    frame_duration: f64,
+    // Seconds from the first frame that are skipped
+    start: f64,
+    trim: Option<f64>,
+    first_timestamp: Option<f64>,
+    count: usize,
+}
+
+// What libav said went wrong, as the conversion's error
+fn failed(e: ffmpeg::Error) -> ConversionError {
+    ConversionError::Decode(e.to_string())
+}
+
+impl Frames {
+    // Take every frame the decoder has ready, returning false once no more are wanted
+    fn receive(&mut self, decoder: &mut ffmpeg::decoder::Video) -> Result<bool> {
+        let mut decoded = Video::empty();
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let Some(timestamp) = decoded.timestamp() else { continue };
+            let timestamp = timestamp as f64 * self.time_base;
+            let first = *self.first_timestamp.get_or_insert(timestamp);
+            let timestamp = timestamp - first - self.start;
+            if timestamp < 0.0 {
+                continue;
+            }
+            if self.trim.is_some_and(|trim| timestamp >= trim) {
+                return Ok(false);
+            }
+            if !self.selector.keep(timestamp + self.frame_duration) {
+                continue;
+            }
+
+            let image = self.to_rgba(&decoded)?;
+            if self.collector.add_frame_rgba(self.count, image, timestamp).is_err() {
+                // The writer gave up, and its own error says why
+                return Ok(false);
+            }
+            self.count += 1;
+        }
+        Ok(true)
+    }
+
+    fn to_rgba(&mut self, frame: &Video) -> Result<ImgVec<RGBA8>> {
+        let quarter_turned = self.turns % 2 == 1;
+        // As it's shown
+        let (source_width, source_height) = match quarter_turned {
+            true => (frame.height(), frame.width()),
+            false => (frame.width(), frame.height()),
+        };
+        // Scaled down to even dimensions like ffmpeg's
+        // `scale='trunc(min(iw,W)/2)*2':-2` would
+        let (width, height) = match self.max_width {
+            Some(max) if source_width > max => {
+                let width = (max & !1).max(2);
+                let height = (u64::from(source_height) * u64::from(width) / u64::from(source_width)) as u32;
+                (width, (height & !1).max(2))
+            }
+            _ => ((source_width & !1).max(2), (source_height & !1).max(2)),
+        };
+        // and scaled before it's turned
+        let (width, height) = match quarter_turned {
+            true => (height, width),
+            false => (width, height),
+        };
+        if self.scaler.is_none() {
+            self.scaler = Some(Scaler::get(
+                frame.format(),
+                frame.width(),
+                frame.height(),
+                Pixel::RGBA,
+                width,
+                height,
+                Flags::BILINEAR,
+            )
+            .map_err(failed)?);
+        }
+        let mut rgba = Video::empty();
+        self.scaler.as_mut().unwrap().run(frame, &mut rgba).map_err(failed)?;
+
+        // Rows can be padded, so they're copied one at a time
+        let (width, height) = (width as usize, height as usize);
+        let stride = rgba.stride(0);
+        let data = rgba.data(0);
+        let mut pixels = Vec::with_capacity(width * height);
+        for row in 0..height {
+            let start = row * stride;
+            pixels.extend(
+                data[start..start + width * 4]
+                    .chunks_exact(4)
+                    .map(|p| RGBA8::new(p[0], p[1], p[2], p[3])),
+            );
+        }
+        if let Some(Background(background)) = self.background {
+            for pixel in &mut pixels {
+                let alpha = u16::from(pixel.a);
+                let over = |channel: u8, background: u8| {
+                    ((u16::from(channel) * alpha + u16::from(background) * (255 - alpha) + 127) / 255) as u8
+                };
+                *pixel = RGBA8::new(over(pixel.r, background[0]), over(pixel.g, background[1]), over(pixel.b, background[2]), 255);
+            }
+        }
+        Ok(turned(ImgVec::new(pixels, width, height), self.turns))
+    }
+}
+
+// `image` turned a quarter clockwise `turns` times
+fn turned(image: ImgVec<RGBA8>, turns: u8) -> ImgVec<RGBA8> {
+    let (width, height) = (image.width(), image.height());
+    let pixels = image.into_buf();
+    let at = |x: usize, y: usize| pixels[y * width + x];
+    match turns % 4 {
+        0 => ImgVec::new(pixels, width, height),
+        2 => ImgVec::new(pixels.into_iter().rev().collect(), width, height),
+        // Each row of what it's turned to is a column of the frame
+        1 => ImgVec::new((0..width).flat_map(|y| (0..height).map(move |x| at(y, height - 1 - x))).collect(), height, width),
+        _ => ImgVec::new((0..width).flat_map(|y| (0..height).map(move |x| at(width - 1 - y, x))).collect(), height, width),
+    }
+}
@@ -0,0 +1,66 @@
+use crate::process::ResourceUsage;
+use crate::upstream::Transfer;
+use std::fmt::Debug;
+
+/// What conversions count as they go, for whatever keeps the metrics. Each
+/// count does nothing unless it's implemented.
+pub trait ConversionMetrics: Debug + Send + Sync {
+    /// A request to the upstream failed. `kind` is one of `connect`,
+    /// `timeout`, `status`, `body` or `other`.
+    fn upstream_error(&self, kind: &'static str) {
+        let _ = kind;
+    }
+
+    /// A request to the upstream got a response, over `family` (`ipv4` or
+    /// `ipv6`).
+    fn upstream_response(&self, family: &'static str) {
+        let _ = family;
+    }
+
+    /// A request to `host` is over, having gone as far as `transfer` says.
+    fn upstream_transfer(&self, host: &str, transfer: &Transfer) {
+        let _ = (host, transfer);
+    }
+
+    /// `process` (`ffmpeg`, `gifski` or `gifsicle`) failed. `reason` is
+    /// `failed` or `resource_limit`.
+    fn subprocess_failed(&self, process: &'static str, reason: &'static str) {
+        let _ = (process, reason);
+    }
+
+    /// What an encoder made wasn't the `format` (`gif` or `webp`) it was
+    /// meant to be, or not all of it.
+    fn invalid_output(&self, format: &'static str) {
+        let _ = format;
+    }
+
+    /// ffmpeg failed decoding on `hwaccel` (e.g. `vaapi`), and the conversion
+    /// is being tried again in software.
+    fn hwaccel_fallback(&self, hwaccel: &'static str) {
+        let _ = hwaccel;
+    }
+
+    /// ffmpeg found no `moov` atom in a video from the upstream. `outcome`
+    /// is `retried` for each time it's asked for again, then `recovered` if
+    /// one of those converted, or `gave_up`.
+    fn moov_not_found(&self, outcome: &'static str) {
+        let _ = outcome;
+    }
+
+    /// `process` (`ffmpeg` or `gifski`) exited having used `usage`.
+    fn child_usage(&self, process: &'static str, usage: ResourceUsage) {
+        let _ = (process, usage);
+    }
+
+    /// gifski crashed, and the conversion was tried once more. `outcome` is
+    /// `succeeded` or `failed`, for how the retry went.
+    fn encoder_retry(&self, outcome: &'static str) {
+        let _ = outcome;
+    }
+}
+
+/// Counting nothing, unless a `Converter` is given something else.
+#[derive(Debug, Default)]
+pub struct NoMetrics;
+
+impl ConversionMetrics for NoMetrics {}
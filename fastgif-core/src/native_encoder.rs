@@ -0,0 +1,250 @@
+use crate::backend::PNG_SIGNATURE;
+use crate::encoder::GifSettings;
+use crate::error::{ConversionError, Result};
+use imgref::ImgVec;
+use rgb::RGBA8;
+use std::io::Read;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::SyncIoBridge;
+use tracing::info;
+
+// Encoding in-process saves spawning gifski and sending the GIF back through
+// a pipe, though quantization costs the same either way. How the two compare
+// is what `cargo bench --features native-encoder -- gifski native` measures.
+// The catch is that the encoder's threads and memory are then the server's
+// own, outside `ChildLimits`, and its blocking threads can only wind down
+// rather than be killed.
+
+/// Encode the y4m stream on `input` into a GIF written to `output`, with the
+/// gifski library on blocking threads. For a GIF that keeps the video's
+/// transparency, `input` is PNGs instead.
+pub async fn encode<R, W>(input: R, output: W, settings: GifSettings) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let input = SyncIoBridge::new(input);
+    let fps = f64::from(settings.fps);
+    match settings.transparent() {
+        true => encode_frames(move |collector| decode_pngs(input, collector, fps), output, settings).await,
+        false => encode_frames(move |collector| decode_frames(input, collector, fps), output, settings).await,
+    }
+}
+
+/// Encode whatever frames `frames` hands the collector (on a blocking thread,
+/// returning how many it added) into a GIF written to `output`.
+pub async fn encode_frames<F, W>(frames: F, output: W, settings: GifSettings) -> Result<()>
+where
+    F: FnOnce(gifski::Collector) -> Result<usize> + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (collector, writer) = gifski::new(gifski::Settings {
+        width: None,
+        height: None,
+        quality: settings.quality,
+        fast: settings.fast,
+        repeat: match settings.repeat {
+            None => gifski::Repeat::Infinite,
+            Some(n) => gifski::Repeat::Finite(n),
+        },
+    })
+    .map_err(|e| encode_error(format!("gifski couldn't start encoding: {}", e)))?;
+
+    let output = SyncIoBridge::new(output);
+    let decode = tokio::task::spawn_blocking(move || frames(collector));
+    let write = tokio::task::spawn_blocking(move || {
+        writer.write(output, &mut gifski::progress::NoProgress {})
+    });
+    let (decoded, written) = tokio::join!(decode, write);
+
+    // A decoding problem explains a writer that came up short, so it goes first
+    let frame_count = decoded.map_err(|e| ConversionError::Internal(format!("Frame decoding task failed: {}", e)))??;
+    written
+        .map_err(|e| ConversionError::Internal(format!("GIF writing task failed: {}", e)))?
+        .map_err(|e| encode_error(format!("gifski failed to encode the GIF: {}", e)))?;
+    info!("Encoded {} frames in-process", frame_count);
+    Ok(())
+}
+
+fn encode_error(message: String) -> ConversionError {
+    ConversionError::Encode { status: None, message }
+}
+
+// Read y4m frames and hand them to the collector, returning how many it got.
+// Like the gifski binary, frames are dropped to bring the video down to `fps`.
+// Dropping the collector at the end is what tells the writer it has them all.
+fn decode_frames(input: impl Read, collector: gifski::Collector, fps: f64) -> Result<usize> {
+    let mut decoder = y4m::Decoder::new(input)
+        .map_err(|e| encode_error(format!("Failed to read y4m header from ffmpeg: {}", e)))?;
+    if !matches!(decoder.get_colorspace(), y4m::Colorspace::C444) {
+        return Err(encode_error(format!(
+            "Expected yuv444p frames from ffmpeg, got {:?}",
+            decoder.get_colorspace()
+        )));
+    }
+    let (width, height) = (decoder.get_width(), decoder.get_height());
+    let framerate = decoder.get_framerate();
+    if framerate.num == 0 || framerate.den == 0 {
+        return Err(encode_error(format!("y4m stream has no usable frame rate ({})", framerate)));
+    }
+    let frame_time = framerate.den as f64 / framerate.num as f64;
+    let mut selector = FrameSelector::new(fps);
+    let conversion = YuvToRgb::for_stream(width, height, decoder.get_raw_params());
+
+    let mut index = 0;
+    let mut timestamp = 0.0;
+    loop {
+        let frame = match decoder.read_frame() {
+            Ok(frame) => frame,
+            Err(y4m::Error::EOF) => break,
+            Err(e) => return Err(encode_error(format!("Failed to read frame {} from ffmpeg: {}", index, e))),
+        };
+        let this_timestamp = timestamp;
+        timestamp += frame_time;
+        if !selector.keep(timestamp) {
+            continue;
+        }
+
+        let pixels = conversion.convert(frame.get_y_plane(), frame.get_u_plane(), frame.get_v_plane());
+        let image = ImgVec::new(pixels, width, height);
+        if collector
+            .add_frame_rgba(index, image, this_timestamp)
+            .is_err()
+        {
+            // The writer gave up, and its own error says why
+            break;
+        }
+        index += 1;
+    }
+    Ok(index)
+}
+
+// Read the PNGs ffmpeg writes one after another and hand them to the
+// collector, returning how many it got. They're already at `fps`, and have
+// nothing to say when they're from, so each follows the last by a frame.
+fn decode_pngs(mut input: impl Read, collector: gifski::Collector, fps: f64) -> Result<usize> {
+    let mut index = 0;
+    while let Some(png) = read_png(&mut input)
+        .map_err(|e| encode_error(format!("Failed to read frame {} from ffmpeg: {}", index, e)))?
+    {
+        let image = lodepng::decode32(&png)
+            .map_err(|e| encode_error(format!("Failed to decode frame {} from ffmpeg: {}", index, e)))?;
+        let image = ImgVec::new(image.buffer, image.width, image.height);
+        if collector.add_frame_rgba(index, image, index as f64 / fps).is_err() {
+            // The writer gave up, and its own error says why
+            break;
+        }
+        index += 1;
+    }
+    Ok(index)
+}
+
+// The next whole PNG in `input`, found by following its chunks to `IEND`, or
+// `None` at the end of the stream
+fn read_png(input: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut png = vec![0; PNG_SIGNATURE.len()];
+    match input.read_exact(&mut png) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if png != PNG_SIGNATURE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not a PNG"));
+    }
+    loop {
+        // Each chunk's length, type, data and CRC
+        let mut header = [0; 8];
+        input.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        png.extend_from_slice(&header);
+        let start = png.len();
+        png.resize(start + length + 4, 0);
+        input.read_exact(&mut png[start..])?;
+        if &header[4..] == b"IEND" {
+            return Ok(Some(png));
+        }
+    }
+}
+
+/// Picks which frames make it into the GIF to bring a video down to `fps`, the
+/// same way the gifski binary does.
+pub struct FrameSelector {
+    wanted_frame_time: f64,
+    wanted_timestamp: f64,
+}
+
+impl FrameSelector {
+    pub fn new(fps: f64) -> Self {
+        Self {
+            wanted_frame_time: 1.0 / fps,
+            wanted_timestamp: 0.0,
+        }
+    }
+
+    /// Whether to keep the frame that's shown until `end` seconds in.
+    pub fn keep(&mut self, end: f64) -> bool {
+        if end < self.wanted_timestamp {
+            return false;
+        }
+        self.wanted_timestamp += self.wanted_frame_time;
+        true
+    }
+}
+
+// The same guess at the colour matrix the gifski binary makes for y4m input:
+// BT.601 for SD, BT.709 otherwise, limited range unless the stream says it's full
+struct YuvToRgb {
+    // Coefficients for R from V, G from U and V, and B from U
+    rv: f32,
+    gu: f32,
+    gv: f32,
+    bu: f32,
+    full_range: bool,
+}
+
+impl YuvToRgb {
+    fn for_stream(width: usize, height: usize, raw_params: &[u8]) -> Self {
+        let full_range = String::from_utf8_lossy(raw_params).contains("XCOLORRANGE=FULL");
+        let (kr, kb) = if width <= 720 && height <= 480 {
+            (0.299, 0.114)
+        } else {
+            (0.2126, 0.0722)
+        };
+        let kg = 1.0 - kr - kb;
+        Self {
+            rv: 2.0 * (1.0 - kr),
+            gu: -2.0 * kb * (1.0 - kb) / kg,
+            gv: -2.0 * kr * (1.0 - kr) / kg,
+            bu: 2.0 * (1.0 - kb),
+            full_range,
+        }
+    }
+
+    fn convert(&self, y: &[u8], u: &[u8], v: &[u8]) -> Vec<RGBA8> {
+        y.iter()
+            .zip(u)
+            .zip(v)
+            .map(|((&y, &u), &v)| {
+                let (y, u, v) = if self.full_range {
+                    (y as f32, u as f32 - 128.0, v as f32 - 128.0)
+                } else {
+                    (
+                        (y as f32 - 16.0) * (255.0 / 219.0),
+                        (u as f32 - 128.0) * (255.0 / 224.0),
+                        (v as f32 - 128.0) * (255.0 / 224.0),
+                    )
+                };
+                RGBA8::new(
+                    clamp(y + self.rv * v),
+                    clamp(y + self.gu * u + self.gv * v),
+                    clamp(y + self.bu * u),
+                    255,
+                )
+            })
+            .collect()
+    }
+}
+
+fn clamp(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
@@ -0,0 +1,120 @@
+use crate::error::{ConversionError, Result};
+use crate::pipeline::{collect_output, GifOutput, PipelineConfig};
+use crate::process::{killed_by_limit, ProcessGuard};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Shrinking finished GIFs with gifsicle, when it's installed.
+#[derive(Debug, Clone)]
+pub struct PostOptimize {
+    /// Every GIF gets optimized (`POST_OPTIMIZE=gifsicle`), not just the ones asked for
+    pub always: bool,
+    /// How long gifsicle gets before we give up and serve the GIF as it was
+    pub timeout: Duration,
+}
+
+/// Run `gif` through `gifsicle -O3 --lossy`. This never fails the conversion:
+/// whatever goes wrong, including gifsicle making things bigger, the original
+/// GIF is returned instead.
+pub async fn gifsicle(
+    mut gif: GifOutput,
+    config: &PipelineConfig,
+    post_optimize: &PostOptimize,
+    cancel: &CancellationToken,
+) -> GifOutput {
+    let original = gif.len();
+    let result = tokio::select! {
+        result = tokio::time::timeout(post_optimize.timeout, run_gifsicle(&mut gif, config, cancel)) => {
+            result.unwrap_or_else(|_| {
+                Err(ConversionError::Internal(format!("gifsicle timed out after {:?}", post_optimize.timeout)))
+            })
+        }
+        _ = cancel.cancelled() => Err(ConversionError::Cancelled),
+    };
+    match result {
+        Ok(optimized) if optimized.len() < original => {
+            info!(
+                bytes_saved = original - optimized.len(),
+                "gifsicle shrank the GIF from {} to {} bytes",
+                original,
+                optimized.len()
+            );
+            optimized
+        }
+        Ok(optimized) => {
+            info!(bytes_saved = 0, "gifsicle couldn't shrink the GIF ({} bytes after)", optimized.len());
+            rewind(gif).await
+        }
+        Err(e) => {
+            warn!("Serving the unoptimized GIF: {}", e);
+            rewind(gif).await
+        }
+    }
+}
+
+async fn run_gifsicle(gif: &mut GifOutput, config: &PipelineConfig, cancel: &CancellationToken) -> Result<GifOutput> {
+    let mut gifsicle = ProcessGuard::spawn(
+        "gifsicle",
+        config.binaries.gifsicle.command()
+            .args(["-O3", "--lossy"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+        &config.child_limits,
+    )
+    .map_err(|source| ConversionError::Spawn { binary: "gifsicle", source })?;
+    let mut stdin = gifsicle
+        .take_stdin()
+        .ok_or_else(|| ConversionError::internal("Failed to take gifsicle stdin"))?;
+    let mut stdout = gifsicle
+        .take_stdout()
+        .ok_or_else(|| ConversionError::internal("Failed to take gifsicle stdout"))?;
+
+    let original = gif.len();
+    let feed = async {
+        let fed = match gif {
+            GifOutput::Memory(bytes) => stdin.write_all(bytes).await,
+            GifOutput::Spilled { handle, .. } => tokio::io::copy(handle, &mut stdin).await.map(drop),
+            GifOutput::Streamed { .. } => return Err(ConversionError::internal("A streamed GIF can't be optimized")),
+        };
+        fed.map_err(ConversionError::pipe("Failed to feed the GIF to gifsicle"))?;
+        drop(stdin);
+        Ok(())
+    };
+    // Its output can't be bigger than what we gave it and still be worth
+    // keeping, so there's no point in letting it be
+    let collect = collect_output(&mut stdout, Some(original), config.spill.as_ref(), &config.temp, None, cancel);
+    let (fed, optimized) = tokio::join!(feed, collect);
+    // Going over that limit also leaves gifsicle with a broken pipe, so it's
+    // checked first to get the real reason
+    let optimized = match optimized {
+        Err(ConversionError::OutputTooLarge(_)) => return Err(ConversionError::internal("gifsicle made the GIF bigger")),
+        result => result?,
+    };
+    fed?;
+
+    let status = gifsicle.wait().await.map_err(ConversionError::pipe("Failed to wait for gifsicle"))?;
+    if !status.success() {
+        let reason = if killed_by_limit(&status) { "resource_limit" } else { "failed" };
+        config.metrics.subprocess_failed("gifsicle", reason);
+        return Err(ConversionError::Internal(format!("gifsicle failed with exit code: {:?}", status.code())));
+    }
+    Ok(optimized)
+}
+
+// A spilled GIF was read to the end to feed gifsicle, so it has to go back to
+// the start before it can be served
+async fn rewind(gif: GifOutput) -> GifOutput {
+    match gif {
+        GifOutput::Spilled { file, mut handle, len } => {
+            if let Err(e) = handle.rewind().await {
+                warn!("Failed to rewind spilled GIF: {}", e);
+            }
+            GifOutput::Spilled { file, handle, len }
+        }
+        other => other,
+    }
+}
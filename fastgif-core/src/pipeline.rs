@@ -0,0 +1,1532 @@
+use crate::auto_quality::Bucket;
+use crate::backend::{self, Backend, Backends, Encoded, Format};
+use crate::encoder::{Background, Decoder, Encoder, GifSettings, GifskiFlags};
+#[cfg(feature = "libav")]
+use crate::libav_decoder;
+#[cfg(feature = "native-encoder")]
+use crate::native_encoder;
+use crate::optimize::{self, PostOptimize};
+use crate::progress::{Progress, ProgressReader};
+use crate::error::{ConversionError, Result};
+use crate::error_class::{unsupported_source, ErrorClass, CUT_SHORT};
+use crate::stderr_tail::{self, StderrTail};
+use crate::segment;
+use crate::integrity::{self, Ends};
+use crate::hwaccel::{self, Hwaccel};
+use crate::fetch::{FetchMode, Fetcher, PinnedAddrs, SourceFetcher, SourceRef, SourceStream};
+use crate::metrics::ConversionMetrics;
+use crate::spill::SpillConfig;
+use crate::temp::{TempFile, TempManager};
+use crate::tier::Tier;
+use crate::timing::{StageTimes, TimedReader};
+use crate::trace::TraceContext;
+use crate::process::{
+    killed_by_broken_pipe, killed_by_limit, Binaries, ChildLimits, ProcessGuard, ResourceUsage, UsageStats,
+    TERMINATE_GRACE,
+};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    process::ChildStdout,
+    task::{AbortHandle, JoinHandle},
+};
+use tokio::sync::mpsc;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{error, info, info_span, warn, Instrument};
+
+enum Outcome<T> {
+    Finished(T),
+    TimedOut,
+    Cancelled,
+}
+
+/// Settings shared by every conversion.
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    /// Where ffmpeg, gifski and gifsicle are
+    pub binaries: Binaries,
+    /// Upper bound on a whole conversion, after which the children get killed
+    pub timeout: Duration,
+    /// Times a video the upstream served without its `moov` atom is asked
+    /// for again, `MOOV_RETRY_DELAY` apart
+    pub moov_retries: u32,
+    pub child_limits: ChildLimits,
+    /// `-threads` for ffmpeg's decoder
+    pub ffmpeg_threads: usize,
+    /// `-filter_threads` for ffmpeg, if set
+    pub ffmpeg_filter_threads: Option<usize>,
+    /// Conversions producing more than this are aborted
+    pub max_output_bytes: Option<u64>,
+    /// Source videos bigger than this are refused
+    pub max_input_bytes: Option<u64>,
+    pub fetch_mode: FetchMode,
+    /// The upstream client
+    pub fetcher: Fetcher,
+    /// What `UPSTREAM_FETCH=inprocess` reads source videos through: the
+    /// upstream client, unless something else was put in its place
+    pub source_fetcher: Arc<dyn SourceFetcher>,
+    /// Where big outputs go instead of memory, if anywhere
+    pub spill: Option<SpillConfig>,
+    /// What every temp file is made by
+    pub temp: Arc<TempManager>,
+    pub encoder: Encoder,
+    /// It and the other backends requests can ask for
+    pub backends: Backends,
+    pub decoder: Decoder,
+    /// What ffmpeg decodes with, when it isn't software
+    pub hwaccel: Option<Hwaccel>,
+    pub gif_settings: GifSettings,
+    /// What the gifski binary takes, so its command line only has those
+    pub gifski_flags: GifskiFlags,
+    /// Shrinking GIFs with gifsicle, if it's installed and enabled
+    pub post_optimize: Option<PostOptimize>,
+    /// Split long videos into up to this many segments converted at once
+    pub parallel_segments: Option<usize>,
+    /// What ffmpeg and gifski have been costing lately
+    pub child_usage: Arc<UsageStats>,
+    /// What failures are counted by
+    pub metrics: Arc<dyn ConversionMetrics>,
+}
+
+/// Settings for one particular conversion.
+#[derive(Debug, Clone, Default)]
+pub struct ConversionOptions {
+    /// Only convert this much of the start of the video
+    pub trim: Option<Duration>,
+    /// Run the GIF through gifsicle afterwards (`?optimize=1`)
+    pub optimize: bool,
+    /// How many times the GIF plays after the first, instead of looping
+    /// forever (`?repeat=`)
+    pub repeat: Option<u16>,
+    /// Encode with a reduced profile because we're busy
+    pub tier: Option<Tier>,
+    /// Encode with the profile for what the source video is like, when the
+    /// request asked for nothing itself (`AUTO_QUALITY`)
+    pub auto: Option<Bucket>,
+    /// How long the source video is, if it's been probed
+    pub duration: Option<Duration>,
+    /// Start converting this far into the video
+    pub start: Option<Duration>,
+    /// Where the conversion's time goes
+    pub timings: StageTimes,
+    /// The end of ffmpeg's and gifski's stderr, in case the conversion fails
+    pub stderr_tail: StderrTail,
+    /// The trace the request is part of, passed on to the upstream
+    pub trace: Option<TraceContext>,
+    /// Encode with this rather than the configured encoder (`?format=`)
+    pub backend: Option<Arc<dyn Backend>>,
+    /// Make it no wider than this, when that's narrower than configured
+    /// (`?width=`)
+    pub max_width: Option<u32>,
+    /// Fewer frames a second than configured (`?fps=`)
+    pub fps: Option<f32>,
+    /// Told how far the conversion has got, for whoever's watching it
+    pub progress: Option<Progress>,
+    /// How the source video has an alpha channel, if it's been probed and
+    /// has one
+    pub alpha: Option<Alpha>,
+    /// Flatten the video's transparency onto this rather than keep it (`?bg=`)
+    pub background: Option<Background>,
+    /// Give up by then, if that's sooner than `timeout` allows: the deadline
+    /// the request set itself
+    pub deadline: Option<std::time::Instant>,
+}
+
+/// How a source video carries an alpha channel, which decides how ffmpeg
+/// has to decode it to keep it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alpha {
+    /// In its pixel format, like `yuva420p` or `rgba`, which ffmpeg's own
+    /// decoders keep
+    PixelFormat,
+    /// Alongside VP8 frames, as a WebM's `alpha_mode` says, which only
+    /// libvpx decodes
+    Vp8,
+    /// Alongside VP9 frames, as sticker WebMs have it
+    Vp9,
+}
+
+impl Alpha {
+    /// The ffmpeg decoder that keeps the alpha channel, when the default
+    /// one doesn't.
+    pub fn ffmpeg_decoder(self) -> Option<&'static str> {
+        match self {
+            Alpha::PixelFormat => None,
+            Alpha::Vp8 => Some("libvpx"),
+            Alpha::Vp9 => Some("libvpx-vp9"),
+        }
+    }
+}
+
+impl ConversionOptions {
+    /// The encoder settings for this conversion. What it asks for can only
+    /// make the GIF smaller than the configuration would.
+    pub fn gif_settings(&self, config: &PipelineConfig) -> GifSettings {
+        let configured = &config.gif_settings;
+        let max_width = match (self.max_width.filter(|width| *width > 0), configured.max_width) {
+            (Some(width), Some(max)) => Some(width.min(max)),
+            (width, max) => width.or(max),
+        };
+        let fps = self.fps.filter(|fps| fps.is_finite() && *fps > 0.0).map_or(configured.fps, |fps| fps.min(configured.fps));
+        let settings = GifSettings {
+            repeat: self.repeat.or(configured.repeat),
+            max_width,
+            fps,
+            alpha: self.alpha.is_some(),
+            background: self.background,
+            ..config.gif_settings
+        };
+        let settings = match self.auto {
+            Some(bucket) => bucket.apply(settings),
+            None => settings,
+        };
+        match self.tier {
+            Some(tier) => tier.apply(settings),
+            None => settings,
+        }
+    }
+}
+
+/// The pipeline on its own, without the server around it.
+pub type Pipeline = PipelineConfig;
+
+/// What a conversion made.
+pub type ConversionOutput = GifOutput;
+
+impl PipelineConfig {
+    /// What encodes this conversion: what it asked for, or else the
+    /// configured encoder.
+    pub fn backend(&self, options: &ConversionOptions) -> Arc<dyn Backend> {
+        options.backend.clone().unwrap_or_else(|| Arc::new(self.encoder))
+    }
+
+    /// The hardware decoder a conversion with `options` is decoded with, if
+    /// any. Hardware decoders drop alpha channels, so a video with one is
+    /// decoded in software.
+    pub fn hwaccel(&self, options: &ConversionOptions) -> Option<Hwaccel> {
+        self.hwaccel.filter(|_| options.alpha.is_none())
+    }
+
+    /// How a conversion with `options` is run through gifsicle afterwards, if
+    /// it is. gifsicle only knows GIFs.
+    pub fn post_optimizes(&self, options: &ConversionOptions) -> Option<&PostOptimize> {
+        let is_gif = self.backend(options).format() == Format::Gif;
+        self.post_optimize.as_ref().filter(|post_optimize| (post_optimize.always || options.optimize) && is_gif)
+    }
+
+    /// Whether `source` is read from an upstream over HTTP rather than from a
+    /// file, so that it being cut short may only mean it isn't all there yet.
+    pub fn reads_from_upstream(&self, source: &SourceRef) -> bool {
+        let input = match self.fetch_mode {
+            FetchMode::Ffmpeg => source.url.clone(),
+            FetchMode::InProcess => self.source_fetcher.probe_input(source),
+        };
+        input.starts_with("http://") || input.starts_with("https://")
+    }
+
+    /// The video at `url` for a conversion with `options`, as a
+    /// `SourceFetcher` gets it. `path` is what it's known by to fetchers that
+    /// don't go by URLs.
+    pub fn source_ref(&self, path: &str, url: String, options: &ConversionOptions) -> SourceRef {
+        SourceRef {
+            path: path.to_string(),
+            url,
+            max_bytes: self.max_input_bytes,
+            trace: options.trace.clone(),
+            pinned: PinnedAddrs::default(),
+            timings: options.timings.clone(),
+        }
+    }
+
+    /// Convert the video at `source` the way a request for it would be,
+    /// post-processing included.
+    pub async fn convert(&self, source: &SourceRef, options: &ConversionOptions) -> Result<ConversionOutput> {
+        process_tweet_video(source, self, options, None, CancellationToken::new()).await
+    }
+
+    /// Start converting the video at `source` like `convert` does, in a task
+    /// of its own, returning the handle that can cancel it.
+    pub fn spawn(&self, source: &SourceRef, options: &ConversionOptions) -> ConversionHandle<Result<ConversionOutput>> {
+        let (config, source, options) = (self.clone(), source.clone(), options.clone());
+        let cancel = CancellationToken::new();
+        let token = cancel.clone();
+        let task = tokio::spawn(async move { process_tweet_video(&source, &config, &options, None, token).await });
+        ConversionHandle::new(task, cancel)
+    }
+}
+
+/// A conversion running in a task of its own, and the token that stops it.
+/// Cancelling stops the conversion wherever it's got to: waiting on the
+/// upstream, feeding ffmpeg or collecting the GIF, with ffmpeg's and the
+/// encoder's process groups killed on the way out. The handle resolves to
+/// what the conversion returned, `ConversionError::Cancelled` if it was
+/// cancelled.
+pub struct ConversionHandle<T> {
+    task: JoinHandle<T>,
+    cancel: CancellationToken,
+}
+
+impl<T> ConversionHandle<T> {
+    pub fn new(task: JoinHandle<T>, cancel: CancellationToken) -> Self {
+        Self { task, cancel }
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// A guard that cancels the conversion when it's dropped, unless it's
+    /// disarmed first.
+    pub fn cancel_on_drop(&self) -> DropGuard {
+        self.cancel.clone().drop_guard()
+    }
+}
+
+impl<T> std::future::Future for ConversionHandle<T> {
+    type Output = Result<T, tokio::task::JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx)
+    }
+}
+
+/// The full ffmpeg argv (minus the binary) for a conversion.
+/// `input` is either the source URL or `pipe:0` when we're feeding it ourselves.
+pub fn ffmpeg_args(input: &str, config: &PipelineConfig, options: &ConversionOptions) -> Vec<String> {
+    let mut args = ffmpeg_input_args(input, config, options);
+    args.extend(config.backend(options).ffmpeg_output(&options.gif_settings(config)));
+    args
+}
+
+/// The part of [`ffmpeg_args`] that reads and decodes the video, up to and
+/// including `-i`.
+pub fn ffmpeg_input_args(input: &str, config: &PipelineConfig, options: &ConversionOptions) -> Vec<String> {
+    let mut args: Vec<String> = vec!["-threads".into(), config.ffmpeg_threads.to_string()];
+    if let Some(filter_threads) = config.ffmpeg_filter_threads {
+        args.extend(["-filter_threads".into(), filter_threads.to_string()]);
+    }
+    if let Some(start) = options.start {
+        args.extend(["-ss".into(), format!("{:.3}", start.as_secs_f64())]);
+    }
+    if let Some(trim) = options.trim {
+        // As an input option, so ffmpeg stops reading the source there too
+        args.extend(["-t".into(), format!("{:.3}", trim.as_secs_f64())]);
+    }
+    if let Some(hwaccel) = config.hwaccel(options) {
+        args.extend(["-hwaccel".into(), hwaccel.ffmpeg_name().into()]);
+    }
+    if let Some(decoder) = options.alpha.and_then(Alpha::ffmpeg_decoder) {
+        args.extend(["-c:v".into(), decoder.into()]);
+    }
+    // ffmpeg turns a rotated video, like a phone's, the right way up before
+    // any of our filters, so they and the GIF see it as it's shown. Nothing
+    // here may pass `-noautorotate`.
+    args.extend(["-i".into(), input.into()]);  // Read from the URL, or whatever we pipe in
+    args
+}
+
+pub async fn process_tweet_video(
+    source: &SourceRef,
+    config: &PipelineConfig,
+    options: &ConversionOptions,
+    sink: Option<mpsc::Sender<std::io::Result<Bytes>>>,
+    cancel: CancellationToken,
+) -> Result<GifOutput> {
+    let streaming = sink.is_some();
+    if let Some(progress) = &options.progress {
+        progress.converting(segment::length(options));
+    }
+    let gif_data = match segment::plan(config, options, streaming) {
+        Some(segments) => segment::convert(source, config, options, segments, cancel.clone()).await?,
+        None => convert(source, config, options, sink, cancel.clone()).await?,
+    };
+    let mut gif_data = match config.post_optimizes(options) {
+        Some(_) if streaming => {
+            info!("Not optimizing a GIF that's already been streamed");
+            gif_data
+        }
+        Some(post_optimize) => optimize::gifsicle(gif_data, config, post_optimize, &cancel).await,
+        None => gif_data,
+    };
+    // Everything exiting cleanly without making a whole image is still a
+    // failure, not something to send and have cached
+    let backend = config.backend(options);
+    let format = backend.format();
+    if let Err(reason) = check_output(&mut gif_data, format).await {
+        warn!(outcome = "invalid_output", "{} made an invalid {}: {}", backend.name(), format.as_str(), reason);
+        config.metrics.invalid_output(format.as_str());
+        return Err(ConversionError::Encode {
+            status: None,
+            message: format!("{} made an invalid {} ({})", backend.name(), format.as_str(), reason),
+        });
+    }
+    Ok(gif_data)
+}
+
+// What's wrong with what a conversion made, if anything. Only the ends of
+// what isn't in memory are looked at: a spilled one is too big to read back
+// just for this, and a streamed one has gone already.
+async fn check_output(output: &mut GifOutput, format: Format) -> std::result::Result<(), String> {
+    match output {
+        GifOutput::Memory(bytes) => integrity::check(bytes, format),
+        GifOutput::Spilled { handle, len, .. } => {
+            let mut head = vec![0; integrity::HEAD.min(*len as usize)];
+            let mut last = [0];
+            let read = async {
+                handle.read_exact(&mut head).await?;
+                handle.seek(std::io::SeekFrom::End(-1)).await?;
+                handle.read_exact(&mut last).await?;
+                handle.rewind().await
+            };
+            read.await.map_err(|e| format!("it couldn't be read back: {}", e))?;
+            Ends::new(&head, Some(last[0])).check(format)
+        }
+        GifOutput::Streamed { ends, .. } => ends.check(format),
+    }
+}
+
+/// Convert the video in one go, without post-processing. If gifski crashes
+/// while ffmpeg was fine, the conversion is tried once more within what's
+/// left of the time budget, unless some of the GIF has already been streamed
+/// or the video can't be fetched again. So is one that ffmpeg failed to
+/// decode on a hardware decoder, in software the second time, and one the
+/// upstream served without its `moov` atom, up to `moov_retries` times.
+pub async fn convert(
+    source: &SourceRef,
+    config: &PipelineConfig,
+    options: &ConversionOptions,
+    sink: Option<mpsc::Sender<std::io::Result<Bytes>>>,
+    cancel: CancellationToken,
+) -> Result<GifOutput> {
+    // The timeout covers the whole conversion, starting with the upstream request
+    // and including any retry
+    let timeout = tokio::time::Instant::now() + config.timeout;
+    let requested = options.deadline.map(tokio::time::Instant::from_std).filter(|deadline| *deadline < timeout);
+    let deadline = requested.unwrap_or(timeout);
+    let refetchable = config.fetch_mode == FetchMode::Ffmpeg || config.source_fetcher.refetchable();
+    let can_retry = sink.is_none() && refetchable;
+    let result = match convert_once(source, config, options, sink.clone(), deadline, cancel.clone()).await {
+        Err(e) if can_retry && encoder_crashed(&e, config) => {
+            warn!(outcome = "encoder_retry", "Retrying the conversion once: {}", e);
+            let result = convert_once(source, config, options, None, deadline, cancel).await;
+            match &result {
+                Ok(_) => {
+                    info!(outcome = "encoder_retry_succeeded", "Conversion succeeded on the retry");
+                    config.metrics.encoder_retry("succeeded");
+                }
+                Err(e) => {
+                    warn!(outcome = "encoder_retry_failed", "Retried conversion failed too: {}", e);
+                    config.metrics.encoder_retry("failed");
+                }
+            }
+            result
+        }
+        Err(e) if can_retry && hwaccel_failed(&e) && config.hwaccel(options).is_some() => {
+            let hwaccel = config.hwaccel(options).map_or(hwaccel::SOFTWARE, Hwaccel::as_str);
+            warn!(outcome = "hwaccel_fallback", "Retrying the conversion in software, since {} failed: {}", hwaccel, e);
+            config.metrics.hwaccel_fallback(hwaccel);
+            options.timings.decoded_in_software();
+            let software = PipelineConfig { hwaccel: None, ..config.clone() };
+            let result = convert_once(source, &software, options, None, deadline, cancel).await;
+            if let Err(e) = &result {
+                warn!(outcome = "hwaccel_fallback_failed", "It failed in software too: {}", e);
+            }
+            result
+        }
+        // Nothing's been streamed of one that couldn't be opened
+        Err(e) if cut_short(&e) && refetchable && config.reads_from_upstream(source) => {
+            retry_cut_short(e, source, config, options, sink, deadline, cancel).await
+        }
+        result => result,
+    };
+    match result {
+        Err(ConversionError::TimedOut(_)) if requested.is_some() => Err(ConversionError::DeadlineExceeded),
+        result => result,
+    }
+}
+
+// Whether a conversion failed because gifski crashed, which is worth another go
+fn encoder_crashed(e: &ConversionError, config: &PipelineConfig) -> bool {
+    match e {
+        ConversionError::Encode { status: Some(_), .. } => true,
+        // Without a CPU or memory limit of ours to have killed it, a gifski that
+        // was killed is most likely down to the OOM killer. One of our limits
+        // would only kill it again.
+        ConversionError::ResourceLimit("gifski") => {
+            config.child_limits.max_cpu_seconds.is_none() && config.child_limits.max_memory_bytes.is_none()
+        }
+        _ => false,
+    }
+}
+
+/// How long a video the upstream served without its `moov` atom is left
+/// before it's asked for again, and how long a client that's told it's
+/// still incomplete is asked to wait.
+pub const MOOV_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+// Whether a conversion failed because there was no `moov` atom in the video
+fn cut_short(e: &ConversionError) -> bool {
+    matches!(e, ConversionError::UnsupportedSource(reason) if *reason == CUT_SHORT)
+}
+
+// Convert a video the upstream served without its `moov` atom again, after
+// `MOOV_RETRY_DELAY`, as many times as `moov_retries` allows and there's time
+// left for before `deadline`. Right after a video's posted, the CDN can have
+// only part of it for a moment. One that's never whole is incomplete rather
+// than unsupported, for the client to come back for.
+async fn retry_cut_short(
+    mut error: ConversionError,
+    source: &SourceRef,
+    config: &PipelineConfig,
+    options: &ConversionOptions,
+    sink: Option<mpsc::Sender<std::io::Result<Bytes>>>,
+    deadline: tokio::time::Instant,
+    cancel: CancellationToken,
+) -> Result<GifOutput> {
+    for attempt in 1..=config.moov_retries {
+        if tokio::time::Instant::now() + MOOV_RETRY_DELAY >= deadline {
+            break;
+        }
+        warn!(outcome = "moov_retry", "Retrying the conversion in {:?} ({} of {}): {}", MOOV_RETRY_DELAY, attempt, config.moov_retries, error);
+        config.metrics.moov_not_found("retried");
+        tokio::select! {
+            _ = tokio::time::sleep(MOOV_RETRY_DELAY) => {}
+            _ = cancel.cancelled() => return Err(ConversionError::Cancelled),
+        }
+        match convert_once(source, config, options, sink.clone(), deadline, cancel.clone()).await {
+            Err(e) if cut_short(&e) => error = e,
+            result => {
+                if result.is_ok() {
+                    info!(outcome = "moov_retry_succeeded", "Conversion succeeded on retry {}", attempt);
+                    config.metrics.moov_not_found("recovered");
+                }
+                return result;
+            }
+        }
+    }
+    warn!(outcome = "source_incomplete", "Giving up on a video the upstream only has part of: {}", error);
+    config.metrics.moov_not_found("gave_up");
+    Err(ConversionError::SourceIncomplete)
+}
+
+// Whether ffmpeg failed in a way a hardware decoder could be to blame for,
+// rather than the upstream or the encoder
+fn hwaccel_failed(e: &ConversionError) -> bool {
+    matches!(e, ConversionError::Ffmpeg { class: ErrorClass::FfmpegDecodeError, .. })
+}
+
+async fn convert_once(
+    source_ref: &SourceRef,
+    config: &PipelineConfig,
+    options: &ConversionOptions,
+    sink: Option<mpsc::Sender<std::io::Result<Bytes>>>,
+    deadline: tokio::time::Instant,
+    cancel: CancellationToken,
+) -> Result<GifOutput> {
+    let timeout = config.timeout;
+    let video_url = source_ref.url.clone();
+    info!("Processing video from {}", video_url);
+
+    // Every stage of this attempt stops on this, which fires however the
+    // attempt ends: cancelled, timed out, or returning early with an error
+    let stop = cancel.child_token();
+    let _stop_on_return = stop.clone().drop_guard();
+
+    let fetch_started = Instant::now();
+    let source = tokio::select! {
+        source = fetch_source(source_ref, config).instrument(info_span!("upstream_fetch")) => source?,
+        _ = tokio::time::sleep_until(deadline) => {
+            warn!("Conversion timed out after {:?} waiting for the upstream", timeout);
+            return Err(ConversionError::TimedOut(timeout));
+        }
+        _ = cancel.cancelled() => {
+            info!(outcome = "client_abort", "Client went away before the upstream responded");
+            return Err(ConversionError::Cancelled);
+        }
+    };
+
+    if source.is_some() {
+        options.timings.upstream_responded(fetch_started.elapsed());
+    }
+
+    if config.decoder == Decoder::Libav {
+        return convert_with_libav(video_url, source, config, options, sink, deadline, cancel).await;
+    }
+
+    // Both children get their own process group, and the guards kill that whole
+    // group if we bail out before they've exited
+
+    // Set up FFmpeg process to read the video (from the URL, or from us on stdin)
+    // and output yuv4mpegpipe
+    let (input, ffmpeg_stdin) = match source {
+        Some(_) => ("pipe:0", Stdio::piped()),
+        None => (video_url.as_str(), Stdio::null()),
+    };
+    let hwaccel = config.hwaccel(options);
+    options.timings.decoding_with(hwaccel.map_or(hwaccel::SOFTWARE, Hwaccel::as_str));
+    let ffmpeg_spawned = Instant::now();
+    options.timings.ffmpeg_started();
+    let mut ffmpeg_process = ProcessGuard::spawn("ffmpeg", config.binaries.ffmpeg.command()
+        .args(ffmpeg_args(input, config, options))
+        .stdin(ffmpeg_stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped()), &config.child_limits)
+        .map_err(|source| ConversionError::Spawn { binary: "ffmpeg", source })?;
+    
+    let ffmpeg_stdout = ffmpeg_process.take_stdout()
+        .ok_or_else(|| ConversionError::internal("Failed to take ffmpeg stdout"))?;
+    let (mut encoder, gif_stream) = config.backend(options).start(ffmpeg_stdout, config, options, &stop)?;
+    let mut gif_stream = TimedReader::new(counting_output(gif_stream, options), options.timings.clone());
+    let ffmpeg_stderr = ffmpeg_process.take_stderr()
+        .ok_or_else(|| ConversionError::internal("Failed to take ffmpeg stderr"))?;
+    
+    // --- Asynchronous Piping and Error Handling ---
+
+    // Task to feed the downloaded video into ffmpeg, if we're the one downloading it
+    let max_input_bytes = config.max_input_bytes;
+    let mut feed_handle = match source {
+        Some(source) => {
+            let ffmpeg_stdin = ffmpeg_process.take_stdin()
+                .ok_or_else(|| ConversionError::internal("Failed to take ffmpeg stdin"))?;
+            let feed = feed_source(source, ffmpeg_stdin, max_input_bytes, options.timings.clone(), stop.clone());
+            Some(tokio::spawn(feed.instrument(info_span!("upstream_download"))))
+        }
+        None => None,
+    };
+
+    // Task to read the encoder's output (the final GIF data)
+    let max_output_bytes = config.max_output_bytes;
+    let (spill, temp) = (config.spill.clone(), config.temp.clone());
+    let pipe_span = info_span!("pipe");
+    let collect_stop = stop.clone();
+    let mut collect_handle = tokio::spawn(async move {
+        info!("Starting to collect gifski output");
+        let result = collect_output(&mut gif_stream, max_output_bytes, spill.as_ref(), &temp, sink.as_ref(), &collect_stop).await;
+        match &result {
+            Ok(output) => info!("Collected {} bytes of GIF data from gifski", output.len()),
+            // Logged where they're noticed
+            Err(ConversionError::OutputTooLarge(_) | ConversionError::Cancelled) => {}
+            Err(e) => error!("Error reading gifski output: {}", e),
+        }
+        result
+    }.instrument(pipe_span));
+
+    // Task to log ffmpeg stderr. It runs for as long as ffmpeg does, so its
+    // span stands in for ffmpeg's, and its end for ffmpeg exiting. It comes
+    // back with the first line that says what went wrong.
+    let ffmpeg_span = info_span!("ffmpeg");
+    let timings = options.timings.clone();
+    let stderr_tail = options.stderr_tail.clone();
+    let stderr_stop = stop.clone();
+    let mut ffmpeg_stderr_handle = tokio::spawn(async move {
+        let mut reader = tokio::io::BufReader::new(ffmpeg_stderr);
+        let mut line = String::new();
+        let mut cause = None;
+        info!("Monitoring ffmpeg stderr...");
+        while read_stderr_line(&mut reader, &mut line, &stderr_stop).await {
+            info!(source = "ffmpeg", line = line.as_str(), "stderr");
+            if cause.is_none() && ErrorClass::from_ffmpeg_line(&line).is_some() {
+                cause = Some(line.clone());
+            }
+            // ffmpeg carries on in software once it's given up on the hardware
+            if hwaccel.is_some() && hwaccel::gave_up(&line) {
+                timings.decoded_in_software();
+            }
+            stderr_tail.push("ffmpeg", &line);
+        }
+        timings.ffmpeg_exited(ffmpeg_spawned);
+        info!("ffmpeg stderr stream finished.");
+        cause
+    }.instrument(ffmpeg_span));
+
+    let mut tasks = vec![
+        collect_handle.abort_handle(),
+        ffmpeg_stderr_handle.abort_handle(),
+    ];
+    tasks.extend(encoder.tasks());
+    tasks.extend(feed_handle.as_ref().map(|handle| handle.abort_handle()));
+
+    // Everything from here on is bounded by the conversion timeout and can be
+    // cancelled. The task and process handles are only borrowed, so if either
+    // of those fire they're still ours to tear down properly.
+    let work = async {
+        // Wait for the feeding and collection tasks to complete.
+        // It's often better to wait for results before waiting for process exit,
+        // especially if process exit status depends on pipes being fully read/closed.
+        // A download that went over the size limit means ffmpeg saw a truncated
+        // video, so nothing it made of that can be trusted
+        if let Some(feed_handle) = &mut feed_handle {
+            feed_handle.await.map_err(task_failed("feed"))??;
+        }
+
+        // If the output got too big, we stop right there rather than waiting
+        // for ffmpeg to notice gifski is gone.
+        let gif_data = (&mut collect_handle).await.map_err(task_failed("collect"))??;
+        info!("Collect task completed successfully.");
+
+        // Now, wait for the processes to exit and check their statuses. The
+        // encoder's about done once its output is. If it failed, ffmpeg only
+        // gets the grace it'd get after SIGTERM to exit on its own, which it
+        // does once it notices, and is otherwise killed with the rest rather
+        // than waited for until the timeout.
+        let encoded = encoder.finish().await;
+        let waited = match &encoded {
+            Ok(()) => Ok(ffmpeg_process.wait().await),
+            Err(_) => tokio::time::timeout(TERMINATE_GRACE, ffmpeg_process.wait()).await,
+        };
+        let ffmpeg_status = match waited {
+            Ok(status) => status.map_err(ConversionError::pipe("Failed to wait for ffmpeg process"))?,
+            Err(_) => {
+                warn!("ffmpeg was still running {:?} after the encoder failed", TERMINATE_GRACE);
+                return Err(blame_encoder(encoded, config.metrics.as_ref()).expect_err("the encoder failed"));
+            }
+        };
+        info!("ffmpeg process exited with status: {}", ffmpeg_status);
+
+        // Wait for stderr logging tasks to finish, since ffmpeg's says how it failed
+        let stderr_cause = (&mut ffmpeg_stderr_handle).await
+            .map_err(task_failed("ffmpeg stderr"))?;
+        info!("Stderr monitoring tasks finished.");
+        check_exit_statuses(&ffmpeg_status, stderr_cause.as_deref(), encoded, config.metrics.as_ref())?;
+        info!("ffmpeg and gifski completed successfully.");
+
+        Ok::<_, ConversionError>(gif_data)
+    };
+
+    let outcome = tokio::select! {
+        result = work => Outcome::Finished(result),
+        _ = tokio::time::sleep_until(deadline) => Outcome::TimedOut,
+        _ = cancel.cancelled() => Outcome::Cancelled,
+    };
+
+    let gif_data = match outcome {
+        Outcome::Finished(Ok(gif_data)) => gif_data,
+        Outcome::Finished(Err(e)) => {
+            // A streamed response's client going away is noticed by the sink
+            if matches!(e, ConversionError::Cancelled) {
+                info!(outcome = "client_abort", "Client went away mid-stream, killing ffmpeg and gifski");
+            }
+            abort_conversion(&stop, &tasks, &mut ffmpeg_process, &mut encoder).await;
+            return Err(e);
+        }
+        Outcome::TimedOut => {
+            warn!("Conversion timed out after {:?}, killing ffmpeg and gifski", timeout);
+            abort_conversion(&stop, &tasks, &mut ffmpeg_process, &mut encoder).await;
+            return Err(ConversionError::TimedOut(timeout));
+        }
+        Outcome::Cancelled => {
+            info!(outcome = "client_abort", "Client went away, killing ffmpeg and gifski");
+            abort_conversion(&stop, &tasks, &mut ffmpeg_process, &mut encoder).await;
+            return Err(ConversionError::Cancelled);
+        }
+    };
+
+    let ffmpeg_usage = ffmpeg_process.usage();
+    let gifski_usage = encoder.usage();
+    let stages = options.timings.snapshot();
+    for (name, usage) in [("ffmpeg", ffmpeg_usage), ("gifski", gifski_usage)] {
+        if let Some(usage) = usage {
+            config.child_usage.record(name, usage);
+            config.metrics.child_usage(name, usage);
+            options.timings.child_used(usage);
+        }
+    }
+    info!(
+        ffmpeg_user_ms = ffmpeg_usage.map(|u| u.user.as_millis() as u64),
+        ffmpeg_system_ms = ffmpeg_usage.map(|u| u.system.as_millis() as u64),
+        ffmpeg_max_rss_kb = ffmpeg_usage.map(|u| u.max_rss_bytes / 1024),
+        gifski_user_ms = gifski_usage.map(|u| u.user.as_millis() as u64),
+        gifski_system_ms = gifski_usage.map(|u| u.system.as_millis() as u64),
+        gifski_max_rss_kb = gifski_usage.map(|u| u.max_rss_bytes / 1024),
+        ffmpeg_ms = stages.ffmpeg.map(|took| took.as_millis() as u64),
+        gifski_ms = stages.gifski.map(|took| took.as_millis() as u64),
+        first_byte_ms = stages.first_byte.map(|took| took.as_millis() as u64),
+        pipe_bytes_per_sec = stages.pipe_bytes_per_sec(),
+        "Successfully generated GIF with {} bytes",
+        gif_data.len()
+    );
+    Ok(gif_data)
+}
+
+// What comes out of the encoder, counted on its way if anyone's watching
+fn counting_output(stream: Encoded, options: &ConversionOptions) -> Encoded {
+    match &options.progress {
+        Some(progress) => Box::new(ProgressReader::bytes(stream, progress.clone())),
+        None => stream,
+    }
+}
+
+// Decode with libav and encode with the gifski library, all inside this process.
+// Nothing here can be killed, so on the way out the decoder is told to stop and
+// everything else winds down once the pipes between them close.
+#[cfg(feature = "libav")]
+async fn convert_with_libav(
+    video_url: String,
+    source: Option<SourceStream>,
+    config: &PipelineConfig,
+    options: &ConversionOptions,
+    sink: Option<mpsc::Sender<std::io::Result<Bytes>>>,
+    deadline: tokio::time::Instant,
+    cancel: CancellationToken,
+) -> Result<GifOutput> {
+    let timeout = config.timeout;
+    let stop = cancel.child_token();
+    let _stop_on_drop = stop.clone().drop_guard();
+
+    // A download of ours reaches libav through an in-memory pipe, so the input
+    // size limit is enforced exactly as it is for ffmpeg
+    let (input, mut feed_handle) = match source {
+        Some(source) => {
+            let (writer, reader) = tokio::io::duplex(64 * 1024);
+            let feed = feed_source(source, writer, config.max_input_bytes, options.timings.clone(), stop.clone());
+            let feed = tokio::spawn(feed);
+            let reader = tokio_util::io::SyncIoBridge::new(reader);
+            (libav_decoder::Input::Stream(Box::new(reader)), Some(feed))
+        }
+        None => (libav_decoder::Input::Url(video_url), None),
+    };
+
+    let (writer, gif_stream) = tokio::io::duplex(64 * 1024);
+    let mut gif_stream = TimedReader::new(counting_output(Box::new(gif_stream), options), options.timings.clone());
+    let settings = options.gif_settings(config);
+    let (start, trim) = (options.start, options.trim);
+    let decoder_stop = stop.clone();
+    let mut encode_handle = tokio::spawn(native_encoder::encode_frames(
+        move |collector| {
+            libav_decoder::decode_frames(input, collector, &settings, start, trim, decoder_stop)
+        },
+        writer,
+        settings,
+    ));
+
+    let max_output_bytes = config.max_output_bytes;
+    let (spill, temp) = (config.spill.clone(), config.temp.clone());
+    let collect_stop = stop.clone();
+    let mut collect_handle = tokio::spawn(async move {
+        collect_output(&mut gif_stream, max_output_bytes, spill.as_ref(), &temp, sink.as_ref(), &collect_stop).await
+    });
+
+    let mut tasks = vec![encode_handle.abort_handle(), collect_handle.abort_handle()];
+    tasks.extend(feed_handle.as_ref().map(|handle| handle.abort_handle()));
+
+    let work = async {
+        if let Some(feed_handle) = &mut feed_handle {
+            feed_handle.await.map_err(task_failed("feed"))??;
+        }
+        let gif_data = (&mut collect_handle).await.map_err(task_failed("collect"))??;
+        // A decoder or encoder that failed still closes the pipe, so a complete
+        // looking GIF only counts once they've both said they're done
+        (&mut encode_handle).await.map_err(task_failed("encode"))??;
+        Ok(gif_data)
+    };
+
+    let outcome = tokio::select! {
+        result = work => Outcome::Finished(result),
+        _ = tokio::time::sleep_until(deadline) => Outcome::TimedOut,
+        _ = cancel.cancelled() => Outcome::Cancelled,
+    };
+    let result = match outcome {
+        Outcome::Finished(result) => result,
+        Outcome::TimedOut => {
+            warn!("Conversion timed out after {:?}, stopping libav", timeout);
+            Err(ConversionError::TimedOut(timeout))
+        }
+        Outcome::Cancelled => {
+            info!(outcome = "client_abort", "Client went away, stopping libav");
+            Err(ConversionError::Cancelled)
+        }
+    };
+    if result.is_err() {
+        stop.cancel();
+        for task in &tasks {
+            task.abort();
+        }
+    }
+    if let Ok(gif_data) = &result {
+        info!("Successfully generated GIF with {} bytes", gif_data.len());
+    }
+    result
+}
+
+#[cfg(not(feature = "libav"))]
+async fn convert_with_libav(
+    _video_url: String,
+    _source: Option<SourceStream>,
+    _config: &PipelineConfig,
+    _options: &ConversionOptions,
+    _sink: Option<mpsc::Sender<std::io::Result<Bytes>>>,
+    _deadline: tokio::time::Instant,
+    _cancel: CancellationToken,
+) -> Result<GifOutput> {
+    Err(ConversionError::internal("This build doesn't include the libav decoder"))
+}
+
+/// How long the health check's test conversion gets.
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Convert a tiny clip the whole way through ffmpeg and the encoder, to check
+/// that they actually work here. The clip is made up on the spot: a few frames
+/// of uncompressed video, so any ffmpeg can read it.
+pub async fn self_test(config: &PipelineConfig) -> Result<()> {
+    if config.decoder == Decoder::Libav {
+        return Err(ConversionError::internal("The self test needs the ffmpeg decoder"));
+    }
+    let options = ConversionOptions::default();
+    let mut args = vec!["-f".to_string(), "yuv4mpegpipe".to_string()];
+    args.extend(ffmpeg_args("pipe:0", config, &options));
+    let mut ffmpeg_process = ProcessGuard::spawn("ffmpeg", config.binaries.ffmpeg.command()
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null()), &config.child_limits)
+        .map_err(|source| ConversionError::Spawn { binary: "ffmpeg", source })?;
+    let mut ffmpeg_stdin = ffmpeg_process.take_stdin()
+        .ok_or_else(|| ConversionError::internal("Failed to take ffmpeg stdin"))?;
+    let ffmpeg_stdout = ffmpeg_process.take_stdout()
+        .ok_or_else(|| ConversionError::internal("Failed to take ffmpeg stdout"))?;
+    let stop = CancellationToken::new();
+    let _stop_on_return = stop.clone().drop_guard();
+    let (mut encoder, mut gif_stream) = config.encoder.start(ffmpeg_stdout, config, &options, &stop)?;
+
+    let work = async {
+        let feed = async move {
+            // ffmpeg hanging up early shows up in its exit status
+            let _ = ffmpeg_stdin.write_all(&test_clip()).await;
+        };
+        let (_, gif) = tokio::join!(feed, collect_output(&mut gif_stream, Some(1024 * 1024), None, &config.temp, None, &stop));
+        let gif = gif?.into_bytes().await?;
+        let ffmpeg_status = ffmpeg_process.wait().await
+            .map_err(ConversionError::pipe("Failed to wait for ffmpeg process"))?;
+        let encoded = encoder.finish().await;
+        check_exit_statuses(&ffmpeg_status, None, encoded, config.metrics.as_ref())?;
+        if !gif.starts_with(b"GIF8") {
+            return Err(ConversionError::internal("The encoder's output isn't a GIF"));
+        }
+        Ok(())
+    };
+    match tokio::time::timeout(SELF_TEST_TIMEOUT, work).await {
+        Ok(result) => result,
+        Err(_) => {
+            abort_conversion(&stop, &encoder.tasks(), &mut ffmpeg_process, &mut encoder).await;
+            Err(ConversionError::internal(format!("The self test timed out after {:?}", SELF_TEST_TIMEOUT)))
+        }
+    }
+}
+
+// Five 16x16 frames fading from black to white, as yuv4mpegpipe
+fn test_clip() -> Vec<u8> {
+    let mut clip = b"YUV4MPEG2 W16 H16 F10:1 Ip A1:1 C420jpeg\n".to_vec();
+    for frame in 0..5u8 {
+        clip.extend_from_slice(b"FRAME\n");
+        clip.extend(std::iter::repeat_n(frame * 60, 16 * 16));
+        clip.extend(std::iter::repeat_n(128, 2 * 8 * 8));
+    }
+    clip
+}
+
+// Work out which side is to blame when the conversion fails. They share a pipe,
+// so one failing usually takes the other with it: an encoder that died leaves
+// ffmpeg with a broken pipe, and a failed ffmpeg leaves the encoder with too few
+// frames. Whichever is to blame is counted as a failed subprocess. The line of
+// ffmpeg's stderr that said what went wrong, if any, is `stderr_cause`.
+fn check_exit_statuses(
+    ffmpeg: &ExitStatus,
+    stderr_cause: Option<&str>,
+    encoded: Result<()>,
+    metrics: &dyn ConversionMetrics,
+) -> Result<()> {
+    if killed_by_limit(ffmpeg) {
+        metrics.subprocess_failed("ffmpeg", "resource_limit");
+        return Err(ConversionError::ResourceLimit("ffmpeg"));
+    }
+    // A broken pipe is only ffmpeg's fault if the encoder finished fine. ffmpeg
+    // either dies of the SIGPIPE or says so and exits.
+    let stderr_class = stderr_cause.and_then(ErrorClass::from_ffmpeg_line);
+    let broken_pipe = killed_by_broken_pipe(ffmpeg) || stderr_class == Some(ErrorClass::PipeBroken);
+    let encoder_to_blame = broken_pipe && encoded.is_err();
+    if !ffmpeg.success() && !encoder_to_blame {
+        metrics.subprocess_failed("ffmpeg", "failed");
+        let class = stderr_class.unwrap_or(if killed_by_broken_pipe(ffmpeg) {
+            ErrorClass::PipeBroken
+        } else {
+            ErrorClass::FfmpegDecodeError
+        });
+        if let Some(reason) = stderr_cause.and_then(unsupported_source).filter(|_| class == ErrorClass::UnsupportedSource) {
+            return Err(ConversionError::UnsupportedSource(reason));
+        }
+        return Err(ConversionError::Ffmpeg { code: ffmpeg.code(), class });
+    }
+    blame_encoder(encoded, metrics)
+}
+
+// How the encoder finished, counted as gifski failing if it did
+fn blame_encoder(encoded: Result<()>, metrics: &dyn ConversionMetrics) -> Result<()> {
+    match &encoded {
+        Err(ConversionError::Encode { status: Some(_), .. }) => metrics.subprocess_failed("gifski", "failed"),
+        Err(ConversionError::ResourceLimit("gifski")) => metrics.subprocess_failed("gifski", "resource_limit"),
+        _ => {}
+    }
+    encoded
+}
+
+/// Whatever is turning ffmpeg's frames into a GIF for one conversion.
+pub enum RunningEncoder {
+    Subprocess {
+        process: Box<ProcessGuard>,
+        stderr: JoinHandle<()>,
+        /// Passing ffmpeg's frames on to gifski, when they're being counted
+        feed: Option<JoinHandle<()>>,
+    },
+    /// Encoding inside this process, like the native encoder
+    Task(JoinHandle<Result<()>>),
+    /// ffmpeg is writing the output itself, so there's nothing else to wait for
+    Ffmpeg,
+}
+
+/// The built-in encoders, each making GIFs.
+impl Backend for Encoder {
+    fn name(&self) -> &'static str {
+        self.header_value()
+    }
+
+    fn format(&self) -> Format {
+        Format::Gif
+    }
+
+    fn ffmpeg_output(&self, settings: &GifSettings) -> Vec<String> {
+        match self {
+            Encoder::Ffmpeg => settings.ffmpeg_gif_args(),
+            // Both gifskis read PNGs as well as y4m, the binary once they're files
+            _ if settings.transparent() => backend::png_output(settings),
+            _ => backend::y4m_output(settings, self.ffmpeg_pix_fmt()),
+        }
+    }
+
+    fn start(
+        &self,
+        ffmpeg_stdout: ChildStdout,
+        config: &PipelineConfig,
+        options: &ConversionOptions,
+        stop: &CancellationToken,
+    ) -> Result<(RunningEncoder, Encoded)> {
+        start_encoder(*self, ffmpeg_stdout, config, options, stop)
+    }
+}
+
+// Start `encoder` on ffmpeg's output, returning it along with the stream the
+// GIF comes out of.
+fn start_encoder(
+    encoder: Encoder,
+    ffmpeg_stdout: ChildStdout,
+    config: &PipelineConfig,
+    options: &ConversionOptions,
+    stop: &CancellationToken,
+) -> Result<(RunningEncoder, Encoded)> {
+    let spawned = Instant::now();
+    let settings = options.gif_settings(config);
+    let timings = options.timings.clone();
+    timings.gifski_started();
+    match encoder {
+        // The gifski binary only reads PNGs as files, so a transparent video's
+        // frames are written out as ffmpeg makes them, and gifski is run on
+        // them once it's done
+        Encoder::Subprocess if settings.transparent() => {
+            let frames: Box<dyn AsyncRead + Unpin + Send> = match &options.progress {
+                Some(progress) => Box::new(ProgressReader::frames(ffmpeg_stdout, progress.clone())),
+                None => Box::new(ffmpeg_stdout),
+            };
+            let mut command = config.binaries.gifski.command();
+            let mut args = settings.gifski_args(&config.gifski_flags);
+            // The `-` for stdin, since the frames are given as files instead
+            args.pop();
+            command.args(args);
+            let (writer, reader) = tokio::io::duplex(64 * 1024);
+            let encode = encode_png_files(frames, command, writer, config.child_limits, config.temp.clone(), options.stderr_tail.clone(), stop.clone());
+            let encode = async move {
+                let result = encode.await;
+                timings.gifski_exited(spawned);
+                result
+            };
+            let handle = tokio::spawn(encode.instrument(info_span!("gifski")));
+            Ok((RunningEncoder::Task(handle), Box::new(reader)))
+        }
+        Encoder::Subprocess => {
+            // gifski reads ffmpeg's stdout pipe directly, so decoded frames never pass
+            // through us. Our end of the pipe goes away with the command once gifski is
+            // spawned, which leaves gifski the only reader. Only when someone's watching
+            // the conversion do the frames come through us instead, to be counted.
+            let (gifski_stdin, frames) = match &options.progress {
+                Some(progress) => (Stdio::piped(), Some(ProgressReader::frames(ffmpeg_stdout, progress.clone()))),
+                None => {
+                    let ffmpeg_stdout: Stdio = ffmpeg_stdout
+                        .try_into()
+                        .map_err(ConversionError::pipe("Failed to hand ffmpeg stdout to gifski"))?;
+                    (ffmpeg_stdout, None)
+                }
+            };
+
+            // Set up gifski process to read yuv4mpegpipe frames from stdin and output to stdout
+            let mut process = ProcessGuard::spawn("gifski", config.binaries.gifski.command()
+                .args(settings.gifski_args(&config.gifski_flags))
+                .stdin(gifski_stdin)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()), &config.child_limits)
+                .map_err(|source| ConversionError::Spawn { binary: "gifski", source })?;
+            let stdout = process.take_stdout()
+                .ok_or_else(|| ConversionError::internal("Failed to take gifski stdout"))?;
+            let gifski_stderr = process.take_stderr()
+                .ok_or_else(|| ConversionError::internal("Failed to take gifski stderr"))?;
+            let feed = match frames {
+                Some(mut frames) => {
+                    let mut gifski_stdin = process.take_stdin()
+                        .ok_or_else(|| ConversionError::internal("Failed to take gifski stdin"))?;
+                    let stop = stop.clone();
+                    // gifski going away shows up in its exit status, and ffmpeg's
+                    Some(tokio::spawn(async move {
+                        tokio::select! {
+                            fed = tokio::io::copy(&mut frames, &mut gifski_stdin) => {
+                                if let Err(e) = fed {
+                                    info!("Stopped feeding gifski: {}", e);
+                                }
+                            }
+                            _ = stop.cancelled() => {}
+                        }
+                    }.instrument(info_span!("gifski_feed"))))
+                }
+                None => None,
+            };
+
+            // Task to log gifski stderr, whose span stands in for gifski's
+            let stderr_tail = options.stderr_tail.clone();
+            let stop = stop.clone();
+            let stderr = tokio::spawn(async move {
+                let mut reader = tokio::io::BufReader::new(gifski_stderr);
+                let mut line = String::new();
+                info!("Monitoring gifski stderr...");
+                while read_stderr_line(&mut reader, &mut line, &stop).await {
+                    info!(source = "gifski", line = line.as_str(), "stderr");
+                    stderr_tail.push("gifski", &line);
+                }
+                timings.gifski_exited(spawned);
+                info!("gifski stderr stream finished.");
+            }.instrument(info_span!("gifski")));
+            Ok((RunningEncoder::Subprocess { process: Box::new(process), stderr, feed }, Box::new(stdout)))
+        }
+        #[cfg(feature = "native-encoder")]
+        Encoder::Native => {
+            let (writer, reader) = tokio::io::duplex(64 * 1024);
+            let frames: Box<dyn AsyncRead + Unpin + Send> = match &options.progress {
+                Some(progress) => Box::new(ProgressReader::frames(ffmpeg_stdout, progress.clone())),
+                None => Box::new(ffmpeg_stdout),
+            };
+            let encode = native_encoder::encode(frames, writer, settings);
+            let encode = async move {
+                let result = encode.await;
+                timings.gifski_exited(spawned);
+                result
+            };
+            let handle = tokio::spawn(encode.instrument(info_span!("gifski", encoder = "native")));
+            Ok((RunningEncoder::Task(handle), Box::new(reader)))
+        }
+        #[cfg(not(feature = "native-encoder"))]
+        Encoder::Native => Err(ConversionError::internal("This build doesn't include the native encoder")),
+        Encoder::Ffmpeg => Ok((RunningEncoder::Ffmpeg, Box::new(ffmpeg_stdout))),
+    }
+}
+
+// What's left of the command line for everything besides the frames' names:
+// gifski's own arguments and our environment
+const ARGS_HEADROOM: usize = 256 * 1024;
+
+// How much of gifski's command line its frames' names can take up, counting
+// each one's terminator and pointer: what `ARG_MAX` leaves, or inside Windows'
+// 32767 characters
+fn frame_args_max() -> usize {
+    #[cfg(unix)]
+    {
+        // It goes with the stack's limit on Linux, so it's asked for rather than assumed
+        let max = unsafe { libc::sysconf(libc::_SC_ARG_MAX) };
+        usize::try_from(max).unwrap_or(1024 * 1024).saturating_sub(ARGS_HEADROOM)
+    }
+    #[cfg(not(unix))]
+    {
+        30_000
+    }
+}
+
+// Write each of the PNGs ffmpeg sends on `frames` to a temp file, then run
+// gifski (`command`, all but its inputs) on them, passing on the GIF it writes
+// to `output`. gifski runs in the temp directory and is given the frames' file
+// names rather than their paths, which keeps a long video's command line short;
+// one with more frames than fit in `frame_args_max` fails. Dropping the task
+// kills gifski and removes the frames.
+async fn encode_png_files(
+    mut frames: impl AsyncRead + Unpin,
+    mut command: tokio::process::Command,
+    mut output: impl AsyncWrite + Unpin,
+    limits: ChildLimits,
+    temp: Arc<TempManager>,
+    stderr_tail: StderrTail,
+    stop: CancellationToken,
+) -> Result<()> {
+    let (mut files, mut names, mut args_len) = (Vec::new(), Vec::new(), 0);
+    let args_max = frame_args_max();
+    while let Some(png) = backend::read_png(&mut frames).await
+        .map_err(ConversionError::pipe(format!("Failed to read frame {} from ffmpeg", files.len())))?
+    {
+        let (file, mut handle) = temp.create("frame.png").await?;
+        let name = file.path().file_name().unwrap_or_default().to_os_string();
+        args_len += name.len() + 1 + std::mem::size_of::<usize>();
+        if args_len > args_max {
+            return Err(ConversionError::Encode {
+                status: None,
+                message: format!("More than {} frames are too many to hand gifski at once", files.len()),
+            });
+        }
+        names.push(name);
+        file.grew(png.len())?;
+        handle.write_all(&png).await.map_err(ConversionError::pipe("Failed to write a frame to its temp file"))?;
+        handle.flush().await.map_err(ConversionError::pipe("Failed to write a frame to its temp file"))?;
+        files.push(file);
+    }
+    info!("Wrote {} frames for gifski", files.len());
+
+    command.args(&names)
+        .current_dir(temp.dir())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut process = ProcessGuard::spawn("gifski", &mut command, &limits)
+        .map_err(|source| ConversionError::Spawn { binary: "gifski", source })?;
+    let mut stdout = process.take_stdout()
+        .ok_or_else(|| ConversionError::internal("Failed to take gifski stdout"))?;
+    let stderr = process.take_stderr()
+        .ok_or_else(|| ConversionError::internal("Failed to take gifski stderr"))?;
+    let log = async {
+        let mut reader = tokio::io::BufReader::new(stderr);
+        let mut line = String::new();
+        while read_stderr_line(&mut reader, &mut line, &stop).await {
+            info!(source = "gifski", line = line.as_str(), "stderr");
+            stderr_tail.push("gifski", &line);
+        }
+    };
+    let (passed_on, ()) = tokio::join!(tokio::io::copy(&mut stdout, &mut output), log);
+    let status = process.wait().await
+        .map_err(ConversionError::pipe("Failed to wait for gifski process"))?;
+    info!("gifski process exited with status: {}", status);
+    if killed_by_limit(&status) {
+        return Err(ConversionError::ResourceLimit("gifski"));
+    }
+    if !status.success() {
+        return Err(ConversionError::encoder_crashed(status));
+    }
+    passed_on.map_err(ConversionError::pipe("Failed to pass on gifski's GIF"))?;
+    output.shutdown().await.map_err(ConversionError::pipe("Failed to pass on gifski's GIF"))
+}
+
+impl RunningEncoder {
+    fn tasks(&self) -> Vec<AbortHandle> {
+        match self {
+            RunningEncoder::Subprocess { stderr, feed, .. } => {
+                std::iter::once(stderr).chain(feed).map(JoinHandle::abort_handle).collect()
+            }
+            RunningEncoder::Task(handle) => vec![handle.abort_handle()],
+            RunningEncoder::Ffmpeg => Vec::new(),
+        }
+    }
+
+    // What the gifski binary cost, once it's exited
+    fn usage(&self) -> Option<ResourceUsage> {
+        match self {
+            RunningEncoder::Subprocess { process, .. } => process.usage(),
+            _ => None,
+        }
+    }
+
+    // Wait for the encoder to be done, and whether it succeeded
+    async fn finish(&mut self) -> Result<()> {
+        match self {
+            RunningEncoder::Subprocess { process, stderr, feed } => {
+                let status = process.wait().await
+                    .map_err(ConversionError::pipe("Failed to wait for gifski process"))?;
+                info!("gifski process exited with status: {}", status);
+                if let Some(feed) = feed {
+                    feed.await.map_err(task_failed("gifski feed"))?;
+                }
+                stderr.await.map_err(task_failed("gifski stderr"))?;
+                if killed_by_limit(&status) {
+                    return Err(ConversionError::ResourceLimit("gifski"));
+                }
+                if !status.success() {
+                    return Err(ConversionError::encoder_crashed(status));
+                }
+                Ok(())
+            }
+            RunningEncoder::Task(handle) => handle.await.map_err(task_failed("encoder"))?,
+            RunningEncoder::Ffmpeg => Ok(()),
+        }
+    }
+
+    async fn terminate(&mut self) {
+        match self {
+            RunningEncoder::Subprocess { process, .. } => process.terminate(TERMINATE_GRACE).await,
+            // Its threads can't be interrupted, but they wind down on their own
+            // once ffmpeg is gone and nobody is reading the GIF anymore
+            RunningEncoder::Task(_) => {}
+            RunningEncoder::Ffmpeg => {}
+        }
+    }
+}
+
+/// A finished GIF, either in memory or (past the spill threshold) in a temp file.
+#[derive(Debug)]
+pub enum GifOutput {
+    Memory(Bytes),
+    Spilled {
+        file: TempFile,
+        handle: tokio::fs::File,
+        len: u64,
+    },
+    /// Already sent on to a streaming response
+    Streamed { len: u64, ends: Ends },
+}
+
+impl GifOutput {
+    pub fn len(&self) -> u64 {
+        match self {
+            GifOutput::Memory(bytes) => bytes.len() as u64,
+            GifOutput::Spilled { len, .. } => *len,
+            GifOutput::Streamed { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The GIF's bytes, read back into memory if it was spilled.
+    pub async fn into_bytes(self) -> Result<Bytes> {
+        match self {
+            GifOutput::Memory(bytes) => Ok(bytes),
+            GifOutput::Spilled { mut handle, len, .. } => {
+                let mut bytes = Vec::with_capacity(len as usize);
+                handle.read_to_end(&mut bytes).await.map_err(ConversionError::pipe("Failed to read the spilled GIF"))?;
+                Ok(bytes.into())
+            }
+            GifOutput::Streamed { .. } => Err(ConversionError::internal("A streamed GIF has already been sent")),
+        }
+    }
+}
+
+/// A response body fed chunk by chunk while the GIF is still being encoded.
+/// Dropping it before the end (the client went away) cancels the conversion.
+pub struct StreamedBody {
+    first_chunk: Option<Bytes>,
+    chunks: mpsc::Receiver<std::io::Result<Bytes>>,
+    _cancel_on_drop: DropGuard,
+}
+
+impl StreamedBody {
+    pub fn new(
+        first_chunk: Bytes,
+        chunks: mpsc::Receiver<std::io::Result<Bytes>>,
+        cancel_on_drop: DropGuard,
+    ) -> Self {
+        Self {
+            first_chunk: Some(first_chunk),
+            chunks,
+            _cancel_on_drop: cancel_on_drop,
+        }
+    }
+}
+
+impl Stream for StreamedBody {
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(chunk) = self.first_chunk.take() {
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        self.chunks.poll_recv(cx)
+    }
+}
+
+// Read gifski's output, moving it from memory to a temp file if it gets big
+// and giving up entirely if it goes over `max_output_bytes`. With a `sink`,
+// the output is passed straight on instead of being kept at all.
+pub async fn collect_output(
+    stdout: &mut (impl AsyncRead + Unpin),
+    max_output_bytes: Option<u64>,
+    spill: Option<&SpillConfig>,
+    temp: &Arc<TempManager>,
+    sink: Option<&mpsc::Sender<std::io::Result<Bytes>>>,
+    stop: &CancellationToken,
+) -> Result<GifOutput> {
+    let mut buffer = Vec::new();
+    let mut spilled: Option<(TempFile, tokio::fs::File)> = None;
+    let mut total = 0u64;
+    let mut ends = Ends::default();
+    let mut chunk = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = tokio::select! {
+            read = stdout.read(&mut chunk) => read.map_err(ConversionError::pipe("Failed to read gifski output"))?,
+            _ = stop.cancelled() => return Err(ConversionError::Cancelled),
+        };
+        if read == 0 {
+            break;
+        }
+        total += read as u64;
+        if let Some(max) = max_output_bytes.filter(|max| total > *max) {
+            // Any temp file goes away with `spilled`
+            warn!(outcome = "output_too_large", "GIF output exceeded {} bytes, giving up", max);
+            return Err(ConversionError::OutputTooLarge(max));
+        }
+
+        if let Some(sink) = sink {
+            ends.feed(&chunk[..read]);
+            // The receiving end goes away when the client does
+            let sent = tokio::select! {
+                sent = sink.send(Ok(Bytes::copy_from_slice(&chunk[..read]))) => sent.is_ok(),
+                _ = stop.cancelled() => false,
+            };
+            if !sent {
+                return Err(ConversionError::Cancelled);
+            }
+            continue;
+        }
+
+        match &mut spilled {
+            Some((file, handle)) => {
+                file.grew(read)?;
+                handle.write_all(&chunk[..read]).await.map_err(ConversionError::pipe("Failed to spill GIF output"))?
+            }
+            None => {
+                buffer.extend_from_slice(&chunk[..read]);
+                if let Some(spill) = spill.filter(|spill| buffer.len() as u64 > spill.threshold) {
+                    let (file, mut handle) = temp.create("output.gif").await?;
+                    info!("GIF output passed {} bytes, spilling to {}", spill.threshold, file.path().display());
+                    file.grew(buffer.len())?;
+                    handle.write_all(&buffer).await.map_err(ConversionError::pipe("Failed to spill GIF output"))?;
+                    buffer = Vec::new();
+                    spilled = Some((file, handle));
+                }
+            }
+        }
+    }
+
+    if sink.is_some() {
+        return Ok(GifOutput::Streamed { len: total, ends });
+    }
+    match spilled {
+        Some((file, mut handle)) => {
+            handle.flush().await.map_err(ConversionError::pipe("Failed to spill GIF output"))?;
+            handle.seek(std::io::SeekFrom::Start(0)).await.map_err(ConversionError::pipe("Failed to rewind the spilled GIF"))?;
+            Ok(GifOutput::Spilled { file, handle, len: total })
+        }
+        None => Ok(GifOutput::Memory(Bytes::from(buffer))),
+    }
+}
+
+// Tear down a conversion that isn't going to finish
+async fn abort_conversion(
+    stop: &CancellationToken,
+    tasks: &[AbortHandle],
+    ffmpeg: &mut ProcessGuard,
+    encoder: &mut RunningEncoder,
+) {
+    // Stop the tasks first so nothing keeps feeding or draining the pipes,
+    // and whatever partial GIF was collected gets thrown away with them.
+    // They stop on the token by themselves; aborting them is for whatever
+    // can't, like an encoder task.
+    stop.cancel();
+    for task in tasks {
+        task.abort();
+    }
+    // Both process groups are signalled together, then reaped so nothing is
+    // left behind as a zombie
+    tokio::join!(
+        ffmpeg.terminate(TERMINATE_GRACE),
+        encoder.terminate(),
+    );
+}
+
+// Read the next line of a child's stderr into `line`, returning false at the
+// end of it, or once the conversion is stopping
+async fn read_stderr_line(
+    reader: &mut (impl tokio::io::AsyncBufRead + Unpin),
+    line: &mut String,
+    stop: &CancellationToken,
+) -> bool {
+    tokio::select! {
+        more = stderr_tail::read_line(reader, line) => more.unwrap_or(false),
+        _ = stop.cancelled() => false,
+    }
+}
+
+// For `map_err` on one of the conversion's tasks, which only fails to be
+// joined if it panicked or was aborted
+fn task_failed(task: &'static str) -> impl FnOnce(tokio::task::JoinError) -> ConversionError {
+    move |e| ConversionError::Internal(format!("The {} task failed: {}", task, e))
+}
+
+/// The video to feed ffmpeg, when we fetch it rather than ffmpeg. Size limits
+/// are checked before anything gets decoded: when we fetch the video
+/// ourselves the fetcher can tell, and otherwise we have to ask.
+pub async fn fetch_source(source: &SourceRef, config: &PipelineConfig) -> Result<Option<SourceStream>> {
+    match config.fetch_mode {
+        FetchMode::InProcess => Ok(Some(config.source_fetcher.fetch(source).await?)),
+        FetchMode::Ffmpeg => {
+            if let Some(max) = config.max_input_bytes {
+                config.fetcher.check_size(source, max).await?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Stream the source into ffmpeg, counting bytes against the input limit.
+pub async fn feed_source(
+    mut source: SourceStream,
+    mut ffmpeg_stdin: impl AsyncWrite + Unpin,
+    max_bytes: Option<u64>,
+    timings: StageTimes,
+    stop: CancellationToken,
+) -> Result<()> {
+    let mut fed = 0u64;
+    loop {
+        let chunk = tokio::select! {
+            chunk = source.next() => chunk,
+            _ = stop.cancelled() => return Err(ConversionError::Cancelled),
+        };
+        let Some(chunk) = chunk.transpose()? else { break };
+        fed += chunk.len() as u64;
+        timings.downloaded(chunk.len());
+        if let Some(max) = max_bytes.filter(|max| fed > *max) {
+            warn!(outcome = "input_too_large", "Download exceeded {} bytes, giving up", max);
+            return Err(ConversionError::InputTooLarge(max));
+        }
+        let written = tokio::select! {
+            written = ffmpeg_stdin.write_all(&chunk) => written,
+            _ = stop.cancelled() => return Err(ConversionError::Cancelled),
+        };
+        match written {
+            Ok(()) => timings.piped(chunk.len()),
+            // ffmpeg stopped reading, e.g. because it's trimming the video. That's
+            // its call to make, and its exit status will say if something went wrong.
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                info!("ffmpeg stopped reading after {} bytes", fed);
+                return Ok(());
+            }
+            Err(source) => return Err(ConversionError::Pipe { context: "Failed to feed video to ffmpeg".into(), source }),
+        }
+    }
+    info!("Fed {} bytes of video to ffmpeg", fed);
+    Ok(())
+}
@@ -0,0 +1,573 @@
+use crate::error::StartupError;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
+use tracing::{error, warn};
+
+/// How long a child gets to exit after SIGTERM before we SIGKILL it.
+pub const TERMINATE_GRACE: Duration = Duration::from_secs(2);
+
+/// A child process running as the leader of its own process group, or on
+/// Windows in a Job Object of its own.
+///
+/// Unless the child has been waited on, dropping the guard SIGKILLs the whole
+/// group, so every way out of a conversion (errors, `?`, timeouts, cancelled
+/// futures) takes the child and anything it spawned down with it.
+pub struct ProcessGuard {
+    name: &'static str,
+    child: Child,
+    pgid: Option<i32>,
+    #[cfg(windows)]
+    job: Option<job::Job>,
+    reaped: bool,
+    usage: Option<ResourceUsage>,
+}
+
+/// What an exited child cost: CPU time and peak memory.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceUsage {
+    pub user: Duration,
+    pub system: Duration,
+    pub max_rss_bytes: u64,
+}
+
+impl ProcessGuard {
+    pub fn spawn(name: &'static str, command: &mut Command, limits: &ChildLimits) -> io::Result<Self> {
+        #[cfg(unix)]
+        command.process_group(0);
+        #[cfg(windows)]
+        command.creation_flags(windows_sys::Win32::System::Threading::CREATE_NO_WINDOW);
+        limits.apply(command);
+        let child = command.spawn()?;
+        let pgid = child.id().map(|pid| pid as i32);
+        // Anything the child starts before it's in the job is left out of it,
+        // but none of the programs we run start anything that early
+        #[cfg(windows)]
+        let job = child.raw_handle().and_then(|handle| {
+            job::Job::with(handle)
+                .map_err(|e| warn!("Couldn't put {} in a job object, so only it will be killed: {}", name, e))
+                .ok()
+        });
+        Ok(Self {
+            name,
+            child,
+            pgid,
+            #[cfg(windows)]
+            job,
+            reaped: false,
+            usage: None,
+        })
+    }
+
+    pub fn take_stdin(&mut self) -> Option<ChildStdin> {
+        self.child.stdin.take()
+    }
+
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.child.stdout.take()
+    }
+
+    pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+        self.child.stderr.take()
+    }
+
+    pub async fn wait(&mut self) -> io::Result<ExitStatus> {
+        #[cfg(target_os = "linux")]
+        if let Some(pid) = self.pgid.filter(|_| self.usage.is_none()) {
+            self.usage = wait_for_exit(pid).await.ok().flatten();
+        }
+        let status = self.child.wait().await?;
+        self.reaped = true;
+        Ok(status)
+    }
+
+    // Whether the leader exits within `grace`. On Linux it's left a zombie
+    // until it's reaped, which keeps its pid, and so the group's ID, ours.
+    async fn exits_within(&mut self, grace: Duration) -> bool {
+        #[cfg(target_os = "linux")]
+        if let Some(pid) = self.pgid {
+            match tokio::time::timeout(grace, wait_for_exit(pid)).await {
+                Ok(Ok(usage)) => {
+                    self.usage = self.usage.or(usage);
+                    return true;
+                }
+                // Without a pidfd, it's waited for the way it is elsewhere
+                Ok(Err(_)) => {}
+                Err(_) => return false,
+            }
+        }
+        tokio::time::timeout(grace, self.child.wait()).await.is_ok()
+    }
+
+    /// What the child cost, once it's been waited on. Only known on Linux.
+    pub fn usage(&self) -> Option<ResourceUsage> {
+        self.usage
+    }
+
+    /// Ask the process group to exit with SIGTERM, escalating to SIGKILL if the
+    /// leader is still around after `grace`, then reap the leader.
+    pub async fn terminate(&mut self, grace: Duration) {
+        if self.reaped {
+            return;
+        }
+        self.signal_group(Signal::Term);
+        if !self.exits_within(grace).await {
+            warn!("{} didn't exit within {:?} of SIGTERM, sending SIGKILL", self.name, grace);
+        }
+        // Whether or not the leader went quietly, anything left in the group is
+        // a straggler that ignored SIGTERM. The leader's still unreaped, so the
+        // group's ID can't have been handed on to another process yet.
+        self.signal_group(Signal::Kill);
+        if let Err(e) = self.child.wait().await {
+            error!("Failed to reap {} process: {}", self.name, e);
+        }
+        self.reaped = true;
+    }
+
+    #[cfg(unix)]
+    fn signal_group(&mut self, signal: Signal) {
+        let Some(pgid) = self.pgid else { return };
+        let signal = match signal {
+            Signal::Term => libc::SIGTERM,
+            Signal::Kill => libc::SIGKILL,
+        };
+        // SAFETY: killpg has no memory safety requirements. ESRCH (the group is
+        // already gone) is expected and harmless.
+        unsafe {
+            libc::killpg(pgid, signal);
+        }
+    }
+
+    // There's no SIGTERM for a child without a console to catch, so both
+    // kill the whole job, or just the child if it isn't in one
+    #[cfg(windows)]
+    fn signal_group(&mut self, _signal: Signal) {
+        let _ = self.pgid;
+        match &self.job {
+            Some(job) => job.terminate(),
+            None => {
+                if let Err(e) = self.child.start_kill() {
+                    warn!("Failed to kill {} process: {}", self.name, e);
+                }
+            }
+        }
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn signal_group(&mut self, _signal: Signal) {
+        let _ = self.pgid;
+        if let Err(e) = self.child.start_kill() {
+            warn!("Failed to kill {} process: {}", self.name, e);
+        }
+    }
+}
+
+impl Drop for ProcessGuard {
+    fn drop(&mut self) {
+        if !self.reaped {
+            // Can't wait here; the tokio runtime reaps dropped children in the background
+            self.signal_group(Signal::Kill);
+        }
+    }
+}
+
+// Wait for the child to exit without reaping it, and read its resource usage
+// while it's still around as a zombie. A pidfd turns readable once the child
+// has exited, so the wait is on the reactor rather than a blocking thread. The
+// raw waitid syscall is then the only wait that can read the usage: it reports
+// rusage even with WNOWAIT, which leaves the reaping (and so the exit status)
+// to tokio as usual. Errs without a pidfd to wait on, before Linux 5.3.
+#[cfg(target_os = "linux")]
+async fn wait_for_exit(pid: i32) -> io::Result<Option<ResourceUsage>> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+    use tokio::io::Interest;
+    use tokio::io::unix::AsyncFd;
+
+    // SAFETY: pidfd_open has no memory safety requirements
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor was just opened, and nothing else owns it
+    let pidfd = AsyncFd::with_interest(unsafe { OwnedFd::from_raw_fd(fd as i32) }, Interest::READABLE)?;
+    loop {
+        let mut ready = pidfd.readable().await?;
+        // SAFETY: both are plain C structs that are valid when zeroed, and they
+        // outlive the call that fills them in
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_waitid,
+                libc::P_PID,
+                pid,
+                &mut info as *mut libc::siginfo_t,
+                libc::WEXITED | libc::WNOWAIT | libc::WNOHANG,
+                &mut usage as *mut libc::rusage,
+            )
+        };
+        if result != 0 {
+            return Ok(None);
+        }
+        // SAFETY: waitid filled `info` in, and with WNOHANG leaves si_pid zero
+        // while the child is still running
+        if unsafe { info.si_pid() } == 0 {
+            ready.clear_ready();
+            continue;
+        }
+        let duration = |time: libc::timeval| {
+            Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+        };
+        return Ok(Some(ResourceUsage {
+            user: duration(usage.ru_utime),
+            system: duration(usage.ru_stime),
+            // Linux reports it in kilobytes
+            max_rss_bytes: usage.ru_maxrss as u64 * 1024,
+        }));
+    }
+}
+
+/// How many of each child's most recent conversions the averages cover.
+const USAGE_SAMPLES: usize = 100;
+
+/// Recent resource usage of each kind of child, for the stats endpoint.
+#[derive(Debug, Default)]
+pub struct UsageStats {
+    recent: Mutex<BTreeMap<&'static str, VecDeque<ResourceUsage>>>,
+}
+
+/// Averages over a child's recent conversions.
+#[derive(Debug, Serialize)]
+pub struct AverageUsage {
+    pub samples: usize,
+    pub user_ms: u64,
+    pub system_ms: u64,
+    pub max_rss_bytes: u64,
+}
+
+impl UsageStats {
+    pub fn record(&self, name: &'static str, usage: ResourceUsage) {
+        let mut recent = self.recent.lock().unwrap();
+        let samples = recent.entry(name).or_default();
+        if samples.len() == USAGE_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(usage);
+    }
+
+    pub fn averages(&self) -> BTreeMap<&'static str, AverageUsage> {
+        let recent = self.recent.lock().unwrap();
+        recent
+            .iter()
+            .filter(|(_, samples)| !samples.is_empty())
+            .map(|(name, samples)| {
+                let count = samples.len() as u64;
+                let average = AverageUsage {
+                    samples: samples.len(),
+                    user_ms: samples.iter().map(|u| u.user.as_millis() as u64).sum::<u64>() / count,
+                    system_ms: samples.iter().map(|u| u.system.as_millis() as u64).sum::<u64>() / count,
+                    max_rss_bytes: samples.iter().map(|u| u.max_rss_bytes).sum::<u64>() / count,
+                };
+                (*name, average)
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Signal {
+    Term,
+    Kill,
+}
+
+/// The programs we run, each where `FFMPEG_PATH`, `FFPROBE_PATH` and
+/// `GIFSKI_PATH` say, or else wherever `PATH` had it at startup. gifsicle is
+/// only ever looked for on `PATH`. On Windows they're `ffmpeg.exe` and so on,
+/// which the settings can leave off.
+#[derive(Debug, Clone)]
+pub struct Binaries {
+    pub ffmpeg: Binary,
+    pub ffprobe: Binary,
+    pub gifski: Binary,
+    pub gifsicle: Binary,
+}
+
+impl Binaries {
+    pub fn resolve(ffmpeg: Option<&Path>, ffprobe: Option<&Path>, gifski: Option<&Path>) -> Result<Self, StartupError> {
+        Ok(Self {
+            ffmpeg: Binary::resolve("ffmpeg", ffmpeg, "FFMPEG_PATH")?,
+            ffprobe: Binary::resolve("ffprobe", ffprobe, "FFPROBE_PATH")?,
+            gifski: Binary::resolve("gifski", gifski, "GIFSKI_PATH")?,
+            gifsicle: Binary { name: "gifsicle", path: find_on_path("gifsicle") },
+        })
+    }
+}
+
+/// One of the programs, and where it is, if anywhere.
+#[derive(Debug, Clone)]
+pub struct Binary {
+    pub name: &'static str,
+    pub path: Option<PathBuf>,
+}
+
+impl Binary {
+    fn resolve(name: &'static str, configured: Option<&Path>, setting: &str) -> Result<Self, StartupError> {
+        let Some(path) = configured else {
+            return Ok(Self { name, path: find_on_path(name) });
+        };
+        if !path.is_absolute() {
+            return Err(StartupError::Invalid(format!("{} has to be an absolute path, not {}", setting, path.display())));
+        }
+        let path = executable_path(path);
+        if !is_executable(&path) {
+            return Err(StartupError::Invalid(format!("{}={} isn't an executable file", setting, path.display())));
+        }
+        Ok(Self { name, path: Some(path) })
+    }
+
+    /// Whether it's there, and still is.
+    pub fn is_installed(&self) -> bool {
+        self.path.as_deref().is_some_and(is_executable)
+    }
+
+    /// A command running it. Without a path that's just its name, which
+    /// fails to spawn the way a missing binary always has.
+    pub fn command(&self) -> Command {
+        match &self.path {
+            Some(path) => Command::new(path),
+            None => Command::new(self.name),
+        }
+    }
+
+    /// A blocking command running it with nothing on stdin, for asking it
+    /// about itself, or `None` if it isn't anywhere.
+    pub fn std_command(&self) -> Option<std::process::Command> {
+        let mut command = std::process::Command::new(self.path.as_ref()?);
+        command.stdin(Stdio::null());
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            command.creation_flags(windows_sys::Win32::System::Threading::CREATE_NO_WINDOW);
+        }
+        Some(command)
+    }
+
+    /// The first line it prints about its version when run with `flag`, or
+    /// `None` if it can't be run.
+    pub fn version(&self, flag: &str) -> Option<String> {
+        let output = self.std_command()?.arg(flag).output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        stdout.lines().next().map(|line| line.trim().to_string()).filter(|line| !line.is_empty())
+    }
+}
+
+/// Where `name` is an executable on `PATH`, if it's anywhere.
+fn find_on_path(name: &str) -> Option<PathBuf> {
+    find_executable(name, &std::env::var_os("PATH")?)
+}
+
+/// What the program `name` is called on disk here: `ffmpeg.exe` on Windows.
+pub fn executable_name(name: &str) -> String {
+    format!("{}{}", name, std::env::consts::EXE_SUFFIX)
+}
+
+/// Where `name` is an executable in one of the directories `path` lists, as
+/// `PATH` does, if it's in any of them.
+pub fn find_executable(name: &str, path: &OsStr) -> Option<PathBuf> {
+    std::env::split_paths(path)
+        .map(|dir| dir.join(executable_name(name)))
+        .find(|candidate| is_executable(candidate))
+}
+
+// A configured path with no extension on Windows means the `.exe` there,
+// unless there's a file at that exact path
+#[cfg(windows)]
+fn executable_path(path: &Path) -> PathBuf {
+    if path.extension().is_none() && !path.is_file() {
+        return path.with_extension("exe");
+    }
+    path.to_path_buf()
+}
+
+#[cfg(not(windows))]
+fn executable_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Priority and resource ceilings applied to every child we spawn, so one
+/// huge video can't starve the rest of the machine.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChildLimits {
+    /// Niceness to run at (`CHILD_NICE`)
+    pub nice: Option<i32>,
+    /// Address space cap in bytes (`CHILD_MAX_MEMORY_MB`)
+    pub max_memory_bytes: Option<u64>,
+    /// CPU time cap in seconds (`CHILD_MAX_CPU_SECONDS`)
+    pub max_cpu_seconds: Option<u64>,
+}
+
+impl ChildLimits {
+    pub fn is_empty(&self) -> bool {
+        self.nice.is_none() && self.max_memory_bytes.is_none() && self.max_cpu_seconds.is_none()
+    }
+
+    #[cfg(unix)]
+    fn apply(&self, command: &mut Command) {
+        if self.is_empty() {
+            return;
+        }
+        let limits = *self;
+        // SAFETY: the closure runs between fork and exec, and only makes
+        // async-signal-safe syscalls without allocating
+        unsafe {
+            command.pre_exec(move || limits.apply_to_current_process());
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn apply(&self, _command: &mut Command) {}
+
+    #[cfg(unix)]
+    fn apply_to_current_process(&self) -> io::Result<()> {
+        if let Some(nice) = self.nice {
+            // SAFETY: plain syscall on our own (freshly forked) process
+            check(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) })?;
+        }
+        // SAFETY (both): the rlimit is a valid value for the duration of the call
+        if let Some(bytes) = self.max_memory_bytes {
+            check(unsafe { libc::setrlimit(libc::RLIMIT_AS, &rlimit(bytes, bytes)) })?;
+        }
+        if let Some(seconds) = self.max_cpu_seconds {
+            // SIGXCPU at the soft limit, SIGKILL a second later if it's ignored
+            check(unsafe { libc::setrlimit(libc::RLIMIT_CPU, &rlimit(seconds, seconds + 1)) })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn rlimit(soft: u64, hard: u64) -> libc::rlimit {
+    libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    }
+}
+
+#[cfg(unix)]
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether `status` means the child was killed for going over its limits:
+/// SIGXCPU/SIGKILL from RLIMIT_CPU, or SIGKILL from the kernel's OOM killer.
+#[cfg(unix)]
+pub fn killed_by_limit(status: &ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    matches!(status.signal(), Some(libc::SIGKILL | libc::SIGXCPU))
+}
+
+#[cfg(not(unix))]
+pub fn killed_by_limit(_status: &ExitStatus) -> bool {
+    false
+}
+
+/// Whether `status` means the child was killed by SIGPIPE, i.e. whatever was
+/// reading its output went away first.
+#[cfg(unix)]
+pub fn killed_by_broken_pipe(status: &ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal() == Some(libc::SIGPIPE)
+}
+
+#[cfg(not(unix))]
+pub fn killed_by_broken_pipe(_status: &ExitStatus) -> bool {
+    false
+}
+
+#[cfg(windows)]
+mod job {
+    use std::io;
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::System::JobObjects::{
+        AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation, SetInformationJobObject,
+        TerminateJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+    };
+
+    /// A Job Object with a child in it, and so everything the child starts:
+    /// Windows' nearest thing to a process group. Everything in it is killed
+    /// when it's closed, which also happens if we exit without getting to.
+    pub struct Job(HANDLE);
+
+    // SAFETY: a job's handle can be used and closed from any thread
+    unsafe impl Send for Job {}
+    unsafe impl Sync for Job {}
+
+    impl Job {
+        /// A new job with `process` in it.
+        pub fn with(process: HANDLE) -> io::Result<Self> {
+            // SAFETY: no security attributes or name are passed, and the handle
+            // is closed by Drop
+            let job = Self(unsafe { CreateJobObjectW(std::ptr::null(), std::ptr::null()) });
+            if job.0.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: it's a plain C struct that's valid when zeroed, and it
+            // outlives the call that reads it
+            let mut limits: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { std::mem::zeroed() };
+            limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+            let set = unsafe {
+                SetInformationJobObject(
+                    job.0,
+                    JobObjectExtendedLimitInformation,
+                    &limits as *const JOBOBJECT_EXTENDED_LIMIT_INFORMATION as *const _,
+                    std::mem::size_of_val(&limits) as u32,
+                )
+            };
+            // SAFETY: the child's handle is valid for as long as its Child is
+            if set == 0 || unsafe { AssignProcessToJobObject(job.0, process) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(job)
+        }
+
+        /// Kill everything in the job. A job that's already empty is fine.
+        pub fn terminate(&self) {
+            // SAFETY: the handle is open until Drop
+            unsafe {
+                TerminateJobObject(self.0, 1);
+            }
+        }
+    }
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            // SAFETY: the handle was opened by `with` and isn't used again
+            unsafe {
+                CloseHandle(self.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::watch;
+
+/// Longest y4m header line read, stream or frame. Past it, frames stop being
+/// counted rather than have us buffer whatever ffmpeg is writing.
+const MAX_HEADER_BYTES: usize = 1024;
+
+/// How far a conversion has got, for whoever is watching it: the frames
+/// ffmpeg has handed the encoder, out of about how many, and what the encoder
+/// has made of them so far. Clones report to the same watchers, so a
+/// segmented or retried conversion adds up.
+#[derive(Debug, Clone)]
+pub struct Progress(Arc<watch::Sender<Snapshot>>);
+
+/// What [`Progress`] has been told so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    /// How much of the video is being converted, once it's known
+    pub length: Option<Duration>,
+    /// Frames that have made it all the way into the encoder. Only counted
+    /// for encoders ffmpeg writes y4m frames to.
+    pub frames: u64,
+    /// About how many frames there'll be, going by the length and the frame
+    /// rate ffmpeg says it's writing
+    pub expected_frames: Option<u64>,
+    /// Bytes that have come out of the encoder
+    pub bytes: u64,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(Snapshot::default()).0))
+    }
+}
+
+impl Progress {
+    /// A receiver that sees every change from now on.
+    pub fn subscribe(&self) -> watch::Receiver<Snapshot> {
+        self.0.subscribe()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        *self.0.borrow()
+    }
+
+    /// The conversion is of `length` of the video, if that's known.
+    pub fn converting(&self, length: Option<Duration>) {
+        self.0.send_modify(|snapshot| snapshot.length = length);
+    }
+
+    // ffmpeg's writing `fps` frames a second
+    fn frame_rate(&self, fps: f64) {
+        self.0.send_modify(|snapshot| {
+            if let Some(length) = snapshot.length {
+                snapshot.expected_frames = Some((length.as_secs_f64() * fps).ceil() as u64);
+            }
+        });
+    }
+
+    fn frame_fed(&self) {
+        self.0.send_modify(|snapshot| snapshot.frames += 1);
+    }
+
+    fn produced(&self, bytes: usize) {
+        self.0.send_modify(|snapshot| snapshot.bytes += bytes as u64);
+    }
+}
+
+/// Counts what passes through it for a [`Progress`]: y4m frames on their
+/// way to the encoder, or bytes on their way out of it.
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: Progress,
+    frames: Option<FrameCounter>,
+}
+
+impl<R> ProgressReader<R> {
+    /// Count the y4m frames read from `inner`.
+    pub fn frames(inner: R, progress: Progress) -> Self {
+        Self { inner, progress, frames: Some(FrameCounter::default()) }
+    }
+
+    /// Count the bytes read from `inner`.
+    pub fn bytes(inner: R, progress: Progress) -> Self {
+        Self { inner, progress, frames: None }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = &buf.filled()[before..];
+        if !read.is_empty() {
+            let this = &mut *self;
+            match &mut this.frames {
+                Some(counter) => counter.feed(read, &this.progress),
+                None => this.progress.produced(read.len()),
+            }
+        }
+        result
+    }
+}
+
+/// Follows a yuv4mpegpipe stream as it goes past, however it's split up,
+/// noting each frame once all of it has.
+#[derive(Debug, Default)]
+pub struct FrameCounter {
+    state: Y4mState,
+    line: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+enum Y4mState {
+    /// Reading the stream header
+    #[default]
+    Stream,
+    /// Reading a frame's header, whose data is this long
+    FrameHeader(usize),
+    /// This much of the current frame's data is still to come
+    Frame { frame_len: usize, remaining: usize },
+    /// Lost track of it, so nothing more is counted
+    Lost,
+}
+
+impl FrameCounter {
+    /// Follow `bytes`, the next of the stream, telling `progress` about the
+    /// frame rate and the frames that are now complete.
+    pub fn feed(&mut self, mut bytes: &[u8], progress: &Progress) {
+        while !bytes.is_empty() {
+            match self.state {
+                Y4mState::Stream | Y4mState::FrameHeader(_) => {
+                    let Some(line) = self.read_line(&mut bytes) else { continue };
+                    self.state = match (&self.state, line) {
+                        (Y4mState::Stream, line) => match stream_header(&line) {
+                            Some((frame_len, fps)) => {
+                                if let Some(fps) = fps {
+                                    progress.frame_rate(fps);
+                                }
+                                Y4mState::FrameHeader(frame_len)
+                            }
+                            None => Y4mState::Lost,
+                        },
+                        (Y4mState::FrameHeader(frame_len), line) if line.starts_with(b"FRAME") => {
+                            Y4mState::Frame { frame_len: *frame_len, remaining: *frame_len }
+                        }
+                        _ => Y4mState::Lost,
+                    };
+                    if let Y4mState::Frame { remaining: 0, frame_len } = self.state {
+                        progress.frame_fed();
+                        self.state = Y4mState::FrameHeader(frame_len);
+                    }
+                }
+                Y4mState::Frame { frame_len, remaining } => {
+                    let taken = remaining.min(bytes.len());
+                    bytes = &bytes[taken..];
+                    self.state = if taken == remaining {
+                        progress.frame_fed();
+                        Y4mState::FrameHeader(frame_len)
+                    } else {
+                        Y4mState::Frame { frame_len, remaining: remaining - taken }
+                    };
+                }
+                Y4mState::Lost => return,
+            }
+        }
+    }
+
+    // The rest of the header line being read, once its newline is in `bytes`,
+    // which is moved past what's used
+    fn read_line(&mut self, bytes: &mut &[u8]) -> Option<Vec<u8>> {
+        let newline = bytes.iter().position(|b| *b == b'\n');
+        let end = newline.unwrap_or(bytes.len());
+        self.line.extend_from_slice(&bytes[..end]);
+        *bytes = &bytes[newline.map_or(end, |at| at + 1)..];
+        if self.line.len() > MAX_HEADER_BYTES {
+            self.state = Y4mState::Lost;
+            return None;
+        }
+        newline.map(|_| std::mem::take(&mut self.line))
+    }
+}
+
+// How long each frame of a stream with this header is, and its frame rate if
+// it says
+fn stream_header(line: &[u8]) -> Option<(usize, Option<f64>)> {
+    let line = std::str::from_utf8(line).ok()?;
+    let params = line.strip_prefix("YUV4MPEG2")?.split_ascii_whitespace();
+    let (mut width, mut height, mut fps, mut colorspace) = (None, None, None, "420jpeg");
+    for param in params {
+        let Some((tag, value)) = param.split_at_checked(1) else { continue };
+        match tag {
+            "W" => width = value.parse::<usize>().ok(),
+            "H" => height = value.parse::<usize>().ok(),
+            "F" => {
+                let (num, den) = value.split_once(':')?;
+                let (num, den) = (num.parse::<f64>().ok()?, den.parse::<f64>().ok()?);
+                fps = (num > 0.0 && den > 0.0).then_some(num / den);
+            }
+            "C" => colorspace = value,
+            _ => {}
+        }
+    }
+    Some((frame_len(width?, height?, colorspace)?, fps))
+}
+
+// The bytes in one frame of `colorspace`, e.g. `420jpeg`, `444` or `422p10`
+fn frame_len(width: usize, height: usize, colorspace: &str) -> Option<usize> {
+    let (chroma, sample) = match colorspace.split_once('p') {
+        Some((chroma, depth)) if !depth.is_empty() && depth.bytes().all(|b| b.is_ascii_digit()) => {
+            (chroma, if depth.parse::<u32>().ok()? > 8 { 2 } else { 1 })
+        }
+        _ => (colorspace, 1),
+    };
+    let luma = width * height;
+    let half_width = width.div_ceil(2);
+    let samples = match chroma {
+        "mono" => luma,
+        "mono16" => return Some(luma * 2),
+        "444alpha" => luma * 4,
+        "444" => luma * 3,
+        "422" => luma + 2 * half_width * height,
+        "411" => luma + 2 * width.div_ceil(4) * height,
+        chroma if chroma.starts_with("420") => luma + 2 * half_width * height.div_ceil(2),
+        _ => return None,
+    };
+    Some(samples * sample)
+}
@@ -0,0 +1,116 @@
+use crate::encoder::{Decoder, Encoder};
+use crate::error::{ConversionError, Result};
+use crate::fetch::{FetchMode, SourceRef};
+use crate::gif;
+use crate::pipeline::{self, ConversionOptions, GifOutput, PipelineConfig};
+use futures_util::future::try_join_all;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Segments shorter than this aren't worth their own ffmpeg and encoder.
+const MIN_SEGMENT: Duration = Duration::from_secs(3);
+
+/// How many segments to split a conversion into, or `None` to convert it in
+/// one go. Only conversions of a known length, with ffmpeg fetching the video
+/// and writing frames to an encoder that outputs a GIF we can get at before
+/// it's sent, can be split. Only the built-in encoders' GIFs are known to
+/// join up.
+pub fn plan(config: &PipelineConfig, options: &ConversionOptions, streaming: bool) -> Option<usize> {
+    let wanted = wanted(config, options, streaming)?;
+    let length = length(options)?;
+    let segments = ((length.as_secs_f64() / MIN_SEGMENT.as_secs_f64()) as usize).min(wanted);
+    (segments >= 2).then_some(segments)
+}
+
+/// The most segments a conversion with `options` could be split into, going
+/// by everything but the video's length.
+pub fn wanted(config: &PipelineConfig, options: &ConversionOptions, streaming: bool) -> Option<usize> {
+    let wanted = config.parallel_segments?;
+    let splittable = !streaming
+        && options.backend.is_none()
+        && config.decoder == Decoder::Ffmpeg
+        && config.encoder != Encoder::Native
+        && config.fetch_mode == FetchMode::Ffmpeg;
+    splittable.then_some(wanted)
+}
+
+/// Convert the video as `segments` pieces at once and join the GIFs. If that
+/// goes wrong in a way a single conversion might not, the video is converted
+/// the usual way instead.
+pub async fn convert(
+    source: &SourceRef,
+    config: &PipelineConfig,
+    options: &ConversionOptions,
+    segments: usize,
+    cancel: CancellationToken,
+) -> Result<GifOutput> {
+    match convert_segments(source, config, options, segments, cancel.clone()).await {
+        Ok(gif) => Ok(gif),
+        Err(
+            e @ (ConversionError::Cancelled
+            | ConversionError::TimedOut(_)
+            | ConversionError::DeadlineExceeded
+            | ConversionError::DiskFull { .. }
+            | ConversionError::TempSpaceExceeded(_)
+            | ConversionError::InputTooLarge(_)
+            | ConversionError::OutputTooLarge(_)),
+        ) => Err(e),
+        Err(e) => {
+            warn!("Segmented conversion failed, converting in one go: {}", e);
+            pipeline::convert(source, config, options, None, cancel).await
+        }
+    }
+}
+
+async fn convert_segments(
+    source: &SourceRef,
+    config: &PipelineConfig,
+    options: &ConversionOptions,
+    segments: usize,
+    cancel: CancellationToken,
+) -> Result<GifOutput> {
+    let length = length(options).expect("only planned for videos of a known length");
+    let segment_length = length / segments as u32;
+    info!("Converting in {} segments of {:?}", segments, segment_length);
+
+    let conversions = (0..segments).map(|index| {
+        let into = segment_length * index as u32;
+        let options = ConversionOptions {
+            start: Some(options.start.unwrap_or_default() + into),
+            // The last segment runs to the end, in case the probed length was short
+            trim: if index + 1 < segments {
+                Some(segment_length)
+            } else {
+                options.trim.map(|trim| trim.saturating_sub(into))
+            },
+            ..options.clone()
+        };
+        let cancel = cancel.clone();
+        async move {
+            pipeline::convert(source, config, &options, None, cancel)
+                .await?
+                .into_bytes()
+                .await
+        }
+    });
+    // The first failure drops the other conversions, which kills their children
+    let gifs = try_join_all(conversions).await?;
+
+    let (joined, frames) = gif::concat(&gifs)
+        .map_err(|e| ConversionError::Internal(format!("Failed to join the segments: {}", e)))?;
+    if let Some(max) = config.max_output_bytes {
+        if joined.len() as u64 > max {
+            return Err(ConversionError::OutputTooLarge(max));
+        }
+    }
+    info!("Joined {} segments into a GIF of {} frames ({} bytes)", segments, frames, joined.len());
+    Ok(GifOutput::Memory(joined.into()))
+}
+
+/// How much of the video gets converted, from its start, if its length is
+/// known.
+pub fn length(options: &ConversionOptions) -> Option<Duration> {
+    let duration = options.duration?.saturating_sub(options.start.unwrap_or_default());
+    Some(options.trim.map_or(duration, |trim| trim.min(duration)))
+}
@@ -0,0 +1,258 @@
+use crate::encoder::Decoder;
+use crate::error::{ConversionError, Result};
+use crate::error_class::ErrorClass;
+use crate::fetch::{FetchMode, SourceFetcher, SourceRef, SourceStream};
+use crate::hwaccel::{self, Hwaccel};
+use crate::pipeline::{self, ConversionOptions, GifOutput, PipelineConfig};
+use crate::process::{killed_by_limit, ProcessGuard};
+use crate::stderr_tail;
+use crate::timing::StageTimes;
+use bytes::{Bytes, BytesMut};
+use futures_util::future::{self, BoxFuture};
+use futures_util::{stream, StreamExt};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, info_span, warn, Instrument};
+
+/// How far ahead of the slowest variant the decode can get, in chunks of
+/// frames.
+const BRANCH_CHUNKS: usize = 16;
+
+/// How much of the decoded video is read at a time.
+const CHUNK_BYTES: usize = 256 * 1024;
+
+/// Whether `variants` of one video can share a decode: there's more than one,
+/// ffmpeg's decoding them, and they start and stop in the same place. The
+/// frames are shared as y4m, so none of them can have an alpha channel.
+pub fn can_share(config: &PipelineConfig, variants: &[ConversionOptions]) -> bool {
+    let Some(first) = variants.first() else { return false };
+    variants.len() > 1
+        && config.decoder == Decoder::Ffmpeg
+        && variants
+            .iter()
+            .all(|options| options.start == first.start && options.trim == first.trim && options.alpha.is_none())
+}
+
+/// Convert `source` once for each of `variants`, each with its own options
+/// and cancellation, post-processing included. Where they [can
+/// share](can_share) it, the video is decoded once, and the frames fed to
+/// every variant's own ffmpeg and encoder; otherwise each is converted on its
+/// own. A variant that fails leaves the others to finish, but the decode
+/// failing fails them all, with what it failed with.
+pub async fn convert(
+    source: &SourceRef,
+    config: &PipelineConfig,
+    variants: Vec<(ConversionOptions, CancellationToken)>,
+) -> Vec<Result<GifOutput>> {
+    let options: Vec<ConversionOptions> = variants.iter().map(|(options, _)| options.clone()).collect();
+    if !can_share(config, &options) {
+        let conversions = variants
+            .into_iter()
+            .map(|(options, cancel)| async move { pipeline::process_tweet_video(source, config, &options, None, cancel).await });
+        return future::join_all(conversions).await;
+    }
+    info!("Decoding {} once for {} variants", source.url, variants.len());
+    let hwaccel = config.hwaccel(&options[0]);
+    let mut senders = Vec::new();
+    let mut conversions = Vec::new();
+    for (options, cancel) in variants {
+        let (sender, receiver) = mpsc::channel(BRANCH_CHUNKS);
+        senders.push(sender);
+        // What's decoded is the part of the video the variants want, as it's
+        // shown, so there's nothing left for their own ffmpeg to trim
+        let branch = PipelineConfig {
+            source_fetcher: Arc::new(Branch::new(receiver)),
+            fetch_mode: FetchMode::InProcess,
+            max_input_bytes: None,
+            hwaccel: None,
+            parallel_segments: None,
+            ..config.clone()
+        };
+        options.timings.decoding_with(hwaccel.map_or(hwaccel::SOFTWARE, Hwaccel::as_str));
+        let branch_options = ConversionOptions { start: None, trim: None, ..options };
+        conversions.push(async move { pipeline::process_tweet_video(source, &branch, &branch_options, None, cancel).await });
+    }
+    let timings: Vec<StageTimes> = options.iter().map(|options| options.timings.clone()).collect();
+    let fetched = StageTimes::default();
+    let decode = decode(source, config, &options[0], senders, &timings, &fetched).instrument(info_span!("shared_decode"));
+    let ((), results) = tokio::join!(decode, future::join_all(conversions));
+    timings.iter().for_each(|timings| timings.fed_by(&fetched));
+    results
+}
+
+// Decode `source` to y4m, handing every chunk of it to each variant still
+// reading. Once it's all been handed out, the variants are left to finish;
+// if the decode failed they're each told why instead.
+async fn decode(
+    source: &SourceRef,
+    config: &PipelineConfig,
+    options: &ConversionOptions,
+    mut branches: Vec<mpsc::Sender<Result<Bytes>>>,
+    timings: &[StageTimes],
+    fetched: &StageTimes,
+) {
+    let decoding = decode_into(source, config, options, &mut branches, timings, fetched);
+    let decoded = tokio::time::timeout(config.timeout, decoding).await;
+    let e = match decoded {
+        Ok(Ok(())) => return,
+        Ok(Err(e)) => e,
+        Err(_) => ConversionError::TimedOut(config.timeout),
+    };
+    warn!("The shared decode failed: {}", e);
+    for branch in branches {
+        let _ = branch.send(Err(again(&e))).await;
+    }
+}
+
+async fn decode_into(
+    source: &SourceRef,
+    config: &PipelineConfig,
+    options: &ConversionOptions,
+    branches: &mut Vec<mpsc::Sender<Result<Bytes>>>,
+    timings: &[StageTimes],
+    fetched: &StageTimes,
+) -> Result<()> {
+    // What it fetched is the decode's, for the variants to share
+    let source = &SourceRef { timings: fetched.clone(), ..source.clone() };
+    let video = pipeline::fetch_source(source, config).await?;
+    let (input, stdin) = match video {
+        Some(_) => ("pipe:0", Stdio::piped()),
+        None => (source.url.as_str(), Stdio::null()),
+    };
+    let mut args = pipeline::ffmpeg_input_args(input, config, options);
+    args.extend(["-an", "-sn", "-pix_fmt", "yuv420p", "-f", "yuv4mpegpipe", "pipe:1"].map(String::from));
+    let mut ffmpeg = ProcessGuard::spawn(
+        "ffmpeg",
+        config.binaries.ffmpeg.command().args(args).stdin(stdin).stdout(Stdio::piped()).stderr(Stdio::piped()),
+        &config.child_limits,
+    )
+    .map_err(|source| ConversionError::Spawn { binary: "ffmpeg", source })?;
+    let mut stdout = ffmpeg.take_stdout().ok_or_else(|| ConversionError::internal("Failed to take ffmpeg stdout"))?;
+    let stderr = ffmpeg.take_stderr().ok_or_else(|| ConversionError::internal("Failed to take ffmpeg stderr"))?;
+
+    // Every stage stops on this, however the decode ends
+    let stop = CancellationToken::new();
+    let _stop_on_return = stop.clone().drop_guard();
+    let feed = match video {
+        Some(video) => {
+            let stdin = ffmpeg.take_stdin().ok_or_else(|| ConversionError::internal("Failed to take ffmpeg stdin"))?;
+            let feed = pipeline::feed_source(video, stdin, config.max_input_bytes, fetched.clone(), stop.clone());
+            Some(tokio::spawn(feed.instrument(info_span!("upstream_download"))))
+        }
+        None => None,
+    };
+    let hwaccel = config.hwaccel(options);
+    let shown = timings.to_vec();
+    let stderr = tokio::spawn(async move {
+        let mut reader = tokio::io::BufReader::new(stderr);
+        let mut line = String::new();
+        let mut cause = None;
+        while stderr_tail::read_line(&mut reader, &mut line).await.unwrap_or(false) {
+            info!(source = "ffmpeg", line = line.as_str(), "stderr");
+            if cause.is_none() && ErrorClass::from_ffmpeg_line(&line).is_some() {
+                cause = Some(line.clone());
+            }
+            if hwaccel.is_some() && hwaccel::gave_up(&line) {
+                shown.iter().for_each(StageTimes::decoded_in_software);
+            }
+        }
+        cause
+    });
+
+    // Handed out a chunk at a time, dropping the variants that stop reading.
+    // With none left there's nothing to decode for.
+    loop {
+        let mut chunk = BytesMut::with_capacity(CHUNK_BYTES);
+        let read = stdout
+            .read_buf(&mut chunk)
+            .await
+            .map_err(ConversionError::pipe("Failed to read the decoded video"))?;
+        if read == 0 {
+            break;
+        }
+        let chunk = chunk.freeze();
+        let mut reading = Vec::with_capacity(branches.len());
+        for branch in branches.drain(..) {
+            if branch.send(Ok(chunk.clone())).await.is_ok() {
+                reading.push(branch);
+            }
+        }
+        *branches = reading;
+        if branches.is_empty() {
+            info!("No variant is reading the shared decode any more, stopping it");
+            return Ok(());
+        }
+    }
+
+    let status = ffmpeg.wait().await.map_err(ConversionError::pipe("Failed to wait for ffmpeg process"))?;
+    if let Some(usage) = ffmpeg.usage() {
+        // Charged to the first variant, so it's only counted once
+        timings[0].child_used(usage);
+    }
+    if let Some(feed) = feed {
+        feed.await.map_err(|e| ConversionError::Internal(format!("The feed task failed: {}", e)))??;
+    }
+    let cause = stderr.await.unwrap_or_default();
+    if killed_by_limit(&status) {
+        config.metrics.subprocess_failed("ffmpeg", "resource_limit");
+        return Err(ConversionError::ResourceLimit("ffmpeg"));
+    }
+    if !status.success() {
+        config.metrics.subprocess_failed("ffmpeg", "failed");
+        let class = cause.as_deref().and_then(ErrorClass::from_ffmpeg_line).unwrap_or(ErrorClass::FfmpegDecodeError);
+        return Err(ConversionError::Ffmpeg { code: status.code(), class });
+    }
+    info!("The shared decode finished");
+    Ok(())
+}
+
+// The same error for another variant, as close to `e` as one can be made
+fn again(e: &ConversionError) -> ConversionError {
+    match e {
+        ConversionError::UpstreamStatus { status, url } => ConversionError::UpstreamStatus { status: *status, url: url.clone() },
+        ConversionError::UpstreamUnresolved { host, reason } => {
+            ConversionError::UpstreamUnresolved { host: host.clone(), reason: reason.clone() }
+        }
+        ConversionError::Ffmpeg { code, class } => ConversionError::Ffmpeg { code: *code, class: *class },
+        ConversionError::UnsupportedSource(reason) => ConversionError::UnsupportedSource(reason),
+        ConversionError::SourceIncomplete => ConversionError::SourceIncomplete,
+        ConversionError::TimedOut(timeout) => ConversionError::TimedOut(*timeout),
+        ConversionError::InputTooLarge(max) => ConversionError::InputTooLarge(*max),
+        ConversionError::TempSpaceExceeded(max) => ConversionError::TempSpaceExceeded(*max),
+        ConversionError::ResourceLimit(binary) => ConversionError::ResourceLimit(binary),
+        ConversionError::Cancelled => ConversionError::Cancelled,
+        // What the rest come to is all the response says of them anyway
+        e => ConversionError::Ffmpeg { code: None, class: ErrorClass::of(e) },
+    }
+}
+
+// One variant's share of the decode, as its ffmpeg reads it. There's only
+// the one go at it.
+struct Branch(Mutex<Option<SourceStream>>);
+
+impl Branch {
+    fn new(mut receiver: mpsc::Receiver<Result<Bytes>>) -> Self {
+        let frames = stream::poll_fn(move |cx| receiver.poll_recv(cx)).boxed();
+        Branch(Mutex::new(Some(frames)))
+    }
+}
+
+impl std::fmt::Debug for Branch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Branch")
+    }
+}
+
+impl SourceFetcher for Branch {
+    fn fetch<'a>(&'a self, _source: &'a SourceRef) -> BoxFuture<'a, Result<SourceStream>> {
+        let frames = self.0.lock().unwrap().take();
+        Box::pin(async move { frames.ok_or_else(|| ConversionError::internal("A shared decode can only be read once")) })
+    }
+
+    fn refetchable(&self) -> bool {
+        false
+    }
+}
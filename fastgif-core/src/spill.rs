@@ -0,0 +1,155 @@
+use bytes::Bytes;
+use futures_util::Stream;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::error::ConversionError;
+use crate::temp::TempFile;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio_util::io::ReaderStream;
+use tracing::{info, warn};
+
+/// How long a look at how much space is free is reused for, so it isn't
+/// looked at for every write.
+const FREE_SPACE_TTL: Duration = Duration::from_secs(5);
+
+/// When big GIFs are written to a temp file instead of kept in memory.
+#[derive(Debug, Clone)]
+pub struct SpillConfig {
+    /// Output beyond this many bytes goes to a temp file (`SPILL_THRESHOLD_BYTES`)
+    pub threshold: u64,
+}
+
+/// Keeps temp files off a disk that's nearly full (`MIN_FREE_DISK_BYTES`),
+/// so a conversion fails saying so up front rather than partway through a
+/// write. What's free is looked at no more than every `FREE_SPACE_TTL`.
+#[derive(Debug)]
+pub struct DiskGuard {
+    dir: PathBuf,
+    min_free: u64,
+    state: Mutex<DiskState>,
+    /// Told every time it's found to be too full, for whatever can make room
+    low: Notify,
+}
+
+#[derive(Debug, Default)]
+struct DiskState {
+    checked: Option<Instant>,
+    low: bool,
+    /// Still too full after an attempt at making room
+    stuck: bool,
+}
+
+impl DiskGuard {
+    pub fn new(dir: PathBuf, min_free: u64) -> Self {
+        Self { dir, min_free, state: Mutex::default(), low: Notify::new() }
+    }
+
+    /// Refuse to write to the disk if it has less than the minimum free.
+    pub fn check(&self) -> crate::error::Result<()> {
+        match self.is_low() {
+            true => Err(ConversionError::DiskFull { dir: self.dir.display().to_string(), min_free: self.min_free }),
+            false => Ok(()),
+        }
+    }
+
+    /// Whether there's less than the minimum free, as of `FREE_SPACE_TTL`
+    /// ago at most. Space that can't be told about is taken to be there.
+    pub fn is_low(&self) -> bool {
+        let (low, looked) = self.look(false);
+        if low && looked {
+            self.low.notify_one();
+        }
+        low
+    }
+
+    // Whether it's too full, and whether that was looked at just now rather
+    // than remembered, as it is for `FREE_SPACE_TTL` unless it's looked at `again`
+    fn look(&self, again: bool) -> (bool, bool) {
+        let mut state = self.state.lock().unwrap();
+        if !again && state.checked.is_some_and(|checked| checked.elapsed() < FREE_SPACE_TTL) {
+            return (state.low, false);
+        }
+        let low = match available(&self.dir) {
+            Ok(free) => {
+                if free < self.min_free && !state.low {
+                    warn!("Only {} bytes are free in {}, under the {} byte minimum", free, self.dir.display(), self.min_free);
+                } else if free >= self.min_free && state.low {
+                    info!("{} bytes are free in {} again", free, self.dir.display());
+                }
+                free < self.min_free
+            }
+            Err(e) => {
+                warn!("Couldn't tell how much space is free in {}: {}", self.dir.display(), e);
+                false
+            }
+        };
+        *state = DiskState { checked: Some(Instant::now()), low, stuck: low && state.stuck };
+        (low, true)
+    }
+
+    /// Whether it's still too full after whatever was meant to make room
+    /// (see [`DiskGuard::made_room`]) has had a go.
+    pub fn is_stuck(&self) -> bool {
+        self.is_low() && self.state.lock().unwrap().stuck
+    }
+
+    /// Wait until it's next found to be too full.
+    pub async fn filled_up(&self) {
+        self.low.notified().await;
+    }
+
+    /// Say room has been made, as far as it can be: the disk is looked at
+    /// again straight away, and if it's still too full, it's stuck.
+    pub fn made_room(&self) {
+        let (low, _) = self.look(true);
+        self.state.lock().unwrap().stuck = low;
+    }
+}
+
+// Bytes free on the filesystem `dir` is on, to whoever isn't root
+#[cfg(unix)]
+fn available(dir: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: statvfs is a plain C struct that's valid when zeroed, and
+    // outlives the call that fills it in; the path is NUL-terminated
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available(_: &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "free space is only looked at on Unix"))
+}
+
+/// A response body streamed from a spilled GIF, which is deleted once the body
+/// is dropped (fully sent or not).
+pub struct SpilledBody {
+    stream: ReaderStream<tokio::fs::File>,
+    _file: TempFile,
+}
+
+impl SpilledBody {
+    pub fn new(file: TempFile, handle: tokio::fs::File) -> Self {
+        Self {
+            stream: ReaderStream::new(handle),
+            _file: file,
+        }
+    }
+}
+
+impl Stream for SpilledBody {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// How many of each child's last stderr lines are kept.
+const TAIL_LINES: usize = 20;
+
+/// Longest stderr line kept (or logged), in bytes. The rest of it is skipped.
+const MAX_LINE_BYTES: usize = 1024;
+
+/// The last lines each of a conversion's children wrote to stderr, for when
+/// it fails. Clones share the lines, so a segmented conversion has one tail.
+#[derive(Debug, Clone, Default)]
+pub struct StderrTail(Arc<Mutex<BTreeMap<&'static str, VecDeque<String>>>>);
+
+impl StderrTail {
+    /// Keep `line` as the latest from `source` (`ffmpeg` or `gifski`),
+    /// forgetting its oldest once there are enough.
+    pub fn push(&self, source: &'static str, line: &str) {
+        let mut tails = self.0.lock().unwrap();
+        let lines = tails.entry(source).or_default();
+        if lines.len() == TAIL_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line.to_string());
+    }
+
+    /// Every child's lines, oldest first.
+    pub fn snapshot(&self) -> BTreeMap<&'static str, Vec<String>> {
+        let tails = self.0.lock().unwrap();
+        tails
+            .iter()
+            .map(|(source, lines)| (*source, lines.iter().cloned().collect()))
+            .collect()
+    }
+
+    /// Forget everything, once it's clear nobody needs to see it.
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// Read the next line of a child's stderr into `line`, without its line
+/// ending. Past `MAX_LINE_BYTES` the rest of the line is skipped, so a child
+/// that never writes a newline can't make us buffer all of its output.
+/// `false` once there's nothing left.
+pub async fn read_line(reader: &mut (impl AsyncBufRead + Unpin), line: &mut String) -> std::io::Result<bool> {
+    let mut bytes = Vec::new();
+    let mut read_any = false;
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            break;
+        }
+        read_any = true;
+        let newline = buf.iter().position(|b| *b == b'\n');
+        let chunk = &buf[..newline.unwrap_or(buf.len())];
+        let room = MAX_LINE_BYTES.saturating_sub(bytes.len());
+        bytes.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        let used = newline.map_or(buf.len(), |at| at + 1);
+        reader.consume(used);
+        if newline.is_some() {
+            break;
+        }
+    }
+    line.clear();
+    line.push_str(String::from_utf8_lossy(&bytes).trim_end());
+    Ok(read_any)
+}
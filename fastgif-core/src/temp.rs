@@ -0,0 +1,265 @@
+use crate::error::{ConversionError, Result};
+use crate::spill::DiskGuard;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Temp files from before they had a directory of their instance's own
+/// started with this, and those directories still do.
+const TEMP_PREFIX: &str = "fastgif-";
+
+/// What an instance's own directory under `TMP_DIR` is called, before the
+/// process ID and when it started.
+const INSTANCE_PREFIX: &str = "fastgif-instance-";
+
+/// How long an instance's directory, or a temp file, can go untouched
+/// before it's taken to be left over from a crash, unless told otherwise
+/// (`TEMP_FILE_MAX_AGE`).
+pub const ORPHAN_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// How many files a paced sweep looks at between pauses.
+const SWEEP_BATCH: u32 = 64;
+
+/// Owns every temp file this instance writes: uploads, spilled GIFs, and
+/// whatever else has to go on disk for a while. They're kept in a directory
+/// of the instance's own under `TMP_DIR`, named for its process and when it
+/// started, which is removed along with the manager. What an instance that
+/// crashed left behind is swept up once its directory has gone untouched
+/// for `orphan_after`; a running instance's directory is touched whenever
+/// its files come and go, and on every sweep.
+#[derive(Debug)]
+pub struct TempManager {
+    /// `TMP_DIR`
+    root: PathBuf,
+    /// This instance's own, in `root`
+    dir: PathBuf,
+    /// What its temp files may take up between them (`TEMP_MAX_BYTES`)
+    max_bytes: Option<u64>,
+    orphan_after: Duration,
+    /// What keeps temp files off the disk once it's nearly full
+    disk: Option<Arc<DiskGuard>>,
+    /// Bytes written to the temp files that are still around
+    used: AtomicU64,
+    /// For each temp file's name
+    created: AtomicU64,
+}
+
+/// What a sweep of temp files removed.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Swept {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl TempManager {
+    /// A manager for temp files in a directory of its own under `root`,
+    /// which isn't made until the first of them is.
+    pub fn new(root: PathBuf, max_bytes: Option<u64>, orphan_after: Duration, disk: Option<Arc<DiskGuard>>) -> Self {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let dir = root.join(format!("{}{}-{:x}", INSTANCE_PREFIX, std::process::id(), started));
+        Self { root, dir, max_bytes, orphan_after, disk, used: AtomicU64::new(0), created: AtomicU64::new(0) }
+    }
+
+    /// `TMP_DIR`, where every instance's directory is.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// This instance's own directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// What keeps temp files off the disk once it's nearly full, if anything.
+    pub fn disk(&self) -> Option<&Arc<DiskGuard>> {
+        self.disk.as_ref()
+    }
+
+    /// Bytes in the temp files that are around now.
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Refuse to write a temp file when the disk is nearly full, or the
+    /// temp files already take up everything they're allowed.
+    pub fn check(&self) -> Result<()> {
+        if let Some(disk) = &self.disk {
+            disk.check()?;
+        }
+        match self.max_bytes {
+            Some(max) if self.used() >= max => Err(ConversionError::TempSpaceExceeded(max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Create a new, empty temp file, named for `name` (e.g. `upload.mp4`)
+    /// with a number to keep it apart from the others. It's deleted when
+    /// the [`TempFile`] is dropped, and what's written to it has to be
+    /// counted with [`TempFile::grew`] first.
+    pub async fn create(self: &Arc<Self>, name: &str) -> Result<(TempFile, tokio::fs::File)> {
+        self.check()?;
+        let name = Path::new(name);
+        let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+        let n = self.created.fetch_add(1, Ordering::Relaxed);
+        let name = match name.extension() {
+            Some(extension) => format!("{}-{}.{}", stem, n, extension.to_string_lossy()),
+            None => format!("{}-{}", stem, n),
+        };
+        let path = self.dir.join(name);
+        let failed = || ConversionError::pipe(format!("Failed to create a temp file in {}", self.dir.display()));
+        // Made again if a sweep took it for an orphan's while it was empty
+        tokio::fs::create_dir_all(&self.dir).await.map_err(failed())?;
+        let file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+            .map_err(failed())?;
+        Ok((TempFile { path, manager: self.clone(), bytes: AtomicU64::new(0) }, file))
+    }
+
+    // Take `bytes` more of the temp space, unless that's more than there is
+    fn reserve(&self, bytes: u64) -> Result<()> {
+        let Some(max) = self.max_bytes else {
+            self.used.fetch_add(bytes, Ordering::Relaxed);
+            return Ok(());
+        };
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| Some(used + bytes).filter(|total| *total <= max))
+            .map(drop)
+            .map_err(|_| {
+                warn!(outcome = "temp_space_exceeded", "Temp files would take up more than {} bytes", max);
+                ConversionError::TempSpaceExceeded(max)
+            })
+    }
+
+    /// Delete what crashed instances left behind, as the server starts.
+    pub fn sweep_orphans(&self) {
+        match self.sweep(None) {
+            Ok(swept) if swept.files > 0 => info!("Removed {} stale temp files from {}", swept.files, self.root.display()),
+            Ok(_) => {}
+            // Nothing's been written there yet, so nothing's been left
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Couldn't look for stale temp files in {}: {}", self.root.display(), e),
+        }
+    }
+
+    /// Delete the directories of the instances that haven't touched theirs
+    /// for `orphan_after`, whatever's in them, and any temp files of ours
+    /// that haven't been written to for that long, this instance's included.
+    /// No more than `max_per_sec` files are looked at a second if that's
+    /// given. It blocks, pauses included, so it's for the blocking pool.
+    pub fn sweep(&self, max_per_sec: Option<u32>) -> io::Result<Swept> {
+        // Ours is still going, if it's been made yet
+        #[cfg(unix)]
+        if let Ok(dir) = std::fs::File::open(&self.dir) {
+            let _ = dir.set_modified(SystemTime::now());
+        }
+        let pause = max_per_sec.filter(|max| *max > 0).map(|max| Duration::from_secs_f64(f64::from(SWEEP_BATCH) / f64::from(max)));
+        let mut seen = 0u32;
+        let mut swept = Swept::default();
+        let mut look = |entry: &std::fs::DirEntry| -> Option<std::fs::Metadata> {
+            seen += 1;
+            if let Some(pause) = pause.filter(|_| seen % SWEEP_BATCH == 0) {
+                std::thread::sleep(pause);
+            }
+            entry.metadata().ok()
+        };
+        let stale = |meta: &std::fs::Metadata| {
+            meta.modified().ok().and_then(|modified| modified.elapsed().ok()).is_some_and(|age| age > self.orphan_after)
+        };
+        for entry in std::fs::read_dir(&self.root)?.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(TEMP_PREFIX) {
+                continue;
+            }
+            let Some(meta) = look(&entry) else { continue };
+            if meta.is_file() {
+                if stale(&meta) && std::fs::remove_file(entry.path()).is_ok() {
+                    swept.files += 1;
+                    swept.bytes += meta.len();
+                }
+                continue;
+            }
+            if !meta.is_dir() || !name.starts_with(INSTANCE_PREFIX) {
+                continue;
+            }
+            let ours = entry.path() == self.dir;
+            let orphaned = !ours && stale(&meta);
+            let Ok(files) = std::fs::read_dir(entry.path()) else { continue };
+            for file in files.flatten() {
+                let Some(meta) = look(&file) else { continue };
+                if meta.is_file() && (orphaned || stale(&meta)) && std::fs::remove_file(file.path()).is_ok() {
+                    swept.files += 1;
+                    swept.bytes += meta.len();
+                }
+            }
+            if orphaned {
+                let _ = std::fs::remove_dir(entry.path());
+            }
+        }
+        Ok(swept)
+    }
+}
+
+impl Drop for TempManager {
+    fn drop(&mut self) {
+        // Every temp file has gone by now, since each holds on to its manager
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove the temp directory {}: {}", self.dir.display(), e);
+            }
+        }
+    }
+}
+
+/// A temp file that deletes itself when dropped, whether that's after the
+/// response was sent, on an error, or while unwinding from a panic, and
+/// gives back the temp space it took.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    manager: Arc<TempManager>,
+    /// Counted against the manager's `max_bytes`
+    bytes: AtomicU64,
+}
+
+impl TempFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Count `bytes` about to be written to the file, refusing them if that
+    /// would take the temp files past what they're allowed.
+    pub fn grew(&self, bytes: usize) -> Result<()> {
+        self.manager.reserve(bytes as u64)?;
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        self.manager.used.fetch_sub(*self.bytes.get_mut(), Ordering::Relaxed);
+        let path = std::mem::take(&mut self.path);
+        // On the blocking pool when there is one, so as not to hold up a
+        // worker on the disk. One the runtime never gets to, as it shuts
+        // down, is left for a later start's sweep.
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(move || remove(&path))),
+            Err(_) => remove(&path),
+        }
+    }
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("Failed to remove temp file {}: {}", path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use crate::encoder::GifSettings;
+
+/// A reduced encoding profile for conversions started while we're busy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    /// At most 480 px wide, 15 fps, quality 70
+    Medium,
+    /// At most 320 px wide, 10 fps, quality 50, fast mode
+    Low,
+}
+
+impl Tier {
+    /// What the `X-FastGIF-Degraded` header says.
+    pub fn header_value(self) -> &'static str {
+        match self {
+            Tier::Medium => "medium",
+            Tier::Low => "low",
+        }
+    }
+
+    /// `settings`, cut down to this tier. Settings that are already below it
+    /// are left as they are.
+    pub fn apply(self, settings: GifSettings) -> GifSettings {
+        let (max_width, fps, quality) = match self {
+            Tier::Medium => (480, 15.0, 70),
+            Tier::Low => (320, 10.0, 50),
+        };
+        GifSettings {
+            quality: settings.quality.min(quality),
+            fast: settings.fast || self == Tier::Low,
+            fps: settings.fps.min(fps),
+            max_width: Some(settings.max_width.map_or(max_width, |width| width.min(max_width))),
+            ..settings
+        }
+    }
+}
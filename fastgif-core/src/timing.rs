@@ -0,0 +1,275 @@
+use crate::process::ResourceUsage;
+use crate::upstream::Transfer;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::Span;
+
+/// Where one conversion's time went, filled in by its stages as they finish.
+/// Clones share the same times, so a segmented or retried conversion adds up
+/// to its slowest piece.
+#[derive(Debug, Clone)]
+pub struct StageTimes {
+    started: Instant,
+    stages: Arc<Mutex<Stages>>,
+    /// Bytes through the pipes so far, counted without taking the lock, since
+    /// every chunk does it
+    bytes: Arc<ByteCounts>,
+}
+
+#[derive(Debug, Default)]
+struct ByteCounts {
+    piped: AtomicU64,
+    collected: AtomicU64,
+}
+
+impl Default for StageTimes {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            stages: Arc::default(),
+            bytes: Arc::default(),
+        }
+    }
+}
+
+/// A snapshot of [`StageTimes`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stages {
+    /// Until the upstream's response headers, when we fetch the video ourselves
+    pub upstream_ttfb: Option<Duration>,
+    /// The slowest of the upstream requests' phases: looking its host up,
+    /// connecting to it, and reading the body after the headers
+    pub upstream_dns: Option<Duration>,
+    pub upstream_connect: Option<Duration>,
+    pub upstream_download: Option<Duration>,
+    /// What the upstream last answered the video's request with
+    pub upstream_status: Option<u16>,
+    /// From spawning ffmpeg until it exited
+    pub ffmpeg: Option<Duration>,
+    /// From spawning gifski (or starting the native encoder) until it was done
+    pub gifski: Option<Duration>,
+    /// From the start of the conversion until the first bytes of GIF
+    pub first_byte: Option<Duration>,
+    last_byte: Option<Duration>,
+    output_bytes: u64,
+    /// How much of the video we downloaded, when we fetch it ourselves
+    pub input_bytes: Option<u64>,
+    /// CPU time, user and system, that ffmpeg and the gifski binary took
+    /// between them, over every piece of the conversion
+    pub cpu: Duration,
+    /// The most memory any one of ffmpeg and the gifski binary had at once
+    pub max_rss_bytes: u64,
+    /// What ffmpeg decoded the video with: a hardware decoder like `vaapi`,
+    /// or `software`, if any piece of it was
+    pub decode: Option<&'static str>,
+    // When ffmpeg and the encoder were started, since the start of the conversion
+    ffmpeg_spawned: Option<Duration>,
+    gifski_spawned: Option<Duration>,
+}
+
+impl StageTimes {
+    pub fn upstream_responded(&self, took: Duration) {
+        let mut stages = self.stages.lock().unwrap();
+        stages.upstream_ttfb = stages.upstream_ttfb.max(Some(took));
+    }
+
+    /// A request for the video is over, having gone as far as `transfer`
+    /// says.
+    pub fn upstream_fetched(&self, transfer: &Transfer) {
+        let mut stages = self.stages.lock().unwrap();
+        stages.upstream_dns = stages.upstream_dns.max(transfer.dns);
+        stages.upstream_connect = stages.upstream_connect.max(transfer.connect);
+        stages.upstream_download = stages.upstream_download.max(transfer.download);
+        stages.upstream_status = transfer.status.or(stages.upstream_status);
+    }
+
+    /// ffmpeg has just been spawned.
+    pub fn ffmpeg_started(&self) {
+        let now = self.started.elapsed();
+        self.stages.lock().unwrap().ffmpeg_spawned.get_or_insert(now);
+    }
+
+    /// The encoder has just been started.
+    pub fn gifski_started(&self) {
+        let now = self.started.elapsed();
+        self.stages.lock().unwrap().gifski_spawned.get_or_insert(now);
+    }
+
+    /// ffmpeg, spawned at `spawned`, has just exited.
+    pub fn ffmpeg_exited(&self, spawned: Instant) {
+        let mut stages = self.stages.lock().unwrap();
+        stages.ffmpeg = stages.ffmpeg.max(Some(spawned.elapsed()));
+    }
+
+    /// The encoder, started at `spawned`, has just finished.
+    pub fn gifski_exited(&self, spawned: Instant) {
+        let mut stages = self.stages.lock().unwrap();
+        stages.gifski = stages.gifski.max(Some(spawned.elapsed()));
+    }
+
+    /// A child that's exited used `usage`: its CPU time adds to the rest,
+    /// and its memory counts if it's the most yet.
+    pub fn child_used(&self, usage: ResourceUsage) {
+        let mut stages = self.stages.lock().unwrap();
+        stages.cpu += usage.user + usage.system;
+        stages.max_rss_bytes = stages.max_rss_bytes.max(usage.max_rss_bytes);
+    }
+
+    /// ffmpeg is decoding with `path`, unless something already said what
+    /// the video is decoded with, like a decode it's shared.
+    pub fn decoding_with(&self, path: &'static str) {
+        self.stages.lock().unwrap().decode.get_or_insert(path);
+    }
+
+    /// ffmpeg has had to decode some of the video in software after all.
+    /// Once any piece of the conversion has been, the whole of it counts as
+    /// that.
+    pub fn decoded_in_software(&self) {
+        self.stages.lock().unwrap().decode = Some(crate::hwaccel::SOFTWARE);
+    }
+
+    /// The video came to this conversion's ffmpeg through a decode it
+    /// shared, so what came in from the upstream, and how, is what that
+    /// fetched, not the frames ffmpeg was fed.
+    pub fn fed_by(&self, decode: &StageTimes) {
+        let fetched = decode.snapshot();
+        let mut stages = self.stages.lock().unwrap();
+        stages.input_bytes = fetched.input_bytes;
+        stages.upstream_dns = fetched.upstream_dns;
+        stages.upstream_connect = fetched.upstream_connect;
+        stages.upstream_download = fetched.upstream_download;
+        stages.upstream_status = fetched.upstream_status;
+    }
+
+    /// `bytes` more of the video came in from the upstream.
+    pub fn downloaded(&self, bytes: usize) {
+        let mut stages = self.stages.lock().unwrap();
+        stages.input_bytes = Some(stages.input_bytes.unwrap_or(0) + bytes as u64);
+    }
+
+    /// `bytes` more of the video have been written to ffmpeg.
+    pub fn piped(&self, bytes: usize) {
+        self.bytes.piped.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes of the video written to ffmpeg so far, when we feed it ourselves.
+    pub fn bytes_piped(&self) -> u64 {
+        self.bytes.piped.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of output read from the encoder so far.
+    pub fn bytes_collected(&self) -> u64 {
+        self.bytes.collected.load(Ordering::Relaxed)
+    }
+
+    fn output(&self, bytes: usize) {
+        self.bytes.collected.fetch_add(bytes as u64, Ordering::Relaxed);
+        let now = self.started.elapsed();
+        let mut stages = self.stages.lock().unwrap();
+        stages.first_byte = Some(stages.first_byte.map_or(now, |first| first.min(now)));
+        stages.last_byte = stages.last_byte.max(Some(now));
+        stages.output_bytes += bytes as u64;
+    }
+
+    pub fn snapshot(&self) -> Stages {
+        *self.stages.lock().unwrap()
+    }
+
+    /// Like [`snapshot`](Self::snapshot), but with ffmpeg and the encoder
+    /// timed up to now if they've started and are still going, for looking
+    /// at a conversion that hasn't finished.
+    pub fn so_far(&self) -> Stages {
+        let now = self.started.elapsed();
+        let mut stages = self.snapshot();
+        stages.ffmpeg = stages.ffmpeg.or(stages.ffmpeg_spawned.map(|spawned| now.saturating_sub(spawned)));
+        stages.gifski = stages.gifski.or(stages.gifski_spawned.map(|spawned| now.saturating_sub(spawned)));
+        stages
+    }
+}
+
+impl Stages {
+    /// How fast the GIF came out of the encoder, from its first byte to its last.
+    pub fn pipe_bytes_per_sec(&self) -> Option<u64> {
+        let elapsed = self.last_byte?.checked_sub(self.first_byte?)?;
+        if elapsed.is_zero() {
+            return None;
+        }
+        Some((self.output_bytes as f64 / elapsed.as_secs_f64()) as u64)
+    }
+
+    /// Record the times on `span`, which has to have declared the fields.
+    /// Stages that didn't happen stay empty.
+    pub fn record(&self, span: &Span) {
+        let stages = [
+            ("upstream_dns_ms", self.upstream_dns),
+            ("upstream_connect_ms", self.upstream_connect),
+            ("upstream_ttfb_ms", self.upstream_ttfb),
+            ("upstream_download_ms", self.upstream_download),
+            ("ffmpeg_ms", self.ffmpeg),
+            ("gifski_ms", self.gifski),
+            ("first_byte_ms", self.first_byte),
+        ];
+        for (field, took) in stages {
+            if let Some(took) = took {
+                span.record(field, took.as_millis() as u64);
+            }
+        }
+        if let Some(bytes) = self.input_bytes {
+            span.record("upstream_bytes", bytes);
+        }
+        if let Some(status) = self.upstream_status {
+            span.record("upstream_status", status);
+        }
+        if let Some(rate) = self.pipe_bytes_per_sec() {
+            span.record("pipe_bytes_per_sec", rate);
+        }
+    }
+
+    /// The same times as a `Server-Timing` header, along with the time spent
+    /// queueing. Stages that haven't finished (or didn't happen) are left out.
+    pub fn server_timing(&self, queue_wait: Duration) -> String {
+        let stages = [
+            ("queue", Some(queue_wait)),
+            ("upstream-dns", self.upstream_dns),
+            ("upstream-connect", self.upstream_connect),
+            ("upstream", self.upstream_ttfb),
+            ("upstream-download", self.upstream_download),
+            ("ffmpeg", self.ffmpeg),
+            ("gifski", self.gifski),
+            ("first-byte", self.first_byte),
+        ];
+        stages
+            .into_iter()
+            .filter_map(|(name, took)| Some(format!("{};dur={}", name, took?.as_millis())))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Notes when the encoder's output arrives, on its way to being collected.
+pub struct TimedReader<R> {
+    inner: R,
+    times: StageTimes,
+}
+
+impl<R> TimedReader<R> {
+    pub fn new(inner: R, times: StageTimes) -> Self {
+        Self { inner, times }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TimedReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        if read > 0 {
+            self.times.output(read);
+        }
+        result
+    }
+}
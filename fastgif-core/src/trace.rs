@@ -0,0 +1,21 @@
+/// Where a conversion sits in a W3C trace: the trace, the span it's part of,
+/// and the span that called that one, if any. It goes along on requests to
+/// the upstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 32 lowercase hex digits
+    pub trace_id: String,
+    /// Ours, 16 lowercase hex digits
+    pub span_id: String,
+    /// The caller's, when we're part of the trace it started
+    pub parent_span_id: Option<String>,
+    pub flags: u8,
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// The `traceparent` for requests made from our span.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+}
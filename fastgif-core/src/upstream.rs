@@ -0,0 +1,130 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How far back a host's health goes.
+pub const HEALTH_WINDOW: Duration = Duration::from_secs(60);
+
+/// The most requests to one host a health summary remembers, however many
+/// there were in the window.
+const REMEMBERED: usize = 1024;
+
+/// How one request to the upstream went, as far as it got. Each phase is
+/// only there if the request went through it: a host that's already pinned
+/// isn't looked up, and a pooled connection isn't connected again.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Transfer {
+    /// Looking the host up
+    pub dns: Option<Duration>,
+    /// Connecting to it, TLS included
+    pub connect: Option<Duration>,
+    /// From sending the request until the response's headers, the connect
+    /// included
+    pub ttfb: Option<Duration>,
+    /// From the headers until the body's last byte, if it was all read
+    pub download: Option<Duration>,
+    /// Of the body, as far as it was read
+    pub bytes: u64,
+    pub status: Option<u16>,
+    /// It got no response, a 5xx, or a body that broke off
+    pub failed: bool,
+}
+
+/// How each upstream host has been doing over the last `HEALTH_WINDOW`,
+/// going by every request to it, for telling a slow or failing upstream
+/// from a slow conversion. Reported on the stats endpoint, and what anything
+/// deciding whether to stop asking a host should go by.
+#[derive(Debug, Default)]
+pub struct UpstreamHealth {
+    hosts: Mutex<HashMap<String, VecDeque<Outcome>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Outcome {
+    at: Instant,
+    transfer: Transfer,
+}
+
+/// A host's [`UpstreamHealth`].
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct HostHealth {
+    pub requests: u64,
+    pub failures: u64,
+    /// Of the requests, between 0 and 1
+    pub failure_rate: f64,
+    pub ttfb_p50_ms: Option<u64>,
+    pub ttfb_p95_ms: Option<u64>,
+    /// How fast bodies that were read to the end came in, all together
+    pub bytes_per_sec: Option<u64>,
+}
+
+impl UpstreamHealth {
+    /// A request to `host` is over.
+    pub fn record(&self, host: &str, transfer: &Transfer) {
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock().unwrap();
+        let outcomes = match hosts.get_mut(host) {
+            Some(outcomes) => outcomes,
+            None => hosts.entry(host.to_string()).or_default(),
+        };
+        forget_old(outcomes, now);
+        if outcomes.len() == REMEMBERED {
+            outcomes.pop_front();
+        }
+        outcomes.push_back(Outcome { at: now, transfer: *transfer });
+    }
+
+    /// How `host` has been doing, which is nothing to go by if it hasn't
+    /// been asked anything lately.
+    pub fn of(&self, host: &str) -> HostHealth {
+        let mut hosts = self.hosts.lock().unwrap();
+        match hosts.get_mut(host) {
+            Some(outcomes) => {
+                forget_old(outcomes, Instant::now());
+                summarize(outcomes)
+            }
+            None => HostHealth::default(),
+        }
+    }
+
+    /// Every host asked anything lately, and how it's been doing.
+    pub fn summary(&self) -> BTreeMap<String, HostHealth> {
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.retain(|_, outcomes| {
+            forget_old(outcomes, now);
+            !outcomes.is_empty()
+        });
+        hosts.iter().map(|(host, outcomes)| (host.clone(), summarize(outcomes))).collect()
+    }
+}
+
+fn forget_old(outcomes: &mut VecDeque<Outcome>, now: Instant) {
+    while outcomes.front().is_some_and(|outcome| now.duration_since(outcome.at) > HEALTH_WINDOW) {
+        outcomes.pop_front();
+    }
+}
+
+fn summarize(outcomes: &VecDeque<Outcome>) -> HostHealth {
+    let requests = outcomes.len() as u64;
+    let failures = outcomes.iter().filter(|outcome| outcome.transfer.failed).count() as u64;
+    let mut ttfbs: Vec<Duration> = outcomes.iter().filter_map(|outcome| outcome.transfer.ttfb).collect();
+    ttfbs.sort_unstable();
+    let percentile = |p: f64| {
+        let last = ttfbs.len().checked_sub(1)?;
+        Some(ttfbs[(last as f64 * p).round() as usize].as_millis() as u64)
+    };
+    let (bytes, took) = outcomes
+        .iter()
+        .filter_map(|outcome| Some((outcome.transfer.bytes, outcome.transfer.download?)))
+        .fold((0, Duration::ZERO), |(bytes, took), (more, longer)| (bytes + more, took + longer));
+    HostHealth {
+        requests,
+        failures,
+        failure_rate: if requests == 0 { 0.0 } else { failures as f64 / requests as f64 },
+        ttfb_p50_ms: percentile(0.5),
+        ttfb_p95_ms: percentile(0.95),
+        bytes_per_sec: (!took.is_zero()).then(|| (bytes as f64 / took.as_secs_f64()) as u64),
+    }
+}
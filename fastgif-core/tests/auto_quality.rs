@@ -0,0 +1,78 @@
+//! Puts videos of every length, size and bit rate in their buckets, and
+//! checks each bucket's profile and what it makes of the configured settings.
+
+use fastgif_core::auto_quality::{Bucket, Length, Profile, Size};
+use fastgif_core::GifSettings;
+use std::time::Duration;
+
+fn secs(secs: f64) -> Duration {
+    Duration::from_secs_f64(secs)
+}
+
+#[test]
+fn videos_go_in_the_bucket_for_their_length_size_and_bit_rate() {
+    let cases = [
+        // Up to and including the edges
+        (secs(10.0), 640, 480, None, Length::Short, Size::Sd, false),
+        (secs(10.001), 640, 480, None, Length::Medium, Size::Sd, false),
+        (secs(30.0), 1280, 720, None, Length::Medium, Size::Hd, false),
+        (secs(30.5), 1280, 721, None, Length::Long, Size::FullHd, false),
+        // Portrait by its shorter side
+        (secs(4.0), 720, 1280, None, Length::Short, Size::Hd, false),
+        (secs(4.0), 1080, 1920, None, Length::Short, Size::FullHd, false),
+        // Busy at 4 bits a second per pixel
+        (secs(4.0), 1280, 720, Some(3_686_400), Length::Short, Size::Hd, true),
+        (secs(4.0), 1280, 720, Some(3_686_399), Length::Short, Size::Hd, false),
+        (secs(4.0), 0, 0, Some(1), Length::Short, Size::Sd, false),
+    ];
+    for (duration, width, height, bit_rate, length, size, busy) in cases {
+        let bucket = Bucket::of(duration, width, height, bit_rate);
+        assert_eq!(bucket, Bucket { length, size, busy }, "{:?} of {}x{} at {:?}", duration, width, height, bit_rate);
+    }
+}
+
+#[test]
+fn every_bucket_has_its_profile() {
+    let profile = |quality, fps, max_width| Profile { quality, fps, max_width };
+    let table = [
+        (Length::Short, Size::Sd, profile(100, None, None)),
+        (Length::Short, Size::Hd, profile(90, None, Some(480))),
+        (Length::Short, Size::FullHd, profile(80, Some(15.0), Some(480))),
+        (Length::Medium, Size::Sd, profile(80, Some(15.0), None)),
+        (Length::Medium, Size::Hd, profile(70, Some(15.0), Some(400))),
+        (Length::Medium, Size::FullHd, profile(70, Some(12.0), Some(360))),
+        (Length::Long, Size::Sd, profile(60, Some(10.0), Some(360))),
+        (Length::Long, Size::Hd, profile(50, Some(10.0), Some(320))),
+        (Length::Long, Size::FullHd, profile(50, Some(8.0), Some(320))),
+    ];
+    for (length, size, expected) in table {
+        let calm = Bucket { length, size, busy: false };
+        assert_eq!(calm.profile(), expected, "{}", calm);
+        let busy = Bucket { busy: true, ..calm };
+        assert_eq!(busy.profile(), Profile { quality: expected.quality - 10, ..expected }, "{}", busy);
+    }
+}
+
+#[test]
+fn buckets_only_ever_lower_the_settings() {
+    let configured = GifSettings::default();
+    let bucket = Bucket { length: Length::Long, size: Size::Hd, busy: true };
+    let held = bucket.apply(configured);
+    assert_eq!((held.quality, held.fps, held.max_width), (40, 10.0, Some(320)));
+    assert_eq!(held.fast, configured.fast);
+
+    // Settings already below the profile stay where they are
+    let low = GifSettings { quality: 30, fps: 5.0, max_width: Some(240), ..configured };
+    let held = bucket.apply(low);
+    assert_eq!((held.quality, held.fps, held.max_width), (30, 5.0, Some(240)));
+
+    // and a short calm video is converted as configured
+    let bucket = Bucket { length: Length::Short, size: Size::Sd, busy: false };
+    assert_eq!(bucket.apply(configured), configured);
+}
+
+#[test]
+fn buckets_are_named_for_the_header() {
+    assert_eq!(Bucket::of(secs(20.0), 1280, 720, None).to_string(), "medium-hd-calm");
+    assert_eq!(Bucket::of(secs(60.0), 1920, 1080, Some(50_000_000)).to_string(), "long-fullhd-busy");
+}
@@ -0,0 +1,142 @@
+//! Cancels conversions at different points along the pipeline, with a shell
+//! script standing in for ffmpeg, and checks each one stops with nothing left
+//! running: not the script, and not anything it started.
+
+#![cfg(target_os = "linux")]
+
+use bytes::Bytes;
+use fastgif_core::fetch::{SourceFetcher, SourceRef, SourceStream};
+use fastgif_core::pipeline::ConversionHandle;
+use fastgif_core::{ConversionError, ConversionOptions, ConversionOutput, Converter, CoreConfig, Encoder, Source};
+use futures_util::future::{self, BoxFuture};
+use futures_util::stream::{self, StreamExt};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// How long a cancelled conversion gets to have stopped, which is comfortably
+// more than the children are given to exit after SIGTERM
+const TEARDOWN: Duration = Duration::from_secs(5);
+
+// What the fetcher does with the video
+#[derive(Debug, Clone, Copy)]
+enum Feed {
+    /// Never answers
+    Stalled,
+    /// Sends the first bit of it and then nothing more
+    Trickle,
+    /// Sends all of it
+    Whole,
+}
+
+impl SourceFetcher for Feed {
+    fn fetch<'a>(&'a self, _: &'a SourceRef) -> BoxFuture<'a, Result<SourceStream, ConversionError>> {
+        let chunk = || Ok(Bytes::from_static(b"video"));
+        match self {
+            Feed::Stalled => Box::pin(future::pending()),
+            Feed::Trickle => Box::pin(future::ready(Ok(stream::iter([chunk()]).chain(stream::pending()).boxed()))),
+            Feed::Whole => Box::pin(future::ready(Ok(stream::iter([chunk()]).boxed()))),
+        }
+    }
+}
+
+// A directory of the test's own with an ffmpeg in it that runs `script` when
+// it's converting, and otherwise (asked what it can do, say) does nothing
+fn fake_ffmpeg(test: &str, script: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fastgif-cancellation-{}-{}", test, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let ffmpeg = dir.join("ffmpeg");
+    let converting = "case \"$*\" in *pipe:0*) ;; *) exit 0 ;; esac";
+    std::fs::write(&ffmpeg, format!("#!/bin/sh\n{}\ncd {}\n{}\n", converting, dir.display(), script)).unwrap();
+    std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+    dir
+}
+
+fn start(dir: &Path, feed: Feed) -> ConversionHandle<Result<ConversionOutput, ConversionError>> {
+    let config = CoreConfig {
+        ffmpeg_path: Some(dir.join("ffmpeg")),
+        encoder: Some(Encoder::Ffmpeg),
+        skip_binary_check: true,
+        ..CoreConfig::default()
+    };
+    let mut converter = Converter::new(config).unwrap();
+    converter.set_source_fetcher(Arc::new(feed));
+    converter.spawn(Source::Url("https://video.twimg.com/tweet_video/abc.mp4".to_string()), &ConversionOptions::default())
+}
+
+// The pid the script wrote to `name`, once it has
+async fn pid(dir: &Path, name: &str) -> u32 {
+    let started = Instant::now();
+    loop {
+        let pid = std::fs::read_to_string(dir.join(name)).ok().and_then(|pid| pid.trim().parse().ok());
+        if let Some(pid) = pid {
+            return pid;
+        }
+        assert!(started.elapsed() < TEARDOWN, "ffmpeg didn't write {}", name);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+// Whether `pid` is still running, as opposed to gone or a zombie waiting for
+// its parent. Orphans are reaped by whoever adopts them, so this waits a bit
+// for that.
+async fn running(pid: u32) -> bool {
+    let started = Instant::now();
+    loop {
+        let state = std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .ok()
+            .and_then(|stat| stat.rsplit_once(") ")?.1.chars().next());
+        if !matches!(state, Some(state) if state != 'Z') {
+            return false;
+        }
+        if started.elapsed() > Duration::from_secs(2) {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+async fn cancel(conversion: ConversionHandle<Result<ConversionOutput, ConversionError>>) {
+    conversion.cancel();
+    let result = tokio::time::timeout(TEARDOWN, conversion)
+        .await
+        .expect("the conversion didn't stop")
+        .unwrap();
+    assert!(matches!(result, Err(ConversionError::Cancelled)), "{:?}", result);
+}
+
+#[tokio::test]
+async fn cancelled_before_ffmpeg_is_started() {
+    let dir = fake_ffmpeg("before-spawn", "touch started\nexec cat");
+    let conversion = start(&dir, Feed::Stalled);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    cancel(conversion).await;
+    assert!(!dir.join("started").exists(), "ffmpeg was started anyway");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn cancelled_while_feeding_ffmpeg() {
+    // Waits on more video, with a child of its own in its process group
+    let dir = fake_ffmpeg("mid-pipe", "sleep 30 &\necho $! > child\necho $$ > pid\nexec cat");
+    let conversion = start(&dir, Feed::Trickle);
+    let (ffmpeg, child) = (pid(&dir, "pid").await, pid(&dir, "child").await);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    cancel(conversion).await;
+    assert!(!running(ffmpeg).await, "ffmpeg is still running");
+    assert!(!running(child).await, "ffmpeg's child is still running");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn cancelled_while_collecting_the_gif() {
+    // Has all the video, and has started on the GIF, but doesn't finish it
+    let dir = fake_ffmpeg("collection", "echo $$ > pid\nprintf GIF89a\nexec sleep 30");
+    let conversion = start(&dir, Feed::Whole);
+    let ffmpeg = pid(&dir, "pid").await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    cancel(conversion).await;
+    assert!(!running(ffmpeg).await, "ffmpeg is still running");
+    let _ = std::fs::remove_dir_all(&dir);
+}
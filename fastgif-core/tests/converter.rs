@@ -0,0 +1,57 @@
+//! Converts through the library the way anyone using it would, with a
+//! shell script standing in for ffmpeg that passes on whatever it's fed.
+
+#![cfg(unix)]
+
+use fastgif_core::pipeline::GifOutput;
+use fastgif_core::{ConversionOptions, Converter, CoreConfig, Encoder, Source};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+// A whole GIF, of one pixel, for the video to be
+const PIXEL: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff\
+    !\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+
+// A directory of the test's own with that ffmpeg in it
+fn fake_ffmpeg(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fastgif-converter-{}-{}", test, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let ffmpeg = dir.join("ffmpeg");
+    std::fs::write(&ffmpeg, "#!/bin/sh\nexec cat\n").unwrap();
+    std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+    dir
+}
+
+fn converter(dir: &std::path::Path) -> Converter {
+    Converter::new(CoreConfig {
+        ffmpeg_path: Some(dir.join("ffmpeg")),
+        encoder: Some(Encoder::Ffmpeg),
+        skip_binary_check: true,
+        ..CoreConfig::default()
+    })
+    .unwrap()
+}
+
+#[tokio::test]
+async fn files_are_converted_where_they_are() {
+    let dir = fake_ffmpeg("path");
+    std::fs::write(dir.join("abc.mp4"), PIXEL).unwrap();
+    let converter = converter(&dir);
+
+    let output = converter.convert(Source::Path(dir.join("abc.mp4")), &ConversionOptions::default()).await.unwrap();
+    match output {
+        GifOutput::Memory(gif) => assert_eq!(&gif[..], PIXEL),
+        other => panic!("expected the GIF in memory, got {} bytes elsewhere", other.len()),
+    }
+    assert!(converter.convert(Source::Path(dir.join("missing.mp4")), &ConversionOptions::default()).await.is_err());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn missing_binaries_are_refused_up_front() {
+    let config = CoreConfig {
+        ffmpeg_path: Some(PathBuf::from("/nonexistent/ffmpeg")),
+        ..CoreConfig::default()
+    };
+    assert!(Converter::new(config).is_err());
+}
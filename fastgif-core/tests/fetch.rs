@@ -0,0 +1,214 @@
+//! Fetches from an upstream on this host by its name, and checks every
+//! request for a source goes where the name was first found, unless nothing
+//! answers there, and that asking about a source again goes by what the
+//! upstream says, and that every request's phases are counted.
+
+use fastgif_core::dns::{connectable, IpPreference};
+use fastgif_core::fetch::{Fetcher, FetcherConfig, PinnedAddrs, Revalidation, SourceFetcher, SourceRef, Validators};
+use fastgif_core::metrics::{ConversionMetrics, NoMetrics};
+use fastgif_core::timing::StageTimes;
+use fastgif_core::upstream::Transfer;
+use futures_util::StreamExt;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+
+// Answers everything with the same small body, on 127.0.0.1 only
+fn serve_upstream() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            // Skip the request; none of these have a body
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                line.clear();
+            }
+            let _ = writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nvideo");
+        }
+    });
+    port
+}
+
+// Answers `/tweet_video/{status}.mp4` with that status, except that a
+// `200` has an `ETag` of `"v1"`, and is a `304` when that's what's asked with
+fn serve_statuses() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                request.push_str(&line);
+                line.clear();
+            }
+            let status = request.split("/tweet_video/").nth(1).and_then(|path| path.get(..3)).unwrap_or("500");
+            let response = match status {
+                "200" if request.to_lowercase().contains("if-none-match: \"v1\"") => "304 Not Modified\r\n".to_string(),
+                "200" => "200 OK\r\nETag: \"v1\"\r\nContent-Length: 5\r\n".to_string(),
+                status => format!("{} Whatever\r\nContent-Length: 0\r\n", status),
+            };
+            let _ = write!(writer, "HTTP/1.1 {}Connection: close\r\n\r\n", response);
+        }
+    });
+    port
+}
+
+fn source(port: u16) -> SourceRef {
+    source_at(port, "abc")
+}
+
+fn source_at(port: u16, name: &str) -> SourceRef {
+    SourceRef {
+        path: format!("{}.mp4", name),
+        url: format!("http://localhost:{}/tweet_video/{}.mp4", port, name),
+        max_bytes: None,
+        trace: None,
+        pinned: PinnedAddrs::default(),
+        timings: StageTimes::default(),
+    }
+}
+
+async fn fetch(fetcher: &Fetcher, source: &SourceRef) -> Vec<u8> {
+    let mut body = fetcher.fetch(source).await.unwrap();
+    let mut fetched = Vec::new();
+    while let Some(chunk) = body.next().await {
+        fetched.extend_from_slice(&chunk.unwrap());
+    }
+    fetched
+}
+
+fn fetcher(retries: u32) -> Fetcher {
+    Fetcher::new(&FetcherConfig { retries, ..FetcherConfig::default() }, Arc::new(NoMetrics)).unwrap()
+}
+
+#[tokio::test]
+async fn a_source_is_pinned_to_where_its_host_was_found() {
+    let port = serve_upstream();
+    let fetcher = fetcher(1);
+    let source = source(port);
+    assert_eq!(fetch(&fetcher, &source).await, b"video");
+    let pinned = source.pinned.get("localhost").unwrap();
+    assert!(pinned.iter().all(|addr| addr.ip().is_loopback()), "{:?}", pinned);
+
+    // and stays there, for its clones too
+    let retried = source.clone();
+    assert_eq!(fetch(&fetcher, &retried).await, b"video");
+    assert_eq!(retried.pinned.get("localhost"), Some(pinned));
+}
+
+#[tokio::test]
+async fn a_host_nothing_answers_for_where_it_was_is_looked_up_again() {
+    let port = serve_upstream();
+    // Without any retries, so it's not one
+    let fetcher = fetcher(0);
+    let source = source(port);
+    let nowhere = SocketAddr::from(([127, 0, 0, 2], 0));
+    source.pinned.set("localhost", vec![nowhere]);
+    assert_eq!(fetch(&fetcher, &source).await, b"video");
+    let pinned = source.pinned.get("localhost").unwrap();
+    assert!(!pinned.contains(&nowhere), "{:?}", pinned);
+}
+
+#[tokio::test]
+async fn a_source_is_asked_about_again_with_what_the_upstream_said_last() {
+    let port = serve_statuses();
+    let fetcher = fetcher(0);
+    let source = source_at(port, "200");
+    let first = fetcher.revalidate(&source, &Validators::default()).await;
+    let validators = Validators { etag: Some("\"v1\"".to_string()), last_modified: None };
+    assert_eq!(first, Revalidation::Current(validators.clone()));
+    assert_eq!(fetcher.revalidate(&source, &validators).await, Revalidation::Current(validators));
+
+    // but one that's changed since isn't current
+    let old = Validators { etag: Some("\"v0\"".to_string()), last_modified: None };
+    assert!(matches!(fetcher.revalidate(&source, &old).await, Revalidation::Unknown(_)));
+    for status in [404, 410] {
+        let source = source_at(port, &status.to_string());
+        assert_eq!(fetcher.revalidate(&source, &Validators::default()).await, Revalidation::Gone(status));
+    }
+    let failing = source_at(port, "503");
+    assert!(matches!(fetcher.revalidate(&failing, &Validators::default()).await, Revalidation::Unknown(_)));
+}
+
+#[tokio::test]
+async fn only_the_preferred_family_is_connected_to() {
+    // The upstream's on 127.0.0.1 only, so whatever else localhost is at
+    // can't be connected to, and auto falls back to IPv4 from it
+    let port = serve_upstream();
+    for preference in [IpPreference::Auto, IpPreference::V4] {
+        let config = FetcherConfig { retries: 0, ip_preference: preference, ..FetcherConfig::default() };
+        let fetcher = Fetcher::new(&config, Arc::new(NoMetrics)).unwrap();
+        let source = source(port);
+        assert_eq!(fetch(&fetcher, &source).await, b"video", "{:?}", preference);
+        let stats = fetcher.stats();
+        assert_eq!((stats.ipv4, stats.ipv6), (1, 0), "{:?}", preference);
+    }
+
+    let config = FetcherConfig { retries: 0, ip_preference: IpPreference::V6, ..FetcherConfig::default() };
+    let fetcher = Fetcher::new(&config, Arc::new(NoMetrics)).unwrap();
+    assert!(fetcher.fetch(&source(port)).await.is_err());
+    assert_eq!(fetcher.stats().ipv4, 0);
+    assert_eq!("v6".parse(), Ok(IpPreference::V6));
+    assert!("ipv6".parse::<IpPreference>().is_err());
+}
+
+// Every transfer reported, by host
+#[derive(Debug, Default)]
+struct Transfers(Mutex<Vec<(String, Transfer)>>);
+
+impl ConversionMetrics for Transfers {
+    fn upstream_transfer(&self, host: &str, transfer: &Transfer) {
+        self.0.lock().unwrap().push((host.to_string(), *transfer));
+    }
+}
+
+#[tokio::test]
+async fn every_request_to_the_upstream_is_timed_and_counted_against_its_host() {
+    let port = serve_upstream();
+    let transfers = Arc::new(Transfers::default());
+    let fetcher = Fetcher::new(&FetcherConfig { retries: 0, ..FetcherConfig::default() }, transfers.clone()).unwrap();
+    let source = source(port);
+    assert_eq!(fetch(&fetcher, &source).await, b"video");
+    let (host, first) = transfers.0.lock().unwrap()[0].clone();
+    assert_eq!(host, "localhost");
+    assert!(first.dns.is_some() && first.connect.is_some() && first.ttfb.is_some() && first.download.is_some(), "{:?}", first);
+    assert_eq!((first.bytes, first.status, first.failed), (5, Some(200), false));
+    let stages = source.timings.snapshot();
+    assert_eq!((stages.upstream_dns, stages.upstream_connect, stages.upstream_status), (first.dns, first.connect, Some(200)));
+
+    // Once it's pinned, the host isn't looked up again
+    assert_eq!(fetch(&fetcher, &source).await, b"video");
+    let again = transfers.0.lock().unwrap()[1].1;
+    assert!(again.dns.is_none() && again.ttfb.is_some(), "{:?}", again);
+
+    // A 5xx is a failure as far as the host's health goes, and a 4xx isn't
+    let statuses = serve_statuses();
+    for status in ["503", "404"] {
+        assert!(fetcher.fetch(&source_at(statuses, status)).await.is_err());
+    }
+    let health = fetcher.health().of("localhost");
+    assert_eq!((health.requests, health.failures), (4, 1));
+    assert_eq!(health.failure_rate, 0.25);
+    assert!(health.ttfb_p50_ms.is_some() && health.bytes_per_sec.is_some(), "{:?}", health);
+    assert_eq!(fetcher.stats().hosts.get("localhost"), Some(&health));
+    let statuses: Vec<_> = transfers.0.lock().unwrap().iter().map(|(_, transfer)| transfer.status).collect();
+    assert_eq!(statuses, [Some(200), Some(200), Some(503), Some(404)]);
+}
+
+#[test]
+fn only_addresses_that_can_be_connected_to_are_pinned() {
+    for ip in ["0.0.0.0", "::", "::ffff:0.0.0.0", "224.0.0.1", "ff02::1", "255.255.255.255"] {
+        assert!(!connectable(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+    }
+    for ip in ["127.0.0.1", "10.1.2.3", "93.184.216.34", "::1", "2606:2800:220:1::"] {
+        assert!(connectable(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+    }
+}
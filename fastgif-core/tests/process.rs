@@ -0,0 +1,139 @@
+//! Finds binaries the way each platform names them, and checks a child that's
+//! terminated, or whose guard is dropped, is gone along with what it started,
+//! with whatever stands in for process groups there. On Linux, also checks
+//! that waiting on a child, and reading what it cost, takes no blocking thread.
+
+use fastgif_core::process::{executable_name, find_executable, Binaries, ChildLimits, ProcessGuard, TERMINATE_GRACE};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::process::{ChildStdout, Command};
+
+// How long a killed child and what it started get to have let go of stdout
+const TEARDOWN: Duration = Duration::from_secs(5);
+
+fn dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fastgif-process-{}-{}", test, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// A program called `name` in `dir`, by the name it'd have here
+fn install(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(executable_name(name));
+    std::fs::write(&path, "#!/bin/sh\n").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    path
+}
+
+// A shell that starts something long-running that shares its stdout, and
+// says "done" once that's finished, which it never should
+fn parent_of_sleeper() -> Command {
+    #[cfg(windows)]
+    {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "ping -n 30 127.0.0.1 & echo done"]);
+        command
+    }
+    #[cfg(not(windows))]
+    {
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 30; echo done"]);
+        command
+    }
+}
+
+fn spawn() -> (ProcessGuard, ChildStdout) {
+    let mut command = parent_of_sleeper();
+    command.stdin(Stdio::null()).stdout(Stdio::piped());
+    let mut guard = ProcessGuard::spawn("sleeper", &mut command, &ChildLimits::default()).unwrap();
+    let stdout = guard.take_stdout().unwrap();
+    (guard, stdout)
+}
+
+// Everything it printed, once nothing has stdout open any more
+async fn read_to_end(mut stdout: ChildStdout) -> String {
+    let mut printed = String::new();
+    tokio::time::timeout(TEARDOWN, stdout.read_to_string(&mut printed))
+        .await
+        .expect("something still had stdout open")
+        .unwrap();
+    printed
+}
+
+#[test]
+fn binaries_are_found_by_their_platform_name() {
+    let dir = dir("find");
+    let ffmpeg = install(&dir, "ffmpeg");
+    assert_eq!(ffmpeg.file_name().unwrap(), if cfg!(windows) { "ffmpeg.exe" } else { "ffmpeg" });
+    let path = std::env::join_paths([dir.join("nowhere"), dir.clone()]).unwrap();
+    assert_eq!(find_executable("ffmpeg", &path), Some(ffmpeg));
+    assert_eq!(find_executable("gifski", &path), None);
+
+    // A file that can't be run isn't it
+    #[cfg(unix)]
+    {
+        std::fs::write(dir.join("gifski"), "").unwrap();
+        assert_eq!(find_executable("gifski", &path), None);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn configured_binaries_can_leave_off_the_extension() {
+    let dir = dir("configured");
+    let ffmpeg = install(&dir, "ffmpeg");
+    let binaries = Binaries::resolve(Some(&dir.join("ffmpeg")), None, None).unwrap();
+    assert_eq!(binaries.ffmpeg.path, Some(ffmpeg));
+
+    let missing = Binaries::resolve(None, Some(&dir.join("ffprobe")), None).unwrap_err();
+    assert!(missing.to_string().contains("isn't an executable file"), "{}", missing);
+    let relative = Binaries::resolve(Some(Path::new("ffmpeg")), None, None).unwrap_err();
+    assert!(relative.to_string().contains("has to be an absolute path"), "{}", relative);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn terminating_takes_what_the_child_started_with_it() {
+    let (mut guard, stdout) = spawn();
+    // Time for the shell to have started its sleeper
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    guard.terminate(TERMINATE_GRACE).await;
+    assert!(!read_to_end(stdout).await.contains("done"));
+}
+
+#[tokio::test]
+async fn dropping_the_guard_does_too() {
+    let (guard, stdout) = spawn();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    drop(guard);
+    assert!(!read_to_end(stdout).await.contains("done"));
+}
+
+// With the only blocking thread taken, a wait that needed one would never end
+#[cfg(target_os = "linux")]
+#[test]
+fn waiting_takes_no_blocking_thread() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .max_blocking_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    let (release, held) = std::sync::mpsc::channel::<()>();
+    let holder = runtime.spawn_blocking(move || held.recv());
+    runtime.block_on(async {
+        let mut command = Command::new("sh");
+        command.args(["-c", "exit 3"]).stdin(Stdio::null());
+        let mut guard = ProcessGuard::spawn("exiter", &mut command, &ChildLimits::default()).unwrap();
+        let status = tokio::time::timeout(TEARDOWN, guard.wait()).await.expect("waiting needed a blocking thread").unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert!(guard.usage().is_some_and(|usage| usage.max_rss_bytes > 0));
+    });
+    release.send(()).unwrap();
+    runtime.block_on(holder).unwrap().unwrap();
+}
@@ -0,0 +1,49 @@
+//! Follows y4m streams the way a watched conversion's frames are counted on
+//! their way to the encoder, however the pipe happens to split them up.
+
+use fastgif_core::progress::{FrameCounter, Progress};
+use std::time::Duration;
+
+// A stream with `header`, then `frames` frames of `len` bytes each
+fn stream(header: &str, frames: usize, len: usize) -> Vec<u8> {
+    let mut stream = format!("{}\n", header).into_bytes();
+    for frame in 0..frames {
+        // The odd frame has parameters of its own, and data that looks like a header
+        stream.extend_from_slice(if frame % 2 == 1 { b"FRAME Ixyz\n" } else { b"FRAME\n" });
+        stream.extend(b"FRAME\n".iter().copied().cycle().take(len));
+    }
+    stream
+}
+
+#[test]
+fn frames_are_counted_however_they_arrive() {
+    let cases = [
+        ("YUV4MPEG2 W16 H16 F10:1 Ip A1:1 C420jpeg", 16 * 16 + 2 * 8 * 8),
+        ("YUV4MPEG2 W5 H3 F30000:1001 C420mpeg2", 15 + 2 * 3 * 2),
+        ("YUV4MPEG2 W4 H2 F25:1 C444", 4 * 2 * 3),
+        ("YUV4MPEG2 W4 H2 F25:1 C422p10", (8 + 2 * 2 * 2) * 2),
+        ("YUV4MPEG2 W7 H3 F25:1", 21 + 2 * 4 * 2),
+    ];
+    for (header, len) in cases {
+        let stream = stream(header, 7, len);
+        for chunk in [1, 5, 64, stream.len()] {
+            let progress = Progress::default();
+            progress.converting(Some(Duration::from_millis(700)));
+            let mut counter = FrameCounter::default();
+            for bytes in stream.chunks(chunk) {
+                counter.feed(bytes, &progress);
+            }
+            let snapshot = progress.snapshot();
+            assert_eq!(snapshot.frames, 7, "{} in chunks of {}", header, chunk);
+            assert!(snapshot.expected_frames.is_some_and(|expected| (7..=21).contains(&expected)), "{}: {:?}", header, snapshot);
+        }
+    }
+
+    // Without a length there's nothing to expect, and what isn't y4m isn't counted
+    let progress = Progress::default();
+    FrameCounter::default().feed(&stream("YUV4MPEG2 W4 H2 F25:1 C444", 3, 24), &progress);
+    assert_eq!((progress.snapshot().frames, progress.snapshot().expected_frames), (3, None));
+    let progress = Progress::default();
+    FrameCounter::default().feed(b"GIF89a, or near enough\nFRAME\n", &progress);
+    assert_eq!(progress.snapshot().frames, 0);
+}
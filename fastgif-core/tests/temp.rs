@@ -0,0 +1,124 @@
+//! Makes temp files through `TempManager`s in a directory of the test's own,
+//! and checks where they go, that they're kept to the budget, and that what
+//! an instance that crashed left behind is swept up by the next one.
+
+use fastgif_core::temp::TempManager;
+use fastgif_core::ConversionError;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+fn setup(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fastgif-temp-{}-{}", test, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn manager(root: &Path, max_bytes: Option<u64>) -> Arc<TempManager> {
+    Arc::new(TempManager::new(root.to_path_buf(), max_bytes, HOUR, None))
+}
+
+// Every file in `root` and the directories in it
+fn files(root: &Path) -> Vec<PathBuf> {
+    let entries = std::fs::read_dir(root).unwrap().flatten().map(|entry| entry.path());
+    entries.flat_map(|path| match path.is_dir() {
+        true => std::fs::read_dir(path).unwrap().flatten().map(|entry| entry.path()).collect(),
+        false => vec![path],
+    })
+    .collect()
+}
+
+// Give the removals on the blocking pool a moment
+async fn settle() {
+    tokio::time::sleep(Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn temp_files_go_in_the_instances_own_directory_and_go_with_it() {
+    let root = setup("own");
+    let temp = manager(&root, None);
+    let (upload, _) = temp.create("upload.mp4").await.unwrap();
+    let (other, _) = temp.create("upload.mp4").await.unwrap();
+    assert_eq!(upload.path().parent(), Some(temp.dir()));
+    assert!(temp.dir().file_name().unwrap().to_string_lossy().starts_with("fastgif-instance-"));
+    let name = upload.path().file_name().unwrap().to_string_lossy().into_owned();
+    assert!(name.starts_with("upload-") && name.ends_with(".mp4"), "{}", name);
+    assert_ne!(upload.path(), other.path());
+
+    let path = upload.path().to_path_buf();
+    drop(upload);
+    settle().await;
+    assert!(!path.exists());
+    assert!(other.path().exists());
+
+    // The directory goes once the manager and everything it made have
+    let dir = temp.dir().to_path_buf();
+    drop(other);
+    drop(temp);
+    settle().await;
+    assert!(!dir.exists());
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[tokio::test]
+async fn temp_files_are_kept_to_the_budget() {
+    let root = setup("budget");
+    let temp = manager(&root, Some(100));
+    let (first, mut handle) = temp.create("output.gif").await.unwrap();
+    first.grew(60).unwrap();
+    handle.write_all(&[0; 60]).await.unwrap();
+    let (second, _) = temp.create("output.gif").await.unwrap();
+    match second.grew(41) {
+        Err(ConversionError::TempSpaceExceeded(100)) => {}
+        other => panic!("{:?}", other),
+    }
+    second.grew(40).unwrap();
+    assert_eq!(temp.used(), 100);
+    // With all of it taken, no more are made
+    assert!(matches!(temp.create("upload").await, Err(ConversionError::TempSpaceExceeded(100))));
+
+    // and what a dropped file took is given back
+    drop(first);
+    assert_eq!(temp.used(), 40);
+    let (third, _) = temp.create("upload").await.unwrap();
+    third.grew(60).unwrap();
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[tokio::test]
+async fn what_a_crashed_instance_left_is_swept_by_the_next() {
+    let root = setup("crashed");
+
+    // One that crashed, long enough ago, and one that's still running
+    let crashed = manager(&root, None);
+    for name in ["upload", "output.gif"] {
+        let (file, mut handle) = crashed.create(name).await.unwrap();
+        file.grew(10).unwrap();
+        handle.write_all(&[0; 10]).await.unwrap();
+        std::mem::forget(file);
+    }
+    let crashed_dir = crashed.dir().to_path_buf();
+    std::mem::forget(crashed);
+    std::fs::File::open(&crashed_dir).unwrap().set_modified(SystemTime::now() - HOUR * 2).unwrap();
+    let running = manager(&root, None);
+    let (running_file, _) = running.create("upload").await.unwrap();
+    // and a file of someone else's
+    std::fs::write(root.join("other.gif"), b"GIF89a").unwrap();
+
+    let restarted = manager(&root, None);
+    let (own_file, _) = restarted.create("upload").await.unwrap();
+    let swept = restarted.sweep(None).unwrap();
+    assert_eq!((swept.files, swept.bytes), (2, 20));
+    assert!(!crashed_dir.exists());
+    assert!(running_file.path().exists() && own_file.path().exists());
+    assert!(root.join("other.gif").exists());
+    assert_eq!(files(&root).len(), 3, "{:?}", files(&root));
+
+    // and there's nothing left for the next sweep
+    assert_eq!(restarted.sweep(None).unwrap().files, 0);
+    let _ = std::fs::remove_dir_all(&root);
+}
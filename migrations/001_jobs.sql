@@ -0,0 +1,22 @@
+-- Jobs as `POST /jobs` took them, and how far each got. Times are Unix
+-- milliseconds.
+CREATE TABLE jobs (
+    id TEXT PRIMARY KEY NOT NULL,
+    -- queued, running, done, failed or cancelled
+    state TEXT NOT NULL,
+    -- The job as it was submitted, as JSON
+    submission TEXT NOT NULL,
+    -- The name of the API key it was submitted with
+    owner TEXT,
+    -- The client's address
+    client TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    started_at INTEGER,
+    finished_at INTEGER,
+    -- A done job's response headers, as JSON pairs, and its GIF
+    result_headers TEXT,
+    result BLOB,
+    -- A failed job's status and error
+    status INTEGER,
+    error TEXT
+);
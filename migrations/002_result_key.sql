@@ -0,0 +1,2 @@
+-- Where a done job's GIF was uploaded to in S3_BUCKET, if it was
+ALTER TABLE jobs ADD COLUMN result_key TEXT;
@@ -0,0 +1,10 @@
+-- What each API key used, a UTC day at a time, for its quotas. `day` is
+-- days since the Unix epoch.
+CREATE TABLE usage (
+    key TEXT NOT NULL,
+    day INTEGER NOT NULL,
+    conversions INTEGER NOT NULL,
+    cpu_ms INTEGER NOT NULL,
+    output_bytes INTEGER NOT NULL,
+    PRIMARY KEY (key, day)
+);
@@ -0,0 +1,319 @@
+use crate::client_ip::{self, TrustedProxies};
+use crate::listener::Peer;
+use crate::metrics::Metrics;
+use crate::popularity::{Popularity, Served};
+use crate::rate_limit::Checked;
+use crate::telemetry::RequestId;
+use crate::tls::ClientCert;
+use axum::body::{Body, HttpBody};
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
+use axum::http::{header, StatusCode, Version};
+use axum::middleware::Next;
+use axum::response::Response;
+use bytes::Bytes;
+use http_body::{Frame, SizeHint};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::{info, Span};
+
+/// What a handler knows about a request that the access log (and the
+/// middleware adding the rate limit headers) can't see from the outside.
+/// Handlers get it as an `Extension`.
+#[derive(Clone, Default)]
+pub struct AccessNote(Arc<Mutex<Note>>);
+
+#[derive(Default)]
+struct Note {
+    outcome: Option<&'static str>,
+    cache: Option<&'static str>,
+    api_key: Option<Arc<str>>,
+    popular: Option<String>,
+    served: Option<Served>,
+    auto_quality: Option<String>,
+    rate_limit: Option<Checked>,
+}
+
+impl AccessNote {
+    /// Why the request ended the way it did, e.g. `input_too_long`. Without
+    /// one it's `ok` or `error`, going by the status.
+    pub fn outcome(&self, outcome: &'static str) {
+        self.0.lock().unwrap().outcome = Some(outcome);
+    }
+
+    /// What `outcome` was last given, if anything.
+    pub fn noted_outcome(&self) -> Option<&'static str> {
+        self.0.lock().unwrap().outcome
+    }
+
+    /// `hit` or `miss`, for the probe cache.
+    pub fn cache(&self, cache: &'static str) {
+        self.0.lock().unwrap().cache = Some(cache);
+    }
+
+    /// The name of the API key the request was made with.
+    pub fn api_key(&self, name: Arc<str>) {
+        self.0.lock().unwrap().api_key = Some(name);
+    }
+
+    /// The video a GIF request was for, which it's counted under for
+    /// `GET /admin/top`.
+    pub fn popular(&self, path: &str) {
+        self.0.lock().unwrap().popular = Some(path.to_string());
+    }
+
+    /// How the GIF was sent.
+    pub fn served(&self, served: Served) {
+        self.0.lock().unwrap().served = Some(served);
+    }
+
+    /// The bucket `AUTO_QUALITY` put the video in.
+    pub fn auto_quality(&self, bucket: String) {
+        self.0.lock().unwrap().auto_quality = Some(bucket);
+    }
+
+    /// The request was held to a rate limit, and this is where that left
+    /// the client. Of a request checked against more than one (a batch and
+    /// its items), the response says whichever leaves it least.
+    pub fn rate_limit(&self, checked: Checked) {
+        let mut note = self.0.lock().unwrap();
+        note.rate_limit = Some(note.rate_limit.map_or(checked, |before| before.tighter(checked)));
+    }
+
+    /// Where the rate limits the request was held to left the client.
+    pub fn rate_limited(&self) -> Option<Checked> {
+        self.0.lock().unwrap().rate_limit
+    }
+}
+
+/// What the access log needs besides the request.
+#[derive(Clone)]
+pub struct AccessLog {
+    /// `TRUST_PROXY`
+    pub trust_proxy: Arc<TrustedProxies>,
+    /// Clients that went away are counted here
+    pub metrics: Arc<Metrics>,
+    /// and GIF requests here, unless `POPULARITY_MAX_PATHS` is 0
+    pub popularity: Option<Arc<Popularity>>,
+}
+
+/// Middleware writing one line per request once its response has been sent,
+/// or has failed, or the client went away.
+pub async fn log_requests(
+    State(log): State<AccessLog>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let note = AccessNote::default();
+    request.extensions_mut().insert(note.clone());
+    let mut entry = Pending(Some(Entry {
+        span: Span::current(),
+        started,
+        method: request.method().to_string(),
+        protocol: protocol(request.version()),
+        route: request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_else(|| "unmatched".to_string()),
+        path: request.uri().path().to_string(),
+        client_ip: client_ip::resolve(request.headers(), peer.ip(), &log.trust_proxy),
+        client_cert: request
+            .extensions()
+            .get::<ClientCert>()
+            .and_then(|cert| cert.name().map(str::to_string)),
+        user_agent: request
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .unwrap_or_default()
+            .to_string(),
+        request_id: request
+            .extensions()
+            .get::<RequestId>()
+            .map(|id| id.0.clone())
+            .unwrap_or_default(),
+        // What nginx logs for a client that left before there was a response
+        status: StatusCode::from_u16(499).unwrap(),
+        note,
+        metrics: log.metrics,
+        popularity: log.popularity,
+    }));
+
+    let response = next.run(request).await;
+    let (parts, body) = response.into_parts();
+    let entry = Entry { status: parts.status, ..entry.0.take().unwrap() };
+    let length = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse().ok())
+        .or_else(|| body.size_hint().exact());
+    let body = LoggedBody {
+        inner: body,
+        entry: Some(entry),
+        length,
+        bytes: 0,
+    };
+    Response::from_parts(parts, Body::new(body))
+}
+
+struct Entry {
+    span: Span,
+    started: Instant,
+    method: String,
+    /// What the connection speaks, as nginx's `$server_protocol` has it
+    protocol: &'static str,
+    route: String,
+    path: String,
+    client_ip: IpAddr,
+    /// Who their certificate says they are, with `TLS_CLIENT_CA`
+    client_cert: Option<String>,
+    user_agent: String,
+    request_id: String,
+    status: StatusCode,
+    note: AccessNote,
+    metrics: Arc<Metrics>,
+    popularity: Option<Arc<Popularity>>,
+}
+
+impl Entry {
+    fn write(self, bytes: u64, finished: Finished) {
+        let note = self.note.0.lock().unwrap();
+        let outcome = match finished {
+            Finished::Complete => note.outcome.unwrap_or(if self.status.is_client_error() || self.status.is_server_error() {
+                "error"
+            } else {
+                "ok"
+            }),
+            Finished::Failed => "error",
+            // Not an error of ours, so it's counted apart from them
+            Finished::Aborted => {
+                let stage = if self.status.as_u16() == 499 { "before_response" } else { "during_response" };
+                self.metrics.client_aborted(stage);
+                "client_abort"
+            }
+        };
+        self.metrics.served(bytes);
+        if let (Some(popularity), Some(path)) = (&self.popularity, &note.popular) {
+            popularity.record(path, note.served, bytes);
+        }
+        self.span.in_scope(|| {
+            info!(
+                method = %self.method,
+                protocol = self.protocol,
+                route = %self.route,
+                path = %self.path,
+                status = self.status.as_u16(),
+                duration_ms = self.started.elapsed().as_millis() as u64,
+                bytes,
+                cache = note.cache.unwrap_or("none"),
+                auto_quality = note.auto_quality.as_deref(),
+                api_key = note.api_key.as_deref(),
+                client_cert = self.client_cert.as_deref(),
+                outcome,
+                client_ip = %self.client_ip,
+                user_agent = %self.user_agent,
+                request_id = %self.request_id,
+                "{} {} {}",
+                self.method,
+                self.path,
+                self.status.as_u16()
+            );
+        });
+    }
+}
+
+fn protocol(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_11 => "HTTP/1.1",
+        Version::HTTP_2 => "HTTP/2.0",
+        Version::HTTP_3 => "HTTP/3.0",
+        _ => "unknown",
+    }
+}
+
+/// The line of a request whose handler is still running. If the handler is
+/// dropped, the client went away before it could be answered.
+struct Pending(Option<Entry>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some(entry) = self.0.take() {
+            entry.write(0, Finished::Aborted);
+        }
+    }
+}
+
+enum Finished {
+    Complete,
+    Failed,
+    Aborted,
+}
+
+/// A response body that writes the request's line once it's done. Dropped
+/// before the end means the client went away (or was dropped for reading too
+/// slowly) and the response never made it.
+struct LoggedBody {
+    inner: Body,
+    entry: Option<Entry>,
+    /// The Content-Length, when there is one
+    length: Option<u64>,
+    bytes: u64,
+}
+
+impl LoggedBody {
+    fn finish(&mut self, finished: Finished) {
+        if let Some(entry) = self.entry.take() {
+            entry.write(self.bytes, finished);
+        }
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = &mut *self;
+        let frame = Pin::new(&mut this.inner).poll_frame(cx);
+        match &frame {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    this.bytes += data.len() as u64;
+                }
+                if this.inner.is_end_stream() {
+                    this.finish(Finished::Complete);
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.finish(Finished::Failed),
+            Poll::Ready(None) => this.finish(Finished::Complete),
+            Poll::Pending => {}
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        // An empty body, or the body of a HEAD response, may never be polled
+        // at all, and hyper needn't poll for the end once it has every byte
+        let head = self.entry.as_ref().is_some_and(|entry| entry.method == "HEAD");
+        let complete = self.inner.is_end_stream() || self.length.is_some_and(|len| self.bytes >= len);
+        let finished = if head || complete { Finished::Complete } else { Finished::Aborted };
+        self.finish(finished);
+    }
+}
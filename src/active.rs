@@ -0,0 +1,180 @@
+use crate::admission::{Admission, Priority};
+use crate::variant::ConversionQuery;
+use fastgif_core::timing::StageTimes;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Every conversion that's been let in and hasn't finished, for
+/// `GET /admin/conversions`.
+#[derive(Default)]
+pub struct ActiveConversions {
+    running: Mutex<HashMap<u64, Entry>>,
+    next: AtomicU64,
+}
+
+struct Entry {
+    conversion: Conversion,
+    killed_by: Arc<OnceLock<IpAddr>>,
+}
+
+/// What's known about a conversion as it's let in.
+pub struct Conversion {
+    pub request_id: String,
+    /// The path asked for, or the upload's name
+    pub path: String,
+    /// The upstream path it's converted from, which the queue takes turns
+    /// by, or `None` for an upload
+    pub source: Option<String>,
+    pub params: ConversionQuery,
+    /// What it'll be cached under, if it will be
+    pub variant: Option<String>,
+    pub client: IpAddr,
+    /// The name of the API key it came with
+    pub api_key: Option<String>,
+    pub priority: Priority,
+    /// When the request came in
+    pub received: Instant,
+    pub queue_wait: Duration,
+    /// Filled in by the conversion as it goes
+    pub timings: StageTimes,
+    /// What the conversion will be spawned with, to stop it
+    pub cancel: CancellationToken,
+}
+
+/// A conversion's place in [`ActiveConversions`], which it leaves when this
+/// is dropped.
+pub struct Registration {
+    active: Arc<ActiveConversions>,
+    id: u64,
+    killed_by: Arc<OnceLock<IpAddr>>,
+}
+
+/// How a conversion is getting on, as `GET /admin/conversions` lists it.
+#[derive(Debug, Serialize)]
+pub struct Status<'a> {
+    pub request_id: &'a str,
+    pub path: &'a str,
+    pub params: &'a ConversionQuery,
+    pub client: IpAddr,
+    pub api_key: Option<&'a str>,
+    pub priority: Priority,
+    /// Since the request came in
+    pub elapsed_ms: u64,
+    pub stages_ms: Stages,
+    /// Of the video written to ffmpeg, when we fetch it ourselves
+    pub bytes_piped: u64,
+    /// Of output read from the encoder
+    pub bytes_collected: u64,
+    /// How many requests for the same video are waiting for their turn.
+    /// Left out for an upload.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queued_for_source: Option<usize>,
+}
+
+/// How long each stage has taken so far. Those that haven't started (or
+/// won't happen) are left out.
+#[derive(Debug, Serialize)]
+pub struct Stages {
+    pub queue: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_ttfb: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ffmpeg: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gifski: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_byte: Option<u64>,
+}
+
+impl ActiveConversions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count `conversion` as running until the registration is dropped.
+    pub fn insert(self: &Arc<Self>, conversion: Conversion) -> Registration {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let killed_by = Arc::new(OnceLock::new());
+        self.running.lock().unwrap().insert(id, Entry { conversion, killed_by: killed_by.clone() });
+        Registration { active: self.clone(), id, killed_by }
+    }
+
+    /// Each conversion as it is now, the longest-running first, as JSON,
+    /// with how many are queued behind it in `admission`.
+    pub fn list(&self, admission: &Admission) -> serde_json::Value {
+        self.statuses(admission, |_| true)
+    }
+
+    /// Cancel the conversions for the request `request_id`, for the admin at
+    /// `admin`, and say what they'd got to, or `None` if there aren't any.
+    /// There's more than one when a batch's items are still converting.
+    pub fn kill(&self, request_id: &str, admin: IpAddr, admission: &Admission) -> Option<serde_json::Value> {
+        let running = self.running.lock().unwrap();
+        let mut killed = false;
+        for entry in running.values().filter(|entry| entry.conversion.request_id == request_id) {
+            let _ = entry.killed_by.set(admin);
+            entry.conversion.cancel.cancel();
+            killed = true;
+        }
+        drop(running);
+        killed.then(|| self.statuses(admission, |conversion| conversion.request_id == request_id))
+    }
+
+    /// Whether what'll be cached under `variant` is being made already.
+    pub fn converting(&self, variant: &str) -> bool {
+        let running = self.running.lock().unwrap();
+        running.values().any(|entry| entry.conversion.variant.as_deref() == Some(variant))
+    }
+
+    fn statuses(&self, admission: &Admission, filter: impl Fn(&Conversion) -> bool) -> serde_json::Value {
+        let running = self.running.lock().unwrap();
+        let mut conversions: Vec<_> = running.values().map(|entry| &entry.conversion).filter(|conversion| filter(conversion)).collect();
+        conversions.sort_by_key(|conversion| conversion.received);
+        let statuses: Vec<_> = conversions.into_iter().map(|conversion| conversion.status(admission)).collect();
+        serde_json::to_value(statuses).expect("a conversion's status is always JSON")
+    }
+}
+
+impl Registration {
+    /// Where the admin who cancels the conversion is, once one has.
+    pub fn killed_by(&self) -> Arc<OnceLock<IpAddr>> {
+        self.killed_by.clone()
+    }
+}
+
+impl Conversion {
+    fn status(&self, admission: &Admission) -> Status<'_> {
+        let stages = self.timings.so_far();
+        let ms = |took: Option<Duration>| took.map(|took| took.as_millis() as u64);
+        Status {
+            request_id: &self.request_id,
+            path: &self.path,
+            params: &self.params,
+            client: self.client,
+            api_key: self.api_key.as_deref(),
+            priority: self.priority,
+            elapsed_ms: self.received.elapsed().as_millis() as u64,
+            stages_ms: Stages {
+                queue: self.queue_wait.as_millis() as u64,
+                upstream_ttfb: ms(stages.upstream_ttfb),
+                ffmpeg: ms(stages.ffmpeg),
+                gifski: ms(stages.gifski),
+                first_byte: ms(stages.first_byte),
+            },
+            bytes_piped: self.timings.bytes_piped(),
+            bytes_collected: self.timings.bytes_collected(),
+            queued_for_source: self.source.as_deref().map(|source| admission.queued_for(source)),
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.active.running.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,514 @@
+use crate::pressure::AdmissionState;
+use crate::reload::Settings;
+use arc_swap::ArcSwap;
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{info, Span};
+
+/// How long a request waits before it's let in ahead of new ones a class
+/// above it, so low-priority work still gets a turn on a busy server.
+const AGING: Duration = Duration::from_secs(2);
+
+/// How much each finished conversion moves the average time they take.
+const AVERAGE_WEIGHT: f64 = 0.2;
+
+/// How soon a conversion should be let in when it has to wait for a permit.
+/// Requests with a higher priority go first, however long the others have
+/// been waiting for, up to `AGING` a class.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+
+    /// What a request says about itself in `X-FastGIF-Priority`, if it says
+    /// anything we know.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get("x-fastgif-priority")?.to_str().ok()?.trim();
+        Priority::ALL.into_iter().find(|priority| value.eq_ignore_ascii_case(priority.as_str()))
+    }
+
+    // Where a request of this priority stands after waiting `waited`, lower
+    // first
+    fn rank(self, waited: Duration) -> u32 {
+        let class = self as u32;
+        class.saturating_sub((waited.as_millis() / AGING.as_millis()) as u32)
+    }
+}
+
+/// Gatekeeper for conversions: a number of permits, plus a bounded queue of
+/// requests allowed to wait (for a limited time) for one of them. The limits
+/// come from the live settings, so a reload can change them. Those waiting
+/// take turns by source video, so one everybody's asking for at once doesn't
+/// hold up the rest. With a pressure
+/// controller, new requests are also turned away whenever it says the system
+/// is overloaded, however many permits are free.
+pub struct Admission {
+    permits: Arc<Semaphore>,
+    /// How many permits there are meant to be
+    max_concurrent: AtomicUsize,
+    /// Permits still held by conversions that a lowered limit will take back
+    /// once they're done
+    retiring: Arc<AtomicUsize>,
+    settings: Arc<ArcSwap<Settings>>,
+    queued: AtomicUsize,
+    /// Those waiting for a permit, which the dispatcher hands them one by one
+    waiting: Arc<Waiting>,
+    pressure: Option<Arc<AdmissionState>>,
+    /// How long conversions have been taking, an exponentially weighted
+    /// average in microseconds (0 until one has finished)
+    average_us: AtomicU64,
+    /// Stops the dispatcher when this is dropped
+    _dispatcher: DropGuard,
+}
+
+#[derive(Default)]
+struct Waiting {
+    queue: Mutex<Queue>,
+    /// How many of the queue are at each priority, to be read without it
+    by_priority: [AtomicUsize; Priority::ALL.len()],
+    /// How many of the queue are prefetches, which aren't counted above
+    spare: AtomicUsize,
+    /// Told whenever someone joins the queue
+    joined: Notify,
+}
+
+#[derive(Default)]
+struct Queue {
+    next_ticket: u64,
+    waiters: Vec<Waiter>,
+    /// How many permits have been handed out
+    turns: u64,
+    /// The turn each source still waiting last had, once it's had one
+    last_turn: HashMap<String, u64>,
+}
+
+struct Waiter {
+    ticket: u64,
+    priority: Priority,
+    /// Only to be let in once nobody else is waiting
+    spare: bool,
+    /// The video it's converting, which it takes turns with others for
+    source: Option<String>,
+    since: Instant,
+    permit: oneshot::Sender<OwnedSemaphorePermit>,
+}
+
+/// How busy conversions are, as reported on the stats endpoint.
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub running: usize,
+    pub queued: usize,
+    /// Of those queued, how many are waiting at each priority
+    pub queued_by_priority: BTreeMap<&'static str, usize>,
+    pub max_concurrent: usize,
+    pub max_queue_depth: usize,
+}
+
+/// How busy conversions are, for callers deciding whether to send another
+/// (`GET /queue`). Only atomics are read for it.
+#[derive(Debug, Serialize)]
+pub struct QueueStatus {
+    /// Conversions holding a permit
+    pub running: usize,
+    pub queued: usize,
+    pub queued_by_priority: BTreeMap<&'static str, usize>,
+    /// Prefetches waiting for a permit nobody else wants, which aren't
+    /// counted as queued
+    pub prefetches_queued: usize,
+    /// The average finished conversion, weighted towards the latest, once
+    /// there's been one
+    pub average_conversion_ms: Option<u64>,
+    /// About how long a conversion sent now would wait for a turn: the
+    /// average conversion for each one queued, spread over the permits
+    pub estimated_wait_secs: f64,
+    /// Whether the pressure controller is turning conversions away
+    pub shedding: bool,
+    pub max_concurrent: usize,
+    pub max_queue_depth: usize,
+    pub queue_wait_timeout_secs: f64,
+}
+
+/// Why a request was turned away instead of being given a permit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// `MAX_QUEUE_DEPTH` requests were already waiting
+    QueueFull,
+    /// We waited `QUEUE_WAIT_TIMEOUT` and no permit became free
+    TimedOut,
+    /// The pressure controller is shedding load
+    SystemPressure,
+    /// The request's deadline would pass, or has, before a permit came free
+    DeadlineExceeded,
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::QueueFull => write!(f, "conversion queue is full"),
+            Rejection::TimedOut => write!(f, "timed out waiting in conversion queue"),
+            Rejection::SystemPressure => write!(f, "system is under too much pressure"),
+            Rejection::DeadlineExceeded => write!(f, "its deadline would pass waiting in conversion queue"),
+        }
+    }
+}
+
+impl Admission {
+    pub fn new(settings: Arc<ArcSwap<Settings>>, pressure: Option<Arc<AdmissionState>>) -> Self {
+        let max_concurrent = settings.load().max_concurrent;
+        let permits = Arc::new(Semaphore::new(max_concurrent));
+        let waiting = Arc::new(Waiting::default());
+        let stop = CancellationToken::new();
+        let dispatcher = dispatch(permits.clone(), waiting.clone());
+        let stopped = stop.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = dispatcher => {}
+                _ = stopped.cancelled() => {}
+            }
+        });
+        Self {
+            permits,
+            max_concurrent: AtomicUsize::new(max_concurrent),
+            retiring: Arc::default(),
+            settings,
+            queued: AtomicUsize::new(0),
+            waiting,
+            pressure,
+            average_us: AtomicU64::new(0),
+            _dispatcher: stop.drop_guard(),
+        }
+    }
+
+    /// Change how many conversions may run at once. Raising it lets waiting
+    /// requests in straight away; lowering it takes permits back as the
+    /// conversions holding them finish, rather than cutting anything short.
+    pub fn resize(&self, max_concurrent: usize) {
+        let was = self.max_concurrent.swap(max_concurrent, Ordering::SeqCst);
+        if max_concurrent >= was {
+            self.permits.add_permits(max_concurrent - was);
+            return;
+        }
+        let excess = was - max_concurrent;
+        let outstanding = excess - self.permits.forget_permits(excess);
+        if outstanding == 0 {
+            return;
+        }
+        self.retiring.fetch_add(outstanding, Ordering::SeqCst);
+        let permits = self.permits.clone();
+        let retiring = self.retiring.clone();
+        tokio::spawn(async move {
+            // The semaphore is fair, so not even the dispatcher gets in ahead of this
+            if let Ok(permit) = permits.acquire_many_owned(outstanding as u32).await {
+                permit.forget();
+            }
+            retiring.fetch_sub(outstanding, Ordering::SeqCst);
+        });
+    }
+
+    /// How many requests are waiting for a permit to convert `source`.
+    pub fn queued_for(&self, source: &str) -> usize {
+        let queue = self.waiting.queue.lock().unwrap();
+        queue.waiters.iter().filter(|waiter| !waiter.spare && waiter.source.as_deref() == Some(source)).count()
+    }
+
+    pub fn pressure(&self) -> Option<&AdmissionState> {
+        self.pressure.as_deref()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let max_concurrent = self.max_concurrent.load(Ordering::SeqCst);
+        let permits = max_concurrent + self.retiring.load(Ordering::SeqCst);
+        Stats {
+            running: permits.saturating_sub(self.permits.available_permits()),
+            queued: self.queued.load(Ordering::SeqCst),
+            queued_by_priority: self.waiting.by_priority(),
+            max_concurrent,
+            max_queue_depth: self.settings.load().max_queue_depth,
+        }
+    }
+
+    pub fn queue_status(&self) -> QueueStatus {
+        let Stats { running, queued, queued_by_priority, max_concurrent, max_queue_depth } = self.stats();
+        let average = self.average();
+        let estimated_wait = self.estimated_wait(queued).as_secs_f64();
+        QueueStatus {
+            running,
+            queued,
+            queued_by_priority,
+            prefetches_queued: self.waiting.spare.load(Ordering::SeqCst),
+            average_conversion_ms: average.map(|average| average.as_millis() as u64),
+            estimated_wait_secs: (estimated_wait * 1000.0).round() / 1000.0,
+            shedding: self.pressure.as_ref().is_some_and(|pressure| pressure.is_shedding()),
+            max_concurrent,
+            max_queue_depth,
+            queue_wait_timeout_secs: self.queue_wait_timeout().as_secs_f64(),
+        }
+    }
+
+    fn average(&self) -> Option<Duration> {
+        Some(self.average_us.load(Ordering::Relaxed)).filter(|us| *us > 0).map(Duration::from_micros)
+    }
+
+    // About how long a conversion with `ahead` queued in front of it waits:
+    // the average conversion for each, spread over the permits
+    fn estimated_wait(&self, ahead: usize) -> Duration {
+        let max_concurrent = self.max_concurrent.load(Ordering::SeqCst).max(1);
+        self.average().unwrap_or_default().mul_f64(ahead as f64 / max_concurrent as f64)
+    }
+
+    /// Note how long a finished conversion took, for the average.
+    pub fn conversion_took(&self, took: Duration) {
+        let took = (took.as_micros() as u64).max(1);
+        let _ = self.average_us.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+            Some(match average {
+                0 => took,
+                average => (average as f64 + AVERAGE_WEIGHT * (took as f64 - average as f64)).max(1.0) as u64,
+            })
+        });
+    }
+
+    /// Why a new request would be turned away right now, if it would be.
+    pub fn saturated(&self) -> Option<Rejection> {
+        if self.pressure.as_ref().is_some_and(|pressure| pressure.is_shedding()) {
+            return Some(Rejection::SystemPressure);
+        }
+        let full = self.permits.available_permits() == 0
+            && self.queued.load(Ordering::SeqCst) >= self.settings.load().max_queue_depth;
+        full.then_some(Rejection::QueueFull)
+    }
+
+    pub fn queue_wait_timeout(&self) -> Duration {
+        self.settings.load().queue_wait_timeout
+    }
+
+    /// Wait for a conversion permit, behind anyone waiting with a higher
+    /// `priority`, and no later than `deadline`. Of those with the same
+    /// priority, each `source` waiting gets a turn before any gets another;
+    /// a request with no source is a source of its own. One whose estimated wait
+    /// would take it past the deadline isn't let into the queue at all. The
+    /// queue position and time spent waiting are recorded on the current span
+    /// (`queue_position`, `queue_wait_ms`).
+    pub async fn acquire(
+        &self,
+        priority: Priority,
+        source: Option<&str>,
+        deadline: Option<Instant>,
+    ) -> Result<OwnedSemaphorePermit, Rejection> {
+        let span = Span::current();
+        let settings = self.settings.load_full();
+
+        if self.pressure.as_ref().is_some_and(|pressure| pressure.is_shedding()) {
+            span.record("queue_position", 0);
+            span.record("queue_wait_ms", 0);
+            return Err(Rejection::SystemPressure);
+        }
+
+        // Fast path: a permit is free right now, and nobody's waiting for it
+        if self.waiting.is_empty() {
+            if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+                span.record("queue_position", 0);
+                span.record("queue_wait_ms", 0);
+                return Ok(permit);
+            }
+        }
+
+        // The slot is released on drop, so a client disconnecting while we
+        // wait (which drops this future) still frees its place in the queue
+        let slot = QueueSlot::take(&self.queued);
+        span.record("queue_position", slot.position);
+        if slot.position > settings.max_queue_depth {
+            span.record("queue_wait_ms", 0);
+            return Err(Rejection::QueueFull);
+        }
+        let started = Instant::now();
+        if deadline.is_some_and(|deadline| started + self.estimated_wait(slot.position - 1) > deadline) {
+            span.record("queue_wait_ms", 0);
+            return Err(Rejection::DeadlineExceeded);
+        }
+
+        info!("Waiting for a conversion permit (queue position {}, {} priority)", slot.position, priority.as_str());
+        let wait = match deadline {
+            Some(deadline) => settings.queue_wait_timeout.min(deadline.saturating_duration_since(started)),
+            None => settings.queue_wait_timeout,
+        };
+        let waiter = self.waiting.join(priority, false, source);
+        let result = tokio::time::timeout(wait, waiter.permit()).await;
+        let waited = started.elapsed();
+        span.record("queue_wait_ms", waited.as_millis() as u64);
+
+        match result {
+            Ok(permit) => {
+                info!("Acquired conversion permit after {}ms", waited.as_millis());
+                Ok(permit)
+            }
+            Err(_) if wait < settings.queue_wait_timeout => Err(Rejection::DeadlineExceeded),
+            Err(_) => Err(Rejection::TimedOut),
+        }
+    }
+
+    /// Wait for a permit for a prefetch, which only gets one nobody else is
+    /// waiting for: it's let in behind every request, however low their
+    /// priority or long it's waited, and doesn't take a place in the queue.
+    pub async fn acquire_spare(&self) -> Result<OwnedSemaphorePermit, Rejection> {
+        if self.pressure.as_ref().is_some_and(|pressure| pressure.is_shedding()) {
+            return Err(Rejection::SystemPressure);
+        }
+        if self.waiting.is_empty() {
+            if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+                return Ok(permit);
+            }
+        }
+        let waiter = self.waiting.join(Priority::Low, true, None);
+        tokio::time::timeout(self.queue_wait_timeout(), waiter.permit()).await.map_err(|_| Rejection::TimedOut)
+    }
+}
+
+// Hand out permits as they come free, each to whoever has waited with the
+// highest priority, for as long as there's anyone waiting
+async fn dispatch(permits: Arc<Semaphore>, waiting: Arc<Waiting>) {
+    loop {
+        // The notification is kept if nobody's waiting on it yet, so a
+        // request that joins in between isn't missed
+        while waiting.is_empty() {
+            waiting.joined.notified().await;
+        }
+        let mut permit = permits.clone().acquire_owned().await.expect("conversion semaphore is never closed");
+        // Whoever's next may have given up since they were picked; dropping
+        // the permit when there's nobody left gives it back
+        while let Some(next) = waiting.next() {
+            match next.send(permit) {
+                Ok(()) => break,
+                Err(unsent) => permit = unsent,
+            }
+        }
+    }
+}
+
+impl Waiting {
+    fn is_empty(&self) -> bool {
+        self.queue.lock().unwrap().waiters.is_empty()
+    }
+
+    fn join(&self, priority: Priority, spare: bool, source: Option<&str>) -> Joined<'_> {
+        let (permit, receiver) = oneshot::channel();
+        let mut queue = self.queue.lock().unwrap();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        let source = source.map(str::to_string);
+        let waiter = Waiter { ticket, priority, spare, source, since: Instant::now(), permit };
+        self.count(&waiter).fetch_add(1, Ordering::SeqCst);
+        queue.waiters.push(waiter);
+        drop(queue);
+        self.joined.notify_one();
+        Joined { waiting: self, ticket, receiver }
+    }
+
+    // Take the waiter to hand the next permit to: the highest priority,
+    // counting how long they've waited, then the one whose source had a turn
+    // longest ago (or never), and the first of those to arrive, with
+    // prefetches after everyone else
+    fn next(&self) -> Option<oneshot::Sender<OwnedSemaphorePermit>> {
+        let mut queue = self.queue.lock().unwrap();
+        let now = Instant::now();
+        let last_turn = |waiter: &Waiter| waiter.source.as_ref().and_then(|source| queue.last_turn.get(source)).copied();
+        let (next, _) = queue.waiters.iter().enumerate().min_by_key(|(_, waiter)| {
+            (waiter.spare, waiter.priority.rank(now - waiter.since), last_turn(waiter), waiter.ticket)
+        })?;
+        let next = queue.waiters.remove(next);
+        queue.turns += 1;
+        if let Some(source) = &next.source {
+            let turn = queue.turns;
+            match queue.waiters.iter().any(|waiter| waiter.source.as_ref() == Some(source)) {
+                true => queue.last_turn.insert(source.clone(), turn),
+                // Nothing to take turns with, and so nothing to remember
+                false => queue.last_turn.remove(source),
+            };
+        }
+        self.count(&next).fetch_sub(1, Ordering::SeqCst);
+        Some(next.permit)
+    }
+
+    // The count `waiter` is one of
+    fn count(&self, waiter: &Waiter) -> &AtomicUsize {
+        match waiter.spare {
+            true => &self.spare,
+            false => &self.by_priority[waiter.priority as usize],
+        }
+    }
+
+    fn by_priority(&self) -> BTreeMap<&'static str, usize> {
+        Priority::ALL
+            .iter()
+            .map(|priority| (priority.as_str(), self.by_priority[*priority as usize].load(Ordering::SeqCst)))
+            .collect()
+    }
+}
+
+/// A place among those waiting for a permit, given up when dropped.
+struct Joined<'a> {
+    waiting: &'a Waiting,
+    ticket: u64,
+    receiver: oneshot::Receiver<OwnedSemaphorePermit>,
+}
+
+impl Joined<'_> {
+    async fn permit(mut self) -> OwnedSemaphorePermit {
+        (&mut self.receiver).await.expect("the dispatcher only gives up a waiter by sending it a permit")
+    }
+}
+
+impl Drop for Joined<'_> {
+    fn drop(&mut self) {
+        let mut queue = self.waiting.queue.lock().unwrap();
+        // Unless the dispatcher has taken it already
+        if let Some(at) = queue.waiters.iter().position(|waiter| waiter.ticket == self.ticket) {
+            let gone = queue.waiters.remove(at);
+            self.waiting.count(&gone).fetch_sub(1, Ordering::SeqCst);
+            if let Some(source) = &gone.source {
+                if !queue.waiters.iter().any(|waiter| waiter.source.as_ref() == Some(source)) {
+                    queue.last_turn.remove(source);
+                }
+            }
+        }
+    }
+}
+
+/// A place in the wait queue, given back when dropped.
+struct QueueSlot<'a> {
+    queued: &'a AtomicUsize,
+    position: usize,
+}
+
+impl<'a> QueueSlot<'a> {
+    fn take(queued: &'a AtomicUsize) -> Self {
+        let position = queued.fetch_add(1, Ordering::SeqCst) + 1;
+        Self { queued, position }
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::access_log::AccessNote;
+use crate::tls::ClientCert;
+use anyhow::{anyhow, Result};
+use axum::extract::{Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::warn;
+
+/// The keys conversions need (`API_KEYS`, `API_KEYS_FILE`), each with the
+/// name it's logged under so the key itself never is.
+#[derive(Debug)]
+pub struct ApiKeys(Vec<ApiKey>);
+
+#[derive(Debug)]
+struct ApiKey {
+    key: String,
+    name: Arc<str>,
+}
+
+/// Who a request authenticated as: the name of its API key. Handlers behind
+/// `require_key` get it as an `Extension`.
+#[derive(Debug, Clone)]
+pub struct Caller(pub Arc<str>);
+
+impl ApiKeys {
+    /// The keys in `keys`, named `key-1`, `key-2` and so on, and those in
+    /// `file`: a `<key> <name>` per line, with blank lines and `#` comments
+    /// skipped. `None` when there are none at all, so nothing needs one.
+    pub fn load(keys: &[String], file: Option<&Path>) -> Result<Option<Self>> {
+        let mut loaded: Vec<ApiKey> = keys
+            .iter()
+            .filter(|key| !key.is_empty())
+            .enumerate()
+            .map(|(i, key)| ApiKey { key: key.clone(), name: format!("key-{}", i + 1).into() })
+            .collect();
+        if let Some(path) = file {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Couldn't read API_KEYS_FILE {}: {}", path.display(), e))?;
+            for (number, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let Some((key, name)) = line.split_once(char::is_whitespace) else {
+                    return Err(anyhow!("Line {} of {} has a key but no name", number + 1, path.display()));
+                };
+                loaded.push(ApiKey { key: key.to_string(), name: name.trim().into() });
+            }
+        }
+        for (i, key) in loaded.iter().enumerate() {
+            if let Some(other) = loaded[..i].iter().find(|other| other.key == key.key) {
+                return Err(anyhow!("The API keys {} and {} are the same", other.name, key.name));
+            }
+        }
+        Ok((!loaded.is_empty()).then_some(Self(loaded)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether one of the keys is called `name`.
+    pub fn named(&self, name: &str) -> bool {
+        self.0.iter().any(|key| &*key.name == name)
+    }
+
+    /// The name of the key `given` is. Every key is compared, all the way
+    /// through, so how long this takes doesn't say how close `given` came.
+    fn find(&self, given: &str) -> Option<Arc<str>> {
+        self.0
+            .iter()
+            .fold(None, |found, key| if same(given, &key.key) { Some(key.name.clone()) } else { found })
+    }
+}
+
+/// Whether two secrets are equal, compared in constant time (for their length).
+pub fn same(given: &str, secret: &str) -> bool {
+    given.len() == secret.len() && given.bytes().zip(secret.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The key a request carries: `Authorization: Bearer <key>`, or for embeds
+/// that can't set headers, `?key=<key>`.
+fn given_key(request: &Request) -> Option<String> {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(key) = bearer {
+        return Some(key.trim().to_string());
+    }
+    Query::<KeyQuery>::try_from_uri(request.uri()).ok()?.0.key
+}
+
+#[derive(Deserialize)]
+struct KeyQuery {
+    key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AuthError {
+    error: &'static str,
+    message: &'static str,
+}
+
+/// Middleware turning away requests without one of `keys`: `401` when there's
+/// no key, `403` for one we don't know. The rest go on as their key's `Caller`.
+pub async fn require_key(State(keys): State<Arc<ApiKeys>>, mut request: Request, next: Next) -> Response {
+    // A verified client certificate says who they are just as well
+    if let Some(name) = request.extensions().get::<ClientCert>().and_then(ClientCert::name) {
+        let caller = Caller(Arc::from(name));
+        request.extensions_mut().insert(caller);
+        return next.run(request).await;
+    }
+    let note = request.extensions().get::<AccessNote>().cloned();
+    let refuse = |status: StatusCode, error: &'static str, message: &'static str| {
+        if let Some(note) = &note {
+            note.outcome(error);
+        }
+        let mut response = (status, Json(AuthError { error, message })).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+        }
+        response
+    };
+    let Some(given) = given_key(&request) else {
+        return refuse(StatusCode::UNAUTHORIZED, "unauthorized", "An API key is needed, as a Bearer token or ?key=");
+    };
+    let Some(name) = keys.find(&given) else {
+        warn!("Refusing a request with an unknown API key");
+        return refuse(StatusCode::FORBIDDEN, "forbidden", "That API key isn't one of ours");
+    };
+    if let Some(note) = &note {
+        note.api_key(name.clone());
+    }
+    request.extensions_mut().insert(Caller(name));
+    next.run(request).await
+}
+
+/// `uri` with any `?key=` blanked out, for logging.
+pub fn redacted(uri: &axum::http::Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.to_string();
+    };
+    if !query.split('&').any(|pair| pair == "key" || pair.starts_with("key=")) {
+        return uri.to_string();
+    }
+    let query: Vec<&str> = query
+        .split('&')
+        .map(|pair| if pair == "key" || pair.starts_with("key=") { "key=REDACTED" } else { pair })
+        .collect();
+    format!("{}?{}", uri.path(), query.join("&"))
+}
@@ -0,0 +1,166 @@
+use crate::metrics::Metrics;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+
+/// Records waiting to be written. Past this, new ones are dropped rather than
+/// holding up the requests they're about.
+const QUEUE_RECORDS: usize = 1024;
+
+/// One completed conversion (or comparison), as a line of the audit log.
+#[derive(Debug, Serialize)]
+pub struct Record {
+    pub request_id: String,
+    pub client_ip: IpAddr,
+    /// The name of the API key it was made with
+    pub api_key: Option<String>,
+    /// Who the client's certificate says it is, with `TLS_CLIENT_CA`
+    pub client_cert: Option<String>,
+    pub path: String,
+    /// Where the video was fetched from
+    pub url: String,
+    pub params: Params,
+    /// `ok`, or the error code it failed with
+    pub outcome: &'static str,
+    pub duration_ms: u64,
+    /// Only known when we downloaded the video ourselves
+    pub input_bytes: Option<u64>,
+    pub output_bytes: Option<u64>,
+    /// Where the admin was who cancelled it, with
+    /// `DELETE /admin/conversions/{request_id}`
+    pub cancelled_by_admin: Option<IpAddr>,
+    /// For `POST /admin/compare`, what each of its conversions was made
+    /// with, e.g. `subprocess/q90`
+    pub compared: Option<Vec<String>>,
+}
+
+/// What the conversion was asked (or decided) to do.
+#[derive(Debug, Serialize)]
+pub struct Params {
+    pub optimize: bool,
+    pub repeat: Option<u16>,
+    pub trim_ms: Option<u64>,
+    /// The reduced profile it was encoded with, if we were busy
+    pub tier: Option<&'static str>,
+}
+
+/// An ndjson file every completed conversion is appended to (`AUDIT_LOG_PATH`).
+/// A task of its own does the writing, so a slow disk never holds up a
+/// request; when it falls too far behind, records are dropped and counted.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    records: mpsc::Sender<Line>,
+    metrics: Arc<Metrics>,
+}
+
+/// A record with its timestamp, which is when it was made rather than written.
+#[derive(Serialize)]
+struct Line {
+    timestamp: String,
+    #[serde(flatten)]
+    record: Record,
+}
+
+impl AuditLog {
+    /// Open `path` for appending, and start writing to it. On SIGUSR1 it's
+    /// reopened, so logrotate can move it aside.
+    pub async fn open(path: PathBuf, metrics: Arc<Metrics>) -> std::io::Result<Self> {
+        let file = open(&path).await?;
+        let (records, queue) = mpsc::channel(QUEUE_RECORDS);
+        tokio::spawn(write_records(path, file, queue, metrics.clone()));
+        Ok(Self { records, metrics })
+    }
+
+    pub fn record(&self, record: Record) {
+        let mut timestamp = String::new();
+        let _ = tracing_subscriber::fmt::time::SystemTime.format_time(&mut Writer::new(&mut timestamp));
+        if self.records.try_send(Line { timestamp, record }).is_err() {
+            self.metrics.audit_record_dropped();
+        }
+    }
+}
+
+async fn open(path: &PathBuf) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path).await
+}
+
+// Write what comes in, flushing whenever there's nothing more waiting, and
+// reopen the file when asked to
+async fn write_records(path: PathBuf, file: File, mut queue: mpsc::Receiver<Line>, metrics: Arc<Metrics>) {
+    let mut file = BufWriter::new(file);
+    let mut reopen = reopen_signal();
+    loop {
+        tokio::select! {
+            line = queue.recv() => {
+                let Some(line) = line else { break };
+                let mut json = serde_json::to_vec(&line).unwrap_or_default();
+                json.push(b'\n');
+                if let Err(e) = file.write_all(&json).await {
+                    warn!("Couldn't write to the audit log {}: {}", path.display(), e);
+                    metrics.audit_record_dropped();
+                }
+                if queue.is_empty() {
+                    if let Err(e) = file.flush().await {
+                        warn!("Couldn't write to the audit log {}: {}", path.display(), e);
+                    }
+                }
+            }
+            Some(()) = recv(&mut reopen) => {
+                let _ = file.flush().await;
+                match open(&path).await {
+                    Ok(reopened) => {
+                        info!("Reopened the audit log {}", path.display());
+                        file = BufWriter::new(reopened);
+                    }
+                    // Better to keep writing to the old file than to nothing
+                    Err(e) => warn!("Couldn't reopen the audit log {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+    let _ = file.flush().await;
+}
+
+#[cfg(unix)]
+type ReopenSignal = Option<tokio::signal::unix::Signal>;
+
+#[cfg(not(unix))]
+type ReopenSignal = Option<()>;
+
+#[cfg(unix)]
+fn reopen_signal() -> ReopenSignal {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::user_defined1()) {
+        Ok(signal) => Some(signal),
+        Err(e) => {
+            warn!("Can't reopen the audit log on SIGUSR1: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn reopen_signal() -> ReopenSignal {
+    None
+}
+
+// Never resolves without a signal to wait for
+#[cfg(unix)]
+async fn recv(signal: &mut ReopenSignal) -> Option<()> {
+    match signal {
+        Some(signal) => signal.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn recv(_signal: &mut ReopenSignal) -> Option<()> {
+    std::future::pending().await
+}
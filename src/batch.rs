@@ -0,0 +1,242 @@
+use axum::http::header;
+use axum::response::Response;
+use crate::source_path;
+use crate::variant::ConversionQuery;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How wide a `thumb` variant is at most.
+const THUMB_WIDTH: u32 = 160;
+
+/// And how many frames a second it has at most.
+const THUMB_FPS: f32 = 10.0;
+
+/// One video of a `POST /batch` (or a `POST /jobs`, as part of a
+/// `jobs::Submission`): a path on this server, or a URL at the upstream, and
+/// the query parameters to convert it with.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Item {
+    /// `abc.gif`, or `/tweet_video/abc.gif`
+    pub path: Option<String>,
+    /// `<VIDEO_BASE_URL>/tweet_video/abc.mp4`, with a query if it likes
+    pub url: Option<String>,
+    /// What would otherwise go in the query, e.g. `{"width": 480}`
+    #[serde(default)]
+    pub params: BTreeMap<String, serde_json::Value>,
+    /// What to make of it, from one decode of the video, e.g. `["default",
+    /// "thumb"]`. Without any, it's just what `params` ask for.
+    #[serde(default)]
+    pub variants: Vec<String>,
+}
+
+/// One of the things an item can ask to have made of its video.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    /// What its parameters ask for
+    Default,
+    /// That, no more than `THUMB_WIDTH` wide at `THUMB_FPS`
+    Thumb,
+    /// That, as an animated WebP
+    Webp,
+}
+
+impl Variant {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Variant::Default => "default",
+            Variant::Thumb => "thumb",
+            Variant::Webp => "webp",
+        }
+    }
+
+    // The parameters `query` comes to for this variant, with the ones it
+    // sets replaced
+    fn query_string(self, query_string: &str, query: &ConversionQuery) -> Result<String, String> {
+        let set = match self {
+            Variant::Default => return Ok(query_string.to_string()),
+            Variant::Thumb => vec![
+                ("width", query.width.map_or(THUMB_WIDTH, |width| width.min(THUMB_WIDTH)).to_string()),
+                ("fps", query.fps.map_or(THUMB_FPS, |fps| fps.min(THUMB_FPS)).to_string()),
+            ],
+            Variant::Webp => vec![("format", "webp".to_string())],
+        };
+        let mut pairs: Vec<(String, String)> = serde_urlencoded::from_str(query_string).map_err(|e| e.to_string())?;
+        pairs.retain(|(name, _)| !set.iter().any(|(set, _)| name == set));
+        pairs.extend(set.into_iter().map(|(name, value)| (name.to_string(), value)));
+        serde_urlencoded::to_string(&pairs).map_err(|e| e.to_string())
+    }
+}
+
+impl std::str::FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Variant::Default),
+            "thumb" => Ok(Variant::Thumb),
+            "webp" => Ok(Variant::Webp),
+            other => Err(format!("unknown variant {:?}, expected default, thumb or webp", other)),
+        }
+    }
+}
+
+/// An item as it's converted, the way `GET /tweet_video/{name}?{query_string}`
+/// would be.
+pub struct Target {
+    pub name: String,
+    /// As `source_path::canonicalize` made it
+    pub path: String,
+    pub query_string: String,
+    pub query: ConversionQuery,
+    /// Which of the item's `variants` it is, if it asked for any
+    pub variant: Option<Variant>,
+}
+
+impl Target {
+    /// Where `GET` sends what converting it makes.
+    pub fn url(&self) -> String {
+        match self.query_string.as_str() {
+            "" => format!("/tweet_video/{}", self.name),
+            query => format!("/tweet_video/{}?{}", self.name, query),
+        }
+    }
+}
+
+impl Item {
+    /// What converting the item asks for, or why it can't be: an error code
+    /// like `invalid_path`, and what's wrong.
+    pub fn resolve(&self, base_url: &str) -> Result<Target, String> {
+        let (name, query_string) = self.target(base_url).map_err(|e| format!("invalid_item ({})", e))?;
+        let path = source_path::canonicalize(&name).map_err(|e| format!("invalid_path ({})", e))?;
+        let query = serde_urlencoded::from_str(&query_string).map_err(|e| format!("invalid_params ({})", e))?;
+        Ok(Target { name, path, query_string, query, variant: None })
+    }
+
+    /// Each of the item's `variants`, in order, or the one `resolve` comes to
+    /// without any. An unknown or repeated variant is an `invalid_variant`.
+    pub fn resolve_variants(&self, base_url: &str) -> Result<Vec<Target>, String> {
+        let target = self.resolve(base_url)?;
+        if self.variants.is_empty() {
+            return Ok(vec![target]);
+        }
+        let mut variants: Vec<Variant> = Vec::with_capacity(self.variants.len());
+        for name in &self.variants {
+            let variant: Variant = name.parse().map_err(|e| format!("invalid_variant ({})", e))?;
+            if variants.contains(&variant) {
+                return Err(format!("invalid_variant ({} is asked for twice)", name));
+            }
+            variants.push(variant);
+        }
+        variants
+            .into_iter()
+            .map(|variant| {
+                let query_string = variant.query_string(&target.query_string, &target.query).map_err(|e| format!("invalid_params ({})", e))?;
+                let query = serde_urlencoded::from_str(&query_string).map_err(|e| format!("invalid_params ({})", e))?;
+                Ok(Target { name: target.name.clone(), path: target.path.clone(), query_string, query, variant: Some(variant) })
+            })
+            .collect()
+    }
+
+    // The name of the video under `/tweet_video/` and the query to convert it
+    // with. A URL has to be at `base_url`, the upstream, since that's the only
+    // place videos are fetched from.
+    fn target(&self, base_url: &str) -> Result<(String, String), String> {
+        let (name, mut query) = match (&self.path, &self.url) {
+            (Some(path), None) => (path.trim_start_matches("/tweet_video/").to_string(), String::new()),
+            (None, Some(url)) => {
+                let prefix = format!("{}/tweet_video/", base_url.trim_end_matches('/'));
+                let rest = url
+                    .strip_prefix(&prefix)
+                    .ok_or_else(|| format!("only videos under {} can be converted", prefix))?;
+                match rest.split_once('?') {
+                    Some((name, query)) => (name.to_string(), query.to_string()),
+                    None => (rest.to_string(), String::new()),
+                }
+            }
+            _ => return Err("each item needs a path or a url, and not both".to_string()),
+        };
+        let mut params = Vec::new();
+        for (name, value) in &self.params {
+            let value = match value {
+                serde_json::Value::String(value) => value.clone(),
+                serde_json::Value::Number(_) | serde_json::Value::Bool(_) => value.to_string(),
+                _ => return Err(format!("the parameter {:?} has to be a string, number or boolean", name)),
+            };
+            params.push((name.as_str(), value));
+        }
+        let params = serde_urlencoded::to_string(&params).map_err(|e| e.to_string())?;
+        if !params.is_empty() {
+            query = if query.is_empty() { params } else { format!("{}&{}", query, params) };
+        }
+        Ok((name, query))
+    }
+
+    /// What the item is reported as: its path or URL, as it was sent.
+    pub fn label(&self) -> String {
+        self.path.clone().or_else(|| self.url.clone()).unwrap_or_default()
+    }
+}
+
+/// How one item of a batch went. A failed item doesn't fail the batch.
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    /// The item's path or URL
+    pub item: String,
+    /// Which of its `variants` this is, if it asked for any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<&'static str>,
+    /// What `GET` would have answered with
+    pub status: u16,
+    /// The GIF's size
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    /// `hit` or `miss` for the server's own cache (`CACHE_MAX_BYTES`), or
+    /// `none` when there isn't one or it wasn't asked
+    pub cache: &'static str,
+    /// Where to fetch the GIF, from the cache when it's on, or straight from
+    /// `S3_BUCKET`, presigned, when it was uploaded there
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Why it failed, e.g. `upstream_not_found`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Outcome {
+    /// An item that was refused before it was converted.
+    pub fn refused(item: String, status: u16, error: &str) -> Self {
+        Outcome { item, variant: None, status, bytes: None, cache: "none", url: None, error: Some(error.to_string()) }
+    }
+
+    /// What `response` says about converting the item, which `GET` would
+    /// answer at `url`, leaving out the GIF itself. `outcome` is why it
+    /// failed, if it did and the access note was told.
+    pub fn of(item: String, url: String, response: &Response, outcome: Option<&str>) -> Self {
+        let status = response.status();
+        let headers = response.headers();
+        let cache = match headers.get("x-cache").and_then(|value| value.to_str().ok()) {
+            Some("HIT") => "hit",
+            Some("STALE") => "stale",
+            Some("MISS") => "miss",
+            _ => "none",
+        };
+        if !status.is_success() {
+            return Outcome {
+                cache,
+                ..Outcome::refused(item, status.as_u16(), outcome.unwrap_or("error"))
+            };
+        }
+        Outcome {
+            item,
+            variant: None,
+            status: status.as_u16(),
+            bytes: headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|len| len.to_str().ok()?.parse().ok()),
+            cache,
+            url: Some(url),
+            error: None,
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use fastgif_core::encoder::{Decoder, Encoder};
+use fastgif_core::process::Binaries;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// What's deployed, for `GET /version` and `/stats`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// `-dirty` if it was built with uncommitted changes
+    pub commit: &'static str,
+    pub built_at: &'static str,
+    /// The cargo features this binary was built with
+    pub features: Vec<&'static str>,
+    /// The encoder and decoder conversions use
+    pub encoder: &'static str,
+    pub decoder: &'static str,
+    /// The first line of `ffmpeg -version`, if there is an ffmpeg
+    pub ffmpeg: Option<String>,
+    /// The first line of `ffprobe -version`, if there is an ffprobe
+    pub ffprobe: Option<String>,
+    /// What `gifski --version` says, if there is a gifski
+    pub gifski: Option<String>,
+    /// Where each of those is, or `null` where it isn't
+    pub paths: BTreeMap<&'static str, Option<PathBuf>>,
+}
+
+impl BuildInfo {
+    /// Put together what we know about ourselves, asking the binaries for
+    /// their versions. Only done once, at startup.
+    pub fn detect(encoder: Encoder, decoder: Decoder, binaries: &Binaries) -> Self {
+        let mut features = Vec::new();
+        if cfg!(feature = "native-encoder") {
+            features.push("native-encoder");
+        }
+        if cfg!(feature = "libav") {
+            features.push("libav");
+        }
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: env!("FASTGIF_GIT_COMMIT"),
+            built_at: env!("FASTGIF_BUILT_AT"),
+            features,
+            encoder: encoder.header_value(),
+            decoder: match decoder {
+                Decoder::Ffmpeg => "ffmpeg",
+                Decoder::Libav => "libav",
+            },
+            ffmpeg: binaries.ffmpeg.version("-version"),
+            ffprobe: binaries.ffprobe.version("-version"),
+            gifski: binaries.gifski.version("--version"),
+            paths: [&binaries.ffmpeg, &binaries.ffprobe, &binaries.gifski]
+                .into_iter()
+                .map(|binary| (binary.name, binary.path.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// The line it's logged as at startup, and what `--version` prints.
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "fastgif {} (commit {}, built {}, features {:?})",
+            self.version, self.commit, self.built_at, self.features
+        )?;
+        for (name, version) in [("ffmpeg", &self.ffmpeg), ("ffprobe", &self.ffprobe), ("gifski", &self.gifski)] {
+            match self.paths.get(name).cloned().flatten() {
+                Some(path) => write!(f, ", {}: {} ({})", name, version.as_deref().unwrap_or("unknown version"), path.display())?,
+                None => write!(f, ", {}: not found", name)?,
+            }
+        }
+        Ok(())
+    }
+}
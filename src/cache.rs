@@ -0,0 +1,400 @@
+use crate::clip::Trimmed;
+use bytes::Bytes;
+use fastgif_core::backend::Backend;
+use fastgif_core::fetch::Validators;
+use fastgif_core::spill::DiskGuard;
+use lru::LruCache;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, warn};
+
+/// A finished GIF, with what its response says besides the bytes.
+pub struct CachedGif {
+    pub body: Bytes,
+    /// What made it, for its `Content-Type` and `X-FastGIF-Encoder`
+    pub backend: Arc<dyn Backend>,
+    /// Cut short to `MAX_INPUT_DURATION` (`X-FastGIF-Trimmed`)
+    pub trimmed: Option<Trimmed>,
+    /// What the request asked for that was left out (`X-FastGIF-Ignored`)
+    pub ignored: Vec<&'static str>,
+    /// Made by a prefetch, until it's first sent
+    pub prefetched: AtomicBool,
+    /// Soft-purged, to be sent until it's been made again
+    pub stale: AtomicBool,
+    /// The upstream video it was made from, to be asked about again after
+    /// `REVALIDATE_AFTER` (uploads have none)
+    pub upstream: Option<Arc<Upstream>>,
+}
+
+/// The video a cached GIF was made from, and when the upstream last said it
+/// was still there. A conversion's GIF in memory and in `CACHE_DIR` share
+/// one, so they're asked about once.
+pub struct Upstream {
+    /// What was asked for under `/tweet_video/`
+    pub path: String,
+    checked: Mutex<(Instant, Validators)>,
+}
+
+impl Upstream {
+    /// The video at `path`, just fetched.
+    pub fn new(path: String) -> Self {
+        Self { path, checked: Mutex::new((Instant::now(), Validators::default())) }
+    }
+
+    /// What to ask the upstream with.
+    pub fn validators(&self) -> Validators {
+        self.checked.lock().unwrap().1.clone()
+    }
+
+    /// The upstream's just said it's still there, and what its version is.
+    pub fn checked(&self, validators: Validators) {
+        *self.checked.lock().unwrap() = (Instant::now(), validators);
+    }
+
+    fn is_due(&self, after: Duration) -> bool {
+        self.checked.lock().unwrap().0.elapsed() >= after
+    }
+}
+
+/// What `POST /admin/purge` does to a cached GIF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Purge {
+    /// Forget it, so the next request for it converts it again
+    Hard,
+    /// Mark it stale, so it's still sent while it's made again
+    Soft,
+}
+
+/// What `/stats` says about the cache.
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+}
+
+/// Finished GIFs kept in memory under their variant's key, so one asked for
+/// again is sent without being converted again (`CACHE_MAX_BYTES`). Once
+/// they add up to more than that, the least recently sent go first.
+pub struct GifCache {
+    max_bytes: u64,
+    entries: Mutex<Entries>,
+}
+
+struct Entries {
+    gifs: LruCache<String, Arc<CachedGif>>,
+    bytes: u64,
+}
+
+impl GifCache {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            entries: Mutex::new(Entries { gifs: LruCache::unbounded(), bytes: 0 }),
+        }
+    }
+
+    /// The GIF kept under `key`, if there is one.
+    pub fn get(&self, key: &str) -> Option<Arc<CachedGif>> {
+        self.entries.lock().unwrap().gifs.get(key).cloned()
+    }
+
+    /// Keep `gif` under `key`, making room for it, unless it's bigger than
+    /// the whole cache.
+    pub fn put(&self, key: String, gif: CachedGif) {
+        let size = gif.body.len() as u64;
+        if size > self.max_bytes {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(replaced) = entries.gifs.put(key, Arc::new(gif)) {
+            entries.bytes -= replaced.body.len() as u64;
+        }
+        entries.bytes += size;
+        while entries.bytes > self.max_bytes {
+            let Some((_, evicted)) = entries.gifs.pop_lru() else { break };
+            entries.bytes -= evicted.body.len() as u64;
+        }
+    }
+
+    /// Purge the GIF kept under `key`, if there is one, and say whether
+    /// there was.
+    pub fn purge(&self, key: &str, purge: Purge) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match purge {
+            Purge::Soft => entries.gifs.peek(key).map(|gif| gif.stale.store(true, Ordering::Relaxed)).is_some(),
+            Purge::Hard => match entries.gifs.pop(key) {
+                Some(purged) => {
+                    entries.bytes -= purged.body.len() as u64;
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// The GIFs whose videos haven't been asked about for `after`, with
+    /// their keys, the least recently sent first.
+    pub fn due(&self, after: Duration) -> Vec<(String, Arc<Upstream>)> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .gifs
+            .iter()
+            .rev()
+            .filter_map(|(key, gif)| Some((key.clone(), gif.upstream.clone()?)))
+            .filter(|(_, upstream)| upstream.is_due(after))
+            .collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let entries = self.entries.lock().unwrap();
+        Stats {
+            entries: entries.gifs.len(),
+            bytes: entries.bytes,
+            max_bytes: self.max_bytes,
+        }
+    }
+}
+
+/// A finished GIF kept in `CACHE_DIR`, with what its response says besides
+/// the bytes, which are read from `path` as they're sent.
+pub struct CachedFile {
+    pub path: PathBuf,
+    pub len: u64,
+    pub backend: Arc<dyn Backend>,
+    pub trimmed: Option<Trimmed>,
+    pub ignored: Vec<&'static str>,
+    pub prefetched: AtomicBool,
+    pub stale: AtomicBool,
+    pub upstream: Option<Arc<Upstream>>,
+}
+
+/// What a GIF is written to `CACHE_DIR` from: the bytes, or the temp file it
+/// was spilled to, opened afresh so it can be read alongside the response.
+pub enum Contents {
+    Memory(Bytes),
+    File(tokio::fs::File),
+}
+
+/// Finished GIFs kept on disk under their variant's key
+/// (`CACHE_DIR_MAX_BYTES`), for those too big to keep many of in memory.
+/// Each file is named for a hash of its key, and which key is in which is
+/// only known to the index here, so what a previous run left is removed at
+/// startup. Once they add up to more than the maximum, the least recently
+/// sent go first.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// What stops it writing to a nearly full disk, if anything
+    disk: Option<Arc<DiskGuard>>,
+    files: Mutex<Files>,
+}
+
+struct Files {
+    index: LruCache<String, Arc<CachedFile>>,
+    bytes: u64,
+}
+
+impl DiskCache {
+    /// A cache in `dir`, made if it isn't there, and emptied of our files
+    /// if it is.
+    pub fn new(dir: PathBuf, max_bytes: u64, disk: Option<Arc<DiskGuard>>) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut removed = 0;
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if is_ours(&path) && std::fs::remove_file(&path).is_ok() {
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            info!("Removed {} GIFs a previous run left in {}", removed, dir.display());
+        }
+        Ok(Self {
+            dir,
+            max_bytes,
+            disk,
+            files: Mutex::new(Files { index: LruCache::unbounded(), bytes: 0 }),
+        })
+    }
+
+    /// The GIF kept under `key`, if there is one.
+    pub fn get(&self, key: &str) -> Option<Arc<CachedFile>> {
+        self.files.lock().unwrap().index.get(key).cloned()
+    }
+
+    /// Purge the GIF kept under `key`, if there is one, and say whether
+    /// there was. A hard purge removes its file.
+    pub fn purge(&self, key: &str, purge: Purge) -> bool {
+        let mut files = self.files.lock().unwrap();
+        match purge {
+            Purge::Soft => files.index.peek(key).map(|gif| gif.stale.store(true, Ordering::Relaxed)).is_some(),
+            Purge::Hard => match files.index.pop(key) {
+                Some(purged) => {
+                    files.bytes -= purged.len;
+                    let _ = std::fs::remove_file(&purged.path);
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// Stop keeping `gif` under `key`, when its file couldn't be sent. One
+    /// that's replaced it since is kept.
+    pub fn forget(&self, key: &str, gif: &Arc<CachedFile>) {
+        let mut files = self.files.lock().unwrap();
+        if files.index.peek(key).is_some_and(|kept| Arc::ptr_eq(kept, gif)) {
+            files.index.pop(key);
+            files.bytes -= gif.len;
+            let _ = std::fs::remove_file(&gif.path);
+        }
+    }
+
+    /// Write the GIF in the background and keep it under `key` once it's
+    /// all there, unless it's bigger than the whole cache or the disk is
+    /// nearly full.
+    #[allow(clippy::too_many_arguments)]
+    pub fn put(
+        self: &Arc<Self>,
+        key: String,
+        contents: Contents,
+        backend: Arc<dyn Backend>,
+        trimmed: Option<Trimmed>,
+        ignored: Vec<&'static str>,
+        prefetched: bool,
+        upstream: Option<Arc<Upstream>>,
+    ) {
+        if let Some(disk) = &self.disk {
+            if disk.is_low() {
+                debug!("Not writing {} to {}: the disk is nearly full", key, self.dir.display());
+                return;
+            }
+        }
+        let name = format!("{}.{}", hex(digest::digest(&digest::SHA256, key.as_bytes()).as_ref()), backend.format().as_str());
+        let path = self.dir.join(name);
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let (len, partial) = match cache.write(&path, contents).await {
+                Ok(written) => written,
+                Err(e) => {
+                    warn!("Failed to write {} to {}: {}", key, cache.dir.display(), e);
+                    return;
+                }
+            };
+            let gif = CachedFile {
+                path,
+                len,
+                backend,
+                trimmed,
+                ignored,
+                prefetched: AtomicBool::new(prefetched),
+                stale: AtomicBool::default(),
+                upstream,
+            };
+            let _ = tokio::task::spawn_blocking(move || cache.keep(key, partial, gif)).await;
+        });
+    }
+
+    // Write `contents` to a file of its own next to `path`, which comes back
+    // with how long it is. It's only moved to `path` once it's kept, so
+    // nothing half-written is ever sent.
+    async fn write(&self, path: &Path, contents: Contents) -> io::Result<(u64, PathBuf)> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(format!(".{}{}", PARTIAL_SUFFIX, COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let partial = PathBuf::from(partial);
+        let written = async {
+            let mut file = tokio::fs::File::create(&partial).await?;
+            let len = match contents {
+                Contents::Memory(bytes) => {
+                    file.write_all(&bytes).await?;
+                    bytes.len() as u64
+                }
+                Contents::File(mut spilled) => tokio::io::copy(&mut spilled, &mut file).await?,
+            };
+            file.flush().await?;
+            match len > self.max_bytes {
+                true => Err(io::Error::other(format!("it's {} bytes, more than the whole cache", len))),
+                false => Ok(len),
+            }
+        }
+        .await;
+        match written {
+            Ok(len) => Ok((len, partial)),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                Err(e)
+            }
+        }
+    }
+
+    // Move `partial` into place as `gif` and keep it under `key`, making
+    // room for it. Done with the index locked, so a file's never removed
+    // or replaced behind the entry for it.
+    fn keep(&self, key: String, partial: PathBuf, gif: CachedFile) {
+        let mut files = self.files.lock().unwrap();
+        if let Err(e) = std::fs::rename(&partial, &gif.path) {
+            warn!("Failed to move {} into place: {}", gif.path.display(), e);
+            let _ = std::fs::remove_file(&partial);
+            return;
+        }
+        let len = gif.len;
+        // Its key's older file was the same one, so that's already gone
+        if let Some(replaced) = files.index.put(key, Arc::new(gif)) {
+            files.bytes -= replaced.len;
+        }
+        files.bytes += len;
+        while files.bytes > self.max_bytes {
+            let Some((_, evicted)) = files.index.pop_lru() else { break };
+            files.bytes -= evicted.len;
+            let _ = std::fs::remove_file(&evicted.path);
+        }
+    }
+
+    /// The GIFs whose videos haven't been asked about for `after`, with
+    /// their keys, the least recently sent first.
+    pub fn due(&self, after: Duration) -> Vec<(String, Arc<Upstream>)> {
+        let files = self.files.lock().unwrap();
+        files
+            .index
+            .iter()
+            .rev()
+            .filter_map(|(key, gif)| Some((key.clone(), gif.upstream.clone()?)))
+            .filter(|(_, upstream)| upstream.is_due(after))
+            .collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let files = self.files.lock().unwrap();
+        Stats {
+            entries: files.index.len(),
+            bytes: files.bytes,
+            max_bytes: self.max_bytes,
+        }
+    }
+}
+
+// What's written to a file before it's moved into place
+const PARTIAL_SUFFIX: &str = "partial-";
+
+// Whether `path` is a file `DiskCache` would have written, whole or partial:
+// its name starts with a SHA-256 in hex
+fn is_ours(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let (hash, _) = name.split_once('.').unwrap_or((name, ""));
+    hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
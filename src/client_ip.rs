@@ -0,0 +1,185 @@
+use axum::http::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// A network like `10.0.0.0/8` or `2001:db8::/32`. A bare address is a
+/// network of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// The network's address, with an IPv4-mapped one as plain IPv4.
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    /// How many of the leading bits of `network` are the network's.
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(network).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(network), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    network >> shift == ip >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address = address
+            .parse::<IpAddr>()
+            .map_err(|_| format!("{:?} isn't an IP address", address))?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("{:?} isn't a prefix length up to {}", prefix, bits))?,
+            None => bits,
+        };
+        // `::ffff:10.0.0.0/104` is 10.0.0.0/8, and matches either way of
+        // writing its addresses
+        let network = address.to_canonical();
+        if network.is_ipv4() && address.is_ipv6() {
+            if prefix < 96 {
+                return Err(format!("{}/{} is wider than the IPv4-mapped addresses", address, prefix));
+            }
+            return Ok(Self { network, prefix: prefix - 96 });
+        }
+        Ok(Self { network, prefix })
+    }
+}
+
+/// Which peers are proxies whose `Forwarded` / `X-Forwarded-For` we believe
+/// (`TRUST_PROXY`).
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    /// The direct peer is trusted whatever it is, but the addresses it
+    /// forwards aren't, so only the entry it added counts
+    any_peer: bool,
+    networks: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    pub fn is_empty(&self) -> bool {
+        !self.any_peer && self.networks.is_empty()
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    /// `false` (nobody), `true` (whoever connects, as a single proxy in front
+    /// of us), or a comma-separated list of networks.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" | "false" => Ok(Self::default()),
+            "true" => Ok(Self { any_peer: true, networks: Vec::new() }),
+            list => Ok(Self {
+                any_peer: false,
+                networks: list
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|network| !network.is_empty())
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?,
+            }),
+        }
+    }
+}
+
+/// Work out who the client is. When the peer is a trusted proxy, that's the
+/// rightmost address in `Forwarded` (or else `X-Forwarded-For`) that isn't
+/// one of our proxies, since everything to the left of that could have been
+/// made up by the client. Otherwise it's whoever is on the other end of the
+/// socket, whatever the headers say.
+pub fn resolve(headers: &HeaderMap, peer: IpAddr, trusted: &TrustedProxies) -> IpAddr {
+    let peer = peer.to_canonical();
+    if !trusted.any_peer && !trusted.networks.iter().any(|network| network.contains(peer)) {
+        return peer;
+    }
+    let mut hops = forwarded_for(headers);
+    if hops.is_empty() {
+        hops = x_forwarded_for(headers);
+    }
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        // Whatever our proxy can't name (`unknown`, `_hidden`, garbage) stops the
+        // walk, and the last address we could trust is as far as it gets
+        let Some(ip) = parse_node(hop) else { break };
+        client = ip.to_canonical();
+        if !trusted.networks.iter().any(|network| network.contains(client)) {
+            break;
+        }
+    }
+    client
+}
+
+// `Forwarded: for=192.0.2.60;proto=http, for="[2001:db8::17]:4711"`, the
+// `for` of every element in order, across all of the headers. Our proxy
+// appended the last.
+fn forwarded_for(headers: &HeaderMap) -> Vec<&str> {
+    headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .map_or("", |(_, node)| node)
+        })
+        .collect()
+}
+
+// `X-Forwarded-For: client, proxy1, proxy2`, across all of the headers
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<&str> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect()
+}
+
+// Accepts `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1`, `[2001:db8::1]:80`, optionally quoted
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    // `[2001:db8::1]` without a port
+    node.strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .and_then(|ip| ip.parse().ok())
+}
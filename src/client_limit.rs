@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Who a conversion is counted against: their API key when keys are
+/// required, or else their address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClientKey {
+    ApiKey(Arc<str>),
+    Ip(IpAddr),
+}
+
+/// How many conversions each client has running (`MAX_CONCURRENT_PER_CLIENT`),
+/// so one client can't take up every slot there is. Only clients with a
+/// conversion running are kept, and every one of those holds a slot, so this
+/// never has more clients in it than there are conversions admitted at once.
+#[derive(Debug)]
+pub struct ClientConcurrency {
+    limit: usize,
+    running: Mutex<HashMap<ClientKey, usize>>,
+}
+
+impl ClientConcurrency {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, running: Mutex::default() }
+    }
+
+    /// How many conversions each client may have running.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// A slot for one more of `client`'s conversions, to be held until it's
+    /// done, or `None` if they already have as many running as they may.
+    pub fn acquire(self: &Arc<Self>, client: ClientKey) -> Option<ClientSlot> {
+        let mut running = self.running.lock().unwrap();
+        let count = running.entry(client.clone()).or_insert(0);
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(ClientSlot { limits: self.clone(), client })
+    }
+}
+
+/// One conversion counted against its client, until this is dropped.
+#[derive(Debug)]
+pub struct ClientSlot {
+    limits: Arc<ClientConcurrency>,
+    client: ClientKey,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut running = self.limits.running.lock().unwrap();
+        if let Some(count) = running.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                running.remove(&self.client);
+            }
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// What's done with a video longer than `MAX_INPUT_DURATION`
+/// (`LONG_VIDEO_POLICY`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LongVideoPolicy {
+    /// It's refused with a `422` and `input_too_long`
+    Reject,
+    /// Only its first `MAX_INPUT_DURATION` is converted
+    Trim,
+}
+
+impl std::str::FromStr for LongVideoPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(LongVideoPolicy::Reject),
+            "trim" => Ok(LongVideoPolicy::Trim),
+            other => Err(format!("unknown LONG_VIDEO_POLICY {:?}, expected reject or trim", other)),
+        }
+    }
+}
+
+/// A length of time in a query string, in seconds, e.g. `?start=1.5`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "f64", into = "f64")]
+pub struct Seconds(pub Duration);
+
+impl TryFrom<f64> for Seconds {
+    type Error = String;
+
+    fn try_from(secs: f64) -> Result<Self, Self::Error> {
+        Duration::try_from_secs_f64(secs).map(Seconds).map_err(|_| format!("{} isn't a number of seconds", secs))
+    }
+}
+
+impl From<Seconds> for f64 {
+    fn from(secs: Seconds) -> Self {
+        secs.0.as_secs_f64()
+    }
+}
+
+/// The part of a video a conversion takes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Clip {
+    /// How far in it starts
+    pub start: Option<Duration>,
+    /// How long it goes on for, if not to the end
+    pub trim: Option<Duration>,
+    /// Set when it was cut short of what was asked for, to the limit
+    pub trimmed: Option<Trimmed>,
+}
+
+/// A conversion cut short to `MAX_INPUT_DURATION`, for `X-FastGIF-Trimmed`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trimmed {
+    /// How long the whole video is, if it's known
+    pub source_duration: Option<Duration>,
+}
+
+/// What's refused for going on longer than `max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TooLong {
+    pub length: Duration,
+    pub max: Duration,
+}
+
+/// The part of a video `source_duration` long (if it's known) that
+/// converting it from `start` for `duration`, as the request asks, comes to.
+/// An explicit request is taken as it is, unless it goes on longer than
+/// `max`, when `policy` decides whether it's cut short or refused, the same
+/// as a whole video that long.
+pub fn clip(
+    start: Option<Duration>,
+    duration: Option<Duration>,
+    source_duration: Option<Duration>,
+    max: Option<Duration>,
+    policy: LongVideoPolicy,
+) -> Result<Clip, TooLong> {
+    let rest = source_duration.map(|source| source.saturating_sub(start.unwrap_or_default()));
+    let length = match (duration, rest) {
+        (Some(duration), Some(rest)) => Some(duration.min(rest)),
+        (duration, rest) => duration.or(rest),
+    };
+    match (length, max) {
+        (Some(length), Some(max)) if length > max => match policy {
+            LongVideoPolicy::Trim => Ok(Clip { start, trim: Some(max), trimmed: Some(Trimmed { source_duration }) }),
+            LongVideoPolicy::Reject => Err(TooLong { length, max }),
+        },
+        _ => Ok(Clip { start, trim: duration, trimmed: None }),
+    }
+}
+
+impl std::fmt::Display for TooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "video is {:.1}s, the limit is {:.1}s", self.length.as_secs_f64(), self.max.as_secs_f64())
+    }
+}
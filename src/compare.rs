@@ -0,0 +1,200 @@
+use crate::batch;
+use crate::jobs::new_id;
+use bytes::Bytes;
+use fastgif_core::backend::{Backend, FfmpegWebm, FfmpegWebp, Format, WebmCodec};
+use fastgif_core::encoder::{Decoder, Encoder};
+use fastgif_core::pipeline::PipelineConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The most combinations one `POST /admin/compare` can ask for.
+pub const MAX_COMBOS: usize = 8;
+
+/// How long a comparison's conversions get between them, waiting for their
+/// turns included. Those it doesn't get to are skipped.
+pub const BUDGET: Duration = Duration::from_secs(10 * 60);
+
+/// How long what a comparison made is kept for.
+pub const KEPT_FOR: Duration = Duration::from_secs(60 * 60);
+
+/// The most bytes of results kept at once, past which the oldest go.
+pub const MAX_KEPT_BYTES: u64 = 256 * 1024 * 1024;
+
+/// What `POST /admin/compare` is asked to do: convert `source` with each of
+/// `combos` in turn.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Comparison {
+    pub source: batch::Item,
+    pub combos: Vec<Combo>,
+}
+
+/// An encoder, and what to tell it, for one of a comparison's conversions.
+/// What isn't given is as configured.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Combo {
+    /// As `ENCODER` has it (`subprocess`, `native` or `ffmpeg`), or
+    /// `ffmpeg-webp` for animated WebP, or `ffmpeg-vp9` or `ffmpeg-av1` for WebM
+    pub encoder: String,
+    pub quality: Option<u8>,
+    pub fast: Option<bool>,
+}
+
+/// A combo, ready to convert with.
+pub struct Prepared {
+    pub pipeline: PipelineConfig,
+    /// In place of the pipeline's encoder, for WebP or WebM
+    pub backend: Option<Arc<dyn Backend>>,
+    pub report: ComboReport,
+}
+
+/// What one of the conversions came to.
+#[derive(Debug, Clone, Serialize)]
+pub struct ComboReport {
+    pub encoder: String,
+    pub quality: u8,
+    pub fast: bool,
+    pub format: &'static str,
+    /// `ok`, the error code the conversion failed with, `over_budget` if it
+    /// was stopped for the comparison's time running out, or `skipped` if it
+    /// had already
+    pub outcome: &'static str,
+    pub error: Option<String>,
+    pub output_bytes: Option<u64>,
+    pub wall_ms: Option<u64>,
+    /// Of ffmpeg and the gifski binary; the native encoder's isn't counted,
+    /// since it runs in the server
+    pub cpu_ms: Option<u64>,
+    /// Of whichever of those children needed the most
+    pub max_rss_bytes: Option<u64>,
+    /// Where what it made can be had, until `expires_at`
+    pub result_url: Option<String>,
+    /// Unix milliseconds
+    pub expires_at: Option<i64>,
+}
+
+/// What `POST /admin/compare` answers.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub path: String,
+    pub budget_ms: u64,
+    pub took_ms: u64,
+    /// In the order the combos were given
+    pub results: Vec<ComboReport>,
+}
+
+impl Comparison {
+    /// Each combo ready to convert with, going by `pipeline`, or what's wrong
+    /// with the comparison.
+    pub fn prepare(&self, pipeline: &PipelineConfig) -> Result<Vec<Prepared>, String> {
+        if self.combos.is_empty() || self.combos.len() > MAX_COMBOS {
+            return Err(format!("there have to be 1 to {} combos, not {}", MAX_COMBOS, self.combos.len()));
+        }
+        self.combos
+            .iter()
+            .enumerate()
+            .map(|(i, combo)| combo.prepare(pipeline).map_err(|e| format!("combo {}: {}", i, e)))
+            .collect()
+    }
+}
+
+impl Combo {
+    fn prepare(&self, pipeline: &PipelineConfig) -> Result<Prepared, String> {
+        let mut pipeline = pipeline.clone();
+        let backend: Option<Arc<dyn Backend>> = match self.encoder.as_str() {
+            "ffmpeg-webp" => Some(Arc::new(FfmpegWebp)),
+            "ffmpeg-vp9" => Some(Arc::new(FfmpegWebm(WebmCodec::Vp9))),
+            "ffmpeg-av1" => Some(Arc::new(FfmpegWebm(WebmCodec::Av1))),
+            encoder => {
+                let encoder: Encoder = encoder.parse()?;
+                if !encoder.is_available() {
+                    return Err(format!("this build has no {} encoder", self.encoder));
+                }
+                pipeline.encoder = encoder;
+                None
+            }
+        };
+        // libav's frames only ever go to the native encoder, which would
+        // make every combo the same
+        pipeline.decoder = Decoder::Ffmpeg;
+        if let Some(quality) = self.quality {
+            if !(1..=100).contains(&quality) {
+                return Err(format!("quality has to be 1-100, not {}", quality));
+            }
+            pipeline.gif_settings.quality = quality;
+        }
+        pipeline.gif_settings.fast = self.fast.unwrap_or(pipeline.gif_settings.fast);
+        let format = backend.as_ref().map_or(Format::Gif, |backend| backend.format());
+        let report = ComboReport {
+            encoder: self.encoder.clone(),
+            quality: pipeline.gif_settings.quality,
+            fast: pipeline.gif_settings.fast,
+            format: format.as_str(),
+            outcome: "skipped",
+            error: None,
+            output_bytes: None,
+            wall_ms: None,
+            cpu_ms: None,
+            max_rss_bytes: None,
+            result_url: None,
+            expires_at: None,
+        };
+        Ok(Prepared { pipeline, backend, report })
+    }
+}
+
+impl ComboReport {
+    /// How the audit log names it, e.g. `subprocess/q90/fast`.
+    pub fn describe(&self) -> String {
+        let fast = if self.fast { "/fast" } else { "" };
+        format!("{}/q{}{}", self.encoder, self.quality, fast)
+    }
+}
+
+/// What comparisons made, for [`KEPT_FOR`], and no more than
+/// [`MAX_KEPT_BYTES`] of it.
+#[derive(Default)]
+pub struct Results {
+    kept: Mutex<VecDeque<Kept>>,
+}
+
+struct Kept {
+    id: String,
+    body: Bytes,
+    format: Format,
+    expires: Instant,
+}
+
+impl Results {
+    /// Keep `body`, returning the ID it can be had by and the Unix
+    /// milliseconds it's kept until, unless it's too big to keep at all.
+    pub fn keep(&self, body: Bytes, format: Format) -> Option<(String, i64)> {
+        if body.len() as u64 > MAX_KEPT_BYTES {
+            return None;
+        }
+        let mut kept = self.kept.lock().unwrap();
+        let now = Instant::now();
+        kept.retain(|result| result.expires > now);
+        let mut total: u64 = kept.iter().map(|result| result.body.len() as u64).sum();
+        while total + body.len() as u64 > MAX_KEPT_BYTES {
+            let Some(oldest) = kept.pop_front() else { break };
+            total -= oldest.body.len() as u64;
+        }
+        let id = new_id();
+        kept.push_back(Kept { id: id.clone(), body, format, expires: now + KEPT_FOR });
+        let expires_at = (SystemTime::now() + KEPT_FOR).duration_since(UNIX_EPOCH).unwrap_or_default();
+        Some((id, expires_at.as_millis() as i64))
+    }
+
+    /// The result kept as `id`, if it hasn't expired.
+    pub fn get(&self, id: &str) -> Option<(Bytes, Format)> {
+        let now = Instant::now();
+        let kept = self.kept.lock().unwrap();
+        kept.iter()
+            .find(|result| result.id == id && result.expires > now)
+            .map(|result| (result.body.clone(), result.format))
+    }
+}
@@ -0,0 +1,1033 @@
+use fastgif_core::backend::Format;
+use fastgif_core::dns::IpPreference;
+use fastgif_core::encoder::{Decoder, Encoder, GifSettings};
+use fastgif_core::fetch::{FetchMode, FetcherConfig};
+use fastgif_core::hwaccel::HwaccelMode;
+use crate::hotlink::RefererPattern;
+use crate::listener::{self, Bind, Owner};
+use fastgif_core::process::{Binaries, ChildLimits};
+use fastgif_core::capabilities::{Minimums, Version};
+use fastgif_core::spill::{DiskGuard, SpillConfig};
+use fastgif_core::temp::TempManager;
+use fastgif_core::CoreConfig;
+use crate::client_ip::{Cidr, TrustedProxies};
+use crate::clip::LongVideoPolicy;
+use crate::rate_limit::Rate;
+use crate::metrics::StatsAccess;
+use crate::telemetry::LogFormat;
+use crate::statsd;
+use crate::throttle::Bandwidth;
+use crate::degrade::LoadTiers;
+use crate::jobs::Interrupted;
+use crate::usage::{Quota, Quotas};
+use anyhow::{anyhow, Result};
+use clap::{Arg, ArgAction, Args, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Everything the server can be told, each as a flag, the environment
+/// variable named in `--help`, or a key in the `--config` file. A flag wins
+/// over its variable, the variable over the file, and the file over the
+/// default. Times are in seconds.
+#[derive(Debug, Parser)]
+#[command(name = "fastgif", about = "Converts Twitter videos to GIFs", disable_version_flag = true)]
+pub struct Config {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Print what this build is, and the ffmpeg and gifski it would use, and exit
+    #[arg(short = 'V', long)]
+    pub version: bool,
+    /// Read settings from this TOML file
+    #[arg(long = "config", env = "FASTGIF_CONFIG", value_name = "PATH")]
+    pub config_file: Option<PathBuf>,
+    /// What was in it
+    #[arg(skip)]
+    pub file: Option<ConfigFile>,
+    /// Every setting as it was given, to tell what a reload changed
+    #[arg(skip)]
+    pub raw: RawSettings,
+
+    #[arg(long, env = "PORT", default_value_t = 3000)]
+    pub port: u16,
+    /// Where to listen: addresses (taking `--port`), address:ports, or `unix:<path>` for unix sockets
+    #[arg(long, env = "BIND", default_value = "0.0.0.0", value_delimiter = ',')]
+    pub bind: Vec<Bind>,
+    /// The `--bind` listeners that admin routes and /metrics answer on [default: all of them]
+    #[arg(long, env = "ADMIN_LISTENERS", value_delimiter = ',')]
+    pub admin_listeners: Vec<Bind>,
+    /// Octal mode of the unix socket, e.g. `660`
+    #[arg(long, env = "SOCKET_MODE", value_parser = listener::parse_mode)]
+    pub socket_mode: Option<u32>,
+    /// Owner of the unix socket: `user`, `user:group` or `:group`
+    #[arg(long, env = "SOCKET_OWNER")]
+    pub socket_owner: Option<Owner>,
+    /// Serve HTTPS with the PEM certificate chain in this file...
+    #[arg(long, env = "TLS_CERT", value_name = "PATH")]
+    pub tls_cert: Option<PathBuf>,
+    /// ...and the PEM private key in this one
+    #[arg(long, env = "TLS_KEY", value_name = "PATH")]
+    pub tls_key: Option<PathBuf>,
+    /// Only take HTTPS clients with a certificate signed by a CA in this PEM file
+    #[arg(long, env = "TLS_CLIENT_CA", value_name = "PATH")]
+    pub tls_client_ca: Option<PathBuf>,
+    /// With HTTPS, also serve plain HTTP on this port
+    #[arg(long, env = "HTTP_PORT")]
+    pub http_port: Option<u16>,
+    /// Also speak HTTP/2 with prior knowledge on plain HTTP listeners, as load balancers that talk h2c to backends do
+    #[arg(long, env = "H2C", action = ArgAction::Set, default_value_t = false, num_args = 0..=1, default_missing_value = "true")]
+    pub h2c: bool,
+    /// `pretty` or `json`
+    #[arg(long, env = "LOG_FORMAT", default_value = "pretty")]
+    pub log_format: LogFormat,
+    /// Which log lines are written, e.g. `info,fastgif::pipeline=debug` [default: info]
+    #[arg(long, env = "RUST_LOG")]
+    pub log_filter: Option<String>,
+    /// Report internal conversion errors to this Sentry project
+    #[arg(long, env = "SENTRY_DSN", hide_env_values = true)]
+    pub sentry_dsn: Option<String>,
+    /// Threads running the server's own async work [default: the number of cores]
+    #[arg(long, env = "WORKER_THREADS")]
+    pub worker_threads: Option<NonZeroUsize>,
+    /// Threads for blocking work, like in-process encoding and file I/O, at most
+    #[arg(long, env = "MAX_BLOCKING_THREADS", default_value = "512")]
+    pub max_blocking_threads: NonZeroUsize,
+
+    /// Conversions running at once [default: the number of cores]
+    #[arg(long = "max-concurrent", env = "MAX_CONCURRENT_CONVERSIONS")]
+    pub max_concurrent_conversions: Option<usize>,
+    /// Conversions waiting for a slot [default: 4 per concurrent conversion]
+    #[arg(long, env = "MAX_QUEUE_DEPTH")]
+    pub max_queue_depth: Option<usize>,
+    /// How long a conversion may wait for a slot
+    #[arg(long, env = "QUEUE_WAIT_TIMEOUT", default_value_t = 10.0)]
+    pub queue_wait_timeout: f64,
+    /// Let anyone see `GET /queue`, rather than only those with an API key or the admin token
+    #[arg(long, env = "QUEUE_STATUS_PUBLIC", action = ArgAction::Set, default_value_t = true, num_args = 0..=1, default_missing_value = "true")]
+    pub queue_status_public: bool,
+    /// Conversions one client (API key, or else address) may have running or queued at once; 0 for no limit
+    #[arg(long, env = "MAX_CONCURRENT_PER_CLIENT", default_value_t = 3)]
+    pub max_concurrent_per_client: usize,
+    /// Start in maintenance mode, refusing new conversions
+    #[arg(long, env = "MAINTENANCE", action = ArgAction::Set, default_value_t = false, num_args = 0..=1, default_missing_value = "true")]
+    pub maintenance: bool,
+    /// What conversions are refused with in maintenance mode
+    #[arg(long, env = "MAINTENANCE_MESSAGE", default_value = "Conversions are paused for maintenance, please try again later")]
+    pub maintenance_message: String,
+    /// Refuse them with this GIF instead of a JSON error
+    #[arg(long, env = "MAINTENANCE_GIF", value_name = "PATH")]
+    pub maintenance_gif: Option<PathBuf>,
+    /// How long refused clients are told to wait, in maintenance mode
+    #[arg(long, env = "MAINTENANCE_RETRY_AFTER", default_value_t = 300)]
+    pub maintenance_retry_after: u64,
+    /// Shed load above this load average per core
+    #[arg(long, env = "LOAD_SHED_MAX_LOAD")]
+    pub load_shed_max_load: Option<f64>,
+    /// Shed load below this much available memory, in MB
+    #[arg(long, env = "LOAD_SHED_MIN_MEMORY_MB")]
+    pub load_shed_min_memory_mb: Option<u64>,
+    /// Shed load while recent conversions take longer than this
+    #[arg(long, env = "LOAD_SHED_MAX_LATENCY")]
+    pub load_shed_max_latency: Option<f64>,
+    /// Conversions in flight at which to encode with reduced profiles, e.g. `4,8`
+    #[arg(long, env = "DEGRADE_AT")]
+    pub degrade_at: Option<String>,
+    /// How long a degraded GIF may be cached for
+    #[arg(long, env = "DEGRADED_MAX_AGE", default_value_t = 0)]
+    pub degraded_max_age: u64,
+
+    /// Conversions allowed per client, e.g. `30/minute`
+    #[arg(long, env = "RATE_LIMIT")]
+    pub rate_limit: Option<Rate>,
+    /// Batches allowed per client, on top of what `RATE_LIMIT` allows their items
+    #[arg(long, env = "BATCH_RATE_LIMIT", default_value = "6/minute")]
+    pub batch_rate_limit: Rate,
+    /// Most items a batch may have
+    #[arg(long, env = "BATCH_MAX_ITEMS", default_value_t = 10)]
+    pub batch_max_items: usize,
+    /// Items of a batch converted at once
+    #[arg(long, env = "BATCH_CONCURRENCY", default_value_t = 2)]
+    pub batch_concurrency: usize,
+    /// Clients the rate limiter keeps track of at once
+    #[arg(long, env = "RATE_LIMIT_MAX_CLIENTS", default_value = "100000")]
+    pub rate_limit_max_clients: NonZeroUsize,
+    /// Send the rate limit headers as `X-RateLimit-*` too, for older clients
+    #[arg(long, env = "RATE_LIMIT_LEGACY_HEADERS", action = ArgAction::Set, default_value_t = false, num_args = 0..=1, default_missing_value = "true")]
+    pub rate_limit_legacy_headers: bool,
+    /// Proxies whose forwarding headers say who the client is: networks, or `true` for any peer
+    #[arg(long, env = "TRUST_PROXY", default_value = "false")]
+    pub trust_proxy: TrustedProxies,
+    /// Only talk to clients in these networks [default: anyone]
+    #[arg(long, env = "ALLOW_CIDRS", value_delimiter = ',')]
+    pub allow_cidrs: Vec<Cidr>,
+    /// Never talk to clients in these networks, even allowed ones
+    #[arg(long, env = "DENY_CIDRS", value_delimiter = ',')]
+    pub deny_cidrs: Vec<Cidr>,
+
+    /// Longest a conversion may take
+    #[arg(long, env = "CONVERSION_TIMEOUT", default_value_t = 60.0)]
+    pub conversion_timeout: f64,
+    /// Niceness of ffmpeg and gifski
+    #[arg(long, env = "CHILD_NICE", allow_negative_numbers = true)]
+    pub child_nice: Option<i32>,
+    /// Address space limit of ffmpeg and gifski, in MB
+    #[arg(long, env = "CHILD_MAX_MEMORY_MB")]
+    pub child_max_memory_mb: Option<u64>,
+    /// CPU time limit of ffmpeg and gifski
+    #[arg(long, env = "CHILD_MAX_CPU_SECONDS")]
+    pub child_max_cpu_seconds: Option<u64>,
+    /// ffmpeg threads per conversion [default: the cores split between concurrent conversions]
+    #[arg(long, env = "FFMPEG_THREADS")]
+    pub ffmpeg_threads: Option<usize>,
+    /// ffmpeg filter threads per conversion [default: ffmpeg's]
+    #[arg(long, env = "FFMPEG_FILTER_THREADS")]
+    pub ffmpeg_filter_threads: Option<usize>,
+    /// Absolute path of the ffmpeg to run [default: found on PATH]
+    #[arg(long, env = "FFMPEG_PATH", value_name = "PATH")]
+    pub ffmpeg_path: Option<PathBuf>,
+    /// Absolute path of the ffprobe to run [default: found on PATH]
+    #[arg(long, env = "FFPROBE_PATH", value_name = "PATH")]
+    pub ffprobe_path: Option<PathBuf>,
+    /// Absolute path of the gifski to run [default: found on PATH]
+    #[arg(long, env = "GIFSKI_PATH", value_name = "PATH")]
+    pub gifski_path: Option<PathBuf>,
+    /// Oldest ffmpeg to start with
+    #[arg(long, env = "FFMPEG_MIN_VERSION", default_value = "4.0")]
+    pub ffmpeg_min_version: Version,
+    /// Oldest gifski to start with
+    #[arg(long, env = "GIFSKI_MIN_VERSION", default_value = "1.10")]
+    pub gifski_min_version: Version,
+    /// Start even if ffmpeg or gifski is missing, too old, or can't do what's needed
+    #[arg(long, env = "SKIP_BINARY_CHECK", action = ArgAction::Set, default_value_t = false, num_args = 0..=1, default_missing_value = "true")]
+    pub skip_binary_check: bool,
+
+    /// Longest video converted, or 0 for no limit
+    #[arg(long, env = "MAX_INPUT_DURATION", default_value_t = 45.0)]
+    pub max_input_duration: f64,
+    /// Convert the start of longer videos instead of refusing them, as `LONG_VIDEO_POLICY=trim` does
+    #[arg(long, env = "AUTO_TRIM", action = ArgAction::Set, default_value_t = false, num_args = 0..=1, default_missing_value = "true")]
+    pub auto_trim: bool,
+    /// What's done with videos longer than MAX_INPUT_DURATION: `reject`, or `trim` to convert their start [default: `trim` with AUTO_TRIM, otherwise `reject`]
+    #[arg(long, env = "LONG_VIDEO_POLICY")]
+    pub long_video_policy: Option<LongVideoPolicy>,
+    /// Who downloads the video: `ffmpeg`, or `inprocess`
+    #[arg(long, env = "UPSTREAM_FETCH", default_value = "ffmpeg")]
+    pub upstream_fetch: FetchMode,
+    /// Largest video converted, in bytes
+    #[arg(long, env = "MAX_INPUT_BYTES")]
+    pub max_input_bytes: Option<u64>,
+    /// Largest GIF sent, in bytes
+    #[arg(long, env = "MAX_OUTPUT_BYTES")]
+    pub max_output_bytes: Option<u64>,
+    /// Largest video `POST /convert` takes, in bytes
+    #[arg(long, env = "MAX_UPLOAD_BYTES", default_value_t = 100 * 1024 * 1024)]
+    pub max_upload_bytes: u64,
+    /// Seconds a finished `POST /jobs` job is kept for
+    #[arg(long, env = "JOB_TTL", default_value_t = 600)]
+    pub job_ttl: u64,
+    /// Seconds the response to a POST with an `Idempotency-Key` is sent
+    /// again for, when it's retried (0 turns it off)
+    #[arg(long, env = "IDEMPOTENCY_TTL", default_value_t = 86400)]
+    pub idempotency_ttl: u64,
+    /// Bytes of those responses kept in all
+    #[arg(long, env = "IDEMPOTENCY_MAX_BYTES", default_value_t = 64 * 1024 * 1024)]
+    pub idempotency_max_bytes: u64,
+    /// Most jobs kept at once, finished or not
+    #[arg(long, env = "MAX_JOBS", default_value_t = 1000)]
+    pub max_jobs: usize,
+    /// SQLite database jobs are kept in, so they outlast a restart [default: only in memory]
+    #[arg(long, env = "JOB_DB_PATH")]
+    pub job_db_path: Option<PathBuf>,
+    /// What becomes of jobs that were running when the server stopped: `fail` or `retry`
+    #[arg(long, env = "INTERRUPTED_JOBS", default_value = "fail")]
+    pub interrupted_jobs: Interrupted,
+    /// Seconds a job's WebSocket can go without hearing from its client
+    #[arg(long, env = "JOB_SOCKET_IDLE_TIMEOUT", default_value_t = 60)]
+    pub job_socket_idle_timeout: u64,
+    /// What job callbacks are signed with; without it, jobs can't have callbacks
+    #[arg(long, env = "WEBHOOK_SECRET", hide_env_values = true)]
+    pub webhook_secret: Option<String>,
+    /// Internal networks job callbacks may be sent to anyway
+    #[arg(long, env = "WEBHOOK_ALLOW_CIDRS", value_delimiter = ',')]
+    pub webhook_allow_cidrs: Vec<Cidr>,
+    /// S3 bucket finished GIFs are uploaded to, to be fetched from there instead
+    #[arg(long, env = "S3_BUCKET")]
+    pub s3_bucket: Option<String>,
+    /// Where the bucket's API is, for S3-compatible stores [default: AWS's, for S3_REGION]
+    #[arg(long, env = "S3_ENDPOINT")]
+    pub s3_endpoint: Option<String>,
+    #[arg(long, env = "S3_REGION", default_value = "us-east-1")]
+    pub s3_region: String,
+    #[arg(long, env = "AWS_ACCESS_KEY_ID", hide_env_values = true)]
+    pub s3_access_key_id: Option<String>,
+    #[arg(long, env = "AWS_SECRET_ACCESS_KEY", hide_env_values = true)]
+    pub s3_secret_access_key: Option<String>,
+    /// For temporary credentials
+    #[arg(long, env = "AWS_SESSION_TOKEN", hide_env_values = true)]
+    pub s3_session_token: Option<String>,
+    /// What uploaded GIFs' keys start with, before the date they were made
+    #[arg(long, env = "S3_KEY_PREFIX", default_value = "fastgif/")]
+    pub s3_key_prefix: String,
+    /// Seconds a presigned URL to an uploaded GIF works for
+    #[arg(long, env = "S3_PRESIGN_TTL", default_value_t = 3600)]
+    pub s3_presign_ttl: u64,
+    #[arg(long, env = "UPSTREAM_CONNECT_TIMEOUT")]
+    pub upstream_connect_timeout: Option<f64>,
+    #[arg(long, env = "UPSTREAM_READ_TIMEOUT")]
+    pub upstream_read_timeout: Option<f64>,
+    /// Idle connections kept per upstream host
+    #[arg(long, env = "UPSTREAM_POOL_MAX_IDLE")]
+    pub upstream_pool_max_idle: Option<usize>,
+    #[arg(long, env = "UPSTREAM_POOL_IDLE_TIMEOUT")]
+    pub upstream_pool_idle_timeout: Option<f64>,
+    #[arg(long, env = "UPSTREAM_DNS_TTL")]
+    pub upstream_dns_ttl: Option<f64>,
+    /// Times a download is tried again after no response or a 5xx [default: 1]
+    #[arg(long, env = "UPSTREAM_RETRIES")]
+    pub upstream_retries: Option<u32>,
+    /// Times a video the upstream serves without its `moov` atom is asked for again, 2 seconds apart
+    #[arg(long, env = "MOOV_RETRIES", default_value_t = 2)]
+    pub moov_retries: u32,
+    /// Which of the upstream's addresses are connected to: `auto` for both, racing one family against the other, or only `v4` or `v6` [default: auto]
+    #[arg(long, env = "UPSTREAM_IP_PREFERENCE")]
+    pub upstream_ip_preference: Option<IpPreference>,
+    /// Read videos from this directory instead of `VIDEO_BASE_URL`
+    #[arg(long, env = "SOURCE_DIR", value_name = "DIR")]
+    pub source_dir: Option<PathBuf>,
+    /// Where videos are fetched from
+    #[arg(long, env = "VIDEO_BASE_URL", default_value = "https://video.twimg.com")]
+    pub video_base_url: String,
+
+    /// Where temp files go [default: the system's]
+    #[arg(long, env = "TMP_DIR")]
+    pub tmp_dir: Option<PathBuf>,
+    /// How often, in seconds, the janitor tidies up after us, or 0 not to
+    #[arg(long, env = "JANITOR_INTERVAL", default_value_t = 300)]
+    pub janitor_interval: u64,
+    /// Seconds after which one of our temp files, or the directory of an
+    /// instance that's stopped touching it, is taken to be left over
+    #[arg(long, env = "TEMP_FILE_MAX_AGE", default_value_t = 3600)]
+    pub temp_file_max_age: u64,
+    /// Most bytes this instance's temp files can take up between them
+    /// [default: no limit]
+    #[arg(long, env = "TEMP_MAX_BYTES")]
+    pub temp_max_bytes: Option<u64>,
+    /// Most files the janitor looks at a second, so a sweep can't hog the disk
+    #[arg(long, env = "JANITOR_MAX_FILES_PER_SEC", default_value_t = 1000)]
+    pub janitor_max_files_per_sec: u32,
+    /// GIFs bigger than this many bytes are written to a temp file
+    #[arg(long, env = "SPILL_THRESHOLD_BYTES")]
+    pub spill_threshold_bytes: Option<u64>,
+    /// Bytes that have to be free on TMP_DIR's disk for a temp file to be
+    /// written there [default: no minimum]
+    #[arg(long, env = "MIN_FREE_DISK_BYTES")]
+    pub min_free_disk_bytes: Option<u64>,
+    /// Bytes of finished GIFs kept in memory to be sent again [default: none]
+    #[arg(long, env = "CACHE_MAX_BYTES")]
+    pub cache_max_bytes: Option<u64>,
+    /// Where finished GIFs are kept on disk to be sent again [default: nowhere]
+    #[arg(long, env = "CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+    /// Bytes of finished GIFs kept in CACHE_DIR
+    #[arg(long, env = "CACHE_DIR_MAX_BYTES", default_value_t = 1024 * 1024 * 1024)]
+    pub cache_dir_max_bytes: u64,
+    /// Seconds after which a cached GIF's video is asked about again
+    /// upstream, or 0 not to [default: never]
+    #[arg(long, env = "REVALIDATE_AFTER", default_value_t = 0)]
+    pub revalidate_after: u64,
+    /// Most cached GIFs' videos asked about again a second
+    #[arg(long, env = "REVALIDATE_MAX_PER_SEC", default_value_t = 10)]
+    pub revalidate_max_per_sec: u32,
+    /// Most paths `GET /admin/top` keeps counts for, or 0 not to count
+    #[arg(long, env = "POPULARITY_MAX_PATHS", default_value_t = 10_000)]
+    pub popularity_max_paths: usize,
+    /// Most prefetches waiting for a turn or converting at once
+    #[arg(long, env = "MAX_PENDING_PREFETCHES", default_value_t = 100)]
+    pub max_pending_prefetches: usize,
+
+    /// 1-100 [default: 90]
+    #[arg(long, env = "GIF_QUALITY")]
+    pub gif_quality: Option<u8>,
+    /// Encode faster at some cost in quality [default: true]
+    #[arg(long, env = "GIF_FAST", action = ArgAction::Set, num_args = 0..=1, default_missing_value = "true")]
+    pub gif_fast: Option<bool>,
+    /// Frame rate of the GIF, up to 50 [default: 20]
+    #[arg(long, env = "GIF_FPS")]
+    pub gif_fps: Option<f32>,
+    /// `subprocess`, `native` or `ffmpeg` [default: subprocess, or ffmpeg without gifski]
+    #[arg(long, env = "ENCODER")]
+    pub encoder: Option<Encoder>,
+    /// `ffmpeg` or `libav`
+    #[arg(long, env = "DECODER", default_value = "ffmpeg")]
+    pub decoder: Decoder,
+    /// Decode on the GPU: `auto`, `vaapi`, `nvdec`, `videotoolbox` or `off`
+    #[arg(long, env = "HWACCEL", default_value = "off")]
+    pub hwaccel: HwaccelMode,
+    /// Run every GIF through this optimizer
+    #[arg(long, env = "POST_OPTIMIZE", value_parser = ["gifsicle"])]
+    pub post_optimize: Option<String>,
+    /// How long the optimizer gets
+    #[arg(long, env = "POST_OPTIMIZE_TIMEOUT", default_value_t = 10.0)]
+    pub post_optimize_timeout: f64,
+    /// Convert long videos in up to this many segments at once
+    #[arg(long, env = "PARALLEL_SEGMENTS")]
+    pub parallel_segments: Option<usize>,
+    /// Refuse requests asking for what the encoder can't do, instead of ignoring that part
+    #[arg(long, env = "STRICT_PARAMS", action = ArgAction::Set, default_value_t = false, num_args = 0..=1, default_missing_value = "true")]
+    pub strict_params: bool,
+    /// Pick lower quality, fps and width for long, big or busy videos when a
+    /// request asks for none of its own
+    #[arg(long, env = "AUTO_QUALITY", action = ArgAction::Set, default_value_t = false, num_args = 0..=1, default_missing_value = "true")]
+    pub auto_quality: bool,
+    /// Send GIFs as they're encoded
+    #[arg(long, env = "STREAM_RESPONSE", action = ArgAction::Set, default_value_t = false, num_args = 0..=1, default_missing_value = "true")]
+    pub stream_response: bool,
+
+    /// Cap on how fast any one GIF is sent, in bytes per second
+    #[arg(long, env = "THROTTLE_BYTES_PER_SEC")]
+    pub throttle_bytes_per_sec: Option<u64>,
+    /// GIFs smaller than this are sent at full speed
+    #[arg(long, env = "THROTTLE_MIN_BYTES", default_value_t = 1024 * 1024)]
+    pub throttle_min_bytes: u64,
+    /// Cap on how fast all GIFs together are sent, e.g. `200Mbit/s`
+    #[arg(long, env = "EGRESS_LIMIT")]
+    pub egress_limit: Option<Bandwidth>,
+    /// Drop clients that take less than `--slow-client-min-bytes` in this long
+    #[arg(long, env = "SLOW_CLIENT_TIMEOUT")]
+    pub slow_client_timeout: Option<f64>,
+    #[arg(long, env = "SLOW_CLIENT_MIN_BYTES", default_value_t = 64 * 1024)]
+    pub slow_client_min_bytes: u64,
+    /// How long in-flight conversions get to finish on shutdown
+    #[arg(long, env = "SHUTDOWN_GRACE", default_value_t = 30.0)]
+    pub shutdown_grace: f64,
+
+    /// Serve `/metrics` on this port instead of the public one
+    #[arg(long, env = "METRICS_PORT")]
+    pub metrics_port: Option<u16>,
+    /// Push metrics to the StatsD agent at this address, e.g. `127.0.0.1:8125`
+    #[arg(long, env = "STATSD_ADDR")]
+    pub statsd_addr: Option<String>,
+    /// Tags sent with every StatsD metric, e.g. `env:prod,region:eu`
+    #[arg(long, env = "STATSD_TAGS")]
+    pub statsd_tags: Option<String>,
+    #[arg(long, env = "STATSD_PREFIX", default_value = "fastgif.")]
+    pub statsd_prefix: String,
+    /// What StatsD's `instance` tag says [default: $HOSTNAME]
+    #[arg(long, env = "INSTANCE_NAME")]
+    pub instance_name: Option<String>,
+    /// Log a summary of what happened this often
+    #[arg(long, env = "STATS_INTERVAL")]
+    pub stats_interval: Option<u64>,
+    /// Requests taking longer than this are logged in detail
+    #[arg(long, env = "SLOW_REQUEST_THRESHOLD")]
+    pub slow_request_threshold: Option<f64>,
+    /// Append a record of every conversion to this file
+    #[arg(long, env = "AUDIT_LOG_PATH")]
+    pub audit_log_path: Option<PathBuf>,
+    /// Have `/healthz` run a test conversion
+    #[arg(long, env = "HEALTH_DEEP", action = ArgAction::Set, default_value_t = false, num_args = 0..=1, default_missing_value = "true")]
+    pub health_deep: bool,
+    /// Put ffmpeg's and gifski's stderr in failed conversions' responses
+    #[arg(long, env = "ERROR_DETAIL", action = ArgAction::Set, default_value_t = false, num_args = 0..=1, default_missing_value = "true")]
+    pub error_detail: bool,
+    /// GIF to answer `200` with when a video is missing, too big or too slow, instead of the error
+    #[arg(long, env = "FAILURE_PLACEHOLDER_PATH", value_name = "PATH")]
+    pub failure_placeholder_path: Option<PathBuf>,
+    /// Who `/stats` answers: `public`, `summary` (everything else needs
+    /// ADMIN_TOKEN) or `admin` (all of it does)
+    #[arg(long, env = "STATS_ACCESS", default_value = "public")]
+    pub stats_access: StatsAccess,
+    /// Bearer token for `/admin`, which doesn't exist without one
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+    /// Keys that conversions need, as a Bearer token or `?key=`
+    #[arg(long, env = "API_KEYS", value_delimiter = ',', hide_env_values = true)]
+    pub api_keys: Vec<String>,
+    /// A file of `<key> <name>` lines, more keys that conversions need
+    #[arg(long, env = "API_KEYS_FILE", value_name = "PATH")]
+    pub api_keys_file: Option<PathBuf>,
+    /// Conversions each API key may run in a UTC day, unless API_KEY_QUOTAS says otherwise (0 for no limit)
+    #[arg(long, env = "QUOTA_CONVERSIONS_PER_DAY", default_value_t = 0)]
+    pub quota_conversions_per_day: u64,
+    /// Seconds of ffmpeg and gifski CPU time each API key may use in a UTC day (0 for no limit)
+    #[arg(long, env = "QUOTA_CPU_SECONDS_PER_DAY", default_value_t = 0)]
+    pub quota_cpu_seconds_per_day: u64,
+    /// Quotas of particular API keys, by name, e.g. `partner-a=1000/3600,partner-b=-/600`
+    #[arg(long, env = "API_KEY_QUOTAS")]
+    pub api_key_quotas: Option<String>,
+    /// Sites GIFs may be embedded on, as hostnames or `*.hostname` [default: anywhere]
+    #[arg(long, env = "ALLOWED_REFERERS", value_delimiter = ',')]
+    pub allowed_referers: Vec<RefererPattern>,
+    /// With `--allowed-referers`, let through requests that have no Referer
+    #[arg(long, env = "ALLOW_EMPTY_REFERER", action = ArgAction::Set, default_value_t = true, num_args = 0..=1, default_missing_value = "true")]
+    pub allow_empty_referer: bool,
+    /// Redirect requests from other sites to this image, rather than refusing them
+    #[arg(long, env = "HOTLINK_PLACEHOLDER_URL", value_name = "URL")]
+    pub hotlink_placeholder_url: Option<reqwest::Url>,
+}
+
+/// Each setting's values, keyed by its name in the config file.
+pub type RawSettings = BTreeMap<String, Vec<OsString>>;
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Check a config file without starting the server, exiting nonzero if
+    /// anything in it is wrong
+    CheckConfig {
+        /// The file [default: --config]
+        path: Option<PathBuf>,
+    },
+    /// Ask the running server's `/healthz` whether it's healthy, exiting
+    /// nonzero if it isn't or doesn't answer in time
+    Healthcheck {
+        /// What to ask [default: /healthz on HTTP_PORT, or the first of BIND]
+        #[arg(long)]
+        url: Option<reqwest::Url>,
+        /// Seconds to wait for an answer
+        #[arg(long, default_value_t = 3.0)]
+        timeout: f64,
+        /// Check the binaries and directories the way starting up would,
+        /// instead of asking a server
+        #[arg(long)]
+        offline: bool,
+    },
+    /// Convert a local video the way the server would, with the same
+    /// settings, and say where the time went. Exits nonzero with the
+    /// error's class if it fails.
+    Convert(ConvertArgs),
+    /// Convert the video on stdin the way the server would, writing what it
+    /// makes to stdout and everything else to stderr. The exit code says
+    /// which part failed, if anything did.
+    Pipe(ConversionArgs),
+    /// Request a list of paths from a running server, so they're converted
+    /// (and cached in front of it) before anyone asks, and sum up how it went.
+    /// Exits nonzero if any of them failed.
+    Warm(WarmArgs),
+}
+
+/// Which video `fastgif convert` converts, and where to.
+#[derive(Debug, Args)]
+pub struct ConvertArgs {
+    /// The video
+    pub input: PathBuf,
+    /// Where the GIF goes, or `-` for stdout
+    #[arg(short, long)]
+    pub output: PathBuf,
+    #[command(flatten)]
+    pub conversion: ConversionArgs,
+}
+
+/// What `fastgif convert` and `fastgif pipe` make. Anything not given here
+/// is whatever the server would use.
+#[derive(Debug, Args)]
+pub struct ConversionArgs {
+    /// Scale wider videos down to this many pixels
+    #[arg(long)]
+    pub width: Option<u32>,
+    /// Frames per second [default: GIF_FPS]
+    #[arg(long)]
+    pub fps: Option<f32>,
+    /// 1-100 [default: GIF_QUALITY]
+    #[arg(long)]
+    pub quality: Option<u8>,
+    /// What to make
+    #[arg(long, default_value = "gif")]
+    pub format: Format,
+}
+
+/// What `fastgif warm` requests, and from where.
+#[derive(Debug, Args)]
+pub struct WarmArgs {
+    /// The server, e.g. `https://gif.example.com`
+    #[arg(long)]
+    pub server: reqwest::Url,
+    /// A file of what to request, one to a line: a URL, a path on the
+    /// server, or a video's name for `/tweet_video/`. Blank lines and ones
+    /// starting with `#` are skipped.
+    #[arg(long)]
+    pub list: PathBuf,
+    /// How many requests are in flight at once
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+    /// Query parameters added to every request, e.g. `width=480`
+    #[arg(long)]
+    pub params: Option<String>,
+    /// Sent as a Bearer token, for a server with API_KEYS
+    #[arg(long, env = "FASTGIF_API_KEY", hide_env_values = true)]
+    pub api_key: Option<String>,
+    /// How many more times a request that failed in passing is tried
+    #[arg(long, default_value_t = 2)]
+    pub retries: u32,
+    /// Seconds each request gets, conversion included
+    #[arg(long, default_value_t = 120.0)]
+    pub timeout: f64,
+    /// Only send HEAD requests, to check what's there
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+impl Config {
+    /// Parse the command line, environment and config file, exiting with
+    /// usage on an invalid flag or variable. A variable that's set but empty
+    /// counts as unset. Unknown keys in the file are left in `file` to be
+    /// warned about; anything else wrong with it is an error, unless we're
+    /// only here to check it.
+    pub fn load() -> Result<Self> {
+        Self::read(std::env::args_os(), true)
+    }
+
+    /// The same again, for a reload, when the config file may have changed.
+    /// Nothing is fatal this time.
+    pub fn reload() -> Result<Self> {
+        Self::read(std::env::args_os(), false)
+    }
+
+    /// Settings from the environment and `FASTGIF_CONFIG` alone, ignoring
+    /// the command line, for starting a server from code rather than a shell.
+    pub fn from_env() -> Result<Self> {
+        Self::read(["fastgif"], false)
+    }
+
+    fn read(args: impl IntoIterator<Item = impl Into<OsString> + Clone>, starting: bool) -> Result<Self> {
+        let fail = |e: clap::Error| if starting { e.exit() } else { anyhow!("{}", e.render()) };
+        let mut command = Self::command().mut_args(|arg| match arg.get_env() {
+            Some(name) if std::env::var_os(name).is_some_and(|value| value.is_empty()) => arg.env(None),
+            _ => arg,
+        });
+        command.build();
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        let matches = command.clone().try_get_matches_from(&args).map_err(fail)?;
+        let (path, checking) = match matches.subcommand() {
+            Some(("check-config", check)) => (check.get_one::<PathBuf>("path").or(matches.get_one("config_file")), true),
+            _ => (matches.get_one("config_file"), false),
+        };
+        let Some(path) = path else {
+            return Self::from_matches(&matches).map_err(fail);
+        };
+        let file = ConfigFile::read(path, &command)?;
+        let matches = if checking && !file.invalid.is_empty() {
+            matches
+        } else if !file.invalid.is_empty() {
+            return Err(anyhow!("Invalid settings in {}:\n  {}", path.display(), file.invalid.join("\n  ")));
+        } else {
+            // What the file says becomes the default, so variables and flags still win
+            command
+                .mut_args(|arg| match file.value(&arg) {
+                    Some(value) => arg.default_value(value.to_string()),
+                    None => arg,
+                })
+                .try_get_matches_from(&args)
+                .map_err(fail)?
+        };
+        let mut config = Self::from_matches(&matches).map_err(fail)?;
+        config.file = Some(file);
+        Ok(config)
+    }
+
+    fn from_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let mut config = Self::from_arg_matches(matches)?;
+        config.raw = Self::command()
+            .get_arguments()
+            .filter(|arg| is_setting(arg))
+            .map(|arg| {
+                let id = arg.get_id().as_str();
+                let values = matches.get_raw(id).map(|values| values.map(OsStr::to_os_string).collect());
+                (id.to_string(), values.unwrap_or_default())
+            })
+            .collect();
+        Ok(config)
+    }
+
+    /// The settings whose values are different from what they were in `raw`.
+    pub fn changed_since(&self, raw: &RawSettings) -> Vec<&str> {
+        self.raw
+            .iter()
+            .filter(|(id, values)| raw.get(*id) != Some(values))
+            .map(|(id, _)| id.as_str())
+            .collect()
+    }
+
+    /// `INSTANCE_NAME`, or else the host's name.
+    pub fn instance(&self) -> Option<String> {
+        self.instance_name
+            .clone()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .filter(|name| !name.is_empty())
+    }
+
+    /// The quotas API keys are held to: the QUOTA_* defaults, but for the
+    /// keys API_KEY_QUOTAS names.
+    pub fn quotas(&self) -> Result<Quotas, String> {
+        let default = Quota {
+            conversions: Some(self.quota_conversions_per_day).filter(|limit| *limit > 0),
+            cpu_seconds: Some(self.quota_cpu_seconds_per_day).filter(|limit| *limit > 0),
+        };
+        Quotas::parse(self.api_key_quotas.as_deref(), default)
+    }
+
+    /// Where ffmpeg, ffprobe and gifski are. One that's set has to be there.
+    pub fn binaries(&self) -> Result<Binaries> {
+        Ok(Binaries::resolve(self.ffmpeg_path.as_deref(), self.ffprobe_path.as_deref(), self.gifski_path.as_deref())?)
+    }
+
+    /// What keeps temp files off `TMP_DIR` once it's nearly full, if anything.
+    pub fn disk_guard(&self) -> Option<Arc<DiskGuard>> {
+        let dir = self.tmp_dir.clone().unwrap_or_else(std::env::temp_dir);
+        self.min_free_disk_bytes.filter(|min| *min > 0).map(|min| Arc::new(DiskGuard::new(dir, min)))
+    }
+
+    /// What keeps GIFs out of `CACHE_DIR` once its disk is nearly full,
+    /// going by the same minimum.
+    pub fn cache_dir_guard(&self) -> Option<Arc<DiskGuard>> {
+        let dir = self.cache_dir.clone()?;
+        self.min_free_disk_bytes.filter(|min| *min > 0).map(|min| Arc::new(DiskGuard::new(dir, min)))
+    }
+
+    /// What converts the videos: everything the conversions themselves are
+    /// told, for the server or `fastgif convert`. ffmpeg gets every core,
+    /// and failures aren't counted, unless the caller says otherwise.
+    pub fn core_config(&self) -> CoreConfig {
+        let child_limits = ChildLimits {
+            nice: self.child_nice,
+            max_memory_bytes: self.child_max_memory_mb.map(|mb| mb * 1024 * 1024),
+            max_cpu_seconds: self.child_max_cpu_seconds,
+        };
+        let default_fetcher = FetcherConfig::default();
+        let fetcher = FetcherConfig {
+            connect_timeout: self
+                .upstream_connect_timeout
+                .filter(|secs| *secs > 0.0)
+                .map_or(default_fetcher.connect_timeout, Duration::from_secs_f64),
+            read_timeout: self
+                .upstream_read_timeout
+                .filter(|secs| *secs > 0.0)
+                .map(Duration::from_secs_f64),
+            max_idle_per_host: self.upstream_pool_max_idle.unwrap_or(default_fetcher.max_idle_per_host),
+            idle_timeout: self
+                .upstream_pool_idle_timeout
+                .filter(|secs| *secs >= 0.0)
+                .map_or(default_fetcher.idle_timeout, Duration::from_secs_f64),
+            dns_ttl: self
+                .upstream_dns_ttl
+                .filter(|secs| *secs >= 0.0)
+                .map_or(default_fetcher.dns_ttl, Duration::from_secs_f64),
+            retries: self.upstream_retries.unwrap_or(default_fetcher.retries),
+            ip_preference: self.upstream_ip_preference.unwrap_or(default_fetcher.ip_preference),
+            ..default_fetcher
+        };
+        // Every temp file goes in a directory of this instance's own, and big
+        // GIFs can be written to one instead of being held in memory
+        let temp = TempManager::new(
+            self.tmp_dir.clone().unwrap_or_else(std::env::temp_dir),
+            self.temp_max_bytes.filter(|max| *max > 0),
+            Duration::from_secs(self.temp_file_max_age),
+            self.disk_guard(),
+        );
+        let default_settings = GifSettings::default();
+        CoreConfig {
+            ffmpeg_path: self.ffmpeg_path.clone(),
+            ffprobe_path: self.ffprobe_path.clone(),
+            gifski_path: self.gifski_path.clone(),
+            encoder: self.encoder,
+            decoder: self.decoder,
+            hwaccel: self.hwaccel,
+            timeout: Duration::from_secs_f64(self.conversion_timeout.max(1.0)),
+            moov_retries: self.moov_retries,
+            child_limits,
+            ffmpeg_threads: self.ffmpeg_threads,
+            ffmpeg_filter_threads: self.ffmpeg_filter_threads,
+            max_output_bytes: self.max_output_bytes,
+            max_input_bytes: self.max_input_bytes,
+            fetch_mode: self.upstream_fetch,
+            fetcher,
+            source_dir: self.source_dir.clone(),
+            spill: self.spill_threshold_bytes.map(|threshold| SpillConfig { threshold }),
+            temp: Arc::new(temp),
+            gif_settings: GifSettings {
+                quality: self.gif_quality.unwrap_or(default_settings.quality),
+                fast: self.gif_fast.unwrap_or(default_settings.fast),
+                fps: self.gif_fps.unwrap_or(default_settings.fps),
+                ..default_settings
+            },
+            post_optimize: self.post_optimize.is_some(),
+            post_optimize_timeout: Duration::from_secs_f64(self.post_optimize_timeout.max(0.1)),
+            parallel_segments: self.parallel_segments,
+            minimums: Minimums {
+                ffmpeg: self.ffmpeg_min_version.clone(),
+                gifski: self.gifski_min_version.clone(),
+            },
+            skip_binary_check: self.skip_binary_check,
+            ..CoreConfig::default()
+        }
+    }
+
+    /// Everything wrong with how the settings go together, as one line each,
+    /// so they can all be fixed at once. Each on its own has parsed by now.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            problems.push("TLS_CERT and TLS_KEY have to be set together".to_string());
+        } else if self.tls_cert.is_none() {
+            if self.http_port.is_some() {
+                problems.push("HTTP_PORT is for serving plain HTTP alongside HTTPS, which needs TLS_CERT and TLS_KEY".to_string());
+            }
+            if self.tls_client_ca.is_some() {
+                problems.push("TLS_CLIENT_CA is for checking HTTPS clients, which needs TLS_CERT and TLS_KEY".to_string());
+            }
+        }
+        let ports = [("HTTP_PORT", self.http_port), ("METRICS_PORT", self.metrics_port)];
+        for (name, port) in ports.iter().filter_map(|(name, port)| port.map(|port| (name, port))) {
+            if self.bind.iter().any(|bind| bind.tcp(self.port).is_some_and(|addr| addr.port() == port)) {
+                problems.push(format!("{} is {}, which BIND already listens on", name, port));
+            }
+        }
+        if self.http_port.is_some() && self.http_port == self.metrics_port {
+            problems.push("HTTP_PORT and METRICS_PORT can't be the same port".to_string());
+        }
+        if self.max_concurrent_conversions == Some(0) {
+            problems.push(match self.max_queue_depth {
+                Some(_) => "MAX_QUEUE_DEPTH queues behind running conversions, so MAX_CONCURRENT_CONVERSIONS can't be 0",
+                None => "MAX_CONCURRENT_CONVERSIONS has to be at least 1",
+            }.to_string());
+        }
+        // A conversion's upload or spilled GIF is still in use while it runs
+        if (self.temp_file_max_age as f64) < self.conversion_timeout {
+            problems.push(format!(
+                "TEMP_FILE_MAX_AGE ({}s) is under CONVERSION_TIMEOUT ({}s), so the janitor could remove a running conversion's files",
+                self.temp_file_max_age, self.conversion_timeout
+            ));
+        }
+        if self.janitor_max_files_per_sec == 0 {
+            problems.push("JANITOR_MAX_FILES_PER_SEC has to be at least 1".to_string());
+        }
+        if self.stats_access != StatsAccess::Public && self.admin_token.as_deref().is_none_or(str::is_empty) {
+            problems.push("STATS_ACCESS keeps /stats for ADMIN_TOKEN, so it has to be set".to_string());
+        }
+        if self.revalidate_after > 0 {
+            if self.janitor_interval == 0 {
+                problems.push("REVALIDATE_AFTER is done by the janitor, so JANITOR_INTERVAL can't be 0".to_string());
+            }
+            if self.revalidate_max_per_sec == 0 {
+                problems.push("REVALIDATE_MAX_PER_SEC has to be at least 1".to_string());
+            }
+        }
+        if let (Some(threshold), Some(max)) = (self.spill_threshold_bytes, self.max_output_bytes) {
+            if threshold > max {
+                problems.push(format!(
+                    "SPILL_THRESHOLD_BYTES ({}) is over MAX_OUTPUT_BYTES ({}), so no GIF would ever be spilled",
+                    threshold, max
+                ));
+            }
+        }
+        if self.job_socket_idle_timeout == 0 {
+            problems.push("JOB_SOCKET_IDLE_TIMEOUT has to be at least 1".to_string());
+        }
+        if self.s3_bucket.as_deref().is_some_and(|bucket| !bucket.is_empty()) {
+            if self.s3_access_key_id.is_none() || self.s3_secret_access_key.is_none() {
+                problems.push("S3_BUCKET needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY to upload with".to_string());
+            }
+            // As long as S3 lets a presigned URL last
+            if !(1..=604_800).contains(&self.s3_presign_ttl) {
+                problems.push(format!("S3_PRESIGN_TTL is {}, but has to be 1-604800 (a week)", self.s3_presign_ttl));
+            }
+        }
+        if let Some(quality) = self.gif_quality.filter(|quality| !(1..=100).contains(quality)) {
+            problems.push(format!("GIF_QUALITY is {}, but has to be 1-100", quality));
+        }
+        if let Some(fps) = self.gif_fps.filter(|fps| !(fps.is_finite() && *fps > 0.0)) {
+            problems.push(format!("GIF_FPS is {}, but has to be more than 0", fps));
+        }
+        if let Some(spec) = &self.degrade_at {
+            if let Err(e) = LoadTiers::parse(spec, Duration::from_secs(self.degraded_max_age)) {
+                problems.push(format!("Invalid DEGRADE_AT {:?}: {}", spec, e));
+            }
+        }
+        if let Some(spec) = &self.statsd_tags {
+            if let Err(e) = statsd::parse_tags(spec) {
+                problems.push(format!("Invalid STATSD_TAGS {:?}: {}", spec, e));
+            }
+        }
+        if let Err(e) = self.quotas() {
+            problems.push(format!("Invalid API_KEY_QUOTAS {:?}: {}", self.api_key_quotas.as_deref().unwrap_or_default(), e));
+        }
+        problems
+    }
+
+    /// [`problems`](Self::problems), as one error.
+    pub fn validate(&self) -> Result<()> {
+        match self.problems().as_slice() {
+            [] => Ok(()),
+            problems => Err(anyhow!("Invalid configuration:\n  {}", problems.join("\n  "))),
+        }
+    }
+
+    /// Every setting that has a value, as `VARIABLE=value`, for logging what
+    /// the server is really running with. Secrets are left out.
+    pub fn effective(&self) -> String {
+        Self::command()
+            .get_arguments()
+            .filter(|arg| is_setting(arg))
+            .filter_map(|arg| {
+                let values = self.raw.get(arg.get_id().as_str()).filter(|values| !values.is_empty())?;
+                let name = arg.get_env().map_or_else(|| arg.get_id().to_string(), |env| env.to_string_lossy().into_owned());
+                let value = if arg.is_hide_env_values_set() {
+                    "[redacted]".to_string()
+                } else {
+                    values.iter().map(|value| value.to_string_lossy()).collect::<Vec<_>>().join(",")
+                };
+                Some(format!("{}={}", name, value))
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Every setting at its default, as if nothing had been set anywhere; the
+/// environment isn't looked at.
+impl Default for Config {
+    fn default() -> Self {
+        let matches = Self::command()
+            .mut_args(|arg| arg.env(None))
+            .try_get_matches_from(["fastgif"])
+            .expect("the defaults are valid");
+        Self::from_matches(&matches).expect("the defaults are valid")
+    }
+}
+
+/// `fastgif check-config`: say what's wrong with the config file, if anything.
+pub fn check(config: &Config) -> Result<()> {
+    let Some(file) = &config.file else {
+        return Err(anyhow!("No config file to check: give its path, or --config"));
+    };
+    for key in &file.unknown {
+        eprintln!("{}: unknown key", key);
+    }
+    for problem in &file.invalid {
+        eprintln!("{}", problem);
+    }
+    // How the settings go together can only be checked once they all parse
+    let problems = if file.invalid.is_empty() { config.problems() } else { Vec::new() };
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    if !file.is_clean() || !problems.is_empty() {
+        return Err(anyhow!(
+            "{} has {} problem(s)",
+            file.path.display(),
+            file.unknown.len() + file.invalid.len() + problems.len()
+        ));
+    }
+    println!("{} is fine", file.path.display());
+    Ok(())
+}
+
+/// A TOML file of settings, keyed by their variables' names in lowercase:
+/// `port = 8080`, `trust_proxy = ["10.0.0.0/8", "192.168.0.0/16"]`.
+#[derive(Debug)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    /// Each setting, as it would be given on the command line
+    values: Vec<(String, String)>,
+    /// Keys that aren't settings, as dotted paths
+    pub unknown: Vec<String>,
+    /// Settings whose values don't parse, and why
+    pub invalid: Vec<String>,
+}
+
+impl ConfigFile {
+    fn read(path: &Path, command: &clap::Command) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|e| anyhow!("Couldn't read {}: {}", path.display(), e))?;
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e| anyhow!("{} isn't valid TOML: {}", path.display(), e))?;
+        let mut file = Self {
+            path: path.to_path_buf(),
+            values: Vec::new(),
+            unknown: Vec::new(),
+            invalid: Vec::new(),
+        };
+        for (key, value) in &table {
+            let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == key.as_str() && is_setting(arg)) else {
+                key_paths(key, value, &mut file.unknown);
+                continue;
+            };
+            let parsed = as_arg(value).and_then(|value| parse_as(arg, &value).map(|()| value));
+            match parsed {
+                Ok(value) => file.values.push((key.clone(), value)),
+                Err(e) => file.invalid.push(format!("{}: {}", key, e)),
+            }
+        }
+        Ok(file)
+    }
+
+    fn value(&self, arg: &Arg) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(key, _)| arg.get_id() == key.as_str())
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether there's nothing in it we don't understand.
+    pub fn is_clean(&self) -> bool {
+        self.unknown.is_empty() && self.invalid.is_empty()
+    }
+}
+
+// Everything but `--version`, `--help` and `--config` itself
+fn is_setting(arg: &Arg) -> bool {
+    arg.get_long().is_some() && !matches!(arg.get_id().as_str(), "version" | "help" | "config_file")
+}
+
+// Parse `value` as the flag would be, on its own
+fn parse_as(arg: &Arg, value: &str) -> Result<(), String> {
+    let long = arg.get_long().unwrap_or_default();
+    clap::Command::new("fastgif")
+        .no_binary_name(true)
+        .arg(arg.clone().env(None))
+        .try_get_matches_from([format!("--{}={}", long, value)])
+        .map(|_| ())
+        .map_err(|e| {
+            let expected: Vec<_> = arg.get_possible_values().iter().map(|v| v.get_name().to_string()).collect();
+            match e.source() {
+                Some(reason) => format!("{:?} is invalid: {}", value, reason),
+                None if !expected.is_empty() => format!("{:?} is invalid, expected {}", value, expected.join(" or ")),
+                None => format!("{:?} is invalid", value),
+            }
+        })
+}
+
+// A value as it'd be written on the command line. Lists are comma-separated.
+fn as_arg(value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(n) => Ok(n.to_string()),
+        toml::Value::Float(n) => Ok(n.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        toml::Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                toml::Value::Array(_) | toml::Value::Table(_) => Err("expected a list of values, not of lists".to_string()),
+                item => as_arg(item),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|items| items.join(",")),
+        toml::Value::Datetime(_) => Err("expected a value, not a date".to_string()),
+        toml::Value::Table(_) => Err("expected a value, not a table".to_string()),
+    }
+}
+
+fn key_paths(key: &str, value: &toml::Value, paths: &mut Vec<String>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (inner, value) in table {
+                key_paths(&format!("{}.{}", key, inner), value, paths);
+            }
+        }
+        _ => paths.push(key.to_string()),
+    }
+}
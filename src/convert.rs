@@ -0,0 +1,239 @@
+use crate::config::{Config, ConversionArgs, ConvertArgs};
+use bytes::Bytes;
+use fastgif_core::error_class::ErrorClass;
+use fastgif_core::fetch::{FetchMode, SourceFetcher, SourceRef, SourceStream};
+use fastgif_core::pipeline::GifOutput;
+use fastgif_core::temp::{TempFile, TempManager};
+use fastgif_core::timing::Stages;
+use fastgif_core::{ConversionError, ConversionOptions, Converter, CoreConfig, GifSettings, Source};
+use futures_util::future::BoxFuture;
+use futures_util::stream::{self, StreamExt};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+/// How much of stdin is looked at to tell whether the video can be read
+/// front to back.
+const SNIFF_BYTES: usize = 64 * 1024;
+
+/// `fastgif convert`: convert `args.input` with the pipeline the server
+/// would set up from `config`, overridden by `args`, and write the result to
+/// `args.output`. The line to print is how big it came out and where the time
+/// went, or for `Err`, the error's class and what the children last said.
+pub async fn run(config: &Config, args: &ConvertArgs) -> Result<String, String> {
+    let (converter, options) = setup(config, &args.conversion)?;
+    let started = Instant::now();
+    let result = converter.convert(Source::Path(args.input.clone()), &options).await;
+    let took = started.elapsed();
+    let gif = result.map_err(|e| failed(&e, &options))?;
+    let len = gif.len();
+    write(gif, &args.output)
+        .await
+        .map_err(|e| format!("failed: couldn't write {}: {}", args.output.display(), e))?;
+    Ok(summary(len, took, &options))
+}
+
+/// `fastgif pipe`: the same for the video on stdin, with the result on
+/// stdout. Most videos are fed to ffmpeg as they come in, but an MP4 whose
+/// index is at the end has to be read from a temp file, so ffmpeg can seek.
+/// `Err` has the code to exit with too, going by `ErrorClass::exit_code`.
+pub async fn pipe(config: &Config, args: &ConversionArgs) -> Result<String, (i32, String)> {
+    let (mut converter, options) = setup(config, args).map_err(|e| (1, e))?;
+    let mut stdin = tokio::io::stdin();
+    let mut head = vec![0; SNIFF_BYTES];
+    let read = read_fully(&mut stdin, &mut head).await.map_err(|e| (1, format!("failed: couldn't read stdin: {}", e)))?;
+    head.truncate(read);
+
+    let started = Instant::now();
+    let result = if needs_seeking(&head) {
+        let (temp, max) = (converter.pipeline().temp.clone(), converter.pipeline().max_input_bytes);
+        match buffer(&temp, head, stdin, max).await {
+            Ok(file) => {
+                // ffmpeg reads the file itself, which has already been held
+                // to the limit
+                let mut pipeline = (**converter.pipeline()).clone();
+                pipeline.fetch_mode = FetchMode::Ffmpeg;
+                pipeline.max_input_bytes = None;
+                let source = pipeline.source_ref("stdin", file.path().display().to_string(), &options);
+                pipeline.convert(&source, &options).await
+            }
+            Err(e) => Err(e),
+        }
+    } else {
+        converter.set_source_fetcher(Arc::new(Stdin(Mutex::new(Some((head, stdin))))));
+        converter.convert(Source::Url("pipe:0".to_string()), &options).await
+    };
+    let took = started.elapsed();
+    let gif = result.map_err(|e| (ErrorClass::of(&e).exit_code(), failed(&e, &options)))?;
+    let len = gif.len();
+    write(gif, Path::new("-")).await.map_err(|e| (1, format!("failed: couldn't write stdout: {}", e)))?;
+    Ok(summary(len, took, &options))
+}
+
+// The converter and options `args` ask for, on top of what the server
+// would use
+fn setup(config: &Config, args: &ConversionArgs) -> Result<(Converter, ConversionOptions), String> {
+    let core = config.core_config();
+    let gif_settings = GifSettings {
+        quality: args.quality.unwrap_or(core.gif_settings.quality),
+        fps: args.fps.unwrap_or(core.gif_settings.fps),
+        max_width: args.width.or(core.gif_settings.max_width),
+        ..core.gif_settings
+    };
+    let converter = crate::new_converter(CoreConfig { gif_settings, ..core }).map_err(|e| format!("failed: {}", e))?;
+    let backends = &converter.pipeline().backends;
+    if !backends.makes(args.format) {
+        return Err(format!("failed: can't make {} here, only {}", args.format.as_str(), backends.formats().join(", ")));
+    }
+    let options = ConversionOptions {
+        backend: backends.registered(args.format),
+        ..Default::default()
+    };
+    Ok((converter, options))
+}
+
+// The error's class and message, then what the children last said
+fn failed(e: &ConversionError, options: &ConversionOptions) -> String {
+    let mut line = format!("{}: {}", ErrorClass::of(e).as_str(), e);
+    for (source, lines) in options.stderr_tail.snapshot() {
+        for stderr in lines {
+            line.push_str(&format!("\n  {}: {}", source, stderr));
+        }
+    }
+    line
+}
+
+fn summary(len: u64, took: Duration, options: &ConversionOptions) -> String {
+    format!("{} bytes in {}ms ({})", len, took.as_millis(), breakdown(&options.timings.snapshot()))
+}
+
+// Each stage that happened, and how long it took. The video comes from a
+// file, so there's no upstream, only reading it.
+fn breakdown(stages: &Stages) -> String {
+    let stages = [
+        ("read", stages.upstream_ttfb),
+        ("ffmpeg", stages.ffmpeg),
+        ("encoder", stages.gifski),
+        ("first byte", stages.first_byte),
+    ];
+    stages
+        .into_iter()
+        .filter_map(|(name, took)| Some(format!("{} {}ms", name, took?.as_millis())))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Write `gif` to `path`, or to stdout for `-`
+async fn write(gif: GifOutput, path: &Path) -> std::io::Result<()> {
+    let mut out: Box<dyn AsyncWrite + Unpin> = if path == Path::new("-") {
+        Box::new(tokio::io::stdout())
+    } else {
+        Box::new(tokio::fs::File::create(path).await?)
+    };
+    match gif {
+        GifOutput::Memory(bytes) => out.write_all(&bytes).await?,
+        // The temp file is removed once it's been copied
+        GifOutput::Spilled { file: _file, mut handle, .. } => {
+            tokio::io::copy(&mut handle, &mut out).await?;
+        }
+        GifOutput::Streamed { .. } => {}
+    }
+    out.flush().await
+}
+
+// Fill `buf` unless the input ends first, returning how much was read
+async fn read_fully(input: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match input.read(&mut buf[read..]).await? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+// Whether `head` is the start of an MP4 (or QuickTime) file whose index
+// (`moov`) doesn't come before its media (`mdat`), or not within `head`, so
+// that reading it front to back, as from a pipe, may get nowhere. Anything
+// else, including fragmented and "fast start" MP4s, streams.
+fn needs_seeking(head: &[u8]) -> bool {
+    if head.get(4..8) != Some(b"ftyp") {
+        return false;
+    }
+    let mut offset = 0usize;
+    while let Some(header) = head.get(offset..offset + 8) {
+        match &header[4..] {
+            b"moov" | b"moof" => return false,
+            b"mdat" => return true,
+            _ => {}
+        }
+        let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // The size is in the next 8 bytes
+            1 => match head.get(offset + 8..offset + 16) {
+                Some(size) => u64::from_be_bytes(size.try_into().unwrap()),
+                None => return true,
+            },
+            // The rest of the file
+            0 => return true,
+            size => u64::from(size),
+        };
+        if size < 8 {
+            return true;
+        }
+        offset = match usize::try_from(size).ok().and_then(|size| offset.checked_add(size)) {
+            Some(next) if next < head.len() => next,
+            _ => return true,
+        };
+    }
+    true
+}
+
+// Write `head` and then the rest of `input` to a temp file from `temp`,
+// giving up once it's more than `max` bytes
+async fn buffer(
+    temp: &Arc<TempManager>,
+    head: Vec<u8>,
+    mut input: impl AsyncRead + Unpin,
+    max: Option<u64>,
+) -> Result<TempFile, ConversionError> {
+    let (file, mut handle) = temp.create("stdin").await?;
+    let write_failed = || ConversionError::pipe(format!("Failed to write {}", file.path().display()));
+    let mut total = 0u64;
+    let mut chunk = head;
+    while !chunk.is_empty() {
+        total += chunk.len() as u64;
+        if let Some(max) = max.filter(|max| total > *max) {
+            return Err(ConversionError::InputTooLarge(max));
+        }
+        file.grew(chunk.len())?;
+        handle.write_all(&chunk).await.map_err(write_failed())?;
+        chunk.resize(SNIFF_BYTES, 0);
+        let read = input.read(&mut chunk).await.map_err(ConversionError::pipe("Failed to read stdin"))?;
+        chunk.truncate(read);
+    }
+    handle.flush().await.map_err(write_failed())?;
+    Ok(file)
+}
+
+/// The video on stdin, as a source that can be fetched once: what's been
+/// read of it already, then the rest.
+#[derive(Debug)]
+struct Stdin(Mutex<Option<(Vec<u8>, tokio::io::Stdin)>>);
+
+impl SourceFetcher for Stdin {
+    fn fetch<'a>(&'a self, _: &'a SourceRef) -> BoxFuture<'a, Result<SourceStream, ConversionError>> {
+        let taken = self.0.lock().unwrap().take();
+        Box::pin(async move {
+            let (head, rest) = taken.ok_or_else(|| ConversionError::Internal("stdin has already been read".to_string()))?;
+            let rest = ReaderStream::new(rest).map(|chunk| chunk.map_err(ConversionError::pipe("Failed to read stdin")));
+            Ok(stream::iter([Ok(Bytes::from(head))]).chain(rest).boxed())
+        })
+    }
+
+    fn refetchable(&self) -> bool {
+        false
+    }
+}
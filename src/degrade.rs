@@ -0,0 +1,59 @@
+use fastgif_core::tier::Tier;
+use std::time::Duration;
+
+/// When conversions get a reduced profile instead of the configured one.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadTiers {
+    /// Conversions already running when new ones start getting `Tier::Medium`
+    pub medium_at: usize,
+    /// ...and `Tier::Low`, if there's a second threshold
+    pub low_at: Option<usize>,
+    /// How long clients and CDNs may cache a degraded GIF, `ZERO` for not at all
+    pub max_age: Duration,
+}
+
+/// A conversion that was given a reduced profile.
+#[derive(Debug, Clone, Copy)]
+pub struct Degraded {
+    pub tier: Tier,
+    pub max_age: Duration,
+}
+
+impl LoadTiers {
+    /// Parse `DEGRADE_AT`: one threshold, or two (`4,8`) for both tiers.
+    pub fn parse(spec: &str, max_age: Duration) -> Result<Self, String> {
+        let mut thresholds = spec.split(',').map(|n| {
+            n.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid threshold {:?}", n))
+        });
+        let medium_at = thresholds.next().ok_or("no thresholds given")??;
+        let low_at = thresholds.next().transpose()?;
+        if thresholds.next().is_some() {
+            return Err("expected at most two thresholds".to_string());
+        }
+        if low_at.is_some_and(|low_at| low_at <= medium_at) {
+            return Err("the second threshold has to be higher than the first".to_string());
+        }
+        Ok(Self {
+            medium_at,
+            low_at,
+            max_age,
+        })
+    }
+
+    /// The tier for a conversion starting while `in_flight` others are running.
+    pub fn degrade(&self, in_flight: usize) -> Option<Degraded> {
+        let tier = if self.low_at.is_some_and(|low_at| in_flight >= low_at) {
+            Tier::Low
+        } else if in_flight >= self.medium_at {
+            Tier::Medium
+        } else {
+            return None;
+        };
+        Some(Degraded {
+            tier,
+            max_age: self.max_age,
+        })
+    }
+}
@@ -0,0 +1,78 @@
+use fastgif_core::error::ConversionError;
+use fastgif_core::error_class::ErrorClass;
+use fastgif_core::stderr_tail::StderrTail;
+use anyhow::{anyhow, Result};
+use sentry::{Breadcrumb, ClientInitGuard, ClientOptions, Level};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once Sentry is.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Start reporting to the Sentry project at `dsn`. The guard flushes whatever
+/// is still being sent when it's dropped, so it has to live as long as main.
+pub fn init(dsn: &str) -> Result<ClientInitGuard> {
+    let dsn = dsn.parse().map_err(|e| anyhow!("Invalid SENTRY_DSN: {}", e))?;
+    let guard = sentry::init(ClientOptions {
+        dsn: Some(dsn),
+        release: sentry::release_name!(),
+        // No client IPs or headers
+        send_default_pii: false,
+        ..Default::default()
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(guard)
+}
+
+fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Report a failed conversion, unless it failed in one of the ways that are
+/// expected to happen: the upstream not having the video, or not all of it
+/// yet, the client going away, shutdown, an admin cancelling it, or one of
+/// our size limits.
+pub fn report(error: &ConversionError, class: ErrorClass, request_id: &str, path: &str, stderr: &StderrTail) {
+    let expected = matches!(
+        class,
+        ErrorClass::UpstreamNotFound
+            | ErrorClass::SourceIncomplete
+            | ErrorClass::ClientAbort
+            | ErrorClass::Shutdown
+            | ErrorClass::CancelledByAdmin
+            | ErrorClass::InputTooLarge
+            | ErrorClass::OutputTooLarge
+    );
+    if !enabled() || expected {
+        return;
+    }
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("error_code", class.as_str());
+            scope.set_tag("request_id", request_id);
+            scope.set_extra("path", sanitize_path(path).into());
+        },
+        || {
+            // Breadcrumbs added in here only go along with this report
+            for (source, lines) in stderr.snapshot() {
+                for line in lines {
+                    sentry::add_breadcrumb(Breadcrumb {
+                        category: Some(source.to_string()),
+                        message: Some(line),
+                        level: Level::Info,
+                        ..Default::default()
+                    });
+                }
+            }
+            sentry::capture_error(error)
+        },
+    );
+}
+
+// Paths come from whoever is asking, so only a bounded amount of printable text
+// goes along
+fn sanitize_path(path: &str) -> String {
+    path.chars()
+        .filter(|c| !c.is_control())
+        .take(200)
+        .collect()
+}
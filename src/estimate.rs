@@ -0,0 +1,157 @@
+use crate::probe::Details;
+use fastgif_core::backend::Format;
+use fastgif_core::encoder::GifSettings;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Conversions after which each one only moves a fit by `1 / SETTLED_AFTER`,
+/// so it keeps up with how the videos we get change.
+const SETTLED_AFTER: u64 = 20;
+
+/// What `GET /tweet_video/{path}/estimate` guesses a conversion will come to:
+/// bytes from the pixels in every frame, times a coefficient for the format
+/// and quality, and time the same way. The coefficients start out as rough
+/// guesses and are fitted to each conversion that finishes, as a moving
+/// average weighted towards the latest.
+#[derive(Default)]
+pub struct SizeModel {
+    fits: Mutex<BTreeMap<(&'static str, u8), Fit>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Fit {
+    bytes_per_pixel: f64,
+    secs_per_megapixel: f64,
+    samples: u64,
+}
+
+/// The frames a conversion will make, and how big they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shape {
+    pub width: u32,
+    pub height: u32,
+    pub frames: u64,
+}
+
+/// What a conversion is expected to come to.
+#[derive(Debug, Clone, Copy)]
+pub struct Estimate {
+    pub bytes: u64,
+    pub took: Duration,
+    /// Conversions the coefficients were fitted to
+    pub samples: u64,
+}
+
+/// A fit, as `/stats` shows it.
+#[derive(Debug, Serialize)]
+pub struct FitStats {
+    pub format: &'static str,
+    /// To the nearest ten
+    pub quality: u8,
+    pub bytes_per_pixel: f64,
+    pub secs_per_megapixel: f64,
+    pub samples: u64,
+}
+
+impl Shape {
+    /// What `settings` make of a video with `details`, from `start` and cut
+    /// to `trim` if it's trimmed. `None` without the video's dimensions and
+    /// duration.
+    pub fn of(details: &Details, settings: &GifSettings, start: Option<Duration>, trim: Option<Duration>) -> Option<Self> {
+        let (source_width, source_height) = (details.width?, details.height?);
+        let duration = details.duration?.saturating_sub(start.unwrap_or_default());
+        let duration = trim.map_or(duration, |trim| duration.min(trim));
+        // Both even, as `GifSettings::scale_filter` makes them
+        let width = (settings.max_width.map_or(source_width, |max| source_width.min(max)) & !1).max(2);
+        // Scaled keeping the aspect ratio, to an even height, as `scale=...:-2` does
+        let height = match width == source_width {
+            true => (source_height & !1).max(2),
+            false => ((f64::from(source_height) * f64::from(width) / f64::from(source_width) / 2.0).round() as u32 * 2).max(2),
+        };
+        Some(Self::of_size(width, height, duration, settings.fps))
+    }
+
+    /// `duration` of frames `width` by `height`, at `fps`.
+    pub fn of_size(width: u32, height: u32, duration: Duration, fps: f32) -> Self {
+        let frames = (duration.as_secs_f64() * f64::from(fps)).ceil().max(1.0) as u64;
+        Self { width, height, frames }
+    }
+
+    /// Of the GIF `gif`, `duration` long at `fps`: its size is in its header.
+    pub fn of_gif(gif: &[u8], duration: Duration, fps: f32) -> Option<Self> {
+        if gif.len() < 10 || !gif.starts_with(b"GIF8") {
+            return None;
+        }
+        let width = u16::from_le_bytes([gif[6], gif[7]]);
+        let height = u16::from_le_bytes([gif[8], gif[9]]);
+        (width > 0 && height > 0).then(|| Self::of_size(width.into(), height.into(), duration, fps))
+    }
+
+    fn pixels(&self) -> f64 {
+        f64::from(self.width) * f64::from(self.height) * self.frames as f64
+    }
+}
+
+impl SizeModel {
+    /// What converting to `shape` in `format` at `quality` should come to.
+    pub fn estimate(&self, format: Format, quality: u8, shape: Shape) -> Estimate {
+        let fit = self.fits.lock().unwrap().get(&key(format, quality)).copied().unwrap_or_else(|| guess(format, quality));
+        let pixels = shape.pixels();
+        Estimate {
+            bytes: (pixels * fit.bytes_per_pixel).round() as u64,
+            took: Duration::from_secs_f64((pixels / 1e6 * fit.secs_per_megapixel).max(0.0)),
+            samples: fit.samples,
+        }
+    }
+
+    /// A conversion to `shape` in `format` at `quality` made `bytes` and
+    /// took `took`.
+    pub fn learn(&self, format: Format, quality: u8, shape: Shape, bytes: u64, took: Duration) {
+        let pixels = shape.pixels();
+        if pixels <= 0.0 {
+            return;
+        }
+        let mut fits = self.fits.lock().unwrap();
+        let fit = fits.entry(key(format, quality)).or_insert_with(|| guess(format, quality));
+        // Until there are enough, each counts as much as those before it
+        let weight = 1.0 / (fit.samples + 1).min(SETTLED_AFTER) as f64;
+        fit.bytes_per_pixel += (bytes as f64 / pixels - fit.bytes_per_pixel) * weight;
+        fit.secs_per_megapixel += (took.as_secs_f64() / (pixels / 1e6) - fit.secs_per_megapixel) * weight;
+        fit.samples += 1;
+    }
+
+    /// Every fit that's been made to a conversion.
+    pub fn stats(&self) -> Vec<FitStats> {
+        self.fits
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((format, quality), fit)| FitStats {
+                format,
+                quality: *quality,
+                bytes_per_pixel: fit.bytes_per_pixel,
+                secs_per_megapixel: fit.secs_per_megapixel,
+                samples: fit.samples,
+            })
+            .collect()
+    }
+}
+
+// Qualities near enough to each other share a fit
+fn key(format: Format, quality: u8) -> (&'static str, u8) {
+    (format.as_str(), ((u16::from(quality) + 5) / 10 * 10).min(100) as u8)
+}
+
+// Where a fit starts, before there's been a conversion to go on: a GIF is
+// somewhere around half a byte a pixel, less at lower qualities, and other
+// formats compress far better
+fn guess(format: Format, quality: u8) -> Fit {
+    let quality = f64::from(key(format, quality).1) / 100.0;
+    let bytes_per_pixel = match format {
+        Format::Gif => 0.15 + 0.45 * quality,
+        _ => 0.05 + 0.15 * quality,
+    };
+    Fit { bytes_per_pixel, secs_per_megapixel: 0.05, samples: 0 }
+}
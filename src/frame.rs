@@ -0,0 +1,74 @@
+use crate::clip::Seconds;
+use crate::stills::ImageFormat;
+use crate::variant::VariantKey;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// How far apart frames are taken to be in a video ffprobe couldn't say the
+/// frame rate of.
+const DEFAULT_FRAME_INTERVAL: Duration = Duration::from_millis(40);
+
+/// What `GET /tweet_video/{path}/frame` takes: when in the video, as what,
+/// and no wider than what.
+#[derive(Debug, Default, Deserialize)]
+pub struct FrameQuery {
+    /// Seconds into the video, from its start
+    pub t: Option<Seconds>,
+    /// `jpg` (the default), `png` or `webp`, as `?fmt=` or `?format=`
+    #[serde(alias = "format")]
+    pub fmt: Option<String>,
+    /// No wider than this, scaling the frame down if it's wider
+    pub width: Option<u32>,
+}
+
+/// The frame asked for, as checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// To the millisecond
+    pub at: Duration,
+    pub format: ImageFormat,
+    pub width: Option<u32>,
+}
+
+impl FrameQuery {
+    pub fn frame(&self) -> Result<Frame, String> {
+        let format = match self.fmt.as_deref() {
+            None => ImageFormat::Jpeg,
+            Some(format) => format.parse()?,
+        };
+        let secs = self.t.map_or(0.0, |t| t.0.as_secs_f64());
+        let at = Duration::from_millis((secs * 1000.0).round() as u64);
+        Ok(Frame { at, format, width: self.width })
+    }
+}
+
+impl Frame {
+    /// What it's kept as for the video `variant` is of, made this wide.
+    pub fn key(&self, variant: &VariantKey) -> String {
+        format!("{} as a frame: at {:?}, {}", variant, self.at, self.format.as_str())
+    }
+
+    /// Where to seek to for it in a video `duration` long at `fps`: where it
+    /// asked, or its last frame if that's at or past the end.
+    pub fn seek(&self, duration: Option<Duration>, fps: Option<f64>) -> Duration {
+        let Some(duration) = duration else { return self.at };
+        let frame = fps.and_then(|fps| Duration::try_from_secs_f64(1.0 / fps).ok()).unwrap_or(DEFAULT_FRAME_INTERVAL);
+        let last = duration.saturating_sub(frame);
+        let last = Duration::from_millis(last.as_millis() as u64);
+        self.at.min(last)
+    }
+
+    /// The ffmpeg argv (minus the binary) for the frame at `seek` in the
+    /// video at `input`, through `scale` (the main route's scale filter for
+    /// the same width), as one image on stdout.
+    pub fn ffmpeg_args(&self, input: &str, seek: Duration, scale: &str, threads: usize) -> Vec<String> {
+        let mut args: Vec<String> = vec!["-v".into(), "error".into(), "-threads".into(), threads.to_string()];
+        // Before `-i`, so ffmpeg jumps to the keyframe before it rather than
+        // decoding its way there, and then decodes only up to it
+        args.extend(["-ss".into(), format!("{:.3}", seek.as_secs_f64())]);
+        args.extend(["-i".into(), input.into()]);
+        args.extend(["-an".into(), "-sn".into(), "-vf".into(), scale.into()]);
+        args.extend(self.format.ffmpeg_output());
+        args
+    }
+}
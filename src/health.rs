@@ -0,0 +1,202 @@
+use crate::admission::{Admission, Rejection};
+use fastgif_core::encoder::{Decoder, Encoder};
+use crate::maintenance::Maintenance;
+use fastgif_core::pipeline::{self, PipelineConfig};
+use fastgif_core::process::Binary;
+use crate::shutdown::Conversions;
+use fastgif_core::spill::DiskGuard;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// How long the result of a deep check is reused for, so that however often
+/// we're probed the test conversion runs at most this often.
+const DEEP_CHECK_TTL: Duration = Duration::from_secs(30);
+
+/// How often the checks are retried while warming up, until they pass.
+const WARM_UP_RETRY: Duration = Duration::from_secs(5);
+
+/// What `/healthz` checks, and the last deep check's result.
+pub struct Health {
+    /// Run a test conversion too (`HEALTH_DEEP`)
+    deep: bool,
+    last_deep: Mutex<Option<(Instant, Check)>>,
+}
+
+/// What `/healthz` reports.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// Whether every critical check passed
+    pub healthy: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    /// A failed critical check makes the whole server unhealthy
+    pub critical: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn new(critical: bool, result: Result<(), String>) -> Self {
+        Self {
+            ok: result.is_ok(),
+            critical,
+            error: result.err(),
+        }
+    }
+}
+
+impl Health {
+    pub fn new(deep: bool) -> Self {
+        Self {
+            deep,
+            last_deep: Mutex::new(None),
+        }
+    }
+
+    /// Check everything a conversion needs. Only what the configuration
+    /// actually uses is checked: no gifski binary is needed to encode with
+    /// ffmpeg, and the temp dir only matters when GIFs are spilled to it.
+    pub async fn check(&self, config: &PipelineConfig) -> Report {
+        // Looking at the binaries' files is disk I/O, for the blocking pool
+        let (decoder, encoder, post_optimize) = (config.decoder, config.encoder, config.post_optimize.is_some());
+        let binaries = config.binaries.clone();
+        let mut checks = tokio::task::spawn_blocking(move || {
+            let mut checks = BTreeMap::new();
+            if decoder == Decoder::Ffmpeg {
+                checks.insert("ffmpeg", Check::new(true, installed(&binaries.ffmpeg)));
+            }
+            if encoder == Encoder::Subprocess {
+                checks.insert("gifski", Check::new(true, installed(&binaries.gifski)));
+            }
+            // Without gifsicle GIFs just don't get optimized
+            if post_optimize {
+                checks.insert("gifsicle", Check::new(false, installed(&binaries.gifsicle)));
+            }
+            checks
+        })
+        .await
+        .unwrap_or_default();
+        if config.spill.is_some() {
+            let writable = config
+                .temp
+                .create("health")
+                .await
+                .map(drop)
+                .map_err(|e| format!("{} isn't writable: {}", config.temp.dir().display(), e));
+            checks.insert("tmp_dir", Check::new(true, writable));
+        }
+        if self.deep {
+            checks.insert("conversion", self.deep_check(config).await);
+        }
+
+        let healthy = checks.values().all(|check| check.ok || !check.critical);
+        for (name, check) in &checks {
+            if let Some(error) = &check.error {
+                warn!("Health check {} failed: {}", name, error);
+            }
+        }
+        Report { healthy, checks }
+    }
+
+    // Everyone probing while a test conversion runs waits for that one
+    async fn deep_check(&self, config: &PipelineConfig) -> Check {
+        let mut last = self.last_deep.lock().await;
+        if let Some((checked, check)) = &*last {
+            if checked.elapsed() < DEEP_CHECK_TTL {
+                return check.clone();
+            }
+        }
+        let result = pipeline::self_test(config).await.map_err(|e| e.to_string());
+        let check = Check::new(true, result);
+        *last = Some((Instant::now(), check.clone()));
+        check
+    }
+}
+
+fn installed(binary: &Binary) -> Result<(), String> {
+    match &binary.path {
+        _ if binary.is_installed() => Ok(()),
+        Some(path) => Err(format!("{} isn't executable any more", path.display())),
+        None => Err(format!("{} isn't an executable on PATH", binary.name)),
+    }
+}
+
+/// Whether we want traffic right now, for `/readyz`. Only changes of mind are
+/// logged, however often we're asked.
+pub struct Readiness {
+    warmed_up: AtomicBool,
+    last: std::sync::Mutex<Result<(), &'static str>>,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self {
+            warmed_up: AtomicBool::new(false),
+            last: std::sync::Mutex::new(Err("warming_up")),
+        }
+    }
+
+    /// Startup is done: everything critical that `/healthz` checks is there.
+    pub fn warmed_up(&self) {
+        self.warmed_up.store(true, Ordering::Relaxed);
+    }
+
+    /// `Ok` if we'll take conversions, otherwise why not: `warming_up`,
+    /// `draining`, `maintenance`, `disk_full` (once making room didn't help),
+    /// `queue_full` or `system_pressure`.
+    pub fn check(
+        &self,
+        admission: &Admission,
+        conversions: &Conversions,
+        maintenance: &Maintenance,
+        disk: Option<&DiskGuard>,
+    ) -> Result<(), &'static str> {
+        let ready = if !self.warmed_up.load(Ordering::Relaxed) {
+            Err("warming_up")
+        } else if conversions.is_draining() {
+            Err("draining")
+        } else if maintenance.is_on() {
+            Err("maintenance")
+        } else if disk.is_some_and(DiskGuard::is_stuck) {
+            Err("disk_full")
+        } else {
+            match admission.saturated() {
+                Some(Rejection::SystemPressure) => Err("system_pressure"),
+                Some(_) => Err("queue_full"),
+                None => Ok(()),
+            }
+        };
+        let mut last = self.last.lock().unwrap();
+        if *last != ready {
+            match ready {
+                Ok(()) => info!("Ready for traffic"),
+                Err(reason) => warn!("Not ready for traffic: {}", reason),
+            }
+            *last = ready;
+        }
+        ready
+    }
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run the health checks until they pass, then tell `readiness` we're warmed up.
+pub async fn warm_up(health: Arc<Health>, readiness: Arc<Readiness>, config: Arc<PipelineConfig>) {
+    while !health.check(&config).await.healthy {
+        tokio::time::sleep(WARM_UP_RETRY).await;
+    }
+    readiness.warmed_up();
+}
@@ -0,0 +1,120 @@
+use fastgif_core::capabilities::{self, Capabilities, Minimums};
+use crate::config::Config;
+use fastgif_core::encoder;
+use crate::listener::Bind;
+use reqwest::Url;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// `fastgif healthcheck`: ask the running server's `/healthz`, and sum up
+/// what it says in a line. `Err` is that line too, for a server that's
+/// unhealthy or didn't answer within `timeout`.
+pub async fn online(config: &Config, url: Option<Url>, timeout: Duration) -> Result<String, String> {
+    let url = match url {
+        Some(url) => url,
+        None => default_url(config)?,
+    };
+    // Only whether it answers is being checked, not who it is, and the
+    // certificate is for a name rather than the loopback address. A proxy
+    // for outbound requests has no business with this one.
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .no_proxy()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .map_err(|e| format!("unhealthy: couldn't set up a client: {}", e))?;
+    let response = client.get(url.clone()).send().await.map_err(|e| {
+        if e.is_timeout() {
+            format!("unhealthy: {} didn't answer within {:?}", url, timeout)
+        } else {
+            format!("unhealthy: couldn't reach {}: {}", url, e)
+        }
+    })?;
+    let status = response.status();
+    let report = response
+        .json::<serde_json::Value>()
+        .await
+        .map_err(|e| format!("unhealthy: {} answered {} without a report: {}", url, status, e))?;
+    let checks = report["checks"].as_object().cloned().unwrap_or_default();
+    if status.is_success() && report["healthy"].as_bool() == Some(true) {
+        let names: Vec<&str> = checks.keys().map(String::as_str).collect();
+        return Ok(format!("healthy: {} ok", names.join(", ")));
+    }
+    let failed: Vec<String> = checks
+        .iter()
+        .filter(|(_, check)| check["ok"].as_bool() != Some(true))
+        .map(|(name, check)| format!("{}: {}", name, check["error"].as_str().unwrap_or("failed")))
+        .collect();
+    Err(format!("unhealthy ({}): {}", status.as_u16(), failed.join("; ")))
+}
+
+/// `/healthz` on the listener a local check reaches most easily: plain HTTP
+/// on `HTTP_PORT` if there is one, or else the first `BIND` entry with a port.
+fn default_url(config: &Config) -> Result<Url, String> {
+    let addr = match config.http_port {
+        Some(port) => Some(SocketAddr::new(crate::listener::tcp_ip(&config.bind), port)),
+        None => config.bind.iter().find_map(|bind: &Bind| bind.tcp(config.port)),
+    };
+    let Some(mut addr) = addr else {
+        return Err("unhealthy: BIND has no address with a port to check, give --url".to_string());
+    };
+    // Listening everywhere includes loopback
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    let scheme = if config.tls_cert.is_some() && config.http_port.is_none() { "https" } else { "http" };
+    Url::parse(&format!("{}://{}/healthz", scheme, addr)).map_err(|e| format!("unhealthy: {}", e))
+}
+
+/// `fastgif healthcheck --offline`: check the binaries as startup does, and
+/// that the directories the configuration writes to can be, without a server
+/// to ask. Running the binaries counts against `timeout` too.
+pub async fn offline(config: &Config, timeout: Duration) -> Result<String, String> {
+    let check = async {
+        let binaries = config.binaries().map_err(|e| vec![e.to_string()])?;
+        let (encoder, decoder) =
+            encoder::choose(config.encoder, config.decoder, &binaries.gifski).map_err(|e| vec![e.to_string()])?;
+        let minimums = Minimums {
+            ffmpeg: config.ffmpeg_min_version.clone(),
+            gifski: config.gifski_min_version.clone(),
+        };
+        let audit_log_path = config.audit_log_path.clone();
+        let mut problems = tokio::task::spawn_blocking(move || {
+            let capabilities = Capabilities::detect(&binaries.ffmpeg, &binaries.gifski);
+            let mut problems = capabilities::problems(&capabilities, encoder, decoder, &minimums);
+            if let Some(path) = audit_log_path.as_ref().filter(|path| !path.as_os_str().is_empty()) {
+                let opened = if path.exists() {
+                    std::fs::OpenOptions::new().append(true).open(path).map(drop)
+                } else {
+                    // The server creates it, in a directory that has to be there
+                    match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                        Some(dir) if !dir.is_dir() => Err(std::io::Error::other(format!("{} isn't a directory", dir.display()))),
+                        _ => Ok(()),
+                    }
+                };
+                if let Err(e) = opened {
+                    problems.push(format!("the audit log {} can't be written: {}", path.display(), e));
+                }
+            }
+            problems
+        })
+        .await
+        .map_err(|e| vec![e.to_string()])?;
+        if config.spill_threshold_bytes.is_some() {
+            let temp = config.core_config().temp;
+            if let Err(e) = temp.create("healthcheck").await {
+                problems.push(format!("{} isn't writable: {}", temp.root().display(), e));
+            }
+        }
+        Ok::<_, Vec<String>>(problems)
+    };
+    match tokio::time::timeout(timeout, check).await {
+        Err(_) => Err(format!("unhealthy: checking the binaries took longer than {:?}", timeout)),
+        Ok(Err(problems)) => Err(format!("unhealthy: {}", problems.join("; "))),
+        Ok(Ok(problems)) if !problems.is_empty() => Err(format!("unhealthy: {}", problems.join("; "))),
+        Ok(Ok(_)) => Ok("healthy: the binaries and directories are as this configuration needs".to_string()),
+    }
+}
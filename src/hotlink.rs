@@ -0,0 +1,106 @@
+use crate::access_log::AccessNote;
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use reqwest::Url;
+use serde::Serialize;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// A site GIFs may be embedded on (`ALLOWED_REFERERS`): a hostname like
+/// `example.com`, or `*.example.com` for any of its subdomains.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefererPattern {
+    Host(String),
+    /// The suffix after the `*`, with its leading dot
+    Subdomains(String),
+}
+
+impl RefererPattern {
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            RefererPattern::Host(pattern) => host == pattern,
+            RefererPattern::Subdomains(suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+impl FromStr for RefererPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = s.trim().trim_end_matches('.').to_ascii_lowercase();
+        let (subdomains, host) = match pattern.strip_prefix("*.") {
+            Some(host) => (true, host),
+            None => (false, pattern.as_str()),
+        };
+        if host.is_empty() || !host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_')) {
+            return Err(format!("{:?} isn't a hostname or *.hostname", s));
+        }
+        Ok(if subdomains {
+            RefererPattern::Subdomains(format!(".{}", host))
+        } else {
+            RefererPattern::Host(host.to_string())
+        })
+    }
+}
+
+/// Where GIFs may be embedded, and what everywhere else gets instead.
+#[derive(Debug)]
+pub struct HotlinkProtection {
+    pub allowed: Vec<RefererPattern>,
+    /// Let through requests with no `Referer` at all (`ALLOW_EMPTY_REFERER`)
+    pub allow_empty: bool,
+    /// Send hotlinkers here rather than a `403` (`HOTLINK_PLACEHOLDER_URL`)
+    pub placeholder: Option<Url>,
+}
+
+impl HotlinkProtection {
+    /// Whether a request with this `Referer` may have its GIF. One that
+    /// isn't a URL with a host doesn't match anything.
+    fn allows(&self, referer: Option<&str>) -> bool {
+        let referer = referer.map(str::trim).filter(|referer| !referer.is_empty());
+        let Some(referer) = referer else {
+            return self.allow_empty;
+        };
+        let Some(host) = Url::parse(referer).ok().and_then(|url| url.host_str().map(str::to_ascii_lowercase)) else {
+            return false;
+        };
+        let host = host.trim_end_matches('.');
+        self.allowed.iter().any(|pattern| pattern.matches(host))
+    }
+}
+
+#[derive(Serialize)]
+struct HotlinkError {
+    error: &'static str,
+    message: &'static str,
+}
+
+/// Middleware answering requests from sites that aren't allowed with the
+/// placeholder, or a `403` when there's none. Neither may be cached, since
+/// the same URL would get its GIF from an allowed site.
+pub async fn protect(State(protection): State<Arc<HotlinkProtection>>, request: Request, next: Next) -> Response {
+    let referer = request.headers().get(header::REFERER).and_then(|value| value.to_str().ok());
+    if protection.allows(referer) {
+        return next.run(request).await;
+    }
+    if let Some(note) = request.extensions().get::<AccessNote>() {
+        note.outcome("hotlinked");
+    }
+    match &protection.placeholder {
+        Some(placeholder) => (
+            StatusCode::FOUND,
+            [(header::LOCATION, placeholder.as_str()), (header::CACHE_CONTROL, "no-store")],
+        )
+            .into_response(),
+        None => (
+            StatusCode::FORBIDDEN,
+            [(header::CACHE_CONTROL, "no-store")],
+            Json(HotlinkError { error: "hotlinked", message: "GIFs from here can't be embedded on that site" }),
+        )
+            .into_response(),
+    }
+}
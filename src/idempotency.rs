@@ -0,0 +1,227 @@
+use crate::client_limit::ClientKey;
+use axum::body::{to_bytes, Body};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::Response;
+use bytes::Bytes;
+use ring::digest;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::sync::watch;
+
+/// Longest `Idempotency-Key` taken.
+pub const MAX_KEY_LEN: usize = 255;
+
+/// What POSTs sent with an `Idempotency-Key` were answered with, so one
+/// that's retried is answered the same way instead of being done again.
+/// Each is kept for `IDEMPOTENCY_TTL` after it's answered, and only so many
+/// bytes of them in all (`IDEMPOTENCY_MAX_BYTES`), the soonest to expire
+/// going first. A retry that comes while the first is still going waits for
+/// it rather than starting another.
+pub struct Idempotency {
+    ttl: Duration,
+    max_bytes: u64,
+    keys: Mutex<Keys>,
+}
+
+#[derive(Default)]
+struct Keys {
+    entries: HashMap<Scope, Entry>,
+    /// Of the responses kept
+    bytes: u64,
+}
+
+/// Keys are only the same for the same route and client: the API key
+/// they're sent with, or without one, their address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Scope {
+    route: &'static str,
+    client: ClientKey,
+    key: String,
+}
+
+struct Entry {
+    /// Of the request that was made with the key
+    fingerprint: [u8; 32],
+    /// Its response, once there is one
+    answered: watch::Receiver<Option<Arc<Stored>>>,
+    /// `None` while the request is still going
+    expires: Option<Instant>,
+    size: u64,
+}
+
+/// A response as it's kept, to be sent again.
+pub struct Stored {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+/// How a request with an `Idempotency-Key` is to be answered.
+pub enum Begun {
+    /// It's the first with the key, so it's made, and its response kept
+    First(Claim),
+    /// As the first with the key was
+    Answered(Arc<Stored>),
+    /// The key was used for a different request
+    Mismatch,
+}
+
+/// The first request with a key, whose response the others wait for. The
+/// key is given up if this is dropped before it's answered.
+pub struct Claim {
+    idempotency: Arc<Idempotency>,
+    scope: Scope,
+    answer: watch::Sender<Option<Arc<Stored>>>,
+}
+
+/// What a request comes to, part by part, to tell a retry of it from a
+/// different request sent with the same key.
+pub struct Fingerprint(digest::Context);
+
+impl Fingerprint {
+    /// Begun with `part`, e.g. the route.
+    pub fn of(part: &[u8]) -> Self {
+        Self(digest::Context::new(&digest::SHA256)).add(part)
+    }
+
+    pub fn add(mut self, part: &[u8]) -> Self {
+        self.0.update(&(part.len() as u64).to_be_bytes());
+        self.0.update(part);
+        self
+    }
+
+    /// Add what's in the file at `path`, read a piece at a time.
+    pub async fn add_file(mut self, path: &Path) -> std::io::Result<Self> {
+        let mut file = tokio::fs::File::open(path).await?;
+        self.0.update(&file.metadata().await?.len().to_be_bytes());
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match file.read(&mut buf).await? {
+                0 => return Ok(self),
+                read => self.0.update(&buf[..read]),
+            }
+        }
+    }
+
+    pub fn finish(self) -> [u8; 32] {
+        self.0.finish().as_ref().try_into().expect("a SHA-256 digest is 32 bytes")
+    }
+}
+
+impl Idempotency {
+    pub fn new(ttl: Duration, max_bytes: u64) -> Self {
+        Self { ttl, max_bytes, keys: Mutex::default() }
+    }
+
+    /// Begin `route`'s request with `key`, from `client`, whose method, URL
+    /// and body come to `fingerprint`, waiting for the first request with
+    /// the key if it's still going.
+    pub async fn begin(self: &Arc<Self>, route: &'static str, client: ClientKey, key: String, fingerprint: [u8; 32]) -> Begun {
+        let scope = Scope { route, client, key };
+        loop {
+            let mut answered = {
+                let mut keys = self.keys.lock().unwrap();
+                keys.expire();
+                match keys.entries.get(&scope) {
+                    Some(entry) if entry.fingerprint != fingerprint => return Begun::Mismatch,
+                    Some(entry) => entry.answered.clone(),
+                    None => {
+                        let (answer, answered) = watch::channel(None);
+                        keys.entries.insert(scope.clone(), Entry { fingerprint, answered, expires: None, size: 0 });
+                        return Begun::First(Claim { idempotency: self.clone(), scope, answer });
+                    }
+                }
+            };
+            // The first gave up if it's gone without an answer, so this can
+            // take its place
+            if let Ok(stored) = answered.wait_for(Option::is_some).await {
+                return Begun::Answered(stored.clone().expect("only an answer is waited for"));
+            }
+        }
+    }
+}
+
+impl Keys {
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let bytes = &mut self.bytes;
+        self.entries.retain(|_, entry| match entry.expires {
+            Some(expires) if expires <= now => {
+                *bytes -= entry.size;
+                false
+            }
+            _ => true,
+        });
+    }
+
+    // Forget the answered requests that expire soonest until what's kept
+    // fits in `max_bytes`
+    fn shrink(&mut self, max_bytes: u64) {
+        while self.bytes > max_bytes {
+            let soonest = self
+                .entries
+                .iter()
+                .filter_map(|(scope, entry)| entry.expires.map(|expires| (expires, scope)))
+                .min_by_key(|(expires, _)| *expires)
+                .map(|(_, scope)| scope.clone());
+            let Some(entry) = soonest.and_then(|scope| self.entries.remove(&scope)) else { break };
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+impl Claim {
+    /// Keep `response` for the key and send it. Responses that say to try
+    /// again, `429`s and `5xx`s, aren't kept, so a retry is made afresh.
+    pub async fn finish(self, response: Response) -> Response {
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            return response;
+        }
+        let (parts, body) = response.into_parts();
+        let body = match to_bytes(body, usize::MAX).await {
+            Ok(body) => body,
+            // Cut short, so there's nothing to keep or send but the status
+            Err(_) => return Response::from_parts(parts, Body::empty()),
+        };
+        let stored = Arc::new(Stored { status: parts.status, headers: parts.headers, body });
+        let size = stored.body.len() as u64;
+        let idempotency = self.idempotency.clone();
+        let mut keys = idempotency.keys.lock().unwrap();
+        if let Some(entry) = keys.entries.get_mut(&self.scope) {
+            entry.expires = Some(Instant::now() + idempotency.ttl);
+            entry.size = size;
+            keys.bytes += size;
+            keys.shrink(idempotency.max_bytes);
+        }
+        self.answer.send_replace(Some(stored.clone()));
+        drop(keys);
+        stored.response(false)
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let mut keys = self.idempotency.keys.lock().unwrap();
+        let unanswered = keys.entries.get(&self.scope).is_some_and(|entry| entry.expires.is_none());
+        if unanswered {
+            keys.entries.remove(&self.scope);
+        }
+    }
+}
+
+impl Stored {
+    /// The response to send, saying whether it's being sent again.
+    pub fn response(&self, replayed: bool) -> Response {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        if replayed {
+            response.headers_mut().insert("idempotent-replayed", HeaderValue::from_static("true"));
+        }
+        response
+    }
+}
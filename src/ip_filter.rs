@@ -0,0 +1,171 @@
+use crate::access_log::AccessNote;
+use crate::client_ip::{self, Cidr, TrustedProxies};
+use crate::listener::Peer;
+use crate::reload::Settings;
+use arc_swap::ArcSwap;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::debug;
+
+/// Networks to look addresses up in, as a binary trie per address family,
+/// so a lookup takes as many steps as an address has bits however many
+/// networks there are.
+#[derive(Debug, Clone, Default)]
+pub struct Networks {
+    v4: Trie,
+    v6: Trie,
+}
+
+impl Networks {
+    pub fn is_empty(&self) -> bool {
+        self.v4.nodes.is_empty() && self.v6.nodes.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => self.v4.contains(u32::from(ip).into(), 32),
+            IpAddr::V6(ip) => self.v6.contains(u128::from(ip), 128),
+        }
+    }
+}
+
+impl FromIterator<Cidr> for Networks {
+    fn from_iter<I: IntoIterator<Item = Cidr>>(cidrs: I) -> Self {
+        let mut networks = Self::default();
+        for cidr in cidrs {
+            match cidr.network() {
+                IpAddr::V4(network) => networks.v4.insert(u32::from(network).into(), 32, cidr.prefix()),
+                IpAddr::V6(network) => networks.v6.insert(u128::from(network), 128, cidr.prefix()),
+            }
+        }
+        networks
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Trie {
+    /// The root is the first, when there's anything in it
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Node {
+    /// Indexes of the nodes for a 0 and a 1 bit next, where 0 (the root,
+    /// which is nobody's child) means there's none
+    children: [u32; 2],
+    /// A network ends here, so everything below is in it
+    end: bool,
+}
+
+// The bit of `address` (`bits` wide) that's `depth` bits in
+fn bit(address: u128, bits: u8, depth: u8) -> usize {
+    ((address >> (bits - 1 - depth)) & 1) as usize
+}
+
+impl Trie {
+    fn insert(&mut self, network: u128, bits: u8, prefix: u8) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::default());
+        }
+        let mut node = 0;
+        for depth in 0..prefix {
+            if self.nodes[node].end {
+                // Already covered by a wider network
+                return;
+            }
+            let bit = bit(network, bits, depth);
+            node = match self.nodes[node].children[bit] {
+                0 => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = child as u32;
+                    child
+                }
+                child => child as usize,
+            };
+        }
+        self.nodes[node].end = true;
+    }
+
+    fn contains(&self, address: u128, bits: u8) -> bool {
+        let mut node = 0;
+        for depth in 0..=bits {
+            let Some(current) = self.nodes.get(node) else {
+                return false;
+            };
+            if current.end {
+                return true;
+            }
+            if depth == bits {
+                return false;
+            }
+            node = match current.children[bit(address, bits, depth)] {
+                0 => return false,
+                child => child as usize,
+            };
+        }
+        false
+    }
+}
+
+/// Which clients may talk to us at all (`ALLOW_CIDRS`, `DENY_CIDRS`).
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    /// Everything else is turned away, unless this is empty
+    allow: Networks,
+    deny: Networks,
+}
+
+impl IpFilter {
+    pub fn new(allow: &[Cidr], deny: &[Cidr]) -> Self {
+        Self {
+            allow: allow.iter().copied().collect(),
+            deny: deny.iter().copied().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether `client` may be answered. Being denied wins over being allowed.
+    pub fn allows(&self, client: IpAddr) -> bool {
+        !self.deny.contains(client) && (self.allow.is_empty() || self.allow.contains(client))
+    }
+}
+
+/// What `filter_clients` needs to know who the client is, and whether we
+/// talk to them.
+#[derive(Clone)]
+pub struct ClientFilter {
+    /// Where the current `IpFilter` is, since a reload can change it
+    pub settings: Arc<ArcSwap<Settings>>,
+    pub trust_proxy: Arc<TrustedProxies>,
+}
+
+/// Middleware answering clients the filter doesn't allow with a bare `403`,
+/// before anything else looks at their request.
+pub async fn filter_clients(
+    State(filter): State<ClientFilter>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let settings = filter.settings.load_full();
+    if settings.ip_filter.is_empty() {
+        return next.run(request).await;
+    }
+    let client = client_ip::resolve(request.headers(), peer.ip(), &filter.trust_proxy);
+    if !settings.ip_filter.allows(client) {
+        debug!("Refusing {}, which ALLOW_CIDRS/DENY_CIDRS keep out", client);
+        if let Some(note) = request.extensions().get::<AccessNote>() {
+            note.outcome("ip_denied");
+        }
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
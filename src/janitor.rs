@@ -0,0 +1,103 @@
+use crate::jobs::Jobs;
+use crate::metrics::Metrics;
+use crate::revalidate::Revalidator;
+use fastgif_core::spill::DiskGuard;
+use fastgif_core::temp::TempManager;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+/// Tidies up what would otherwise pile up until the disk fills: temp files
+/// (uploads and spilled GIFs) that a crashed conversion or instance left
+/// behind in `TMP_DIR`, and jobs that have been over for longer than
+/// `JOB_TTL`. It runs every `JANITOR_INTERVAL`, and on
+/// `POST /admin/janitor/run`.
+pub struct Janitor {
+    /// What's taken to be left over is up to it (`TEMP_FILE_MAX_AGE`)
+    temp: Arc<TempManager>,
+    /// How many files a sweep looks at a second, at most
+    max_files_per_sec: u32,
+    jobs: Arc<Jobs>,
+    metrics: Arc<Metrics>,
+    /// Held for a sweep, so a manual one waits for the periodic one
+    sweeping: Mutex<()>,
+}
+
+/// What a sweep removed.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Reclaimed {
+    pub temp_files: u64,
+    pub temp_bytes: u64,
+    pub jobs: u64,
+    pub took_ms: u64,
+}
+
+impl Janitor {
+    pub fn new(temp: Arc<TempManager>, max_files_per_sec: u32, jobs: Arc<Jobs>, metrics: Arc<Metrics>) -> Self {
+        Self { temp, max_files_per_sec, jobs, metrics, sweeping: Mutex::new(()) }
+    }
+
+    /// Sweep now, once any sweep already going is done.
+    pub async fn sweep(&self) -> Reclaimed {
+        let _sweeping = self.sweeping.lock().await;
+        let started = Instant::now();
+        let mut reclaimed = Reclaimed { jobs: self.jobs.prune() as u64, ..Reclaimed::default() };
+
+        let (temp, max_per_sec) = (self.temp.clone(), self.max_files_per_sec);
+        match tokio::task::spawn_blocking(move || temp.sweep(Some(max_per_sec))).await {
+            Ok(Ok(swept)) => {
+                reclaimed.temp_files = swept.files;
+                reclaimed.temp_bytes = swept.bytes;
+            }
+            Ok(Err(e)) => warn!("The janitor couldn't sweep {}: {}", self.temp.root().display(), e),
+            Err(e) => warn!("The janitor's sweep of {} failed: {}", self.temp.root().display(), e),
+        }
+
+        reclaimed.took_ms = started.elapsed().as_millis() as u64;
+        self.metrics.janitor_swept(reclaimed.temp_files, reclaimed.temp_bytes, reclaimed.jobs);
+        if reclaimed.temp_files > 0 || reclaimed.jobs > 0 {
+            info!(
+                temp_files = reclaimed.temp_files,
+                temp_bytes = reclaimed.temp_bytes,
+                jobs = reclaimed.jobs,
+                took_ms = reclaimed.took_ms,
+                "The janitor removed {} leftover temp files ({} bytes) and {} expired jobs",
+                reclaimed.temp_files,
+                reclaimed.temp_bytes,
+                reclaimed.jobs
+            );
+        } else {
+            debug!(took_ms = reclaimed.took_ms, "The janitor found nothing to remove");
+        }
+        reclaimed
+    }
+}
+
+/// Sweep whenever `disk` is found to be too full, however long it is until
+/// the next sweep, and say whether that made enough room.
+pub async fn on_disk_full(janitor: Arc<Janitor>, disk: Arc<DiskGuard>) {
+    loop {
+        disk.filled_up().await;
+        warn!("The disk is nearly full, so the janitor is sweeping now");
+        janitor.sweep().await;
+        disk.made_room();
+    }
+}
+
+/// Sweep every `interval`, for as long as the server runs, and revalidate
+/// cached GIFs after each sweep with what's left of it.
+pub async fn run(janitor: Arc<Janitor>, interval: Duration, revalidator: Option<Arc<Revalidator>>) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let started = Instant::now();
+        janitor.sweep().await;
+        if let Some(revalidator) = &revalidator {
+            revalidator.revalidate(interval.saturating_sub(started.elapsed())).await;
+        }
+    }
+}
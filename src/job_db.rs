@@ -0,0 +1,262 @@
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection};
+use std::path::Path;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+/// The schema, one change at a time, in the order they're made. The
+/// database's `user_version` is how many of them it's had.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/001_jobs.sql"),
+    include_str!("../migrations/002_result_key.sql"),
+    include_str!("../migrations/003_usage.sql"),
+];
+
+/// How long a write waits for another connection to let go of the database.
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// A job as it's kept in the database. Times are Unix milliseconds.
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub id: String,
+    /// `queued`, `running`, `done`, `failed` or `cancelled`
+    pub state: &'static str,
+    /// The job as it was submitted, as JSON
+    pub submission: String,
+    /// The name of the API key it was submitted with
+    pub owner: Option<String>,
+    pub client: String,
+    pub created_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    /// A done job's response headers, as JSON pairs, and its GIF
+    pub result: Option<(String, Vec<u8>)>,
+    /// Its GIF's key in `S3_BUCKET`, if it was uploaded there
+    pub result_key: Option<String>,
+    /// A failed job's status and error
+    pub failure: Option<(u16, String)>,
+}
+
+/// What an API key used on one UTC day, as it's kept in the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageRow {
+    pub key: String,
+    /// Days since the Unix epoch
+    pub day: i64,
+    pub conversions: i64,
+    pub cpu_ms: i64,
+    pub output_bytes: i64,
+}
+
+enum Write {
+    Save(Box<Row>),
+    Delete(Vec<String>),
+    /// A key's day, and that its days before `oldest` can go
+    Usage { row: UsageRow, oldest: i64 },
+    /// Told once everything before it has been written
+    Flush(oneshot::Sender<()>),
+}
+
+/// Where jobs, and what API keys have used, are kept so they outlast the
+/// server (`JOB_DB_PATH`), an SQLite database. Writes go to a task of their
+/// own, in the order they're made, so a slow disk never holds up a request;
+/// reads only happen at startup.
+#[derive(Debug, Clone)]
+pub struct JobDb {
+    writes: mpsc::UnboundedSender<Write>,
+}
+
+impl JobDb {
+    /// Open the database at `path`, creating it and bringing its schema up to
+    /// date as needed, and read back every job and day of usage in it.
+    pub async fn open(path: &Path) -> Result<(Self, Vec<Row>, Vec<UsageRow>)> {
+        let path = path.to_path_buf();
+        let (connection, rows, usage) = tokio::task::spawn_blocking(move || {
+            let mut connection = Connection::open(&path)
+                .map_err(|e| anyhow!("Couldn't open JOB_DB_PATH {}: {}", path.display(), e))?;
+            migrate(&mut connection).map_err(|e| anyhow!("Couldn't set up JOB_DB_PATH {}: {}", path.display(), e))?;
+            let read = |e| anyhow!("Couldn't read JOB_DB_PATH {}: {}", path.display(), e);
+            let rows = load(&connection).map_err(read)?;
+            let usage = load_usage(&connection).map_err(read)?;
+            Ok::<_, anyhow::Error>((connection, rows, usage))
+        })
+        .await??;
+        let (writes, queue) = mpsc::unbounded_channel();
+        tokio::spawn(write_jobs(connection, queue));
+        Ok((Self { writes }, rows, usage))
+    }
+
+    /// Keep `row` as it is now, replacing what was kept for the job before.
+    pub fn save(&self, row: Row) {
+        let _ = self.writes.send(Write::Save(Box::new(row)));
+    }
+
+    /// Forget the jobs `ids`.
+    pub fn delete(&self, ids: Vec<String>) {
+        if !ids.is_empty() {
+            let _ = self.writes.send(Write::Delete(ids));
+        }
+    }
+
+    /// Keep `row` as the key's usage for its day, and forget its days before
+    /// `oldest`.
+    pub fn save_usage(&self, row: UsageRow, oldest: i64) {
+        let _ = self.writes.send(Write::Usage { row, oldest });
+    }
+
+    /// Wait for everything saved or deleted so far to be written.
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.writes.send(Write::Flush(done)).is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+// Make the changes in MIGRATIONS the database hasn't had yet, each in a
+// transaction of its own
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    connection.busy_timeout(BUSY_TIMEOUT)?;
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.pragma_update(None, "synchronous", "NORMAL")?;
+    let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (number, migration) in (1..).zip(MIGRATIONS).skip(version as usize) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", number)?;
+        transaction.commit()?;
+        info!("Migrated the job database to version {}", number);
+    }
+    Ok(())
+}
+
+fn load(connection: &Connection) -> rusqlite::Result<Vec<Row>> {
+    let mut statement = connection.prepare(
+        "SELECT id, state, submission, owner, client, created_at, started_at, finished_at, result_headers, result, status, error, result_key
+         FROM jobs ORDER BY created_at",
+    )?;
+    let rows = statement.query_map([], |row| {
+        let state: String = row.get(1)?;
+        let result = match (row.get::<_, Option<String>>(8)?, row.get::<_, Option<Vec<u8>>>(9)?) {
+            (Some(headers), Some(body)) => Some((headers, body)),
+            _ => None,
+        };
+        let failure = match (row.get::<_, Option<u16>>(10)?, row.get::<_, Option<String>>(11)?) {
+            (Some(status), Some(error)) => Some((status, error)),
+            _ => None,
+        };
+        Ok(Row {
+            id: row.get(0)?,
+            state: ["queued", "running", "done", "failed", "cancelled"]
+                .into_iter()
+                .find(|known| *known == state)
+                .unwrap_or("unknown"),
+            submission: row.get(2)?,
+            owner: row.get(3)?,
+            client: row.get(4)?,
+            created_at: row.get(5)?,
+            started_at: row.get(6)?,
+            finished_at: row.get(7)?,
+            result,
+            result_key: row.get(12)?,
+            failure,
+        })
+    })?;
+    rows.collect()
+}
+
+fn load_usage(connection: &Connection) -> rusqlite::Result<Vec<UsageRow>> {
+    let mut statement = connection.prepare("SELECT key, day, conversions, cpu_ms, output_bytes FROM usage ORDER BY day")?;
+    let rows = statement.query_map([], |row| {
+        Ok(UsageRow {
+            key: row.get(0)?,
+            day: row.get(1)?,
+            conversions: row.get(2)?,
+            cpu_ms: row.get(3)?,
+            output_bytes: row.get(4)?,
+        })
+    })?;
+    rows.collect()
+}
+
+// Write what comes in, everything that's waiting at once in one transaction,
+// on the blocking pool
+async fn write_jobs(mut connection: Connection, mut queue: mpsc::UnboundedReceiver<Write>) {
+    while let Some(first) = queue.recv().await {
+        let mut writes = vec![first];
+        while let Ok(next) = queue.try_recv() {
+            writes.push(next);
+        }
+        let written = tokio::task::spawn_blocking(move || {
+            if let Err(e) = apply(&mut connection, &writes) {
+                warn!("Couldn't write {} change(s) to the job database: {}", writes.len(), e);
+            }
+            for write in writes {
+                if let Write::Flush(done) = write {
+                    let _ = done.send(());
+                }
+            }
+            connection
+        })
+        .await;
+        match written {
+            Ok(back) => connection = back,
+            Err(e) => {
+                warn!("The job database's writer failed, so no more jobs will be kept: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn apply(connection: &mut Connection, writes: &[Write]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    for write in writes {
+        match write {
+            Write::Save(row) => {
+                let (result_headers, result) = match &row.result {
+                    Some((headers, body)) => (Some(headers.as_str()), Some(body.as_slice())),
+                    None => (None, None),
+                };
+                let (status, error) = match &row.failure {
+                    Some((status, error)) => (Some(*status), Some(error.as_str())),
+                    None => (None, None),
+                };
+                transaction.execute(
+                    "INSERT OR REPLACE INTO jobs
+                     (id, state, submission, owner, client, created_at, started_at, finished_at, result_headers, result, status, error, result_key)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+                    params![
+                        row.id,
+                        row.state,
+                        row.submission,
+                        row.owner,
+                        row.client,
+                        row.created_at,
+                        row.started_at,
+                        row.finished_at,
+                        result_headers,
+                        result,
+                        status,
+                        error,
+                        row.result_key
+                    ],
+                )?;
+            }
+            Write::Delete(ids) => {
+                for id in ids {
+                    transaction.execute("DELETE FROM jobs WHERE id = ?1", params![id])?;
+                }
+            }
+            Write::Usage { row, oldest } => {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO usage (key, day, conversions, cpu_ms, output_bytes) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![row.key, row.day, row.conversions, row.cpu_ms, row.output_bytes],
+                )?;
+                transaction.execute("DELETE FROM usage WHERE key = ?1 AND day < ?2", params![row.key, oldest])?;
+            }
+            Write::Flush(_) => {}
+        }
+    }
+    transaction.commit()
+}
@@ -0,0 +1,94 @@
+use crate::jobs::{Jobs, Uncancellable, Update, Watch};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, info};
+
+/// What a job's socket takes from the client, e.g. `{"op": "cancel"}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+enum Command {
+    /// Stop the job, queued or running
+    Cancel,
+}
+
+/// Who's on the other end of a job's socket, as far as cancelling it goes.
+pub struct Canceller {
+    /// The name of their API key, if they needed one
+    pub caller: Option<Arc<str>>,
+    /// Whether they have the admin token
+    pub admin: bool,
+}
+
+/// Follow the job `id` on `socket` for `GET /ws/jobs/{id}`: each of the
+/// updates its event stream would have, as `{"event": ..., "data": ...}`,
+/// until it's over and the socket is closed. The client can cancel the job
+/// over it too, if it could with `DELETE /jobs/{id}`. A client that hasn't been heard from for `idle`, not even
+/// answering the pings sent every half of that, is hung up on. However the
+/// socket ends, the job carries on as it was.
+pub async fn serve(mut socket: WebSocket, watch: Watch, jobs: Arc<Jobs>, id: String, canceller: Canceller, idle: Duration) {
+    let mut updates = std::pin::pin!(watch.updates());
+    let mut heard = Instant::now();
+    let mut ping = tokio::time::interval_at(Instant::now() + idle / 2, idle / 2);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let reply = tokio::select! {
+            update = updates.next() => match update {
+                Some(update) => update,
+                None => {
+                    close(socket, close_code::NORMAL, "the job is over").await;
+                    return;
+                }
+            },
+            message = socket.recv() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    // Gone, which is up to them
+                    None | Some(Err(_)) => return,
+                };
+                heard = Instant::now();
+                match message {
+                    Message::Text(text) => match serde_json::from_str::<Command>(&text) {
+                        Ok(Command::Cancel) => match jobs.cancel(&id, canceller.caller.as_deref(), canceller.admin) {
+                            Err(Uncancellable::NotYours) => {
+                                Update { event: "error", data: json!({ "error": "forbidden", "message": "That job was submitted with another API key" }) }
+                            }
+                            cancelled => {
+                                let cancelled = cancelled.is_ok();
+                                info!(cancelled, "Asked to cancel job {} over its socket", id);
+                                Update { event: "cancel", data: json!({ "cancelled": cancelled }) }
+                            }
+                        },
+                        Err(e) => Update { event: "error", data: json!({ "error": "invalid_message", "message": e.to_string() }) },
+                    },
+                    Message::Close(_) => return,
+                    _ => continue,
+                }
+            }
+            _ = ping.tick() => {
+                if socket.send(Message::Ping(Default::default())).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            _ = tokio::time::sleep_until(heard + idle) => {
+                debug!("Hanging up job {}'s idle socket", id);
+                close(socket, close_code::AWAY, "idle").await;
+                return;
+            }
+        };
+        let text = serde_json::to_string(&reply).expect("a job's updates are always JSON");
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn close(mut socket: WebSocket, code: u16, reason: &'static str) {
+    let frame = CloseFrame { code, reason: reason.into() };
+    let _ = socket.send(Message::Close(Some(frame))).await;
+}
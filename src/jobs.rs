@@ -0,0 +1,685 @@
+use crate::admission::Priority;
+use crate::batch::Item;
+use crate::job_db::{JobDb, Row};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use axum::response::sse::Event;
+use bytes::Bytes;
+use fastgif_core::progress::{Progress, Snapshot};
+use futures_util::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Random bytes in a job's ID, which is all that stands between one client
+/// and another's GIF.
+const ID_BYTES: usize = 16;
+
+/// Event streams and sockets one job can have open at once.
+const MAX_EVENT_STREAMS: usize = 4;
+
+/// How often a running job's progress is sent to its event streams, at most.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What `POST /jobs` takes: an item as `POST /batch` has them, and where to
+/// say when it's over. It's kept as it was taken, to be converted again after
+/// a restart.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Submission {
+    pub path: Option<String>,
+    pub url: Option<String>,
+    #[serde(default)]
+    pub params: BTreeMap<String, serde_json::Value>,
+    /// POSTed the job's status once it's over (`WEBHOOK_SECRET`)
+    pub callback_url: Option<String>,
+    /// How soon it's converted when it has to wait its turn
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+impl Submission {
+    /// The item to convert, the callback to make afterwards, and the job's
+    /// priority.
+    pub fn split(self) -> (Item, Option<String>, Priority) {
+        let Submission { path, url, params, callback_url, priority } = self;
+        (Item { path, url, params, variants: Vec::new() }, callback_url, priority)
+    }
+}
+
+/// A GIF a job made, with the headers its response would have had.
+#[derive(Clone)]
+pub struct Finished {
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// Its key in `S3_BUCKET`, once it's been uploaded there
+    pub stored: Option<String>,
+}
+
+/// Why a job didn't make anything: the status and error a `GET` would have
+/// been answered with.
+#[derive(Debug, Clone)]
+pub struct Failed {
+    pub status: u16,
+    pub error: String,
+}
+
+/// What's done with jobs that were running when the server stopped, once it's
+/// started again with the same `JOB_DB_PATH` (`INTERRUPTED_JOBS`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupted {
+    /// They fail with the error `interrupted`
+    Fail,
+    /// They're queued to be converted again from the start
+    Retry,
+}
+
+impl std::str::FromStr for Interrupted {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Interrupted::Fail),
+            "retry" => Ok(Interrupted::Retry),
+            other => Err(format!("unknown INTERRUPTED_JOBS {:?}, expected fail or retry", other)),
+        }
+    }
+}
+
+/// Where a job is at. It goes from `Queued` to `Running` once it's admitted,
+/// and from either to one of the others, where it stays.
+enum State {
+    Queued,
+    Running,
+    Done(Finished),
+    Failed(Failed),
+    Cancelled,
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Queued => "queued",
+            State::Running => "running",
+            State::Done(_) => "done",
+            State::Failed(_) => "failed",
+            State::Cancelled => "cancelled",
+        }
+    }
+
+    fn is_over(&self) -> bool {
+        matches!(self, State::Done(_) | State::Failed(_) | State::Cancelled)
+    }
+
+    /// Whether a job in this state can go on to be in `next`.
+    fn can_become(&self, next: &State) -> bool {
+        match (self, next) {
+            (State::Queued, State::Queued) => false,
+            (State::Queued, _) => true,
+            (State::Running, next) => next.is_over(),
+            _ => false,
+        }
+    }
+}
+
+struct Job {
+    state: State,
+    created: Instant,
+    /// When it was admitted
+    started: Option<Instant>,
+    /// When it was over, after which it's kept for the TTL
+    finished: Option<Instant>,
+    origin: Origin,
+    /// What its conversion has got through
+    progress: Progress,
+    /// Its status, sent again each time its state changes
+    updates: watch::Sender<Status>,
+    /// Held by each of its event streams, to count them
+    streams: Arc<()>,
+    /// Cancelled to stop it, wherever it's got to
+    cancel: CancellationToken,
+}
+
+impl Job {
+    fn new(origin: Origin, created: Instant) -> Self {
+        Job {
+            state: State::Queued,
+            created,
+            started: None,
+            finished: None,
+            origin,
+            progress: Progress::default(),
+            updates: watch::channel(Status::default()).0,
+            streams: Arc::default(),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    fn created(&self, id: &str) -> Created {
+        Created { id: id.to_string(), progress: self.progress.clone(), cancel: self.cancel.clone() }
+    }
+}
+
+/// Who submitted a job and what they asked for.
+#[derive(Debug, Clone)]
+pub struct Origin {
+    /// The name of the API key it was submitted with, if it needed one
+    pub owner: Option<Arc<str>>,
+    /// The [`Submission`] as JSON
+    pub submission: String,
+    /// Who it's from, past the proxies we trust
+    pub client: IpAddr,
+}
+
+/// A job from before a restart that there's something to be done about.
+pub enum Restored {
+    /// Queued to be converted, as it was before or again
+    Resumed { created: Created, origin: Origin },
+    /// Failed as `interrupted`, with its submission, to call back
+    Interrupted { status: Status, submission: String },
+}
+
+/// A job that's just been created, and what its conversion is run with.
+pub struct Created {
+    pub id: String,
+    /// What it reports how far it's got to
+    pub progress: Progress,
+    /// Cancelled when someone asks for the job to stop
+    pub cancel: CancellationToken,
+}
+
+/// What `GET /jobs/{id}` says about a job, and its callback is sent.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Status {
+    pub id: String,
+    /// `queued`, `running`, `done`, `failed` or `cancelled`
+    pub state: &'static str,
+    /// How long it waited for a turn, or has been waiting so far
+    pub queued_ms: u64,
+    /// How long it's been running, or ran for, once it started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub running_ms: Option<u64>,
+    /// Where to fetch the GIF, once it's done
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// The tier it was converted with, if it had to be degraded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub degraded: Option<String>,
+    /// The status a `GET` would have failed with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Why it failed, e.g. `upstream_not_found`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Where to fetch the GIF straight from `S3_BUCKET`, presigned, if it
+    /// was uploaded there
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_url: Option<String>,
+}
+
+/// Conversions asked for with `POST /jobs`, kept in memory until `ttl`
+/// after they're done, and with a `db` there too, so they survive a restart.
+/// Jobs still queued or running are never dropped, and there are never more
+/// than `max` of them, whatever state they're in.
+pub struct Jobs {
+    ttl: Duration,
+    max: usize,
+    jobs: Mutex<HashMap<String, Job>>,
+    db: Option<JobDb>,
+}
+
+impl Jobs {
+    pub fn new(ttl: Duration, max: usize, db: Option<JobDb>) -> Self {
+        Self { ttl, max, jobs: Mutex::default(), db }
+    }
+
+    /// Whether jobs are kept in a database, to outlast the server.
+    pub fn is_persistent(&self) -> bool {
+        self.db.is_some()
+    }
+
+    /// A new queued job from `origin`, or `None` if there's no room for
+    /// another.
+    pub fn create(&self, origin: Origin) -> Option<Created> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.expire(&mut jobs);
+        if jobs.len() >= self.max {
+            return None;
+        }
+        let id = new_id();
+        let job = Job::new(origin, Instant::now());
+        job.updates.send_replace(describe(&id, &job));
+        self.save(&id, &job);
+        let created = job.created(&id);
+        jobs.insert(id, job);
+        Some(created)
+    }
+
+    /// Take back the jobs kept in the database before a restart. Those that
+    /// were queued come back to be converted, and so do those that were
+    /// running if `interrupted` says to retry them; otherwise they fail.
+    /// Jobs that have been over for longer than the TTL since are dropped.
+    pub fn restore(&self, rows: Vec<Row>, interrupted: Interrupted) -> Vec<Restored> {
+        if rows.is_empty() {
+            return Vec::new();
+        }
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut restored = Vec::new();
+        let mut unreadable = Vec::new();
+        for row in rows {
+            let origin = Origin {
+                owner: row.owner.map(Arc::from),
+                submission: row.submission,
+                client: row.client.parse().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            };
+            let mut job = Job::new(origin, instant_at(row.created_at));
+            job.started = row.started_at.map(instant_at);
+            job.finished = row.finished_at.map(instant_at);
+            job.state = match (row.state, row.result, row.failure) {
+                ("queued", ..) => State::Queued,
+                ("running", ..) if interrupted == Interrupted::Retry => {
+                    job.started = None;
+                    State::Queued
+                }
+                ("running", ..) => {
+                    job.finished = Some(Instant::now());
+                    State::Failed(Failed { status: 503, error: "interrupted".to_string() })
+                }
+                ("done", Some((headers, body)), _) => {
+                    State::Done(Finished { headers: headers_from_json(&headers), body: body.into(), stored: row.result_key })
+                }
+                ("failed", _, Some((status, error))) => State::Failed(Failed { status, error }),
+                ("cancelled", ..) => State::Cancelled,
+                (state, ..) => {
+                    warn!("Dropping job {} from the job database, as it's {} with nothing to show for it", row.id, state);
+                    unreadable.push(row.id);
+                    continue;
+                }
+            };
+            let status = describe(&row.id, &job);
+            job.updates.send_replace(status.clone());
+            match &job.state {
+                State::Queued => {
+                    self.save(&row.id, &job);
+                    restored.push(Restored::Resumed { created: job.created(&row.id), origin: job.origin.clone() });
+                }
+                State::Failed(_) if row.state == "running" => {
+                    self.save(&row.id, &job);
+                    restored.push(Restored::Interrupted { status, submission: job.origin.submission.clone() });
+                }
+                _ => {}
+            }
+            jobs.insert(row.id, job);
+        }
+        if let Some(db) = &self.db {
+            db.delete(unreadable);
+        }
+        self.expire(&mut jobs);
+        info!("Restored {} jobs from the job database, {} of them to be converted", jobs.len(), restored.iter().filter(|job| matches!(job, Restored::Resumed { .. })).count());
+        restored
+    }
+
+    /// Wait for every change to the jobs so far to be in the database.
+    pub async fn flush(&self) {
+        if let Some(db) = &self.db {
+            db.flush().await;
+        }
+    }
+
+    /// Stop the job `id` for `caller`, the name of the API key asking, or
+    /// for an admin. A queued job is cancelled there and then. A running one
+    /// has its conversion stopped, and is cancelled once that's done. What
+    /// comes back is the job's status as of now.
+    pub fn cancel(&self, id: &str, caller: Option<&str>, admin: bool) -> Result<Status, Uncancellable> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.expire(&mut jobs);
+        let job = jobs.get_mut(id).ok_or(Uncancellable::NoSuchJob)?;
+        if !admin && job.origin.owner.as_deref() != caller {
+            return Err(Uncancellable::NotYours);
+        }
+        if job.state.is_over() {
+            return Err(Uncancellable::Over(Box::new(describe(id, job))));
+        }
+        if matches!(job.state, State::Queued) && transition(id, job, State::Cancelled) {
+            self.save(id, job);
+        }
+        job.cancel.cancel();
+        Ok(describe(id, job))
+    }
+
+    /// Note that the job `id` has been admitted and is now converting.
+    pub fn running(&self, id: &str) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            if transition(id, job, State::Running) {
+                self.save(id, job);
+            }
+        }
+    }
+
+    /// Note how the job `id` ended, which starts its TTL, returning its
+    /// status as of now. A job that's already over, like one that was
+    /// cancelled while it was queued, stays as it ended.
+    pub fn finish(&self, id: &str, result: Result<Finished, Failed>) -> Option<Status> {
+        self.end(id, match result {
+            Ok(finished) => State::Done(finished),
+            Err(failed) => State::Failed(failed),
+        })
+    }
+
+    /// Note that the job `id` stopped when it was cancelled, like `finish`.
+    pub fn cancelled(&self, id: &str) -> Option<Status> {
+        self.end(id, State::Cancelled)
+    }
+
+    fn end(&self, id: &str, state: State) -> Option<Status> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(id)?;
+        if transition(id, job, state) {
+            self.save(id, job);
+        }
+        Some(describe(id, job))
+    }
+
+    /// Follow the job `id` as it goes, for `GET /jobs/{id}/events`, unless
+    /// there's no such job or it's being watched enough already.
+    pub fn watch(&self, id: &str) -> Result<Watch, Unwatchable> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.expire(&mut jobs);
+        let job = jobs.get(id).ok_or(Unwatchable::NoSuchJob)?;
+        // Every stream holds a clone, and the job itself the original
+        if Arc::strong_count(&job.streams) > MAX_EVENT_STREAMS {
+            return Err(Unwatchable::TooManyStreams);
+        }
+        Ok(Watch {
+            updates: job.updates.subscribe(),
+            progress: job.progress.subscribe(),
+            _stream: job.streams.clone(),
+        })
+    }
+
+    /// Where the job `id` is at, unless there's no such job (any more).
+    pub fn status(&self, id: &str) -> Option<Status> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.expire(&mut jobs);
+        jobs.get(id).map(|job| describe(id, job))
+    }
+
+    /// The GIF the job `id` made, or where it's at if it hasn't made one.
+    pub fn result(&self, id: &str) -> Option<Result<Finished, Status>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.expire(&mut jobs);
+        let finished = match &jobs.get(id)?.state {
+            State::Done(finished) => Some(finished.clone()),
+            _ => None,
+        };
+        drop(jobs);
+        match finished {
+            Some(finished) => Some(Ok(finished)),
+            None => self.status(id).map(Err),
+        }
+    }
+
+    /// Drop the jobs that have been over for longer than the TTL, even if
+    /// nobody's asked about any since, and say how many there were.
+    pub fn prune(&self) -> usize {
+        let mut jobs = self.jobs.lock().unwrap();
+        let before = jobs.len();
+        self.expire(&mut jobs);
+        before - jobs.len()
+    }
+
+    // Drop the jobs that have been over for longer than the TTL
+    fn expire(&self, jobs: &mut HashMap<String, Job>) {
+        let mut expired = Vec::new();
+        jobs.retain(|id, job| {
+            let keep = job.finished.is_none_or(|finished| finished.elapsed() < self.ttl);
+            if !keep {
+                expired.push(id.clone());
+            }
+            keep
+        });
+        if let Some(db) = &self.db {
+            db.delete(expired);
+        }
+    }
+
+    // Keep `job`, which is `id`, in the database as it is now
+    fn save(&self, id: &str, job: &Job) {
+        let Some(db) = &self.db else { return };
+        let (result, result_key, failure) = match &job.state {
+            State::Done(finished) => (Some((headers_to_json(&finished.headers), finished.body.to_vec())), finished.stored.clone(), None),
+            State::Failed(failed) => (None, None, Some((failed.status, failed.error.clone()))),
+            State::Queued | State::Running | State::Cancelled => (None, None, None),
+        };
+        db.save(Row {
+            id: id.to_string(),
+            state: job.state.name(),
+            submission: job.origin.submission.clone(),
+            owner: job.origin.owner.as_deref().map(str::to_string),
+            client: job.origin.client.to_string(),
+            created_at: unix_ms(job.created),
+            started_at: job.started.map(unix_ms),
+            finished_at: job.finished.map(unix_ms),
+            result,
+            result_key,
+            failure,
+        });
+    }
+}
+
+// When `at` was, in Unix milliseconds
+fn unix_ms(at: Instant) -> i64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.saturating_sub(at.elapsed()).as_millis() as i64
+}
+
+// The instant that was `unix_ms`, or as near as the clock goes back
+fn instant_at(unix_ms: i64) -> Instant {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+    let ago = Duration::from_millis(now.saturating_sub(unix_ms).max(0) as u64);
+    Instant::now().checked_sub(ago).unwrap_or_else(Instant::now)
+}
+
+fn headers_to_json(headers: &HeaderMap) -> String {
+    let pairs: Vec<(&str, &str)> = headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+        .collect();
+    serde_json::to_string(&pairs).expect("headers are always JSON")
+}
+
+fn headers_from_json(json: &str) -> HeaderMap {
+    let pairs: Vec<(String, String)> = serde_json::from_str(json).unwrap_or_default();
+    pairs
+        .into_iter()
+        .filter_map(|(name, value)| Some((HeaderName::try_from(name).ok()?, HeaderValue::try_from(value).ok()?)))
+        .collect()
+}
+
+// Move `job`, which is `id`, on to `next`, if it can go there, telling its
+// watchers. Whether it did.
+fn transition(id: &str, job: &mut Job, next: State) -> bool {
+    if !job.state.can_become(&next) {
+        debug!("Job {} can't go from {} to {}", id, job.state.name(), next.name());
+        return false;
+    }
+    match next {
+        State::Queued => {}
+        State::Running => job.started = Some(Instant::now()),
+        State::Done(_) | State::Failed(_) | State::Cancelled => job.finished = Some(Instant::now()),
+    }
+    job.state = next;
+    job.updates.send_replace(describe(id, job));
+    true
+}
+
+/// Why a job can't be cancelled.
+#[derive(Debug)]
+pub enum Uncancellable {
+    NoSuchJob,
+    /// It was submitted with another API key
+    NotYours,
+    /// It's already over, and this is how it ended
+    Over(Box<Status>),
+}
+
+/// Why a job can't be watched.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Unwatchable {
+    NoSuchJob,
+    TooManyStreams,
+}
+
+/// One of a job's event streams or sockets. Dropping it, like a client that
+/// hangs up does, leaves the job as it was.
+pub struct Watch {
+    updates: watch::Receiver<Status>,
+    progress: watch::Receiver<Snapshot>,
+    _stream: Arc<()>,
+}
+
+/// A running job's `progress` event.
+#[derive(Debug, Serialize)]
+struct ProgressEvent {
+    /// Frames handed to the encoder so far
+    frames: u64,
+    /// About how many there'll be, if the video's length is known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected_frames: Option<u64>,
+    /// How far along that is, never 100 until it's done
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percent: Option<u64>,
+    /// What the encoder has made so far
+    bytes: u64,
+}
+
+/// Something a job's watchers are told: the event's name, e.g. `progress`,
+/// and what it says.
+#[derive(Debug, Serialize)]
+pub struct Update {
+    pub event: &'static str,
+    pub data: serde_json::Value,
+}
+
+impl Update {
+    fn of(event: &'static str, data: impl Serialize) -> Self {
+        Self { event, data: serde_json::to_value(data).expect("a job's updates are always JSON") }
+    }
+
+    /// As a server-sent event.
+    pub fn into_event(self) -> Event {
+        Event::default().event(self.event).data(self.data.to_string())
+    }
+}
+
+impl Watch {
+    /// What the job's watchers are told: a `state` event with its status to
+    /// begin with and whenever it changes, `progress` every so often while
+    /// it's converting, and finally `done`, `failed` or `cancelled` with its
+    /// last status.
+    /// A job that expires while it's being watched ends with `expired`.
+    pub fn updates(self) -> impl Stream<Item = Update> + Send {
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + PROGRESS_INTERVAL, PROGRESS_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        stream::unfold(Some((self, ticker, true)), |watching| async move {
+            let (mut watch, mut ticker, first) = watching?;
+            if first {
+                let status = watch.updates.borrow_and_update().clone();
+                let (update, over) = state_update(status);
+                return Some((update, (!over).then_some((watch, ticker, false))));
+            }
+            loop {
+                tokio::select! {
+                    changed = watch.updates.changed() => {
+                        if changed.is_err() {
+                            return Some((Update::of("expired", serde_json::json!({})), None));
+                        }
+                        let status = watch.updates.borrow_and_update().clone();
+                        let (update, over) = state_update(status);
+                        return Some((update, (!over).then_some((watch, ticker, false))));
+                    }
+                    _ = ticker.tick() => {
+                        if watch.progress.has_changed().unwrap_or(false) {
+                            let snapshot = *watch.progress.borrow_and_update();
+                            return Some((progress_update(snapshot), Some((watch, ticker, false))));
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+// The update for `status`, and whether it's the last
+fn state_update(status: Status) -> (Update, bool) {
+    let over = matches!(status.state, "done" | "failed" | "cancelled");
+    let event = if over { status.state } else { "state" };
+    (Update::of(event, status), over)
+}
+
+fn progress_update(snapshot: Snapshot) -> Update {
+    let percent = snapshot
+        .expected_frames
+        .filter(|expected| *expected > 0)
+        .map(|expected| (snapshot.frames * 100 / expected).min(99));
+    let progress = ProgressEvent {
+        frames: snapshot.frames,
+        expected_frames: snapshot.expected_frames,
+        percent,
+        bytes: snapshot.bytes,
+    };
+    Update::of("progress", progress)
+}
+
+// What's said about `job`, which is `id`
+fn describe(id: &str, job: &Job) -> Status {
+    let millis = |from: Instant, to: Option<Instant>| to.unwrap_or_else(Instant::now).duration_since(from).as_millis() as u64;
+    let mut status = Status {
+        id: id.to_string(),
+        state: job.state.name(),
+        queued_ms: millis(job.created, job.started.or(job.finished)),
+        running_ms: job.started.map(|started| millis(started, job.finished)),
+        result_url: None,
+        bytes: None,
+        content_type: None,
+        etag: None,
+        degraded: None,
+        status: None,
+        error: None,
+        download_url: None,
+    };
+    match &job.state {
+        State::Queued | State::Running | State::Cancelled => {}
+        State::Done(finished) => {
+            let header = |name| finished.headers.get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+            status.result_url = Some(format!("/jobs/{}/result", id));
+            status.bytes = Some(finished.body.len() as u64);
+            status.content_type = header(header::CONTENT_TYPE);
+            status.etag = header(header::ETAG);
+            status.degraded = header(header::HeaderName::from_static("x-fastgif-degraded"));
+        }
+        State::Failed(failed) => {
+            status.status = Some(failed.status);
+            status.error = Some(failed.error.clone());
+        }
+    }
+    status
+}
+
+/// 128 bits from the OS, as hex.
+pub fn new_id() -> String {
+    let mut bytes = [0u8; ID_BYTES];
+    getrandom::fill(&mut bytes).expect("the OS has no random numbers to give");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
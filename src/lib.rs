@@ -0,0 +1,3947 @@
+mod access_log;
+mod active;
+pub mod admission;
+mod api_keys;
+mod audit;
+mod batch;
+mod build_info;
+mod cache;
+mod client_ip;
+pub mod clip;
+mod client_limit;
+mod compare;
+pub mod config;
+pub mod convert;
+mod degrade;
+mod error_report;
+mod estimate;
+mod frame;
+mod health;
+pub mod healthcheck;
+mod hotlink;
+mod idempotency;
+mod ip_filter;
+mod janitor;
+mod job_db;
+mod job_socket;
+mod jobs;
+mod listener;
+mod maintenance;
+mod metrics;
+mod prefetch;
+mod popularity;
+mod pressure;
+mod probe;
+pub mod rate_limit;
+pub mod reload;
+mod revalidate;
+mod rolling;
+mod s3;
+mod server;
+mod shared_decode;
+mod shutdown;
+mod slow_client;
+pub mod source_path;
+mod sprite;
+mod stills;
+mod statsd;
+mod summary;
+mod systemd;
+mod telemetry;
+mod throttle;
+mod tls;
+mod upload;
+mod usage;
+pub mod variant;
+pub mod warm;
+mod webhook;
+
+pub use listener::Connection;
+pub use telemetry::LogFilter;
+
+use access_log::{AccessLog, AccessNote};
+use active::ActiveConversions;
+use admission::{Admission, Priority, Rejection};
+use api_keys::{ApiKeys, Caller};
+use audit::AuditLog;
+use build_info::BuildInfo;
+use cache::{CachedFile, CachedGif, Contents, DiskCache, GifCache, Purge, Upstream};
+use client_ip::TrustedProxies;
+use clip::{LongVideoPolicy, Trimmed};
+use client_limit::{ClientConcurrency, ClientKey, ClientSlot};
+use config::Config;
+use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use axum::{
+    body::Body,
+    middleware::{self, Next},
+    extract::{ws::WebSocketUpgrade, ConnectInfo, DefaultBodyLimit, Extension, FromRequestParts, Path, Query, Request, State},
+    http::{header, request::Parts, HeaderMap, StatusCode, Uri},
+    response::{sse::{KeepAlive, Sse}, IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use degrade::Degraded;
+use estimate::{Shape, SizeModel};
+use frame::FrameQuery;
+use fastgif_core::auto_quality;
+use fastgif_core::backend::{Backend, Format};
+use fastgif_core::error_class::{ErrorClass, CUT_SHORT};
+use fastgif_core::fetch::{FetchMode, SourceFetcher, SourceRef};
+use fastgif_core::hwaccel::{self, Hwaccel};
+use fastgif_core::pipeline::{process_tweet_video, GifOutput, PipelineConfig, StreamedBody, MOOV_RETRY_DELAY};
+use fastgif_core::progress::Progress;
+use fastgif_core::spill::SpilledBody;
+use fastgif_core::temp::{TempFile, TempManager};
+use fastgif_core::stderr_tail::StderrTail;
+use fastgif_core::tier::Tier;
+use fastgif_core::timing::StageTimes;
+use fastgif_core::trace::TraceContext;
+use fastgif_core::{ConversionError, ConversionOptions, Converter, CoreConfig, Encoder, StartupError};
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+use health::{Health, Readiness};
+use hotlink::HotlinkProtection;
+use idempotency::{Begun, Claim, Fingerprint, Idempotency};
+use ip_filter::ClientFilter;
+use janitor::Janitor;
+use job_db::JobDb;
+use job_socket::Canceller;
+use jobs::{Created, Failed, Finished, Jobs, Origin, Restored, Uncancellable, Unwatchable};
+use maintenance::Maintenance;
+use listener::{Bind, Peer};
+use metrics::{Metrics, StatsAccess};
+use prefetch::{Declined, Prefetches};
+use pressure::{AdmissionState, PressureThresholds};
+use probe::Prober;
+use rate_limit::{Checked, Rate, RateLimiter};
+use popularity::{Popular, Popularity, Served, Window};
+use reload::{Reloader, Settings};
+use revalidate::Revalidator;
+use s3::Bucket;
+use serde::{Deserialize, Serialize};
+use shutdown::Conversions;
+use slow_client::{GuardedListener, SlowClientGuard};
+use sprite::SpriteQuery;
+use stills::{ImageFormat, Still, Stills};
+use telemetry::RequestId;
+use throttle::{Egress, Pacing, Throttle};
+use upload::{Refused, Upload};
+use usage::{Exceeded, Usage};
+use variant::{ConversionQuery, VariantKey};
+use webhook::Webhooks;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use tower_http::services::ServeFile;
+use tower_http::trace::TraceLayer;
+use tracing::{error, field::Empty, info, info_span, warn, Instrument, Span};
+
+/// How often StatsD is sent the gauges.
+const STATSD_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many chunks of a streamed GIF may be waiting on a slow client.
+const STREAM_CHANNEL_CHUNKS: usize = 16;
+
+/// How much of an upload's form may be other than the video, its fields and
+/// the parts' headers.
+const FORM_OVERHEAD_BYTES: u64 = 64 * 1024;
+
+/// Everything the routes share.
+#[derive(Clone)]
+pub struct AppState {
+    admission: Arc<Admission>,
+    /// What a reload can change
+    settings: Arc<ArcSwap<Settings>>,
+    reloader: Arc<Reloader>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Batches each client has sent (`BATCH_RATE_LIMIT`), apart from their items
+    batch_rate_limiter: Arc<RateLimiter>,
+    batch_rate_limit: Rate,
+    /// Most items a batch may have (`BATCH_MAX_ITEMS`)
+    batch_max_items: usize,
+    /// Items of a batch converted at once (`BATCH_CONCURRENCY`)
+    batch_concurrency: usize,
+    /// Finished GIFs to send again without converting them (`CACHE_MAX_BYTES`)
+    cache: Option<Arc<GifCache>>,
+    /// and those kept on disk, to be sent from there (`CACHE_DIR`)
+    disk_cache: Option<Arc<DiskCache>>,
+    /// GIFs being made for `POST /prefetch`
+    prefetches: Arc<Prefetches>,
+    /// Conversions asked for with `POST /jobs`, to be asked after later
+    jobs: Arc<Jobs>,
+    /// How POSTs with an `Idempotency-Key` were answered (`IDEMPOTENCY_TTL`)
+    idempotency: Option<Arc<Idempotency>>,
+    /// What clears away leftover temp files and expired jobs
+    janitor: Arc<Janitor>,
+    /// Which paths are asked for most (`POPULARITY_MAX_PATHS`)
+    popularity: Option<Arc<Popularity>>,
+    /// What tells jobs' submitters they're over, when there's a `WEBHOOK_SECRET`
+    webhooks: Option<Arc<Webhooks>>,
+    /// Where finished GIFs are uploaded to, to be fetched from instead (`S3_BUCKET`)
+    bucket: Option<Arc<Bucket>>,
+    /// How long a job's socket can go without hearing from its client
+    job_socket_idle_timeout: Duration,
+    /// Refusing new conversions (`MAINTENANCE`, `/admin/maintenance`)
+    maintenance: Arc<Maintenance>,
+    /// Conversions each client has running (`MAX_CONCURRENT_PER_CLIENT`)
+    per_client: Option<Arc<ClientConcurrency>>,
+    trust_proxy: Arc<TrustedProxies>,
+    converter: Converter,
+    metrics: Arc<Metrics>,
+    prober: Arc<Prober>,
+    /// What `GET /tweet_video/{path}/estimate` guesses with, fitted to conversions as they finish
+    size_model: Arc<SizeModel>,
+    max_input_duration: Option<Duration>,
+    long_video_policy: LongVideoPolicy,
+    stream_response: bool,
+    /// Hold conversions to the profile for what their video is like, when
+    /// they ask for nothing of their own (`AUTO_QUALITY`)
+    auto_quality: bool,
+    /// Refuse what the encoder can't do rather than ignore it (`STRICT_PARAMS`)
+    strict_params: bool,
+    /// What uploads are written to while they're converted, in `TMP_DIR`,
+    /// and what keeps them off it once it's nearly full
+    /// (`MIN_FREE_DISK_BYTES`) or too much of it is taken (`TEMP_MAX_BYTES`)
+    temp: Arc<TempManager>,
+    /// The largest upload taken (`MAX_UPLOAD_BYTES`)
+    max_upload_bytes: u64,
+    pacing: Pacing,
+    conversions: Conversions,
+    /// What each of them is up to, for `GET /admin/conversions`
+    active: Arc<ActiveConversions>,
+    log_filter: LogFilter,
+    health: Arc<Health>,
+    readiness: Arc<Readiness>,
+    build: Arc<BuildInfo>,
+    /// Bearer token for `/admin`, which doesn't exist without one
+    admin_token: Option<Arc<str>>,
+    /// Who `/stats` answers
+    stats_access: StatsAccess,
+    /// Put the children's stderr in failed conversions' responses (`ERROR_DETAIL`)
+    error_detail: bool,
+    /// Sent for failures an embed can't do anything about (`FAILURE_PLACEHOLDER_PATH`)
+    failure_placeholder: Option<Bytes>,
+    /// Requests taking longer than this get logged in detail (`SLOW_REQUEST_THRESHOLD`)
+    slow_request_threshold: Option<Duration>,
+    /// Where completed conversions are recorded (`AUDIT_LOG_PATH`)
+    audit: Option<AuditLog>,
+    /// Conversions need one of these, if there are any (`API_KEYS`)
+    api_keys: Option<Arc<ApiKeys>>,
+    /// What each key has used, and the quotas they're held to
+    usage: Arc<Usage>,
+    /// What `POST /admin/compare` made, for a while
+    compared: Arc<compare::Results>,
+    /// What `GET /tweet_video/{path}/sprite` and `/frame` made, for a while
+    stills: Arc<Stills>,
+    /// What the TLS listeners serve, and a reload reads again
+    certificates: Option<Arc<tls::Certificates>>,
+    /// Whether requests join their caller's trace, which they only do when
+    /// traces are being exported
+    exporting_traces: bool,
+}
+
+impl AppState {
+    /// Set up everything the routes share from `config`: the binaries, the
+    /// pipeline, admission and the rest. `log_filter` is what
+    /// `/admin/log-level` changes.
+    pub async fn new(config: &Config, log_filter: LogFilter, exporting_traces: bool) -> Result<Self> {
+        // What can be changed by a reload, read through this from now on
+        let settings = Settings::from_config(config)?;
+
+        // Conversion admission: how many run at once, and how many may queue behind them
+        let default_concurrency = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let max_concurrent = settings.max_concurrent;
+        info!(
+            "Allowing {} concurrent conversions, {} queued for up to {:?}",
+            max_concurrent, settings.max_queue_depth, settings.queue_wait_timeout
+        );
+
+        // Optionally also turn conversions away while the machine itself is struggling
+        let pressure_thresholds = PressureThresholds {
+            max_load_per_core: config.load_shed_max_load.filter(|load| *load > 0.0),
+            min_available_memory_bytes: config.load_shed_min_memory_mb.map(|mb| mb * 1024 * 1024),
+            max_latency: config
+                .load_shed_max_latency
+                .filter(|secs| *secs > 0.0)
+                .map(Duration::from_secs_f64),
+        };
+        let pressure = if pressure_thresholds.is_empty() {
+            None
+        } else {
+            info!("Shedding load when the system is under pressure: {:?}", pressure_thresholds);
+            let pressure = Arc::new(AdmissionState::new(pressure_thresholds, default_concurrency));
+            pressure::spawn(pressure.clone());
+            Some(pressure)
+        };
+
+        // Rather than making clients wait, busy periods can get smaller, quicker GIFs
+        if let Some(tiers) = &settings.load_tiers {
+            info!("Degrading conversions under load: {:?}", tiers);
+        }
+
+        // Per-client rate limiting of conversions, off unless RATE_LIMIT is set (e.g. `30/minute`)
+        let max_clients = config.rate_limit_max_clients;
+        if let Some(rate) = settings.rate_limit {
+            info!(
+                "Rate limiting conversions to {} per {:?} per client (tracking up to {} clients)",
+                rate.requests, rate.period, max_clients
+            );
+        }
+        let rate_limiter = Arc::new(RateLimiter::new(max_clients));
+        let batch_rate_limiter = Arc::new(RateLimiter::new(max_clients));
+        if !config.trust_proxy.is_empty() {
+            info!("Taking client addresses from proxy headers: {:?}", config.trust_proxy);
+        }
+        let trust_proxy = Arc::new(config.trust_proxy.clone());
+
+        // Videos longer than this are refused, or with AUTO_TRIM only their start is converted
+        let max_input_duration = Some(config.max_input_duration)
+            .filter(|secs| *secs > 0.0)
+            .map(Duration::from_secs_f64);
+        let long_video_policy = config.long_video_policy.unwrap_or(match config.auto_trim {
+            true => LongVideoPolicy::Trim,
+            false => LongVideoPolicy::Reject,
+        });
+        match max_input_duration {
+            Some(max) if long_video_policy == LongVideoPolicy::Trim => info!("Videos longer than {:?} are trimmed", max),
+            Some(max) => info!("Videos longer than {:?} are refused", max),
+            None => info!("Video duration is not limited"),
+        }
+
+        // Send GIFs to clients as they're encoded rather than once they're finished
+        let stream_response = config.stream_response;
+
+        // and keep the finished ones around, to send again to whoever asks next
+        let cache = config.cache_max_bytes.filter(|max| *max > 0).map(|max| {
+            info!("Keeping up to {} bytes of finished GIFs in memory", max);
+            Arc::new(GifCache::new(max))
+        });
+        let disk_cache = match &config.cache_dir {
+            Some(dir) if config.cache_dir_max_bytes > 0 => {
+                let disk_cache = DiskCache::new(dir.clone(), config.cache_dir_max_bytes, config.cache_dir_guard())
+                    .map_err(|e| anyhow!("Couldn't use CACHE_DIR {}: {}", dir.display(), e))?;
+                info!("Keeping up to {} bytes of finished GIFs in {}", config.cache_dir_max_bytes, dir.display());
+                Some(Arc::new(disk_cache))
+            }
+            _ => None,
+        };
+
+        // The outliers get a detailed warning of their own
+        let slow_request_threshold = config
+            .slow_request_threshold
+            .filter(|secs| *secs > 0.0)
+            .map(Duration::from_secs_f64);
+        if let Some(threshold) = slow_request_threshold {
+            info!("Requests taking over {:?} are logged as slow", threshold);
+        }
+        if stream_response {
+            info!("Streaming GIFs to clients as they're encoded");
+        }
+
+        // Optionally cap how fast any one client can download a big GIF
+        let throttle = config
+            .throttle_bytes_per_sec
+            .filter(|rate| *rate > 0)
+            .map(|bytes_per_sec| Throttle {
+                bytes_per_sec,
+                min_bytes: config.throttle_min_bytes,
+            });
+        if let Some(throttle) = &throttle {
+            info!(
+                "Sending GIFs over {} bytes at up to {} bytes/s",
+                throttle.min_bytes, throttle.bytes_per_sec
+            );
+        }
+        // and how fast all of them together can go, e.g. `200Mbit/s`
+        let egress = match config.egress_limit {
+            Some(bandwidth) => {
+                info!("Sending GIFs at up to {} bytes/s in total", bandwidth.0);
+                Some(Arc::new(Egress::new(bandwidth)))
+            }
+            None => None,
+        };
+
+        // Metrics can also be pushed to a StatsD agent, as well as scraped
+        let statsd = match config.statsd_addr.as_deref().filter(|addr| !addr.is_empty()) {
+            Some(addr) => {
+                let mut tags = match &config.statsd_tags {
+                    Some(spec) => statsd::parse_tags(spec).map_err(|e| anyhow!("Invalid STATSD_TAGS {:?}: {}", spec, e))?,
+                    None => Vec::new(),
+                };
+                if let Some(instance) = config.instance() {
+                    tags.insert(0, format!("instance:{}", instance));
+                }
+                let statsd = statsd::Statsd::connect(addr, &config.statsd_prefix, tags)
+                    .map_err(|e| anyhow!("Invalid STATSD_ADDR {:?}: {}", addr, e))?;
+                info!("Sending metrics to StatsD at {}", addr);
+                Some(statsd)
+            }
+            None => None,
+        };
+        let metrics = Arc::new(Metrics::new(statsd));
+        if let Some(host) = reqwest::Url::parse(&config.video_base_url).ok().as_ref().and_then(reqwest::Url::host_str) {
+            metrics.upstream_host(host);
+        }
+
+        // The conversions themselves, now that there's somewhere to count their
+        // failures. ffmpeg would happily use every core for each one, so split
+        // them between the conversions we allow to run at once.
+        let core = config.core_config();
+        // The same one spills are made by, for uploads too
+        let temp = core.temp.clone();
+        let converter = new_converter(CoreConfig {
+            ffmpeg_threads: Some(config.ffmpeg_threads.unwrap_or((default_concurrency / max_concurrent).max(1))),
+            metrics: metrics.clone(),
+            ..core
+        })?;
+        let pipeline = converter.pipeline();
+
+        // Exactly what's deployed, for triaging reports
+        let build = Arc::new(BuildInfo::detect(pipeline.encoder, pipeline.decoder, &pipeline.binaries));
+        info!("{}", build);
+        let prober = Arc::new(Prober::new(pipeline.binaries.ffprobe.clone(), core.child_limits, metrics.clone()));
+
+        // A durable record of what was converted for whom, for abuse investigations
+        let audit = match config.audit_log_path.clone().filter(|path| !path.as_os_str().is_empty()) {
+            Some(path) => {
+                let audit = AuditLog::open(path.clone(), metrics.clone())
+                    .await
+                    .map_err(|e| anyhow!("Couldn't open AUDIT_LOG_PATH {}: {}", path.display(), e))?;
+                info!("Recording conversions in {}", path.display());
+                Some(audit)
+            }
+            None => None,
+        };
+        // HTTPS straight from us, for when there's no proxy in front to do it
+        let certificates = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => {
+                let certificates = tls::Certificates::load(cert, key, config.tls_client_ca.as_deref())?;
+                info!("Serving HTTPS with the certificate {}", cert.display());
+                Some(Arc::new(certificates))
+            }
+            (None, None) => None,
+            _ => return Err(anyhow!("TLS_CERT and TLS_KEY have to be set together")),
+        };
+        // Conversions can be paused without stopping the server
+        let refusal = maintenance::Refusal {
+            message: config.maintenance_message.clone(),
+            gif: read_gif("MAINTENANCE_GIF", config.maintenance_gif.as_deref()).await?,
+            retry_after: Duration::from_secs(config.maintenance_retry_after),
+        };
+        let maintenance = Arc::new(Maintenance::new(config.maintenance, refusal));
+        if config.maintenance {
+            warn!("Starting in maintenance mode: refusing new conversions");
+        }
+        // Shared secrets for conversions, for when the server is out in the open
+        let api_keys = ApiKeys::load(&config.api_keys, config.api_keys_file.as_deref())?;
+        // Jobs outlast a restart if there's somewhere to keep them
+        let (job_db, kept_jobs, kept_usage) = match &config.job_db_path {
+            Some(path) => {
+                let (db, rows, usage) = JobDb::open(path).await?;
+                info!("Keeping jobs in {}", path.display());
+                (Some(db), rows, usage)
+            }
+            None => (None, Vec::new(), Vec::new()),
+        };
+        let quotas = config.quotas().map_err(|e| anyhow!("Invalid API_KEY_QUOTAS: {}", e))?;
+        let usage = Arc::new(Usage::new(quotas, job_db.clone(), kept_usage));
+        let bucket = match config.s3_bucket.as_deref().filter(|name| !name.is_empty()) {
+            Some(name) => Some(Arc::new(Bucket::new(name, config)?)),
+            None => None,
+        };
+        let jobs = Arc::new(Jobs::new(Duration::from_secs(config.job_ttl), config.max_jobs, job_db));
+        let restored = jobs.restore(kept_jobs, config.interrupted_jobs);
+        let janitor = Arc::new(Janitor::new(
+            temp.clone(),
+            config.janitor_max_files_per_sec,
+            jobs.clone(),
+            metrics.clone(),
+        ));
+        let settings = Arc::new(ArcSwap::from_pointee(settings));
+        let admission = Arc::new(Admission::new(settings.clone(), pressure));
+        let reloader = Arc::new(Reloader::new(
+            config,
+            settings.clone(),
+            admission.clone(),
+            log_filter.clone(),
+            certificates.clone(),
+        ));
+        let state = AppState {
+            admission,
+            settings: settings.clone(),
+            reloader,
+            rate_limiter,
+            batch_rate_limiter,
+            batch_rate_limit: config.batch_rate_limit,
+            batch_max_items: config.batch_max_items,
+            batch_concurrency: config.batch_concurrency.max(1),
+            cache,
+            disk_cache,
+            prefetches: Arc::new(Prefetches::new(config.max_pending_prefetches)),
+            jobs,
+            idempotency: (config.idempotency_ttl > 0)
+                .then(|| Arc::new(Idempotency::new(Duration::from_secs(config.idempotency_ttl), config.idempotency_max_bytes))),
+            janitor,
+            popularity: (config.popularity_max_paths > 0).then(|| Arc::new(Popularity::new(config.popularity_max_paths))),
+            job_socket_idle_timeout: Duration::from_secs(config.job_socket_idle_timeout),
+            webhooks: config
+                .webhook_secret
+                .as_deref()
+                .filter(|secret| !secret.is_empty())
+                .map(|secret| Arc::new(Webhooks::new(secret, &config.webhook_allow_cidrs))),
+            bucket,
+            maintenance,
+            per_client: (config.max_concurrent_per_client > 0)
+                .then(|| Arc::new(ClientConcurrency::new(config.max_concurrent_per_client))),
+            trust_proxy,
+            converter,
+            metrics,
+            prober,
+            size_model: Arc::default(),
+            max_input_duration,
+            long_video_policy,
+            stream_response,
+            auto_quality: config.auto_quality,
+            strict_params: config.strict_params,
+            temp,
+            max_upload_bytes: config.max_upload_bytes,
+            pacing: Pacing { throttle, egress },
+            conversions: Conversions::new(),
+            active: Arc::new(ActiveConversions::new()),
+            log_filter,
+            health: Arc::new(Health::new(config.health_deep)),
+            readiness: Arc::new(Readiness::new()),
+            build,
+            admin_token: config.admin_token.as_deref().filter(|token| !token.is_empty()).map(Arc::from),
+            stats_access: config.stats_access,
+            error_detail: config.error_detail,
+            failure_placeholder: read_gif("FAILURE_PLACEHOLDER_PATH", config.failure_placeholder_path.as_deref()).await?,
+            slow_request_threshold,
+            audit,
+            api_keys: api_keys.map(Arc::new),
+            usage,
+            compared: Arc::default(),
+            stills: Arc::default(),
+            certificates,
+            exporting_traces,
+        };
+        resume_jobs(&state, restored);
+        Ok(state)
+    }
+
+    /// What conversions go through.
+    pub fn pipeline(&self) -> &Arc<PipelineConfig> {
+        self.converter.pipeline()
+    }
+
+    /// What converts the videos, for converting one without a request.
+    pub fn converter(&self) -> &Converter {
+        &self.converter
+    }
+
+    /// Read source videos through `fetcher` instead of the upstream client,
+    /// so every conversion does, whatever `UPSTREAM_FETCH` says.
+    pub fn set_source_fetcher(&mut self, fetcher: Arc<dyn SourceFetcher>) {
+        self.converter.set_source_fetcher(fetcher);
+    }
+
+    /// Add a backend for requests to ask for by its format, taking over that
+    /// format from the configured encoder or anything added before.
+    pub fn register_backend(&mut self, backend: Arc<dyn Backend>) {
+        self.converter.register_backend(backend);
+    }
+
+    /// What the upstream is asked for to convert `path`.
+    fn source_ref(&self, path: &str, options: &ConversionOptions) -> SourceRef {
+        let url = format!("{}/tweet_video/{}", self.settings.load().video_base_url, path);
+        self.pipeline().source_ref(path, url, options)
+    }
+}
+
+/// The routes `config` asks for, with all their middleware, to be served on
+/// every listener.
+pub fn app(config: &Config, state: AppState) -> Router {
+    let exporting_traces = state.exporting_traces;
+    let metrics = state.metrics.clone();
+    let mut conversions_app = Router::new()
+        .route(maintenance::GIF_ROUTE, get(handle_tweet_video))
+        .route("/tweet_video/{path}/estimate", get(handle_estimate))
+        .route("/tweet_video/{path}/sprite", get(handle_sprite))
+        .route("/tweet_video/{path}/frame", get(handle_frame));
+    if !config.allowed_referers.is_empty() {
+        info!("Only serving GIFs to be embedded on {} site pattern(s)", config.allowed_referers.len());
+        let protection = HotlinkProtection {
+            allowed: config.allowed_referers.clone(),
+            allow_empty: config.allow_empty_referer,
+            placeholder: config.hotlink_placeholder_url.clone(),
+        };
+        conversions_app = conversions_app.route_layer(middleware::from_fn_with_state(Arc::new(protection), hotlink::protect));
+    } else if config.hotlink_placeholder_url.is_some() {
+        warn!("HOTLINK_PLACEHOLDER_URL does nothing without ALLOWED_REFERERS");
+    }
+    // Uploads aren't embedded, so there's no referer to check. The video is
+    // counted against MAX_UPLOAD_BYTES as it's read, and the rest of a form
+    // against this.
+    let form_limit = usize::try_from(config.max_upload_bytes.saturating_add(FORM_OVERHEAD_BYTES)).unwrap_or(usize::MAX);
+    conversions_app = conversions_app
+        .route("/convert", post(handle_convert).layer(DefaultBodyLimit::max(form_limit)))
+        .route("/batch", post(handle_batch))
+        .route("/jobs", post(handle_create_job));
+    // A prefetched GIF is only any use if it's kept
+    if state.cache.is_some() || state.disk_cache.is_some() {
+        conversions_app = conversions_app.route("/prefetch", post(handle_prefetch));
+    }
+    // Jobs can be asked after while new conversions are refused, since
+    // they're only what's already been taken on
+    let mut jobs_app = Router::new()
+        .route("/jobs/{id}", get(handle_job_status))
+        .route("/jobs/{id}/result", get(handle_job_result))
+        .route("/jobs/{id}/events", get(handle_job_events))
+        .route("/ws/jobs/{id}", get(handle_job_socket));
+    if let Some(keys) = state.api_keys.clone() {
+        info!("Conversions need one of {} API keys", keys.len());
+        // Only a key has usage of its own to ask about
+        jobs_app = jobs_app.route("/usage", get(handle_usage));
+        conversions_app = conversions_app.route_layer(middleware::from_fn_with_state(keys.clone(), api_keys::require_key));
+        jobs_app = jobs_app.route_layer(middleware::from_fn_with_state(keys, api_keys::require_key));
+    }
+    // Cancelling is for whoever submitted the job, or an admin, who needn't
+    // have an API key of their own
+    let cancelling = delete(handle_cancel_job).layer(middleware::from_fn_with_state(state.clone(), admin_or_key));
+    jobs_app = jobs_app.route("/jobs/{id}", cancelling);
+    conversions_app = conversions_app.route_layer(middleware::from_fn_with_state(state.maintenance.clone(), maintenance::refuse));
+    // Asking how busy we are isn't a conversion, so maintenance doesn't stop it
+    let mut queue_status = get(handle_queue);
+    if !config.queue_status_public {
+        queue_status = queue_status.layer(middleware::from_fn_with_state(state.clone(), admin_or_key));
+    }
+    let app = conversions_app
+        .merge(jobs_app)
+        .route("/queue", queue_status)
+        .route("/stats", get(handle_stats))
+        .route("/healthz", get(handle_healthz))
+        .route("/version", get(handle_version));
+    let mut admin = Router::new();
+    let mut has_admin_routes = false;
+    if config.metrics_port.is_none() {
+        admin = admin.route("/metrics", get(handle_metrics));
+        has_admin_routes = true;
+    }
+    if state.admin_token.is_some() {
+        admin = admin
+            .route("/admin/log-level", get(handle_get_log_level).put(handle_set_log_level))
+            .route("/admin/reload", post(handle_reload))
+            .route("/admin/maintenance", get(handle_get_maintenance).post(handle_set_maintenance))
+            .route("/admin/conversions", get(handle_active_conversions))
+            .route("/admin/conversions/{request_id}", delete(handle_kill_conversion))
+            .route("/admin/janitor/run", post(handle_run_janitor))
+            .route("/admin/purge", post(handle_purge))
+            .route("/admin/top", get(handle_top).delete(handle_reset_top))
+            .route("/admin/keys/{name}/usage", get(handle_key_usage))
+            .route("/admin/compare", post(handle_compare))
+            .route("/admin/compare/{id}", get(handle_compared));
+        has_admin_routes = true;
+    }
+    if has_admin_routes && !config.admin_listeners.is_empty() {
+        let allowed: Arc<[Bind]> = config.admin_listeners.as_slice().into();
+        let allowed_list = allowed.iter().map(|bind| bind.to_string()).collect::<Vec<_>>().join(", ");
+        info!("Only answering admin routes and /metrics on {}", allowed_list);
+        admin = admin.route_layer(middleware::from_fn_with_state(allowed, listener::only_on));
+    }
+    app.merge(admin)
+        .fallback(handle_not_found)
+        .layer(middleware::from_fn_with_state(config.rate_limit_legacy_headers, rate_limit::add_headers))
+        .layer(middleware::from_fn_with_state(
+            ClientFilter { settings: state.settings.clone(), trust_proxy: state.trust_proxy.clone() },
+            ip_filter::filter_clients,
+        ))
+        .layer(middleware::from_fn_with_state(metrics.clone(), metrics::track_requests))
+        .layer(middleware::from_fn_with_state(
+            AccessLog { trust_proxy: state.trust_proxy.clone(), metrics: metrics.clone(), popularity: state.popularity.clone() },
+            access_log::log_requests,
+        ))
+        .layer(TraceLayer::new_for_http().make_span_with(move |request: &Request| {
+            let request_id = request.extensions().get::<RequestId>().map(|id| id.0.as_str()).unwrap_or_default();
+            let span = info_span!(
+                "request",
+                request_id = %request_id,
+                method = %request.method(),
+                uri = %api_keys::redacted(request.uri()),
+                path = %request.uri().path(),
+                deadline_ms = Empty,
+                queue_position = Empty,
+                queue_wait_ms = Empty,
+                upstream_dns_ms = Empty,
+                upstream_connect_ms = Empty,
+                upstream_ttfb_ms = Empty,
+                upstream_download_ms = Empty,
+                upstream_bytes = Empty,
+                upstream_status = Empty,
+                ffmpeg_ms = Empty,
+                gifski_ms = Empty,
+                first_byte_ms = Empty,
+                pipe_bytes_per_sec = Empty,
+                trace_id = Empty,
+                span_id = Empty,
+                parent_span_id = Empty,
+            );
+            if exporting_traces {
+                telemetry::set_parent(&span, request.headers());
+            }
+            if let Some(trace) = telemetry::trace_of(&span, request.extensions().get()) {
+                span.record("trace_id", trace.trace_id.as_str());
+                span.record("span_id", trace.span_id.as_str());
+                if let Some(parent) = &trace.parent_span_id {
+                    span.record("parent_span_id", parent.as_str());
+                }
+            }
+            span
+        }))
+        .layer(middleware::from_fn(telemetry::assign_request_id))
+        .layer(middleware::from_fn(listener::connection_info))
+        // Load balancers ask these every few seconds, which isn't worth a log
+        // line (or a metric) each time, so they're added after all the layers
+        .route("/livez", get(handle_livez))
+        .route("/readyz", get(handle_readyz))
+        .with_state(state)
+}
+
+/// The converter `config` sets up, failing with what's wrong with the
+/// binaries the way startup does.
+fn new_converter(config: CoreConfig) -> Result<Converter> {
+    Converter::new(config).map_err(|e| match e {
+        StartupError::Problems(problems) => anyhow!("{}; pass --skip-binary-check to start anyway", problems),
+        e => e.into(),
+    })
+}
+
+/// Run the server `config` describes until it's told to stop, or with
+/// `--version` or `check-config` answer that instead.
+pub async fn run(config: Config) -> Result<()> {
+    if config.version {
+        let encoder = config.encoder.unwrap_or(Encoder::Subprocess);
+        println!("{}", BuildInfo::detect(encoder, config.decoder, &config.binaries()?));
+        return Ok(());
+    }
+    if let Some(config::Command::CheckConfig { .. }) = config.command {
+        return config::check(&config);
+    }
+    // Every setting parsed, but they have to make sense together too
+    config.validate()?;
+
+    let (log_filter, tracer_provider) = telemetry::init(config.log_format, config.log_filter.as_deref())?;
+    if let Some(file) = &config.file {
+        info!("Read settings from {}", file.path.display());
+        if !file.unknown.is_empty() {
+            warn!("Ignoring unknown keys in {}: {}", file.path.display(), file.unknown.join(", "));
+        }
+    }
+
+    info!("Starting FastGIF server");
+    info!("Settings: {}", config.effective());
+    info!(
+        "Running on {} worker threads, with up to {} more for blocking work",
+        tokio::runtime::Handle::current().metrics().num_workers(),
+        config.max_blocking_threads
+    );
+
+    // Internal errors can be reported to Sentry; the guard flushes them on the way out
+    let _sentry = match config.sentry_dsn.as_deref().filter(|dsn| !dsn.is_empty()) {
+        Some(dsn) => {
+            let guard = error_report::init(dsn)?;
+            info!("Reporting conversion errors to Sentry");
+            Some(guard)
+        }
+        None => None,
+    };
+
+    let state = AppState::new(&config, log_filter, tracer_provider.is_some()).await?;
+    let conversions = state.conversions.clone();
+    let jobs = state.jobs.clone();
+    tokio::spawn(reload::on_sighup(state.reloader.clone()));
+    tokio::spawn(systemd::watchdog());
+    let warmed_up = tokio::spawn(health::warm_up(state.health.clone(), state.readiness.clone(), state.pipeline().clone()));
+    if state.metrics.has_statsd() {
+        tokio::spawn(flush_statsd(state.clone()));
+    }
+    if config.janitor_interval > 0 {
+        // Only GIFs made from an upstream's videos are revalidated
+        let revalidator = (config.revalidate_after > 0 && (state.cache.is_some() || state.disk_cache.is_some())).then(|| {
+            info!("Revalidating cached GIFs' videos after {}s", config.revalidate_after);
+            Arc::new(Revalidator::new(
+                state.cache.clone(),
+                state.disk_cache.clone(),
+                Duration::from_secs(config.revalidate_after),
+                config.revalidate_max_per_sec,
+                state.pipeline().clone(),
+                state.settings.clone(),
+                state.metrics.clone(),
+            ))
+        });
+        tokio::spawn(janitor::run(state.janitor.clone(), Duration::from_secs(config.janitor_interval), revalidator));
+    }
+    if let Some(disk) = state.temp.disk() {
+        tokio::spawn(janitor::on_disk_full(state.janitor.clone(), disk.clone()));
+    }
+    if let Some(interval) = config.stats_interval.filter(|secs| *secs > 0) {
+        info!("Logging a stats summary every {}s", interval);
+        tokio::spawn(log_summary(state.clone(), Duration::from_secs(interval)));
+    }
+
+    // Drop clients that won't read their response, so they can't pin a GIF (or,
+    // when streaming, a whole conversion) for as long as they like
+    let slow_client_guard = config
+        .slow_client_timeout
+        .filter(|secs| *secs > 0.0)
+        .map(|secs| SlowClientGuard {
+            min_bytes: config.slow_client_min_bytes,
+            timeout: Duration::from_secs_f64(secs),
+        });
+    if let Some(guard) = &slow_client_guard {
+        info!(
+            "Dropping clients that take less than {} bytes in {:?}",
+            guard.min_bytes, guard.timeout
+        );
+    }
+
+    // How long in-flight conversions get to finish once we're asked to shut down
+    let shutdown_grace = Duration::from_secs_f64(config.shutdown_grace.max(0.0));
+
+    // Metrics get a listener of their own with METRICS_PORT, so they needn't be public
+    let metrics_port = config.metrics_port;
+
+    // Everywhere we serve the app: each BIND, or when systemd started us by
+    // socket activation each socket it passed, over TLS if there are
+    // certificates, and then plain HTTP on HTTP_PORT
+    let activated = systemd::listen_fds()?;
+    let certificates = state.certificates.clone();
+    let mut binds: Vec<(Bind, Option<Arc<tls::Certificates>>, Option<Bound>)> = Vec::new();
+    if activated.is_empty() {
+        binds.extend(config.bind.iter().map(|bind| (bind.clone(), certificates.clone(), None)));
+    } else {
+        for (activated, name) in activated {
+            let bind = activated.bind()?;
+            info!("Using the socket systemd passed for {} ({}) instead of BIND", bind, name);
+            let listener = match activated {
+                systemd::Activated::Tcp(listener) => Bound::Tcp(tokio::net::TcpListener::from_std(listener)?),
+                #[cfg(unix)]
+                systemd::Activated::Unix(listener) => Bound::Unix(tokio::net::UnixListener::from_std(listener)?),
+            };
+            binds.push((bind, certificates.clone(), Some(listener)));
+        }
+    }
+    if let Some(http_port) = config.http_port {
+        binds.push((Bind::Addr(SocketAddr::new(listener::tcp_ip(&config.bind), http_port)), None, None));
+    }
+    // Admin routes and metrics can be kept off the public listeners
+    if let Some(unknown) = config.admin_listeners.iter().find(|listener| binds.iter().all(|(bind, _, _)| bind != *listener)) {
+        return Err(anyhow!("ADMIN_LISTENERS has {}, which isn't one of the BIND listeners", unknown));
+    }
+
+    let app = app(&config, state.clone());
+    if let Some(metrics_port) = metrics_port {
+        let metrics_app = Router::new()
+            .route("/metrics", get(handle_metrics))
+            .layer(middleware::from_fn_with_state(state.metrics.clone(), metrics::track_requests))
+            .with_state(state);
+        // Only the main listeners can be unix sockets; metrics still need a port
+        let metrics_addr = SocketAddr::new(listener::tcp_ip(&config.bind), metrics_port);
+        let metrics_listener = tokio::net::TcpListener::bind(metrics_addr)
+            .await
+            .map_err(|e| anyhow!("Couldn't listen on {}: {}", metrics_addr, e))?;
+        info!("Serving metrics on http://{}/metrics", metrics_listener.local_addr()?);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(metrics_listener, metrics_app).await {
+                error!("Metrics listener failed: {}", e);
+            }
+        });
+    }
+
+    // On SIGTERM/SIGINT we stop accepting connections straight away, then give
+    // in-flight conversions the grace period before cancelling them
+    let (drained_tx, drained_rx) = tokio::sync::oneshot::channel();
+    let stopping = CancellationToken::new();
+    let shutdown = {
+        let stopping = stopping.clone();
+        async move {
+            let signal = shutdown::signal().await;
+            info!("Received {}, shutting down", signal);
+            systemd::notify("STOPPING=1");
+            stopping.cancel();
+            tokio::spawn(async move {
+                conversions.drain(shutdown_grace).await;
+                let _ = drained_tx.send(());
+            });
+        }
+    };
+    tokio::spawn(shutdown);
+    // Bind everything before serving anything, so one that can't be had stops
+    // us before we've answered a request. Unix socket files are removed once
+    // we're done serving, however that ends.
+    let mut servers = Vec::new();
+    let mut listening = Vec::new();
+    #[cfg(unix)]
+    let mut socket_files = Vec::new();
+    let mut bound = Vec::new();
+    for (bind, certificates, activated) in binds {
+        let scheme = if certificates.is_some() { "https" } else { "http" };
+        // systemd's sockets are already listening, and are its to clean up
+        if let Some(listener) = activated {
+            match &bind {
+                Bind::Unix(_) => info!("Listening on {} ({})", bind, scheme),
+                _ => info!("Listening on {}://{}", scheme, bind),
+            }
+            listening.push(bind.to_string());
+            bound.push((listener, bind, certificates));
+            continue;
+        }
+        match &bind {
+            Bind::Unix(path) => {
+                #[cfg(unix)]
+                {
+                    let (listener, socket_file) = listener::bind_unix(path, config.socket_mode, config.socket_owner)?;
+                    socket_files.push(socket_file);
+                    info!("Listening on unix:{} ({})", path.display(), scheme);
+                    listening.push(format!("unix:{}", path.display()));
+                    bound.push((Bound::Unix(listener), bind, certificates));
+                }
+                #[cfg(not(unix))]
+                return Err(anyhow!("BIND=unix:{} needs a unix system", path.display()));
+            }
+            tcp => {
+                let addr = tcp.tcp(config.port).expect("not a unix socket");
+                let listener = tokio::net::TcpListener::bind(addr)
+                    .await
+                    .map_err(|e| anyhow!("Couldn't listen on {}: {}", addr, e))?;
+                let local_addr = listener.local_addr()?;
+                info!("Listening on {}://{}", scheme, local_addr);
+                listening.push(local_addr.to_string());
+                bound.push((Bound::Tcp(listener), bind, certificates));
+            }
+        }
+    }
+    for (listener, bind, certificates) in bound {
+        // Tagged with where they came in, for ADMIN_LISTENERS
+        let app = app.clone().layer(Extension(listener::ListenerTag(Arc::new(bind))));
+        let stopped = stopping.clone().cancelled_owned();
+        servers.push(match listener {
+            Bound::Tcp(listener) => tokio::spawn(serve(listener, certificates, config.h2c, slow_client_guard, app, stopped)),
+            #[cfg(unix)]
+            Bound::Unix(listener) => tokio::spawn(serve(listener, certificates, config.h2c, slow_client_guard, app, stopped)),
+        });
+    }
+    // Under systemd, we're started once we're listening and warmed up
+    tokio::spawn(async move {
+        if warmed_up.await.is_ok() {
+            systemd::notify(&format!("READY=1\nSTATUS=Listening on {}", listening.join(", ")));
+        }
+    });
+    for server in servers {
+        server.await??;
+    }
+    #[cfg(unix)]
+    drop(socket_files);
+
+    // Every connection is closed, but make sure the cancelled conversions have
+    // finished reaping their children before we exit
+    let _ = drained_rx.await;
+    jobs.flush().await;
+    info!("Shutdown complete");
+    if let Some(provider) = tracer_provider {
+        let _ = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+    }
+
+    Ok(())
+}
+
+/// A listener that's been bound, not yet served on.
+enum Bound {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+// A GIF a setting names, read once at startup, so a bad one stops the server
+// rather than failing requests
+async fn read_gif(setting: &str, path: Option<&std::path::Path>) -> Result<Option<Bytes>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let gif = tokio::fs::read(path)
+        .await
+        .map_err(|e| anyhow!("Couldn't read {} {}: {}", setting, path.display(), e))?;
+    match fastgif_core::gif::frames(&gif) {
+        Ok(0) => Err(anyhow!("{} {} has no frames", setting, path.display())),
+        Ok(_) => Ok(Some(Bytes::from(gif))),
+        Err(e) => Err(anyhow!("{} {} isn't a GIF: {}", setting, path.display(), e)),
+    }
+}
+
+/// Serve `app` on `listener` until `shutdown`, over TLS when there are
+/// certificates, which offers HTTP/2 as well. Plain HTTP is only HTTP/1.1,
+/// unless `h2c`.
+async fn serve<L>(
+    listener: L,
+    certificates: Option<Arc<tls::Certificates>>,
+    h2c: bool,
+    slow_client_guard: Option<SlowClientGuard>,
+    app: Router,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()>
+where
+    L: axum::serve::Listener,
+    L::Addr: Into<Peer> + Into<Connection>,
+{
+    match certificates {
+        Some(certificates) => {
+            let listener = tls::TlsListener::new(listener, certificates);
+            server::serve(GuardedListener::new(listener, slow_client_guard), true, app, shutdown).await
+        }
+        None => server::serve(GuardedListener::new(listener, slow_client_guard), h2c, app, shutdown).await,
+    }
+}
+
+// Define the 404 handler function
+async fn handle_not_found(uri: Uri) -> impl IntoResponse {
+    (StatusCode::NOT_FOUND, format!("404 Not Found: {}", uri))
+}
+
+/// What `GET /queue` reports.
+#[derive(Serialize)]
+struct QueueResponse {
+    /// Conversions still running, including streamed ones whose handler is done
+    in_flight: usize,
+    #[serde(flatten)]
+    queue: admission::QueueStatus,
+}
+
+/// What `/stats` tells anyone, with `STATS_ACCESS=summary`, for dashboards
+/// and status pages.
+#[derive(Serialize)]
+struct StatsSummary {
+    uptime_secs: u64,
+    requests: u64,
+    conversions: ConversionCounts,
+    /// Conversions still running, including streamed ones whose handler is done
+    in_flight: usize,
+    queued: usize,
+    /// Of the requests that looked in the GIF caches, how many found it
+    /// there, until any have
+    cache_hit_ratio: Option<f64>,
+    /// Successful conversions' times over the last five minutes
+    latency: RecentLatency,
+    bytes_served: u64,
+}
+
+#[derive(Serialize)]
+struct ConversionCounts {
+    succeeded: u64,
+    failed: u64,
+    /// Only the classes that actually failed
+    failed_by_class: std::collections::BTreeMap<&'static str, u64>,
+}
+
+#[derive(Serialize)]
+struct RecentLatency {
+    window_secs: u64,
+    conversions: u64,
+    p50_ms: Option<u64>,
+    p95_ms: Option<u64>,
+    p99_ms: Option<u64>,
+}
+
+/// What `/stats` reports.
+#[derive(Serialize)]
+struct StatsResponse {
+    #[serde(flatten)]
+    summary: StatsSummary,
+    admission: admission::Stats,
+    /// Only there when load shedding on system pressure is configured
+    pressure: Option<pressure::Snapshot>,
+    upstream: fastgif_core::fetch::FetchStats,
+    /// Average cost of each kind of child over its recent conversions (Linux only)
+    child_usage: std::collections::BTreeMap<&'static str, fastgif_core::process::AverageUsage>,
+    /// Only there when `EGRESS_LIMIT` is set
+    egress: Option<throttle::EgressStats>,
+    /// Only there when `CACHE_MAX_BYTES` is set
+    gif_cache: Option<cache::Stats>,
+    /// Only there when `CACHE_DIR` is set
+    gif_disk_cache: Option<cache::Stats>,
+    maintenance: maintenance::Status,
+    /// Counted since the server started
+    totals: metrics::Totals,
+    /// What estimates are made with, for each format and quality conversions have been made at
+    size_model: Vec<estimate::FitStats>,
+    build: BuildInfo,
+    capabilities: fastgif_core::capabilities::Capabilities,
+    /// What ffmpeg decodes with: the hardware decoder `HWACCEL` found, or
+    /// `software`
+    decode: &'static str,
+}
+
+fn stats_summary(state: &AppState, totals: &metrics::Totals, admission: &admission::Stats) -> StatsSummary {
+    let failed_by_class: std::collections::BTreeMap<_, _> =
+        totals.errors.iter().filter(|(_, count)| **count > 0).map(|(class, count)| (*class, *count)).collect();
+    let lookups = totals.gif_cache_hits + totals.gif_cache_misses;
+    let (durations, conversions) = state.metrics.recent_durations();
+    let millis = |q| summary::quantile(&durations, conversions, q).map(|took| took.as_millis() as u64);
+    StatsSummary {
+        uptime_secs: state.metrics.uptime().as_secs(),
+        requests: totals.requests,
+        conversions: ConversionCounts {
+            succeeded: totals.conversions,
+            failed: failed_by_class.values().sum(),
+            failed_by_class,
+        },
+        in_flight: state.conversions.in_flight(),
+        queued: admission.queued,
+        cache_hit_ratio: (lookups > 0).then(|| totals.gif_cache_hits as f64 / lookups as f64),
+        latency: RecentLatency {
+            window_secs: metrics::RECENT_WINDOW.as_secs(),
+            conversions,
+            p50_ms: millis(0.5),
+            p95_ms: millis(0.95),
+            p99_ms: millis(0.99),
+        },
+        bytes_served: totals.bytes_served,
+    }
+}
+
+async fn handle_stats(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let detailed = match state.stats_access {
+        StatsAccess::Public => true,
+        StatsAccess::Summary => is_admin(&state, &headers),
+        StatsAccess::Admin if is_admin(&state, &headers) => true,
+        StatsAccess::Admin => return unauthorized_response(),
+    };
+    let totals = state.metrics.totals(&state.pipeline().fetcher.stats().dns);
+    let admission = state.admission.stats();
+    let summary = stats_summary(&state, &totals, &admission);
+    if !detailed {
+        return Json(summary).into_response();
+    }
+    Json(StatsResponse {
+        summary,
+        admission,
+        pressure: state.admission.pressure().map(AdmissionState::snapshot),
+        upstream: state.pipeline().fetcher.stats(),
+        child_usage: state.pipeline().child_usage.averages(),
+        egress: state.pacing.egress.as_deref().map(Egress::stats),
+        gif_cache: state.cache.as_deref().map(GifCache::stats),
+        gif_disk_cache: state.disk_cache.as_deref().map(DiskCache::stats),
+        maintenance: state.maintenance.status(),
+        totals,
+        size_model: state.size_model.stats(),
+        build: (*state.build).clone(),
+        capabilities: (**state.converter.capabilities()).clone(),
+        decode: state.pipeline().hwaccel.map_or(hwaccel::SOFTWARE, Hwaccel::as_str),
+    })
+    .into_response()
+}
+
+async fn handle_queue(State(state): State<AppState>) -> Json<QueueResponse> {
+    Json(QueueResponse { in_flight: state.conversions.in_flight(), queue: state.admission.queue_status() })
+}
+
+async fn handle_healthz(State(state): State<AppState>) -> Response {
+    let report = state.health.check(state.pipeline()).await;
+    let status = if report.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(report)).into_response()
+}
+
+async fn handle_version(State(state): State<AppState>) -> Json<BuildInfo> {
+    Json((*state.build).clone())
+}
+
+// Answering at all is the point
+async fn handle_livez() -> &'static str {
+    "ok"
+}
+
+async fn handle_readyz(State(state): State<AppState>) -> Response {
+    match state.readiness.check(&state.admission, &state.conversions, &state.maintenance, state.temp.disk().map(Arc::as_ref)) {
+        Ok(()) => "ready".into_response(),
+        Err(reason) => (StatusCode::SERVICE_UNAVAILABLE, format!("not ready: {}", reason)).into_response(),
+    }
+}
+
+async fn handle_metrics(State(state): State<AppState>) -> Response {
+    let body = state
+        .metrics
+        .render(
+            state.conversions.in_flight(),
+            &state.admission.stats().queued_by_priority,
+            &state.pipeline().fetcher.stats().dns,
+        );
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response()
+}
+
+// Keep StatsD's gauges current, which Prometheus reads when it scrapes instead
+async fn flush_statsd(state: AppState) {
+    let mut interval = tokio::time::interval(STATSD_FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        state.metrics.flush_statsd(
+            state.conversions.in_flight(),
+            &state.admission.stats().queued_by_priority,
+            &state.pipeline().fetcher.stats().dns,
+        );
+    }
+}
+
+// Log what happened every `interval`, from the same counters as `/stats`,
+// unless nothing did
+async fn log_summary(state: AppState, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.tick().await;
+    let mut last = state.metrics.totals(&state.pipeline().fetcher.stats().dns);
+    loop {
+        ticks.tick().await;
+        let dns = state.pipeline().fetcher.stats().dns;
+        let totals = state.metrics.totals(&dns);
+        let summary = summary::Summary::between(&last, &totals);
+        if !summary.is_idle() {
+            summary.log(
+                state.conversions.in_flight(),
+                state.admission.stats().queued,
+                state.prober.cached(),
+                dns.cached,
+            );
+        }
+        last = totals;
+    }
+}
+
+// Whether the request carries the admin token, compared in constant time
+fn is_admin(state: &AppState, headers: &HeaderMap) -> bool {
+    let Some(token) = state.admin_token.as_deref() else {
+        return false;
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or("");
+    api_keys::same(given, token)
+}
+
+// Let requests with the admin token through as they are, and otherwise ask
+// for an API key like any other job route, if there are keys to ask for
+async fn admin_or_key(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    match state.api_keys.clone() {
+        Some(keys) if !is_admin(&state, request.headers()) => api_keys::require_key(State(keys), request, next).await,
+        _ => next.run(request).await,
+    }
+}
+
+fn unauthorized_response() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        "Missing or wrong admin token",
+    )
+        .into_response()
+}
+
+async fn handle_get_log_level(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    state.log_filter.current().into_response()
+}
+
+// Takes a filter in `RUST_LOG` syntax as the body
+async fn handle_set_log_level(State(state): State<AppState>, headers: HeaderMap, body: String) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    match state.log_filter.set(body.trim()) {
+        Ok(()) => {
+            let current = state.log_filter.current();
+            warn!("Log filter changed to {:?}", current);
+            current.into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, format!("Invalid log filter: {}", e)).into_response(),
+    }
+}
+
+// Re-reads the configuration, like SIGHUP does, and says what changed
+async fn handle_reload(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    match state.reloader.reload().await {
+        Ok(reloaded) => Json(reloaded).into_response(),
+        Err(e) => {
+            warn!("Couldn't reload the configuration, keeping the current one: {:#}", e);
+            (StatusCode::BAD_REQUEST, format!("Couldn't reload the configuration: {:#}", e)).into_response()
+        }
+    }
+}
+
+async fn handle_get_maintenance(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    Json(state.maintenance.status()).into_response()
+}
+
+// Takes `on` or `off` as the body
+async fn handle_set_maintenance(State(state): State<AppState>, headers: HeaderMap, body: String) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    let enabled = match body.trim() {
+        "on" | "true" => true,
+        "off" | "false" => false,
+        other => return (StatusCode::BAD_REQUEST, format!("Expected on or off, not {:?}", other)).into_response(),
+    };
+    Json(state.maintenance.set(enabled)).into_response()
+}
+
+// Every conversion that has been let in and is still going, oldest first
+async fn handle_active_conversions(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    Json(state.active.list(&state.admission)).into_response()
+}
+
+/// What `DELETE /admin/conversions/{request_id}` cancelled.
+#[derive(Serialize)]
+struct Killed {
+    request_id: String,
+    /// Each of its conversions, as `GET /admin/conversions` lists them
+    cancelled: serde_json::Value,
+}
+
+// Sweeps now rather than waiting for the next JANITOR_INTERVAL, e.g. when
+// the disk's filling up, and says what was removed
+async fn handle_run_janitor(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    Json(state.janitor.sweep().await).into_response()
+}
+
+/// What `GET /admin/top` is asked for.
+#[derive(Deserialize)]
+struct TopQuery {
+    window: Option<String>,
+    n: Option<usize>,
+}
+
+/// What `GET /admin/top` answers with.
+#[derive(Serialize)]
+struct Top {
+    window: String,
+    /// How many paths are being tracked, of `POPULARITY_MAX_PATHS`
+    tracked: usize,
+    max_paths: usize,
+    paths: Vec<Popular>,
+}
+
+// The paths asked for most, for capacity planning and sizing the caches
+async fn handle_top(State(state): State<AppState>, Query(query): Query<TopQuery>, headers: HeaderMap) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    let Some(popularity) = &state.popularity else {
+        return (StatusCode::NOT_FOUND, "Paths aren't tracked with POPULARITY_MAX_PATHS at 0").into_response();
+    };
+    let window = query.window.unwrap_or_else(|| "1h".to_string());
+    let parsed = match Window::parse(&window) {
+        Ok(parsed) => parsed,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Failed to list the top paths: {}", e)).into_response(),
+    };
+    let (tracked, max_paths) = popularity.tracked();
+    let paths = popularity.top(parsed, query.n.unwrap_or(50));
+    Json(Top { window, tracked, max_paths, paths }).into_response()
+}
+
+// Starts counting again, e.g. after a launch that skewed everything
+async fn handle_reset_top(State(state): State<AppState>, ConnectInfo(peer): ConnectInfo<Peer>, headers: HeaderMap) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    let Some(popularity) = &state.popularity else {
+        return (StatusCode::NOT_FOUND, "Paths aren't tracked with POPULARITY_MAX_PATHS at 0").into_response();
+    };
+    let reset = popularity.reset();
+    let admin = client_ip::resolve(&headers, peer.ip(), &state.trust_proxy);
+    info!(admin = %admin, "Reset the counts for {} paths", reset);
+    Json(serde_json::json!({ "reset": reset })).into_response()
+}
+
+/// How `POST /admin/purge` purges.
+#[derive(Deserialize)]
+struct PurgeQuery {
+    mode: Option<Purge>,
+}
+
+/// What `POST /admin/purge` answers with.
+#[derive(Serialize)]
+struct Purged {
+    mode: Purge,
+    /// Which caches had the GIF: `memory`, `disk`, both or neither
+    purged: Vec<&'static str>,
+}
+
+// Drops a GIF from the caches, e.g. one that was made wrong. A soft purge
+// keeps it to be sent while it's made again, so that one everyone's asking
+// for isn't converted for all of them at once
+async fn handle_purge(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    Query(query): Query<PurgeQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    let target = match serde_json::from_slice::<batch::Item>(&body) {
+        Ok(item) => item.resolve(&state.settings.load().video_base_url),
+        Err(e) => Err(format!("invalid_purge ({})", e)),
+    };
+    let target = match target {
+        Ok(target) => target,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Failed to purge: {}", e)).into_response(),
+    };
+    let mode = query.mode.unwrap_or(Purge::Hard);
+    let mut purged = Vec::new();
+    if let Some(key) = variant_key(&state, &target.path, &target.query).await.map(|variant| variant.to_string()) {
+        if state.cache.as_ref().is_some_and(|cache| cache.purge(&key, mode)) {
+            purged.push("memory");
+        }
+        if state.disk_cache.as_ref().is_some_and(|cache| cache.purge(&key, mode)) {
+            purged.push("disk");
+        }
+    }
+    let admin = client_ip::resolve(&headers, peer.ip(), &state.trust_proxy);
+    info!(admin = %admin, "Purged {} ({:?}) from {:?}", target.url(), mode, purged);
+    Json(Purged { mode, purged }).into_response()
+}
+
+// What the API key `name` has used, and may, e.g. a partner's close to
+// its quota
+async fn handle_key_usage(State(state): State<AppState>, Path(name): Path<String>, headers: HeaderMap) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    let known = state.api_keys.as_ref().is_some_and(|keys| keys.named(&name));
+    if !known && !state.usage.has_used(&name) {
+        return (StatusCode::NOT_FOUND, "No API key has that name").into_response();
+    }
+    Json(state.usage.report(&name)).into_response()
+}
+
+// Converts one video with each of several encoders and settings in turn,
+// one at a time and taking turns like any other conversion, for tuning them,
+// and says what each came to and where to see it
+async fn handle_compare(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    Extension(note): Extension<AccessNote>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    let started = Instant::now();
+    let client = client_ip::resolve(&headers, peer.ip(), &state.trust_proxy);
+    let prepared = serde_json::from_slice::<compare::Comparison>(&body)
+        .map_err(|e| e.to_string())
+        .and_then(|comparison| {
+            let target = comparison.source.resolve(&state.settings.load().video_base_url)?;
+            Ok((comparison.prepare(state.pipeline())?, target))
+        });
+    let (prepared, target) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            note.outcome("invalid_comparison");
+            return (StatusCode::BAD_REQUEST, format!("Failed to compare: invalid_comparison ({})", e)).into_response();
+        }
+    };
+    let mut options = ConversionOptions {
+        optimize: target.query.optimize(),
+        repeat: target.query.repeat,
+        max_width: target.query.width,
+        fps: target.query.fps,
+        background: target.query.bg,
+        ..Default::default()
+    };
+    let source = state.source_ref(&target.path, &options);
+    // Too long a video is cut short, so every combo converts the same thing
+    if let Some(max_duration) = state.max_input_duration {
+        if let Ok(probed) = state.prober.duration(&state.pipeline().source_fetcher.probe_input(&source)).await {
+            options.alpha = probed.alpha;
+            if probed.duration.is_some_and(|duration| duration > max_duration) {
+                options.trim = Some(max_duration);
+            }
+        }
+    }
+    warn!(admin = %client, "Comparing {} combos on {} on an admin's say-so", prepared.len(), target.name);
+
+    let mut results = Vec::new();
+    for compare::Prepared { pipeline, backend, mut report } in prepared {
+        let left = compare::BUDGET.saturating_sub(started.elapsed());
+        if !left.is_zero() {
+            let options = ConversionOptions { backend, timings: StageTimes::default(), stderr_tail: StderrTail::default(), ..options.clone() };
+            compare_one(&state, &source, pipeline, options, left, &mut report).await;
+        }
+        results.push(report);
+    }
+    let took = started.elapsed();
+
+    info!("Compared {} combos on {} in {:?}", results.len(), target.name, took);
+    if let Some(audit) = &state.audit {
+        audit.record(audit::Record {
+            request_id: request_id.0.clone(),
+            client_ip: client,
+            api_key: None,
+            client_cert: None,
+            path: target.name.clone(),
+            url: source.url.clone(),
+            params: audit::Params {
+                optimize: options.optimize,
+                repeat: options.repeat,
+                trim_ms: options.trim.map(|trim| trim.as_millis() as u64),
+                tier: None,
+            },
+            outcome: results.iter().map(|result| result.outcome).find(|outcome| *outcome != "ok").unwrap_or("ok"),
+            duration_ms: took.as_millis() as u64,
+            input_bytes: None,
+            output_bytes: Some(results.iter().filter_map(|result| result.output_bytes).sum()),
+            cancelled_by_admin: None,
+            compared: Some(results.iter().map(compare::ComboReport::describe).collect()),
+        });
+    }
+    let report = compare::Report {
+        path: target.name,
+        budget_ms: compare::BUDGET.as_millis() as u64,
+        took_ms: took.as_millis() as u64,
+        results,
+    };
+    ([(header::CACHE_CONTROL, "no-store")], Json(report)).into_response()
+}
+
+// Convert `source` for a comparison, within `left` of its budget, and fill
+// in `report` with how it went
+async fn compare_one(
+    state: &AppState,
+    source: &SourceRef,
+    pipeline: PipelineConfig,
+    options: ConversionOptions,
+    left: Duration,
+    report: &mut compare::ComboReport,
+) {
+    let started = Instant::now();
+    let permit = match tokio::time::timeout(left, state.admission.acquire(Priority::Low, None, None)).await {
+        Ok(Ok(permit)) => permit,
+        Ok(Err(rejection)) => {
+            warn!("Not comparing {}: {}", report.describe(), rejection);
+            report.outcome = "overloaded";
+            return;
+        }
+        // Still skipped
+        Err(_) => return,
+    };
+    let format = pipeline.backend(&options).format();
+    let timings = options.timings.clone();
+    let cancel = state.conversions.token();
+    let conversion = state.conversions.spawn(cancel, {
+        let source = source.clone();
+        move |cancel| async move {
+            let _permit = permit;
+            match process_tweet_video(&source, &pipeline, &options, None, cancel).await {
+                Ok(output) => output.into_bytes().await,
+                Err(e) => Err(e),
+            }
+        }
+    });
+    // Whatever's still going when the budget's over, or the admin gives up
+    // waiting, is stopped
+    let _cancel_on_drop = conversion.cancel_on_drop();
+    let result = match tokio::time::timeout(left.saturating_sub(started.elapsed()), conversion).await {
+        Ok(result) => result.unwrap_or_else(|e| Err(ConversionError::Internal(format!("Conversion task failed: {}", e)))),
+        Err(_) => {
+            report.outcome = "over_budget";
+            return;
+        }
+    };
+    let stages = timings.snapshot();
+    report.wall_ms = Some(started.elapsed().as_millis() as u64);
+    report.cpu_ms = Some(stages.cpu.as_millis() as u64);
+    report.max_rss_bytes = Some(stages.max_rss_bytes);
+    match result {
+        Ok(body) => {
+            report.outcome = "ok";
+            report.output_bytes = Some(body.len() as u64);
+            if let Some((id, expires_at)) = state.compared.keep(body, format) {
+                report.result_url = Some(format!("/admin/compare/{}", id));
+                report.expires_at = Some(expires_at);
+            }
+        }
+        Err(e) => {
+            report.outcome = ErrorClass::of(&e).as_str();
+            report.error = Some(e.to_string());
+        }
+    }
+}
+
+// One of the things a comparison made
+async fn handle_compared(State(state): State<AppState>, Path(id): Path<String>, headers: HeaderMap) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    match state.compared.get(&id) {
+        Some((body, format)) => ([(header::CONTENT_TYPE, format.content_type()), (header::CACHE_CONTROL, "no-store")], body).into_response(),
+        None => (StatusCode::NOT_FOUND, "No comparison made that, or it's expired").into_response(),
+    }
+}
+
+// What the calling API key has used, and may
+async fn handle_usage(State(state): State<AppState>, Extension(Caller(name)): Extension<Caller>) -> Response {
+    ([(header::CACHE_CONTROL, "no-store")], Json(state.usage.report(&name))).into_response()
+}
+
+// Cancels the conversions for a request ID, e.g. one wedged on a pathological
+// video, and says what they'd got to
+async fn handle_kill_conversion(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    Path(request_id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if !is_admin(&state, &headers) {
+        return unauthorized_response();
+    }
+    let admin = client_ip::resolve(&headers, peer.ip(), &state.trust_proxy);
+    match state.active.kill(&request_id, admin, &state.admission) {
+        Some(killed) => {
+            warn!(admin = %admin, "Cancelling the conversion for request {} on an admin's say-so", request_id);
+            Json(Killed { request_id, cancelled: killed }).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "No conversion is running for that request ID").into_response(),
+    }
+}
+
+// Sent when we can't take on another conversion right now
+fn overloaded_response(retry_after: Duration) -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, retry_after.as_secs().max(1).to_string())],
+        "Server is busy, please try again later",
+    )
+        .into_response()
+}
+
+// Everything that went into a conversion that took `took`, to find out why
+fn log_slow_request(
+    path: &str,
+    pipeline: &PipelineConfig,
+    options: &ConversionOptions,
+    took: Duration,
+    queue_wait: Duration,
+    result: &Result<GifOutput, ConversionError>,
+) {
+    let millis = |duration: Duration| duration.as_millis() as u64;
+    let settings = options.gif_settings(pipeline);
+    warn!(
+        outcome = "slow",
+        took_ms = millis(took),
+        stages = %options.timings.snapshot().server_timing(queue_wait),
+        source_duration_ms = options.duration.map(millis),
+        start_ms = options.start.map(millis),
+        trim_ms = options.trim.map(millis),
+        encoder = pipeline.encoder.header_value(),
+        tier = options.tier.map(|tier| tier.header_value()),
+        quality = settings.quality,
+        fps = settings.fps,
+        max_width = settings.max_width,
+        fast = settings.fast,
+        optimize = options.optimize,
+        output_bytes = result.as_ref().ok().map(GifOutput::len),
+        failed = result.is_err(),
+        "Slow request: {} took {:?}",
+        path,
+        took
+    );
+}
+
+/// The body of a failed conversion's response.
+#[derive(Serialize)]
+struct ErrorBody {
+    /// What went wrong, e.g. `upstream_not_found`
+    error: &'static str,
+    message: String,
+    /// The last lines ffmpeg and gifski wrote to stderr, with `ERROR_DETAIL`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<BTreeMap<&'static str, Vec<String>>>,
+}
+
+/// A failed conversion, as the client is told about it. Every conversion
+/// error becomes a response here and nowhere else.
+struct ConversionFailure {
+    class: ErrorClass,
+    error: ConversionError,
+    stderr: Option<BTreeMap<&'static str, Vec<String>>>,
+    /// `FAILURE_PLACEHOLDER_PATH`, unless the request is `?strict`
+    placeholder: Option<Bytes>,
+}
+
+impl IntoResponse for ConversionFailure {
+    fn into_response(self) -> Response {
+        let class = self.class;
+        // For embeds, which would otherwise show a broken image. It's not to be
+        // cached, since the video may turn up or a retry work.
+        if let Some(gif) = self.placeholder.filter(|_| class.has_placeholder()) {
+            return (
+                StatusCode::OK,
+                [
+                    (header::CONTENT_TYPE, "image/gif"),
+                    (header::CACHE_CONTROL, "no-store"),
+                    (header::HeaderName::from_static("x-fastgif-error"), class.as_str()),
+                ],
+                gif,
+            )
+                .into_response();
+        }
+        if class == ErrorClass::Shutdown {
+            let body = ErrorBody {
+                error: class.as_str(),
+                message: "Server is shutting down, please try again".to_string(),
+                stderr: self.stderr,
+            };
+            return (class.status(), [(header::RETRY_AFTER, "5")], Json(body)).into_response();
+        }
+        if class == ErrorClass::CancelledByAdmin {
+            let body = ErrorBody { error: class.as_str(), message: "The conversion was cancelled by an admin".to_string(), stderr: self.stderr };
+            return (class.status(), [(header::CACHE_CONTROL, "no-store")], Json(body)).into_response();
+        }
+        // The video's most likely still being posted, so the client's to come
+        // back, and nobody's to remember it failing
+        if class == ErrorClass::SourceIncomplete {
+            let body = ErrorBody { error: class.as_str(), message: self.error.to_string(), stderr: self.stderr };
+            let retry_after = MOOV_RETRY_DELAY.as_secs().to_string();
+            return (class.status(), [(header::RETRY_AFTER, retry_after), (header::CACHE_CONTROL, "no-store".to_string())], Json(body))
+                .into_response();
+        }
+        let body = ErrorBody {
+            error: class.as_str(),
+            message: format!("Failed to process video: {}", self.error),
+            stderr: self.stderr,
+        };
+        (class.status(), Json(body)).into_response()
+    }
+}
+
+// Sent when a client has used up its rate limit. The `RateLimit-*` headers
+// are added on the way out, as they are to every response that was checked.
+fn rate_limited_response(checked: Checked) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, checked.retry_after.as_secs().max(1).to_string())],
+        "Too many requests, please slow down",
+    )
+        .into_response()
+}
+
+/// The body of a response refusing a key that's used up one of its quotas.
+#[derive(Serialize)]
+struct QuotaError {
+    error: &'static str,
+    message: String,
+    /// `conversions` or `cpu_seconds`, per day
+    quota: &'static str,
+    limit: u64,
+    /// Unix milliseconds at which the key's usage starts over
+    resets_at: i64,
+}
+
+// Sent when an API key has used up one of its quotas for the day
+fn quota_exceeded_response(exceeded: Exceeded) -> Response {
+    let retry_after = exceeded.resets_at.duration_since(SystemTime::now()).unwrap_or_default();
+    let message = match exceeded.quota {
+        "conversions" => format!("This API key has run its {} conversions for the day", exceeded.limit),
+        _ => format!("This API key has used its {} seconds of CPU time for the day", exceeded.limit),
+    };
+    let body = QuotaError {
+        error: "quota_exceeded",
+        message,
+        quota: exceeded.quota,
+        limit: exceeded.limit,
+        resets_at: exceeded.resets_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64,
+    };
+    (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, retry_after.as_secs().max(1).to_string())], Json(body)).into_response()
+}
+
+// The response refusing `caller` another conversion today, if it's used up
+// a quota
+fn over_quota(state: &AppState, caller: Option<&Extension<Caller>>, note: &AccessNote) -> Option<Response> {
+    let Extension(Caller(name)) = caller?;
+    let exceeded = state.usage.check(name).err()?;
+    warn!("{} has used up its {} quota for the day, refusing another conversion", name, exceeded.quota);
+    note.outcome("quota_exceeded");
+    Some(quota_exceeded_response(exceeded))
+}
+
+// The response body for a finished GIF. A spilled GIF is streamed from disk
+// and its temp file removed once the body is dropped.
+fn gif_body(gif: GifOutput) -> Body {
+    match gif {
+        GifOutput::Memory(bytes) => Body::from(bytes),
+        GifOutput::Spilled { file, handle, .. } => Body::from_stream(SpilledBody::new(file, handle)),
+        GifOutput::Streamed { .. } => Body::empty(),
+    }
+}
+
+// A successful GIF response, or whatever else the backend made. Streamed
+// bodies don't know their length up front.
+fn gif_response(
+    body: Body,
+    content_length: Option<u64>,
+    trimmed: Option<Trimmed>,
+    ignored: &[&str],
+    backend: &dyn Backend,
+    degraded: Option<Degraded>,
+    pacing: &Pacing,
+) -> Response {
+    let body = pacing.apply(body, content_length);
+    let mut response = body.into_response();
+    if let Some(len) = content_length {
+        response.headers_mut().insert(header::CONTENT_LENGTH, len.into());
+    }
+    with_gif_headers(response, trimmed, ignored, backend, degraded)
+}
+
+// Say what a GIF is and how it was made, on whatever response it's sent in
+fn with_gif_headers(
+    mut response: Response,
+    trimmed: Option<Trimmed>,
+    ignored: &[&str],
+    backend: &dyn Backend,
+    degraded: Option<Degraded>,
+) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static(backend.format().content_type()));
+    headers.insert("X-Powered-By", header::HeaderValue::from_static("fastgif"));
+    headers.insert("X-FastGIF-Encoder", header::HeaderValue::from_static(backend.name()));
+    headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("public, max-age=31536000"));
+    if let Some(trimmed) = trimmed {
+        headers.insert("X-FastGIF-Trimmed", header::HeaderValue::from_static("true"));
+        if let Some(duration) = trimmed.source_duration {
+            headers.insert("X-FastGIF-Source-Duration-Ms", (duration.as_millis() as u64).into());
+        }
+    }
+    if !ignored.is_empty() {
+        headers.insert("X-FastGIF-Ignored", header::HeaderValue::from_str(&ignored.join(", ")).unwrap());
+    }
+    // A degraded GIF is only what we could manage at the time, so it mustn't be
+    // cached for as long as the real thing would be
+    if let Some(degraded) = degraded {
+        headers.insert("X-FastGIF-Degraded", header::HeaderValue::from_static(degraded.tier.header_value()));
+        let cache_control = if degraded.max_age.is_zero() {
+            "no-store".to_string()
+        } else {
+            format!("public, max-age={}", degraded.max_age.as_secs())
+        };
+        headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_str(&cache_control).unwrap());
+    }
+    response
+}
+
+// Say which bucket `AUTO_QUALITY` put the video in, for a GIF held to its
+// profile
+fn with_auto_quality(mut response: Response, auto: Option<auto_quality::Bucket>) -> Response {
+    if let Some(bucket) = auto {
+        if let Ok(value) = header::HeaderValue::from_str(&bucket.to_string()) {
+            response.headers_mut().insert("X-FastGIF-Auto-Quality", value);
+        }
+    }
+    response
+}
+
+// Tell the client where the time went, with the same numbers our logs have.
+// A streamed response only knows about what happened before it started.
+fn with_server_timing(mut response: Response, server_timing: &str) -> Response {
+    if let Ok(value) = header::HeaderValue::from_str(server_timing) {
+        response.headers_mut().insert("Server-Timing", value);
+    }
+    response
+}
+
+// Say what ffmpeg decoded the video with, a hardware decoder or software, on
+// a GIF that's just been made
+fn with_decode(mut response: Response, decode: Option<&'static str>) -> Response {
+    if let Some(path) = decode {
+        response.headers_mut().insert("X-FastGIF-Decode", header::HeaderValue::from_static(path));
+    }
+    response
+}
+
+// Tag the response with what it was made from, so caches can tell variants
+// apart, or if it can't be asked for again, tell them not to keep it
+fn with_caching(mut response: Response, cacheable: bool, etag: Option<&str>) -> Response {
+    if !cacheable {
+        response.headers_mut().insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-store"));
+        return response;
+    }
+    if let Some(value) = etag.and_then(|etag| header::HeaderValue::from_str(etag).ok()) {
+        response.headers_mut().insert(header::ETAG, value);
+    }
+    response
+}
+
+// Say whether the response came from our own cache (`CACHE_MAX_BYTES`),
+// the way CDNs do
+fn with_cache_status(mut response: Response, status: &'static str) -> Response {
+    response.headers_mut().insert("X-Cache", header::HeaderValue::from_static(status));
+    response
+}
+
+/// What every route that converts takes from a request before anything of
+/// its own: the server, who's asking and when, and what was noted about the
+/// request on its way in. A request whose `X-Request-Timeout` isn't a number
+/// of milliseconds is refused here.
+struct Incoming {
+    state: AppState,
+    /// When it came in, which is where a slow request is timed from
+    received: Instant,
+    note: AccessNote,
+    request_id: RequestId,
+    caller_trace: Option<Extension<TraceContext>>,
+    caller: Option<Extension<Caller>>,
+    client_cert: Option<Extension<tls::ClientCert>>,
+    /// Who it's from, past the proxies we trust
+    client: IpAddr,
+    /// When the client would rather have an error than wait any longer
+    deadline: Option<Instant>,
+}
+
+impl FromRequestParts<AppState> for Incoming {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Response> {
+        let received = Instant::now();
+        let ConnectInfo(peer) = ConnectInfo::<Peer>::from_request_parts(parts, state).await.map_err(IntoResponse::into_response)?;
+        let Extension(note) = Extension::<AccessNote>::from_request_parts(parts, state).await.map_err(IntoResponse::into_response)?;
+        let Extension(request_id) = Extension::<RequestId>::from_request_parts(parts, state).await.map_err(IntoResponse::into_response)?;
+        let deadline = request_deadline(state, &parts.headers, received, &note).map_err(|response| *response)?;
+        Ok(Incoming {
+            state: state.clone(),
+            received,
+            client: client_ip::resolve(&parts.headers, peer.ip(), &state.trust_proxy),
+            caller_trace: parts.extensions.get().cloned().map(Extension),
+            caller: parts.extensions.get().cloned().map(Extension),
+            client_cert: parts.extensions.get().cloned().map(Extension),
+            note,
+            request_id,
+            deadline,
+        })
+    }
+}
+
+/// A conversion asked for on either route, once it's been read: who it's
+/// for, and what they asked for.
+struct ConversionRequest {
+    /// When it came in, which is where a slow request is timed from
+    received: Instant,
+    note: AccessNote,
+    request_id: RequestId,
+    caller_trace: Option<Extension<TraceContext>>,
+    caller: Option<Extension<Caller>>,
+    client_cert: Option<Extension<tls::ClientCert>>,
+    /// Who it's from, past the proxies we trust
+    client: IpAddr,
+    /// What it's logged and audited as: the path asked for, or the upload's name
+    name: String,
+    query: ConversionQuery,
+    format: Format,
+    /// Told once the conversion has been admitted, for a job to say it's running
+    admitted: Option<oneshot::Sender<()>>,
+    /// Told how far the conversion has got, for a job's events
+    progress: Option<Progress>,
+    /// How soon it's let in when it has to wait for a permit
+    priority: Priority,
+    /// Made to be cached, only with a permit nobody else is waiting for
+    prefetch: bool,
+    /// When the client would rather have an error than wait any longer
+    /// (`X-Request-Timeout`)
+    deadline: Option<Instant>,
+    /// `Range` and the conditions on it, for a GIF sent from `CACHE_DIR`
+    range_headers: HeaderMap,
+    /// Made again to replace a soft-purged GIF, which is a prefetch too
+    refresh: bool,
+    /// Its place among the other variants of the video it's decoded with,
+    /// when an item asked for several
+    shared: Option<shared_decode::Member>,
+}
+
+/// Where the video to convert is.
+enum Video {
+    /// At the upstream, under this path (as `source_path::canonicalize` made it)
+    Upstream(String),
+    /// Sent to `POST /convert`. What's made from it can only be cached under
+    /// the client's `Idempotency-Key`, since nothing else says what it was.
+    Upload { upload: Upload, idempotency_key: Option<String> },
+}
+
+/// When a conversion takes its client's slot (`MAX_CONCURRENT_PER_CLIENT`).
+enum ClientTurn {
+    /// It was taken before the conversion was started, if it was needed
+    Held(Option<ClientSlot>),
+    /// Only once the GIF isn't found in the cache, since sending one that's
+    /// there doesn't count. It's refused then in maintenance mode, which
+    /// lets it through for the cache.
+    OnMiss,
+}
+
+// The format asked for, or the response refusing one this server doesn't make
+fn requested_format(state: &AppState, query: &ConversionQuery, note: &AccessNote) -> Result<Format, Box<Response>> {
+    match query.format.as_deref().map(str::parse::<Format>) {
+        None => Ok(Format::Gif),
+        Some(Ok(format)) if state.pipeline().backends.makes(format) => Ok(format),
+        Some(_) => {
+            note.outcome("unsupported_format");
+            let formats = state.pipeline().backends.formats().join(", ");
+            Err(Box::new(
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Failed to process video: unsupported_format (this server makes {})", formats),
+                )
+                    .into_response(),
+            ))
+        }
+    }
+}
+
+// Hold `client` to its rate limit and the conversions it may have going,
+// returning its slot among those, or the response refusing it
+fn limit_client(
+    state: &AppState,
+    note: &AccessNote,
+    client: IpAddr,
+    caller: Option<&Extension<Caller>>,
+) -> Result<Option<ClientSlot>, Box<Response>> {
+    limit_rate(state, note, client)?;
+    client_slot(state, note, client, caller)
+}
+
+// The response refusing `client` if it's over RATE_LIMIT
+fn limit_rate(state: &AppState, note: &AccessNote, client: IpAddr) -> Result<(), Box<Response>> {
+    if let Some(rate) = state.settings.load().rate_limit {
+        let checked = state.rate_limiter.check(client, rate);
+        note.rate_limit(checked);
+        if !checked.allowed {
+            warn!("Rate limiting {}", client);
+            note.outcome("rate_limited");
+            return Err(Box::new(rate_limited_response(checked)));
+        }
+    }
+    Ok(())
+}
+
+// One of the conversions the client may have going at once, if there's a
+// limit, or the response refusing it
+fn client_slot(
+    state: &AppState,
+    note: &AccessNote,
+    client: IpAddr,
+    caller: Option<&Extension<Caller>>,
+) -> Result<Option<ClientSlot>, Box<Response>> {
+    // Waiting in the queue counts too, or one client could fill it
+    let Some(per_client) = &state.per_client else {
+        return Ok(None);
+    };
+    let key = match caller {
+        Some(Extension(Caller(name))) => ClientKey::ApiKey(name.clone()),
+        None => ClientKey::Ip(client),
+    };
+    match per_client.acquire(key) {
+        Some(slot) => Ok(Some(slot)),
+        None => {
+            warn!("{} already has as many conversions as it may, refusing another", client);
+            note.outcome("too_many_conversions");
+            Err(Box::new(
+                (StatusCode::TOO_MANY_REQUESTS, "Too many conversions at once, please wait for one to finish").into_response(),
+            ))
+        }
+    }
+}
+
+// When a request that came in at `received` would rather have a
+// `deadline_exceeded` than wait any longer for its GIF, as `X-Request-Timeout`
+// says in milliseconds, but no later than CONVERSION_TIMEOUT would give up
+// anyway. It's recorded on the request's span as `deadline_ms`. A header
+// that isn't a number of milliseconds gets the response refusing it.
+fn request_deadline(state: &AppState, headers: &HeaderMap, received: Instant, note: &AccessNote) -> Result<Option<Instant>, Box<Response>> {
+    let Some(value) = headers.get("x-request-timeout") else {
+        return Ok(None);
+    };
+    let Some(ms) = value.to_str().ok().and_then(|value| value.trim().parse::<u64>().ok()).filter(|ms| *ms > 0) else {
+        note.outcome("invalid_request_timeout");
+        return Err(Box::new(
+            (
+                StatusCode::BAD_REQUEST,
+                "Failed to process video: invalid_request_timeout (X-Request-Timeout has to be a number of milliseconds)",
+            )
+                .into_response(),
+        ));
+    };
+    let timeout = Duration::from_millis(ms).min(state.pipeline().timeout);
+    Span::current().record("deadline_ms", timeout.as_millis() as u64);
+    Ok(Some(received + timeout))
+}
+
+// Whether the client would rather be given a job to ask after than wait for
+// the GIF (`Prefer: respond-async`, RFC 7240)
+fn prefers_async(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|preference| preference.split([';', '=']).next().unwrap_or_default().trim().eq_ignore_ascii_case("respond-async"))
+}
+
+// Whether converting `path` with `query` would be answered from the cache
+async fn is_cached(state: &AppState, path: &str, query: &ConversionQuery) -> bool {
+    variant_key(state, path, query).await.is_some_and(|variant| is_kept(state, &variant.to_string()))
+}
+
+// What converting `path` with `query` is kept as, once `AUTO_QUALITY` has
+// had its say
+async fn variant_key(state: &AppState, path: &str, query: &ConversionQuery) -> Option<VariantKey> {
+    let auto = probed_bucket(state, path, query).await;
+    VariantKey::from(path, query, auto, state)
+}
+
+// The bucket `AUTO_QUALITY` puts `path` in, from what ffprobe says about it.
+// A video that can't be probed is converted with the configured settings.
+async fn probed_bucket(state: &AppState, path: &str, query: &ConversionQuery) -> Option<auto_quality::Bucket> {
+    if !state.auto_quality || !query.auto() {
+        return None;
+    }
+    let source = state.source_ref(path, &ConversionOptions::default());
+    match state.prober.details(&state.pipeline().source_fetcher.probe_input(&source)).await {
+        Ok(details) => auto_bucket(state, query, &details),
+        Err(e) => {
+            warn!("Couldn't probe {} to pick its settings, converting it as configured: {}", path, e);
+            None
+        }
+    }
+}
+
+// The same, for a video that's been probed already
+fn auto_bucket(state: &AppState, query: &ConversionQuery, details: &probe::Details) -> Option<auto_quality::Bucket> {
+    if !state.auto_quality || !query.auto() {
+        return None;
+    }
+    Some(auto_quality::Bucket::of(details.duration?, details.width?, details.height?, details.bit_rate))
+}
+
+// Whether the GIF for `key` is in either cache, and not stale
+fn is_kept(state: &AppState, key: &str) -> bool {
+    let fresh = |stale: &AtomicBool| !stale.load(Ordering::Relaxed);
+    state.cache.as_ref().and_then(|cache| cache.get(key)).is_some_and(|gif| fresh(&gif.stale))
+        || state.disk_cache.as_ref().and_then(|cache| cache.get(key)).is_some_and(|gif| fresh(&gif.stale))
+}
+
+// Whether the GIF for `key` that would be sent is stale
+fn is_stale(state: &AppState, key: &str) -> bool {
+    match state.cache.as_ref().and_then(|cache| cache.get(key)) {
+        Some(gif) => gif.stale.load(Ordering::Relaxed),
+        None => state.disk_cache.as_ref().and_then(|cache| cache.get(key)).is_some_and(|gif| gif.stale.load(Ordering::Relaxed)),
+    }
+}
+
+// What of a request's headers a GIF sent from `CACHE_DIR` goes by, since
+// only a file can be sent in part
+fn range_headers(headers: &HeaderMap) -> HeaderMap {
+    [header::RANGE, header::IF_RANGE, header::IF_MODIFIED_SINCE, header::IF_UNMODIFIED_SINCE]
+        .into_iter()
+        .filter_map(|name| Some((name.clone(), headers.get(&name)?.clone())))
+        .collect()
+}
+
+async fn handle_tweet_video(
+    incoming: Incoming,
+    headers: HeaderMap,
+    Path(raw_path): Path<String>,
+    Query(query): Query<ConversionQuery>,
+) -> Response {
+    let Incoming { state, received, note, request_id, caller_trace, caller, client_cert, client, deadline } = incoming;
+    // replace .gif with .mp4 in URL. Discord seems to be picky about file extensions...?
+    let path = match source_path::canonicalize(&raw_path) {
+        Ok(path) => path,
+        Err(e) => {
+            note.outcome("invalid_path");
+            warn!(outcome = "invalid_path", "Refusing the path {:?}: {}", raw_path, e);
+            return (StatusCode::BAD_REQUEST, format!("Failed to process video: invalid_path ({})", e)).into_response();
+        }
+    };
+    info!("Processing video: {}", raw_path);
+    note.popular(&path);
+    let format = match requested_format(&state, &query, &note) {
+        Ok(format) => format,
+        Err(response) => return *response,
+    };
+    if let Err(response) = limit_rate(&state, &note, client) {
+        return *response;
+    }
+    // Only what has to be converted is made a job
+    let respond_async = prefers_async(&headers) && !is_cached(&state, &path, &query).await;
+    // Uploaded whole before the client's sent to it, so there's no streaming
+    let bucket = state.bucket.clone().filter(|_| query.redirect() && !respond_async);
+    let mut state = state;
+    if bucket.is_some() {
+        state.stream_response = false;
+    }
+    let request = ConversionRequest {
+        received,
+        note: if respond_async { AccessNote::default() } else { note.clone() },
+        request_id,
+        caller_trace,
+        caller,
+        client_cert,
+        client,
+        name: raw_path,
+        query,
+        format,
+        admitted: None,
+        progress: None,
+        // Someone's waiting on it, most likely to embed it
+        priority: Priority::from_headers(&headers).unwrap_or(Priority::High),
+        prefetch: false,
+        deadline: deadline.filter(|_| !respond_async),
+        range_headers: range_headers(&headers),
+        refresh: false,
+        shared: None,
+    };
+    if respond_async {
+        // Kept the way `POST /jobs` would have taken it, to be converted again after a restart
+        let params = match serde_json::to_value(&request.query) {
+            Ok(serde_json::Value::Object(params)) => params.into_iter().filter(|(_, value)| !value.is_null()).collect(),
+            _ => BTreeMap::new(),
+        };
+        let submission = jobs::Submission {
+            path: Some(request.name.clone()),
+            url: None,
+            params,
+            callback_url: None,
+            priority: request.priority,
+        };
+        let owner = request.caller.as_ref().map(|Extension(Caller(name))| name.clone());
+        let origin = Origin { owner, submission: serde_json::to_string(&submission).expect("a job is always JSON"), client };
+        if state.maintenance.is_on() {
+            return state.maintenance.refusal(&note);
+        }
+        // Held by the job from the start, since it's going to be converted
+        let client_slot = match client_slot(&state, &note, client, request.caller.as_ref()) {
+            Ok(slot) => slot,
+            Err(response) => return *response,
+        };
+        let mut response = create_job(&state, &note, origin, request, path, client_slot, None);
+        if response.status() == StatusCode::ACCEPTED {
+            note.outcome("job");
+            let headers = response.headers_mut();
+            headers.insert("preference-applied", header::HeaderValue::from_static("respond-async"));
+            headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-store"));
+        }
+        return response;
+    }
+    let response = convert_video(state.clone(), request, Video::Upstream(path), ClientTurn::OnMiss).await;
+    match bucket {
+        Some(bucket) => match upload_response(&state, &bucket, response).await {
+            Ok(key) => redirect_to_bucket(&bucket, &key),
+            Err(response) => response,
+        },
+        None => response,
+    }
+}
+
+// Upload the GIF `response` sends, if it's a whole one, to the bucket, for
+// its key there. It goes by way of a temp file, held to MAX_OUTPUT_BYTES,
+// rather than memory. Anything else, or one that couldn't be uploaded, comes
+// back as a response to send instead.
+async fn upload_response(state: &AppState, bucket: &Bucket, response: Response) -> Result<String, Response> {
+    if response.status() != StatusCode::OK {
+        return Err(response);
+    }
+    let (parts, body) = response.into_parts();
+    let (file, len) = match spill_body(&state.temp, body, state.pipeline().max_output_bytes).await {
+        Ok(spilled) => spilled,
+        Err(error) => {
+            let class = ErrorClass::of(&error);
+            error!(outcome = class.as_str(), "Failed to write a GIF to upload: {}", error);
+            return Err(ConversionFailure { class, error, stderr: None, placeholder: None }.into_response());
+        }
+    };
+    let uploaded = match tokio::fs::File::open(file.path()).await {
+        Ok(handle) => bucket.upload(&parts.headers, handle, len).await,
+        Err(e) => {
+            error!("Failed to read {} to upload: {}", file.path().display(), e);
+            None
+        }
+    };
+    if let Some(key) = uploaded {
+        return Ok(key);
+    }
+    // Sent from the file instead, which goes once it's been sent
+    match tokio::fs::File::open(file.path()).await {
+        Ok(handle) => Err(Response::from_parts(parts, Body::from_stream(SpilledBody::new(file, handle)))),
+        Err(e) => {
+            error!("Failed to read {} to send: {}", file.path().display(), e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to process video: {}", ErrorClass::Internal.as_str())).into_response())
+        }
+    }
+}
+
+// Write `body` to a new temp file from `temp`, giving up once it's more than
+// `max` bytes, for its file and how long it came to
+async fn spill_body(temp: &Arc<TempManager>, body: Body, max: Option<u64>) -> Result<(TempFile, u64), ConversionError> {
+    let (file, mut handle) = temp.create("upload.gif").await?;
+    let write_failed = || ConversionError::pipe(format!("Failed to write {}", file.path().display()));
+    let mut body = body.into_data_stream();
+    let mut total = 0u64;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| ConversionError::internal(format!("Failed to read the GIF: {}", e)))?;
+        total += chunk.len() as u64;
+        if let Some(max) = max.filter(|max| total > *max) {
+            return Err(ConversionError::OutputTooLarge(max));
+        }
+        file.grew(chunk.len())?;
+        handle.write_all(&chunk).await.map_err(write_failed())?;
+    }
+    handle.flush().await.map_err(write_failed())?;
+    Ok((file, total))
+}
+
+async fn handle_convert(incoming: Incoming, request: Request) -> Response {
+    let Incoming { state, received, note, request_id, caller_trace, caller, client_cert, client, deadline } = incoming;
+    // Before the upload is read, so nobody gets to send one they'd be refused
+    let client_slot = match limit_client(&state, &note, client, caller.as_ref()) {
+        Ok(slot) => slot,
+        Err(response) => return *response,
+    };
+    let idempotency_key = match idempotency_key(request.headers(), &note) {
+        Ok(key) => key,
+        Err(response) => return *response,
+    };
+    let query_string = request.uri().query().unwrap_or_default().to_string();
+    let priority = Priority::from_headers(request.headers()).unwrap_or(Priority::High);
+    let upload = match upload::receive(request, &state.temp, state.max_upload_bytes).await {
+        Ok(upload) => upload,
+        Err(Refused::Invalid(reason)) => {
+            note.outcome("invalid_upload");
+            warn!(outcome = "invalid_upload", "Refusing an upload: {}", reason);
+            return (StatusCode::BAD_REQUEST, format!("Failed to process video: invalid_upload ({})", reason)).into_response();
+        }
+        Err(Refused::Failed(error)) => {
+            let class = ErrorClass::of(&error);
+            note.outcome(class.as_str());
+            warn!(outcome = class.as_str(), "Failed to receive an upload: {}", error);
+            return ConversionFailure { class, error, stderr: None, placeholder: None }.into_response();
+        }
+    };
+    // The form's fields are parameters too, just like the query's
+    let query = match serde_urlencoded::to_string(&upload.fields)
+        .map(|fields| [query_string.as_str(), &fields].join("&"))
+        .map_err(|e| e.to_string())
+        .and_then(|params| serde_urlencoded::from_str::<ConversionQuery>(&params).map_err(|e| e.to_string()))
+    {
+        Ok(query) => query,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Failed to deserialize query string: {}", e)).into_response(),
+    };
+    let name = upload.name.clone().unwrap_or_else(|| "upload".to_string());
+    info!("Processing upload: {}", name);
+    let format = match requested_format(&state, &query, &note) {
+        Ok(format) => format,
+        Err(response) => return *response,
+    };
+    // The same upload, converted the same way
+    let fingerprint = match &idempotency_key {
+        Some(_) if state.idempotency.is_some() => {
+            let fingerprint = Fingerprint::of(b"/convert").add(query_string.as_bytes());
+            let fields = serde_urlencoded::to_string(&upload.fields).unwrap_or_default();
+            match fingerprint.add(fields.as_bytes()).add_file(upload.file.path()).await {
+                Ok(fingerprint) => Some(fingerprint.finish()),
+                Err(e) => {
+                    let error = ConversionError::Internal(format!("Couldn't read the upload back: {}", e));
+                    return ConversionFailure { class: ErrorClass::of(&error), error, stderr: None, placeholder: None }.into_response();
+                }
+            }
+        }
+        _ => None,
+    };
+    let claim = match idempotent(&state, &note, "/convert", idempotency_key.as_deref(), caller.as_ref(), client, fingerprint).await {
+        Ok(claim) => claim,
+        Err(response) => return response,
+    };
+    // Kept whole, to be sent again
+    let mut state = state;
+    if claim.is_some() {
+        state.stream_response = false;
+    }
+    let request = ConversionRequest {
+        received,
+        note,
+        request_id,
+        caller_trace,
+        caller,
+        client_cert,
+        client,
+        name,
+        query,
+        format,
+        admitted: None,
+        progress: None,
+        priority,
+        prefetch: false,
+        deadline,
+        range_headers: HeaderMap::new(),
+        refresh: false,
+        shared: None,
+    };
+    let response = convert_video(state, request, Video::Upload { upload, idempotency_key }, ClientTurn::Held(client_slot)).await;
+    match claim {
+        Some(claim) => claim.finish(response).await,
+        None => response,
+    }
+}
+
+// The request's `Idempotency-Key`, if it has one, or the response refusing
+// one that won't do
+fn idempotency_key(headers: &HeaderMap, note: &AccessNote) -> Result<Option<String>, Box<Response>> {
+    let Some(key) = headers.get("idempotency-key").map(|key| key.to_str().map(str::trim)) else {
+        return Ok(None);
+    };
+    match key {
+        Ok("") => Ok(None),
+        Ok(key) if key.len() <= idempotency::MAX_KEY_LEN => Ok(Some(key.to_string())),
+        _ => {
+            note.outcome("invalid_idempotency_key");
+            let message = format!("Failed to process request: invalid_idempotency_key (it has to be ASCII, and at most {} bytes)", idempotency::MAX_KEY_LEN);
+            Err(Box::new((StatusCode::BAD_REQUEST, message).into_response()))
+        }
+    }
+}
+
+// Where a POST to `route` with `key` stands: the claim to keep its response
+// with if it's the first (or `None`, without a key or IDEMPOTENCY_TTL), or
+// else the response it gets instead. `fingerprint` is what the request came to.
+async fn idempotent(
+    state: &AppState,
+    note: &AccessNote,
+    route: &'static str,
+    key: Option<&str>,
+    caller: Option<&Extension<Caller>>,
+    client: IpAddr,
+    fingerprint: Option<[u8; 32]>,
+) -> Result<Option<Claim>, Response> {
+    let (Some(idempotency), Some(key), Some(fingerprint)) = (&state.idempotency, key, fingerprint) else {
+        return Ok(None);
+    };
+    let client = match caller {
+        Some(Extension(Caller(name))) => ClientKey::ApiKey(name.clone()),
+        None => ClientKey::Ip(client),
+    };
+    match idempotency.begin(route, client, key.to_string(), fingerprint).await {
+        Begun::First(claim) => Ok(Some(claim)),
+        Begun::Answered(stored) => {
+            info!("Answering a retried {} as the first with its Idempotency-Key was", route);
+            note.outcome("idempotent_replay");
+            Err(stored.response(true))
+        }
+        Begun::Mismatch => {
+            warn!("Refusing a {} whose Idempotency-Key was sent with a different request", route);
+            note.outcome("idempotency_key_reused");
+            Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Failed to process request: idempotency_key_reused (that Idempotency-Key was sent with a different request)",
+            )
+                .into_response())
+        }
+    }
+}
+
+async fn handle_batch(incoming: Incoming, headers: HeaderMap, body: Bytes) -> Response {
+    // The deadline is every item's, since the batch is answered once they're all done
+    let Incoming { state, received, note, request_id, caller_trace, caller, client_cert, client, deadline } = incoming;
+    // Each item counts against RATE_LIMIT too, but a batch is a lot to ask at once
+    let checked = state.batch_rate_limiter.check(client, state.batch_rate_limit);
+    note.rate_limit(checked);
+    if !checked.allowed {
+        warn!("Rate limiting batches from {}", client);
+        note.outcome("rate_limited");
+        return rate_limited_response(checked);
+    }
+    let items: Vec<batch::Item> = match serde_json::from_slice(&body) {
+        Ok(items) => items,
+        Err(e) => {
+            note.outcome("invalid_batch");
+            return (StatusCode::BAD_REQUEST, format!("Failed to read the batch: invalid_batch ({})", e)).into_response();
+        }
+    };
+    if items.is_empty() || items.len() > state.batch_max_items {
+        note.outcome("invalid_batch");
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Failed to read the batch: invalid_batch (it has {} items, 1 to {} are allowed)", items.len(), state.batch_max_items),
+        )
+            .into_response();
+    }
+    info!("Processing a batch of {} videos", items.len());
+    // Only the GIFs' sizes are wanted here, so they're converted to the end
+    // rather than streamed, and failures aren't hidden behind a placeholder
+    let mut batch_state = state.clone();
+    batch_state.stream_response = false;
+    batch_state.failure_placeholder = None;
+    let base_url = state.settings.load().video_base_url.clone();
+    // No more at once than the client would be allowed anyway
+    let concurrency = match state.per_client.as_ref().map(|per_client| per_client.limit()) {
+        Some(max) => state.batch_concurrency.min(max),
+        None => state.batch_concurrency,
+    };
+    // Nobody's waiting on a batch's GIFs to be embedded
+    let priority = Priority::from_headers(&headers).unwrap_or(Priority::Normal);
+    let convert_item = |item: batch::Item| {
+        let state = batch_state.clone();
+        let (request_id, caller_trace, caller, client_cert) = (request_id.clone(), caller_trace.clone(), caller.clone(), client_cert.clone());
+        let base_url = base_url.clone();
+        async move {
+            let label = item.label();
+            let targets = match item.resolve_variants(&base_url) {
+                Ok(targets) => targets,
+                Err(e) => return vec![batch::Outcome::refused(label, 400, &e)],
+            };
+            // An item's variants are made from one decode of its video, each
+            // going as it would as an item of its own. Only the first holds
+            // a place among the client's conversions, since they're one
+            // conversion as far as the client's concerned.
+            let shared = shared_decode::group(targets.len());
+            let variants = targets.into_iter().zip(shared).enumerate().map(|(i, (target, shared))| {
+                let state = state.clone();
+                let (request_id, caller_trace, caller, client_cert) = (request_id.clone(), caller_trace.clone(), caller.clone(), client_cert.clone());
+                let label = label.clone();
+                async move {
+                    // What each item went through is noted apart from the batch itself
+                    let note = AccessNote::default();
+                    let variant = target.variant.map(batch::Variant::as_str);
+                    let refused = |response: &Response| batch::Outcome {
+                        variant,
+                        ..batch::Outcome::of(label.clone(), target.url(), response, note.noted_outcome())
+                    };
+                    let format = match requested_format(&state, &target.query, &note) {
+                        Ok(format) => format,
+                        Err(response) => return refused(&response),
+                    };
+                    // The batch takes one slot, for its first item, once that's
+                    // not found in the cache
+                    let client_turn = match i {
+                        0 => match limit_rate(&state, &note, client) {
+                            Ok(()) => ClientTurn::OnMiss,
+                            Err(response) => return refused(&response),
+                        },
+                        _ => ClientTurn::Held(None),
+                    };
+                    let url = target.url();
+                    let request = ConversionRequest {
+                        received,
+                        note: note.clone(),
+                        request_id,
+                        caller_trace,
+                        caller,
+                        client_cert,
+                        client,
+                        name: target.name.clone(),
+                        query: target.query,
+                        format,
+                        admitted: None,
+                        progress: None,
+                        priority,
+                        prefetch: false,
+                        deadline,
+                        range_headers: HeaderMap::new(),
+                        refresh: false,
+                        shared: Some(shared),
+                    };
+                    let response = convert_video(state.clone(), request, Video::Upstream(target.path), client_turn).await;
+                    let mut outcome = batch::Outcome { variant, ..batch::Outcome::of(label, url, &response, note.noted_outcome()) };
+                    if let Some(bucket) = &state.bucket {
+                        if let Ok(key) = upload_response(&state, bucket, response).await {
+                            outcome.url = Some(bucket.presign(&key));
+                        }
+                    }
+                    outcome
+                }
+            });
+            future::join_all(variants).await
+        }
+    };
+    let outcomes: Vec<batch::Outcome> = stream::iter(items)
+        .map(convert_item)
+        .buffered(concurrency)
+        .flat_map(stream::iter)
+        .collect()
+        .await;
+    Json(outcomes).into_response()
+}
+
+/// What `GET /tweet_video/{path}/estimate` guesses converting the video would
+/// come to. Every number but the source's is an estimate.
+#[derive(Serialize)]
+struct EstimateResponse {
+    path: String,
+    format: &'static str,
+    /// Of what would be made, scaled down to `?width=`
+    width: u32,
+    height: u32,
+    estimated_frames: u64,
+    estimated_bytes: u64,
+    estimated_seconds: f64,
+    /// Conversions like it the estimate is fitted to. With none, it's a rough guess.
+    based_on_conversions: u64,
+    /// Whether only the first MAX_INPUT_DURATION of the video would be converted
+    trimmed: bool,
+    source: SourceDetails,
+}
+
+/// What ffprobe said about a video.
+#[derive(Serialize)]
+struct SourceDetails {
+    /// As it's shown, turned by `rotation`
+    width: Option<u32>,
+    height: Option<u32>,
+    /// Degrees clockwise
+    rotation: Option<u32>,
+    duration_secs: Option<f64>,
+    fps: Option<f64>,
+    bit_rate: Option<u64>,
+}
+
+// Guess what converting `path` would come to, from what ffprobe says about
+// the video and what conversions like it have come to, without converting it
+async fn handle_estimate(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    Extension(note): Extension<AccessNote>,
+    headers: HeaderMap,
+    Path(raw_path): Path<String>,
+    Query(query): Query<ConversionQuery>,
+) -> Response {
+    let path = match source_path::canonicalize(&raw_path) {
+        Ok(path) => path,
+        Err(e) => {
+            note.outcome("invalid_path");
+            return (StatusCode::BAD_REQUEST, format!("Failed to estimate: invalid_path ({})", e)).into_response();
+        }
+    };
+    let format = match requested_format(&state, &query, &note) {
+        Ok(format) => format,
+        Err(response) => return *response,
+    };
+    // Probing isn't a conversion, so it only holds the client to its rate limit
+    let client = client_ip::resolve(&headers, peer.ip(), &state.trust_proxy);
+    if let Some(rate) = state.settings.load().rate_limit {
+        let checked = state.rate_limiter.check(client, rate);
+        note.rate_limit(checked);
+        if !checked.allowed {
+            warn!("Rate limiting {}", client);
+            note.outcome("rate_limited");
+            return rate_limited_response(checked);
+        }
+    }
+    let pipeline = state.pipeline();
+    let mut options = ConversionOptions {
+        backend: pipeline.backends.registered(format),
+        max_width: query.width,
+        fps: query.fps,
+        ..Default::default()
+    };
+    let source = state.source_ref(&path, &options);
+    let details = match state.prober.details(&pipeline.source_fetcher.probe_input(&source)).await {
+        Ok(details) => details,
+        Err(e) => {
+            warn!(outcome = "probe_failed", "Couldn't probe {} for an estimate: {}", raw_path, e);
+            note.outcome("probe_failed");
+            return (StatusCode::BAD_GATEWAY, format!("Failed to estimate: probe_failed ({})", e)).into_response();
+        }
+    };
+    options.auto = auto_bucket(&state, &query, &details);
+    // Converted the way convert_video would
+    let start = query.start.map(|start| start.0);
+    let clip = match clip::clip(start, query.duration.map(|duration| duration.0), details.duration, state.max_input_duration, state.long_video_policy) {
+        Ok(clip) => clip,
+        Err(too_long) => {
+            note.outcome("input_too_long");
+            return (StatusCode::UNPROCESSABLE_ENTITY, format!("Failed to estimate: input_too_long ({})", too_long)).into_response();
+        }
+    };
+    let settings = options.gif_settings(pipeline);
+    let Some(shape) = Shape::of(&details, &settings, clip.start, clip.trim) else {
+        note.outcome("unknown_size");
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Failed to estimate: unknown_size (ffprobe couldn't tell how big or how long the video is)",
+        )
+            .into_response();
+    };
+    let estimate = state.size_model.estimate(format, settings.quality, shape);
+    Json(EstimateResponse {
+        path: raw_path,
+        format: format.as_str(),
+        width: shape.width,
+        height: shape.height,
+        estimated_frames: shape.frames,
+        estimated_bytes: estimate.bytes,
+        estimated_seconds: (estimate.took.as_secs_f64() * 10.0).round() / 10.0,
+        based_on_conversions: estimate.samples,
+        trimmed: clip.trimmed.is_some(),
+        source: SourceDetails {
+            width: details.width,
+            height: details.height,
+            rotation: details.rotation,
+            duration_secs: details.duration.map(|duration| duration.as_secs_f64()),
+            fps: details.fps,
+            bit_rate: details.bit_rate,
+        },
+    })
+    .into_response()
+}
+
+/// `GET /tweet_video/{path}/sprite`: frames from across the video, tiled
+/// into one image, with headers saying how to find a moment in it.
+async fn handle_sprite(incoming: Incoming, Path(raw_path): Path<String>, Query(query): Query<SpriteQuery>) -> Response {
+    let (state, note) = (&incoming.state, &incoming.note);
+    let kind = stills::Kind::Sprite;
+    let path = match source_path::canonicalize(&raw_path) {
+        Ok(path) => path,
+        Err(e) => {
+            note.outcome("invalid_path");
+            return (StatusCode::BAD_REQUEST, format!("Failed to make a sprite sheet: invalid_path ({})", e)).into_response();
+        }
+    };
+    let grid = match query.grid() {
+        Ok(grid) => grid,
+        Err(e) => {
+            note.outcome("invalid_grid");
+            return (StatusCode::BAD_REQUEST, format!("Failed to make a sprite sheet: invalid_grid ({})", e)).into_response();
+        }
+    };
+    let key = grid.key(&VariantKey::still(&path, Some(grid.width), state));
+    still_response(&incoming, kind, &raw_path, &path, key, grid.format, |pipeline, input, details| {
+        let (Some(duration), Some(width), Some(height)) = (details.duration, details.width, details.height) else {
+            note.outcome("unknown_size");
+            return Err(Box::new(
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Failed to make a sprite sheet: unknown_size (ffprobe couldn't tell how big or how long the video is)",
+                )
+                    .into_response(),
+            ));
+        };
+        let cell_height = grid.cell_height(width, height);
+        let args = grid.ffmpeg_args(input, duration, cell_height, pipeline.ffmpeg_threads);
+        Ok((args, grid.headers(cell_height, duration)))
+    })
+    .await
+}
+
+/// `GET /tweet_video/{path}/frame`: the frame nearest a moment in the video,
+/// as an image.
+async fn handle_frame(incoming: Incoming, Path(raw_path): Path<String>, Query(query): Query<FrameQuery>) -> Response {
+    let (state, note) = (&incoming.state, &incoming.note);
+    let kind = stills::Kind::Frame;
+    let path = match source_path::canonicalize(&raw_path) {
+        Ok(path) => path,
+        Err(e) => {
+            note.outcome("invalid_path");
+            return (StatusCode::BAD_REQUEST, format!("Failed to make a frame: invalid_path ({})", e)).into_response();
+        }
+    };
+    // The same formats as converting: WebP only from a server that makes it
+    let frame = query.frame().and_then(|frame| match frame.format {
+        ImageFormat::Webp if !state.pipeline().backends.makes(Format::Webp) => Err("this server doesn't make webp".to_string()),
+        _ => Ok(frame),
+    });
+    let frame = match frame {
+        Ok(frame) => frame,
+        Err(e) => {
+            note.outcome("unsupported_format");
+            return (StatusCode::BAD_REQUEST, format!("Failed to make a frame: unsupported_format ({})", e)).into_response();
+        }
+    };
+    let key = frame.key(&VariantKey::still(&path, frame.width, state));
+    still_response(&incoming, kind, &raw_path, &path, key, frame.format, |pipeline, input, details| {
+        let seek = frame.seek(details.duration, details.fps);
+        let options = ConversionOptions { max_width: frame.width, ..Default::default() };
+        let scale = options.gif_settings(pipeline).scale_filter();
+        let args = frame.ffmpeg_args(input, seek, &scale, pipeline.ffmpeg_threads);
+        Ok((args, vec![("x-fastgif-frame-time", format!("{:.3}", seek.as_secs_f64()))]))
+    })
+    .await
+}
+
+// The still kept as `key`, or one ffmpeg makes with the argv `args` works
+// out from what ffprobe says about the video, sent with the headers it
+// gives. Stills are held to the client's limits and its key's quota, and
+// making one takes a turn like a conversion, since ffmpeg decodes the video
+// for it. It's fetched into a temp file first, the way a conversion fetches
+// it, so it's held to MAX_INPUT_BYTES and read from the host it was pinned to.
+async fn still_response(
+    incoming: &Incoming,
+    kind: stills::Kind,
+    raw_path: &str,
+    path: &str,
+    key: String,
+    format: ImageFormat,
+    args: impl FnOnce(&PipelineConfig, &str, &probe::Details) -> Result<(Vec<String>, Vec<(&'static str, String)>), Box<Response>>,
+) -> Response {
+    let Incoming { state, note, client, .. } = incoming;
+    let caller = incoming.caller.as_ref();
+    let noun = kind.noun();
+    if let Err(response) = limit_rate(state, note, *client) {
+        return *response;
+    }
+    let caller_name = caller.map(|Extension(Caller(name))| name);
+    if let Some(still) = state.stills.get(&key) {
+        state.metrics.cache_hit(kind.as_str());
+        if let Some(caller) = caller_name {
+            state.usage.served(caller, still.body.len() as u64);
+        }
+        return still.into_response("HIT");
+    }
+    state.metrics.cache_miss(kind.as_str());
+    let _client_slot = match client_slot(state, note, *client, caller) {
+        Ok(slot) => slot,
+        Err(response) => return *response,
+    };
+    if let Some(response) = over_quota(state, caller, note) {
+        return response;
+    }
+    let _permit = match state.admission.acquire(Priority::Low, None, None).await {
+        Ok(permit) => permit,
+        Err(rejection) => {
+            warn!("Not making {} of {}: {}", noun, raw_path, rejection);
+            note.outcome("overloaded");
+            return overloaded_response(state.admission.queue_wait_timeout());
+        }
+    };
+
+    let pipeline = state.pipeline();
+    let options = ConversionOptions::default();
+    let source = state.source_ref(path, &options);
+    let video = match stills::download(pipeline.source_fetcher.as_ref(), &source, &pipeline.temp).await {
+        Ok(video) => video,
+        Err(e) => return still_failed(note, kind, raw_path, e),
+    };
+    let input = video.path().display().to_string();
+    let details = match state.prober.details_once(&input).await {
+        Ok(details) => details,
+        Err(e) => return still_failed(note, kind, raw_path, e),
+    };
+    let (args, headers) = match args(pipeline, &input, &details) {
+        Ok(made) => made,
+        Err(response) => return *response,
+    };
+    let made = stills::make(&pipeline.binaries.ffmpeg, &pipeline.child_limits, &args, pipeline.max_output_bytes, &options.timings).await;
+    if let Some(caller) = caller_name {
+        state.usage.converted(caller, options.timings.snapshot().cpu, made.as_ref().map_or(0, |body| body.len() as u64));
+    }
+    let body = match made {
+        Ok(body) => body,
+        Err(e) => return still_failed(note, kind, raw_path, e),
+    };
+    info!("Made {} of {} ({} bytes)", noun, raw_path, body.len());
+    let still = Still { body, format, headers };
+    state.stills.put(key, still.clone());
+    still.into_response("MISS")
+}
+
+// The response for failing to make `kind` of the video at `raw_path`
+fn still_failed(note: &AccessNote, kind: stills::Kind, raw_path: &str, error: ConversionError) -> Response {
+    let class = ErrorClass::of(&error);
+    warn!(outcome = class.as_str(), "Couldn't make {} of {}: {}", kind.noun(), raw_path, error);
+    note.outcome(class.as_str());
+    ConversionFailure { class, error, stderr: None, placeholder: None }.into_response()
+}
+
+async fn handle_prefetch(incoming: Incoming, body: Bytes) -> Response {
+    let Incoming { state, received, note, request_id, caller_trace, caller, client_cert, client, .. } = incoming;
+    // It doesn't hold a place among the client's conversions while it
+    // waits, which would keep out the ones it's waiting on
+    if let Err(response) = limit_rate(&state, &note, client) {
+        return *response;
+    }
+    let item: batch::Item = match serde_json::from_slice(&body) {
+        Ok(item) => item,
+        Err(e) => {
+            note.outcome("invalid_prefetch");
+            return (StatusCode::BAD_REQUEST, format!("Failed to read the prefetch: invalid_prefetch ({})", e)).into_response();
+        }
+    };
+    let targets = match item.resolve_variants(&state.settings.load().video_base_url) {
+        Ok(targets) => targets,
+        Err(e) => {
+            note.outcome("invalid_prefetch");
+            return (StatusCode::BAD_REQUEST, format!("Failed to prefetch: {}", e)).into_response();
+        }
+    };
+    let mut formats = Vec::with_capacity(targets.len());
+    for target in &targets {
+        match requested_format(&state, &target.query, &note) {
+            Ok(format) => formats.push(format),
+            Err(response) => return *response,
+        }
+    }
+    // Each variant is taken on by itself, and those that are kept already,
+    // or on their way, are left out of the decode
+    let mut taken_on = Vec::with_capacity(targets.len());
+    for (target, format) in targets.into_iter().zip(formats) {
+        let Some(variant) = variant_key(&state, &target.path, &target.query).await else { continue };
+        let variant = variant.to_string();
+        // Nothing to do for a GIF that's kept already, or on its way
+        let taken = if is_kept(&state, &variant) {
+            Err("cached")
+        } else if state.active.converting(&variant) {
+            Err("in_flight")
+        } else {
+            match state.prefetches.take(&variant) {
+                Ok(pending) => Ok(pending),
+                Err(Declined::Pending) => Err("in_flight"),
+                Err(Declined::Full) => {
+                    warn!("Refusing to prefetch {}: too many prefetches are pending", target.name);
+                    state.metrics.prefetched("full");
+                    note.outcome("overloaded");
+                    return overloaded_response(state.admission.queue_wait_timeout());
+                }
+            }
+        };
+        match taken {
+            Ok(pending) => taken_on.push((target, format, pending)),
+            Err(outcome) => {
+                state.metrics.prefetched(outcome);
+                note.outcome(outcome);
+            }
+        }
+    }
+    if taken_on.is_empty() {
+        return StatusCode::ACCEPTED.into_response();
+    }
+    info!("Prefetching {}", taken_on[0].0.name);
+    note.outcome("prefetching");
+    // Converted to the end, since it's kept rather than sent
+    let mut prefetch_state = state.clone();
+    prefetch_state.stream_response = false;
+    prefetch_state.failure_placeholder = None;
+    let shared = shared_decode::group(taken_on.len());
+    for ((target, format, pending), shared) in taken_on.into_iter().zip(shared) {
+        let note = AccessNote::default();
+        let request = ConversionRequest {
+            received,
+            note: note.clone(),
+            request_id: request_id.clone(),
+            caller_trace: caller_trace.clone(),
+            caller: caller.clone(),
+            client_cert: client_cert.clone(),
+            client,
+            name: target.name,
+            query: target.query,
+            format,
+            admitted: None,
+            progress: None,
+            priority: Priority::Low,
+            prefetch: true,
+            deadline: None,
+            range_headers: HeaderMap::new(),
+            refresh: false,
+            shared: Some(shared),
+        };
+        let (state, prefetch_state) = (state.clone(), prefetch_state.clone());
+        let prefetching = async move {
+            let _pending = pending;
+            let response = convert_video(prefetch_state, request, Video::Upstream(target.path), ClientTurn::Held(None)).await;
+            let outcome = match note.noted_outcome() {
+                _ if response.status().is_success() => "converted",
+                Some("overloaded") => "skipped",
+                _ => "failed",
+            };
+            state.metrics.prefetched(outcome);
+        };
+        tokio::spawn(prefetching.instrument(Span::current()));
+    }
+    StatusCode::ACCEPTED.into_response()
+}
+
+/// What `POST /jobs` answers with.
+#[derive(Serialize)]
+struct JobCreated {
+    id: String,
+    state: &'static str,
+    /// Where to ask how it's going
+    status_url: String,
+}
+
+async fn handle_create_job(incoming: Incoming, headers: HeaderMap, body: Bytes) -> Response {
+    let Incoming { state, received, note, request_id, caller_trace, caller, client_cert, client, .. } = incoming;
+    let (submission, (item, callback_url, priority)) = match serde_json::from_slice::<jobs::Submission>(&body) {
+        Ok(submission) => (serde_json::to_string(&submission).expect("a job is always JSON"), submission.split()),
+        Err(e) => {
+            note.outcome("invalid_job");
+            return (StatusCode::BAD_REQUEST, format!("Failed to read the job: invalid_job ({})", e)).into_response();
+        }
+    };
+    let target = match item.resolve(&state.settings.load().video_base_url) {
+        Ok(target) => target,
+        Err(e) => {
+            note.outcome("invalid_job");
+            return (StatusCode::BAD_REQUEST, format!("Failed to process video: {}", e)).into_response();
+        }
+    };
+    let format = match requested_format(&state, &target.query, &note) {
+        Ok(format) => format,
+        Err(response) => return *response,
+    };
+    // Refused now if it's somewhere we won't send it, rather than once it's too late to say
+    if let Some(url) = &callback_url {
+        let checked = match &state.webhooks {
+            Some(webhooks) => webhooks.check(url).await.map(|_| ()),
+            None => Err("this server has no WEBHOOK_SECRET to sign callbacks with".to_string()),
+        };
+        if let Err(e) = checked {
+            note.outcome("invalid_callback");
+            warn!(outcome = "invalid_callback", "Refusing a job's callback: {}", e);
+            return (StatusCode::BAD_REQUEST, format!("Failed to read the job: invalid_callback ({})", e)).into_response();
+        }
+    }
+    let key = match idempotency_key(&headers, &note) {
+        Ok(key) => key,
+        Err(response) => return *response,
+    };
+    let fingerprint = Some(Fingerprint::of(b"/jobs").add(&body).finish());
+    let claim = match idempotent(&state, &note, "/jobs", key.as_deref(), caller.as_ref(), client, fingerprint).await {
+        Ok(claim) => claim,
+        Err(response) => return response,
+    };
+    // Held by the job until it's done, queued or not, like any other conversion
+    let client_slot = match limit_client(&state, &note, client, caller.as_ref()) {
+        Ok(slot) => slot,
+        Err(response) => return *response,
+    };
+    let origin = Origin { owner: caller.as_ref().map(|Extension(Caller(name))| name.clone()), submission, client };
+    let request = ConversionRequest {
+        received,
+        note: AccessNote::default(),
+        request_id,
+        caller_trace,
+        caller,
+        client_cert,
+        client,
+        name: target.name,
+        query: target.query,
+        format,
+        admitted: None,
+        progress: None,
+        priority,
+        prefetch: false,
+        deadline: None,
+        range_headers: HeaderMap::new(),
+        refresh: false,
+        shared: None,
+    };
+    let response = create_job(&state, &note, origin, request, target.path, client_slot, callback_url);
+    match claim {
+        Some(claim) => claim.finish(response).await,
+        None => response,
+    }
+}
+
+// Take `request` on as a job converting `path`, answering with where to ask
+// after it, or why it can't be
+fn create_job(
+    state: &AppState,
+    note: &AccessNote,
+    origin: Origin,
+    request: ConversionRequest,
+    path: String,
+    client_slot: Option<ClientSlot>,
+    callback_url: Option<String>,
+) -> Response {
+    if let Some(response) = over_quota(state, request.caller.as_ref(), note) {
+        return response;
+    }
+    let Some(created) = state.jobs.create(origin) else {
+        warn!("Too many jobs already, refusing another");
+        note.outcome("too_many_jobs");
+        return overloaded_response(state.admission.queue_wait_timeout());
+    };
+    let id = created.id.clone();
+    info!("Processing video as job {}: {}", id, request.name);
+    start_job(state, created, request, path, client_slot, callback_url);
+    let created = JobCreated { id: id.clone(), state: "queued", status_url: format!("/jobs/{}", id) };
+    (StatusCode::ACCEPTED, [(header::LOCATION, created.status_url.clone())], Json(created)).into_response()
+}
+
+// Convert `path` for the job `created` in a task of its own, as `request`
+// asks, recording how it went and calling back `callback_url` once it's over
+fn start_job(
+    state: &AppState,
+    created: Created,
+    mut request: ConversionRequest,
+    path: String,
+    client_slot: Option<ClientSlot>,
+    callback_url: Option<String>,
+) {
+    let Created { id, progress, cancel } = created;
+    // The GIF is kept for later, so it's converted to the end, not held back
+    // by pacing meant for the client, and a failure is recorded as it is
+    let mut job_state = state.clone();
+    job_state.stream_response = false;
+    job_state.failure_placeholder = None;
+    job_state.pacing = Pacing::default();
+    let (admitted, on_admitted) = oneshot::channel();
+    request.admitted = Some(admitted);
+    request.progress = Some(progress);
+    let job_note = request.note.clone();
+    let jobs = state.jobs.clone();
+    let conversions = state.conversions.clone();
+    let webhooks = state.webhooks.clone();
+    let bucket = state.bucket.clone();
+    let job_id = id.clone();
+    tokio::spawn(
+        async move {
+            let (watched, watched_id) = (jobs.clone(), job_id.clone());
+            tokio::spawn(async move {
+                if on_admitted.await.is_ok() {
+                    watched.running(&watched_id);
+                }
+            });
+            // Dropping the conversion stops it, as it would for a client that hung up
+            let response = tokio::select! {
+                response = convert_video(job_state, request, Video::Upstream(path), ClientTurn::Held(client_slot)) => Some(response),
+                _ = cancel.cancelled() => None,
+            };
+            let status = match response {
+                Some(response) => {
+                    let mut result = job_result(&job_id, response, &job_note).await;
+                    // Kept as it was, to be taken up again after the restart
+                    if jobs.is_persistent() && conversions.is_draining() && result.is_err() {
+                        info!("Job {} was stopped by the shutdown", job_id);
+                        return;
+                    }
+                    if let (Some(bucket), Ok(finished)) = (&bucket, &mut result) {
+                        finished.stored = bucket.upload(&finished.headers, finished.body.clone(), finished.body.len() as u64).await;
+                    }
+                    jobs.finish(&job_id, result)
+                }
+                None => {
+                    info!(outcome = "cancelled", "Job {} was cancelled", job_id);
+                    jobs.cancelled(&job_id)
+                }
+            };
+            if let (Some(url), Some(webhooks), Some(mut status)) = (callback_url, webhooks, status) {
+                status.download_url = download_url(bucket.as_deref(), &jobs, &job_id);
+                webhooks.deliver(&url, &status).await;
+            }
+        }
+        .instrument(info_span!("job", job_id = %id)),
+    );
+}
+
+// Take up the jobs from before a restart: convert those that were queued,
+// and call back those that were interrupted
+fn resume_jobs(state: &AppState, restored: Vec<Restored>) {
+    for job in restored {
+        let (created, origin) = match job {
+            Restored::Resumed { created, origin } => (created, origin),
+            Restored::Interrupted { status, submission } => {
+                warn!(outcome = "interrupted", "Job {} was interrupted by a restart", status.id);
+                let callback_url = serde_json::from_str::<jobs::Submission>(&submission).ok().and_then(|submission| submission.callback_url);
+                if let (Some(url), Some(webhooks)) = (callback_url, state.webhooks.clone()) {
+                    tokio::spawn(async move { webhooks.deliver(&url, &status).await });
+                }
+                continue;
+            }
+        };
+        let id = created.id.clone();
+        // What it was submitted as is checked again, since the settings it
+        // was checked against may not be the same
+        let note = AccessNote::default();
+        let resolved = serde_json::from_str::<jobs::Submission>(&origin.submission)
+            .map_err(|e| e.to_string())
+            .and_then(|submission| {
+                let (item, callback_url, priority) = submission.split();
+                let target = item.resolve(&state.settings.load().video_base_url)?;
+                let format = requested_format(state, &target.query, &note).map_err(|_| "its format isn't offered".to_string())?;
+                Ok((target, format, callback_url, priority))
+            });
+        let (target, format, callback_url, priority) = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                warn!("Job {} can't be resumed: {}", id, e);
+                state.jobs.finish(&id, Err(Failed { status: 400, error: "invalid_job".to_string() }));
+                continue;
+            }
+        };
+        info!("Resuming job {}: {}", id, target.name);
+        let request = ConversionRequest {
+            received: Instant::now(),
+            note,
+            request_id: RequestId(id.clone()),
+            caller_trace: None,
+            caller: origin.owner.map(|owner| Extension(Caller(owner))),
+            client_cert: None,
+            client: origin.client,
+            name: target.name,
+            query: target.query,
+            format,
+            admitted: None,
+            progress: None,
+            priority,
+            prefetch: false,
+            deadline: None,
+            range_headers: HeaderMap::new(),
+            refresh: false,
+            shared: None,
+        };
+        start_job(state, created, request, target.path, None, callback_url);
+    }
+}
+
+// What job `id` made of `response`, its conversion's, which `note` was told
+// about
+async fn job_result(id: &str, response: Response, note: &AccessNote) -> Result<Finished, Failed> {
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Failed {
+            status: status.as_u16(),
+            error: note.noted_outcome().unwrap_or("error").to_string(),
+        });
+    }
+    let (parts, body) = response.into_parts();
+    match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => Ok(Finished { headers: parts.headers, body, stored: None }),
+        Err(e) => {
+            error!("Failed to keep job {}'s GIF: {}", id, e);
+            Err(Failed { status: 500, error: ErrorClass::Internal.as_str().to_string() })
+        }
+    }
+}
+
+// A presigned URL to the job `id`'s GIF, if it's done and was uploaded to
+// `bucket`
+fn download_url(bucket: Option<&Bucket>, jobs: &Jobs, id: &str) -> Option<String> {
+    match (bucket, jobs.result(id)) {
+        (Some(bucket), Some(Ok(Finished { stored: Some(key), .. }))) => Some(bucket.presign(&key)),
+        _ => None,
+    }
+}
+
+async fn handle_job_status(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.jobs.status(&id) {
+        Some(mut status) => {
+            status.download_url = download_url(state.bucket.as_deref(), &state.jobs, &id);
+            Json(status).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "No such job, or it's expired").into_response(),
+    }
+}
+
+// The job's state and progress as server-sent events, until it's over
+async fn handle_job_events(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.jobs.watch(&id) {
+        Ok(watch) => {
+            let events = watch.updates().map(|update| Ok::<_, Infallible>(update.into_event()));
+            Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+        }
+        Err(Unwatchable::TooManyStreams) => {
+            (StatusCode::TOO_MANY_REQUESTS, "This job is being watched by too many streams already").into_response()
+        }
+        Err(Unwatchable::NoSuchJob) => (StatusCode::NOT_FOUND, "No such job, or it's expired").into_response(),
+    }
+}
+
+// Stop a queued or running job: a queued one is cancelled there and then,
+// and a running one once its conversion has stopped
+async fn handle_cancel_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    caller: Option<Extension<Caller>>,
+    headers: HeaderMap,
+) -> Response {
+    let caller = caller.as_ref().map(|Extension(Caller(name))| &**name);
+    match state.jobs.cancel(&id, caller, is_admin(&state, &headers)) {
+        Ok(status) => {
+            info!("Cancelling job {}, which was {}", id, if status.state == "cancelled" { "queued" } else { status.state });
+            let code = if status.state == "cancelled" { StatusCode::OK } else { StatusCode::ACCEPTED };
+            (code, Json(status)).into_response()
+        }
+        Err(Uncancellable::Over(status)) => (StatusCode::CONFLICT, Json(status)).into_response(),
+        Err(Uncancellable::NotYours) => (StatusCode::FORBIDDEN, "That job was submitted with another API key").into_response(),
+        Err(Uncancellable::NoSuchJob) => (StatusCode::NOT_FOUND, "No such job, or it's expired").into_response(),
+    }
+}
+
+// The same as the job's events, over a WebSocket it can be cancelled on too
+async fn handle_job_socket(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    caller: Option<Extension<Caller>>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    match state.jobs.watch(&id) {
+        Ok(watch) => {
+            let (jobs, idle) = (state.jobs.clone(), state.job_socket_idle_timeout);
+            let canceller = Canceller { caller: caller.map(|Extension(Caller(name))| name), admin: is_admin(&state, &headers) };
+            upgrade.on_upgrade(move |socket| job_socket::serve(socket, watch, jobs, id, canceller, idle))
+        }
+        Err(Unwatchable::TooManyStreams) => {
+            (StatusCode::TOO_MANY_REQUESTS, "This job is being watched by too many streams already").into_response()
+        }
+        Err(Unwatchable::NoSuchJob) => (StatusCode::NOT_FOUND, "No such job, or it's expired").into_response(),
+    }
+}
+
+// The job's GIF as it would have been sent, or while there isn't one, what
+// the job's status says
+async fn handle_job_result(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match (state.jobs.result(&id), &state.bucket) {
+        (Some(Ok(Finished { stored: Some(key), .. })), Some(bucket)) => redirect_to_bucket(bucket, &key),
+        (Some(Ok(Finished { headers, body, .. })), _) => {
+            let len = body.len() as u64;
+            let mut response = state.pacing.apply(Body::from(body), Some(len)).into_response();
+            *response.headers_mut() = headers;
+            response
+        }
+        (Some(Err(status)), _) => (StatusCode::CONFLICT, Json(status)).into_response(),
+        (None, _) => (StatusCode::NOT_FOUND, "No such job, or it's expired").into_response(),
+    }
+}
+
+// Send the client to `key` in `bucket`, for as long as the URL works
+fn redirect_to_bucket(bucket: &Bucket, key: &str) -> Response {
+    (StatusCode::TEMPORARY_REDIRECT, [(header::LOCATION, bucket.presign(key)), (header::CACHE_CONTROL, "no-store".to_string())]).into_response()
+}
+
+// The cached GIF for `key`, as the response to a request for `name`, from
+// memory if it's there, or else from `CACHE_DIR`. A stale one only if that
+// will do.
+#[allow(clippy::too_many_arguments)]
+async fn from_cache(
+    state: &AppState,
+    key: &str,
+    variant: Option<&VariantKey>,
+    name: &str,
+    caller: Option<&Arc<str>>,
+    note: &AccessNote,
+    range_headers: &HeaderMap,
+    stale_ok: bool,
+) -> Option<Response> {
+    let usable = |stale: &AtomicBool| stale_ok || !stale.load(Ordering::Relaxed);
+    let Some(gif) = state.cache.as_ref().and_then(|cache| cache.get(key)).filter(|gif| usable(&gif.stale)) else {
+        let gif = state.disk_cache.as_ref()?.get(key).filter(|gif| usable(&gif.stale))?;
+        return from_disk_cache(state, key, gif, variant, name, caller, note, range_headers).await;
+    };
+    state.metrics.cache_hit("gif");
+    if gif.prefetched.swap(false, Ordering::Relaxed) {
+        state.metrics.prefetch_hit();
+    }
+    let stale = gif.stale.load(Ordering::Relaxed);
+    note.cache(if stale { "stale" } else { "hit" });
+    note.served(Served::Cache);
+    info!("Sending {} from the cache ({} bytes)", name, gif.body.len());
+    let len = gif.body.len() as u64;
+    if let Some(caller) = caller {
+        state.usage.served(caller, len);
+    }
+    let body = Body::from(gif.body.clone());
+    let response = gif_response(body, Some(len), gif.trimmed, &gif.ignored, gif.backend.as_ref(), None, &state.pacing);
+    let response = with_auto_quality(response, variant.and_then(VariantKey::auto));
+    let etag = variant.map(VariantKey::etag);
+    Some(with_staleness(with_caching(response, true, etag.as_deref()), stale))
+}
+
+// Say a cached GIF is `HIT`, or `STALE` once it's been soft-purged, which
+// nothing in between should keep, since it's about to be replaced
+fn with_staleness(response: Response, stale: bool) -> Response {
+    if !stale {
+        return with_cache_status(response, "HIT");
+    }
+    let mut response = with_cache_status(response, "STALE");
+    let headers = response.headers_mut();
+    headers.insert(header::WARNING, header::HeaderValue::from_static("110 - \"Response is Stale\""));
+    headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-store"));
+    response
+}
+
+// `gif`, kept in `CACHE_DIR` under `key`, sent straight from its file, all
+// of it or the `Range` asked for. One whose file has gone since is
+// forgotten, and converted again.
+#[allow(clippy::too_many_arguments)]
+async fn from_disk_cache(
+    state: &AppState,
+    key: &str,
+    gif: Arc<CachedFile>,
+    variant: Option<&VariantKey>,
+    name: &str,
+    caller: Option<&Arc<str>>,
+    note: &AccessNote,
+    range_headers: &HeaderMap,
+) -> Option<Response> {
+    let mut request = axum::http::Request::new(Body::empty());
+    *request.headers_mut() = range_headers.clone();
+    let response = match ServeFile::new(&gif.path).try_call(request).await {
+        Ok(response) if response.status() != StatusCode::NOT_FOUND => response,
+        result => {
+            let error = result.err().map_or_else(|| "it's gone".to_string(), |e| e.to_string());
+            warn!("Couldn't send {} from {}, converting it again: {}", name, gif.path.display(), error);
+            state.disk_cache.as_ref()?.forget(key, &gif);
+            return None;
+        }
+    };
+    state.metrics.cache_hit("gif");
+    if gif.prefetched.swap(false, Ordering::Relaxed) {
+        state.metrics.prefetch_hit();
+    }
+    let stale = gif.stale.load(Ordering::Relaxed);
+    note.cache(if stale { "stale" } else { "hit" });
+    note.served(Served::Cache);
+    let (parts, body) = response.into_parts();
+    let len = parts.headers.get(header::CONTENT_LENGTH).and_then(|len| len.to_str().ok()?.parse().ok());
+    info!("Sending {} from {} ({} of {} bytes)", name, gif.path.display(), len.unwrap_or(0), gif.len);
+    if let Some(caller) = caller {
+        state.usage.served(caller, len.unwrap_or(0));
+    }
+    let response = Response::from_parts(parts, state.pacing.apply(Body::new(body), len));
+    let response = with_gif_headers(response, gif.trimmed, &gif.ignored, gif.backend.as_ref(), None);
+    let response = with_auto_quality(response, variant.and_then(VariantKey::auto));
+    let etag = variant.map(VariantKey::etag);
+    Some(with_staleness(with_caching(response, true, etag.as_deref()), stale))
+}
+
+// Convert `path` again in the background, to replace the stale GIF for
+// `key` that's sent meanwhile. It's taken on like a prefetch, and only
+// once, so it can't be going already.
+#[allow(clippy::too_many_arguments)]
+fn refresh_stale(state: &AppState, key: &str, path: &str, name: &str, query: &ConversionQuery, format: Format, request_id: &RequestId, client: IpAddr) {
+    if state.active.converting(key) {
+        return;
+    }
+    let Ok(pending) = state.prefetches.take(key) else {
+        return;
+    };
+    info!("Converting {} again to replace its stale GIF", name);
+    let mut refresh_state = state.clone();
+    refresh_state.stream_response = false;
+    refresh_state.failure_placeholder = None;
+    let request = ConversionRequest {
+        received: Instant::now(),
+        note: AccessNote::default(),
+        request_id: request_id.clone(),
+        caller_trace: None,
+        caller: None,
+        client_cert: None,
+        client,
+        name: name.to_string(),
+        query: query.clone(),
+        format,
+        admitted: None,
+        progress: None,
+        priority: Priority::Low,
+        prefetch: true,
+        deadline: None,
+        range_headers: HeaderMap::new(),
+        refresh: true,
+        shared: None,
+    };
+    let video = Video::Upstream(path.to_string());
+    let name = name.to_string();
+    let refreshing = async move {
+        let _pending = pending;
+        let response = convert_video(refresh_state, request, video, ClientTurn::Held(None)).await;
+        if !response.status().is_success() {
+            warn!("Failed to convert {} again, so its stale GIF is kept", name);
+        }
+    };
+    tokio::spawn(refreshing.instrument(Span::current()));
+}
+
+// Make the GIF `request` asks for from `video`, once there's room for
+// another conversion, and answer with it or with why it couldn't be made
+async fn convert_video(state: AppState, request: ConversionRequest, video: Video, client_turn: ClientTurn) -> Response {
+    let ConversionRequest { received, note, request_id, caller_trace, caller, client_cert, client, name, query, format, admitted, progress, priority, prefetch, deadline, range_headers, refresh, shared } = request;
+    let settings = state.settings.load_full();
+    // Uploads are converted as configured, since there's no probing them
+    // again to find their key
+    let auto = match &video {
+        Video::Upstream(path) => probed_bucket(&state, path, &query).await,
+        Video::Upload { .. } => None,
+    };
+    if let Some(bucket) = auto {
+        note.auto_quality(bucket.to_string());
+    }
+    let mut variant = match &video {
+        Video::Upstream(path) => VariantKey::from(path, &query, auto, &state),
+        Video::Upload { idempotency_key, .. } => idempotency_key
+            .as_ref()
+            .and_then(|key| VariantKey::from(&format!("upload/{}", key), &query, None, &state)),
+    };
+    // Sent again as it was, without waiting for a turn to convert it
+    let cache_key = variant.as_ref().filter(|_| state.cache.is_some() || state.disk_cache.is_some()).map(VariantKey::to_string);
+    let caller_name = caller.as_ref().map(|Extension(Caller(name))| name.clone());
+    if let Some(key) = &cache_key {
+        // A soft-purged GIF is sent as it is while it's made again, unless
+        // this is what's making it
+        if !prefetch && is_stale(&state, key) {
+            if let Video::Upstream(path) = &video {
+                refresh_stale(&state, key, path, &name, &query, format, &request_id, client);
+            }
+        }
+        if let Some(response) = from_cache(&state, key, variant.as_ref(), &name, caller_name.as_ref(), &note, &range_headers, !prefetch).await {
+            return response;
+        }
+        state.metrics.cache_miss("gif");
+    }
+    let client_slot = match client_turn {
+        ClientTurn::Held(slot) => slot,
+        // What maintenance mode let through for the cache is turned away now
+        ClientTurn::OnMiss if state.maintenance.is_on() => return state.maintenance.refusal(&note),
+        ClientTurn::OnMiss => match client_slot(&state, &note, client, caller.as_ref()) {
+            Ok(slot) => slot,
+            Err(response) => return *response,
+        },
+    };
+    // or not at all, if it would take the key over its quota
+    if let Some(response) = over_quota(&state, caller.as_ref(), &note) {
+        return response;
+    }
+    // Requests for the same video take turns with those for others
+    let source = match &video {
+        Video::Upstream(path) => Some(path.clone()),
+        Video::Upload { .. } => None,
+    };
+    let queued = Instant::now();
+    let permit = match prefetch {
+        true => state.admission.acquire_spare().await,
+        false => state.admission.acquire(priority, source.as_deref(), deadline).await,
+    };
+    let queue_wait = queued.elapsed();
+    if !prefetch {
+        state.metrics.queue_waited(priority, queue_wait);
+    }
+    let permit = match permit {
+        Ok(permit) => permit,
+        Err(Rejection::DeadlineExceeded) => {
+            let class = ErrorClass::DeadlineExceeded;
+            warn!(outcome = class.as_str(), "Rejecting request: {}", Rejection::DeadlineExceeded);
+            note.outcome(class.as_str());
+            let error = ConversionError::DeadlineExceeded;
+            return ConversionFailure { class, error, stderr: None, placeholder: None }.into_response();
+        }
+        Err(rejection) => {
+            warn!("Rejecting request: {}", rejection);
+            note.outcome("overloaded");
+            return overloaded_response(state.admission.queue_wait_timeout());
+        }
+    };
+    // or made while it waited, by a conversion of the same GIF that was
+    // already going, e.g. the one a `Prefer: respond-async` job came after
+    if let Some(key) = &cache_key {
+        if let Some(response) = from_cache(&state, key, variant.as_ref(), &name, caller_name.as_ref(), &note, &range_headers, false).await {
+            return response;
+        }
+    }
+    if let Some(admitted) = admitted {
+        let _ = admitted.send(());
+    }
+
+    let mut options = ConversionOptions {
+        optimize: query.optimize(),
+        repeat: query.repeat,
+        trace: telemetry::trace_of(&Span::current(), caller_trace.as_ref().map(|Extension(trace)| trace)),
+        backend: state.pipeline().backends.registered(format),
+        max_width: query.width,
+        fps: query.fps,
+        progress,
+        background: query.bg,
+        deadline,
+        auto,
+        ..Default::default()
+    };
+    // Made now, so an admin can cancel the conversion before it's spawned
+    let cancel = state.conversions.token();
+    let registered = state.active.insert(active::Conversion {
+        request_id: request_id.0.clone(),
+        path: name.clone(),
+        source,
+        params: query.clone(),
+        variant: cache_key.clone(),
+        client,
+        api_key: caller.as_ref().map(|Extension(Caller(name))| name.to_string()),
+        priority,
+        received,
+        queue_wait,
+        timings: options.timings.clone(),
+        cancel: cancel.clone(),
+    });
+    let killed_by = registered.killed_by();
+    // What a cached GIF of it is revalidated against
+    let upstream = match &video {
+        Video::Upstream(path) => Some(Arc::new(Upstream::new(path.clone()))),
+        Video::Upload { .. } => None,
+    };
+    let (pipeline, source) = match &video {
+        Video::Upstream(path) => {
+            info!("New path: {}", path);
+            (state.pipeline().clone(), state.source_ref(path, &options))
+        }
+        Video::Upload { upload, .. } => {
+            // ffmpeg reads the file itself, so that it can seek in it, and
+            // it's already been held to MAX_UPLOAD_BYTES
+            let mut pipeline = (**state.pipeline()).clone();
+            pipeline.fetch_mode = FetchMode::Ffmpeg;
+            pipeline.max_input_bytes = None;
+            let source = pipeline.source_ref(&name, upload.file.path().display().to_string(), &options);
+            (Arc::new(pipeline), source)
+        }
+    };
+    // What the client can't ask for again the same way mustn't be cached
+    let cacheable = !matches!(&video, Video::Upload { idempotency_key: None, .. });
+    // The length is needed to enforce the limit, and to split the video into
+    // segments. A job only wants it to say how far along it is.
+    let needs_duration = state.max_input_duration.is_some() || pipeline.parallel_segments.is_some();
+    if needs_duration || options.progress.is_some() {
+        let probed = match &video {
+            // Nobody will ask about an upload again, so it isn't remembered
+            Video::Upload { .. } => state.prober.duration_once(&source.url).await,
+            Video::Upstream(_) => state.prober.duration(&pipeline.source_fetcher.probe_input(&source)).await,
+        };
+        match probed {
+            // The upstream may only have part of it so far, which converting
+            // it waits out
+            Ok(probed) if probed.unsupported == Some(CUT_SHORT) && pipeline.reads_from_upstream(&source) => {
+                note.cache("miss");
+                warn!("Probed {} before the upstream had all of it, converting it anyway", name);
+                if needs_duration {
+                    variant = None;
+                }
+            }
+            Ok(probed) => {
+                note.cache(if probed.cached { "hit" } else { "miss" });
+                // Not worth starting ffmpeg and the encoder for
+                if let Some(reason) = probed.unsupported {
+                    let error = ConversionError::UnsupportedSource(reason);
+                    warn!(outcome = "unsupported_source", "Refusing to convert {}: {}", name, error);
+                    note.outcome("unsupported_source");
+                    return ConversionFailure { class: ErrorClass::UnsupportedSource, error, stderr: None, placeholder: None }.into_response();
+                }
+                options.duration = probed.duration;
+                options.alpha = probed.alpha;
+            }
+            Err(e) => {
+                note.cache("miss");
+                warn!("Couldn't probe video duration, converting it anyway: {}", e);
+                // so it isn't converted the way its key says
+                if needs_duration {
+                    variant = None;
+                }
+            }
+        }
+    }
+    // What's asked for wins, as long as it's within the limit too
+    let start = query.start.map(|start| start.0);
+    let trimmed = match clip::clip(start, query.duration.map(|duration| duration.0), options.duration, state.max_input_duration, state.long_video_policy) {
+        Ok(clip) => {
+            if clip.trimmed.is_some() {
+                info!("Video is {:?} long, only converting {:?} of it", options.duration, clip.trim);
+            }
+            options.start = clip.start;
+            options.trim = clip.trim;
+            clip.trimmed
+        }
+        Err(too_long) => {
+            warn!(outcome = "input_too_long", "Video is {:?} long, refusing to convert it", too_long.length);
+            note.outcome("input_too_long");
+            return (StatusCode::UNPROCESSABLE_ENTITY, format!("Failed to process video: input_too_long ({})", too_long)).into_response();
+        }
+    };
+
+    // What the request asked for that the gifski binary has no flag for gets
+    // left out, and said so in a header, unless we're being strict about it
+    let ignored: Vec<&'static str> = match pipeline.encoder {
+        Encoder::Subprocess if query.repeat.is_some() && options.backend.is_none() => options
+            .gif_settings(&pipeline)
+            .unsupported(&pipeline.gifski_flags)
+            .into_iter()
+            .filter(|setting| *setting == "repeat")
+            .collect(),
+        _ => Vec::new(),
+    };
+    if !ignored.is_empty() {
+        if state.strict_params {
+            warn!(outcome = "unsupported_param", "This gifski can't do {}, refusing the request", ignored.join(", "));
+            note.outcome("unsupported_param");
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Failed to process video: unsupported_param (this server's gifski can't do {})", ignored.join(", ")),
+            )
+                .into_response();
+        }
+        info!("This gifski can't do {}, converting without", ignored.join(", "));
+    }
+
+    let degraded = settings
+        .load_tiers
+        .and_then(|tiers| tiers.degrade(state.conversions.in_flight()));
+    if let Some(degraded) = degraded {
+        info!(degraded = degraded.tier.header_value(), "Busy, converting with a reduced profile");
+        options.tier = Some(degraded.tier);
+    }
+
+    // The details an estimate probed are how big the GIF's frames are
+    let probe_url = match &video {
+        Video::Upstream(_) => Some(pipeline.source_fetcher.probe_input(&source)),
+        Video::Upload { .. } => None,
+    };
+
+    // The conversion runs in its own task so that it can clean up after itself. If the
+    // client disconnects, axum drops this future, the guard fires and the task kills
+    // ffmpeg and gifski instead of finishing a GIF nobody will receive. The task
+    // holds on to the permit, since in streaming mode it outlives this handler, and
+    // to the upload, which is removed once it's done with.
+    let upload = match video {
+        Video::Upload { upload, .. } => Some(upload),
+        Video::Upstream(_) => None,
+    };
+    let metrics = state.metrics.clone();
+    let admission = state.admission.clone();
+    let conversions = state.conversions.clone();
+    let timings = options.timings.clone();
+    let stderr_tail = options.stderr_tail.clone();
+    let slow_request_threshold = state.slow_request_threshold;
+    let slow_note = note.clone();
+    let audit = state.audit.clone();
+    let usage = state.usage.clone();
+    let prober = state.prober.clone();
+    let size_model = state.size_model.clone();
+    let backend = pipeline.backend(&options);
+    let output_format = backend.format();
+    // Degraded GIFs are whatever we could manage, not what the key stands for
+    let etag = variant.filter(|_| degraded.is_none()).map(|variant| variant.etag());
+    let (sink, chunks) = if state.stream_response {
+        let (tx, rx) = mpsc::channel(STREAM_CHANNEL_CHUNKS);
+        (Some(tx), Some(rx))
+    } else {
+        (None, None)
+    };
+    let task_killed_by = killed_by.clone();
+    let conversion = state.conversions.spawn(cancel, move |cancel| {
+        async move {
+            let _permit = permit;
+            let _client_slot = client_slot;
+            let _upload = upload;
+            let _registered = registered;
+            let started = Instant::now();
+            // A streamed variant has gone as it was made, so it can't wait for the others
+            let result = match shared.filter(|_| sink.is_none()) {
+                Some(member) => member.convert(&source, &pipeline, &options, cancel).await,
+                None => process_tweet_video(&source, &pipeline, &options, sink.clone(), cancel).await,
+            };
+            options.timings.snapshot().record(&Span::current());
+            if let Some(caller) = &caller_name {
+                usage.converted(caller, options.timings.snapshot().cpu, result.as_ref().map_or(0, GifOutput::len));
+            }
+            if let Ok(gif) = &result {
+                options.stderr_tail.clear();
+                let took = started.elapsed();
+                metrics.conversion_finished(took, gif.len());
+                if let Some(path) = options.timings.snapshot().decode {
+                    metrics.decoded(path);
+                }
+                // What it came to goes towards the next estimate, if there's
+                // enough to tell what it was up against
+                let settings = options.gif_settings(&pipeline);
+                let duration = fastgif_core::segment::length(&options);
+                let shape = probe_url
+                    .as_deref()
+                    .and_then(|url| prober.cached_details(url))
+                    .and_then(|details| Shape::of(&details, &settings, options.start, options.trim))
+                    .or_else(|| match gif {
+                        GifOutput::Memory(bytes) => Shape::of_gif(bytes, duration?, settings.fps),
+                        _ => None,
+                    });
+                if let Some(shape) = shape {
+                    size_model.learn(output_format, settings.quality, shape, gif.len(), took);
+                }
+                admission.conversion_took(took);
+                if let Some(pressure) = admission.pressure() {
+                    pressure.record_latency(took);
+                }
+            }
+            // Cancelled while we're not shutting down means nobody's waiting
+            // anymore, unless an admin did it
+            let class = result.as_ref().err().map(|e| match e {
+                ConversionError::Cancelled if task_killed_by.get().is_some() => ErrorClass::CancelledByAdmin,
+                ConversionError::Cancelled if !conversions.is_draining() => ErrorClass::ClientAbort,
+                e => ErrorClass::of(e),
+            });
+            if let (Err(e), Some(class)) = (&result, class) {
+                // The access log counts clients going away, since it sees them all
+                if class != ErrorClass::ClientAbort {
+                    metrics.conversion_failed(class);
+                }
+                error_report::report(e, class, &request_id.0, &name, &options.stderr_tail);
+            }
+            let took = received.elapsed();
+            if slow_request_threshold.is_some_and(|threshold| took > threshold) {
+                metrics.slow_request();
+                // A failure's outcome says why it failed instead
+                slow_note.outcome("slow");
+                log_slow_request(&name, &pipeline, &options, took, queue_wait, &result);
+            }
+            if let Some(audit) = &audit {
+                audit.record(audit::Record {
+                    request_id: request_id.0.clone(),
+                    client_ip: client,
+                    // A certificate stands in for a key, so there isn't one to name
+                    api_key: caller
+                        .filter(|_| client_cert.is_none())
+                        .map(|Extension(Caller(name))| name.to_string()),
+                    client_cert: client_cert.and_then(|Extension(cert)| cert.name().map(str::to_string)),
+                    path: name.clone(),
+                    url: source.url.clone(),
+                    params: audit::Params {
+                        optimize: options.optimize,
+                        repeat: options.repeat,
+                        trim_ms: options.trim.map(|trim| trim.as_millis() as u64),
+                        tier: options.tier.map(Tier::header_value),
+                    },
+                    outcome: class.map_or("ok", ErrorClass::as_str),
+                    duration_ms: took.as_millis() as u64,
+                    input_bytes: options.timings.snapshot().input_bytes,
+                    output_bytes: result.as_ref().ok().map(GifOutput::len),
+                    cancelled_by_admin: task_killed_by.get().copied(),
+                    compared: None,
+                });
+            }
+            // Once a streamed response has started, this is the only way left to tell
+            // the client something went wrong: the body ends in an error, so they see
+            // a broken transfer instead of a "complete" truncated GIF
+            if let (Err(e), Some(sink)) = (&result, sink) {
+                let _ = sink.send(Err(std::io::Error::other(e.to_string()))).await;
+            }
+            result
+        }
+        .instrument(Span::current())
+    });
+    let cancel_on_drop = conversion.cancel_on_drop();
+
+    // Streaming starts as soon as gifski produces its first bytes. Anything that goes
+    // wrong before then still gets a proper error response.
+    if let Some(mut chunks) = chunks {
+        if let Some(Ok(first_chunk)) = chunks.recv().await {
+            info!("Streaming GIF to the client as it's encoded");
+            note.served(Served::Conversion);
+            let body = StreamedBody::new(first_chunk, chunks, cancel_on_drop);
+            let response = gif_response(Body::from_stream(body), None, trimmed, &ignored, backend.as_ref(), degraded, &state.pacing);
+            let response = with_auto_quality(response, auto);
+            let response = with_caching(response, cacheable, etag.as_deref());
+            let response = match &cache_key {
+                Some(_) => with_cache_status(response, "MISS"),
+                None => response,
+            };
+            let stages = timings.snapshot();
+            return with_decode(with_server_timing(response, &stages.server_timing(queue_wait)), stages.decode);
+        }
+    }
+
+    let result = conversion
+        .await
+        .unwrap_or_else(|e| Err(ConversionError::Internal(format!("Conversion task failed: {}", e))));
+    cancel_on_drop.disarm();
+
+    match result {
+        Ok(gif_data) => {
+            info!("Successfully converted video to GIF ({} bytes)", gif_data.len());
+            note.served(Served::Conversion);
+            let len = gif_data.len();
+            // Only what the key stands for is kept, and in memory only what's already there
+            if let (Some(cache), Some(key), Some(_), GifOutput::Memory(body)) = (&state.cache, &cache_key, &etag, &gif_data) {
+                let gif = CachedGif {
+                    body: body.clone(),
+                    backend: backend.clone(),
+                    trimmed,
+                    ignored: ignored.clone(),
+                    prefetched: AtomicBool::new(prefetch && !refresh),
+                    stale: AtomicBool::default(),
+                    upstream: upstream.clone(),
+                };
+                cache.put(key.clone(), gif);
+            }
+            // A stale GIF that couldn't be replaced in memory isn't sent any more
+            if let (Some(cache), Some(key), Some(_)) = (&state.cache, &cache_key, &etag) {
+                if cache.get(key).is_some_and(|gif| gif.stale.load(Ordering::Relaxed)) {
+                    cache.purge(key, Purge::Hard);
+                }
+            }
+            if let (Some(disk_cache), Some(key), Some(_)) = (&state.disk_cache, &cache_key, &etag) {
+                let contents = match &gif_data {
+                    GifOutput::Memory(body) => Some(Contents::Memory(body.clone())),
+                    GifOutput::Spilled { file, .. } => tokio::fs::File::open(file.path()).await.ok().map(Contents::File),
+                    GifOutput::Streamed { .. } => None,
+                };
+                if let Some(contents) = contents {
+                    disk_cache.put(key.clone(), contents, backend.clone(), trimmed, ignored.clone(), prefetch && !refresh, upstream);
+                }
+            }
+            let response = gif_response(gif_body(gif_data), Some(len), trimmed, &ignored, backend.as_ref(), degraded, &state.pacing);
+            let response = with_auto_quality(response, auto);
+            let response = with_caching(response, cacheable, etag.as_deref());
+            let response = match &cache_key {
+                Some(_) => with_cache_status(response, "MISS"),
+                None => response,
+            };
+            let stages = timings.snapshot();
+            with_decode(with_server_timing(response, &stages.server_timing(queue_wait)), stages.decode)
+        }
+        Err(error) => {
+            // Only shutdown or an admin can cancel a conversion while we're
+            // still waiting on it
+            let class = match &error {
+                ConversionError::Cancelled if killed_by.get().is_some() => ErrorClass::CancelledByAdmin,
+                error => ErrorClass::of(error),
+            };
+            note.outcome(class.as_str());
+            let stderr = stderr_tail.snapshot();
+            if class == ErrorClass::Shutdown {
+                warn!(outcome = class.as_str(), "Conversion cancelled by shutdown");
+            } else if class == ErrorClass::CancelledByAdmin {
+                warn!(outcome = class.as_str(), "Conversion cancelled by an admin");
+            } else {
+                error!(outcome = class.as_str(), stderr = ?stderr, "Failed to process video: {}", error);
+            }
+            let strict = query.strict();
+            ConversionFailure {
+                class,
+                error,
+                stderr: state.error_detail.then_some(stderr),
+                placeholder: state.failure_placeholder.clone().filter(|_| !strict),
+            }
+            .into_response()
+        }
+    }
+}
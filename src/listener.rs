@@ -0,0 +1,279 @@
+use crate::tls::ClientCert;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// One of the places we listen (`BIND`): an address to take with `PORT`, an
+/// address and port like `[::]:3000`, or with `unix:/run/fastgif.sock` a unix
+/// socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bind {
+    Ip(IpAddr),
+    Addr(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Bind {
+    /// The address to listen on, for a TCP listener.
+    pub fn tcp(&self, port: u16) -> Option<SocketAddr> {
+        match self {
+            Bind::Ip(ip) => Some(SocketAddr::new(*ip, port)),
+            Bind::Addr(addr) => Some(*addr),
+            Bind::Unix(_) => None,
+        }
+    }
+}
+
+impl FromStr for Bind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("expected a path after unix:".to_string()),
+            Some(path) => Ok(Bind::Unix(path.into())),
+            None => s
+                .parse()
+                .map(Bind::Addr)
+                .or_else(|_| s.parse().map(Bind::Ip))
+                .map_err(|_| format!("{:?} is neither an address, an address:port nor unix:<path>", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Bind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Bind::Ip(ip) => ip.fmt(f),
+            Bind::Addr(addr) => addr.fmt(f),
+            Bind::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The address the listeners that have to be TCP (`METRICS_PORT` and
+/// `HTTP_PORT`) take: that of the first `BIND` with one, or all of them.
+pub fn tcp_ip(binds: &[Bind]) -> IpAddr {
+    binds
+        .iter()
+        .find_map(|bind| bind.tcp(0))
+        .map_or(Ipv4Addr::UNSPECIFIED.into(), |addr| addr.ip())
+}
+
+/// Which of our listeners a request came in on, as it appears in `BIND`.
+#[derive(Debug, Clone)]
+pub struct ListenerTag(pub Arc<Bind>);
+
+/// Only answer requests that came in on one of `allowed`
+/// (`ADMIN_LISTENERS`). Anywhere else the route doesn't exist.
+pub async fn only_on(State(allowed): State<Arc<[Bind]>>, request: Request, next: Next) -> Response {
+    let tag = request.extensions().get::<ListenerTag>();
+    if tag.is_some_and(|tag| allowed.contains(&tag.0)) {
+        return next.run(request).await;
+    }
+    (StatusCode::NOT_FOUND, format!("404 Not Found: {}", request.uri())).into_response()
+}
+
+/// Who's on the other end of a connection, for `ConnectInfo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// Whatever connected to our unix socket, which has no address to speak of
+    Unix,
+}
+
+impl Peer {
+    /// The address to treat the peer as having. Only something on this machine
+    /// can reach a unix socket, so that counts as loopback, which is also what
+    /// `TRUST_PROXY` has to include to believe a proxy in front of one.
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            Peer::Tcp(addr) => addr.ip(),
+            Peer::Unix => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        }
+    }
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Tcp(addr) => addr.fmt(f),
+            Peer::Unix => f.write_str("unix socket client"),
+        }
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Peer::Tcp(addr)
+    }
+}
+
+#[cfg(unix)]
+impl From<tokio::net::unix::SocketAddr> for Peer {
+    fn from(_: tokio::net::unix::SocketAddr) -> Self {
+        Peer::Unix
+    }
+}
+
+/// A connection's `ConnectInfo`: who it's from, and what their certificate
+/// says if they had to present one. [`connection_info`] splits it up for
+/// everything after, which only ever wants one or the other.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub peer: Peer,
+    pub client_cert: Option<ClientCert>,
+}
+
+impl From<Peer> for Connection {
+    fn from(peer: Peer) -> Self {
+        Self { peer, client_cert: None }
+    }
+}
+
+impl From<SocketAddr> for Connection {
+    fn from(addr: SocketAddr) -> Self {
+        Peer::from(addr).into()
+    }
+}
+
+#[cfg(unix)]
+impl From<tokio::net::unix::SocketAddr> for Connection {
+    fn from(addr: tokio::net::unix::SocketAddr) -> Self {
+        Peer::from(addr).into()
+    }
+}
+
+/// Middleware putting the connection's peer in the request as
+/// `ConnectInfo<Peer>`, and its client certificate, if any, as a
+/// [`ClientCert`].
+pub async fn connection_info(ConnectInfo(connection): ConnectInfo<Connection>, mut request: Request, next: Next) -> Response {
+    request.extensions_mut().insert(ConnectInfo(connection.peer));
+    if let Some(client_cert) = connection.client_cert {
+        request.extensions_mut().insert(client_cert);
+    }
+    next.run(request).await
+}
+
+/// Who owns the unix socket (`SOCKET_OWNER`): `user`, `user:group` or
+/// `:group`, by name or number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FromStr for Owner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (s, None),
+        };
+        let uid = match user {
+            "" => None,
+            user => Some(user.parse().or_else(|_| users::uid(user))?),
+        };
+        let gid = match group {
+            None | Some("") => None,
+            Some(group) => Some(group.parse().or_else(|_| users::gid(group))?),
+        };
+        if uid.is_none() && gid.is_none() {
+            return Err("expected user, user:group or :group".to_string());
+        }
+        Ok(Owner { uid, gid })
+    }
+}
+
+/// A 3 or 4 digit octal file mode like `660` (`SOCKET_MODE`).
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("{:?} isn't an octal file mode", s))
+}
+
+#[cfg(unix)]
+mod users {
+    use std::ffi::CString;
+
+    pub fn uid(name: &str) -> Result<u32, String> {
+        let c_name = CString::new(name).map_err(|_| format!("no user {:?}", name))?;
+        // Only looked up while reading the configuration, before anything
+        // else could be using the user database
+        let entry = unsafe { libc::getpwnam(c_name.as_ptr()) };
+        if entry.is_null() {
+            return Err(format!("no user {:?}", name));
+        }
+        Ok(unsafe { (*entry).pw_uid })
+    }
+
+    pub fn gid(name: &str) -> Result<u32, String> {
+        let c_name = CString::new(name).map_err(|_| format!("no group {:?}", name))?;
+        let entry = unsafe { libc::getgrnam(c_name.as_ptr()) };
+        if entry.is_null() {
+            return Err(format!("no group {:?}", name));
+        }
+        Ok(unsafe { (*entry).gr_gid })
+    }
+}
+
+#[cfg(not(unix))]
+mod users {
+    pub fn uid(name: &str) -> Result<u32, String> {
+        Err(format!("can't look up user {:?} here", name))
+    }
+
+    pub fn gid(name: &str) -> Result<u32, String> {
+        Err(format!("can't look up group {:?} here", name))
+    }
+}
+
+/// Our unix socket's file, which is removed once we're done with it.
+#[cfg(unix)]
+pub struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            tracing::warn!("Couldn't remove the socket {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Listen on a unix socket at `path`, replacing one a previous run left
+/// behind. One that something is still listening on is left alone, and so is
+/// anything at `path` that isn't a socket at all.
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path, mode: Option<u32>, owner: Option<Owner>) -> anyhow::Result<(tokio::net::UnixListener, SocketFile)> {
+    use anyhow::anyhow;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(existing) = std::fs::symlink_metadata(path) {
+        if !existing.file_type().is_socket() {
+            return Err(anyhow!("{} already exists and isn't a socket", path.display()));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow!("Something is already listening on {}", path.display()));
+        }
+        tracing::info!("Removing the stale socket {}", path.display());
+        std::fs::remove_file(path).map_err(|e| anyhow!("Couldn't remove the stale socket {}: {}", path.display(), e))?;
+    }
+    let listener = tokio::net::UnixListener::bind(path).map_err(|e| anyhow!("Couldn't listen on {}: {}", path.display(), e))?;
+    let file = SocketFile(path.to_path_buf());
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .map_err(|e| anyhow!("Couldn't set the mode of {}: {}", path.display(), e))?;
+    }
+    if let Some(owner) = owner {
+        std::os::unix::fs::chown(path, owner.uid, owner.gid)
+            .map_err(|e| anyhow!("Couldn't change the owner of {}: {}", path.display(), e))?;
+    }
+    Ok((listener, file))
+}
@@ -0,0 +1,126 @@
+use crate::access_log::AccessNote;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use bytes::Bytes;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+
+/// The route of the GIFs themselves.
+pub const GIF_ROUTE: &str = "/tweet_video/{path}";
+
+/// Maintenance mode (`MAINTENANCE`, `POST /admin/maintenance`): new
+/// conversions are turned away while everything else keeps going, including
+/// the conversions already running.
+#[derive(Debug)]
+pub struct Maintenance {
+    /// When it was turned on, while it's on
+    since: Mutex<Option<String>>,
+    refusal: Refusal,
+}
+
+/// What conversions are refused with.
+#[derive(Debug)]
+pub struct Refusal {
+    /// `MAINTENANCE_MESSAGE`
+    pub message: String,
+    /// A GIF to send instead of the JSON error (`MAINTENANCE_GIF`)
+    pub gif: Option<Bytes>,
+    /// `MAINTENANCE_RETRY_AFTER`
+    pub retry_after: Duration,
+}
+
+/// What `GET /admin/maintenance` and `/stats` say.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub enabled: bool,
+    /// When it was turned on, while it's on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+}
+
+impl Maintenance {
+    pub fn new(enabled: bool, refusal: Refusal) -> Self {
+        Self { since: Mutex::new(enabled.then(now)), refusal }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.since.lock().unwrap().is_some()
+    }
+
+    /// The `503` a conversion is refused with. It isn't to be cached, since
+    /// the same URL works again once it's over.
+    pub fn refusal(&self, note: &AccessNote) -> Response {
+        note.outcome("maintenance");
+        let refusal = &self.refusal;
+        let headers = [
+            (header::RETRY_AFTER, refusal.retry_after.as_secs().max(1).to_string()),
+            (header::CACHE_CONTROL, "no-store".to_string()),
+        ];
+        match &refusal.gif {
+            Some(gif) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                headers,
+                [(header::CONTENT_TYPE, "image/gif")],
+                gif.clone(),
+            )
+                .into_response(),
+            None => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                headers,
+                Json(MaintenanceError { error: "maintenance", message: &refusal.message }),
+            )
+                .into_response(),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let since = self.since.lock().unwrap().clone();
+        Status { enabled: since.is_some(), since }
+    }
+
+    /// Turn it on or off. Doing either twice changes nothing, so `since`
+    /// stays when it was first turned on.
+    pub fn set(&self, enabled: bool) -> Status {
+        let mut since = self.since.lock().unwrap();
+        if since.is_some() != enabled {
+            if enabled {
+                warn!("Maintenance mode on: refusing new conversions");
+            } else {
+                warn!("Maintenance mode off: taking conversions again");
+            }
+            *since = enabled.then(now);
+        }
+        Status { enabled, since: since.clone() }
+    }
+}
+
+fn now() -> String {
+    let mut timestamp = String::new();
+    let _ = tracing_subscriber::fmt::time::SystemTime.format_time(&mut Writer::new(&mut timestamp));
+    timestamp
+}
+
+#[derive(Serialize)]
+struct MaintenanceError<'a> {
+    error: &'static str,
+    message: &'a str,
+}
+
+/// Middleware refusing conversions while in maintenance mode. A GIF
+/// (`GET /tweet_video/{path}`) is let through, to be sent if it's cached:
+/// the handler refuses it once it's found not to be.
+pub async fn refuse(State(maintenance): State<Arc<Maintenance>>, request: Request, next: Next) -> Response {
+    let gif = request.method() == Method::GET && request.extensions().get::<MatchedPath>().is_some_and(|path| path.as_str() == GIF_ROUTE);
+    if !maintenance.is_on() || gif {
+        return next.run(request).await;
+    }
+    let note = request.extensions().get::<AccessNote>().cloned().unwrap_or_default();
+    maintenance.refusal(&note)
+}
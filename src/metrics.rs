@@ -0,0 +1,796 @@
+use crate::admission::Priority;
+use crate::rolling::RollingHistogram;
+use crate::statsd::Statsd;
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use fastgif_core::backend::Format;
+use fastgif_core::dns::DnsStats;
+use fastgif_core::error_class::ErrorClass;
+use fastgif_core::hwaccel::{self, Hwaccel};
+use fastgif_core::metrics::ConversionMetrics;
+use fastgif_core::process::ResourceUsage;
+use fastgif_core::upstream::Transfer;
+use prometheus::core::Metric as _;
+use prometheus::{
+    exponential_buckets, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry,
+    TextEncoder,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The routes requests are counted under. Anything else is `unmatched`.
+const ROUTES: [&str; 15] = [
+    "/tweet_video/{path}",
+    "/convert",
+    "/batch",
+    "/prefetch",
+    "/jobs",
+    "/jobs/{id}",
+    "/jobs/{id}/result",
+    "/jobs/{id}/events",
+    "/ws/jobs/{id}",
+    "/stats",
+    "/healthz",
+    "/version",
+    "/metrics",
+    "/admin/log-level",
+    "unmatched",
+];
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+/// Children we count failures of, and the ways they can fail.
+const PROCESSES: [&str; 4] = ["ffmpeg", "gifski", "ffprobe", "gifsicle"];
+const FAILURE_REASONS: [&str; 2] = ["failed", "resource_limit"];
+
+// The children whose resource usage is measured as they exit
+const MEASURED_PROCESSES: [&str; 2] = ["ffmpeg", "gifski"];
+
+/// Caches whose hits and misses are counted.
+const CACHES: [&str; 5] = ["probe", "dns", "gif", "sprite", "frame"];
+
+/// The ways a request to the upstream can go wrong.
+const UPSTREAM_ERRORS: [&str; 5] = ["connect", "timeout", "status", "body", "other"];
+
+/// Which address family an upstream response came over.
+const FAMILIES: [&str; 2] = ["ipv4", "ipv6"];
+
+/// What an upstream request's time is split into.
+const UPSTREAM_PHASES: [&str; 4] = ["dns", "connect", "ttfb", "download"];
+
+/// When a client can go away: before its response started, or during it.
+const ABORT_STAGES: [&str; 2] = ["before_response", "during_response"];
+
+/// What the janitor removes.
+const JANITOR_KINDS: [&str; 2] = ["temp_file", "job"];
+
+/// What becomes of a prefetch: its GIF is made, it was already cached or
+/// being made, too many were pending already, it wasn't let in before
+/// `QUEUE_WAIT_TIMEOUT`, or the conversion failed.
+const PREFETCH_OUTCOMES: [&str; 6] = ["converted", "cached", "in_flight", "full", "skipped", "failed"];
+
+// What asking the upstream about a cached GIF's video again can come to
+const REVALIDATION_OUTCOMES: [&str; 3] = ["current", "gone", "unknown"];
+
+// What a video the upstream served without its `moov` atom comes to: asked
+// for again, then converted on one of those or given up on
+const MOOV_OUTCOMES: [&str; 3] = ["retried", "recovered", "gave_up"];
+
+// How a conversion tried again after gifski crashed went
+const ENCODER_RETRY_OUTCOMES: [&str; 2] = ["succeeded", "failed"];
+
+/// Running totals since the server started, for `/stats` and the periodic
+/// summary, which reports the difference between two of them.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Totals {
+    pub requests: u64,
+    /// Conversions that succeeded
+    pub conversions: u64,
+    /// Conversions that failed, by error class
+    pub errors: BTreeMap<&'static str, u64>,
+    /// Requests whose client went away
+    pub client_aborts: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Of those, the GIF caches'
+    pub gif_cache_hits: u64,
+    pub gif_cache_misses: u64,
+    /// Response bytes sent to clients
+    pub bytes_served: u64,
+    /// How many successful conversions took at most each bucket's bound, in
+    /// seconds
+    #[serde(skip)]
+    pub durations: Vec<(f64, u64)>,
+}
+
+/// Who `/stats` answers (`STATS_ACCESS`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsAccess {
+    /// Everything, to anyone, the default
+    Public,
+    /// The summary to anyone, and everything with `ADMIN_TOKEN`
+    Summary,
+    /// Nobody without `ADMIN_TOKEN`
+    Admin,
+}
+
+impl std::str::FromStr for StatsAccess {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(StatsAccess::Public),
+            "summary" => Ok(StatsAccess::Summary),
+            "admin" => Ok(StatsAccess::Admin),
+            other => Err(format!("unknown stats access {:?}, expected public, summary or admin", other)),
+        }
+    }
+}
+
+/// Everything `/metrics` reports. Every series is created here, up front, so
+/// recording never has to register anything and a scrape only reads values.
+/// With `STATSD_ADDR` everything recorded is sent to StatsD as well.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    conversion_duration: Histogram,
+    queue_wait: HistogramVec,
+    output_bytes: Histogram,
+    child_user_cpu: HistogramVec,
+    child_system_cpu: HistogramVec,
+    child_max_rss: HistogramVec,
+    in_flight: IntGauge,
+    queued: IntGaugeVec,
+    cache_hits: IntCounterVec,
+    cache_misses: IntCounterVec,
+    upstream_errors: IntCounterVec,
+    upstream_responses: IntCounterVec,
+    upstream_duration: HistogramVec,
+    upstream_fetched_bytes: IntCounterVec,
+    upstream_statuses: IntCounterVec,
+    subprocess_failures: IntCounterVec,
+    invalid_outputs: IntCounterVec,
+    decodes: IntCounterVec,
+    hwaccel_fallbacks: IntCounterVec,
+    moov_not_found: IntCounterVec,
+    encoder_retries: IntCounterVec,
+    conversion_errors: IntCounterVec,
+    client_aborts: IntCounterVec,
+    slow_requests: IntCounter,
+    audit_dropped: IntCounter,
+    janitor_removed: IntCounterVec,
+    janitor_reclaimed_bytes: IntCounter,
+    prefetches: IntCounterVec,
+    prefetch_hits: IntCounter,
+    revalidations: IntCounterVec,
+    served_bytes: IntCounter,
+    /// Successful conversions' durations over the last `RECENT_WINDOW`, for
+    /// `/stats`
+    recent_durations: RollingHistogram,
+    started: Instant,
+    statsd: Option<Statsd>,
+}
+
+/// How far back `/stats` looks for its conversion percentiles.
+pub const RECENT_WINDOW: Duration = Duration::from_secs(300);
+
+impl Metrics {
+    pub fn new(statsd: Option<Statsd>) -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new("fastgif_http_requests_total", "HTTP requests by route and status class"),
+            &["route", "status"],
+        )
+        .unwrap();
+        let conversion_duration = Histogram::with_opts(
+            HistogramOpts::new("fastgif_conversion_duration_seconds", "Time taken by successful conversions")
+                .buckets(exponential_buckets(0.25, 2.0, 10).unwrap()),
+        )
+        .unwrap();
+        let queue_wait = HistogramVec::new(
+            HistogramOpts::new("fastgif_queue_wait_seconds", "Time spent waiting for a conversion slot, by priority")
+                .buckets(vec![0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["priority"],
+        )
+        .unwrap();
+        let served_bytes = IntCounter::new("fastgif_served_bytes_total", "Response bytes sent to clients").unwrap();
+        let output_bytes = Histogram::with_opts(
+            HistogramOpts::new("fastgif_output_bytes", "Size of the GIFs produced")
+                .buckets(exponential_buckets(64.0 * 1024.0, 2.0, 10).unwrap()),
+        )
+        .unwrap();
+        let child_user_cpu = HistogramVec::new(
+            HistogramOpts::new("fastgif_child_user_cpu_seconds", "User CPU time each child process used, by process")
+                .buckets(exponential_buckets(0.01, 2.0, 14).unwrap()),
+            &["process"],
+        )
+        .unwrap();
+        let child_system_cpu = HistogramVec::new(
+            HistogramOpts::new("fastgif_child_system_cpu_seconds", "System CPU time each child process used, by process")
+                .buckets(exponential_buckets(0.01, 2.0, 14).unwrap()),
+            &["process"],
+        )
+        .unwrap();
+        let child_max_rss = HistogramVec::new(
+            HistogramOpts::new("fastgif_child_max_rss_bytes", "Peak resident memory of each child process, by process")
+                .buckets(exponential_buckets(4.0 * 1024.0 * 1024.0, 2.0, 10).unwrap()),
+            &["process"],
+        )
+        .unwrap();
+        let in_flight = IntGauge::new("fastgif_conversions_in_flight", "Conversions currently running").unwrap();
+        let queued = IntGaugeVec::new(
+            Opts::new("fastgif_conversions_queued", "Conversions waiting for a slot, by priority"),
+            &["priority"],
+        )
+        .unwrap();
+        let cache_hits =
+            IntCounterVec::new(Opts::new("fastgif_cache_hits_total", "Cache hits by cache"), &["cache"]).unwrap();
+        let cache_misses =
+            IntCounterVec::new(Opts::new("fastgif_cache_misses_total", "Cache misses by cache"), &["cache"]).unwrap();
+        let upstream_errors = IntCounterVec::new(
+            Opts::new("fastgif_upstream_errors_total", "Failed requests to the upstream by kind"),
+            &["kind"],
+        )
+        .unwrap();
+        let upstream_responses = IntCounterVec::new(
+            Opts::new("fastgif_upstream_responses_total", "Requests to the upstream that got a response, by address family"),
+            &["family"],
+        )
+        .unwrap();
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new("fastgif_upstream_duration_seconds", "Time upstream requests spent in each phase, by host")
+                .buckets(vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            &["host", "phase"],
+        )
+        .unwrap();
+        let upstream_fetched_bytes = IntCounterVec::new(
+            Opts::new("fastgif_upstream_fetched_bytes_total", "Bytes of response bodies read from the upstream, by host"),
+            &["host"],
+        )
+        .unwrap();
+        let upstream_statuses = IntCounterVec::new(
+            Opts::new("fastgif_upstream_statuses_total", "Upstream responses by host and status class"),
+            &["host", "status"],
+        )
+        .unwrap();
+        let subprocess_failures = IntCounterVec::new(
+            Opts::new("fastgif_subprocess_failures_total", "Child processes that failed, by process and reason"),
+            &["process", "reason"],
+        )
+        .unwrap();
+        let invalid_outputs = IntCounterVec::new(
+            Opts::new("fastgif_invalid_outputs_total", "Conversions whose output failed the integrity check, by format"),
+            &["format"],
+        )
+        .unwrap();
+
+        let decodes = IntCounterVec::new(
+            Opts::new("fastgif_decodes_total", "Successful conversions by what ffmpeg decoded them with"),
+            &["path"],
+        )
+        .unwrap();
+        let hwaccel_fallbacks = IntCounterVec::new(
+            Opts::new("fastgif_hwaccel_fallbacks_total", "Conversions retried in software after failing on a hardware decoder"),
+            &["hwaccel"],
+        )
+        .unwrap();
+        let moov_not_found = IntCounterVec::new(
+            Opts::new("fastgif_moov_not_found_total", "Upstream videos ffmpeg found no moov atom in, by what became of them"),
+            &["outcome"],
+        )
+        .unwrap();
+        let encoder_retries = IntCounterVec::new(
+            Opts::new("fastgif_encoder_retries_total", "Conversions tried again after gifski crashed, by how the retry went"),
+            &["outcome"],
+        )
+        .unwrap();
+
+        let conversion_errors = IntCounterVec::new(
+            Opts::new("fastgif_conversion_errors_total", "Failed conversions by error class"),
+            &["class"],
+        )
+        .unwrap();
+
+        let client_aborts = IntCounterVec::new(
+            Opts::new("fastgif_client_aborts_total", "Requests whose client went away, by when"),
+            &["stage"],
+        )
+        .unwrap();
+
+        let slow_requests = IntCounter::new(
+            "fastgif_slow_requests_total",
+            "Conversions that took longer than SLOW_REQUEST_THRESHOLD",
+        )
+        .unwrap();
+
+        let audit_dropped = IntCounter::new(
+            "fastgif_audit_records_dropped_total",
+            "Audit log records dropped because the writer fell behind or couldn't write",
+        )
+        .unwrap();
+
+        let janitor_removed = IntCounterVec::new(
+            Opts::new("fastgif_janitor_removed_total", "Leftovers the janitor removed, by kind"),
+            &["kind"],
+        )
+        .unwrap();
+        let janitor_reclaimed_bytes = IntCounter::new(
+            "fastgif_janitor_reclaimed_bytes_total",
+            "Bytes of disk the janitor freed by removing leftover temp files",
+        )
+        .unwrap();
+
+        let prefetches = IntCounterVec::new(Opts::new("fastgif_prefetches_total", "Prefetches asked for, by outcome"), &["outcome"]).unwrap();
+        let revalidations = IntCounterVec::new(
+            Opts::new("fastgif_revalidations_total", "Cached GIFs' videos asked about again upstream, by outcome"),
+            &["outcome"],
+        )
+        .unwrap();
+        let prefetch_hits = IntCounter::new(
+            "fastgif_prefetch_hits_total",
+            "Prefetched GIFs that were asked for, each counted the first time it's sent from the cache",
+        )
+        .unwrap();
+
+        for route in ROUTES {
+            for status in STATUS_CLASSES {
+                requests.with_label_values(&[route, status]);
+            }
+        }
+        for cache in CACHES {
+            cache_hits.with_label_values(&[cache]);
+            cache_misses.with_label_values(&[cache]);
+        }
+        for kind in UPSTREAM_ERRORS {
+            upstream_errors.with_label_values(&[kind]);
+        }
+        for family in FAMILIES {
+            upstream_responses.with_label_values(&[family]);
+        }
+        for process in MEASURED_PROCESSES {
+            child_user_cpu.with_label_values(&[process]);
+            child_system_cpu.with_label_values(&[process]);
+            child_max_rss.with_label_values(&[process]);
+        }
+        for process in PROCESSES {
+            for reason in FAILURE_REASONS {
+                subprocess_failures.with_label_values(&[process, reason]);
+            }
+        }
+        for format in [Format::Gif, Format::Webp, Format::Webm] {
+            invalid_outputs.with_label_values(&[format.as_str()]);
+        }
+        decodes.with_label_values(&[hwaccel::SOFTWARE]);
+        for hwaccel in Hwaccel::ALL {
+            decodes.with_label_values(&[hwaccel.as_str()]);
+            hwaccel_fallbacks.with_label_values(&[hwaccel.as_str()]);
+        }
+        // A client going away isn't a failure of ours, and has a counter of its own
+        for class in ErrorClass::ALL.into_iter().filter(|class| *class != ErrorClass::ClientAbort) {
+            conversion_errors.with_label_values(&[class.as_str()]);
+        }
+        for stage in ABORT_STAGES {
+            client_aborts.with_label_values(&[stage]);
+        }
+        for kind in JANITOR_KINDS {
+            janitor_removed.with_label_values(&[kind]);
+        }
+        for outcome in PREFETCH_OUTCOMES {
+            prefetches.with_label_values(&[outcome]);
+        }
+        for outcome in REVALIDATION_OUTCOMES {
+            revalidations.with_label_values(&[outcome]);
+        }
+        for outcome in MOOV_OUTCOMES {
+            moov_not_found.with_label_values(&[outcome]);
+        }
+        for outcome in ENCODER_RETRY_OUTCOMES {
+            encoder_retries.with_label_values(&[outcome]);
+        }
+        for priority in Priority::ALL {
+            queue_wait.with_label_values(&[priority.as_str()]);
+            queued.with_label_values(&[priority.as_str()]);
+        }
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(conversion_duration.clone())).unwrap();
+        registry.register(Box::new(queue_wait.clone())).unwrap();
+        registry.register(Box::new(output_bytes.clone())).unwrap();
+        registry.register(Box::new(child_user_cpu.clone())).unwrap();
+        registry.register(Box::new(child_system_cpu.clone())).unwrap();
+        registry.register(Box::new(child_max_rss.clone())).unwrap();
+        registry.register(Box::new(in_flight.clone())).unwrap();
+        registry.register(Box::new(queued.clone())).unwrap();
+        registry.register(Box::new(cache_hits.clone())).unwrap();
+        registry.register(Box::new(cache_misses.clone())).unwrap();
+        registry.register(Box::new(upstream_errors.clone())).unwrap();
+        registry.register(Box::new(upstream_responses.clone())).unwrap();
+        registry.register(Box::new(upstream_duration.clone())).unwrap();
+        registry.register(Box::new(upstream_fetched_bytes.clone())).unwrap();
+        registry.register(Box::new(upstream_statuses.clone())).unwrap();
+        registry.register(Box::new(subprocess_failures.clone())).unwrap();
+        registry.register(Box::new(invalid_outputs.clone())).unwrap();
+        registry.register(Box::new(decodes.clone())).unwrap();
+        registry.register(Box::new(hwaccel_fallbacks.clone())).unwrap();
+        registry.register(Box::new(moov_not_found.clone())).unwrap();
+        registry.register(Box::new(encoder_retries.clone())).unwrap();
+        registry.register(Box::new(conversion_errors.clone())).unwrap();
+        registry.register(Box::new(client_aborts.clone())).unwrap();
+        registry.register(Box::new(slow_requests.clone())).unwrap();
+        registry.register(Box::new(audit_dropped.clone())).unwrap();
+        registry.register(Box::new(janitor_removed.clone())).unwrap();
+        registry.register(Box::new(janitor_reclaimed_bytes.clone())).unwrap();
+        registry.register(Box::new(prefetches.clone())).unwrap();
+        registry.register(Box::new(prefetch_hits.clone())).unwrap();
+        registry.register(Box::new(revalidations.clone())).unwrap();
+        registry.register(Box::new(served_bytes.clone())).unwrap();
+
+        Self {
+            registry,
+            requests,
+            conversion_duration,
+            queue_wait,
+            output_bytes,
+            child_user_cpu,
+            child_system_cpu,
+            child_max_rss,
+            in_flight,
+            queued,
+            cache_hits,
+            cache_misses,
+            upstream_errors,
+            upstream_responses,
+            upstream_duration,
+            upstream_fetched_bytes,
+            upstream_statuses,
+            subprocess_failures,
+            invalid_outputs,
+            decodes,
+            hwaccel_fallbacks,
+            moov_not_found,
+            encoder_retries,
+            conversion_errors,
+            client_aborts,
+            slow_requests,
+            audit_dropped,
+            janitor_removed,
+            janitor_reclaimed_bytes,
+            prefetches,
+            prefetch_hits,
+            revalidations,
+            served_bytes,
+            // Finer than the Prometheus histogram's, from 50ms to about 5
+            // minutes, 25% apart, so a p99 means something
+            recent_durations: RollingHistogram::new(exponential_buckets(0.05, 1.25, 40).unwrap(), RECENT_WINDOW),
+            started: Instant::now(),
+            statsd,
+        }
+    }
+
+    /// A response to a request for `route`, with a status in `class` (`2xx` etc).
+    fn request(&self, route: &'static str, class: &'static str) {
+        self.requests.with_label_values(&[route, class]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("http_requests", 1, &[("route", route), ("status", class)]);
+        }
+    }
+
+    /// Create `host`'s upstream series, so they're there (at zero) before
+    /// anything is fetched from it.
+    pub fn upstream_host(&self, host: &str) {
+        for phase in UPSTREAM_PHASES {
+            self.upstream_duration.with_label_values(&[host, phase]);
+        }
+        self.upstream_fetched_bytes.with_label_values(&[host]);
+        for status in STATUS_CLASSES {
+            self.upstream_statuses.with_label_values(&[host, status]);
+        }
+    }
+
+    /// A conversion that succeeded, making a GIF of `bytes`.
+    pub fn conversion_finished(&self, took: Duration, bytes: u64) {
+        self.conversion_duration.observe(took.as_secs_f64());
+        self.recent_durations.observe(took.as_secs_f64());
+        self.output_bytes.observe(bytes as f64);
+        if let Some(statsd) = &self.statsd {
+            statsd.timing("conversion_duration", took, &[]);
+            statsd.histogram("output_bytes", bytes, &[]);
+        }
+    }
+
+    /// A conversion that succeeded was decoded with `path`, `software` or a
+    /// hardware decoder's name.
+    pub fn decoded(&self, path: &'static str) {
+        self.decodes.with_label_values(&[path]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("decodes", 1, &[("path", path)]);
+        }
+    }
+
+    pub fn queue_waited(&self, priority: Priority, wait: Duration) {
+        self.queue_wait.with_label_values(&[priority.as_str()]).observe(wait.as_secs_f64());
+        if let Some(statsd) = &self.statsd {
+            statsd.timing("queue_wait", wait, &[("priority", priority.as_str())]);
+        }
+    }
+
+    /// The body of a response went out, `bytes` of it.
+    pub fn served(&self, bytes: u64) {
+        self.served_bytes.inc_by(bytes);
+        if let Some(statsd) = &self.statsd {
+            statsd.count("served_bytes", bytes, &[]);
+        }
+    }
+
+    /// How long since the server started.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Successful conversions' durations over the last `RECENT_WINDOW`, as
+    /// cumulative bucket counts and their total.
+    pub fn recent_durations(&self) -> (Vec<(f64, u64)>, u64) {
+        self.recent_durations.cumulative()
+    }
+
+    pub fn cache_hit(&self, cache: &'static str) {
+        self.cache_hits.with_label_values(&[cache]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("cache_hits", 1, &[("cache", cache)]);
+        }
+    }
+
+    pub fn cache_miss(&self, cache: &'static str) {
+        self.cache_misses.with_label_values(&[cache]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("cache_misses", 1, &[("cache", cache)]);
+        }
+    }
+
+
+    pub fn conversion_failed(&self, class: ErrorClass) {
+        self.conversion_errors.with_label_values(&[class.as_str()]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("conversion_errors", 1, &[("class", class.as_str())]);
+        }
+    }
+
+    /// `stage` is `before_response` or `during_response`.
+    pub fn client_aborted(&self, stage: &'static str) {
+        self.client_aborts.with_label_values(&[stage]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("client_aborts", 1, &[("stage", stage)]);
+        }
+    }
+
+    pub fn slow_request(&self) {
+        self.slow_requests.inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("slow_requests", 1, &[]);
+        }
+    }
+
+    pub fn audit_record_dropped(&self) {
+        self.audit_dropped.inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("audit_records_dropped", 1, &[]);
+        }
+    }
+
+    /// A janitor's sweep removed `temp_files` temp files, `bytes` of them,
+    /// and `jobs` expired jobs.
+    pub fn janitor_swept(&self, temp_files: u64, bytes: u64, jobs: u64) {
+        self.janitor_removed.with_label_values(&["temp_file"]).inc_by(temp_files);
+        self.janitor_removed.with_label_values(&["job"]).inc_by(jobs);
+        self.janitor_reclaimed_bytes.inc_by(bytes);
+        if let Some(statsd) = &self.statsd {
+            statsd.count("janitor_removed", temp_files, &[("kind", "temp_file")]);
+            statsd.count("janitor_removed", jobs, &[("kind", "job")]);
+            statsd.count("janitor_reclaimed_bytes", bytes, &[]);
+        }
+    }
+
+    /// A prefetch came to `outcome`, one of `PREFETCH_OUTCOMES`.
+    pub fn prefetched(&self, outcome: &'static str) {
+        self.prefetches.with_label_values(&[outcome]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("prefetches", 1, &[("outcome", outcome)]);
+        }
+    }
+
+    /// A cached GIF's video was asked about again, and that came to
+    /// `outcome`, one of `REVALIDATION_OUTCOMES`.
+    pub fn revalidated(&self, outcome: &'static str) {
+        self.revalidations.with_label_values(&[outcome]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("revalidations", 1, &[("outcome", outcome)]);
+        }
+    }
+
+    /// A prefetched GIF was sent from the cache for the first time, which is
+    /// a conversion the prefetch saved someone waiting for.
+    pub fn prefetch_hit(&self) {
+        self.prefetch_hits.inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("prefetch_hits", 1, &[]);
+        }
+    }
+
+    /// Send StatsD what's only kept elsewhere: the gauges and the resolver's
+    /// counts. Prometheus gets those when it scrapes.
+    pub fn flush_statsd(&self, in_flight: usize, queued: &BTreeMap<&'static str, usize>, dns: &DnsStats) {
+        if let Some(statsd) = &self.statsd {
+            statsd.flush(in_flight, queued, dns);
+        }
+    }
+
+    /// Everything counted so far, with the resolver's cache counts folded in.
+    pub fn totals(&self, dns: &DnsStats) -> Totals {
+        let requests = ROUTES
+            .iter()
+            .flat_map(|route| STATUS_CLASSES.iter().map(move |status| (*route, *status)))
+            .map(|(route, status)| self.requests.with_label_values(&[route, status]).get())
+            .sum();
+        let histogram = self.conversion_duration.metric();
+        let durations = histogram
+            .get_histogram()
+            .get_bucket()
+            .iter()
+            .map(|bucket| (bucket.upper_bound(), bucket.cumulative_count()))
+            .collect();
+        Totals {
+            requests,
+            conversions: self.conversion_duration.get_sample_count(),
+            errors: ErrorClass::ALL
+                .iter()
+                .filter(|class| **class != ErrorClass::ClientAbort)
+                .map(|class| (class.as_str(), self.conversion_errors.with_label_values(&[class.as_str()]).get()))
+                .collect(),
+            client_aborts: ABORT_STAGES
+                .iter()
+                .map(|stage| self.client_aborts.with_label_values(&[stage]).get())
+                .sum(),
+            // The resolver's counts only reach the counters when they're rendered
+            cache_hits: ["probe", "gif"].iter().map(|cache| self.cache_hits.with_label_values(&[cache]).get()).sum::<u64>() + dns.hits,
+            cache_misses: ["probe", "gif"].iter().map(|cache| self.cache_misses.with_label_values(&[cache]).get()).sum::<u64>()
+                + dns.misses,
+            gif_cache_hits: self.cache_hits.with_label_values(&["gif"]).get(),
+            gif_cache_misses: self.cache_misses.with_label_values(&["gif"]).get(),
+            bytes_served: self.served_bytes.get(),
+            durations,
+        }
+    }
+
+    pub fn has_statsd(&self) -> bool {
+        self.statsd.is_some()
+    }
+
+    /// The current values in Prometheus' text format. Some of them are only
+    /// kept elsewhere, so they're brought up to date first.
+    pub fn render(&self, in_flight: usize, queued: &BTreeMap<&'static str, usize>, dns: &DnsStats) -> String {
+        self.in_flight.set(in_flight as i64);
+        for (priority, queued) in queued {
+            self.queued.with_label_values(&[priority]).set(*queued as i64);
+        }
+        // The resolver keeps its own counts, which only ever go up
+        let hits = self.cache_hits.with_label_values(&["dns"]);
+        hits.inc_by(dns.hits.saturating_sub(hits.get()));
+        let misses = self.cache_misses.with_label_values(&["dns"]);
+        misses.inc_by(dns.misses.saturating_sub(misses.get()));
+
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_default()
+    }
+}
+
+/// What the pipeline counts, plus ffprobe failing.
+impl ConversionMetrics for Metrics {
+    fn upstream_error(&self, kind: &'static str) {
+        self.upstream_errors.with_label_values(&[kind]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("upstream_errors", 1, &[("kind", kind)]);
+        }
+    }
+
+    fn upstream_response(&self, family: &'static str) {
+        self.upstream_responses.with_label_values(&[family]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("upstream_responses", 1, &[("family", family)]);
+        }
+    }
+
+    fn upstream_transfer(&self, host: &str, transfer: &Transfer) {
+        let phases = [("dns", transfer.dns), ("connect", transfer.connect), ("ttfb", transfer.ttfb), ("download", transfer.download)];
+        for (phase, took) in phases {
+            let Some(took) = took else { continue };
+            self.upstream_duration.with_label_values(&[host, phase]).observe(took.as_secs_f64());
+            if let Some(statsd) = &self.statsd {
+                statsd.timing("upstream_duration", took, &[("host", host), ("phase", phase)]);
+            }
+        }
+        if transfer.bytes > 0 {
+            self.upstream_fetched_bytes.with_label_values(&[host]).inc_by(transfer.bytes);
+            if let Some(statsd) = &self.statsd {
+                statsd.count("upstream_fetched_bytes", transfer.bytes, &[("host", host)]);
+            }
+        }
+        if let Some(status) = transfer.status {
+            let class = STATUS_CLASSES[(status / 100).clamp(1, 5) as usize - 1];
+            self.upstream_statuses.with_label_values(&[host, class]).inc();
+            if let Some(statsd) = &self.statsd {
+                statsd.count("upstream_statuses", 1, &[("host", host), ("status", class)]);
+            }
+        }
+    }
+
+    fn subprocess_failed(&self, process: &'static str, reason: &'static str) {
+        self.subprocess_failures.with_label_values(&[process, reason]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("subprocess_failures", 1, &[("process", process), ("reason", reason)]);
+        }
+    }
+
+    fn invalid_output(&self, format: &'static str) {
+        self.invalid_outputs.with_label_values(&[format]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("invalid_outputs", 1, &[("format", format)]);
+        }
+    }
+
+    fn hwaccel_fallback(&self, hwaccel: &'static str) {
+        self.hwaccel_fallbacks.with_label_values(&[hwaccel]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("hwaccel_fallbacks", 1, &[("hwaccel", hwaccel)]);
+        }
+    }
+
+    fn moov_not_found(&self, outcome: &'static str) {
+        self.moov_not_found.with_label_values(&[outcome]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("moov_not_found", 1, &[("outcome", outcome)]);
+        }
+    }
+
+    fn child_usage(&self, process: &'static str, usage: ResourceUsage) {
+        self.child_user_cpu.with_label_values(&[process]).observe(usage.user.as_secs_f64());
+        self.child_system_cpu.with_label_values(&[process]).observe(usage.system.as_secs_f64());
+        self.child_max_rss.with_label_values(&[process]).observe(usage.max_rss_bytes as f64);
+        if let Some(statsd) = &self.statsd {
+            statsd.timing("child_user_cpu", usage.user, &[("process", process)]);
+            statsd.timing("child_system_cpu", usage.system, &[("process", process)]);
+            statsd.histogram("child_max_rss_bytes", usage.max_rss_bytes, &[("process", process)]);
+        }
+    }
+
+    fn encoder_retry(&self, outcome: &'static str) {
+        self.encoder_retries.with_label_values(&[outcome]).inc();
+        if let Some(statsd) = &self.statsd {
+            statsd.count("encoder_retries", 1, &[("outcome", outcome)]);
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+/// Middleware counting every response by the route it matched and its status class.
+pub async fn track_requests(State(metrics): State<Arc<Metrics>>, request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str())
+        .and_then(|path| ROUTES.iter().copied().find(|route| *route == path))
+        .unwrap_or("unmatched");
+    let response = next.run(request).await;
+    let class = STATUS_CLASSES[(response.status().as_u16() / 100).clamp(1, 5) as usize - 1];
+    metrics.request(route, class);
+    response
+}
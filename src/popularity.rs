@@ -0,0 +1,208 @@
+use lru::LruCache;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How long each of a path's counts is kept apart for, and how many of them
+// there are, which is the longest window that can be asked about
+const BUCKET_SECS: u64 = 300;
+const BUCKETS: usize = 12;
+
+/// The longest window `GET /admin/top` can ask about, besides `all`.
+pub const MAX_WINDOW: Duration = Duration::from_secs(BUCKET_SECS * BUCKETS as u64);
+
+/// How a GIF request's GIF was sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Served {
+    /// From one of the caches, stale or not
+    Cache,
+    /// Converted for it
+    Conversion,
+}
+
+/// Which paths are asked for most, for `GET /admin/top`: how many requests
+/// each had, how many were converted or sent from the cache, and how many
+/// bytes went out, in five-minute buckets for the last hour and since it
+/// was last reset. Only the `POPULARITY_MAX_PATHS` paths most recently
+/// asked for are tracked, so an endless number of paths can't grow it past
+/// that; the lock's only held to find a path's counters, which are atomics.
+pub struct Popularity {
+    max_paths: usize,
+    started: Instant,
+    paths: Mutex<LruCache<String, Arc<Counters>>>,
+}
+
+struct Counters {
+    buckets: [Bucket; BUCKETS],
+    total: Bucket,
+}
+
+#[derive(Default)]
+struct Bucket {
+    /// Which five minutes since `started` it's counting, plus one, or 0 for
+    /// none yet
+    slot: AtomicU64,
+    hits: AtomicU64,
+    conversions: AtomicU64,
+    cache_hits: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Bucket {
+    fn add(&self, served: Option<Served>, bytes: u64) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        match served {
+            Some(Served::Conversion) => self.conversions.fetch_add(1, Ordering::Relaxed),
+            Some(Served::Cache) => self.cache_hits.fetch_add(1, Ordering::Relaxed),
+            None => 0,
+        };
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn clear(&self) {
+        for count in [&self.hits, &self.conversions, &self.cache_hits, &self.bytes] {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn add_to(&self, into: &mut Popular) {
+        into.hits += self.hits.load(Ordering::Relaxed);
+        into.conversions += self.conversions.load(Ordering::Relaxed);
+        into.cache_hits += self.cache_hits.load(Ordering::Relaxed);
+        into.bytes += self.bytes.load(Ordering::Relaxed);
+    }
+}
+
+/// What `GET /admin/top` lists for a path.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Popular {
+    pub path: String,
+    pub hits: u64,
+    pub conversions: u64,
+    #[serde(skip)]
+    pub cache_hits: u64,
+    /// Of the hits, how many were sent from the cache
+    pub cache_hit_ratio: f64,
+    pub bytes: u64,
+}
+
+/// How far back `GET /admin/top` looks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    /// This long, to the nearest five minutes
+    Last(Duration),
+    /// Since the tracker was last reset
+    All,
+}
+
+impl Window {
+    /// `all`, or a number of seconds, or of minutes or hours with `m` or
+    /// `h` after it, e.g. `15m` or `1h`.
+    pub fn parse(window: &str) -> Result<Self, String> {
+        if window == "all" {
+            return Ok(Window::All);
+        }
+        let (number, unit) = match window.strip_suffix(['s', 'm', 'h']) {
+            Some(number) => (number, &window[number.len()..]),
+            None => (window, "s"),
+        };
+        let secs = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(match unit {
+                "h" => 3600,
+                "m" => 60,
+                _ => 1,
+            }))
+            .filter(|secs| *secs > 0)
+            .ok_or_else(|| format!("{:?} isn't a window: it's `all`, or a length of time like `15m` or `1h`", window))?;
+        match Duration::from_secs(secs) {
+            window if window > MAX_WINDOW => Err(format!("Only the last {}s are kept apart, or `all`", MAX_WINDOW.as_secs())),
+            window => Ok(Window::Last(window)),
+        }
+    }
+}
+
+impl Popularity {
+    pub fn new(max_paths: usize) -> Self {
+        Self { max_paths, started: Instant::now(), paths: Mutex::new(LruCache::unbounded()) }
+    }
+
+    /// Count a request for `path`, and the bytes sent for it.
+    pub fn record(&self, path: &str, served: Option<Served>, bytes: u64) {
+        let counters = {
+            let mut paths = self.paths.lock().unwrap();
+            match paths.get(path) {
+                Some(counters) => counters.clone(),
+                None => {
+                    let counters = Arc::new(Counters { buckets: Default::default(), total: Bucket::default() });
+                    paths.put(path.to_string(), counters.clone());
+                    while paths.len() > self.max_paths {
+                        paths.pop_lru();
+                    }
+                    counters
+                }
+            }
+        };
+        let slot = self.slot();
+        let bucket = &counters.buckets[slot as usize % BUCKETS];
+        // Whoever gets to a bucket first for its five minutes starts it
+        // again; what's added by others just then may go with the old counts
+        if bucket.slot.swap(slot + 1, Ordering::AcqRel) != slot + 1 {
+            bucket.clear();
+        }
+        bucket.add(served, bytes);
+        counters.total.add(served, bytes);
+    }
+
+    /// The `n` paths with the most hits in `window`, the most first.
+    pub fn top(&self, window: Window, n: usize) -> Vec<Popular> {
+        let paths: Vec<(String, Arc<Counters>)> =
+            self.paths.lock().unwrap().iter().map(|(path, counters)| (path.clone(), counters.clone())).collect();
+        let now = self.slot();
+        let mut top: Vec<Popular> = paths
+            .into_iter()
+            .map(|(path, counters)| {
+                let mut popular = Popular { path, ..Popular::default() };
+                match window {
+                    Window::All => counters.total.add_to(&mut popular),
+                    Window::Last(window) => {
+                        let slots = window.as_secs().div_ceil(BUCKET_SECS);
+                        for bucket in &counters.buckets {
+                            let slot = bucket.slot.load(Ordering::Acquire);
+                            if slot > 0 && now + 1 - slot < slots {
+                                bucket.add_to(&mut popular);
+                            }
+                        }
+                    }
+                }
+                if popular.hits > 0 {
+                    popular.cache_hit_ratio = popular.cache_hits as f64 / popular.hits as f64;
+                }
+                popular
+            })
+            .filter(|popular| popular.hits > 0)
+            .collect();
+        top.sort_by(|a, b| b.hits.cmp(&a.hits).then_with(|| b.bytes.cmp(&a.bytes)).then_with(|| a.path.cmp(&b.path)));
+        top.truncate(n);
+        top
+    }
+
+    /// Forget everything counted so far, and say for how many paths.
+    pub fn reset(&self) -> usize {
+        let mut paths = self.paths.lock().unwrap();
+        let tracked = paths.len();
+        paths.clear();
+        tracked
+    }
+
+    /// How many paths are tracked, and how many can be.
+    pub fn tracked(&self) -> (usize, usize) {
+        (self.paths.lock().unwrap().len(), self.max_paths)
+    }
+
+    fn slot(&self) -> u64 {
+        self.started.elapsed().as_secs() / BUCKET_SECS
+    }
+}
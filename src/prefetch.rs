@@ -0,0 +1,50 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// Prefetches that have been taken on and haven't finished, by the key their
+/// GIF will be cached under, so the same one isn't made twice at once
+/// (`POST /prefetch`). There are never more than `MAX_PENDING_PREFETCHES`.
+pub struct Prefetches {
+    pending: Mutex<HashSet<String>>,
+    max_pending: usize,
+}
+
+/// A prefetch's place among those pending, given up when dropped.
+pub struct Pending {
+    prefetches: Arc<Prefetches>,
+    variant: String,
+}
+
+/// Why a prefetch wasn't taken on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Declined {
+    /// The same GIF is being prefetched already
+    Pending,
+    /// `MAX_PENDING_PREFETCHES` are already
+    Full,
+}
+
+impl Prefetches {
+    pub fn new(max_pending: usize) -> Self {
+        Self { pending: Mutex::default(), max_pending }
+    }
+
+    /// Take on prefetching what'll be cached under `variant`.
+    pub fn take(self: &Arc<Self>, variant: &str) -> Result<Pending, Declined> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.contains(variant) {
+            return Err(Declined::Pending);
+        }
+        if pending.len() >= self.max_pending {
+            return Err(Declined::Full);
+        }
+        pending.insert(variant.to_string());
+        Ok(Pending { prefetches: self.clone(), variant: variant.to_string() })
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.prefetches.pending.lock().unwrap().remove(&self.variant);
+    }
+}
@@ -0,0 +1,194 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How often the system is sampled.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Conversion latencies older than this no longer count as recent. Without
+/// that, once slow conversions tip us into shedding, nothing new would ever
+/// finish to bring the latency back down.
+const LATENCY_WINDOW: Duration = Duration::from_secs(60);
+
+/// How many recent conversion latencies are kept.
+const MAX_LATENCIES: usize = 50;
+
+/// Shedding stops once every signal is back below this fraction of its
+/// threshold, so a value hovering right at the threshold doesn't flap.
+const RECOVERY_RATIO: f64 = 0.9;
+
+/// Where system pressure becomes too much to take on new conversions. Any that
+/// aren't set aren't checked.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PressureThresholds {
+    /// One-minute load average, divided by the number of cores
+    pub max_load_per_core: Option<f64>,
+    /// Memory the kernel says is available for new work
+    pub min_available_memory_bytes: Option<u64>,
+    /// Median of recently finished conversions
+    pub max_latency: Option<Duration>,
+}
+
+impl PressureThresholds {
+    pub fn is_empty(&self) -> bool {
+        self.max_load_per_core.is_none()
+            && self.min_available_memory_bytes.is_none()
+            && self.max_latency.is_none()
+    }
+}
+
+/// What the pressure controller last saw, and whether it's shedding load
+/// because of it. Updated by the background task from [`spawn`].
+pub struct AdmissionState {
+    thresholds: PressureThresholds,
+    cores: usize,
+    shedding: AtomicBool,
+    latest: Mutex<Snapshot>,
+    latencies: Mutex<VecDeque<(Instant, Duration)>>,
+}
+
+/// A sample of system pressure, as reported on the stats endpoint.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Snapshot {
+    pub shedding: bool,
+    /// Which signal started the shedding
+    pub reason: Option<&'static str>,
+    pub load_per_core: Option<f64>,
+    pub available_memory_bytes: Option<u64>,
+    pub recent_latency_ms: Option<u64>,
+}
+
+impl AdmissionState {
+    pub fn new(thresholds: PressureThresholds, cores: usize) -> Self {
+        Self {
+            thresholds,
+            cores: cores.max(1),
+            shedding: AtomicBool::new(false),
+            latest: Mutex::new(Snapshot::default()),
+            latencies: Mutex::new(VecDeque::with_capacity(MAX_LATENCIES)),
+        }
+    }
+
+    /// Whether new conversions should be turned away right now.
+    pub fn is_shedding(&self) -> bool {
+        self.shedding.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.latest.lock().unwrap().clone()
+    }
+
+    /// Note how long a finished conversion took.
+    pub fn record_latency(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().unwrap();
+        if latencies.len() == MAX_LATENCIES {
+            latencies.pop_front();
+        }
+        latencies.push_back((Instant::now(), latency));
+    }
+
+    fn recent_latency(&self) -> Option<Duration> {
+        let mut latencies = self.latencies.lock().unwrap();
+        while latencies
+            .front()
+            .is_some_and(|(finished, _)| finished.elapsed() > LATENCY_WINDOW)
+        {
+            latencies.pop_front();
+        }
+        let mut recent: Vec<Duration> = latencies.iter().map(|(_, latency)| *latency).collect();
+        recent.sort();
+        recent.get(recent.len() / 2).copied()
+    }
+
+    fn sample(&self) {
+        let thresholds = &self.thresholds;
+        let load_per_core = thresholds
+            .max_load_per_core
+            .and(load_average().map(|load| load / self.cores as f64));
+        let available_memory_bytes = thresholds.min_available_memory_bytes.and(available_memory());
+        let recent_latency = thresholds.max_latency.and(self.recent_latency());
+
+        let was_shedding = self.is_shedding();
+        // While shedding, a signal has to fall back further before it counts as fine
+        let ratio = if was_shedding { RECOVERY_RATIO } else { 1.0 };
+        let reason = if load_per_core
+            .zip(thresholds.max_load_per_core)
+            .is_some_and(|(load, max)| load > max * ratio)
+        {
+            Some("load")
+        } else if available_memory_bytes
+            .zip(thresholds.min_available_memory_bytes)
+            .is_some_and(|(available, min)| available as f64 * ratio < min as f64)
+        {
+            Some("memory")
+        } else if recent_latency
+            .zip(thresholds.max_latency)
+            .is_some_and(|(latency, max)| latency.as_secs_f64() > max.as_secs_f64() * ratio)
+        {
+            Some("latency")
+        } else {
+            None
+        };
+
+        let shedding = reason.is_some();
+        self.shedding.store(shedding, Ordering::Relaxed);
+        match (was_shedding, reason) {
+            (false, Some(reason)) => warn!(
+                "System is under pressure ({}), turning away new conversions",
+                reason
+            ),
+            (true, None) => info!("System pressure is back to normal, accepting conversions again"),
+            _ => {}
+        }
+
+        let mut latest = self.latest.lock().unwrap();
+        // Keep reporting what started the shedding for as long as it lasts
+        let reason = if was_shedding && shedding { latest.reason.or(reason) } else { reason };
+        *latest = Snapshot {
+            shedding,
+            reason,
+            load_per_core,
+            available_memory_bytes,
+            recent_latency_ms: recent_latency.map(|latency| latency.as_millis() as u64),
+        };
+    }
+}
+
+/// Sample the system in the background for as long as the server runs.
+pub fn spawn(state: Arc<AdmissionState>) {
+    if state.thresholds.max_load_per_core.is_some() && load_average().is_none() {
+        warn!("Can't read the load average on this system, it won't be checked");
+    }
+    if state.thresholds.min_available_memory_bytes.is_some() && available_memory().is_none() {
+        warn!("Can't read available memory on this system, it won't be checked");
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            // Reading /proc is file I/O like any other, as far as the runtime knows
+            let sampling = state.clone();
+            let _ = tokio::task::spawn_blocking(move || sampling.sample()).await;
+        }
+    });
+}
+
+fn load_average() -> Option<f64> {
+    std::fs::read_to_string("/proc/loadavg")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
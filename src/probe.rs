@@ -0,0 +1,339 @@
+use crate::metrics::Metrics;
+use fastgif_core::error_class::unsupported_source;
+use fastgif_core::metrics::ConversionMetrics;
+use fastgif_core::error::{ConversionError, Result};
+use fastgif_core::pipeline::Alpha;
+use fastgif_core::process::{killed_by_limit, Binary, ChildLimits, ProcessGuard};
+use lru::LruCache;
+use serde::Deserialize;
+use std::num::NonZeroUsize;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tracing::info;
+
+/// How long ffprobe gets to tell us about a video.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How many probe results we remember.
+const PROBE_CACHE_SIZE: usize = 10_000;
+
+/// What ffprobe is asked for to learn a video's duration, and whether it has
+/// an alpha channel, as `key=value` lines.
+const DURATION_ARGS: [&str; 8] = [
+    "-v", "error",
+    "-select_streams", "v:0",
+    "-show_entries", "format=duration:stream=codec_name,pix_fmt:stream_tags=alpha_mode",
+    "-of", "default=noprint_wrappers=1",
+];
+
+/// What ffprobe is asked for to learn a video's [`Details`], as JSON. The
+/// side data is asked for as a whole list, the only way older ffprobes (4.2,
+/// for one) have of naming a stream's.
+const DETAILS_ARGS: [&str; 8] = [
+    "-v", "error",
+    "-select_streams", "v:0",
+    "-show_entries", "format=duration,bit_rate:stream=width,height,avg_frame_rate,codec_name,pix_fmt:stream_tags=rotate,alpha_mode:stream_side_data_list",
+    "-of", "json",
+];
+
+/// What a probe found out.
+pub struct Probed {
+    /// `None` if the container doesn't say
+    pub duration: Option<Duration>,
+    /// `None` if it hasn't got one
+    pub alpha: Option<Alpha>,
+    /// Why there's nothing in it to convert, if there isn't: it has no video
+    /// stream, or ffprobe couldn't read it at all
+    pub unsupported: Option<&'static str>,
+    /// Whether the answer came from the cache
+    pub cached: bool,
+}
+
+// What's remembered of a video whose duration is known
+#[derive(Clone, Copy)]
+struct Known {
+    duration: Duration,
+    alpha: Option<Alpha>,
+    unsupported: Option<&'static str>,
+}
+
+/// What a fuller probe found out, for estimating what a video will come to.
+/// Anything the video doesn't say is `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Details {
+    pub duration: Option<Duration>,
+    /// Of its first video stream, as it's shown: turned by `rotation`, as
+    /// ffmpeg turns it when converting
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Degrees clockwise it's turned to be shown, one of 0, 90, 180 and 270,
+    /// like a phone's videos are
+    pub rotation: Option<u32>,
+    pub alpha: Option<Alpha>,
+    /// Frames a second, on average
+    pub fps: Option<f64>,
+    /// Bits a second, over the whole container
+    pub bit_rate: Option<u64>,
+}
+
+// What `-of json` prints
+#[derive(Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbedStream>,
+    format: Option<ProbedFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbedStream {
+    width: Option<u32>,
+    height: Option<u32>,
+    avg_frame_rate: Option<String>,
+    codec_name: Option<String>,
+    pix_fmt: Option<String>,
+    // Where older muxers put the rotation, in degrees clockwise
+    tags: Option<ProbedTags>,
+    // and newer ones, as a display matrix ffprobe says is turned this many
+    // degrees anticlockwise
+    #[serde(default)]
+    side_data_list: Vec<ProbedSideData>,
+}
+
+#[derive(Deserialize)]
+struct ProbedTags {
+    rotate: Option<String>,
+    alpha_mode: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProbedSideData {
+    rotation: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct ProbedFormat {
+    duration: Option<String>,
+    bit_rate: Option<String>,
+}
+
+/// Looks up facts about source videos with ffprobe, remembering the answers so
+/// repeated requests for the same video (especially ones we turn away) stay cheap.
+pub struct Prober {
+    ffprobe: Binary,
+    durations: Mutex<LruCache<String, Known>>,
+    details: Mutex<LruCache<String, Details>>,
+    limits: ChildLimits,
+    metrics: Arc<Metrics>,
+}
+
+impl Prober {
+    pub fn new(ffprobe: Binary, limits: ChildLimits, metrics: Arc<Metrics>) -> Self {
+        Self {
+            ffprobe,
+            durations: Mutex::new(LruCache::new(NonZeroUsize::new(PROBE_CACHE_SIZE).unwrap())),
+            details: Mutex::new(LruCache::new(NonZeroUsize::new(PROBE_CACHE_SIZE).unwrap())),
+            limits,
+            metrics,
+        }
+    }
+
+    /// How many videos' durations are remembered.
+    pub fn cached(&self) -> usize {
+        self.durations.lock().unwrap().len()
+    }
+
+    /// The duration of the video at `video_url`, and how it has an alpha
+    /// channel if it does.
+    pub async fn duration(&self, video_url: &str) -> Result<Probed> {
+        if let Some(known) = self.durations.lock().unwrap().get(video_url) {
+            self.metrics.cache_hit("probe");
+            return Ok(Probed { duration: Some(known.duration), alpha: known.alpha, unsupported: known.unsupported, cached: true });
+        }
+        self.metrics.cache_miss("probe");
+
+        let probed = self.duration_once(video_url).await?;
+        if let Some(duration) = probed.duration {
+            let known = Known { duration, alpha: probed.alpha, unsupported: probed.unsupported };
+            self.durations.lock().unwrap().put(video_url.to_string(), known);
+        }
+        Ok(probed)
+    }
+
+    /// What [`duration`](Self::duration) finds out about the video at
+    /// `video_url`, without remembering it, for one nobody will ask about
+    /// again, like an upload.
+    pub async fn duration_once(&self, video_url: &str) -> Result<Probed> {
+        let output = tokio::time::timeout(PROBE_TIMEOUT, self.ffprobe_duration(video_url))
+            .await
+            .map_err(|_| timed_out())?;
+        let output = match output {
+            Ok(output) => output,
+            Err(ConversionError::UnsupportedSource(reason)) => {
+                info!("Probed {}: nothing to convert, {}", video_url, reason);
+                return Ok(Probed { duration: None, alpha: None, unsupported: Some(reason), cached: false });
+            }
+            Err(e) => return Err(e),
+        };
+
+        let value = |key: &str| {
+            output.lines().find_map(|line| line.trim().strip_prefix(key)?.strip_prefix('=')).filter(|value| !value.is_empty())
+        };
+        // The container's always there to say it has no duration, so without
+        // a stream beside it there's no video stream to select
+        let has = |key: &str| output.lines().any(|line| line.trim().starts_with(&format!("{}=", key)));
+        let unsupported = (has("duration") && !has("codec_name")).then_some("it has no video stream");
+        // ffprobe prints `N/A` when the container has no duration
+        let duration = value("duration")
+            .and_then(|secs| secs.parse::<f64>().ok())
+            .filter(|secs| secs.is_finite() && *secs >= 0.0)
+            .map(Duration::from_secs_f64);
+        let alpha = alpha(value("codec_name"), value("pix_fmt"), value("TAG:alpha_mode"));
+        info!("Probed {}: duration {:?}, alpha {:?}, unsupported {:?}", video_url, duration, alpha, unsupported);
+        Ok(Probed { duration, alpha, unsupported, cached: false })
+    }
+
+    /// The dimensions, frame rate, bit rate and duration of the video at
+    /// `video_url`. Its duration is remembered for [`duration`](Self::duration) too.
+    pub async fn details(&self, video_url: &str) -> Result<Details> {
+        if let Some(details) = self.details.lock().unwrap().get(video_url) {
+            self.metrics.cache_hit("probe");
+            return Ok(*details);
+        }
+        self.metrics.cache_miss("probe");
+
+        let (details, unsupported) = self.probe_details(video_url).await?;
+        self.details.lock().unwrap().put(video_url.to_string(), details);
+        if let Some(duration) = details.duration {
+            self.durations.lock().unwrap().put(video_url.to_string(), Known { duration, alpha: details.alpha, unsupported });
+        }
+        Ok(details)
+    }
+
+    /// What [`details`](Self::details) finds out about the video at
+    /// `video_url`, without remembering it, for one nobody will ask about
+    /// again, like a copy of it in a temp file.
+    pub async fn details_once(&self, video_url: &str) -> Result<Details> {
+        Ok(self.probe_details(video_url).await?.0)
+    }
+
+    // The details, and why there's nothing to convert if there isn't
+    async fn probe_details(&self, video_url: &str) -> Result<(Details, Option<&'static str>)> {
+        let output = tokio::time::timeout(PROBE_TIMEOUT, self.ffprobe(&DETAILS_ARGS, video_url))
+            .await
+            .map_err(|_| timed_out())??;
+        let probed: ProbeOutput = serde_json::from_str(&output)
+            .map_err(|e| ConversionError::Decode(format!("Couldn't read what ffprobe said: {}", e)))?;
+        let stream = probed.streams.first();
+        let format = probed.format.as_ref();
+        let rotation = stream.and_then(rotation);
+        let width = stream.and_then(|stream| stream.width).filter(|width| *width > 0);
+        let height = stream.and_then(|stream| stream.height).filter(|height| *height > 0);
+        let (width, height) = match rotation {
+            Some(90 | 270) => (height, width),
+            _ => (width, height),
+        };
+        let details = Details {
+            duration: format
+                .and_then(|format| format.duration.as_deref()?.parse::<f64>().ok())
+                .filter(|secs| secs.is_finite() && *secs >= 0.0)
+                .map(Duration::from_secs_f64),
+            width,
+            height,
+            rotation,
+            alpha: stream.and_then(|stream| {
+                let alpha_mode = stream.tags.as_ref().and_then(|tags| tags.alpha_mode.as_deref());
+                alpha(stream.codec_name.as_deref(), stream.pix_fmt.as_deref(), alpha_mode)
+            }),
+            fps: stream.and_then(|stream| frame_rate(stream.avg_frame_rate.as_deref()?)),
+            bit_rate: format.and_then(|format| format.bit_rate.as_deref()?.parse().ok()),
+        };
+        info!("Probed {}: {:?}", video_url, details);
+        Ok((details, probed.streams.is_empty().then_some("it has no video stream")))
+    }
+
+    /// The details of the video at `video_url`, if they've been probed and
+    /// are still remembered.
+    pub fn cached_details(&self, video_url: &str) -> Option<Details> {
+        self.details.lock().unwrap().peek(video_url).copied()
+    }
+
+    async fn ffprobe_duration(&self, video_url: &str) -> Result<String> {
+        self.ffprobe(&DURATION_ARGS, video_url).await
+    }
+
+    async fn ffprobe(&self, args: &[&str], video_url: &str) -> Result<String> {
+        let mut ffprobe = ProcessGuard::spawn(
+            "ffprobe",
+            self.ffprobe.command()
+                .args(args)
+                .arg(video_url)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+            &self.limits,
+        )
+        .map_err(|source| ConversionError::Spawn { binary: "ffprobe", source })?;
+
+        let mut stdout = ffprobe
+            .take_stdout()
+            .ok_or_else(|| ConversionError::internal("Failed to take ffprobe stdout"))?;
+        let mut stderr = ffprobe
+            .take_stderr()
+            .ok_or_else(|| ConversionError::internal("Failed to take ffprobe stderr"))?;
+        let (mut output, mut said) = (String::new(), String::new());
+        let (read, _) = tokio::join!(stdout.read_to_string(&mut output), stderr.read_to_string(&mut said));
+        read.map_err(ConversionError::pipe("Failed to read ffprobe's output"))?;
+
+        let status = ffprobe.wait().await.map_err(ConversionError::pipe("Failed to wait for ffprobe"))?;
+        if !status.success() {
+            let reason = if killed_by_limit(&status) { "resource_limit" } else { "failed" };
+            self.metrics.subprocess_failed("ffprobe", reason);
+            // What it says when the video can't be read at all
+            if let Some(reason) = said.lines().find_map(unsupported_source) {
+                return Err(ConversionError::UnsupportedSource(reason));
+            }
+            return Err(ConversionError::Decode(format!("ffprobe failed with exit code: {:?}", status.code())));
+        }
+        Ok(output)
+    }
+}
+
+fn timed_out() -> ConversionError {
+    ConversionError::internal(format!("ffprobe timed out after {:?}", PROBE_TIMEOUT))
+}
+
+// How a stream of `codec` in `pix_fmt` has an alpha channel, if it does:
+// in the pixel format, or for VP8 and VP9, in a side channel a WebM's
+// `alpha_mode` tag says is there
+fn alpha(codec: Option<&str>, pix_fmt: Option<&str>, alpha_mode: Option<&str>) -> Option<Alpha> {
+    let pix_fmt = pix_fmt.unwrap_or_default();
+    let with_alpha = ["yuva", "gbrap", "ya8", "ya16"].iter().any(|prefix| pix_fmt.starts_with(prefix))
+        || ["rgba", "bgra", "argb", "abgr"].iter().any(|packed| pix_fmt.contains(packed));
+    match (codec, alpha_mode.map(str::trim)) {
+        _ if with_alpha => Some(Alpha::PixelFormat),
+        (Some("vp8"), Some("1")) => Some(Alpha::Vp8),
+        (Some("vp9"), Some("1")) => Some(Alpha::Vp9),
+        _ => None,
+    }
+}
+
+// How far the stream is turned clockwise to be shown, to the nearest quarter
+// turn, going by its display matrix if it has one
+fn rotation(stream: &ProbedStream) -> Option<u32> {
+    let degrees = stream
+        .side_data_list
+        .iter()
+        .find_map(|side_data| side_data.rotation)
+        .map(|anticlockwise| -anticlockwise)
+        .or_else(|| stream.tags.as_ref()?.rotate.as_deref()?.trim().parse().ok())
+        .filter(|degrees: &f64| degrees.is_finite())?;
+    Some(((degrees / 90.0).round() as i64).rem_euclid(4) as u32 * 90)
+}
+
+// A frame rate as ffprobe gives it, e.g. `30000/1001`. `0/0` is none.
+fn frame_rate(rate: &str) -> Option<f64> {
+    let (frames, secs) = rate.split_once('/').unwrap_or((rate, "1"));
+    let fps = frames.parse::<f64>().ok()? / secs.parse::<f64>().ok()?;
+    (fps.is_finite() && fps > 0.0).then_some(fps)
+}
@@ -0,0 +1,159 @@
+use crate::access_log::AccessNote;
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use lru::LruCache;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A rate like `30/minute`: `requests` allowed per `period`, with bursts of up to `requests`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl std::str::FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, unit) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected <count>/<unit>, got {:?}", s))?;
+        let requests = count
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("invalid request count {:?}", count))?;
+        let period = match unit.trim() {
+            "s" | "sec" | "second" => Duration::from_secs(1),
+            "m" | "min" | "minute" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(60 * 60),
+            "d" | "day" => Duration::from_secs(24 * 60 * 60),
+            other => return Err(format!("unknown rate unit {:?}", other)),
+        };
+        Ok(Rate { requests, period })
+    }
+}
+
+/// Where a client's bucket was left by checking a request against it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Checked {
+    /// Whether the request was let through, taking a token
+    pub allowed: bool,
+    pub limit: u32,
+    /// Whole tokens left in the bucket
+    pub remaining: u32,
+    /// How long until the client may make another request, zero if it may
+    /// straight away
+    pub retry_after: Duration,
+    /// How long until the bucket is completely full again
+    pub reset: Duration,
+}
+
+impl Checked {
+    /// Whichever of `self` and `other` leaves the client less: a refusal,
+    /// or else fewer requests left.
+    pub fn tighter(self, other: Checked) -> Checked {
+        match (self.allowed, other.allowed) {
+            (true, false) => other,
+            (false, true) => self,
+            _ if other.remaining < self.remaining => other,
+            _ => self,
+        }
+    }
+
+    /// `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` (in
+    /// whole seconds, rounded up), as the IETF draft has them, and the same
+    /// again as `X-RateLimit-*` if `legacy`.
+    pub fn headers(&self, legacy: bool) -> Vec<(HeaderName, HeaderValue)> {
+        let reset = self.reset.as_millis().div_ceil(1000) as u64;
+        let mut headers = vec![
+            (HeaderName::from_static("ratelimit-limit"), HeaderValue::from(self.limit)),
+            (HeaderName::from_static("ratelimit-remaining"), HeaderValue::from(self.remaining)),
+            (HeaderName::from_static("ratelimit-reset"), HeaderValue::from(reset)),
+        ];
+        if legacy {
+            headers.extend([
+                (HeaderName::from_static("x-ratelimit-limit"), HeaderValue::from(self.limit)),
+                (HeaderName::from_static("x-ratelimit-remaining"), HeaderValue::from(self.remaining)),
+                (HeaderName::from_static("x-ratelimit-reset"), HeaderValue::from(reset)),
+            ]);
+        }
+        headers
+    }
+}
+
+/// Token buckets keyed by client IP. Only the most recently seen clients keep a
+/// bucket, so spraying requests from many addresses can't grow this without bound
+/// (an evicted client simply starts over with a full bucket).
+pub struct RateLimiter {
+    buckets: Mutex<LruCache<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(max_clients: NonZeroUsize) -> Self {
+        Self {
+            buckets: Mutex::new(LruCache::new(max_clients)),
+        }
+    }
+
+    /// Take a token from the client's bucket, if there is one to take. The
+    /// rate is given each time, since a reload can change it; buckets fuller
+    /// than a lowered rate allows are cut down to size.
+    pub fn check(&self, client: IpAddr, rate: Rate) -> Checked {
+        self.check_at(client, rate, Instant::now())
+    }
+
+    /// [`check`](Self::check), as if it were `now`, which mustn't go back
+    /// from one check of a client to the next.
+    pub fn check_at(&self, client: IpAddr, rate: Rate, now: Instant) -> Checked {
+        let capacity = f64::from(rate.requests);
+        let per_second = capacity / rate.period.as_secs_f64();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert_mut(client, || Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Checked {
+            allowed,
+            limit: rate.requests,
+            remaining: bucket.tokens.floor() as u32,
+            retry_after: Duration::from_secs_f64((1.0 - bucket.tokens).max(0.0) / per_second),
+            reset: Duration::from_secs_f64((capacity - bucket.tokens) / per_second),
+        }
+    }
+}
+
+/// Middleware adding the rate limit headers to every response whose request
+/// was held to a limit, whether it was let through or not.
+/// `RATE_LIMIT_LEGACY_HEADERS` (the state) adds the `X-RateLimit-*` ones.
+pub async fn add_headers(State(legacy): State<bool>, request: Request, next: Next) -> Response {
+    let note = request.extensions().get::<AccessNote>().cloned();
+    let mut response = next.run(request).await;
+    if let Some(checked) = note.and_then(|note| note.rate_limited()) {
+        for (name, value) in checked.headers(legacy) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
@@ -0,0 +1,192 @@
+use crate::admission::Admission;
+use crate::config::{Config, RawSettings};
+use crate::degrade::LoadTiers;
+use crate::ip_filter::IpFilter;
+use crate::rate_limit::Rate;
+use crate::telemetry::LogFilter;
+use crate::tls::Certificates;
+use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// The settings a reload can change, by their names in the config file.
+/// Everything else is only read at startup.
+const RELOADABLE: &[&str] = &[
+    "rate_limit",
+    "degrade_at",
+    "degraded_max_age",
+    "max_concurrent_conversions",
+    "max_queue_depth",
+    "queue_wait_timeout",
+    "video_base_url",
+    "log_filter",
+    "allow_cidrs",
+    "deny_cidrs",
+];
+
+/// What a reload can change, swapped as a whole so a request never sees half
+/// of an old configuration and half of a new one.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Conversions allowed per client (`RATE_LIMIT`)
+    pub rate_limit: Option<Rate>,
+    /// When busy conversions get reduced profiles, and how long those may be cached
+    pub load_tiers: Option<LoadTiers>,
+    pub max_concurrent: usize,
+    pub max_queue_depth: usize,
+    pub queue_wait_timeout: Duration,
+    /// Scheme and host videos are fetched from (`VIDEO_BASE_URL`)
+    pub video_base_url: String,
+    /// What the log filter was set to (`RUST_LOG`)
+    pub log_filter: Option<String>,
+    /// Which clients we talk to (`ALLOW_CIDRS`, `DENY_CIDRS`)
+    pub ip_filter: IpFilter,
+}
+
+impl Settings {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let default_concurrency = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let max_concurrent = config.max_concurrent_conversions.unwrap_or(default_concurrency).max(1);
+        let load_tiers = match &config.degrade_at {
+            Some(spec) => Some(
+                LoadTiers::parse(spec, Duration::from_secs(config.degraded_max_age))
+                    .map_err(|e| anyhow!("Invalid DEGRADE_AT {:?}: {}", spec, e))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            rate_limit: config.rate_limit,
+            load_tiers,
+            max_concurrent,
+            max_queue_depth: config.max_queue_depth.unwrap_or(max_concurrent * 4),
+            queue_wait_timeout: Duration::from_secs_f64(config.queue_wait_timeout.max(0.0)),
+            video_base_url: config.video_base_url.trim_end_matches('/').to_string(),
+            log_filter: config.log_filter.clone(),
+            ip_filter: IpFilter::new(&config.allow_cidrs, &config.deny_cidrs),
+        })
+    }
+}
+
+/// What a reload did, for `POST /admin/reload`.
+#[derive(Debug, Serialize)]
+pub struct Reloaded {
+    /// Settings that now have new values, and `tls_certificate` when the
+    /// certificate did
+    pub changed: Vec<String>,
+    /// Settings that changed but need a restart, so still have their old ones
+    pub ignored: Vec<String>,
+}
+
+/// Re-reads the configuration on SIGHUP or `POST /admin/reload`, and puts
+/// what's changed of `Settings` into effect. The TLS certificate and key are
+/// read again too, so a renewed certificate is picked up.
+pub struct Reloader {
+    settings: Arc<ArcSwap<Settings>>,
+    admission: Arc<Admission>,
+    log_filter: LogFilter,
+    certificates: Option<Arc<Certificates>>,
+    /// Every setting as it was last put into effect. Also keeps two reloads
+    /// from running at once.
+    applied: Mutex<RawSettings>,
+}
+
+impl Reloader {
+    pub fn new(
+        config: &Config,
+        settings: Arc<ArcSwap<Settings>>,
+        admission: Arc<Admission>,
+        log_filter: LogFilter,
+        certificates: Option<Arc<Certificates>>,
+    ) -> Self {
+        Self {
+            settings,
+            admission,
+            log_filter,
+            certificates,
+            applied: Mutex::new(config.raw.clone()),
+        }
+    }
+
+    /// Read the configuration again. If anything in it is invalid, nothing
+    /// changes at all. The config file and certificates are read on the
+    /// blocking pool.
+    pub async fn reload(self: &Arc<Self>) -> Result<Reloaded> {
+        let reloader = self.clone();
+        tokio::task::spawn_blocking(move || reloader.reload_now())
+            .await
+            .map_err(|e| anyhow!("Reloading failed: {}", e))?
+    }
+
+    fn reload_now(&self) -> Result<Reloaded> {
+        let config = Config::reload()?;
+        config.validate()?;
+        let settings = Settings::from_config(&config)?;
+        let certificates = self.certificates.as_ref().map(|certificates| certificates.reread()).transpose()?;
+        let mut applied = self.applied.lock().unwrap();
+        let (mut changed, ignored): (Vec<&str>, Vec<&str>) = config
+            .changed_since(&applied)
+            .into_iter()
+            .partition(|id| RELOADABLE.contains(id));
+        if let Some(file) = &config.file {
+            if !file.unknown.is_empty() {
+                warn!("Ignoring unknown keys in {}: {}", file.path.display(), file.unknown.join(", "));
+            }
+        }
+        for id in &ignored {
+            warn!("{} changed, but only takes effect after a restart", id);
+        }
+
+        // The only part that can still fail, so it goes first
+        if changed.contains(&"log_filter") {
+            self.log_filter
+                .set(settings.log_filter.as_deref().unwrap_or("info"))
+                .map_err(|e| anyhow!("Invalid log filter: {}", e))?;
+        }
+        self.admission.resize(settings.max_concurrent);
+        self.settings.store(Arc::new(settings));
+        for id in &changed {
+            applied.insert(id.to_string(), config.raw[*id].clone());
+        }
+        if let (Some(current), Some(reread)) = (&self.certificates, certificates) {
+            if reread.changed {
+                changed.push("tls_certificate");
+            }
+            current.apply(reread);
+        }
+
+        if changed.is_empty() {
+            info!("Reloaded the configuration, nothing changed");
+        } else {
+            info!("Reloaded the configuration, changing {}", changed.join(", "));
+        }
+        Ok(Reloaded {
+            changed: changed.into_iter().map(String::from).collect(),
+            ignored: ignored.into_iter().map(String::from).collect(),
+        })
+    }
+}
+
+/// Reload whenever we get SIGHUP.
+#[cfg(unix)]
+pub async fn on_sighup(reloader: Arc<Reloader>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            warn!("Can't reload the configuration on SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading the configuration");
+        if let Err(e) = reloader.reload().await {
+            warn!("Couldn't reload the configuration, keeping the current one: {:#}", e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn on_sighup(_reloader: Arc<Reloader>) {}
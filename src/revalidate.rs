@@ -0,0 +1,129 @@
+use crate::cache::{DiskCache, GifCache, Purge, Upstream};
+use crate::metrics::Metrics;
+use crate::reload::Settings;
+use arc_swap::ArcSwap;
+use fastgif_core::fetch::Revalidation;
+use fastgif_core::pipeline::{ConversionOptions, PipelineConfig};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Asks the upstream again about the videos cached GIFs were made from,
+/// once they've gone `REVALIDATE_AFTER` without being asked about, with a
+/// conditional HEAD request. One that's been taken down has its GIFs
+/// forgotten, so it isn't sent any more; one that's still there isn't asked
+/// about for another `REVALIDATE_AFTER`. The janitor does it after each
+/// periodic sweep, at most `REVALIDATE_MAX_PER_SEC`.
+pub struct Revalidator {
+    cache: Option<Arc<GifCache>>,
+    disk_cache: Option<Arc<DiskCache>>,
+    after: Duration,
+    max_per_sec: u32,
+    pipeline: Arc<PipelineConfig>,
+    settings: Arc<ArcSwap<Settings>>,
+    metrics: Arc<Metrics>,
+}
+
+/// What a round of revalidating came to.
+#[derive(Debug, Default, Clone)]
+pub struct Revalidated {
+    pub current: u64,
+    pub gone: u64,
+    pub unknown: u64,
+}
+
+impl Revalidator {
+    pub fn new(
+        cache: Option<Arc<GifCache>>,
+        disk_cache: Option<Arc<DiskCache>>,
+        after: Duration,
+        max_per_sec: u32,
+        pipeline: Arc<PipelineConfig>,
+        settings: Arc<ArcSwap<Settings>>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self { cache, disk_cache, after, max_per_sec: max_per_sec.max(1), pipeline, settings, metrics }
+    }
+
+    /// Ask about every video that's due, for up to `budget`; what's left is
+    /// asked about next time. Requests are sent one at a time, and nothing's
+    /// locked while they're waited on.
+    pub async fn revalidate(&self, budget: Duration) -> Revalidated {
+        let started = Instant::now();
+        let mut revalidated = Revalidated::default();
+        let due = self.due();
+        let spacing = Duration::from_secs(1) / self.max_per_sec;
+        for (i, (key, upstreams)) in due.iter().enumerate() {
+            if i > 0 {
+                if started.elapsed() + spacing > budget {
+                    debug!("Leaving {} cached GIFs to be revalidated next time", due.len() - i);
+                    break;
+                }
+                tokio::time::sleep(spacing).await;
+            }
+            let upstream = &upstreams[0];
+            let url = format!("{}/tweet_video/{}", self.settings.load().video_base_url, upstream.path);
+            let source = self.pipeline.source_ref(&upstream.path, url, &ConversionOptions::default());
+            match self.pipeline.source_fetcher.revalidate(&source, &upstream.validators()).await {
+                Revalidation::Current(validators) => {
+                    for upstream in upstreams {
+                        upstream.checked(validators.clone());
+                    }
+                    revalidated.current += 1;
+                    self.metrics.revalidated("current");
+                }
+                Revalidation::Gone(status) => {
+                    info!("The upstream said {} for {}, so its cached GIF is forgotten", status, upstream.path);
+                    if let Some(cache) = &self.cache {
+                        cache.purge(key, Purge::Hard);
+                    }
+                    if let Some(disk_cache) = &self.disk_cache {
+                        disk_cache.purge(key, Purge::Hard);
+                    }
+                    revalidated.gone += 1;
+                    self.metrics.revalidated("gone");
+                }
+                Revalidation::Unknown(reason) => {
+                    debug!("Couldn't revalidate {}: {}", upstream.path, reason);
+                    revalidated.unknown += 1;
+                    self.metrics.revalidated("unknown");
+                }
+            }
+        }
+        if !due.is_empty() {
+            debug!(
+                current = revalidated.current,
+                gone = revalidated.gone,
+                unknown = revalidated.unknown,
+                "Revalidated {} cached GIFs' videos",
+                revalidated.current + revalidated.gone + revalidated.unknown
+            );
+        }
+        revalidated
+    }
+
+    // Every key that's due in either cache, each with the one or two videos'
+    // checks to update, in memory's order and then the disk's
+    fn due(&self) -> Vec<(String, Vec<Arc<Upstream>>)> {
+        let mut due: Vec<(String, Vec<Arc<Upstream>>)> = Vec::new();
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        let memory = self.cache.iter().flat_map(|cache| cache.due(self.after));
+        let disk = self.disk_cache.iter().flat_map(|disk_cache| disk_cache.due(self.after));
+        for (key, upstream) in memory.chain(disk) {
+            match seen.get(&key) {
+                Some(&i) => {
+                    let upstreams = &mut due[i].1;
+                    if !upstreams.iter().any(|seen| Arc::ptr_eq(seen, &upstream)) {
+                        upstreams.push(upstream);
+                    }
+                }
+                None => {
+                    seen.insert(key.clone(), due.len());
+                    due.push((key, vec![upstream]));
+                }
+            }
+        }
+        due
+    }
+}
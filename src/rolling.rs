@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// The window is cut into this many slices, each forgotten as a whole once
+// it's older than the window
+const SLICES: usize = 10;
+
+/// A histogram of only what was observed in the last `window`, for
+/// percentiles that follow the server as it is rather than since it
+/// started. It's a fixed set of buckets for each slice of the window, so it
+/// takes the same memory however much is observed, and observing is a few
+/// atomic operations.
+#[derive(Debug)]
+pub struct RollingHistogram {
+    /// Each bucket's upper bound; past the last is one more, unbounded
+    bounds: Vec<f64>,
+    window: Duration,
+    started: Instant,
+    slices: Vec<Slice>,
+}
+
+#[derive(Debug)]
+struct Slice {
+    /// Which slice of time since `started` it's counting, plus one, or 0
+    /// for none yet
+    slot: AtomicU64,
+    counts: Vec<AtomicU64>,
+}
+
+impl RollingHistogram {
+    pub fn new(bounds: Vec<f64>, window: Duration) -> Self {
+        let slices = (0..SLICES)
+            .map(|_| Slice { slot: AtomicU64::new(0), counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect() })
+            .collect();
+        Self { bounds, window, started: Instant::now(), slices }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let slot = self.slot();
+        let slice = &self.slices[slot as usize % SLICES];
+        // Whoever gets to a slice first for its time starts it again; what's
+        // observed by others just then may go with the old counts
+        if slice.slot.swap(slot + 1, Ordering::AcqRel) != slot + 1 {
+            for count in &slice.counts {
+                count.store(0, Ordering::Relaxed);
+            }
+        }
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        slice.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Each bucket's bound with how many in the window were at most that,
+    /// and how many there were in all, the way `summary::quantile` takes
+    /// them.
+    pub fn cumulative(&self) -> (Vec<(f64, u64)>, u64) {
+        let now = self.slot();
+        let mut counts = vec![0; self.bounds.len() + 1];
+        for slice in &self.slices {
+            let slot = slice.slot.load(Ordering::Acquire);
+            if slot > 0 && now + 1 - slot < SLICES as u64 {
+                for (total, count) in counts.iter_mut().zip(&slice.counts) {
+                    *total += count.load(Ordering::Relaxed);
+                }
+            }
+        }
+        let mut cumulative = 0;
+        let buckets = self
+            .bounds
+            .iter()
+            .zip(&counts)
+            .map(|(bound, count)| {
+                cumulative += count;
+                (*bound, cumulative)
+            })
+            .collect();
+        (buckets, counts.iter().sum())
+    }
+
+    fn slot(&self) -> u64 {
+        (self.started.elapsed().as_secs_f64() / (self.window.as_secs_f64() / SLICES as f64)) as u64
+    }
+}
@@ -0,0 +1,195 @@
+use crate::config::Config;
+use anyhow::{anyhow, Result};
+use reqwest::header::{self, HeaderMap};
+use reqwest::{Body, Url};
+use ring::{digest, hmac};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// How long an upload may take, connecting included.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long the presigned URL an upload is sent to works for.
+const UPLOAD_EXPIRES: Duration = Duration::from_secs(300);
+
+/// Where finished GIFs go to be fetched straight from (`S3_BUCKET`), rather
+/// than through us. Requests are signed with AWS Signature Version 4, in the
+/// query string, so the URLs handed out work without credentials until
+/// they expire. The bucket is addressed by path, which S3-compatible stores
+/// all take.
+pub struct Bucket {
+    client: reqwest::Client,
+    /// The API, e.g. `https://s3.us-east-1.amazonaws.com`
+    endpoint: Url,
+    name: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    prefix: String,
+    /// How long the URLs handed out work for (`S3_PRESIGN_TTL`)
+    presign_ttl: Duration,
+}
+
+impl Bucket {
+    /// The bucket `name`, as the rest of `config` says to reach it.
+    pub fn new(name: &str, config: &Config) -> Result<Self> {
+        let endpoint = config.s3_endpoint.clone().unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", config.s3_region));
+        let endpoint = Url::parse(&endpoint).map_err(|e| anyhow!("Invalid S3_ENDPOINT {:?}: {}", endpoint, e))?;
+        if !matches!(endpoint.scheme(), "http" | "https") || endpoint.host_str().is_none() {
+            return Err(anyhow!("Invalid S3_ENDPOINT {}: it has to be an http or https URL", endpoint));
+        }
+        let (Some(access_key_id), Some(secret_access_key)) = (&config.s3_access_key_id, &config.s3_secret_access_key) else {
+            return Err(anyhow!("S3_BUCKET needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY to upload with"));
+        };
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .user_agent(concat!("fastgif/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        info!("Uploading finished GIFs to the S3 bucket {} at {}", name, endpoint);
+        Ok(Self {
+            client,
+            endpoint,
+            name: name.to_string(),
+            region: config.s3_region.clone(),
+            access_key_id: access_key_id.clone(),
+            secret_access_key: secret_access_key.clone(),
+            session_token: config.s3_session_token.clone(),
+            prefix: config.s3_key_prefix.clone(),
+            presign_ttl: Duration::from_secs(config.s3_presign_ttl),
+        })
+    }
+
+    /// Upload `body`, a GIF of `len` bytes sent with `headers`, under a key
+    /// of its own dated today, e.g. `fastgif/2026/10/14/<random>.gif`, which
+    /// comes back. The dates are for the bucket's lifecycle rules to expire
+    /// old ones by. It's streamed, so a spilled GIF goes straight from its
+    /// file. If it can't be uploaded, that's logged and it's up to the caller
+    /// to send it some other way.
+    pub async fn upload(&self, headers: &HeaderMap, body: impl Into<Body>, len: u64) -> Option<String> {
+        let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("image/gif");
+        let now = SystemTime::now();
+        let (year, month, day, ..) = civil(now);
+        let mut name = [0u8; 16];
+        getrandom::fill(&mut name).expect("the OS has no random numbers to give");
+        let extension = content_type.rsplit('/').next().unwrap_or("gif");
+        let key = format!("{}{:04}/{:02}/{:02}/{}.{}", self.prefix, year, month, day, hex(&name), extension);
+        match self.put(&key, body.into(), len, content_type, now).await {
+            Ok(()) => {
+                info!("Uploaded {} ({} bytes) to S3", key, len);
+                Some(key)
+            }
+            Err(e) => {
+                warn!(outcome = "upload_failed", "Couldn't upload a GIF to S3, so it's sent directly: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn put(&self, key: &str, body: Body, len: u64, content_type: &str, now: SystemTime) -> Result<(), String> {
+        // S3 won't take a streamed body without its length
+        let response = self
+            .client
+            .put(self.presigned("PUT", key, UPLOAD_EXPIRES, now))
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, len)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("the bucket answered {}", status)),
+        }
+    }
+
+    /// A URL anyone can fetch `key` from for the next `S3_PRESIGN_TTL`.
+    pub fn presign(&self, key: &str) -> String {
+        self.presigned("GET", key, self.presign_ttl, SystemTime::now()).to_string()
+    }
+
+    // `key` to be `method`ed until `expires` after `now`, as a query-string
+    // signed URL
+    fn presigned(&self, method: &str, key: &str, expires: Duration, now: SystemTime) -> Url {
+        let (year, month, day, hour, minute, second) = civil(now);
+        let date = format!("{:04}{:02}{:02}", year, month, day);
+        let timestamp = format!("{}T{:02}{:02}{:02}Z", date, hour, minute, second);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let base = self.endpoint.path().trim_end_matches('/');
+        let path = format!("{}/{}/{}", base, encode(&self.name, true), encode(key, false));
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{}", self.endpoint.host_str().unwrap_or_default(), port),
+            None => self.endpoint.host_str().unwrap_or_default().to_string(),
+        };
+
+        // Already in order, as the canonical query has to be
+        let mut query = vec![
+            ("X-Amz-Algorithm", "AWS4-HMAC-SHA256".to_string()),
+            ("X-Amz-Credential", format!("{}/{}", self.access_key_id, scope)),
+            ("X-Amz-Date", timestamp.clone()),
+            ("X-Amz-Expires", expires.as_secs().to_string()),
+        ];
+        if let Some(token) = &self.session_token {
+            query.push(("X-Amz-Security-Token", token.clone()));
+        }
+        query.push(("X-Amz-SignedHeaders", "host".to_string()));
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, encode(value, true)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let canonical = format!("{}\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD", method, path, query, host);
+        let digest = hex(digest::digest(&digest::SHA256, canonical.as_bytes()).as_ref());
+        let to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", timestamp, scope, digest);
+        let mut key = format!("AWS4{}", self.secret_access_key).into_bytes();
+        for part in [date.as_str(), &self.region, "s3", "aws4_request"] {
+            key = sign(&key, part.as_bytes());
+        }
+        let signature = hex(&sign(&key, to_sign.as_bytes()));
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        url.set_query(Some(&format!("{}&X-Amz-Signature={}", query, signature)));
+        url
+    }
+}
+
+fn sign(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data).as_ref().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Percent-encoded the way SigV4 wants: everything but the unreserved
+// characters, and `/` too unless it's splitting up a key
+fn encode(value: &str, slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if !slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// `at` in UTC: the year, month, day, hour, minute and second
+fn civil(at: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, time) = ((secs / 86_400) as i64, secs % 86_400);
+    // Howard Hinnant's days-to-civil, counting from 0000-03-01
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, time / 3_600, time % 3_600 / 60, time % 60)
+}
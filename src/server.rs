@@ -0,0 +1,100 @@
+use crate::listener::{Connection, Peer};
+use axum::extract::ConnectInfo;
+use axum::serve::Listener;
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use std::future::Future;
+use std::pin::Pin;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tower_http::add_extension::AddExtension;
+use tracing::debug;
+
+/// How much of a response each HTTP/2 stream can have queued while the
+/// client's window is shut. hyper's default is 400 KiB; a bit more keeps a
+/// big GIF moving at the pace the client opens its window, without holding
+/// much more of it in memory per stream.
+const H2_SEND_BUFFER: usize = 1024 * 1024;
+
+/// Serve `app` on `listener`'s connections until `shutdown`, then wait for
+/// the ones still open to finish what they're doing. With `http2` a
+/// connection can speak HTTP/2 as well as HTTP/1.1, going by what it starts
+/// with: after TLS negotiated `h2`, or in cleartext with prior knowledge
+/// (`H2C`). Without it, it's HTTP/1.1 only.
+///
+/// This is `axum::serve` with the builders ours to set up, which it doesn't
+/// let us do.
+pub async fn serve<L>(
+    mut listener: L,
+    http2: bool,
+    app: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()>
+where
+    L: Listener<Addr = Connection>,
+{
+    let mut either = auto::Builder::new(TokioExecutor::new());
+    either
+        .http2()
+        // Job sockets are websockets, which HTTP/2 needs CONNECT for
+        .enable_connect_protocol()
+        // Uploads get a window that grows with the connection's
+        // bandwidth-delay product, rather than a fixed 1 MiB
+        .adaptive_window(true)
+        .max_send_buf_size(H2_SEND_BUFFER);
+    // The auto builder looks for HTTP/2's preface even when it's told
+    // HTTP/1.1 only, so that's one of hyper's own
+    let http1 = hyper::server::conn::http1::Builder::new();
+    let connections = TaskTracker::new();
+    let stopping = CancellationToken::new();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, connection) = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        let peer = connection.peer;
+        let service = TowerToHyperService::new(AddExtension::new(app.clone(), ConnectInfo(connection)));
+        let stream = TokioIo::new(stream);
+        if http2 {
+            let connection = either.serve_connection_with_upgrades(stream, service).into_owned();
+            connections.spawn(finish(connection, |connection| connection.graceful_shutdown(), stopping.clone(), peer));
+        } else {
+            let connection = http1.serve_connection(stream, service).with_upgrades();
+            connections.spawn(finish(connection, |connection| connection.graceful_shutdown(), stopping.clone(), peer));
+        }
+    }
+    // No more connections, and those still open are told to finish up
+    drop(listener);
+    connections.close();
+    stopping.cancel();
+    connections.wait().await;
+    Ok(())
+}
+
+// Serve `connection` until it's done, asking it to finish what it's doing
+// and take nothing more once `stopping` fires
+async fn finish<C, E>(connection: C, graceful_shutdown: fn(Pin<&mut C>), stopping: CancellationToken, peer: Peer)
+where
+    C: Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    tokio::pin!(connection);
+    let stopped = stopping.cancelled();
+    tokio::pin!(stopped);
+    let mut asked = false;
+    let result = loop {
+        tokio::select! {
+            result = connection.as_mut() => break result,
+            _ = &mut stopped, if !asked => {
+                asked = true;
+                graceful_shutdown(connection.as_mut());
+            }
+        }
+    };
+    if let Err(e) = result {
+        debug!("Connection from {} failed: {}", peer, e);
+    }
+}
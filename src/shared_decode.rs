@@ -0,0 +1,134 @@
+use fastgif_core::error::Result;
+use fastgif_core::fetch::SourceRef;
+use fastgif_core::pipeline::{process_tweet_video, GifOutput, PipelineConfig};
+use fastgif_core::shared_decode;
+use fastgif_core::{ConversionError, ConversionOptions};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, Instrument, Span};
+
+/// How long the first variant to be let in waits for the others before
+/// they're decoded without them. Each is admitted on its own, so one that
+/// can't get a slot while the rest hold theirs would otherwise keep them all
+/// waiting.
+const GATHER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// One variant's place among the variants of a video that share its decode
+/// (`"variants"` in `POST /batch` and `POST /prefetch`). The decode starts
+/// once every variant has joined or gone without converting, or after
+/// `GATHER_TIMEOUT` with those that have; any that come after that are
+/// converted on their own.
+pub struct Member {
+    gathering: Arc<Mutex<Gathering>>,
+    joined: bool,
+}
+
+struct Gathering {
+    /// Members that haven't joined or gone yet
+    waiting_for: usize,
+    joined: Vec<Joined>,
+    started: bool,
+}
+
+struct Joined {
+    source: SourceRef,
+    pipeline: Arc<PipelineConfig>,
+    options: ConversionOptions,
+    cancel: CancellationToken,
+    result: oneshot::Sender<Result<GifOutput>>,
+}
+
+/// A place for each of `variants` variants of the same video.
+pub fn group(variants: usize) -> Vec<Member> {
+    let gathering = Arc::new(Mutex::new(Gathering { waiting_for: variants, joined: Vec::new(), started: false }));
+    (0..variants).map(|_| Member { gathering: gathering.clone(), joined: false }).collect()
+}
+
+impl Member {
+    /// Convert this variant, from the decode it shares with the others if
+    /// it's in time for it, as `process_tweet_video` would without a sink.
+    pub async fn convert(
+        mut self,
+        source: &SourceRef,
+        pipeline: &Arc<PipelineConfig>,
+        options: &ConversionOptions,
+        cancel: CancellationToken,
+    ) -> Result<GifOutput> {
+        let (result, converted) = oneshot::channel();
+        let too_late = {
+            let mut gathering = self.gathering.lock().unwrap();
+            if !gathering.started {
+                gathering.joined.push(Joined {
+                    source: source.clone(),
+                    pipeline: pipeline.clone(),
+                    options: options.clone(),
+                    cancel: cancel.clone(),
+                    result,
+                });
+                gathering.waiting_for -= 1;
+                self.joined = true;
+                if gathering.waiting_for == 0 {
+                    gathering.start();
+                }
+            }
+            !self.joined
+        };
+        if too_late {
+            return process_tweet_video(source, pipeline, options, None, cancel).await;
+        }
+        tokio::pin!(converted);
+        let converted = tokio::select! {
+            converted = &mut converted => converted,
+            _ = tokio::time::sleep(GATHER_TIMEOUT) => {
+                self.gathering.lock().unwrap().start();
+                converted.await
+            }
+        };
+        converted.unwrap_or(Err(ConversionError::Cancelled))
+    }
+}
+
+impl Drop for Member {
+    fn drop(&mut self) {
+        if self.joined {
+            return;
+        }
+        // Sent from the cache, refused, or converted some other way
+        let mut gathering = self.gathering.lock().unwrap();
+        gathering.waiting_for -= 1;
+        if gathering.waiting_for == 0 {
+            gathering.start();
+        }
+    }
+}
+
+impl Gathering {
+    // Decode for the variants that have joined, unless it's started already
+    fn start(&mut self) {
+        if self.started || self.joined.is_empty() {
+            return;
+        }
+        self.started = true;
+        let joined = std::mem::take(&mut self.joined);
+        info!("Converting {} variants of {}", joined.len(), joined[0].source.url);
+        tokio::spawn(convert(joined).instrument(Span::current()));
+    }
+}
+
+// Each joined variant, sent what became of it. They're all of the same
+// video, converted by the same pipeline.
+async fn convert(joined: Vec<Joined>) {
+    let (source, pipeline) = (joined[0].source.clone(), joined[0].pipeline.clone());
+    let mut variants = Vec::with_capacity(joined.len());
+    let mut results = Vec::with_capacity(joined.len());
+    for Joined { options, cancel, result, .. } in joined {
+        variants.push((options, cancel));
+        results.push(result);
+    }
+    let converted = shared_decode::convert(&source, &pipeline, variants).await;
+    for (result, converted) in results.into_iter().zip(converted) {
+        let _ = result.send(converted);
+    }
+}
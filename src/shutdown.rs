@@ -0,0 +1,98 @@
+use fastgif_core::pipeline::ConversionHandle;
+use std::future::Future;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{info, warn};
+
+/// Every running conversion, so shutdown can wait for them to finish and
+/// cancel whichever are still going when the grace period runs out.
+#[derive(Clone, Default)]
+pub struct Conversions {
+    tracker: TaskTracker,
+    cancel: CancellationToken,
+}
+
+impl Conversions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A token for a conversion that's about to be spawned, which fires if
+    /// it gets cancelled during shutdown.
+    pub fn token(&self) -> CancellationToken {
+        self.cancel.child_token()
+    }
+
+    /// Spawn a conversion. It's handed `token`, from [`token`](Self::token),
+    /// which also fires if it gets cancelled through the returned handle.
+    pub fn spawn<F, Fut>(&self, token: CancellationToken, conversion: F) -> ConversionHandle<Fut::Output>
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        ConversionHandle::new(self.tracker.spawn(conversion(token.clone())), token)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.tracker.len()
+    }
+
+    /// Whether shutdown has started, so no new conversions should come our way.
+    pub fn is_draining(&self) -> bool {
+        self.tracker.is_closed()
+    }
+
+    /// Give in-flight conversions up to `grace` to finish, then cancel the rest
+    /// and wait for them to clean up after themselves.
+    pub async fn drain(&self, grace: Duration) {
+        self.tracker.close();
+        if self.tracker.is_empty() {
+            return;
+        }
+        info!(
+            "Waiting up to {:?} for {} in-flight conversions to finish",
+            grace,
+            self.in_flight()
+        );
+        if tokio::time::timeout(grace, self.tracker.wait()).await.is_ok() {
+            info!("All in-flight conversions finished");
+            return;
+        }
+        warn!(
+            "Grace period over, cancelling {} remaining conversions",
+            self.in_flight()
+        );
+        self.cancel.cancel();
+        self.tracker.wait().await;
+        info!("Remaining conversions cancelled");
+    }
+}
+
+/// Resolves once we've been asked to stop, with the name of the signal.
+pub async fn signal() -> &'static str {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+        "SIGINT"
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+        "SIGTERM"
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<&'static str>();
+
+    tokio::select! {
+        name = ctrl_c => name,
+        name = terminate => name,
+    }
+}
@@ -0,0 +1,166 @@
+use crate::listener::{Connection, Peer};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+use tracing::warn;
+
+/// Drops connections whose client won't take what we send
+/// (`SLOW_CLIENT_TIMEOUT` / `SLOW_CLIENT_MIN_BYTES`).
+#[derive(Debug, Clone, Copy)]
+pub struct SlowClientGuard {
+    /// The client has to accept this many bytes...
+    pub min_bytes: u64,
+    /// ...within this much time spent waiting on it
+    pub timeout: Duration,
+}
+
+/// A TCP or unix listener whose connections are watched by the guard, if
+/// there is one.
+pub struct GuardedListener<L> {
+    inner: L,
+    guard: Option<SlowClientGuard>,
+}
+
+impl<L> GuardedListener<L> {
+    pub fn new(inner: L, guard: Option<SlowClientGuard>) -> Self {
+        Self { inner, guard }
+    }
+}
+
+impl<L> axum::serve::Listener for GuardedListener<L>
+where
+    L: axum::serve::Listener,
+    L::Addr: Into<Connection>,
+{
+    type Io = GuardedStream<L::Io>;
+    type Addr = Connection;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, connection) = self.inner.accept().await;
+        let connection = connection.into();
+        (GuardedStream::new(stream, self.guard, connection.peer), connection)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr().map(Into::into)
+    }
+}
+
+/// A connection that fails its writes once the client has kept us waiting for
+/// longer than the timeout without taking `min_bytes`.
+///
+/// Only time spent blocked on a full socket counts. An idle keep-alive
+/// connection, or a response that's paced by the throttle, isn't waiting on the
+/// client. hyper drops the response when a write fails, which frees the GIF and,
+/// for a streamed response, cancels the conversion.
+pub struct GuardedStream<S> {
+    inner: S,
+    guard: Option<SlowClientGuard>,
+    peer: Peer,
+    /// Bytes written since the client last took `min_bytes`
+    written: u64,
+    /// Time spent blocked since then, not counting the current wait
+    blocked: Duration,
+    blocked_since: Option<Instant>,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S: AsyncWrite + Unpin> GuardedStream<S> {
+    fn new(inner: S, guard: Option<SlowClientGuard>, peer: Peer) -> Self {
+        Self {
+            inner,
+            guard,
+            peer,
+            written: 0,
+            blocked: Duration::ZERO,
+            blocked_since: None,
+            timer: None,
+        }
+    }
+
+    fn poll_guarded(
+        &mut self,
+        cx: &mut Context<'_>,
+        write: impl FnOnce(Pin<&mut S>, &mut Context<'_>) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<usize>> {
+        let result = write(Pin::new(&mut self.inner), cx);
+        let Some(guard) = self.guard else {
+            return result;
+        };
+        let now = Instant::now();
+        match result {
+            Poll::Ready(Ok(written)) => {
+                if let Some(since) = self.blocked_since.take() {
+                    self.blocked += now - since;
+                }
+                self.timer = None;
+                self.written += written as u64;
+                if self.written >= guard.min_bytes {
+                    self.written = 0;
+                    self.blocked = Duration::ZERO;
+                }
+                Poll::Ready(Ok(written))
+            }
+            Poll::Pending => {
+                let since = *self.blocked_since.get_or_insert(now);
+                let deadline = since + guard.timeout.saturating_sub(self.blocked);
+                if now >= deadline {
+                    warn!(
+                        outcome = "client_too_slow",
+                        "{} took less than {} bytes in {:?}, dropping the connection",
+                        self.peer,
+                        guard.min_bytes,
+                        guard.timeout
+                    );
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "client too slow")));
+                }
+                // Get polled again when time's up, in case the socket never frees up
+                let timer = self.timer.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+                if timer.deadline() != deadline {
+                    timer.as_mut().reset(deadline);
+                }
+                if timer.as_mut().poll(cx).is_ready() {
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Pending
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for GuardedStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for GuardedStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.poll_guarded(cx, |inner, cx| inner.poll_write(cx, buf))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_guarded(cx, |inner, cx| inner.poll_write_vectored(cx, bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
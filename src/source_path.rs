@@ -0,0 +1,36 @@
+/// Why a `/tweet_video/` path was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidPath(pub &'static str);
+
+impl std::fmt::Display for InvalidPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPath {}
+
+/// Longest path taken, well past any video's name upstream.
+const MAX_LEN: usize = 200;
+
+/// The upstream path for `/tweet_video/{raw}`, where `raw` is already
+/// percent-decoded: one file name of ASCII letters, digits, `-`, `_` and `.`,
+/// with `.gif` asked for as the `.mp4` it's made from. What comes back goes
+/// into URLs and `SOURCE_DIR` paths as it is, so it never has a `/`, a `..`,
+/// or anything a URL or a log line would read differently.
+pub fn canonicalize(raw: &str) -> Result<String, InvalidPath> {
+    if raw.is_empty() {
+        return Err(InvalidPath("the path is empty"));
+    }
+    if raw.len() > MAX_LEN {
+        return Err(InvalidPath("the path is too long"));
+    }
+    if !raw.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(InvalidPath("only letters, digits, '-', '_' and '.' are allowed"));
+    }
+    if raw.starts_with('.') || raw.contains("..") {
+        return Err(InvalidPath("the path can't start with '.' or have '..' in it"));
+    }
+    // god i hope they don't only render gifs from tenor...
+    Ok(raw.replace(".gif", ".mp4"))
+}
@@ -0,0 +1,105 @@
+use crate::stills::ImageFormat;
+use crate::variant::VariantKey;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// The most columns, and the most rows, a sprite sheet can have.
+pub const MAX_GRID: u32 = 10;
+
+/// The widest a sprite sheet's cells can be, in pixels.
+pub const MAX_CELL_WIDTH: u32 = 320;
+
+/// What `GET /tweet_video/{path}/sprite` takes: how many cells across and
+/// down, how wide each is, and whether it's a PNG or a JPEG.
+#[derive(Debug, Default, Deserialize)]
+pub struct SpriteQuery {
+    pub cols: Option<u32>,
+    pub rows: Option<u32>,
+    pub width: Option<u32>,
+    pub format: Option<String>,
+}
+
+/// A sprite sheet's layout, as asked for and checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Grid {
+    pub cols: u32,
+    pub rows: u32,
+    pub width: u32,
+    pub format: ImageFormat,
+}
+
+impl SpriteQuery {
+    /// The grid asked for, 5 by 4 cells 160 pixels wide as a PNG unless it
+    /// says otherwise, or why it can't be made.
+    pub fn grid(&self) -> Result<Grid, String> {
+        let cols = self.cols.unwrap_or(5);
+        let rows = self.rows.unwrap_or(4);
+        let width = self.width.unwrap_or(160);
+        if !(1..=MAX_GRID).contains(&cols) || !(1..=MAX_GRID).contains(&rows) {
+            return Err(format!("cols and rows have to be from 1 to {}", MAX_GRID));
+        }
+        if !(2..=MAX_CELL_WIDTH).contains(&width) {
+            return Err(format!("width has to be from 2 to {}", MAX_CELL_WIDTH));
+        }
+        let format = match self.format.as_deref().map(str::parse) {
+            None => ImageFormat::Png,
+            Some(Ok(format @ (ImageFormat::Png | ImageFormat::Jpeg))) => format,
+            Some(_) => return Err(format!("{:?} isn't png or jpeg", self.format.as_deref().unwrap_or_default())),
+        };
+        Ok(Grid { cols, rows, width, format })
+    }
+}
+
+impl Grid {
+    pub fn frames(&self) -> u32 {
+        self.cols * self.rows
+    }
+
+    /// How tall each cell is for a video shown `width` by `height`: as tall
+    /// as keeps its shape at the cell's width, to the nearest even pixel, so
+    /// a JPEG's halved chroma lines up with the cells.
+    pub fn cell_height(&self, width: u32, height: u32) -> u32 {
+        let height = f64::from(self.width) * f64::from(height) / f64::from(width);
+        ((height / 2.0).round() as u32 * 2).max(2)
+    }
+
+    /// How far apart the frames are taken from a video of `duration`, the
+    /// first at its start.
+    pub fn interval(&self, duration: Duration) -> Duration {
+        duration / self.frames()
+    }
+
+    /// What it's kept as for the video `variant` is of, made as wide as a cell.
+    pub fn key(&self, variant: &VariantKey) -> String {
+        format!("{} as a sprite sheet: {}x{} cells, {}", variant, self.cols, self.rows, self.format.as_str())
+    }
+
+    /// The ffmpeg argv (minus the binary) that tiles `cell_height` high
+    /// frames of the video at `input`, `duration` long, into one image on
+    /// stdout.
+    pub fn ffmpeg_args(&self, input: &str, duration: Duration, cell_height: u32, threads: usize) -> Vec<String> {
+        let filter = format!(
+            "fps={}/{:.3},scale={}:{},setsar=1,tile={}x{}",
+            self.frames(),
+            duration.as_secs_f64().max(0.001),
+            self.width,
+            cell_height,
+            self.cols,
+            self.rows,
+        );
+        let mut args: Vec<String> = vec!["-v".into(), "error".into(), "-threads".into(), threads.to_string()];
+        args.extend(["-i".into(), input.into()]);
+        args.extend(["-an".into(), "-sn".into(), "-vf".into(), filter]);
+        args.extend(self.format.ffmpeg_output());
+        args
+    }
+
+    /// The headers that say where each frame is in the sheet.
+    pub fn headers(&self, cell_height: u32, duration: Duration) -> Vec<(&'static str, String)> {
+        vec![
+            ("x-fastgif-sprite-grid", format!("{}x{}", self.cols, self.rows)),
+            ("x-fastgif-sprite-cell", format!("{}x{}", self.width, cell_height)),
+            ("x-fastgif-sprite-interval", format!("{:.3}", self.interval(duration).as_secs_f64())),
+        ]
+    }
+}
@@ -0,0 +1,108 @@
+use fastgif_core::dns::DnsStats;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Sends metrics as DogStatsD datagrams, for agents listening on UDP. Every
+/// metric is sent as it's recorded, and anything that can't be sent right
+/// away is dropped rather than waited on.
+#[derive(Debug)]
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    /// Tags sent with everything, e.g. `instance:web-1`
+    tags: Vec<String>,
+    // The resolver's counts as of the last flush, since it only keeps totals
+    dns_hits: AtomicU64,
+    dns_misses: AtomicU64,
+}
+
+impl Statsd {
+    /// Send to `addr` (e.g. `127.0.0.1:8125`), naming metrics `<prefix><name>`.
+    pub fn connect(addr: &str, prefix: &str, tags: Vec<String>) -> Result<Self> {
+        let addr: SocketAddr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("{} doesn't resolve to anything", addr))?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            prefix: prefix.to_string(),
+            tags,
+            dns_hits: AtomicU64::new(0),
+            dns_misses: AtomicU64::new(0),
+        })
+    }
+
+    pub fn count(&self, name: &str, value: u64, tags: &[(&str, &str)]) {
+        self.send(name, value, "c", tags);
+    }
+
+    pub fn gauge(&self, name: &str, value: u64, tags: &[(&str, &str)]) {
+        self.send(name, value, "g", tags);
+    }
+
+    pub fn timing(&self, name: &str, took: Duration, tags: &[(&str, &str)]) {
+        self.send(name, format!("{:.3}", took.as_secs_f64() * 1000.0), "ms", tags);
+    }
+
+    pub fn histogram(&self, name: &str, value: u64, tags: &[(&str, &str)]) {
+        self.send(name, value, "h", tags);
+    }
+
+    /// Send what's only kept as a current value or a running total elsewhere.
+    pub fn flush(&self, in_flight: usize, queued: &BTreeMap<&'static str, usize>, dns: &DnsStats) {
+        self.gauge("conversions_in_flight", in_flight as u64, &[]);
+        for (priority, queued) in queued {
+            self.gauge("conversions_queued", *queued as u64, &[("priority", priority)]);
+        }
+        let hits = dns.hits.saturating_sub(self.dns_hits.swap(dns.hits, Ordering::Relaxed));
+        let misses = dns.misses.saturating_sub(self.dns_misses.swap(dns.misses, Ordering::Relaxed));
+        for (name, count) in [("cache_hits", hits), ("cache_misses", misses)] {
+            if count > 0 {
+                self.count(name, count, &[("cache", "dns")]);
+            }
+        }
+    }
+
+    fn send(&self, name: &str, value: impl Display, kind: &str, tags: &[(&str, &str)]) {
+        let mut datagram = format!("{}{}:{}|{}", self.prefix, name, value, kind);
+        let tags = self
+            .tags
+            .iter()
+            .cloned()
+            .chain(tags.iter().map(|(key, value)| format!("{}:{}", key, value)))
+            .collect::<Vec<_>>();
+        if !tags.is_empty() {
+            datagram.push_str("|#");
+            datagram.push_str(&tags.join(","));
+        }
+        // Nobody listening, or a full buffer: either way it's not worth waiting for
+        let _ = self.socket.send(datagram.as_bytes());
+    }
+}
+
+/// Parse `STATSD_TAGS`: comma-separated `key:value` pairs (or bare tags).
+pub fn parse_tags(spec: &str) -> Result<Vec<String>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| {
+            if tag.contains(['|', '#']) {
+                Err(format!("tag {:?} can't contain '|' or '#'", tag))
+            } else {
+                Ok(tag.to_string())
+            }
+        })
+        .collect()
+}
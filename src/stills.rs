@@ -0,0 +1,280 @@
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use fastgif_core::error_class::{unsupported_source, ErrorClass};
+use fastgif_core::fetch::{SourceFetcher, SourceRef};
+use fastgif_core::process::{killed_by_limit, Binary, ChildLimits, ProcessGuard};
+use fastgif_core::stderr_tail;
+use fastgif_core::temp::{TempFile, TempManager};
+use fastgif_core::timing::StageTimes;
+use fastgif_core::error::Result;
+use fastgif_core::ConversionError;
+use futures_util::StreamExt;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tracing::{info, warn};
+
+/// How long ffmpeg gets to make a still.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a still is kept for.
+const KEPT_FOR: Duration = Duration::from_secs(60 * 60);
+
+/// The most bytes of stills kept at once, past which the least recently
+/// sent go.
+const MAX_KEPT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// The most stills kept at once, however small.
+const MAX_KEPT: usize = 1_000;
+
+/// What a still image is encoded as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ImageFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+
+    /// ffmpeg's output options for one image in this format, on stdout.
+    pub fn ffmpeg_output(self) -> Vec<String> {
+        let codec: &[&str] = match self {
+            ImageFormat::Png => &["-c:v", "png"],
+            ImageFormat::Jpeg => &["-c:v", "mjpeg", "-q:v", "3"],
+            ImageFormat::Webp => &["-c:v", "libwebp", "-quality", "80"],
+        };
+        let mut args: Vec<String> = vec!["-frames:v".into(), "1".into()];
+        args.extend(codec.iter().map(|arg| arg.to_string()));
+        args.extend(["-f".into(), "image2pipe".into(), "pipe:1".into()]);
+        args
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(ImageFormat::Png),
+            "jpeg" | "jpg" => Ok(ImageFormat::Jpeg),
+            "webp" => Ok(ImageFormat::Webp),
+            other => Err(format!("{:?} isn't png, jpeg or webp", other)),
+        }
+    }
+}
+
+/// An image made from a video's frames, with the headers that say what in
+/// the video it shows.
+#[derive(Debug, Clone)]
+pub struct Still {
+    pub body: Bytes,
+    pub format: ImageFormat,
+    pub headers: Vec<(&'static str, String)>,
+}
+
+impl Still {
+    /// `cache` is `HIT` or `MISS`, for `X-Cache`.
+    pub fn into_response(self, cache: &'static str) -> Response {
+        let mut response = (
+            [
+                (header::CONTENT_TYPE, self.format.content_type()),
+                (header::CACHE_CONTROL, "public, max-age=3600"),
+                (header::HeaderName::from_static("x-cache"), cache),
+            ],
+            self.body,
+        )
+            .into_response();
+        for (name, value) in self.headers {
+            if let Ok(value) = header::HeaderValue::from_str(&value) {
+                response.headers_mut().insert(name, value);
+            }
+        }
+        response
+    }
+}
+
+/// Fetch `source` with `fetcher` into a temp file from `temp`, counting it
+/// against `source.max_bytes` as it arrives, for ffmpeg to make stills from.
+pub async fn download(fetcher: &dyn SourceFetcher, source: &SourceRef, temp: &Arc<TempManager>) -> Result<TempFile> {
+    let mut video = fetcher.fetch(source).await?;
+    let (file, mut handle) = temp.create("source").await?;
+    let write_failed = || ConversionError::pipe(format!("Failed to write {}", file.path().display()));
+    let mut total = 0u64;
+    while let Some(chunk) = video.next().await.transpose()? {
+        total += chunk.len() as u64;
+        source.timings.downloaded(chunk.len());
+        if let Some(max) = source.max_bytes.filter(|max| total > *max) {
+            warn!(outcome = "input_too_large", "Download exceeded {} bytes, giving up", max);
+            return Err(ConversionError::InputTooLarge(max));
+        }
+        file.grew(chunk.len())?;
+        handle.write_all(&chunk).await.map_err(write_failed())?;
+    }
+    handle.flush().await.map_err(write_failed())?;
+    Ok(file)
+}
+
+/// Run ffmpeg with `args` and take the image it makes on stdout, giving up
+/// once it's more than `max_bytes`. What ffmpeg used goes to `timings`.
+pub async fn make(ffmpeg: &Binary, limits: &ChildLimits, args: &[String], max_bytes: Option<u64>, timings: &StageTimes) -> Result<Bytes> {
+    let mut process = ProcessGuard::spawn(
+        "ffmpeg",
+        ffmpeg.command().args(args).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()),
+        limits,
+    )
+    .map_err(|source| ConversionError::Spawn { binary: "ffmpeg", source })?;
+    let stdout = process.take_stdout().ok_or_else(|| ConversionError::internal("Failed to take ffmpeg stdout"))?;
+    let stderr = process.take_stderr().ok_or_else(|| ConversionError::internal("Failed to take ffmpeg stderr"))?;
+    let read = async { tokio::try_join!(read_image(stdout, max_bytes), read_cause(stderr)) };
+    let (image, cause) = match tokio::time::timeout(TIMEOUT, read).await {
+        Ok(Ok(read)) => read,
+        Ok(Err(e)) => {
+            process.terminate(Duration::from_secs(1)).await;
+            return Err(e);
+        }
+        Err(_) => {
+            process.terminate(Duration::from_secs(1)).await;
+            return Err(ConversionError::TimedOut(TIMEOUT));
+        }
+    };
+    let status = process.wait().await.map_err(ConversionError::pipe("Failed to wait for ffmpeg"))?;
+    if let Some(usage) = process.usage() {
+        timings.child_used(usage);
+    }
+    if killed_by_limit(&status) {
+        return Err(ConversionError::ResourceLimit("ffmpeg"));
+    }
+    if !status.success() {
+        if let Some(reason) = cause.as_deref().and_then(unsupported_source) {
+            return Err(ConversionError::UnsupportedSource(reason));
+        }
+        let class = cause.as_deref().and_then(ErrorClass::from_ffmpeg_line).unwrap_or(ErrorClass::FfmpegDecodeError);
+        return Err(ConversionError::Ffmpeg { code: status.code(), class });
+    }
+    if image.is_empty() {
+        return Err(ConversionError::Decode("ffmpeg made no image".to_string()));
+    }
+    Ok(image.into())
+}
+
+// All of ffmpeg's stdout, unless it's more than `max_bytes`
+async fn read_image(stdout: impl AsyncRead + Unpin, max_bytes: Option<u64>) -> Result<Vec<u8>> {
+    let mut image = Vec::new();
+    // One byte over is enough to tell
+    let limit = max_bytes.map_or(u64::MAX, |max| max.saturating_add(1));
+    stdout.take(limit).read_to_end(&mut image).await.map_err(ConversionError::pipe("Failed to read ffmpeg's image"))?;
+    match max_bytes.filter(|max| image.len() as u64 > *max) {
+        Some(max) => Err(ConversionError::OutputTooLarge(max)),
+        None => Ok(image),
+    }
+}
+
+// The first line of ffmpeg's stderr that says what went wrong, if one does.
+// Lines are read no longer than `stderr_tail` keeps them, and logged.
+async fn read_cause(stderr: impl AsyncRead + Unpin) -> Result<Option<String>> {
+    let mut reader = tokio::io::BufReader::new(stderr);
+    let mut line = String::new();
+    let mut cause = None;
+    while stderr_tail::read_line(&mut reader, &mut line).await.map_err(ConversionError::pipe("Failed to read ffmpeg stderr"))? {
+        info!(source = "ffmpeg", line = line.as_str(), "stderr");
+        if cause.is_none() && ErrorClass::from_ffmpeg_line(&line).is_some() {
+            cause = Some(line.clone());
+        }
+    }
+    Ok(cause)
+}
+
+/// The stills made lately, each for [`KEPT_FOR`], and no more than
+/// [`MAX_KEPT_BYTES`] of them.
+pub struct Stills {
+    kept: Mutex<Kept>,
+}
+
+struct Kept {
+    stills: LruCache<String, (Still, Instant)>,
+    bytes: u64,
+}
+
+impl Default for Stills {
+    fn default() -> Self {
+        let stills = LruCache::new(NonZeroUsize::new(MAX_KEPT).unwrap());
+        Self { kept: Mutex::new(Kept { stills, bytes: 0 }) }
+    }
+}
+
+impl Stills {
+    pub fn get(&self, key: &str) -> Option<Still> {
+        let mut kept = self.kept.lock().unwrap();
+        let (still, expires) = kept.stills.get(key)?;
+        if *expires > Instant::now() {
+            return Some(still.clone());
+        }
+        if let Some((still, _)) = kept.stills.pop(key) {
+            kept.bytes -= still.body.len() as u64;
+        }
+        None
+    }
+
+    pub fn put(&self, key: String, still: Still) {
+        let size = still.body.len() as u64;
+        if size > MAX_KEPT_BYTES {
+            return;
+        }
+        let mut kept = self.kept.lock().unwrap();
+        if let Some((replaced, _)) = kept.stills.pop(&key) {
+            kept.bytes -= replaced.body.len() as u64;
+        }
+        while kept.bytes + size > MAX_KEPT_BYTES {
+            let Some((_, (oldest, _))) = kept.stills.pop_lru() else { break };
+            kept.bytes -= oldest.body.len() as u64;
+        }
+        if let Some((_, (evicted, _))) = kept.stills.push(key, (still, Instant::now() + KEPT_FOR)) {
+            kept.bytes -= evicted.body.len() as u64;
+        }
+        kept.bytes += size;
+    }
+}
+
+/// What a still is of the video, for its errors, logs and metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Sprite,
+    Frame,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Sprite => "sprite",
+            Kind::Frame => "frame",
+        }
+    }
+
+    pub fn noun(self) -> &'static str {
+        match self {
+            Kind::Sprite => "a sprite sheet",
+            Kind::Frame => "a frame",
+        }
+    }
+}
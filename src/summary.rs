@@ -0,0 +1,93 @@
+use crate::metrics::Totals;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::info;
+
+/// What happened between two `Totals`, for the `STATS_INTERVAL` log line.
+#[derive(Debug)]
+pub struct Summary {
+    pub requests: u64,
+    /// Conversions run, whether or not they succeeded
+    pub conversions: u64,
+    /// Only the classes that actually failed
+    pub errors: BTreeMap<&'static str, u64>,
+    pub client_aborts: u64,
+    /// `None` when nothing looked in a cache
+    pub cache_hit_ratio: Option<f64>,
+    pub p50: Option<Duration>,
+    pub p95: Option<Duration>,
+}
+
+impl Summary {
+    pub fn between(earlier: &Totals, now: &Totals) -> Self {
+        let errors: BTreeMap<_, _> = now
+            .errors
+            .iter()
+            .map(|(class, count)| (*class, count - earlier.errors.get(class).copied().unwrap_or(0)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        let hits = now.cache_hits - earlier.cache_hits;
+        let lookups = hits + now.cache_misses - earlier.cache_misses;
+        let durations: Vec<(f64, u64)> = now
+            .durations
+            .iter()
+            .zip(earlier.durations.iter().map(|(_, count)| *count).chain(std::iter::repeat(0)))
+            .map(|((bound, count), before)| (*bound, count - before))
+            .collect();
+        let succeeded = now.conversions - earlier.conversions;
+        Self {
+            requests: now.requests - earlier.requests,
+            conversions: succeeded + errors.values().sum::<u64>(),
+            errors,
+            client_aborts: now.client_aborts - earlier.client_aborts,
+            cache_hit_ratio: (lookups > 0).then(|| hits as f64 / lookups as f64),
+            p50: quantile(&durations, succeeded, 0.5),
+            p95: quantile(&durations, succeeded, 0.95),
+        }
+    }
+
+    /// Nothing happened, so there's nothing worth logging.
+    pub fn is_idle(&self) -> bool {
+        self.requests == 0 && self.conversions == 0
+    }
+
+    pub fn log(&self, in_flight: usize, queued: usize, probe_cached: usize, dns_cached: usize) {
+        let millis = |took: Option<Duration>| took.map(|took| took.as_millis() as u64);
+        info!(
+            requests = self.requests,
+            conversions = self.conversions,
+            cache_hit_ratio = self.cache_hit_ratio.map(|ratio| (ratio * 1000.0).round() / 1000.0),
+            p50_ms = millis(self.p50),
+            p95_ms = millis(self.p95),
+            errors = ?self.errors,
+            client_aborts = self.client_aborts,
+            in_flight,
+            queued,
+            probe_cached,
+            dns_cached,
+            "Stats summary"
+        );
+    }
+}
+
+/// Estimate the `q` quantile of `count` observations from cumulative bucket
+/// counts, assuming they're spread evenly within each bucket, the way
+/// Prometheus' `histogram_quantile` does. Anything past the last bucket is
+/// reported as its bound.
+pub fn quantile(buckets: &[(f64, u64)], count: u64, q: f64) -> Option<Duration> {
+    if count == 0 {
+        return None;
+    }
+    let rank = q * count as f64;
+    let mut lower = (0.0, 0);
+    for (bound, cumulative) in buckets {
+        if *cumulative as f64 >= rank {
+            let (lower_bound, lower_count) = lower;
+            let within = (*cumulative - lower_count).max(1) as f64;
+            let secs = lower_bound + (bound - lower_bound) * (rank - lower_count as f64) / within;
+            return Some(Duration::from_secs_f64(secs.max(0.0)));
+        }
+        lower = (*bound, *cumulative);
+    }
+    buckets.last().map(|(bound, _)| Duration::from_secs_f64(*bound))
+}
@@ -0,0 +1,148 @@
+use crate::listener::Bind;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Tell the service manager that started us about `state`, e.g. `READY=1`,
+/// over its `NOTIFY_SOCKET`. Nothing happens when there's no such socket,
+/// that is when we aren't a `Type=notify` service.
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        match path.as_encoded_bytes().strip_prefix(b"@") {
+            // An abstract socket, which only Linux has
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => Err(std::io::Error::other("abstract sockets need Linux")),
+            None => socket.send_to(state.as_bytes(), &path),
+        }
+    });
+    match sent {
+        Ok(_) => debug!("Told systemd {:?}", state),
+        Err(e) => warn!("Couldn't tell systemd {:?}: {}", state, e),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
+
+/// A listening socket systemd passed us (`LISTEN_FDS`), for socket activation.
+pub enum Activated {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Activated {
+    /// Where it listens, as it would be given in `BIND`.
+    pub fn bind(&self) -> std::io::Result<Bind> {
+        match self {
+            Activated::Tcp(listener) => listener.local_addr().map(Bind::Addr),
+            #[cfg(unix)]
+            Activated::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().ok_or_else(|| std::io::Error::other("an unnamed unix socket"))?;
+                Ok(Bind::Unix(path.to_path_buf()))
+            }
+        }
+    }
+}
+
+/// The sockets systemd is holding for us, if it started us by socket
+/// activation, in the order the socket unit lists them, each with its
+/// `FileDescriptorName`. Empty when we weren't socket-activated.
+#[cfg(unix)]
+pub fn listen_fds() -> anyhow::Result<Vec<(Activated, String)>> {
+    use anyhow::anyhow;
+
+    /// The first fd systemd passes, after stdin, stdout and stderr
+    const LISTEN_FDS_START: i32 = 3;
+
+    let Some(count) = std::env::var("LISTEN_FDS").ok() else {
+        return Ok(Vec::new());
+    };
+    // Meant for us rather than a process we were started by
+    if std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        debug!("Ignoring LISTEN_FDS, which is for another process");
+        return Ok(Vec::new());
+    }
+    let count = count.parse::<i32>().map_err(|_| anyhow!("LISTEN_FDS={:?} isn't a number", count))?;
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            let name = names.next().filter(|name| !name.is_empty()).unwrap_or("unknown").to_string();
+            // Everything here is ours, and taken over only this once
+            let activated = unsafe { adopt(fd) }.map_err(|e| anyhow!("Couldn't use the socket systemd passed as fd {} ({}): {}", fd, name, e))?;
+            Ok((activated, name))
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> anyhow::Result<Vec<(Activated, String)>> {
+    Ok(Vec::new())
+}
+
+/// Take over the listening socket `fd`, which is TCP or a unix socket. It's
+/// closed on exec from here on, so it isn't handed down to ffmpeg and the
+/// other children.
+///
+/// # Safety
+///
+/// `fd` has to be an open file descriptor that nothing else owns or will
+/// close.
+#[cfg(unix)]
+unsafe fn adopt(fd: std::os::fd::RawFd) -> std::io::Result<Activated> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // Only an IP socket has an address a TcpListener understands
+    let tcp = std::net::TcpListener::from(fd);
+    if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true)?;
+        return Ok(Activated::Tcp(tcp));
+    }
+    let unix = std::os::unix::net::UnixListener::from(OwnedFd::from(tcp));
+    unix.local_addr()?;
+    unix.set_nonblocking(true)?;
+    Ok(Activated::Unix(unix))
+}
+
+/// How often systemd wants to hear we're alive (`WatchdogSec`), if it does.
+fn watchdog_timeout() -> Option<Duration> {
+    // Meant for us rather than a process we were started by, or by systemd itself
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Ping the watchdog at half its timeout for as long as the runtime keeps
+/// running us, so a wedged server stops pinging and gets restarted.
+pub async fn watchdog() {
+    let Some(timeout) = watchdog_timeout() else {
+        return;
+    };
+    info!("Pinging the systemd watchdog every {:?}", timeout / 2);
+    let mut ticks = tokio::time::interval(timeout / 2);
+    loop {
+        ticks.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
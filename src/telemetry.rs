@@ -0,0 +1,321 @@
+use anyhow::Result;
+use axum::extract::Request;
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use fastgif_core::trace::TraceContext;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{TraceContextExt, TracerProvider};
+use opentelemetry_sdk::trace::{IdGenerator, RandomIdGenerator};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use serde_json::{Map, Value};
+use std::env;
+use std::fmt;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{warn, Event, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::reload;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+
+/// How log lines are written (`LOG_FORMAT`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines, the default
+    Pretty,
+    /// One JSON object per line, for log pipelines
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format {:?}, expected json or pretty", other)),
+        }
+    }
+}
+
+/// Which log lines get written, changeable while we're running
+/// (`PUT /admin/log-level`). Starts out as `RUST_LOG`, or `info`.
+#[derive(Clone)]
+pub struct LogFilter(reload::Handle<EnvFilter, Registry>);
+
+impl LogFilter {
+    /// The filter in `RUST_LOG` syntax, e.g. `info,fastgif::pipeline=debug`.
+    pub fn current(&self) -> String {
+        self.0.with_current(|filter| filter.to_string()).unwrap_or_default()
+    }
+
+    /// Switch to the filter in `spec`. One that doesn't parse is refused and
+    /// the current filter stays.
+    pub fn set(&self, spec: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(spec).map_err(|e| e.to_string())?;
+        self.0.reload(filter).map_err(|e| e.to_string())
+    }
+}
+
+/// A filter no subscriber uses, for running the app without `init`, as
+/// tests do. It can't be set.
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self(reload::Layer::new(EnvFilter::new("info")).1)
+    }
+}
+
+/// Set up logging with the `filter` given (`RUST_LOG`), and trace export when
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set. The returned provider has to be shut
+/// down on exit so the last spans get sent.
+pub fn init(format: LogFormat, filter: Option<&str>) -> Result<(LogFilter, Option<SdkTracerProvider>)> {
+    let (filter, invalid) = match filter {
+        Some(spec) => match EnvFilter::try_new(spec) {
+            Ok(filter) => (filter, None),
+            Err(e) => (EnvFilter::new("info"), Some((spec, e))),
+        },
+        None => (EnvFilter::new("info"), None),
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+
+    // Colours only make sense on a terminal, whatever the format
+    let ansi = std::io::stdout().is_terminal();
+    let fmt = match format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer()
+            .with_target(false)
+            .with_thread_ids(false)
+            .with_thread_names(false)
+            .with_ansi(ansi)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .fmt_fields(JsonFields::new())
+            .event_format(JsonFormat)
+            .boxed(),
+    };
+
+    // The exporter picks the endpoint (and the rest of the OTEL_EXPORTER_OTLP_*
+    // settings) up from the environment itself
+    let provider = match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(_) => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder().with_http().build()?;
+            let service_name = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "fastgif".to_string());
+            let provider = SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(Resource::builder().with_service_name(service_name).build())
+                .build();
+            opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+            Some(provider)
+        }
+        Err(_) => None,
+    };
+    let otel = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("fastgif")));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt.and_then(otel))
+        .init();
+    if let Some((spec, e)) = invalid {
+        warn!("Ignoring invalid RUST_LOG {:?}: {}", spec, e);
+    }
+    Ok((LogFilter(handle), provider))
+}
+
+/// The ID a request's log lines are tagged with: the caller's `X-Request-Id`
+/// if it sent one, otherwise one of ours. It's in the request's extensions.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Middleware giving each request its [`RequestId`] and, if the caller sent a
+/// valid `traceparent`, its `TraceContext`, before anything that logs gets
+/// to see them.
+pub async fn assign_request_id(mut request: Request, next: Next) -> Response {
+    let id = request_id(request.headers());
+    request.extensions_mut().insert(RequestId(id));
+    if let Some(trace) = caller_trace(request.headers()) {
+        request.extensions_mut().insert(trace);
+    }
+    next.run(request).await
+}
+
+/// The caller's trace context, with a new span of ours in it. A missing,
+/// repeated or malformed `traceparent` means there's none; a `tracestate`
+/// that doesn't look right is dropped and the rest kept. It's put in the
+/// request's extensions.
+pub fn caller_trace(headers: &HeaderMap) -> Option<TraceContext> {
+    let mut values = headers.get_all("traceparent").iter();
+    let (Some(value), None) = (values.next(), values.next()) else {
+        return None;
+    };
+    let (trace_id, parent_span_id, flags) = parse_traceparent(value.to_str().ok()?)?;
+    let tracestate = headers
+        .get_all("tracestate")
+        .iter()
+        .map(|value| value.to_str().ok())
+        .collect::<Option<Vec<_>>>()
+        .map(|values| values.join(","))
+        .filter(|state| !state.is_empty() && state.len() <= 512 && state.bytes().all(|b| (b' '..=b'~').contains(&b)));
+    Some(TraceContext {
+        trace_id: trace_id.to_string(),
+        span_id: RandomIdGenerator::default().new_span_id().to_string(),
+        parent_span_id: Some(parent_span_id.to_string()),
+        flags,
+        tracestate,
+    })
+}
+
+/// The trace `span` is in: the one being exported, if it's part of one,
+/// otherwise the caller's.
+pub fn trace_of(span: &Span, caller: Option<&TraceContext>) -> Option<TraceContext> {
+    let context = span.context();
+    let exported = context.span().span_context().clone();
+    if !exported.is_valid() {
+        return caller.cloned();
+    }
+    let tracestate = exported.trace_state().header();
+    Some(TraceContext {
+        trace_id: exported.trace_id().to_string(),
+        span_id: exported.span_id().to_string(),
+        parent_span_id: caller.and_then(|caller| caller.parent_span_id.clone()),
+        flags: exported.trace_flags().to_u8(),
+        tracestate: Some(tracestate).filter(|state| !state.is_empty()),
+    })
+}
+
+// `00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01`: the trace ID, the
+// parent span ID and the flags. Versions after 00 may add fields, which are
+// ignored; ff is never valid.
+fn parse_traceparent(value: &str) -> Option<(&str, &str, u8)> {
+    let value = value.trim();
+    let version = value.get(..2)?;
+    let lower_hex = |part: &str, len: usize| {
+        part.len() == len && part.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+    if !lower_hex(version, 2) || version == "ff" {
+        return None;
+    }
+    let fields = match version {
+        "00" if value.len() == 55 => value,
+        "00" => return None,
+        _ if value.len() == 55 || value.as_bytes().get(55) == Some(&b'-') => value.get(..55)?,
+        _ => return None,
+    };
+    let mut parts = fields.split('-');
+    let (_, trace_id, parent_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let valid = lower_hex(trace_id, 32)
+        && lower_hex(parent_id, 16)
+        && lower_hex(flags, 2)
+        && trace_id.bytes().any(|b| b != b'0')
+        && parent_id.bytes().any(|b| b != b'0');
+    if !valid {
+        return None;
+    }
+    Some((trace_id, parent_id, u8::from_str_radix(flags, 16).ok()?))
+}
+
+fn request_id(headers: &HeaderMap) -> String {
+    static STARTED: OnceLock<u64> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    if let Some(id) = headers.get("x-request-id").and_then(|id| id.to_str().ok()) {
+        return id.to_string();
+    }
+    // Unique enough across restarts without keeping any state around
+    let started = STARTED.get_or_init(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+    format!("{:x}-{}", started, NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Make `span` part of the trace the caller is in, going by its `traceparent`
+/// (and `tracestate`) headers.
+pub fn set_parent(span: &Span, headers: &HeaderMap) {
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    let _ = span.set_parent(parent);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Writes each event as one JSON object: `timestamp`, `level`, `message` and
+/// the event's own fields, plus the fields of every span it's in (inner spans
+/// win) and the name of the innermost one as `span`.
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut timestamp = String::new();
+        tracing_subscriber::fmt::time::SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let mut line = Map::new();
+        line.insert("timestamp".into(), timestamp.into());
+        line.insert("level".into(), event.metadata().level().as_str().into());
+
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                // JsonFields has already written each span's fields as an object
+                let extensions = span.extensions();
+                let fields = extensions
+                    .get::<FormattedFields<N>>()
+                    .and_then(|fields| serde_json::from_str::<Map<String, Value>>(fields).ok());
+                line.extend(fields.unwrap_or_default());
+                line.insert("span".into(), span.name().into());
+            }
+        }
+        event.record(&mut JsonVisitor(&mut line));
+
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().into(), format!("{:?}", value).into());
+    }
+}
@@ -0,0 +1,340 @@
+use axum::body::Body;
+use bytes::Bytes;
+use futures_util::Stream;
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+
+/// Caps how fast a single response body is sent (`THROTTLE_BYTES_PER_SEC`).
+#[derive(Debug, Clone, Copy)]
+pub struct Throttle {
+    pub bytes_per_sec: u64,
+    /// Bodies smaller than this aren't slowed down at all
+    pub min_bytes: u64,
+}
+
+impl Throttle {
+    /// Pace `body` if it's big enough to be worth it. A body of unknown length
+    /// is sent at full speed until it's gone past `min_bytes`.
+    pub fn apply(&self, body: Body, content_length: Option<u64>) -> Body {
+        let free_bytes = match content_length {
+            Some(len) if len < self.min_bytes => return body,
+            Some(_) => 0,
+            None => self.min_bytes,
+        };
+        Body::from_stream(ThrottledBody::new(body, self.bytes_per_sec, free_bytes))
+    }
+}
+
+/// Everything that slows GIF responses down: a per-response cap, a budget
+/// shared by all of them, both or neither.
+#[derive(Clone, Default)]
+pub struct Pacing {
+    pub throttle: Option<Throttle>,
+    pub egress: Option<Arc<Egress>>,
+}
+
+impl Pacing {
+    pub fn apply(&self, body: Body, content_length: Option<u64>) -> Body {
+        let body = match &self.throttle {
+            Some(throttle) => throttle.apply(body, content_length),
+            None => body,
+        };
+        match &self.egress {
+            Some(egress) => egress.apply(body),
+            None => body,
+        }
+    }
+}
+
+/// A body whose chunks are let through by a token bucket. Big chunks are split
+/// so the pace stays even, and dropping the body (the client went away) stops
+/// it straight away, mid-wait or not.
+struct ThrottledBody {
+    inner: axum::body::BodyDataStream,
+    bytes_per_sec: u64,
+    /// Largest piece sent at once: a tenth of a second's worth
+    piece: usize,
+    free_bytes: u64,
+    /// Bytes we're allowed to send right now; negative after a piece that
+    /// hasn't been paid for yet
+    tokens: f64,
+    refilled: Instant,
+    pending: Option<Bytes>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl ThrottledBody {
+    fn new(body: Body, bytes_per_sec: u64, free_bytes: u64) -> Self {
+        let piece = (bytes_per_sec / 10).clamp(1024, 64 * 1024) as usize;
+        Self {
+            inner: body.into_data_stream(),
+            bytes_per_sec,
+            piece,
+            free_bytes,
+            tokens: piece as f64,
+            refilled: Instant::now(),
+            pending: None,
+            sleep: None,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = (now - self.refilled).as_secs_f64() * self.bytes_per_sec as f64;
+        // No saving up for a burst beyond one piece
+        self.tokens = (self.tokens + earned).min(self.piece as f64);
+        self.refilled = now;
+    }
+}
+
+impl Stream for ThrottledBody {
+    type Item = Result<Bytes, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.sleep = None;
+            }
+
+            let mut chunk = match this.pending.take() {
+                Some(chunk) => chunk,
+                None => match Pin::new(&mut this.inner).poll_next(cx) {
+                    Poll::Ready(Some(Ok(chunk))) => chunk,
+                    other => return other,
+                },
+            };
+            if chunk.is_empty() {
+                continue;
+            }
+
+            // The start of a body of unknown length goes out unthrottled
+            if this.free_bytes > 0 {
+                let free = chunk.len().min(this.free_bytes as usize);
+                this.free_bytes -= free as u64;
+                let rest = chunk.split_off(free);
+                if !rest.is_empty() {
+                    this.pending = Some(rest);
+                }
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+
+            this.refill();
+            if this.tokens <= 0.0 {
+                let wait = -this.tokens / this.bytes_per_sec as f64;
+                this.pending = Some(chunk);
+                this.sleep = Some(Box::pin(tokio::time::sleep(Duration::from_secs_f64(wait))));
+                continue;
+            }
+            if chunk.len() > this.piece {
+                this.pending = Some(chunk.split_off(this.piece));
+            }
+            this.tokens -= chunk.len() as f64;
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+    }
+}
+
+/// A bandwidth like `200Mbit/s` or `25MB/s`, in bytes per second. A plain
+/// number is taken as bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bandwidth(pub u64);
+
+impl std::str::FromStr for Bandwidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_suffix("/s").unwrap_or(s);
+        let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number = number
+            .parse::<f64>()
+            .ok()
+            .filter(|n| *n > 0.0)
+            .ok_or_else(|| format!("invalid amount {:?}", number))?;
+        let bytes = match unit.trim() {
+            "" | "B" => 1.0,
+            "KB" => 1e3,
+            "MB" => 1e6,
+            "GB" => 1e9,
+            "kbit" | "Kbit" => 1e3 / 8.0,
+            "Mbit" => 1e6 / 8.0,
+            "Gbit" => 1e9 / 8.0,
+            other => return Err(format!("unknown bandwidth unit {:?}", other)),
+        };
+        Ok(Bandwidth(((number * bytes) as u64).max(1)))
+    }
+}
+
+/// How far the budget may run ahead after being idle, so a quiet moment can't
+/// be saved up into a big burst later.
+const EGRESS_BURST: Duration = Duration::from_millis(50);
+
+/// The outbound budget every GIF response shares (`EGRESS_LIMIT`).
+///
+/// Time on the wire is handed out a piece at a time: each response books the
+/// next free slot for one piece, and only books another once that's been sent.
+/// So responses take turns, and a small one waits behind at most one piece of
+/// each big one instead of behind all of it.
+pub struct Egress {
+    bytes_per_sec: u64,
+    /// Largest piece booked at once: a hundredth of a second's worth
+    piece: usize,
+    schedule: Mutex<Schedule>,
+    active: AtomicUsize,
+    sent: AtomicU64,
+}
+
+struct Schedule {
+    /// When the budget is next free
+    next_free: Instant,
+    /// Bytes booked since `window_start`, for measuring the current rate
+    window_start: Instant,
+    window_bytes: u64,
+    last_rate: u64,
+}
+
+/// How much of the egress budget is in use, as reported on the stats endpoint.
+#[derive(Debug, Serialize)]
+pub struct EgressStats {
+    pub limit_bytes_per_sec: u64,
+    /// Measured over roughly the last second
+    pub bytes_per_sec: u64,
+    pub bytes_sent: u64,
+    /// Responses currently drawing from the budget
+    pub active_responses: usize,
+    /// How far ahead the budget is booked; above zero means it's saturated
+    pub backlog_ms: u64,
+}
+
+impl Egress {
+    pub fn new(bandwidth: Bandwidth) -> Self {
+        let now = Instant::now();
+        Self {
+            bytes_per_sec: bandwidth.0,
+            piece: (bandwidth.0 / 100).clamp(1024, 64 * 1024) as usize,
+            schedule: Mutex::new(Schedule {
+                next_free: now,
+                window_start: now,
+                window_bytes: 0,
+                last_rate: 0,
+            }),
+            active: AtomicUsize::new(0),
+            sent: AtomicU64::new(0),
+        }
+    }
+
+    pub fn apply(self: &Arc<Self>, body: Body) -> Body {
+        Body::from_stream(EgressBody::new(body, self.clone()))
+    }
+
+    pub fn stats(&self) -> EgressStats {
+        let now = Instant::now();
+        let schedule = self.schedule.lock().unwrap();
+        let window = now - schedule.window_start;
+        // A window that's run long means nothing has been booked for a while
+        let bytes_per_sec = if window < Duration::from_secs(1) {
+            schedule.last_rate
+        } else {
+            (schedule.window_bytes as f64 / window.as_secs_f64()) as u64
+        };
+        EgressStats {
+            limit_bytes_per_sec: self.bytes_per_sec,
+            bytes_per_sec,
+            bytes_sent: self.sent.load(Ordering::Relaxed),
+            active_responses: self.active.load(Ordering::Relaxed),
+            backlog_ms: schedule.next_free.saturating_duration_since(now).as_millis() as u64,
+        }
+    }
+
+    /// Book the next free slot for `len` bytes, returning when it starts.
+    fn book(&self, len: usize) -> Instant {
+        let now = Instant::now();
+        let mut schedule = self.schedule.lock().unwrap();
+        let start = match now.checked_sub(EGRESS_BURST) {
+            Some(earliest) => schedule.next_free.max(earliest),
+            None => schedule.next_free,
+        };
+        schedule.next_free = start + Duration::from_secs_f64(len as f64 / self.bytes_per_sec as f64);
+
+        let window = now - schedule.window_start;
+        if window >= Duration::from_secs(1) {
+            schedule.last_rate = (schedule.window_bytes as f64 / window.as_secs_f64()) as u64;
+            schedule.window_start = now;
+            schedule.window_bytes = 0;
+        }
+        schedule.window_bytes += len as u64;
+        self.sent.fetch_add(len as u64, Ordering::Relaxed);
+        start
+    }
+}
+
+/// A body that waits for its turn in the shared budget before each piece.
+struct EgressBody {
+    inner: axum::body::BodyDataStream,
+    egress: Arc<Egress>,
+    /// A piece that's been booked, and the sleep until its slot
+    booked: Option<(Bytes, Pin<Box<Sleep>>)>,
+    pending: Option<Bytes>,
+}
+
+impl EgressBody {
+    fn new(body: Body, egress: Arc<Egress>) -> Self {
+        egress.active.fetch_add(1, Ordering::Relaxed);
+        Self {
+            inner: body.into_data_stream(),
+            egress,
+            booked: None,
+            pending: None,
+        }
+    }
+}
+
+impl Drop for EgressBody {
+    fn drop(&mut self) {
+        self.egress.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Stream for EgressBody {
+    type Item = Result<Bytes, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some((_, sleep)) = &mut this.booked {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                let (piece, _) = this.booked.take().unwrap();
+                return Poll::Ready(Some(Ok(piece)));
+            }
+
+            let mut chunk = match this.pending.take() {
+                Some(chunk) => chunk,
+                None => match Pin::new(&mut this.inner).poll_next(cx) {
+                    Poll::Ready(Some(Ok(chunk))) => chunk,
+                    other => return other,
+                },
+            };
+            if chunk.is_empty() {
+                continue;
+            }
+            if chunk.len() > this.egress.piece {
+                this.pending = Some(chunk.split_off(this.egress.piece));
+            }
+            let start = this.egress.book(chunk.len());
+            this.booked = Some((chunk, Box::pin(tokio::time::sleep_until(start))));
+        }
+    }
+}
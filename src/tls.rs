@@ -0,0 +1,253 @@
+use crate::listener::{Connection, Peer};
+use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
+use axum::serve::Listener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{Error, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info};
+
+/// How long a client gets to finish its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handshakes in progress at once. Past this we stop accepting connections
+/// until some finish, rather than collecting half-open ones without limit.
+const MAX_HANDSHAKES: usize = 1024;
+
+/// The certificate and key we serve HTTPS with (`TLS_CERT` / `TLS_KEY`), and
+/// the CA clients' certificates have to be signed by, if they need one
+/// (`TLS_CLIENT_CA`). They're read again on reload, so a renewed certificate
+/// needn't wait for a restart.
+pub struct Certificates {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    client_ca_path: Option<PathBuf>,
+    current: ArcSwap<Loaded>,
+}
+
+struct Loaded {
+    chain: Vec<CertificateDer<'static>>,
+    config: Arc<ServerConfig>,
+}
+
+impl Certificates {
+    pub fn load(cert_path: &Path, key_path: &Path, client_ca_path: Option<&Path>) -> Result<Self> {
+        let loaded = read(cert_path, key_path, client_ca_path)?;
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            client_ca_path: client_ca_path.map(Path::to_path_buf),
+            current: ArcSwap::from_pointee(loaded),
+        })
+    }
+
+    /// Read the files again, to be put into effect by `apply`. Nothing
+    /// changes until then, so a reload that fails elsewhere doesn't swap them.
+    pub fn reread(&self) -> Result<Reread> {
+        let loaded = read(&self.cert_path, &self.key_path, self.client_ca_path.as_deref())?;
+        let changed = loaded.chain != self.current.load().chain;
+        Ok(Reread { loaded, changed })
+    }
+
+    pub fn apply(&self, reread: Reread) {
+        if reread.changed {
+            info!("Serving the new certificate from {}", self.cert_path.display());
+        }
+        self.current.store(Arc::new(reread.loaded));
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.load().config.clone())
+    }
+}
+
+/// Certificates read on reload, not yet in use.
+pub struct Reread {
+    loaded: Loaded,
+    /// Whether they differ from the ones being served
+    pub changed: bool,
+}
+
+fn read(cert_path: &Path, key_path: &Path, client_ca_path: Option<&Path>) -> Result<Loaded> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("Couldn't read the certificate {}: {}", cert_path.display(), e))?;
+    if chain.is_empty() {
+        return Err(anyhow!("No certificates in {}", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| anyhow!("Couldn't read the private key {}: {}", key_path.display(), e))?;
+
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| anyhow!("Couldn't set up TLS: {}", e))?;
+    let builder = match client_ca_path {
+        // Every client has to have a certificate from one of these, or the
+        // handshake fails
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| anyhow!("Couldn't read the client CA {}: {}", path.display(), e))?
+            {
+                roots
+                    .add(ca)
+                    .map_err(|e| anyhow!("Couldn't use the client CA {}: {}", path.display(), e))?;
+            }
+            if roots.is_empty() {
+                return Err(anyhow!("No certificates in {}", path.display()));
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| anyhow!("Couldn't use the client CA {}: {}", path.display(), e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(chain.clone(), key)
+        .map_err(|e| match e {
+            Error::InconsistentKeys(_) => {
+                anyhow!("The key {} doesn't go with the certificate {}", key_path.display(), cert_path.display())
+            }
+            e => anyhow!("Couldn't use the certificate {}: {}", cert_path.display(), e),
+        })?;
+    // HTTP/2 for clients that offer it, HTTP/1.1 for the rest
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Loaded { chain, config: Arc::new(config) })
+}
+
+/// Who a client's certificate says it is, when `TLS_CLIENT_CA` has it
+/// present one. It's been verified against the CA by the time we see it.
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    /// The subject's common name (CN)
+    pub common_name: Option<Arc<str>>,
+    /// Its DNS names and URIs, from the subject alternative names (SAN)
+    pub alt_names: Arc<[String]>,
+}
+
+impl ClientCert {
+    fn read(der: &CertificateDer<'_>) -> Option<Self> {
+        let cert = webpki::EndEntityCert::try_from(der).ok()?;
+        let alt_names = cert.valid_dns_names().chain(cert.valid_uri_names()).map(str::to_string).collect();
+        Some(Self { common_name: common_name(cert.subject()).map(Arc::from), alt_names })
+    }
+
+    /// What the client is called in logs and counted as: its common name, or
+    /// else its first alternative name.
+    pub fn name(&self) -> Option<&str> {
+        self.common_name.as_deref().or_else(|| self.alt_names.first().map(String::as_str))
+    }
+}
+
+// The CN in a certificate's subject, a sequence of sets of (OID, string)
+// sequences in DER. The OID is 2.5.4.3.
+fn common_name(mut subject: &[u8]) -> Option<String> {
+    const CN: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+    while !subject.is_empty() {
+        let (set, rest) = der_item(subject, 0x31)?;
+        subject = rest;
+        let (attribute, _) = der_item(set, 0x30)?;
+        if let Some(value) = attribute.strip_prefix(CN) {
+            // UTF8String, PrintableString or IA5String
+            let tag = *value.first()?;
+            if matches!(tag, 0x0c | 0x13 | 0x16) {
+                let (name, _) = der_item(value, tag)?;
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+// The contents of the DER item with this tag at the start of `der`, and what
+// comes after it
+fn der_item(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&first, der) = der.split_first()?;
+    if first != tag {
+        return None;
+    }
+    let (&length, mut der) = der.split_first()?;
+    let length = if length < 0x80 {
+        length as usize
+    } else {
+        let bytes = (length & 0x7f) as usize;
+        if bytes > 4 || der.len() < bytes {
+            return None;
+        }
+        let (length, rest) = der.split_at(bytes);
+        der = rest;
+        length.iter().fold(0, |length, byte| length << 8 | *byte as usize)
+    };
+    (der.len() >= length).then(|| der.split_at(length))
+}
+
+/// A listener whose connections speak TLS. Handshakes run on tasks of their
+/// own, so one slow client can't hold up accepting the others.
+pub struct TlsListener<L: Listener> {
+    inner: L,
+    certificates: Arc<Certificates>,
+    handshakes: JoinSet<Option<(TlsStream<L::Io>, Connection)>>,
+}
+
+impl<L: Listener> TlsListener<L> {
+    pub fn new(inner: L, certificates: Arc<Certificates>) -> Self {
+        Self { inner, certificates, handshakes: JoinSet::new() }
+    }
+}
+
+impl<L> Listener for TlsListener<L>
+where
+    L: Listener,
+    L::Addr: Into<Peer>,
+{
+    type Io = TlsStream<L::Io>;
+    type Addr = Connection;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                (stream, peer) = self.inner.accept(), if self.handshakes.len() < MAX_HANDSHAKES => {
+                    let peer: Peer = peer.into();
+                    let acceptor = self.certificates.acceptor();
+                    self.handshakes.spawn(async move {
+                        match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => {
+                                let client_cert = stream.get_ref().1.peer_certificates()
+                                    .and_then(|chain| chain.first())
+                                    .and_then(ClientCert::read);
+                                Some((stream, Connection { peer, client_cert }))
+                            }
+                            Ok(Err(e)) => {
+                                debug!("TLS handshake with {} failed: {}", peer, e);
+                                None
+                            }
+                            Err(_) => {
+                                debug!("{} didn't finish its TLS handshake in {:?}", peer, HANDSHAKE_TIMEOUT);
+                                None
+                            }
+                        }
+                    });
+                }
+                Some(done) = self.handshakes.join_next() => {
+                    if let Ok(Some(connection)) = done {
+                        return connection;
+                    }
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        self.inner.local_addr().map(|addr| Connection::from(addr.into()))
+    }
+}
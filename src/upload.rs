@@ -0,0 +1,96 @@
+use axum::extract::{FromRequest, Multipart, Request};
+use axum::http::header;
+use bytes::Bytes;
+use fastgif_core::temp::{TempFile, TempManager};
+use fastgif_core::ConversionError;
+use futures_util::{Stream, StreamExt};
+use std::fmt::Display;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// A video sent to `POST /convert`, written to a temp file so that ffmpeg
+/// can seek in it, which an MP4 with its index at the end needs. The file
+/// is removed once this is dropped, however the conversion went.
+pub struct Upload {
+    pub file: TempFile,
+    /// What the client called it, if it sent a form that said
+    pub name: Option<String>,
+    /// The form's other fields, which stand in for query parameters
+    pub fields: Vec<(String, String)>,
+}
+
+/// Why an upload wasn't taken.
+pub enum Refused {
+    /// It wasn't a video, or a form with one in it
+    Invalid(String),
+    /// Too big, or the temp file couldn't be written, or there's no room for it
+    Failed(ConversionError),
+}
+
+/// Read the video in `request`, either its whole body (`video/*`) or the
+/// `file` part of a `multipart/form-data` form, into a temp file from
+/// `temp`. It's counted against `max` as it arrives, so a video that's too
+/// big is refused once it goes over, rather than after it's all been
+/// received. When `temp` says there's too little space, it isn't read at all.
+pub async fn receive(request: Request, temp: &Arc<TempManager>, max: u64) -> Result<Upload, Refused> {
+    temp.check().map_err(Refused::Failed)?;
+    let content_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    if content_type.starts_with("multipart/form-data") {
+        let mut form = Multipart::from_request(request, &()).await.map_err(|e| Refused::Invalid(e.body_text()))?;
+        let mut upload = None;
+        let mut fields = Vec::new();
+        while let Some(field) = form.next_field().await.map_err(|e| Refused::Invalid(e.body_text()))? {
+            match field.name().map(str::to_string) {
+                Some(name) if name == "file" && upload.is_none() => {
+                    let file_name = field.file_name().map(str::to_string);
+                    upload = Some((write(temp, field, max).await?, file_name));
+                }
+                Some(name) => fields.push((name, field.text().await.map_err(|e| Refused::Invalid(e.body_text()))?)),
+                None => {}
+            }
+        }
+        let (file, name) = upload.ok_or_else(|| Refused::Invalid("the form has no file part".to_string()))?;
+        Ok(Upload { file, name, fields })
+    } else if content_type.starts_with("video/") {
+        // Spared reading it at all when it says up front
+        let len = request
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+        if len.is_some_and(|len| len > max) {
+            return Err(Refused::Failed(ConversionError::InputTooLarge(max)));
+        }
+        let file = write(temp, request.into_body().into_data_stream(), max).await?;
+        Ok(Upload { file, name: None, fields: Vec::new() })
+    } else {
+        Err(Refused::Invalid("expected a video/mp4 body, or multipart/form-data with a file part".to_string()))
+    }
+}
+
+// Write `body` to a new temp file, giving up once it's more than `max` bytes
+// or more than the temp files are allowed
+async fn write<E: Display>(temp: &Arc<TempManager>, body: impl Stream<Item = Result<Bytes, E>>, max: u64) -> Result<TempFile, Refused> {
+    let (file, mut handle) = temp.create("upload").await.map_err(Refused::Failed)?;
+    let write_failed = |e| Refused::Failed(ConversionError::pipe(format!("Failed to write {}", file.path().display()))(e));
+    let mut body = std::pin::pin!(body);
+    let mut total = 0u64;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| Refused::Invalid(format!("the upload broke off: {}", e)))?;
+        total += chunk.len() as u64;
+        if total > max {
+            return Err(Refused::Failed(ConversionError::InputTooLarge(max)));
+        }
+        file.grew(chunk.len()).map_err(Refused::Failed)?;
+        handle.write_all(&chunk).await.map_err(write_failed)?;
+    }
+    handle.flush().await.map_err(write_failed)?;
+    if total == 0 {
+        return Err(Refused::Invalid("the video is empty".to_string()));
+    }
+    Ok(file)
+}
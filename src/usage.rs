@@ -0,0 +1,220 @@
+use crate::job_db::{JobDb, UsageRow};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Days of usage kept for each key, today's among them.
+pub const DAYS_KEPT: i64 = 30;
+
+const DAY_SECS: u64 = 86_400;
+
+/// What each API key has used, a UTC day at a time for the last
+/// [`DAYS_KEPT`] days, and the quotas it's held to for the day
+/// (`QUOTA_CONVERSIONS_PER_DAY`, `QUOTA_CPU_SECONDS_PER_DAY`,
+/// `API_KEY_QUOTAS`). The days are kept in `JOB_DB_PATH` when there is one.
+///
+/// The counting is approximate: a conversion is counted once it's finished,
+/// so a key with several going at once can go over its quota by those, and
+/// only the CPU time of the ffmpeg and gifski binaries is counted (not the
+/// native encoder's, which runs in the server).
+pub struct Usage {
+    quotas: Quotas,
+    keys: Mutex<HashMap<Arc<str>, BTreeMap<i64, Totals>>>,
+    db: Option<JobDb>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Totals {
+    conversions: u64,
+    cpu_ms: u64,
+    output_bytes: u64,
+}
+
+/// How much of something a key may use in a day. `None` is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub conversions: Option<u64>,
+    pub cpu_seconds: Option<u64>,
+}
+
+/// The quotas for every key: its own, or the default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quotas {
+    default: Quota,
+    keys: HashMap<String, Quota>,
+}
+
+/// A key that's used up one of its quotas for the day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exceeded {
+    /// `conversions` or `cpu_seconds`
+    pub quota: &'static str,
+    pub limit: u64,
+    /// When the day is over and the key can convert again
+    pub resets_at: SystemTime,
+}
+
+/// What a key has used, as `GET /usage` and `GET /admin/keys/{name}/usage`
+/// answer.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub key: String,
+    pub quota: QuotaReport,
+    pub today: DayReport,
+    /// Unix milliseconds at which today's usage starts over
+    pub resets_at: i64,
+    /// Every day kept, oldest first, today's included if there's anything
+    /// in it
+    pub days: Vec<DayReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuotaReport {
+    pub conversions_per_day: Option<u64>,
+    pub cpu_seconds_per_day: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DayReport {
+    /// Unix milliseconds at which the UTC day begins
+    pub started_at: i64,
+    pub conversions: u64,
+    pub cpu_seconds: f64,
+    pub output_bytes: u64,
+}
+
+impl Quotas {
+    /// `default` for every key, but those `spec` (`API_KEY_QUOTAS`) names:
+    /// comma-separated `<name>=<conversions>/<cpu-seconds>`, either of which
+    /// can be `-` for no limit, e.g. `partner-a=1000/3600,partner-b=-/600`.
+    pub fn parse(spec: Option<&str>, default: Quota) -> Result<Self, String> {
+        let mut keys = HashMap::new();
+        for entry in spec.unwrap_or_default().split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let Some((name, limits)) = entry.rsplit_once('=') else {
+                return Err(format!("{:?} has no `=<conversions>/<cpu-seconds>`", entry));
+            };
+            let Some((conversions, cpu_seconds)) = limits.split_once('/') else {
+                return Err(format!("{:?} has to be `<conversions>/<cpu-seconds>`", limits));
+            };
+            let limit = |limit: &str| match limit.trim() {
+                "-" => Ok(None),
+                limit => limit.parse().map(Some).map_err(|_| format!("invalid limit {:?}", limit)),
+            };
+            let quota = Quota { conversions: limit(conversions)?, cpu_seconds: limit(cpu_seconds)? };
+            if keys.insert(name.trim().to_string(), quota).is_some() {
+                return Err(format!("{} is given more than once", name.trim()));
+            }
+        }
+        Ok(Self { default, keys })
+    }
+
+    pub fn of(&self, key: &str) -> Quota {
+        self.keys.get(key).copied().unwrap_or(self.default)
+    }
+}
+
+impl Usage {
+    /// Held to `quotas`, starting from the days `kept` in `db`.
+    pub fn new(quotas: Quotas, db: Option<JobDb>, kept: Vec<UsageRow>) -> Self {
+        let oldest = today() - DAYS_KEPT + 1;
+        let mut keys: HashMap<Arc<str>, BTreeMap<i64, Totals>> = HashMap::new();
+        for row in kept.into_iter().filter(|row| row.day >= oldest) {
+            let count = |n: i64| n.max(0) as u64;
+            let totals = Totals { conversions: count(row.conversions), cpu_ms: count(row.cpu_ms), output_bytes: count(row.output_bytes) };
+            keys.entry(row.key.into()).or_default().insert(row.day, totals);
+        }
+        Self { quotas, keys: Mutex::new(keys), db }
+    }
+
+    /// Whether `key` may start another conversion today.
+    pub fn check(&self, key: &str) -> Result<(), Exceeded> {
+        let quota = self.quotas.of(key);
+        if quota == Quota::default() {
+            return Ok(());
+        }
+        let today = today();
+        let used = self.keys.lock().unwrap().get(key).and_then(|days| days.get(&today).copied()).unwrap_or_default();
+        let resets_at = UNIX_EPOCH + Duration::from_secs((today + 1) as u64 * DAY_SECS);
+        if let Some(limit) = quota.conversions.filter(|limit| used.conversions >= *limit) {
+            return Err(Exceeded { quota: "conversions", limit, resets_at });
+        }
+        if let Some(limit) = quota.cpu_seconds.filter(|limit| used.cpu_ms >= limit.saturating_mul(1000)) {
+            return Err(Exceeded { quota: "cpu_seconds", limit, resets_at });
+        }
+        Ok(())
+    }
+
+    /// `key` ran a conversion that took `cpu` and made `output_bytes` of GIF
+    /// (none if it failed).
+    pub fn converted(&self, key: &Arc<str>, cpu: Duration, output_bytes: u64) {
+        self.add(key, Totals { conversions: 1, cpu_ms: cpu.as_millis() as u64, output_bytes });
+    }
+
+    /// `key` was sent `bytes` of GIF from the cache.
+    pub fn served(&self, key: &Arc<str>, bytes: u64) {
+        self.add(key, Totals { output_bytes: bytes, ..Totals::default() });
+    }
+
+    fn add(&self, key: &Arc<str>, more: Totals) {
+        let today = today();
+        let oldest = today - DAYS_KEPT + 1;
+        let mut keys = self.keys.lock().unwrap();
+        let days = keys.entry(key.clone()).or_default();
+        let totals = days.entry(today).or_default();
+        totals.conversions += more.conversions;
+        totals.cpu_ms += more.cpu_ms;
+        totals.output_bytes += more.output_bytes;
+        let totals = *totals;
+        days.retain(|day, _| *day >= oldest);
+        drop(keys);
+        if let Some(db) = &self.db {
+            let row = UsageRow {
+                key: key.to_string(),
+                day: today,
+                conversions: totals.conversions as i64,
+                cpu_ms: totals.cpu_ms as i64,
+                output_bytes: totals.output_bytes as i64,
+            };
+            db.save_usage(row, oldest);
+        }
+    }
+
+    /// Whether `key` has used anything in the days kept.
+    pub fn has_used(&self, key: &str) -> bool {
+        self.keys.lock().unwrap().contains_key(key)
+    }
+
+    /// What `key` has used, and may.
+    pub fn report(&self, key: &str) -> Report {
+        let today = today();
+        let oldest = today - DAYS_KEPT + 1;
+        let quota = self.quotas.of(key);
+        let days: Vec<(i64, Totals)> = match self.keys.lock().unwrap().get(key) {
+            Some(days) => days.range(oldest..).map(|(day, totals)| (*day, *totals)).collect(),
+            None => Vec::new(),
+        };
+        let used_today = days.iter().find(|(day, _)| *day == today).map(|(_, totals)| *totals).unwrap_or_default();
+        Report {
+            key: key.to_string(),
+            quota: QuotaReport { conversions_per_day: quota.conversions, cpu_seconds_per_day: quota.cpu_seconds },
+            today: day_report(today, used_today),
+            resets_at: (today + 1) * DAY_SECS as i64 * 1000,
+            days: days.into_iter().map(|(day, totals)| day_report(day, totals)).collect(),
+        }
+    }
+}
+
+fn day_report(day: i64, totals: Totals) -> DayReport {
+    DayReport {
+        started_at: day * DAY_SECS as i64 * 1000,
+        conversions: totals.conversions,
+        cpu_seconds: totals.cpu_ms as f64 / 1000.0,
+        output_bytes: totals.output_bytes,
+    }
+}
+
+// Days since the Unix epoch, in UTC
+fn today() -> i64 {
+    (SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / DAY_SECS) as i64
+}
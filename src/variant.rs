@@ -0,0 +1,191 @@
+use fastgif_core::auto_quality::Bucket;
+use fastgif_core::backend::Format;
+use fastgif_core::encoder::{Background, Decoder, Encoder, GifSettings};
+use fastgif_core::pipeline::ConversionOptions;
+use crate::clip::{LongVideoPolicy, Seconds};
+use crate::AppState;
+use fastgif_core::segment;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+/// Query parameters a conversion can be tuned with.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct ConversionQuery {
+    /// Run the GIF through gifsicle when it's available (`?optimize=1`)
+    pub optimize: Option<String>,
+    /// Play the GIF this many more times after the first, rather than forever
+    pub repeat: Option<u16>,
+    /// Make this instead of a GIF, e.g. `webp` (`?format=`)
+    pub format: Option<String>,
+    /// Fail with the error's status even with a `FAILURE_PLACEHOLDER_PATH` (`?strict=1`)
+    pub strict: Option<String>,
+    /// No wider than this, scaling the video down if it's wider
+    pub width: Option<u32>,
+    /// Fewer frames a second than `GIF_FPS`
+    pub fps: Option<f32>,
+    /// Flatten a transparent video onto this colour rather than keep its
+    /// transparency
+    pub bg: Option<Background>,
+    /// Start this many seconds into the video (`?start=`)
+    pub start: Option<Seconds>,
+    /// Convert only this many seconds of it (`?duration=`), within
+    /// `MAX_INPUT_DURATION` all the same
+    pub duration: Option<Seconds>,
+    /// `redirect` to be sent to the GIF in `S3_BUCKET` rather than the GIF itself
+    pub delivery: Option<String>,
+    /// `0` to convert with the configured settings even with `AUTO_QUALITY` on
+    pub auto: Option<String>,
+}
+
+impl ConversionQuery {
+    pub fn optimize(&self) -> bool {
+        self.optimize.as_deref().is_some_and(is_truthy)
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict.as_deref().is_some_and(is_truthy)
+    }
+
+    pub fn redirect(&self) -> bool {
+        self.delivery.as_deref() == Some("redirect")
+    }
+
+    /// Whether `AUTO_QUALITY` gets to pick the settings: it asked for no
+    /// width or frame rate of its own, and didn't turn it off.
+    pub fn auto(&self) -> bool {
+        self.width.is_none() && self.fps.is_none() && !matches!(self.auto.as_deref(), Some("0" | "false" | "no"))
+    }
+}
+
+fn is_truthy(value: &str) -> bool {
+    matches!(value, "1" | "true" | "yes")
+}
+
+/// Everything that decides what a conversion makes of a video at full
+/// quality. Two requests have the same key exactly when the server would
+/// convert them the same way: what they asked for as the server resolves it
+/// (settings the encoder can't honour and gifsicle that isn't there make no
+/// difference), and the configuration that applies to them. Which video it
+/// is counts, but not where from, since a reload can point the same video at
+/// another host. Degraded conversions aren't covered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariantKey {
+    path: String,
+    format: Format,
+    backend: &'static str,
+    decoder: Decoder,
+    settings: GifSettings,
+    /// What `AUTO_QUALITY` made of the video, which `settings` are held to
+    auto: Option<Bucket>,
+    optimized: bool,
+    /// The part of the video asked for
+    start: Option<Duration>,
+    duration: Option<Duration>,
+    /// What anything longer is cut short to, so changing the policy or the
+    /// limit makes for another key
+    trimmed_to: Option<Duration>,
+    segments: Option<usize>,
+}
+
+impl VariantKey {
+    /// The key for converting `path` (as `source_path::canonicalize` made it)
+    /// with `params`, held to `auto`'s profile if `AUTO_QUALITY` picked one,
+    /// or `None` if this server doesn't make the format asked for.
+    pub fn from(path: &str, params: &ConversionQuery, auto: Option<Bucket>, state: &AppState) -> Option<Self> {
+        let pipeline = state.pipeline();
+        let format = match params.format.as_deref().map(str::parse::<Format>) {
+            None => Format::Gif,
+            Some(Ok(format)) if pipeline.backends.makes(format) => format,
+            Some(_) => return None,
+        };
+        let options = ConversionOptions {
+            optimize: params.optimize(),
+            repeat: params.repeat,
+            backend: pipeline.backends.registered(format),
+            max_width: params.width,
+            fps: params.fps,
+            background: params.bg,
+            auto,
+            ..Default::default()
+        };
+        let mut settings = options.gif_settings(pipeline);
+        // gifski's command line leaves out what it has no flag for
+        if pipeline.encoder == Encoder::Subprocess && options.backend.is_none() {
+            for unsupported in settings.unsupported(&pipeline.gifski_flags) {
+                match unsupported {
+                    "fast" => settings.fast = false,
+                    "repeat" => settings.repeat = None,
+                    _ => {}
+                }
+            }
+        }
+        Some(VariantKey {
+            path: path.to_string(),
+            format,
+            backend: pipeline.backend(&options).name(),
+            decoder: pipeline.decoder,
+            settings,
+            auto,
+            optimized: pipeline.post_optimizes(&options).is_some() && !state.stream_response,
+            start: params.start.map(|start| start.0),
+            duration: params.duration.map(|duration| duration.0),
+            trimmed_to: state.max_input_duration.filter(|_| state.long_video_policy == LongVideoPolicy::Trim),
+            segments: segment::wanted(pipeline, &options, state.stream_response),
+        })
+    }
+
+    /// The key for stills of `path` no wider than `width`, which are decoded
+    /// and scaled the way a GIF of it is, for a still's own key to build on.
+    pub fn still(path: &str, width: Option<u32>, state: &AppState) -> Self {
+        let params = ConversionQuery { width, ..Default::default() };
+        Self::from(path, &params, None, state).expect("every server makes GIFs")
+    }
+
+    /// The bucket `AUTO_QUALITY` put the video in, if it did.
+    pub fn auto(&self) -> Option<Bucket> {
+        self.auto
+    }
+
+    /// A weak `ETag` for what's made with this key. Weak, since the same
+    /// settings can still come out different with another release of the
+    /// encoder.
+    pub fn etag(&self) -> String {
+        // FNV-1a, so the tag stays the same across builds and restarts
+        let hash = self.to_string().bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+        format!("W/\"{:016x}\"", hash)
+    }
+}
+
+/// Every field, the same way every time, which is what the `ETag` is made from.
+impl fmt::Display for VariantKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let settings = &self.settings;
+        write!(
+            f,
+            "{} as {} by {} from {:?}: quality {}, fast {}, {} fps, repeat {:?}, max width {:?}, background {:?}, optimized {}, start {:?}, duration {:?}, trimmed to {:?}, segments {:?}",
+            self.path,
+            self.format.as_str(),
+            self.backend,
+            self.decoder,
+            settings.quality,
+            settings.fast,
+            settings.fps,
+            settings.repeat,
+            settings.max_width,
+            settings.background.map(|background| background.to_string()),
+            self.optimized,
+            self.start,
+            self.duration,
+            self.trimmed_to,
+            self.segments,
+        )?;
+        // Only there when it's used, so keys from before it stay the same
+        match self.auto {
+            Some(bucket) => write!(f, ", auto {}", bucket),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use crate::config::WarmArgs;
+use futures_util::stream::{self, StreamExt};
+use reqwest::{header, Method, Response, StatusCode, Url};
+use std::time::Duration;
+
+/// Longest we'll wait on a `Retry-After` before trying again.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// How a request went, in the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Converted,
+    /// Answered from a cache in front of the server, or not modified
+    Cached,
+    Failed,
+}
+
+// Why an attempt failed, and whether it's worth trying again
+struct Failure {
+    reason: String,
+    transient: bool,
+    retry_after: Option<Duration>,
+}
+
+/// `fastgif warm`: request everything in `args.list` from `args.server`,
+/// `args.concurrency` at a time, and sum up how it went in a table followed
+/// by what failed. `Err` is that summary too, when anything did fail.
+pub async fn run(args: &WarmArgs) -> Result<String, String> {
+    let list = std::fs::read_to_string(&args.list).map_err(|e| format!("couldn't read {}: {}", args.list.display(), e))?;
+    let urls = list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| target(&args.server, line, args.params.as_deref()))
+        .collect::<Result<Vec<Url>, String>>()?;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs_f64(args.timeout.max(0.1)))
+        .build()
+        .map_err(|e| format!("couldn't set up a client: {}", e))?;
+    let method = if args.dry_run { Method::HEAD } else { Method::GET };
+
+    let results: Vec<(Outcome, u64, Option<String>)> = stream::iter(&urls)
+        .map(|url| warm(&client, &method, url, args))
+        .buffer_unordered(args.concurrency.max(1))
+        .collect()
+        .await;
+    let count = |outcome| results.iter().filter(|(other, ..)| *other == outcome).count();
+    let bytes: u64 = results.iter().map(|(_, bytes, _)| bytes).sum();
+    let mut summary = format!(
+        "converted {:>10}\ncached    {:>10}\nfailed    {:>10}\nbytes     {:>10}",
+        count(Outcome::Converted),
+        count(Outcome::Cached),
+        count(Outcome::Failed),
+        bytes
+    );
+    for reason in results.iter().filter_map(|(_, _, reason)| reason.as_deref()) {
+        summary.push_str(&format!("\nfailed: {}", reason));
+    }
+    if count(Outcome::Failed) > 0 {
+        Err(summary)
+    } else {
+        Ok(summary)
+    }
+}
+
+// What to request for a line of the list: a URL as it is, a path on the
+// server, or else a video's name. Either way with `params` added.
+fn target(server: &Url, line: &str, params: Option<&str>) -> Result<Url, String> {
+    let url = if line.contains("://") {
+        Url::parse(line)
+    } else if line.starts_with('/') {
+        server.join(line)
+    } else {
+        server.join(&format!("/tweet_video/{}", line))
+    };
+    let mut url = url.map_err(|e| format!("invalid entry {:?} in the list: {}", line, e))?;
+    if let Some(params) = params.filter(|params| !params.is_empty()) {
+        let query = match url.query().filter(|query| !query.is_empty()) {
+            Some(query) => format!("{}&{}", query, params),
+            None => params.to_string(),
+        };
+        url.set_query(Some(&query));
+    }
+    Ok(url)
+}
+
+// Request `url` until it works, fails for good, or runs out of retries.
+// The bytes are the body's, or for a HEAD, what it says the body would be.
+async fn warm(client: &reqwest::Client, method: &Method, url: &Url, args: &WarmArgs) -> (Outcome, u64, Option<String>) {
+    let mut attempt = 0;
+    loop {
+        match fetch(client, method, url, args.api_key.as_deref()).await {
+            Ok((outcome, bytes)) => return (outcome, bytes, None),
+            Err(failure) if failure.transient && attempt < args.retries => {
+                let backoff = Duration::from_millis(500) * 2u32.pow(attempt);
+                tokio::time::sleep(failure.retry_after.unwrap_or(backoff).min(MAX_RETRY_AFTER)).await;
+                attempt += 1;
+            }
+            Err(failure) => return (Outcome::Failed, 0, Some(format!("{}: {}", url, failure.reason))),
+        }
+    }
+}
+
+async fn fetch(client: &reqwest::Client, method: &Method, url: &Url, api_key: Option<&str>) -> Result<(Outcome, u64), Failure> {
+    // Behind anyone waiting to see a GIF
+    let mut request = client.request(method.clone(), url.clone()).header("X-FastGIF-Priority", "low");
+    if let Some(key) = api_key {
+        request = request.bearer_auth(key);
+    }
+    let mut response = request.send().await.map_err(|e| Failure {
+        reason: e.to_string(),
+        transient: e.is_connect() || e.is_timeout() || e.is_request(),
+        retry_after: None,
+    })?;
+    let status = response.status();
+    if !status.is_success() && status != StatusCode::NOT_MODIFIED {
+        let class = response.headers().get("x-fastgif-error").and_then(|class| class.to_str().ok());
+        return Err(Failure {
+            reason: match class {
+                Some(class) => format!("{} ({})", status, class),
+                None => status.to_string(),
+            },
+            transient: matches!(
+                status,
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+            ),
+            retry_after: response
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|secs| secs.to_str().ok()?.parse().ok())
+                .map(Duration::from_secs),
+        });
+    }
+    let outcome = if cached(&response) { Outcome::Cached } else { Outcome::Converted };
+    if method == Method::HEAD {
+        return Ok((outcome, response.content_length().unwrap_or(0)));
+    }
+    // Counted as it comes, rather than holding on to the GIF
+    let mut bytes = 0;
+    while let Some(chunk) = response.chunk().await.map_err(|e| Failure {
+        reason: format!("the body broke off: {}", e),
+        transient: true,
+        retry_after: None,
+    })? {
+        bytes += chunk.len() as u64;
+    }
+    Ok((outcome, bytes))
+}
+
+// Whether a cache answered rather than the server: unmodified, aged, or
+// saying it was a hit the way CDNs do
+fn cached(response: &Response) -> bool {
+    let header = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok());
+    response.status() == StatusCode::NOT_MODIFIED
+        || header("age").and_then(|age| age.parse::<u64>().ok()).is_some_and(|age| age > 0)
+        || ["x-cache", "cf-cache-status"]
+            .into_iter()
+            .any(|name| header(name).is_some_and(|value| value.to_ascii_lowercase().contains("hit")))
+}
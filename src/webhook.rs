@@ -0,0 +1,164 @@
+use crate::client_ip::Cidr;
+use crate::ip_filter::Networks;
+use crate::jobs::Status;
+use reqwest::{header, StatusCode, Url};
+use ring::hmac;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+/// Tries at delivering a callback, the first included.
+const ATTEMPTS: u32 = 4;
+
+/// How long to wait before the first retry, doubled for each one after.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long one try may take, connecting included.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where callbacks don't go unless `WEBHOOK_ALLOW_CIDRS` says they may:
+/// this host, private and shared networks, link-local (cloud metadata
+/// endpoints among them), and addresses that aren't anyone's.
+const INTERNAL: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/3",
+    "::/127",
+    "64:ff9b::/96",
+    "2001:db8::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Calls back whoever submitted a job once it's over (`WEBHOOK_SECRET`),
+/// with its status signed so they can tell it's from us.
+pub struct Webhooks {
+    key: hmac::Key,
+    internal: Networks,
+    /// Internal networks callbacks may go to anyway (`WEBHOOK_ALLOW_CIDRS`)
+    allowed: Networks,
+}
+
+impl Webhooks {
+    pub fn new(secret: &str, allowed: &[Cidr]) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            internal: INTERNAL.iter().map(|cidr| cidr.parse::<Cidr>().unwrap()).collect(),
+            allowed: allowed.iter().cloned().collect(),
+        }
+    }
+
+    /// Whether a callback may be sent to `url`: an `http` or `https` URL whose
+    /// host is only at addresses outside `INTERNAL`, or in `allowed`. What
+    /// comes back is the address to send it to, so the host can't be looked
+    /// up to somewhere else in between.
+    pub async fn check(&self, url: &str) -> Result<(Url, SocketAddr), String> {
+        let url = Url::parse(url).map_err(|e| format!("{:?} isn't a URL: {}", url, e))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("callbacks can only be sent over http or https, not {}", url.scheme()));
+        }
+        let host = url.host_str().ok_or("the callback URL has no host")?.to_string();
+        let port = url.port_or_known_default().unwrap_or(443);
+        let addrs: Vec<SocketAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => tokio::net::lookup_host((host.as_str(), port))
+                .await
+                .map_err(|e| format!("couldn't look up {}: {}", host, e))?
+                .collect(),
+        };
+        // Every one of them, since any could be the one connected to
+        if let Some(refused) = addrs.iter().find(|addr| !self.allows(addr.ip())) {
+            return Err(format!("{} is at {}, which callbacks can't be sent to", host, refused.ip()));
+        }
+        let addr = addrs.first().copied().ok_or_else(|| format!("{} has no addresses", host))?;
+        Ok((url, addr))
+    }
+
+    fn allows(&self, ip: IpAddr) -> bool {
+        self.allowed.contains(ip) || !self.internal.contains(ip)
+    }
+
+    /// POST `status` to `url`, trying again after server errors and timeouts,
+    /// and logging it as a dead letter when that runs out or it's refused.
+    pub async fn deliver(&self, url: &str, status: &Status) {
+        let body = serde_json::to_vec(status).unwrap();
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempt = 1;
+        let reason = loop {
+            let (reason, transient) = match self.send(url, &body).await {
+                Ok(()) => {
+                    info!("Sent job {}'s callback", status.id);
+                    return;
+                }
+                Err(failure) => failure,
+            };
+            if !transient || attempt == ATTEMPTS {
+                break reason;
+            }
+            warn!("Job {}'s callback failed, trying again in {:?}: {}", status.id, backoff, reason);
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        };
+        error!(
+            outcome = "webhook_dead_letter",
+            job_id = %status.id,
+            callback_url = url,
+            attempts = attempt,
+            payload = %String::from_utf8_lossy(&body),
+            "Giving up on job {}'s callback: {}",
+            status.id,
+            reason
+        );
+    }
+
+    // One try at sending `body`, or why it failed and whether it's worth
+    // another. The host is looked up and checked again every time.
+    async fn send(&self, url: &str, body: &[u8]) -> Result<(), (String, bool)> {
+        let (url, addr) = self.check(url).await.map_err(|e| (e, false))?;
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .user_agent(concat!("fastgif/", env!("CARGO_PKG_VERSION")))
+            .resolve(url.host_str().unwrap_or_default(), addr)
+            .build()
+            .map_err(|e| (format!("couldn't set up a client: {}", e), false))?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let response = client
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-FastGIF-Timestamp", timestamp.to_string())
+            .header("X-FastGIF-Signature", self.signature(timestamp, body))
+            .body(body.to_vec())
+            .send()
+            .await
+            .map_err(|e| (e.to_string(), e.is_timeout() || e.is_connect() || e.is_request()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let transient = status.is_server_error() || matches!(status, StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS);
+        Err((format!("the receiver answered {}", status), transient))
+    }
+
+    /// `sha256=` and the hex HMAC-SHA256 of `<timestamp>.<body>`, keyed with
+    /// the secret.
+    fn signature(&self, timestamp: u64, body: &[u8]) -> String {
+        let mut signed = format!("{}.", timestamp).into_bytes();
+        signed.extend_from_slice(body);
+        let tag = hmac::sign(&self.key, &signed);
+        let hex: String = tag.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("sha256={}", hex)
+    }
+}
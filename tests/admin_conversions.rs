@@ -0,0 +1,148 @@
+//! Holds a conversion up part of the way through and checks what
+//! `GET /admin/conversions` says about it, and that
+//! `DELETE /admin/conversions/{request_id}` stops it.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::Value;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use support::router::{self, send, setup, HANGS, VIDEO};
+
+mod support;
+
+fn config(dir: &Path) -> Config {
+    Config {
+        api_keys: vec!["key".to_string()],
+        admin_token: Some("admin".to_string()),
+        ..router::config(dir)
+    }
+}
+
+// A conversion as `request_id`, which comes back with its status and body
+fn convert(app: &Router, request_id: &'static str) -> tokio::task::JoinHandle<(u16, Vec<u8>)> {
+    let app = app.clone();
+    tokio::spawn(async move {
+        let request = Request::get("/tweet_video/abc.gif?width=100")
+            .header("authorization", "Bearer key")
+            .header("x-request-id", request_id)
+            .header("x-fastgif-priority", "low")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&app, request).await;
+        let status = response.status().as_u16();
+        (status, to_bytes(response.into_body(), usize::MAX).await.unwrap_or_default().to_vec())
+    })
+}
+
+async fn conversions(app: &Router) -> Value {
+    let request = Request::get("/admin/conversions").header("authorization", "Bearer admin").body(Body::empty()).unwrap();
+    let response = send(app, request).await;
+    assert_eq!(response.status(), 200);
+    serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn running_conversions_are_listed() {
+    let dir = setup("listed", HANGS);
+    let app = router::app(config(&dir)).await;
+    assert_eq!(conversions(&app).await, serde_json::json!([]));
+
+    std::fs::write(dir.join("hang"), "").unwrap();
+    let converting = convert(&app, "listed-1");
+
+    // Fed to ffmpeg, which is taking its time
+    let started = Instant::now();
+    let listed = loop {
+        let listed = conversions(&app).await;
+        if listed[0]["bytes_piped"] == VIDEO.len() && listed[0]["stages_ms"]["ffmpeg"].as_u64().is_some_and(|ms| ms >= 100) {
+            break listed;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "{}", listed);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert_eq!(listed.as_array().unwrap().len(), 1, "{}", listed);
+    let conversion = &listed[0];
+    assert_eq!(conversion["request_id"], "listed-1");
+    assert_eq!(conversion["path"], "abc.gif");
+    assert_eq!(conversion["params"]["width"], 100);
+    assert_eq!(conversion["client"], "127.0.0.1");
+    assert_eq!(conversion["api_key"], "key-1");
+    assert_eq!(conversion["priority"], "low");
+    assert_eq!(conversion["bytes_collected"], 0);
+    assert!(conversion["stages_ms"]["queue"].is_u64(), "{}", conversion);
+    assert!(conversion["stages_ms"].get("first_byte").is_none(), "{}", conversion);
+    assert!(conversion["elapsed_ms"].as_u64().unwrap() >= conversion["stages_ms"]["ffmpeg"].as_u64().unwrap());
+
+    // and nothing's listed once it's done
+    std::fs::remove_file(dir.join("hang")).unwrap();
+    assert_eq!(converting.await.unwrap().0, 200);
+    assert_eq!(conversions(&app).await, serde_json::json!([]));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn admins_can_cancel_a_conversion() {
+    let dir = setup("cancel", HANGS);
+    let audit = dir.join("audit.log");
+    let app = router::app(Config { audit_log_path: Some(audit.clone()), ..config(&dir) }).await;
+    let kill = |request_id: &str| {
+        let request = Request::delete(format!("/admin/conversions/{}", request_id)).header("authorization", "Bearer admin");
+        send(&app, request.body(Body::empty()).unwrap())
+    };
+    assert_eq!(kill("nobody").await.status(), 404);
+
+    std::fs::write(dir.join("hang"), "").unwrap();
+    let converting = convert(&app, "wedged-1");
+    let started = Instant::now();
+    while conversions(&app).await[0]["bytes_piped"] != VIDEO.len() {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let response = kill("wedged-1").await;
+    assert_eq!(response.status(), 200);
+    let killed: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+    assert_eq!(killed["request_id"], "wedged-1");
+    assert_eq!(killed["cancelled"][0]["path"], "abc.gif", "{}", killed);
+
+    // The client hears why, however long ffmpeg would have gone on for
+    let (status, body) = tokio::time::timeout(Duration::from_secs(5), converting).await.unwrap().unwrap();
+    assert_eq!(status, 503);
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"], "cancelled_by_admin");
+    assert_eq!(conversions(&app).await, serde_json::json!([]));
+    assert_eq!(kill("wedged-1").await.status(), 404);
+
+    // and the audit log who did it
+    let started = Instant::now();
+    let record = loop {
+        let log = std::fs::read_to_string(&audit).unwrap_or_default();
+        if let Some(line) = log.lines().next() {
+            break serde_json::from_str::<Value>(line).unwrap();
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert_eq!(record["outcome"], "cancelled_by_admin");
+    assert_eq!(record["cancelled_by_admin"], "127.0.0.1");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn conversions_are_only_listed_for_admins() {
+    let dir = setup("admins", HANGS);
+    let app = router::app(config(&dir)).await;
+    for token in [None, Some("key"), Some("wrong")] {
+        for request in [Request::get("/admin/conversions"), Request::delete("/admin/conversions/any")] {
+            let request = match token {
+                Some(token) => request.header("authorization", format!("Bearer {}", token)),
+                None => request,
+            };
+            assert_eq!(send(&app, request.body(Body::empty()).unwrap()).await.status(), 401, "{:?}", token);
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
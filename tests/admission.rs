@@ -0,0 +1,65 @@
+//! Asks for a conversion permit when there's only one, and checks whether it
+//! comes straight away, once the conversion holding it is done, or not at all
+//! within `QUEUE_WAIT_TIMEOUT`.
+
+use fastgif::admission::{Admission, Priority, Rejection};
+use fastgif::config::Config;
+use fastgif::reload::Settings;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const QUEUE_WAIT_TIMEOUT: Duration = Duration::from_millis(300);
+
+fn admission() -> Arc<Admission> {
+    let config = Config {
+        max_concurrent_conversions: Some(1),
+        max_queue_depth: Some(2),
+        queue_wait_timeout: QUEUE_WAIT_TIMEOUT.as_secs_f64(),
+        ..Config::default()
+    };
+    let settings = Settings::from_config(&config).unwrap();
+    Arc::new(Admission::new(Arc::new(arc_swap::ArcSwap::from_pointee(settings)), None))
+}
+
+#[tokio::test]
+async fn a_free_permit_is_had_straight_away() {
+    let admission = admission();
+    let started = Instant::now();
+    let permit = admission.acquire(Priority::Normal, Some("abc"), None).await;
+    assert!(permit.is_ok());
+    assert!(started.elapsed() < Duration::from_millis(100), "took {:?}", started.elapsed());
+    assert_eq!(admission.stats().running, 1);
+}
+
+#[tokio::test]
+async fn a_permit_is_had_once_the_conversion_holding_it_is_done() {
+    let admission = admission();
+    let held = admission.acquire(Priority::Normal, Some("abc"), None).await.unwrap();
+    let waiting = {
+        let admission = admission.clone();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let permit = admission.acquire(Priority::Normal, Some("def"), None).await;
+            (permit.is_ok(), started.elapsed())
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(admission.stats().queued, 1);
+    drop(held);
+
+    let (acquired, waited) = waiting.await.unwrap();
+    assert!(acquired);
+    assert!(waited >= Duration::from_millis(100) && waited < QUEUE_WAIT_TIMEOUT, "waited {:?}", waited);
+    assert_eq!(admission.stats().queued, 0);
+}
+
+#[tokio::test]
+async fn waiting_too_long_for_a_permit_times_out() {
+    let admission = admission();
+    let _held = admission.acquire(Priority::Normal, Some("abc"), None).await.unwrap();
+    let started = Instant::now();
+    let rejected = admission.acquire(Priority::Normal, Some("def"), None).await.err();
+    assert_eq!(rejected, Some(Rejection::TimedOut));
+    assert!(started.elapsed() >= QUEUE_WAIT_TIMEOUT, "gave up after {:?}", started.elapsed());
+    assert_eq!(admission.stats().queued, 0);
+}
@@ -0,0 +1,117 @@
+//! Converts with `AUTO_QUALITY` on, from an ffprobe that says the video is a
+//! minute of 720p, and checks the GIF is held to that bucket's profile, says
+//! so, and is kept apart from the one converted as configured. Requests that
+//! ask for a width or frame rate of their own, or `?auto=0`, get what they
+//! ask for, and with it off nothing's probed at all.
+#![cfg(unix)]
+
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use fastgif::Connection;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use support::router::{self, header, tool};
+use tower::ServiceExt;
+
+mod support;
+
+const PROBED: &str = r#"{"streams": [{"width": 1280, "height": 720, "avg_frame_rate": "30/1"}], "format": {"duration": "60.000000", "bit_rate": "2000000"}}"#;
+
+// A directory of the test's own, with `abc.mp4`, an ffmpeg in it that passes
+// on what it's given to read and leaves its arguments as a line in `runs`,
+// and an ffprobe that answers with `PROBED`, counting its runs in `probes`
+fn setup(test: &str) -> PathBuf {
+    let ffmpeg = "here=\"$(dirname \"$0\")\"\n\
+        case \"$*\" in *-i*) echo \"$*\" >> \"$here/runs\";; esac\n\
+        while [ $# -gt 0 ]; do [ \"$1\" = \"-i\" ] && [ \"$2\" != pipe:0 ] && exec cat \"$2\"; shift; done\n\
+        exec cat";
+    let dir = router::setup(test, ffmpeg);
+    let ffprobe = format!("case \"$*\" in *-show_entries*) echo >> \"$(dirname \"$0\")/probes\";; esac\necho '{}'", PROBED);
+    tool(&dir, "ffprobe", &ffprobe);
+    dir
+}
+
+fn config(dir: &Path, auto_quality: bool) -> Config {
+    Config {
+        cache_max_bytes: Some(1024 * 1024),
+        auto_quality,
+        ..router::config(dir)
+    }
+}
+
+async fn get(app: &Router, uri: &str) -> Response {
+    let mut request = Request::get(uri).body(Body::empty()).unwrap();
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+    request.extensions_mut().insert(ConnectInfo(Connection::from(peer)));
+    app.clone().oneshot(request).await.unwrap()
+}
+
+fn runs(dir: &Path) -> Vec<String> {
+    std::fs::read_to_string(dir.join("runs")).unwrap_or_default().lines().map(str::to_string).collect()
+}
+
+fn probes(dir: &Path) -> usize {
+    std::fs::read_to_string(dir.join("probes")).map_or(0, |probes| probes.lines().count())
+}
+
+#[tokio::test]
+async fn a_long_hd_video_is_held_to_its_bucket() {
+    let dir = setup("held");
+    let app = router::app(config(&dir, true)).await;
+
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(header(&response, "x-cache"), "MISS");
+    assert_eq!(header(&response, "x-fastgif-auto-quality"), "long-hd-calm");
+    let runs = runs(&dir);
+    assert_eq!(runs.len(), 1, "{:?}", runs);
+    assert!(runs[0].contains("scale='trunc(min(iw,320)/2)*2':-2,fps=10"), "{}", runs[0]);
+
+    // and it's kept under the settings it was held to
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(header(&response, "x-cache"), "HIT");
+    assert_eq!(header(&response, "x-fastgif-auto-quality"), "long-hd-calm");
+    assert_eq!(self::runs(&dir).len(), 1);
+    assert_eq!(probes(&dir), 1);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn what_a_request_asks_for_wins() {
+    let dir = setup("asked");
+    let app = router::app(config(&dir, true)).await;
+    get(&app, "/tweet_video/abc.gif").await;
+
+    // Converted as configured, not sent the GIF held to the bucket
+    let response = get(&app, "/tweet_video/abc.gif?auto=0").await;
+    assert_eq!(header(&response, "x-cache"), "MISS");
+    assert_eq!(header(&response, "x-fastgif-auto-quality"), "");
+    let runs = runs(&dir);
+    assert!(runs[1].contains("-filter_complex scale=trunc(iw/2)*2:trunc(ih/2)*2,fps=20,"), "{}", runs[1]);
+
+    for uri in ["/tweet_video/abc.gif?width=640", "/tweet_video/abc.gif?fps=15"] {
+        let response = get(&app, uri).await;
+        assert_eq!(response.status(), 200, "{}", uri);
+        assert_eq!(header(&response, "x-fastgif-auto-quality"), "", "{}", uri);
+    }
+    let runs = self::runs(&dir);
+    assert!(runs[2].contains("scale='trunc(min(iw,640)/2)*2':-2,fps=20"), "{}", runs[2]);
+    assert!(runs[3].contains(",fps=15,"), "{}", runs[3]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn nothing_is_probed_with_it_off() {
+    let dir = setup("off");
+    let app = router::app(config(&dir, false)).await;
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(header(&response, "x-fastgif-auto-quality"), "");
+    assert!(runs(&dir)[0].contains("-filter_complex scale=trunc(iw/2)*2:trunc(ih/2)*2,fps=20,"), "{}", runs(&dir)[0]);
+    assert_eq!(probes(&dir), 0);
+    let _ = std::fs::remove_dir_all(&dir);
+}
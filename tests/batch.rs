@@ -0,0 +1,115 @@
+//! Sends batches to `POST /batch` through the router, converting videos from
+//! a `SOURCE_DIR` with an ffmpeg that passes on what it's fed, and checks
+//! what each item reports, that the GIFs land in the cache and how batches
+//! are limited.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::{json, Value};
+use support::router::{self, send, setup, PASSES_ON, VIDEO};
+
+mod support;
+
+// POST `body` to `/batch`
+async fn post_batch(app: &Router, body: &str) -> Response {
+    let request = Request::post("/batch").header("content-type", "application/json").body(Body::from(body.to_string())).unwrap();
+    send(app, request).await
+}
+
+// POST `body` to `/batch`, returning the status and what came back
+async fn batch(app: &Router, body: &str) -> (u16, String) {
+    let response = post_batch(app, body).await;
+    let status = response.status().as_u16();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+#[tokio::test]
+async fn items_succeed_or_fail_on_their_own() {
+    let dir = setup("items", PASSES_ON);
+    let config = Config {
+        cache_max_bytes: Some(1024 * 1024),
+        video_base_url: "https://video.example.com".to_string(),
+        ..router::config(&dir)
+    };
+    let app = router::app(config).await;
+    let items = json!([
+        {"path": "abc.gif"},
+        {"path": "/tweet_video/missing.gif"},
+        {"path": "../etc/passwd"},
+        {"url": "https://video.example.com/tweet_video/abc.mp4?repeat=2", "params": {"width": 480}},
+        {"url": "https://elsewhere.example.com/tweet_video/abc.mp4"},
+        {"path": "abc.gif", "params": {"width": [480]}},
+    ]);
+    let (status, body) = batch(&app, &items.to_string()).await;
+    assert_eq!(status, 200, "{}", body);
+    let outcomes: Vec<Value> = serde_json::from_str(&body).unwrap();
+    let statuses: Vec<u64> = outcomes.iter().map(|outcome| outcome["status"].as_u64().unwrap()).collect();
+    assert_eq!(statuses, [200, 404, 400, 200, 400, 400], "{}", body);
+
+    assert_eq!(outcomes[0]["bytes"], VIDEO.len());
+    assert_eq!(outcomes[0]["cache"], "miss");
+    assert_eq!(outcomes[0]["url"], "/tweet_video/abc.gif");
+    assert_eq!(outcomes[1]["error"], "upstream_not_found");
+    assert!(outcomes[1].get("url").is_none());
+    assert!(outcomes[2]["error"].as_str().unwrap().starts_with("invalid_path"));
+    assert_eq!(outcomes[3]["url"], "/tweet_video/abc.mp4?repeat=2&width=480");
+
+    // What was converted is sent from the cache, without the body inline
+    let response = send(&app, Request::get("/tweet_video/abc.gif").body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-cache"], "HIT");
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    let (_, body) = batch(&app, r#"[{"path": "abc.gif"}, {"path": "abc.mp4", "params": {"width": "480", "repeat": 2}}]"#).await;
+    let outcomes: Vec<Value> = serde_json::from_str(&body).unwrap();
+    assert_eq!(outcomes[0]["cache"], "hit", "{}", body);
+    assert_eq!(outcomes[1]["cache"], "hit", "{}", body);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn batches_are_limited() {
+    let dir = setup("limits", PASSES_ON);
+    let config = Config {
+        batch_max_items: 2,
+        batch_rate_limit: "3/minute".parse().unwrap(),
+        rate_limit_legacy_headers: true,
+        ..router::config(&dir)
+    };
+    let app = router::app(config).await;
+
+    let three = r#"[{"path": "abc.gif"}, {"path": "abc.gif"}, {"path": "abc.gif"}]"#;
+    assert_eq!(batch(&app, three).await.0, 400);
+    let (status, body) = batch(&app, r#"[{"path": "abc.gif", "extra": 1}]"#).await;
+    assert_eq!(status, 400);
+    assert!(body.contains("invalid_batch"), "{}", body);
+    let (status, body) = batch(&app, r#"[{"path": "abc.gif"}]"#).await;
+    // Without a cache there's nothing to say about one
+    assert_eq!(status, 200, "{}", body);
+    assert!(body.contains(r#""cache":"none""#), "{}", body);
+    // Every batch counts, even the ones that were refused, and every
+    // response says how many are left
+    let header = |response: &Response, name| response.headers().get(name).map(|value| value.to_str().unwrap().to_string());
+    let refused = post_batch(&app, r#"[{"path": "abc.gif"}]"#).await;
+    assert_eq!(refused.status(), 429);
+    assert_eq!(header(&refused, "ratelimit-limit").as_deref(), Some("3"));
+    assert_eq!(header(&refused, "ratelimit-remaining").as_deref(), Some("0"));
+    assert_eq!(header(&refused, "x-ratelimit-remaining").as_deref(), Some("0"));
+    assert!(header(&refused, "ratelimit-reset").is_some_and(|reset| (55..=60).contains(&reset.parse::<u64>().unwrap())));
+    assert!(header(&refused, "retry-after").is_some());
+
+    // Without the legacy headers, for a client starting out
+    let app = router::app(Config { batch_rate_limit: "3/minute".parse().unwrap(), ..router::config(&dir) }).await;
+    let first = post_batch(&app, r#"[{"path": "abc.gif"}]"#).await;
+    assert_eq!(first.status(), 200);
+    assert_eq!(header(&first, "ratelimit-remaining").as_deref(), Some("2"));
+    assert_eq!(header(&first, "ratelimit-reset").as_deref(), Some("20"));
+    assert_eq!(header(&first, "x-ratelimit-remaining"), None);
+    let second = post_batch(&app, r#"[{"path": "abc.gif"}]"#).await;
+    assert_eq!(header(&second, "ratelimit-remaining").as_deref(), Some("1"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
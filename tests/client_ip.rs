@@ -0,0 +1,132 @@
+//! Sends requests with every kind of forwarding header to servers that do and
+//! don't trust the peer as a proxy, and checks the client address their
+//! access log lines end up with.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{ChildStdout, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use support::server::{free_port, server_command};
+
+mod support;
+
+/// Headers sent from 127.0.0.1, and who the client should be when 127.0.0.0/8
+/// and 10.0.0.0/8 are trusted proxies.
+const TRUSTED_PEER: &[(&[&str], &str)] = &[
+    (&[], "127.0.0.1"),
+    (&["X-Forwarded-For: 203.0.113.7"], "203.0.113.7"),
+    // Whatever the client put in front of the proxy's entry is ignored
+    (&["X-Forwarded-For: 198.51.100.1, 203.0.113.7"], "203.0.113.7"),
+    // Our own proxies are skipped
+    (&["X-Forwarded-For: 198.51.100.1, 203.0.113.7, 10.1.2.3"], "203.0.113.7"),
+    (&["X-Forwarded-For: 10.0.0.1, 10.0.0.2"], "10.0.0.1"),
+    (&["X-Forwarded-For: 203.0.113.7:4711"], "203.0.113.7"),
+    (&["X-Forwarded-For: garbage, 203.0.113.7"], "203.0.113.7"),
+    // Nothing our proxy said can be made sense of
+    (&["X-Forwarded-For: 203.0.113.7, garbage"], "127.0.0.1"),
+    // Several headers make one list
+    (&["X-Forwarded-For: 198.51.100.1", "X-Forwarded-For: 203.0.113.7, 10.0.0.5"], "203.0.113.7"),
+    (&["Forwarded: for=203.0.113.9;proto=https"], "203.0.113.9"),
+    (&["Forwarded: for=\"[2001:db8::17]:4711\""], "2001:db8::17"),
+    (&["Forwarded: for=198.51.100.1, for=203.0.113.9;by=10.0.0.1, for=10.0.0.3"], "203.0.113.9"),
+    (&["Forwarded: for=unknown"], "127.0.0.1"),
+    // `Forwarded` wins over `X-Forwarded-For`
+    (&["X-Forwarded-For: 198.51.100.1", "Forwarded: for=203.0.113.9"], "203.0.113.9"),
+];
+
+/// The same, when only 192.0.2.0/24 is trusted: the headers are spoofed.
+const UNTRUSTED_PEER: &[(&[&str], &str)] = &[
+    (&[], "127.0.0.1"),
+    (&["X-Forwarded-For: 203.0.113.7"], "127.0.0.1"),
+    (&["X-Forwarded-For: 203.0.113.7, 192.0.2.1"], "127.0.0.1"),
+    (&["Forwarded: for=203.0.113.9"], "127.0.0.1"),
+];
+
+struct Server {
+    _server: support::server::Server,
+    port: u16,
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+fn start(trust_proxy: &str) -> Server {
+    let port = free_port();
+    let mut command = server_command(port, &[("LOG_FORMAT", "json"), ("TRUST_PROXY", trust_proxy)]);
+    command.stdout(Stdio::piped());
+    let mut server = support::server::start(command, port);
+    let lines = collect(server.0.stdout.take().unwrap());
+    Server { _server: server, port, lines }
+}
+
+fn collect(stdout: ChildStdout) -> Arc<Mutex<Vec<String>>> {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let collected = lines.clone();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else { break };
+            collected.lock().unwrap().push(line);
+        }
+    });
+    lines
+}
+
+// The client address in the access log line of a request with `headers`
+fn client_ip(server: &Server, request_id: &str, headers: &[&str]) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    let headers: String = headers.iter().map(|header| format!("{}\r\n", header)).collect();
+    write!(
+        stream,
+        "GET /version HTTP/1.1\r\nHost: localhost\r\nX-Request-Id: {}\r\n{}Connection: close\r\n\r\n",
+        request_id, headers
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    // The line is written once the response is sent, and read on another thread
+    let needle = format!("\"request_id\":\"{}\"", request_id);
+    let started = Instant::now();
+    loop {
+        let line = server
+            .lines
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|line| line.contains(&needle) && line.contains("\"client_ip\""))
+            .cloned();
+        if let Some(line) = line {
+            let (_, rest) = line.split_once("\"client_ip\":\"").unwrap();
+            return rest.split('"').next().unwrap().to_string();
+        }
+        assert!(started.elapsed() < Duration::from_secs(2), "no access log line for {}", request_id);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn client_comes_from_trusted_proxy_headers() {
+    let server = start("127.0.0.0/8, 10.0.0.0/8");
+    for (i, (headers, expected)) in TRUSTED_PEER.iter().enumerate() {
+        let got = client_ip(&server, &format!("trusted-{}", i), headers);
+        assert_eq!(got, *expected, "{:?}", headers);
+    }
+}
+
+#[test]
+fn untrusted_peer_headers_are_ignored() {
+    let server = start("192.0.2.0/24");
+    for (i, (headers, expected)) in UNTRUSTED_PEER.iter().enumerate() {
+        let got = client_ip(&server, &format!("untrusted-{}", i), headers);
+        assert_eq!(got, *expected, "{:?}", headers);
+    }
+}
+
+#[test]
+fn any_peer_counts_only_its_own_entry() {
+    // `true`: one proxy in front, whatever its address
+    let server = start("true");
+    let got = client_ip(&server, "any-0", &["X-Forwarded-For: 198.51.100.1, 203.0.113.7"]);
+    assert_eq!(got, "203.0.113.7");
+    let got = client_ip(&server, "any-1", &["X-Forwarded-For: 203.0.113.7, 10.0.0.1"]);
+    assert_eq!(got, "10.0.0.1");
+}
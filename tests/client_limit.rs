@@ -0,0 +1,54 @@
+//! Gives each client one conversion at a time (`MAX_CONCURRENT_PER_CLIENT`)
+//! and checks a GIF sent from the cache doesn't need a turn of its own.
+#![cfg(unix)]
+
+use axum::body::to_bytes;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use support::router::{self, get, setup, HANGS, VIDEO};
+
+mod support;
+
+// Until the conversion that's hanging is in flight
+async fn wait_for_one(app: &Router) {
+    let started = Instant::now();
+    loop {
+        let response = get(app, "/queue").await;
+        let queue: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        if queue["in_flight"] == 1 {
+            return;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "{}", queue);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn cache_hits_dont_take_a_clients_turn() {
+    let dir = setup("hits", HANGS);
+    std::fs::write(dir.join("def.mp4"), VIDEO).unwrap();
+    std::fs::write(dir.join("ghi.mp4"), VIDEO).unwrap();
+    let config = Config { max_concurrent_per_client: 1, cache_max_bytes: Some(1024 * 1024), ..router::config(&dir) };
+    let app = router::app(config).await;
+    assert_eq!(get(&app, "/tweet_video/abc.gif").await.status(), 200);
+
+    std::fs::write(dir.join("hang"), b"").unwrap();
+    let hanging = {
+        let app = app.clone();
+        tokio::spawn(async move { get(&app, "/tweet_video/def.gif").await.status() })
+    };
+    wait_for_one(&app).await;
+
+    let hit = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(hit.status(), 200);
+    assert_eq!(hit.headers()["x-cache"], "HIT");
+    // while one that has to be converted waits for the client's turn
+    assert_eq!(get(&app, "/tweet_video/ghi.gif").await.status(), 429);
+
+    std::fs::remove_file(dir.join("hang")).unwrap();
+    assert_eq!(hanging.await.unwrap(), 200);
+    assert_eq!(get(&app, "/tweet_video/ghi.gif").await.status(), 200);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,132 @@
+//! Compares encoders with `POST /admin/compare`, and checks each combo is
+//! converted with what it asked for, reported on, kept to be looked at, and
+//! the comparison audited.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use support::router::{self, send, tool, VIDEO};
+
+mod support;
+
+// What gifski makes, a GIF of its own, and ffmpeg makes of anything it's
+// asked to make WebP of: a lossless pixel
+const GIFSKI_MADE: &[u8] = b"GIF89a\x02\x00\x02\x00\x00\x00\x00,\x00\x00\x00\x00\x02\x00\x02\x00\x00\x02\x02D\x01\x00;";
+const WEBP: &[u8] = b"RIFF\x1a\x00\x00\x00WEBPVP8L\x0d\x00\x00\x00\x2f\x00\x00\x00\x10\x07\x10\x11\x11\x88\x88\xfe\x07\x00";
+
+// A directory of the test's own, with `abc.mp4`, an ffmpeg in it that makes
+// `WEBP` of anything it's asked to make WebP of and otherwise passes on what
+// it's fed, and a gifski that writes what it was told to `said`
+fn setup(test: &str) -> PathBuf {
+    let ffmpeg = "case \"$*\" in *libwebp_anim*) cat > /dev/null; exec cat \"$(dirname \"$0\")/made.webp\";; esac\nexec cat";
+    let dir = router::setup(test, ffmpeg);
+    tool(&dir, "gifski", "printf 'gifski %s' \"$*\" > \"$(dirname \"$0\")/said\"\ncat > /dev/null\nexec cat \"$(dirname \"$0\")/made.gif\"");
+    std::fs::write(dir.join("made.gif"), GIFSKI_MADE).unwrap();
+    std::fs::write(dir.join("made.webp"), WEBP).unwrap();
+    dir
+}
+
+fn config(dir: &Path) -> Config {
+    Config {
+        admin_token: Some("admin".to_string()),
+        ..router::config(dir)
+    }
+}
+
+async fn compare(app: &Router, comparison: &str, authorization: &str) -> Response {
+    let request = Request::post("/admin/compare")
+        .header("authorization", format!("Bearer {}", authorization))
+        .header("content-type", "application/json")
+        .body(Body::from(comparison.to_string()))
+        .unwrap();
+    send(app, request).await
+}
+
+async fn fetch(app: &Router, uri: &str) -> Response {
+    send(app, Request::get(uri).header("authorization", "Bearer admin").body(Body::empty()).unwrap()).await
+}
+
+#[tokio::test]
+async fn each_combo_is_converted_and_kept() {
+    let dir = setup("combos");
+    let audit = dir.join("audit.log");
+    let app = router::app(Config { audit_log_path: Some(audit.clone()), ..config(&dir) }).await;
+    let comparison = r#"{"source": {"path": "abc.gif", "params": {"width": 320}}, "combos": [
+        {"encoder": "subprocess", "quality": 50, "fast": false},
+        {"encoder": "ffmpeg"},
+        {"encoder": "ffmpeg-webp", "quality": 75}
+    ]}"#;
+    let response = compare(&app, comparison, "admin").await;
+    assert_eq!(response.status(), 200);
+    let report: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+    assert_eq!(report["path"], "abc.gif");
+    let results = report["results"].as_array().unwrap();
+    assert_eq!(results.len(), 3);
+    for result in results {
+        assert_eq!(result["outcome"], "ok", "{}", result);
+        assert!(result["wall_ms"].is_u64() && result["cpu_ms"].is_u64() && result["max_rss_bytes"].is_u64(), "{}", result);
+        assert!(result["expires_at"].as_i64().unwrap() > 0);
+    }
+    assert_eq!((results[0]["quality"].as_u64(), results[0]["fast"].as_bool()), (Some(50), Some(false)));
+    assert_eq!((results[1]["quality"].as_u64(), results[1]["fast"].as_bool()), (Some(90), Some(true)));
+    assert_eq!(results[2]["format"], "webp");
+
+    // What each made can be looked at
+    let gifski = fetch(&app, results[0]["result_url"].as_str().unwrap()).await;
+    assert_eq!(gifski.headers()["content-type"], "image/gif");
+    let made = to_bytes(gifski.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(made, GIFSKI_MADE);
+    let said = std::fs::read_to_string(dir.join("said")).unwrap();
+    assert!(said.contains("--quality 50"), "{}", said);
+    assert_eq!(results[0]["output_bytes"], made.len());
+    let ffmpeg = fetch(&app, results[1]["result_url"].as_str().unwrap()).await;
+    assert_eq!(to_bytes(ffmpeg.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    let webp = fetch(&app, results[2]["result_url"].as_str().unwrap()).await;
+    assert_eq!(webp.headers()["content-type"], "image/webp");
+    assert_eq!(to_bytes(webp.into_body(), usize::MAX).await.unwrap(), WEBP);
+
+    // and the audit log has what was compared
+    let started = Instant::now();
+    let record = loop {
+        let log = std::fs::read_to_string(&audit).unwrap_or_default();
+        if let Some(line) = log.lines().next() {
+            break serde_json::from_str::<Value>(line).unwrap();
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert_eq!(record["outcome"], "ok");
+    assert_eq!(record["compared"], serde_json::json!(["subprocess/q50", "ffmpeg/q90/fast", "ffmpeg-webp/q75/fast"]));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn comparisons_are_for_admins_and_bounded() {
+    let dir = setup("bounded");
+    let app = router::app(config(&dir)).await;
+    let one = r#"{"source": {"path": "abc.gif"}, "combos": [{"encoder": "ffmpeg"}]}"#;
+    assert_eq!(compare(&app, one, "nope").await.status(), 401);
+    assert_eq!(compare(&app, one, "admin").await.status(), 200);
+
+    let too_many = format!(r#"{{"source": {{"path": "abc.gif"}}, "combos": [{}]}}"#, [r#"{"encoder": "ffmpeg"}"#; 9].join(","));
+    for comparison in [
+        too_many.as_str(),
+        r#"{"source": {"path": "abc.gif"}, "combos": []}"#,
+        r#"{"source": {"path": "abc.gif"}, "combos": [{"encoder": "potato"}]}"#,
+        r#"{"source": {"path": "abc.gif"}, "combos": [{"encoder": "ffmpeg", "quality": 0}]}"#,
+        r#"{"source": {}, "combos": [{"encoder": "ffmpeg"}]}"#,
+    ] {
+        let response = compare(&app, comparison, "admin").await;
+        assert_eq!(response.status(), 400, "{}", comparison);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("invalid_comparison"), "{:?}", body);
+    }
+    assert_eq!(fetch(&app, "/admin/compare/0123456789abcdef0123456789abcdef").await.status(), 404);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,109 @@
+//! Converts videos through the router against a mock upstream serving
+//! `fixtures/clip.y4m` (2 s of 32x32 video at 5 fps), and checks what comes
+//! back when the upstream is missing the video or too slow. The conversions
+//! themselves need real ffmpeg and ffprobe binaries, and pass without
+//! checking anything when they aren't installed.
+
+use axum::body::to_bytes;
+use axum::routing::get;
+use axum::Router;
+use fastgif::config::Config;
+use fastgif_core::fetch::FetchMode;
+use std::time::Duration;
+use support::{is_installed, router};
+
+mod support;
+
+const FIXTURE: &[u8] = include_bytes!("fixtures/clip.y4m");
+
+// Serves the fixture as `clip.mp4`, and after a long wait as `slow.mp4`.
+// Anything else is a 404.
+async fn serve_upstream() -> String {
+    let upstream = Router::new()
+        .route("/tweet_video/clip.mp4", get(|| async { FIXTURE }))
+        .route(
+            "/tweet_video/slow.mp4",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                FIXTURE
+            }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, upstream).await });
+    format!("http://{}", addr)
+}
+
+// Send a GET through the router and return the status code and body
+async fn oneshot(app: &Router, path: &str) -> (u16, Vec<u8>) {
+    let response = router::get(app, path).await;
+    let status = response.status().as_u16();
+    (status, to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec())
+}
+
+fn assert_gif(gif: &[u8]) {
+    assert!(gif.starts_with(b"GIF89a"), "body isn't a GIF");
+    assert_eq!(gif.last(), Some(&0x3B), "GIF has no trailer");
+    // Ten frames of 32x32 come to a few kilobytes one way or the other
+    assert!((200..200_000).contains(&gif.len()), "GIF is {} bytes", gif.len());
+}
+
+#[tokio::test]
+async fn upstream_failures_are_answered_without_converting() {
+    // Downloading it ourselves, nothing is spawned until the upstream has answered
+    let config = Config {
+        video_base_url: serve_upstream().await,
+        upstream_fetch: FetchMode::InProcess,
+        conversion_timeout: 1.0,
+        max_input_duration: 0.0,
+        skip_binary_check: true,
+        ..Config::default()
+    };
+    let app = router::app(config).await;
+
+    let (status, body) = oneshot(&app, "/tweet_video/missing.gif").await;
+    assert_eq!(status, 404, "{}", String::from_utf8_lossy(&body));
+    assert!(String::from_utf8_lossy(&body).contains("upstream_not_found"));
+
+    let (status, body) = tokio::time::timeout(Duration::from_secs(10), oneshot(&app, "/tweet_video/slow.gif"))
+        .await
+        .expect("the conversion timeout didn't apply");
+    assert_eq!(status, 504, "{}", String::from_utf8_lossy(&body));
+    assert!(String::from_utf8_lossy(&body).contains("timeout"));
+}
+
+#[tokio::test]
+async fn converts_end_to_end() {
+    if !is_installed("ffmpeg") || !is_installed("ffprobe") {
+        eprintln!("ffmpeg or ffprobe isn't installed, skipping");
+        return;
+    }
+    // gifski if it's there, or else ffmpeg on its own. The identical
+    // requests below all come from one client, more than it'd be let have.
+    let config = Config {
+        video_base_url: serve_upstream().await,
+        max_concurrent_per_client: 0,
+        ..Config::default()
+    };
+    let app = router::app(config).await;
+
+    let (status, gif) = oneshot(&app, "/tweet_video/clip.gif").await;
+    assert_eq!(status, 200, "{}", String::from_utf8_lossy(&gif));
+    assert_gif(&gif);
+
+    // The server caches nothing but the probe, which the second request hits
+    let (status, again) = oneshot(&app, "/tweet_video/clip.gif").await;
+    assert_eq!(status, 200);
+    assert_eq!(again, gif);
+    let (_, metrics) = oneshot(&app, "/metrics").await;
+    let metrics = String::from_utf8_lossy(&metrics);
+    assert!(metrics.contains(r#"fastgif_cache_hits_total{cache="probe"} 1"#), "{}", metrics);
+    assert!(metrics.contains(r#"fastgif_cache_misses_total{cache="probe"} 1"#), "{}", metrics);
+
+    // Identical requests at once are each converted, and agree
+    let conversions = (0..4).map(|_| oneshot(&app, "/tweet_video/clip.gif"));
+    for (status, body) in futures_util::future::join_all(conversions).await {
+        assert_eq!(status, 200, "{}", String::from_utf8_lossy(&body));
+        assert_eq!(body, gif);
+    }
+}
@@ -0,0 +1,69 @@
+//! Runs `fastgif convert` on a local file, with `tests/support/fake-tool.sh`
+//! as ffmpeg and gifski, and checks where the GIF goes, what's said about
+//! it, and how a failure is reported.
+#![cfg(target_os = "linux")]
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use support::router::{fake_tools, temp_dir};
+
+mod support;
+
+// A directory of the test's own with the fake tools and a "video" in it
+fn setup(test: &str) -> PathBuf {
+    let dir = temp_dir(test);
+    fake_tools(&dir);
+    std::fs::write(dir.join("input.mp4"), vec![0u8; 1000]).unwrap();
+    dir
+}
+
+fn convert(dir: &Path, args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fastgif"))
+        .arg("convert")
+        .arg(dir.join("input.mp4"))
+        .args(args)
+        .env("FFMPEG_PATH", dir.join("ffmpeg"))
+        .env("GIFSKI_PATH", dir.join("gifski"))
+        .env("ENCODER", "subprocess")
+        .envs(env.iter().copied())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn converts_to_a_file_or_stdout() {
+    let dir = setup("output");
+    let gif = dir.join("output.gif");
+    let pixel = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/pixel.gif");
+    let made = [("FAKE_GIFSKI_OUTPUT", pixel.to_str().unwrap())];
+    let output = convert(&dir, &["-o", gif.to_str().unwrap(), "--width", "480", "--fps", "10"], &made);
+    let said = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", said);
+    assert_eq!(std::fs::read(&gif).unwrap(), std::fs::read(&pixel).unwrap());
+    assert!(said.starts_with("43 bytes in "), "{}", said);
+    assert!(said.contains("ffmpeg ") && said.contains("encoder "), "{}", said);
+
+    let output = convert(&dir, &["-o", "-"], &made);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, std::fs::read(&pixel).unwrap());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn failures_exit_nonzero_with_their_class() {
+    let dir = setup("failure");
+    let env = [("FAKE_FFMPEG_STDERR", "Invalid data found when processing input"), ("FAKE_FFMPEG_EXIT", "1")];
+    let output = convert(&dir, &["-o", "-"], &env);
+    let said = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", said);
+    assert!(said.starts_with("unsupported_source: "), "{}", said);
+    assert!(said.contains("ffmpeg: Invalid data found when processing input"), "{}", said);
+    assert!(output.stdout.is_empty());
+
+    // This ffmpeg has no libwebp
+    let output = convert(&dir, &["-o", "-", "--format", "webp"], &[]);
+    let said = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", said);
+    assert!(said.contains("can't make webp"), "{}", said);
+    let _ = std::fs::remove_dir_all(&dir);
+}
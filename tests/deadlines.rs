@@ -0,0 +1,141 @@
+//! Sends conversions with an `X-Request-Timeout`, to an ffmpeg that waits for
+//! as long as the test says, and checks they're given up on by then, or
+//! turned away if their turn wouldn't come in time.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use fastgif::Connection;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use support::router::{self, setup, TAKES_A_WHILE};
+use tower::ServiceExt;
+
+mod support;
+
+fn config(dir: &Path) -> Config {
+    Config {
+        max_concurrent_conversions: Some(1),
+        max_queue_depth: Some(5),
+        queue_wait_timeout: 30.0,
+        // They all come from the one client
+        max_concurrent_per_client: 0,
+        ..router::config(dir)
+    }
+}
+
+async fn send(app: &Router, timeout: Option<&str>) -> Response {
+    let mut request = Request::get("/tweet_video/abc.gif");
+    if let Some(timeout) = timeout {
+        request = request.header("x-request-timeout", timeout);
+    }
+    router::send(app, request.body(Body::empty()).unwrap()).await
+}
+
+// What a conversion that gives up within `timeout` comes to, and how long it took
+async fn convert(app: &Router, timeout: Option<&str>) -> (u16, String, Duration) {
+    let started = Instant::now();
+    let response = send(app, timeout).await;
+    let status = response.status().as_u16();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let error = serde_json::from_slice::<Value>(&body).ok().and_then(|body| body["error"].as_str().map(str::to_string));
+    (status, error.unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned()), started.elapsed())
+}
+
+// One conversion held up for as long as there's a `hang` file
+fn hold_up(app: &Router, dir: &Path) -> tokio::task::JoinHandle<u16> {
+    std::fs::write(dir.join("hang"), "").unwrap();
+    let app = app.clone();
+    tokio::spawn(async move { convert(&app, None).await.0 })
+}
+
+#[tokio::test]
+async fn a_conversion_past_its_deadline_is_given_up_on() {
+    let dir = setup("conversion", TAKES_A_WHILE);
+    let app = router::app(config(&dir)).await;
+    std::fs::write(dir.join("hang"), "").unwrap();
+    let (status, error, took) = convert(&app, Some("300")).await;
+    assert_eq!((status, error.as_str()), (504, "deadline_exceeded"));
+    assert!(took < Duration::from_secs(2), "{:?}", took);
+
+    // Without one, it's converted however long it takes
+    std::fs::remove_file(dir.join("hang")).unwrap();
+    assert_eq!(convert(&app, Some("10000")).await.0, 200);
+    assert_eq!(convert(&app, None).await.0, 200);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn a_deadline_is_no_later_than_the_servers_own() {
+    let dir = setup("capped", TAKES_A_WHILE);
+    let app = router::app(Config { conversion_timeout: 1.0, ..config(&dir) }).await;
+    std::fs::write(dir.join("hang"), "").unwrap();
+    // It's given up on a second after it came in, not a minute
+    let (status, error, took) = convert(&app, Some("60000")).await;
+    assert_eq!((status, error.as_str()), (504, "deadline_exceeded"));
+    assert!(took < Duration::from_secs(3), "{:?}", took);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn what_isnt_a_number_of_milliseconds_is_refused() {
+    let dir = setup("invalid", TAKES_A_WHILE);
+    let app = router::app(config(&dir)).await;
+    for timeout in ["soon", "0", "-5", "1.5"] {
+        let (status, body, _) = convert(&app, Some(timeout)).await;
+        assert_eq!(status, 400, "{}", timeout);
+        assert!(body.contains("invalid_request_timeout"), "{}: {}", timeout, body);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn one_whose_turn_would_come_too_late_isnt_let_in() {
+    let dir = setup("admission", TAKES_A_WHILE);
+    let app = router::app(config(&dir)).await;
+    // It takes at least 200ms, which the estimate goes by
+    assert_eq!(convert(&app, None).await.0, 200);
+
+    // One running, and two waiting behind it
+    let running = hold_up(&app, &dir);
+    let waiting: Vec<_> = (0..2)
+        .map(|_| {
+            let app = app.clone();
+            tokio::spawn(async move { convert(&app, Some("20000")).await.0 })
+        })
+        .collect();
+    let started = Instant::now();
+    while queue(&app).await["queued"] != 2 {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // Its turn's about 400ms away at best
+    let (status, error, took) = convert(&app, Some("250")).await;
+    assert_eq!((status, error.as_str()), (504, "deadline_exceeded"));
+    assert!(took < Duration::from_millis(200), "{:?}", took);
+    // while one with the time to wait for it waits, and then gives up
+    let (status, error, took) = convert(&app, Some("1000")).await;
+    assert_eq!((status, error.as_str()), (504, "deadline_exceeded"));
+    assert!(took >= Duration::from_millis(900), "{:?}", took);
+
+    std::fs::remove_file(dir.join("hang")).unwrap();
+    assert_eq!(running.await.unwrap(), 200);
+    for waiting in waiting {
+        assert_eq!(waiting.await.unwrap(), 200);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+async fn queue(app: &Router) -> Value {
+    let mut request = Request::get("/queue").body(Body::empty()).unwrap();
+    request.extensions_mut().insert(ConnectInfo(Connection::from(SocketAddr::from(([127, 0, 0, 1], 40000)))));
+    let response = app.clone().oneshot(request).await.unwrap();
+    serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+}
@@ -0,0 +1,143 @@
+//! Converts with `CACHE_DIR` set, and checks the GIFs are sent again from
+//! their files there, in part when a `Range` asks for it, with the headers
+//! a conversion would have had.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use fastgif::Connection;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use support::router::{self, header, READS_INPUT, VIDEO};
+use tower::ServiceExt;
+
+mod support;
+
+// A directory of the test's own, with `abc.mp4`, a `cache`, and an ffmpeg
+// in it that passes on what it's given to read, and counts its runs in `runs`
+fn setup(test: &str) -> PathBuf {
+    let dir = router::setup(test, &format!("echo >> \"$(dirname \"$0\")/runs\"\n{}", READS_INPUT));
+    std::fs::create_dir_all(dir.join("cache")).unwrap();
+    dir
+}
+
+fn config(dir: &Path) -> Config {
+    Config {
+        tmp_dir: Some(dir.to_path_buf()),
+        cache_dir: Some(dir.join("cache")),
+        ..router::config(dir)
+    }
+}
+
+async fn get(app: &Router, range: Option<&str>) -> Response {
+    let mut request = Request::get("/tweet_video/abc.gif");
+    if let Some(range) = range {
+        request = request.header("range", range);
+    }
+    let mut request = request.body(Body::empty()).unwrap();
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+    request.extensions_mut().insert(ConnectInfo(Connection::from(peer)));
+    app.clone().oneshot(request).await.unwrap()
+}
+
+fn runs(dir: &Path) -> usize {
+    std::fs::read_to_string(dir.join("runs")).map_or(0, |runs| runs.lines().count())
+}
+
+// The files kept in `cache`, once the first has been written
+async fn cached_files(dir: &Path) -> Vec<PathBuf> {
+    let started = Instant::now();
+    loop {
+        let files: Vec<_> = std::fs::read_dir(dir.join("cache")).unwrap().map(|entry| entry.unwrap().path()).collect();
+        if files.iter().any(|file| file.extension().is_some_and(|extension| extension == "gif")) {
+            return files;
+        }
+        assert!(started.elapsed() < Duration::from_secs(5), "nothing was written to the cache");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn a_gif_is_sent_again_from_its_file() {
+    let dir = setup("hit");
+    let app = router::app(config(&dir)).await;
+    let converted = get(&app, None).await;
+    assert_eq!(header(&converted, "x-cache"), "MISS");
+    let etag = header(&converted, "etag").to_string();
+    let converting = runs(&dir);
+
+    // under a hash of its key
+    let files = cached_files(&dir).await;
+    assert_eq!(files.len(), 1, "{:?}", files);
+    let name = files[0].file_stem().unwrap().to_str().unwrap();
+    assert!(name.len() == 64 && name.bytes().all(|byte| byte.is_ascii_hexdigit()), "{}", name);
+
+    let cached = get(&app, None).await;
+    assert_eq!(cached.status(), 200);
+    assert_eq!(header(&cached, "x-cache"), "HIT");
+    assert_eq!(header(&cached, "etag"), etag);
+    assert_eq!(header(&cached, "content-type"), "image/gif");
+    assert_eq!(header(&cached, "x-fastgif-encoder"), "ffmpeg");
+    assert_eq!(header(&cached, "cache-control"), "public, max-age=31536000");
+    assert_eq!(header(&cached, "content-length"), VIDEO.len().to_string());
+    assert_eq!(to_bytes(cached.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    assert_eq!(runs(&dir), converting);
+
+    // or the part of it asked for
+    let part = get(&app, Some("bytes=0-5")).await;
+    assert_eq!(part.status(), 206);
+    assert_eq!(header(&part, "content-range"), format!("bytes 0-5/{}", VIDEO.len()));
+    assert_eq!(header(&part, "x-cache"), "HIT");
+    assert_eq!(to_bytes(part.into_body(), usize::MAX).await.unwrap(), &VIDEO[..6]);
+    assert_eq!(runs(&dir), converting);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn spilled_gifs_are_kept_too() {
+    let dir = setup("spilled");
+    let app = router::app(Config { spill_threshold_bytes: Some(16), ..config(&dir) }).await;
+    assert_eq!(to_bytes(get(&app, None).await.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    let converting = runs(&dir);
+    cached_files(&dir).await;
+    let cached = get(&app, None).await;
+    assert_eq!(header(&cached, "x-cache"), "HIT");
+    assert_eq!(to_bytes(cached.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    assert_eq!(runs(&dir), converting);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn a_gif_whose_file_has_gone_is_converted_again() {
+    let dir = setup("gone");
+    let app = router::app(config(&dir)).await;
+    get(&app, None).await;
+    let converting = runs(&dir);
+    for file in cached_files(&dir).await {
+        std::fs::remove_file(file).unwrap();
+    }
+    let converted = get(&app, None).await;
+    assert_eq!(converted.status(), 200);
+    assert_eq!(header(&converted, "x-cache"), "MISS");
+    assert_eq!(to_bytes(converted.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    assert!(runs(&dir) > converting);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn what_a_previous_run_left_is_removed() {
+    let dir = setup("leftovers");
+    let left = dir.join("cache").join(format!("{}.gif", "ab".repeat(32)));
+    std::fs::write(&left, VIDEO).unwrap();
+    let theirs = dir.join("cache").join("notes.txt");
+    std::fs::write(&theirs, "").unwrap();
+    let _app = router::app(config(&dir)).await;
+    assert!(!left.exists());
+    assert!(theirs.exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
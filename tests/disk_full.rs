@@ -0,0 +1,108 @@
+//! Converts with `MIN_FREE_DISK_BYTES` set to more than any disk has, and
+//! checks what would be written to `TMP_DIR` is refused as `disk_full`, what
+//! wouldn't is converted as usual, and the server stops being ready once
+//! sweeping doesn't make room.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::response::Response;
+use fastgif::config::Config;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use support::router::{self, send, READS_INPUT, VIDEO};
+use support::server::spawn_server;
+
+mod support;
+
+// More than there is free anywhere
+const MIN_FREE: u64 = u64::MAX / 2;
+
+// A directory of the test's own, with `abc.mp4`, a `tmp`, and an ffmpeg in
+// it that passes on what it's given to read, piped or uploaded
+fn setup(test: &str) -> PathBuf {
+    let dir = router::setup(test, READS_INPUT);
+    std::fs::create_dir_all(dir.join("tmp")).unwrap();
+    dir
+}
+
+fn config(dir: &Path) -> Config {
+    Config {
+        tmp_dir: Some(dir.join("tmp")),
+        min_free_disk_bytes: Some(MIN_FREE),
+        ..router::config(dir)
+    }
+}
+
+async fn status_and_body(response: Response) -> (u16, String) {
+    let status = response.status().as_u16();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+#[tokio::test]
+async fn what_would_be_spilled_is_refused() {
+    let dir = setup("spill");
+    let app = router::app(Config { spill_threshold_bytes: Some(16), ..config(&dir) }).await;
+    let (status, body) = status_and_body(send(&app, Request::get("/tweet_video/abc.gif").body(Body::empty()).unwrap()).await).await;
+    assert_eq!(status, 507, "{}", body);
+    assert!(body.contains("\"error\":\"disk_full\""), "{}", body);
+    assert!(body.contains(&format!("Less than {} bytes are free", MIN_FREE)), "{}", body);
+    assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+
+    // while a GIF that fits in memory doesn't need the disk
+    let app = router::app(Config { spill_threshold_bytes: Some(1024 * 1024), ..config(&dir) }).await;
+    let (status, body) = status_and_body(send(&app, Request::get("/tweet_video/abc.gif").body(Body::empty()).unwrap()).await).await;
+    assert_eq!(status, 200, "{}", body);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn uploads_are_refused_before_theyre_read() {
+    let dir = setup("upload");
+    let app = router::app(config(&dir)).await;
+    let request = Request::post("/convert").header("content-type", "video/mp4").body(Body::from(VIDEO)).unwrap();
+    let (status, body) = status_and_body(send(&app, request).await).await;
+    assert_eq!(status, 507, "{}", body);
+    assert!(body.contains("\"error\":\"disk_full\""), "{}", body);
+    assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+
+    // and taken as usual with room to spare
+    let app = router::app(Config { min_free_disk_bytes: Some(1), ..config(&dir) }).await;
+    let request = Request::post("/convert").header("content-type", "video/mp4").body(Body::from(VIDEO)).unwrap();
+    let (status, body) = status_and_body(send(&app, request).await).await;
+    assert_eq!(status, 200, "{}", body);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+fn readyz(port: u16) -> Option<String> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+    write!(stream, "GET /readyz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    Some(response)
+}
+
+#[test]
+fn the_server_isnt_ready_once_sweeping_doesnt_make_room() {
+    let dir = setup("ready");
+    let (ffmpeg, tmp) = (dir.join("ffmpeg"), dir.join("tmp"));
+    let (_server, port) = spawn_server(&[
+        ("FFMPEG_PATH", ffmpeg.to_str().unwrap()),
+        ("TMP_DIR", tmp.to_str().unwrap()),
+        ("MIN_FREE_DISK_BYTES", &MIN_FREE.to_string()),
+    ]);
+    let started = Instant::now();
+    loop {
+        let response = readyz(port).unwrap_or_default();
+        if response.contains("not ready: disk_full") {
+            assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(20), "never said disk_full: {}", response);
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
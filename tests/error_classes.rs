@@ -0,0 +1,242 @@
+//! Runs conversions against a stand-in ffmpeg that fails with stderr captured
+//! from real ffmpeg runs, and checks each failure gets the right class: the
+//! status, the `error` code in the response and the per-class counter. Then
+//! checks which of them get `FAILURE_PLACEHOLDER_PATH` instead, and that a
+//! video with no `moov` atom yet is asked for again.
+#![cfg(unix)]
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use support::server::{spawn_server, Server};
+
+mod support;
+
+/// The name the video is requested under, what ffmpeg wrote to stderr before
+/// exiting with 1, and the code and status that should come of it.
+const SAMPLES: &[(&str, &str, &str, u16)] = &[
+    (
+        "not_found",
+        "[https @ 0x5581a6c0e2c0] HTTP error 404 Not Found\n\
+         [in#0 @ 0x5581a6c0c940] Error opening input: Server returned 404 Not Found\n\
+         Error opening input file https://video.twimg.com/tweet_video/not_found.mp4.\n\
+         Error opening input files: Server returned 404 Not Found\n",
+        "upstream_not_found",
+        404,
+    ),
+    (
+        "forbidden",
+        "[https @ 0x55d0b2f5a2c0] HTTP error 403 Forbidden\n\
+         https://video.twimg.com/tweet_video/forbidden.mp4: Server returned 403 Forbidden (access denied)\n",
+        "upstream_not_found",
+        404,
+    ),
+    (
+        "unavailable",
+        "[https @ 0x5603e1b4f2c0] HTTP error 503 Service Unavailable\n\
+         [in#0 @ 0x5603e1b4d940] Error opening input: Server returned 5XX Server Error reply\n\
+         Error opening input file https://video.twimg.com/tweet_video/unavailable.mp4.\n",
+        "upstream_5xx",
+        502,
+    ),
+    (
+        "timed_out",
+        "[tcp @ 0x55f1d3a8e340] Connection to tcp://video.twimg.com:443 failed: Connection timed out\n\
+         [in#0 @ 0x55f1d3a8c940] Error opening input: Connection timed out\n",
+        "upstream_timeout",
+        504,
+    ),
+    (
+        "refused",
+        "[tcp @ 0x5612c8f4e340] Connection to tcp://127.0.0.1:8080 failed: Connection refused\n\
+         http://127.0.0.1:8080/tweet_video/refused.mp4: Connection refused\n",
+        "upstream_5xx",
+        502,
+    ),
+    (
+        "truncated",
+        "[mov,mp4,m4a,3gp,3g2,mj2 @ 0x55b8f0a6f900] moov atom not found\n\
+         [in#0 @ 0x55b8f0a6c940] Error opening input: Invalid data found when processing input\n\
+         Error opening input file https://video.twimg.com/tweet_video/truncated.mp4.\n",
+        "source_incomplete",
+        503,
+    ),
+    (
+        "audio_only",
+        "Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'https://video.twimg.com/tweet_video/audio_only.mp4':\n\
+         \x20 Duration: 00:00:04.02, start: 0.000000, bitrate: 130 kb/s\n\
+         \x20 Stream #0:0[0x1](und): Audio: aac (LC) (mp4a / 0x6134706D), 44100 Hz, stereo, fltp, 128 kb/s (default)\n\
+         [out#0/yuv4mpegpipe @ 0x55d5e1b7a2c0] Output file does not contain any stream\n\
+         Error opening output file pipe:.\n\
+         Error opening output files: Invalid argument\n",
+        "unsupported_source",
+        422,
+    ),
+    (
+        "corrupt",
+        "Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'https://video.twimg.com/tweet_video/corrupt.mp4':\n\
+         \x20 Duration: 00:00:06.21, start: 0.000000, bitrate: 812 kb/s\n\
+         [h264 @ 0x56370fb2e9c0] Invalid NAL unit size (28377 > 1563).\n\
+         [h264 @ 0x56370fb2e9c0] Error splitting the input into NAL units.\n\
+         [vist#0:0/h264 @ 0x56370fb2c3c0] Error while decoding stream #0:0: Invalid data found when processing input\n",
+        "ffmpeg_decode_error",
+        500,
+    ),
+    (
+        "broken_pipe",
+        "Output #0, yuv4mpegpipe, to 'pipe:':\n\
+         [out#0/yuv4mpegpipe @ 0x55e9c4a1e2c0] Error muxing a packet\n\
+         av_interleaved_write_frame(): Broken pipe\n\
+         Error writing trailer of pipe:: Broken pipe\n",
+        "pipe_broken",
+        500,
+    ),
+    // Nothing we recognise: ffmpeg failed on the video, somehow
+    (
+        "mystery",
+        "[graph 0 input from stream 0:0 @ 0x55c2a1f3b480] Something nobody has seen before\n",
+        "ffmpeg_decode_error",
+        500,
+    ),
+];
+
+/// A 1x1 GIF to stand in for failures
+const PLACEHOLDER: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff\
+    \x21\xf9\x04\x01\x00\x00\x00\x00\x2c\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02\x44\x01\x00\x3b";
+
+// Send a GET and return the whole response
+fn request(port: u16, path: &str) -> Vec<u8> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    response
+}
+
+// Send a GET and return the status code and body
+fn get(port: u16, path: &str) -> (u16, String) {
+    let response = String::from_utf8_lossy(&request(port, path)).into_owned();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default();
+    (status, body)
+}
+
+// A directory with an `ffmpeg` that prints the sample named after the video
+// it's asked to convert, and fails. Each test gets its own.
+fn fake_ffmpeg(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fastgif-error-classes-{}-{}", test, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, stderr, _, _) in SAMPLES {
+        std::fs::write(dir.join(format!("{}.txt", name)), stderr).unwrap();
+    }
+    let script = format!(
+        "#!/bin/sh\n\
+         for arg; do case \"$arg\" in */tweet_video/*) name=${{arg##*/}}; name=${{name%.mp4}} ;; esac; done\n\
+         [ -n \"$name\" ] && cat \"{}/$name.txt\" >&2\n\
+         exit 1\n",
+        dir.display()
+    );
+    let ffmpeg = dir.join("ffmpeg");
+    std::fs::write(&ffmpeg, script).unwrap();
+    std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+    dir
+}
+
+// Start the server with the fake ffmpeg first on its PATH
+fn start(bin: &std::path::Path, extra_env: &[(&str, &str)]) -> (Server, u16) {
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default());
+    let env = [&[("PATH", path.as_str())], extra_env].concat();
+    spawn_server(&env)
+}
+
+#[test]
+fn ffmpeg_failures_are_classified() {
+    let bin = fake_ffmpeg("classified");
+    let (_server, port) = start(&bin, &[("MOOV_RETRIES", "0")]);
+
+    for (name, _, code, status) in SAMPLES {
+        let (got_status, body) = get(port, &format!("/tweet_video/{}.gif", name));
+        assert_eq!(got_status, *status, "{}: {}", name, body);
+        assert!(body.contains(&format!("\"error\":\"{}\"", code)), "{}: {}", name, body);
+    }
+
+    let (_, metrics) = get(port, "/metrics");
+    let count = |class: &str| {
+        let series = format!("fastgif_conversion_errors_total{{class=\"{}\"}} ", class);
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(&series))
+            .and_then(|count| count.parse::<usize>().ok())
+            .unwrap_or(0)
+    };
+    for (_, _, code, _) in SAMPLES {
+        let expected = SAMPLES.iter().filter(|(_, _, other, _)| other == code).count();
+        assert_eq!(count(code), expected, "{}", code);
+    }
+    let _ = std::fs::remove_dir_all(bin);
+}
+
+#[test]
+fn placeholder_stands_in_for_failures_nobody_can_fix() {
+    let bin = fake_ffmpeg("placeholder");
+    let placeholder = bin.join("placeholder.gif");
+    std::fs::write(&placeholder, PLACEHOLDER).unwrap();
+    let (_server, port) = start(&bin, &[("FAILURE_PLACEHOLDER_PATH", placeholder.to_str().unwrap())]);
+
+    let response = request(port, "/tweet_video/not_found.gif");
+    let (head, body) = response.split_at(response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4);
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+    assert!(head.starts_with("http/1.1 200"), "{}", head);
+    assert!(head.contains("content-type: image/gif\r\n"), "{}", head);
+    assert!(head.contains("cache-control: no-store\r\n"), "{}", head);
+    assert!(head.contains("x-fastgif-error: upstream_not_found\r\n"), "{}", head);
+    assert_eq!(body, PLACEHOLDER);
+
+    // API callers can still have the real status
+    let (status, body) = get(port, "/tweet_video/not_found.gif?strict=1");
+    assert_eq!(status, 404, "{}", body);
+    // and something being wrong on our side isn't papered over
+    let (status, body) = get(port, "/tweet_video/corrupt.gif");
+    assert_eq!(status, 500, "{}", body);
+    let _ = std::fs::remove_dir_all(bin);
+}
+
+#[test]
+fn a_video_without_its_moov_atom_yet_is_asked_for_again() {
+    // `late` is cut short the first time it's asked for, and whole after
+    // that, and anything else is cut short every time
+    let bin = fake_ffmpeg("moov");
+    std::fs::write(bin.join("whole.gif"), PLACEHOLDER).unwrap();
+    let script = format!(
+        "#!/bin/sh\n\
+         for arg; do case \"$arg\" in */tweet_video/*) name=${{arg##*/}}; name=${{name%.mp4}} ;; esac; done\n\
+         [ -z \"$name\" ] && exit 1\n\
+         [ \"$name\" = late ] && [ -e \"{dir}/late.seen\" ] && exec cat \"{dir}/whole.gif\"\n\
+         touch \"{dir}/$name.seen\"\n\
+         cat \"{dir}/truncated.txt\" >&2\n\
+         exit 1\n",
+        dir = bin.display()
+    );
+    std::fs::write(bin.join("ffmpeg"), script).unwrap();
+    let (_server, port) = start(&bin, &[("MOOV_RETRIES", "1")]);
+
+    let response = request(port, "/tweet_video/late.gif");
+    assert!(response.starts_with(b"HTTP/1.1 200"), "{}", String::from_utf8_lossy(&response));
+    assert!(response.ends_with(PLACEHOLDER));
+
+    // One that's never whole is for the client to come back for, and isn't
+    // to be kept
+    let response = String::from_utf8_lossy(&request(port, "/tweet_video/never.gif")).to_ascii_lowercase();
+    assert!(response.starts_with("http/1.1 503"), "{}", response);
+    assert!(response.contains("retry-after: 2\r\n"), "{}", response);
+    assert!(response.contains("cache-control: no-store\r\n"), "{}", response);
+    assert!(response.contains("\"error\":\"source_incomplete\""), "{}", response);
+
+    let (_, metrics) = get(port, "/metrics");
+    for (outcome, count) in [("retried", 2), ("recovered", 1), ("gave_up", 1)] {
+        let series = format!("fastgif_moov_not_found_total{{outcome=\"{}\"}} {}", outcome, count);
+        assert!(metrics.contains(&series), "{}: {}", series, metrics);
+    }
+    let _ = std::fs::remove_dir_all(bin);
+}
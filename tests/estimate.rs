@@ -0,0 +1,128 @@
+//! Asks `GET /tweet_video/{path}/estimate` what conversions would come to,
+//! and checks it's worked out from what ffprobe says, without converting
+//! anything, and fitted to the conversions that have been made.
+#![cfg(unix)]
+
+use axum::body::to_bytes;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use support::router::{self, get, tool, VIDEO};
+
+mod support;
+
+const PROBED: &str = r#"{"streams": [{"width": 1280, "height": 720, "avg_frame_rate": "30000/1001"}], "format": {"duration": "8.000000", "bit_rate": "2000000"}}"#;
+
+// As some videos come
+const ODD: &str = r#"{"streams": [{"width": 479, "height": 361, "avg_frame_rate": "25/1"}], "format": {"duration": "2.000000"}}"#;
+
+// Portrait, as phones record it: stored landscape and turned on the way to
+// being shown, by a display matrix or by an older muxer's tag
+const ROTATED: &str = r#"{"streams": [{"width": 1280, "height": 720, "avg_frame_rate": "30/1", "side_data_list": [{"side_data_type": "Display Matrix", "rotation": -90}]}], "format": {"duration": "2.000000"}}"#;
+const TAGGED: &str = r#"{"streams": [{"width": 1280, "height": 720, "avg_frame_rate": "30/1", "tags": {"rotate": "270"}}], "format": {"duration": "2.000000"}}"#;
+
+// A directory of the test's own, with `abc.mp4`, an ffmpeg in it that leaves
+// a line in `runs` for each conversion and passes on what it's fed, and an
+// ffprobe that leaves a line in `probes` for each estimate's probe and
+// answers with `probed`
+fn setup(test: &str, probed: &str) -> PathBuf {
+    let dir = router::setup(test, "case \"$*\" in *pipe:0*) echo >> \"$(dirname \"$0\")/runs\";; esac\nexec cat");
+    tool(&dir, "ffprobe", &format!("case \"$*\" in *json*) echo >> \"$(dirname \"$0\")/probes\";; esac\necho '{}'", probed));
+    dir
+}
+
+async fn estimate(app: &Router, uri: &str) -> Value {
+    let response = get(app, uri).await;
+    assert_eq!(response.status(), 200);
+    serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+}
+
+fn lines(dir: &Path, name: &str) -> usize {
+    std::fs::read_to_string(dir.join(name)).unwrap_or_default().lines().count()
+}
+
+#[tokio::test]
+async fn estimates_are_worked_out_from_the_probe() {
+    let dir = setup("probed", PROBED);
+    let app = router::app(router::config(&dir)).await;
+    let guessed = estimate(&app, "/tweet_video/abc.gif/estimate?width=480&fps=10").await;
+    assert_eq!((guessed["width"].as_u64(), guessed["height"].as_u64()), (Some(480), Some(270)));
+    assert_eq!(guessed["estimated_frames"], 80);
+    assert!(guessed["estimated_bytes"].as_u64().unwrap() > 0);
+    assert!(guessed["estimated_seconds"].as_f64().is_some());
+    assert_eq!(guessed["based_on_conversions"], 0);
+    assert_eq!(guessed["trimmed"], false);
+    assert_eq!(guessed["source"]["width"], 1280);
+    assert_eq!(guessed["source"]["bit_rate"], 2_000_000);
+    assert!((guessed["source"]["fps"].as_f64().unwrap() - 29.97).abs() < 0.01);
+
+    // Nothing was converted, and the probe is remembered
+    let full = estimate(&app, "/tweet_video/abc.gif/estimate").await;
+    assert_eq!((full["width"].as_u64(), full["height"].as_u64(), full["estimated_frames"].as_u64()), (Some(1280), Some(720), Some(160)));
+    assert_eq!(lines(&dir, "probes"), 1);
+    assert_eq!(lines(&dir, "runs"), 0);
+
+    assert_eq!(get(&app, "/tweet_video/abc.gif/estimate?format=avif").await.status(), 400);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn estimates_are_fitted_to_conversions() {
+    let dir = setup("fitted", PROBED);
+    let app = router::app(router::config(&dir)).await;
+    let guessed = estimate(&app, "/tweet_video/abc.gif/estimate?width=480&fps=10").await;
+    assert_ne!(guessed["estimated_bytes"], VIDEO.len());
+
+    let response = get(&app, "/tweet_video/abc.gif?width=480&fps=10").await;
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    let fitted = estimate(&app, "/tweet_video/abc.gif/estimate?width=480&fps=10").await;
+    assert_eq!(fitted["based_on_conversions"], 1);
+    assert_eq!(fitted["estimated_bytes"], VIDEO.len());
+    // and so is anything else at the same quality, in proportion
+    let bigger = estimate(&app, "/tweet_video/abc.gif/estimate?width=960&fps=10").await;
+    assert_eq!(bigger["estimated_bytes"], VIDEO.len() * 4);
+
+    let stats = estimate(&app, "/stats").await;
+    let fit = &stats["size_model"][0];
+    assert_eq!((fit["format"].as_str(), fit["quality"].as_u64(), fit["samples"].as_u64()), (Some("gif"), Some(90), Some(1)));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn long_videos_are_estimated_as_theyd_be_converted() {
+    let dir = setup("long", PROBED);
+    let trimmed = router::app(Config { max_input_duration: 5.0, auto_trim: true, ..router::config(&dir) }).await;
+    let estimate = estimate(&trimmed, "/tweet_video/abc.gif/estimate?fps=10").await;
+    assert_eq!((estimate["estimated_frames"].as_u64(), estimate["trimmed"].as_bool()), (Some(50), Some(true)));
+
+    let refused = router::app(Config { max_input_duration: 5.0, auto_trim: false, ..router::config(&dir) }).await;
+    assert_eq!(get(&refused, "/tweet_video/abc.gif/estimate").await.status(), 422);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn odd_sizes_are_estimated_evened_out() {
+    let dir = setup("odd", ODD);
+    let app = router::app(router::config(&dir)).await;
+    let full = estimate(&app, "/tweet_video/abc.gif/estimate").await;
+    assert_eq!((full["width"].as_u64(), full["height"].as_u64()), (Some(478), Some(360)));
+    assert_eq!(full["source"]["width"], 479);
+    let narrower = estimate(&app, "/tweet_video/abc.gif/estimate?width=301").await;
+    assert_eq!((narrower["width"].as_u64(), narrower["height"].as_u64()), (Some(300), Some(226)));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn rotated_videos_are_estimated_as_theyre_shown() {
+    for (test, probed, rotation) in [("rotated", ROTATED, 90), ("tagged", TAGGED, 270)] {
+        let dir = setup(test, probed);
+        let app = router::app(router::config(&dir)).await;
+        let full = estimate(&app, "/tweet_video/abc.gif/estimate").await;
+        assert_eq!((full["width"].as_u64(), full["height"].as_u64()), (Some(720), Some(1280)), "{}", test);
+        assert_eq!((full["source"]["width"].as_u64(), full["source"]["rotation"].as_u64()), (Some(720), Some(rotation)));
+        let narrower = estimate(&app, "/tweet_video/abc.gif/estimate?width=360").await;
+        assert_eq!((narrower["width"].as_u64(), narrower["height"].as_u64()), (Some(360), Some(640)), "{}", test);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
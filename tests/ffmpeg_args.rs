@@ -0,0 +1,61 @@
+//! Converts a video with an ffmpeg that writes down its command line, and
+//! pins the whole of it, so that any change to what ffmpeg is told to do is
+//! a deliberate one.
+#![cfg(unix)]
+
+use axum::body::to_bytes;
+use fastgif::config::Config;
+use fastgif_core::Encoder;
+use std::path::{Path, PathBuf};
+use support::router::{self, get, tool};
+
+mod support;
+
+// One pixel of GIF, for gifski to pass on as what it made
+const GIF: &str = r"printf 'GIF89a\001\000\001\000\000\000\000,\000\000\000\000\001\000\001\000\000\002\002D\001\000;'";
+
+// A directory of the test's own, with `abc.mp4`, an ffmpeg in it that writes
+// its command line to `said`, and a gifski that makes a GIF of whatever it's fed
+fn setup(test: &str) -> PathBuf {
+    let dir = router::setup(test, "printf '%s\\n' \"$*\" > \"$(dirname \"$0\")/said\"\nexec cat");
+    tool(&dir, "gifski", &format!("cat > /dev/null\n{}", GIF));
+    dir
+}
+
+// What ffmpeg in `dir` was told to do, converting with `config`
+async fn ffmpeg_args(dir: &Path, config: Config) -> String {
+    // More conversions than there are cores, so the default thread count is
+    // 1 however many this machine has, and the gifski beside ffmpeg as the
+    // encoder
+    let config = Config {
+        max_concurrent_conversions: Some(1024),
+        encoder: Some(Encoder::Subprocess),
+        ..config
+    };
+    let app = router::app(config).await;
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.status(), 200);
+    to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let args = std::fs::read_to_string(dir.join("said")).unwrap();
+    let _ = std::fs::remove_dir_all(dir);
+    args.trim_end().to_string()
+}
+
+#[tokio::test]
+async fn the_default_command_line() {
+    let dir = setup("default");
+    assert_eq!(
+        ffmpeg_args(&dir, router::config(&dir)).await,
+        "-threads 1 -i pipe:0 -vf scale=trunc(iw/2)*2:trunc(ih/2)*2,fps=20 -f yuv4mpegpipe -"
+    );
+}
+
+#[tokio::test]
+async fn the_command_line_with_threads_set() {
+    let dir = setup("threads");
+    let config = Config { ffmpeg_threads: Some(3), ffmpeg_filter_threads: Some(2), ..router::config(&dir) };
+    assert_eq!(
+        ffmpeg_args(&dir, config).await,
+        "-threads 3 -filter_threads 2 -i pipe:0 -vf scale=trunc(iw/2)*2:trunc(ih/2)*2,fps=20 -f yuv4mpegpipe -"
+    );
+}
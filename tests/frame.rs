@@ -0,0 +1,128 @@
+//! Asks `GET /tweet_video/{path}/frame` for single frames, and checks ffmpeg
+//! seeks before it reads, that a moment past the end is the last frame, that
+//! a frame is only made once, and that the video is held to
+//! `MAX_INPUT_BYTES`. With ffmpeg installed, it checks one it made is as wide
+//! as asked for.
+#![cfg(unix)]
+
+use axum::body::to_bytes;
+use fastgif::config::Config;
+use std::path::{Path, PathBuf};
+use support::router::{self, get, header};
+use support::stills::{self, runs};
+use support::is_installed;
+
+mod support;
+
+const PROBED: &str = r#"{"streams": [{"width": 1280, "height": 720, "avg_frame_rate": "25/1"}], "format": {"duration": "8.000000"}}"#;
+
+// What the fake ffmpeg makes, passed on as the frame
+const IMAGE: &[u8] = b"\xff\xd8\xffnot really";
+
+// A directory of the test's own, with the image and probe above faked
+fn setup(test: &str) -> PathBuf {
+    stills::setup(test, "-ss", IMAGE, PROBED)
+}
+
+#[tokio::test]
+async fn the_frame_is_sought_to_before_reading() {
+    let dir = setup("seek");
+    let app = router::app(router::config(&dir)).await;
+
+    let response = get(&app, "/tweet_video/abc.mp4/frame?t=3.2&fmt=png&width=640").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(header(&response, "content-type"), "image/png");
+    assert_eq!(header(&response, "x-cache"), "MISS");
+    assert_eq!(header(&response, "x-fastgif-frame-time"), "3.200");
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), IMAGE);
+    let runs = runs(&dir);
+    assert_eq!(runs.len(), 1);
+    // from a copy fetched into a temp file, not the source itself
+    assert!(runs[0].contains("-ss 3.200 -i "), "{}", runs[0]);
+    assert!(!runs[0].contains("abc.mp4"), "{}", runs[0]);
+    assert!(runs[0].contains("-vf scale='trunc(min(iw,640)/2)*2':-2 -frames:v 1 -c:v png"), "{}", runs[0]);
+
+    // and once it's made, it's kept
+    let response = get(&app, "/tweet_video/abc.mp4/frame?t=3.2&format=png&width=640").await;
+    assert_eq!(header(&response, "x-cache"), "HIT");
+    assert_eq!(self::runs(&dir).len(), 1);
+
+    // but at another moment, or another size, it's another frame
+    get(&app, "/tweet_video/abc.mp4/frame?t=3.3&fmt=png&width=640").await;
+    get(&app, "/tweet_video/abc.mp4/frame?t=3.2&fmt=png").await;
+    assert_eq!(self::runs(&dir).len(), 3);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn past_the_end_is_the_last_frame() {
+    let dir = setup("clamped");
+    let app = router::app(router::config(&dir)).await;
+    let response = get(&app, "/tweet_video/abc.mp4/frame?t=100").await;
+    assert_eq!(response.status(), 200);
+    // A JPEG unless asked for otherwise, one frame (at 25 a second) before the end
+    assert_eq!(header(&response, "content-type"), "image/jpeg");
+    assert_eq!(header(&response, "x-fastgif-frame-time"), "7.960");
+    assert!(runs(&dir)[0].contains("-ss 7.960 "), "{}", runs(&dir)[0]);
+
+    // and with no `t`, the first
+    let response = get(&app, "/tweet_video/abc.mp4/frame").await;
+    assert_eq!(header(&response, "x-fastgif-frame-time"), "0.000");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn only_the_formats_the_server_makes_are_made() {
+    let dir = setup("formats");
+    // This ffmpeg has no libwebp, so the server makes no WebP
+    let app = router::app(router::config(&dir)).await;
+    for query in ["fmt=webp", "fmt=gif"] {
+        let response = get(&app, &format!("/tweet_video/abc.mp4/frame?{}", query)).await;
+        assert_eq!(response.status(), 400, "{}", query);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("unsupported_format"), "{}", query);
+    }
+    assert_eq!(get(&app, "/tweet_video/abc.mp4/frame?t=soon").await.status(), 400);
+    assert!(runs(&dir).is_empty());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn a_source_over_max_input_bytes_is_refused() {
+    let dir = setup("too_large");
+    let config = Config { max_input_bytes: Some(4), ..router::config(&dir) };
+    let response = get(&router::app(config).await, "/tweet_video/abc.mp4/frame").await;
+    assert_eq!(response.status(), 413);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("input_too_large"));
+    assert!(runs(&dir).is_empty());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// A PNG's width and height, from its IHDR chunk, which comes first
+fn dimensions(png: &[u8]) -> (u32, u32) {
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    (u32::from_be_bytes(png[16..20].try_into().unwrap()), u32::from_be_bytes(png[20..24].try_into().unwrap()))
+}
+
+#[tokio::test]
+async fn a_real_frame_is_as_wide_as_asked() {
+    if !is_installed("ffmpeg") || !is_installed("ffprobe") {
+        eprintln!("ffmpeg or ffprobe isn't installed, skipping");
+        return;
+    }
+    let dir = router::tools_dir("fixture");
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/clip.y4m");
+    std::fs::copy(fixture, dir.join("clip.mp4")).unwrap();
+    // with the real ffmpeg and ffprobe
+    let config = Config { ffmpeg_path: None, ffprobe_path: None, ..router::config(&dir) };
+    let app = router::app(config).await;
+
+    for (query, size) in [("t=0.5&fmt=png&width=16", (16, 16)), ("t=100&fmt=png", (32, 32))] {
+        let response = get(&app, &format!("/tweet_video/clip.mp4/frame?{}", query)).await;
+        assert_eq!(response.status(), 200, "{}", query);
+        let png = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(dimensions(&png), size, "{}", query);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
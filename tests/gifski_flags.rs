@@ -0,0 +1,124 @@
+//! Runs conversions through stand-in gifskis that print the `--help` of three
+//! gifski releases, and checks each is only passed flags it lists.
+#![cfg(unix)]
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use support::server::{spawn_server, Server};
+
+mod support;
+
+// Send a GET and return the status code and the whole response
+fn get(port: u16, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let response = String::from_utf8_lossy(&response).into_owned();
+    let status = response[9..12].parse().unwrap();
+    (status, response)
+}
+
+// A directory with an ffmpeg that writes a few bytes of "video", and a gifski
+// that prints the captured `--help` of `version`, writes the arguments it was
+// run with to `args` and answers with a 2x2 GIF
+fn fake_binaries(version: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fastgif-gifski-flags-{}-{}", version, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let help = Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("tests/fixtures/gifski-help/{}.txt", version));
+    let scripts = [
+        ("ffmpeg", "#!/bin/sh\nprintf 'YUV4MPEG2 W2 H2 F10:1\\n'\n".to_string()),
+        (
+            "gifski",
+            format!(
+                "#!/bin/sh\n\
+                 case \"$1\" in\n\
+                 --help) cat \"{help}\"; exit 0 ;;\n\
+                 --version) echo 'gifski {version}'; exit 0 ;;\n\
+                 esac\n\
+                 echo \"$@\" > \"{dir}/args\"\n\
+                 cat > /dev/null\n\
+                 printf 'GIF89a\\002\\000\\002\\000\\000\\000\\000,\\000\\000\\000\\000\\002\\000\\002\\000\\000\\002\\002D\\001\\000;'\n",
+                help = help.display(),
+                version = version,
+                dir = dir.display(),
+            ),
+        ),
+    ];
+    for (name, script) in scripts {
+        let path = dir.join(name);
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    dir
+}
+
+fn start(bin: &Path, strict: bool) -> (Server, u16) {
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default());
+    spawn_server(&[("PATH", &path), ("ENCODER", "subprocess"), ("STRICT_PARAMS", &strict.to_string())])
+}
+
+// Convert with `query` and return the response and the arguments gifski got
+fn convert(port: u16, bin: &Path, query: &str) -> (String, String) {
+    let _ = std::fs::remove_file(bin.join("args"));
+    let (status, response) = get(port, &format!("/tweet_video/x.gif?{}", query));
+    assert_eq!(status, 200, "{}", response);
+    let args = std::fs::read_to_string(bin.join("args")).unwrap_or_default();
+    (response, args)
+}
+
+fn ignored(response: &str) -> Option<&str> {
+    response
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| line.strip_prefix("x-fastgif-ignored: "))
+        .map(str::trim)
+}
+
+#[test]
+fn gifski_without_repeat() {
+    let bin = fake_binaries("0.10.1");
+    let (server, port) = start(&bin, false);
+
+    // Looping once is all this gifski can say
+    let (response, args) = convert(port, &bin, "repeat=0");
+    assert!(args.contains("--once"), "{}", args);
+    assert!(!args.contains("--repeat"), "{}", args);
+    assert_eq!(ignored(&response), None);
+
+    let (response, args) = convert(port, &bin, "repeat=2");
+    assert!(!args.contains("--repeat") && !args.contains("--once"), "{}", args);
+    assert_eq!(ignored(&response), Some("repeat"));
+
+    // Asked for nothing, it's passed nothing
+    let (response, args) = convert(port, &bin, "");
+    assert!(!args.contains("--once"), "{}", args);
+    assert_eq!(ignored(&response), None);
+    drop(server);
+
+    let (_server, port) = start(&bin, true);
+    let (status, response) = get(port, "/tweet_video/x.gif?repeat=2");
+    assert_eq!(status, 400, "{}", response);
+    assert!(response.contains("unsupported_param"), "{}", response);
+    let _ = std::fs::remove_dir_all(bin);
+}
+
+#[test]
+fn gifski_with_repeat() {
+    for version in ["1.7.0", "1.32.0"] {
+        let bin = fake_binaries(version);
+        let (_server, port) = start(&bin, true);
+
+        let (response, args) = convert(port, &bin, "repeat=2");
+        assert!(args.contains("--repeat 2"), "{}: {}", version, args);
+        assert_eq!(ignored(&response), None, "{}", version);
+
+        // gifski's own count is of repeats, where -1 is none
+        let (_, args) = convert(port, &bin, "repeat=0");
+        assert!(args.contains("--repeat -1"), "{}: {}", version, args);
+        assert!(!args.contains("--once"), "{}: {}", version, args);
+        let _ = std::fs::remove_dir_all(bin);
+    }
+}
@@ -0,0 +1,401 @@
+//! Converts the fixtures with particular settings and checks the structure of
+//! each GIF against `fixtures/golden.toml`: its size on screen, how many
+//! frames and loops it has, how big its colour tables are, how long it plays
+//! for, whether it's transparent and roughly how many bytes it comes to. Each
+//! encoder has its own expectations, and both are checked unless `ENCODER`
+//! (`subprocess` or `ffmpeg`) picks one. It also checks a WebM made from a
+//! video with sound is one, with no sound in it.
+//!
+//! These need real ffmpeg and ffprobe binaries, gifski for `subprocess` and an
+//! ffmpeg with a WebM encoder, so they're ignored by a plain `cargo test`.
+//! `cargo test --test golden -- --ignored` runs them, and fails on whatever
+//! isn't installed rather than passing without checking anything.
+//!
+//! After a deliberate change to what conversions produce, run
+//! `FASTGIF_BLESS=1 cargo test --test golden -- --ignored` to write what they
+//! produce now into `golden.toml`, and review the diff.
+
+use fastgif_core::backend::Format;
+use fastgif_core::gif::{self, Summary};
+use fastgif_core::integrity;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use support::server::{free_port, server_command, start};
+
+mod support;
+
+const CLIP: &[u8] = include_bytes!("fixtures/clip.y4m");
+
+/// Frames a conversion can have more or fewer of than expected, since
+/// trimming and frame rate conversion round differently across ffmpeg builds.
+const FRAME_TOLERANCE: usize = 1;
+
+/// How far a blessed size is widened either way, as a fraction of it.
+const BYTES_BAND: f64 = 0.25;
+
+/// How far a GIF can play for longer or shorter than expected: a frame at
+/// 5 fps, in milliseconds.
+const DURATION_TOLERANCE_MS: u64 = 200;
+
+/// The encoders there are expectations for, each a table of `golden.toml`.
+const ENCODERS: &[&str] = &["subprocess", "ffmpeg"];
+
+// A case's name in golden.toml, the video, the query string and the server's
+// environment
+type Case = (&'static str, &'static str, &'static str, &'static [(&'static str, &'static str)]);
+
+const CASES: &[Case] = &[
+    ("default", "clip", "", &[]),
+    ("repeat", "clip", "?repeat=3", &[]),
+    ("trim", "clip", "", &[("MAX_INPUT_DURATION", "1"), ("AUTO_TRIM", "true")]),
+    // Everything gets the medium tier, which is at most 480 px wide
+    ("width", "wide", "", &[("DEGRADE_AT", "0")]),
+    // Evened out to 478x360
+    ("odd", "odd", "", &[]),
+    // Plays for as long as the video does, however unevenly its frames come
+    ("vfr", "vfr", "", &[]),
+    // Shown upright, 48x64, the way a phone would show it
+    ("rotated", "rotated", "", &[]),
+    // Its transparency kept, unless there's a background to flatten it onto
+    ("transparent", "transparent", "", &[]),
+    ("background", "transparent", "?bg=ffffff", &[]),
+];
+
+// 5 fps, 2 s, made here rather than committed since they're hundreds of KB:
+// 640x32, and 479x361, as some videos come
+fn wide() -> Vec<u8> {
+    y4m(640, 32)
+}
+
+fn odd() -> Vec<u8> {
+    y4m(479, 361)
+}
+
+// 4 s of 64x48 at a variable frame rate, as screen recordings come: 3 frames
+// in quick succession out of every 10 at 30 fps, and then nothing. Made with
+// ffmpeg, since there's no writing a container with timestamps by hand.
+fn vfr() -> Vec<u8> {
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-f", "lavfi", "-i", "testsrc=duration=4:size=64x48:rate=30"])
+        .args(["-vf", "select='lt(mod(n,10),3)'", "-vsync", "vfr", "-c:v", "mpeg4", "-f", "matroska", "-"])
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "ffmpeg couldn't make the VFR fixture");
+    output.stdout
+}
+
+// 2 s of 64x48 at 5 fps in an MP4 that says to turn it a quarter clockwise,
+// as phones record portrait videos. It's made upright and then copied with
+// the rotation added, which newer ffmpegs only take as `-display_rotation`
+// (in degrees anticlockwise) and older ones only as a tag.
+fn rotated() -> Vec<u8> {
+    let path = |name: &str| std::env::temp_dir().join(format!("fastgif-golden-{}-{}.mp4", name, std::process::id()));
+    let (upright, rotated) = (path("upright"), path("rotated"));
+    let ffmpeg = |args: &[&str]| {
+        Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-y"])
+            .args(args)
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    };
+    let (upright_path, rotated_path) = (upright.to_str().unwrap(), rotated.to_str().unwrap());
+    let made = ffmpeg(&["-f", "lavfi", "-i", "testsrc=duration=2:size=64x48:rate=5", "-c:v", "mpeg4", upright_path])
+        && (ffmpeg(&["-display_rotation", "-90", "-i", upright_path, "-c", "copy", rotated_path])
+            || ffmpeg(&["-i", upright_path, "-c", "copy", "-metadata:s:v:0", "rotate=90", rotated_path]));
+    assert!(made, "ffmpeg couldn't make the rotated fixture");
+    let video = std::fs::read(&rotated).unwrap();
+    let _ = std::fs::remove_file(&upright);
+    let _ = std::fs::remove_file(&rotated);
+    video
+}
+
+// 2 s of 64x48 at 5 fps, transparent but for a box in the middle, as sticker
+// videos come. In FFV1, which every ffmpeg can write with an alpha channel.
+// The alpha is zeroed by hand, since older ffmpegs' `color` source has none
+// to give `black@0`, and converting it to yuva420p makes it opaque.
+fn transparent() -> Vec<u8> {
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-f", "lavfi", "-i", "color=c=black:size=64x48:rate=5:duration=2"])
+        .args(["-vf", "format=rgba,colorchannelmixer=aa=0,format=yuva420p,drawbox=x=16:y=12:w=32:h=24:c=red@1:replace=1:t=fill"])
+        .args(["-c:v", "ffv1", "-pix_fmt", "yuva420p", "-f", "matroska", "-"])
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "ffmpeg couldn't make the transparent fixture");
+    output.stdout
+}
+
+// 2 s of 64x48 at 5 fps with a tone over it, as most videos come. In MPEG-4
+// and PCM, which every ffmpeg can write.
+fn voiced() -> Vec<u8> {
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-f", "lavfi", "-i", "testsrc=duration=2:size=64x48:rate=5"])
+        .args(["-f", "lavfi", "-i", "sine=duration=2"])
+        .args(["-c:v", "mpeg4", "-c:a", "pcm_s16le", "-f", "matroska", "-"])
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "ffmpeg couldn't make the voiced fixture");
+    output.stdout
+}
+
+// Ten frames of a gradient moving along
+fn y4m(width: usize, height: usize) -> Vec<u8> {
+    // Odd dimensions round the chroma planes up
+    let chroma = width.div_ceil(2) * height.div_ceil(2);
+    let mut video = format!("YUV4MPEG2 W{} H{} F5:1 Ip A1:1 C420jpeg\n", width, height).into_bytes();
+    for frame in 0..10 {
+        video.extend_from_slice(b"FRAME\n");
+        video.extend((0..width * height).map(|i| ((i % width + frame * 20) % 220 + 16) as u8));
+        video.extend(std::iter::repeat_n((frame * 25 % 256) as u8, chroma));
+        video.extend(std::iter::repeat_n(128, chroma));
+    }
+    video
+}
+
+// Answers `/tweet_video/<name>.mp4` with that fixture, honouring
+// `Range: bytes=N-` so ffprobe can seek in it, and anything else with a 404
+fn serve_fixtures() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (wide, odd, vfr, rotated, transparent, voiced) = (wide(), odd(), vfr(), rotated(), transparent(), voiced());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let mut request = [0u8; 4096];
+            let read = stream.read(&mut request).unwrap_or(0);
+            let request = String::from_utf8_lossy(&request[..read]).into_owned();
+            let path = request.split(' ').nth(1).unwrap_or_default();
+            let video: &[u8] = match path {
+                "/tweet_video/clip.mp4" => CLIP,
+                "/tweet_video/wide.mp4" => &wide,
+                "/tweet_video/odd.mp4" => &odd,
+                "/tweet_video/vfr.mp4" => &vfr,
+                "/tweet_video/rotated.mp4" => &rotated,
+                "/tweet_video/transparent.mp4" => &transparent,
+                "/tweet_video/voiced.mp4" => &voiced,
+                _ => {
+                    let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                    continue;
+                }
+            };
+            let from = request
+                .to_lowercase()
+                .lines()
+                .find_map(|line| line.strip_prefix("range: bytes=")?.split('-').next()?.trim().parse::<usize>().ok())
+                .filter(|from| *from < video.len());
+            let head = match from {
+                Some(from) => format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    from,
+                    video.len() - 1,
+                    video.len(),
+                    video.len() - from
+                ),
+                None => format!(
+                    "HTTP/1.1 200 OK\r\nAccept-Ranges: bytes\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    video.len()
+                ),
+            };
+            let _ = stream.write_all(head.as_bytes());
+            if !request.starts_with("HEAD ") {
+                let _ = stream.write_all(&video[from.unwrap_or(0)..]);
+            }
+        }
+    });
+    port
+}
+
+fn is_installed(name: &str, version_flag: &str, expected: &str) -> bool {
+    Command::new(name)
+        .arg(version_flag)
+        .stderr(Stdio::null())
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains(expected))
+}
+
+// These only run when asked for, so a missing tool is a failure rather than
+// something to skip
+fn require(name: &str, version_flag: &str, expected: &str) {
+    assert!(is_installed(name, version_flag, expected), "`{} {}` doesn't say {:?}; is {} installed?", name, version_flag, expected, name);
+}
+
+// Convert `video` with `encoder` and a server started with `env`, returning
+// the GIF
+fn convert(upstream: u16, encoder: &str, video: &str, query: &str, env: &[(&str, &str)]) -> Vec<u8> {
+    let port = free_port();
+    let base_url = format!("http://127.0.0.1:{}", upstream);
+    let mut command = server_command(port, &[("VIDEO_BASE_URL", base_url.as_str()), ("ENCODER", encoder), ("GIF_FPS", "5")]);
+    // With the real tools, so checked for, what they say shown, and the
+    // videos probed as they would be. The case's own environment goes on
+    // after, so a MAX_INPUT_DURATION it sets is kept.
+    command.env("SKIP_BINARY_CHECK", "false").env_remove("MAX_INPUT_DURATION").envs(env.iter().copied()).stderr(Stdio::inherit());
+    let _server = start(command, port);
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET /tweet_video/{}.gif{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", video, query).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&response[..split]);
+    let body = response[split + 4..].to_vec();
+    assert!(head.starts_with("HTTP/1.1 200"), "unexpected response: {}\n\n{}", head, String::from_utf8_lossy(&body));
+    body
+}
+
+/// What `golden.toml` says a case comes out as with one encoder. Sizes are a
+/// band, since gifski and ffmpeg releases compress a little differently. The colour tables and size
+/// depend on the encoder more than on the settings, so they're only checked
+/// once they've been blessed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Golden {
+    width: u16,
+    height: u16,
+    frames: usize,
+    loop_count: Option<u16>,
+    palette: Option<usize>,
+    bytes: Option<[usize; 2]>,
+    duration_ms: Option<u64>,
+    transparent: Option<bool>,
+}
+
+// What the GIF comes to, once it's passed the same check the server makes of
+// what it sends
+fn parse(gif: &[u8]) -> Summary {
+    if let Err(e) = integrity::check(gif, Format::Gif) {
+        panic!("body isn't a GIF the server would send: {}", e);
+    }
+    gif::summary(gif).unwrap()
+}
+
+// Whether any pixel of the GIF's first frame is see-through, as ffmpeg
+// decodes it. The GIF having a transparent colour isn't enough, since
+// ffmpeg's own encoder sets one aside whether or not anything uses it.
+fn see_through(gif: &[u8]) -> bool {
+    let path = std::env::temp_dir().join(format!("fastgif-golden-{}.gif", std::process::id()));
+    std::fs::write(&path, gif).unwrap();
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(&path)
+        .args(["-frames:v", "1", "-pix_fmt", "rgba", "-f", "rawvideo", "-"])
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert!(output.status.success(), "ffmpeg couldn't decode the GIF's first frame");
+    output.stdout.chunks_exact(4).any(|pixel| pixel[3] < 255)
+}
+
+fn check(case: &str, got: &Summary, bytes: usize, transparent: bool, golden: &Golden) -> Vec<String> {
+    let mut problems = Vec::new();
+    if (got.width, got.height) != (golden.width, golden.height) {
+        problems.push(format!("is {}x{}, not {}x{}", got.width, got.height, golden.width, golden.height));
+    }
+    if got.frames.abs_diff(golden.frames) > FRAME_TOLERANCE {
+        problems.push(format!("has {} frames, not {} (±{})", got.frames, golden.frames, FRAME_TOLERANCE));
+    }
+    if got.loop_count != golden.loop_count {
+        problems.push(format!("loops {:?} times, not {:?}", got.loop_count, golden.loop_count));
+    }
+    if let Some(palette) = golden.palette.filter(|palette| *palette != got.palette) {
+        problems.push(format!("has {} colours at most, not {}", got.palette, palette));
+    }
+    if let Some(duration_ms) = golden.duration_ms.filter(|duration_ms| duration_ms.abs_diff(got.duration_ms) > DURATION_TOLERANCE_MS) {
+        problems.push(format!("plays for {}ms, not {}ms (±{})", got.duration_ms, duration_ms, DURATION_TOLERANCE_MS));
+    }
+    if golden.transparent.is_some_and(|expected| expected != transparent) {
+        problems.push(format!("is {}transparent", if transparent { "" } else { "not " }));
+    }
+    if let Some([min, max]) = golden.bytes.filter(|[min, max]| !(min..=max).contains(&&bytes)) {
+        problems.push(format!("is {} bytes, outside {}-{}", bytes, min, max));
+    }
+    problems.into_iter().map(|problem| format!("{} {}", case, problem)).collect()
+}
+
+#[test]
+#[ignore = "needs ffmpeg, ffprobe and gifski installed"]
+fn conversions_match_golden_structure() {
+    require("ffmpeg", "-version", "ffmpeg version");
+    require("ffprobe", "-version", "ffprobe version");
+    let encoders = match std::env::var("ENCODER") {
+        Ok(encoder) => {
+            assert!(ENCODERS.contains(&encoder.as_str()), "ENCODER is {:?}, not one of {:?}", encoder, ENCODERS);
+            vec![encoder]
+        }
+        Err(_) => ENCODERS.iter().map(|encoder| encoder.to_string()).collect(),
+    };
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/golden.toml");
+    let bless = std::env::var("FASTGIF_BLESS").is_ok_and(|value| !value.is_empty() && value != "0");
+    let expected: BTreeMap<String, BTreeMap<String, Golden>> = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+
+    let upstream = serve_fixtures();
+    let mut blessed = expected.clone();
+    let mut problems = Vec::new();
+    for encoder in &encoders {
+        if encoder == "subprocess" {
+            require("gifski", "--version", "gifski");
+        }
+        let (expected, blessed) = (expected.get(encoder), blessed.entry(encoder.clone()).or_default());
+        for (case, video, query, env) in CASES {
+            let gif = convert(upstream, encoder, video, query, env);
+            let (got, bytes, transparent) = (parse(&gif), gif.len(), see_through(&gif));
+            let label = format!("{}.{}", encoder, case);
+            match expected.and_then(|expected| expected.get(*case)) {
+                Some(golden) if !bless => problems.extend(check(&label, &got, bytes, transparent, golden)),
+                None if !bless => problems.push(format!("{} isn't in golden.toml, run with FASTGIF_BLESS=1", label)),
+                _ => {}
+            }
+            let band = (bytes as f64 * BYTES_BAND) as usize;
+            let golden = Golden {
+                width: got.width,
+                height: got.height,
+                frames: got.frames,
+                loop_count: got.loop_count,
+                palette: Some(got.palette),
+                bytes: Some([bytes - band, bytes + band]),
+                duration_ms: Some(got.duration_ms),
+                transparent: Some(transparent),
+            };
+            blessed.insert(case.to_string(), golden);
+        }
+    }
+    if bless {
+        std::fs::write(&path, toml::to_string(&blessed).unwrap()).unwrap();
+        eprintln!("Wrote {}", path.display());
+    }
+    assert!(problems.is_empty(), "{}", problems.join("\n"));
+}
+
+#[test]
+#[ignore = "needs ffmpeg with a WebM encoder, and ffprobe, installed"]
+fn webm_is_a_silent_video() {
+    require("ffprobe", "-version", "ffprobe version");
+    assert!(
+        ["libvpx-vp9", "libsvtav1"].iter().any(|encoder| is_installed("ffmpeg", "-encoders", encoder)),
+        "ffmpeg isn't installed, or has no WebM encoder"
+    );
+    let upstream = serve_fixtures();
+    let webm = convert(upstream, "subprocess", "voiced", "?format=webm", &[("SKIP_BINARY_CHECK", "true")]);
+    assert!(webm.starts_with(b"\x1a\x45\xdf\xa3"), "body doesn't start with the EBML magic");
+    if let Err(e) = integrity::check(&webm, Format::Webm) {
+        panic!("body isn't a WebM the server would send: {}", e);
+    }
+
+    let path = std::env::temp_dir().join(format!("fastgif-golden-{}.webm", std::process::id()));
+    std::fs::write(&path, &webm).unwrap();
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "stream=codec_type,codec_name", "-of", "csv=p=0"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_file(&path);
+    let streams = String::from_utf8_lossy(&output.stdout).into_owned();
+    let streams: Vec<&str> = streams.lines().collect();
+    assert!(matches!(streams[..], ["vp9,video"] | ["av1,video"]), "streams: {:?}", streams);
+}
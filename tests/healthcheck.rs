@@ -0,0 +1,62 @@
+//! Runs `fastgif healthcheck` against stand-in servers, and checks what it
+//! makes of their answers, or of no answer at all.
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+// Answer one request with this status and JSON body, on a thread
+fn answer_once(status: &'static str, body: &'static str) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request);
+        let _ = write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+    });
+    port
+}
+
+fn healthcheck(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fastgif")).arg("healthcheck").args(args).output().unwrap()
+}
+
+#[test]
+fn healthy_server() {
+    let port = answer_once("200 OK", r#"{"healthy":true,"checks":{"ffmpeg":{"ok":true,"critical":true}}}"#);
+    let output = healthcheck(&["--url", &format!("http://127.0.0.1:{}/healthz", port)]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "healthy: ffmpeg ok\n");
+}
+
+#[test]
+fn unhealthy_server() {
+    let port = answer_once(
+        "503 Service Unavailable",
+        r#"{"healthy":false,"checks":{"ffmpeg":{"ok":false,"critical":true,"error":"not installed"}}}"#,
+    );
+    let output = healthcheck(&["--url", &format!("http://127.0.0.1:{}/healthz", port)]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "unhealthy (503): ffmpeg: not installed\n");
+}
+
+#[test]
+fn server_that_never_answers() {
+    // Connections are accepted by the kernel, but nothing ever reads them
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let started = Instant::now();
+    let output = healthcheck(&["--url", &format!("http://127.0.0.1:{}/healthz", port), "--timeout", "0.5"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("didn't answer within 500ms"));
+    drop(listener);
+}
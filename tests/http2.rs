@@ -0,0 +1,45 @@
+//! Runs the server with and without `H2C`, and checks a client with prior
+//! knowledge of HTTP/2 gets its GIF over it only when it's on, while
+//! HTTP/1.1 clients are served either way.
+#![cfg(unix)]
+
+use std::path::Path;
+use support::router::{setup, READS_INPUT, VIDEO};
+use support::server::{spawn_server, Server};
+
+mod support;
+
+// The server on a port of its own, once it's accepting connections
+fn start(dir: &Path, h2c: bool) -> (Server, u16) {
+    let (ffmpeg, dir) = (dir.join("ffmpeg"), dir.to_str().unwrap());
+    spawn_server(&[
+        ("BIND", "127.0.0.1"),
+        ("FFMPEG_PATH", ffmpeg.to_str().unwrap()),
+        ("SOURCE_DIR", dir),
+        ("TMP_DIR", dir),
+        ("H2C", &h2c.to_string()),
+    ])
+}
+
+async fn get(client: &reqwest::Client, port: u16) -> reqwest::Result<(reqwest::Version, Vec<u8>)> {
+    let response = client.get(format!("http://127.0.0.1:{}/tweet_video/abc.gif", port)).send().await?;
+    let version = response.version();
+    Ok((version, response.bytes().await?.to_vec()))
+}
+
+#[tokio::test]
+async fn h2c_is_spoken_only_when_its_on() {
+    let dir = setup("h2c", READS_INPUT);
+    let http1 = reqwest::Client::builder().http1_only().build().unwrap();
+    let http2 = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+
+    let (server, port) = start(&dir, true);
+    assert_eq!(get(&http2, port).await.unwrap(), (reqwest::Version::HTTP_2, VIDEO.to_vec()));
+    assert_eq!(get(&http1, port).await.unwrap(), (reqwest::Version::HTTP_11, VIDEO.to_vec()));
+    drop(server);
+
+    let (_server, port) = start(&dir, false);
+    assert!(get(&http2, port).await.is_err());
+    assert_eq!(get(&http1, port).await.unwrap(), (reqwest::Version::HTTP_11, VIDEO.to_vec()));
+    let _ = std::fs::remove_dir_all(&dir);
+}
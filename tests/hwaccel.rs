@@ -0,0 +1,157 @@
+//! Starts servers with `HWACCEL` set, on an ffmpeg that lists `vaapi` and
+//! `cuda` and decodes its test clip with whichever it's told to, and checks
+//! the one that works is passed to every conversion and said to have been
+//! used. A conversion ffmpeg fails to decode on it is tried again in
+//! software, and one it gives up on partway through counts as software.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use fastgif::Connection;
+use fastgif_core::hwaccel::{Hwaccel, HwaccelMode};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use support::router::{self, header};
+use tower::ServiceExt;
+
+mod support;
+
+// How the fake ffmpeg takes to being asked to decode on the GPU
+#[derive(Clone, Copy)]
+enum Gpu {
+    /// Fine, for its test clip and the conversions
+    Works,
+    /// Decodes the test clip with vaapi, but gives up on cuda
+    NoCuda,
+    /// Decodes the test clip, but fails every conversion
+    FailsConversions,
+    /// Decodes the test clip, but falls back to software in conversions
+    GivesUp,
+}
+
+// A directory of the test's own, with `abc.mp4`, and an ffmpeg in it that
+// lists its hwaccels, makes and decodes its test clip, and passes on what a
+// conversion gives it to read, leaving the conversion's arguments as a line
+// in `runs` and every other invocation in `asked`
+fn setup(test: &str, gpu: Gpu) -> PathBuf {
+    let (decode_test, conversion) = match gpu {
+        Gpu::Works => ("", ""),
+        Gpu::NoCuda => ("case \"$*\" in *'-hwaccel cuda'*) echo 'Failed setup for format cuda: hwaccel initialisation returned error.' >&2;; esac", ""),
+        Gpu::FailsConversions => ("", "case \"$*\" in *'-hwaccel '*) echo 'Error while decoding stream #0:0: Generic error in an external library' >&2; exit 1;; esac"),
+        Gpu::GivesUp => ("", "case \"$*\" in *'-hwaccel '*) echo 'Failed setup for format vaapi: hwaccel initialisation returned error.' >&2;; esac"),
+    };
+    let ffmpeg = format!(
+        "here=\"$(dirname \"$0\")\"\n\
+         case \"$*\" in\n\
+         *-hwaccels*) echo \"$*\" >> \"$here/asked\"; printf 'Hardware acceleration methods:\\nvaapi\\ncuda\\n\\n'; exit 0;;\n\
+         *lavfi*) echo \"$*\" >> \"$here/asked\"; printf 'a clip'; exit 0;;\n\
+         *'-f null'*) echo \"$*\" >> \"$here/asked\"; cat > /dev/null; {}\n exit 0;;\n\
+         *-i*) echo \"$*\" >> \"$here/runs\";;\n\
+         esac\n\
+         {}\n\
+         while [ $# -gt 0 ]; do [ \"$1\" = \"-i\" ] && [ \"$2\" != pipe:0 ] && exec cat \"$2\"; shift; done\n\
+         exec cat",
+        decode_test, conversion
+    );
+    router::setup(test, &ffmpeg)
+}
+
+fn config(dir: &Path, hwaccel: HwaccelMode) -> Config {
+    Config {
+        hwaccel,
+        ..router::config(dir)
+    }
+}
+
+async fn get(app: &Router, uri: &str) -> Response {
+    let mut request = Request::get(uri).body(Body::empty()).unwrap();
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+    request.extensions_mut().insert(ConnectInfo(Connection::from(peer)));
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn text(app: &Router, uri: &str) -> String {
+    let body = to_bytes(get(app, uri).await.into_body(), usize::MAX).await.unwrap();
+    String::from_utf8_lossy(&body).into_owned()
+}
+
+fn lines(dir: &Path, file: &str) -> Vec<String> {
+    std::fs::read_to_string(dir.join(file)).unwrap_or_default().lines().map(str::to_string).collect()
+}
+
+#[tokio::test]
+async fn the_first_that_works_is_used() {
+    let dir = setup("auto", Gpu::Works);
+    let app = router::app(config(&dir, HwaccelMode::Auto)).await;
+
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(header(&response, "x-fastgif-decode"), "nvdec");
+    let runs = lines(&dir, "runs");
+    assert_eq!(runs.len(), 1, "{:?}", runs);
+    assert!(runs[0].contains("-hwaccel cuda -i pipe:0"), "{}", runs[0]);
+
+    let stats = text(&app, "/stats").await;
+    assert!(stats.contains(r#""decode":"nvdec""#), "{}", stats);
+    let metrics = text(&app, "/metrics").await;
+    assert!(metrics.contains(r#"fastgif_decodes_total{path="nvdec"} 1"#), "{}", metrics);
+    assert!(metrics.contains(r#"fastgif_decodes_total{path="software"} 0"#), "{}", metrics);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn one_that_fails_its_test_is_passed_over() {
+    let dir = setup("passed-over", Gpu::NoCuda);
+    let app = router::app(config(&dir, HwaccelMode::Auto)).await;
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(header(&response, "x-fastgif-decode"), "vaapi");
+    assert!(lines(&dir, "runs")[0].contains("-hwaccel vaapi "), "{:?}", lines(&dir, "runs"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn a_failed_decode_is_retried_in_software() {
+    let dir = setup("fallback", Gpu::FailsConversions);
+    let app = router::app(config(&dir, HwaccelMode::Only(Hwaccel::Vaapi))).await;
+
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(header(&response, "x-fastgif-decode"), "software");
+    let runs = lines(&dir, "runs");
+    assert_eq!(runs.len(), 2, "{:?}", runs);
+    assert!(runs[0].contains("-hwaccel vaapi "), "{}", runs[0]);
+    assert!(!runs[1].contains("-hwaccel"), "{}", runs[1]);
+
+    let metrics = text(&app, "/metrics").await;
+    assert!(metrics.contains(r#"fastgif_hwaccel_fallbacks_total{hwaccel="vaapi"} 1"#), "{}", metrics);
+    assert!(metrics.contains(r#"fastgif_decodes_total{path="software"} 1"#), "{}", metrics);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn giving_up_partway_counts_as_software() {
+    let dir = setup("gave-up", Gpu::GivesUp);
+    let app = router::app(config(&dir, HwaccelMode::Only(Hwaccel::Vaapi))).await;
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(header(&response, "x-fastgif-decode"), "software");
+    // ffmpeg carried on by itself, so there was nothing to retry
+    assert_eq!(lines(&dir, "runs").len(), 1);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn nothing_is_tested_with_it_off() {
+    let dir = setup("off", Gpu::Works);
+    let app = router::app(config(&dir, HwaccelMode::Off)).await;
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(header(&response, "x-fastgif-decode"), "software");
+    assert!(!lines(&dir, "runs")[0].contains("-hwaccel"));
+    assert!(lines(&dir, "asked").is_empty(), "{:?}", lines(&dir, "asked"));
+    assert!(text(&app, "/stats").await.contains(r#""decode":"software""#));
+    let _ = std::fs::remove_dir_all(&dir);
+}
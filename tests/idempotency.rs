@@ -0,0 +1,140 @@
+//! Retries `POST /jobs` and `POST /convert` with an `Idempotency-Key`, and
+//! checks they're answered the way the first was, without the work being
+//! done again, even while the first is still going.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use fastgif::Connection;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use support::router::{self, VIDEO};
+use tower::ServiceExt;
+
+mod support;
+
+// A directory of the test's own, with `abc.mp4` and an ffmpeg in it that
+// leaves a line in `runs` for each conversion, waits for as long as there's
+// a `hang` file, then passes on what it was given
+fn setup(test: &str) -> PathBuf {
+    let script = "here=\"$(dirname \"$0\")\"
+case \"$*\" in *pipe:0*|*-i*) echo >> \"$here/runs\"; while [ -e \"$here/hang\" ]; do sleep 0.05; done;; esac
+while [ $# -gt 0 ]; do [ \"$1\" = \"-i\" ] && [ \"$2\" != \"pipe:0\" ] && exec cat \"$2\"; shift; done
+exec cat";
+    let dir = router::setup(test, script);
+    std::fs::create_dir_all(dir.join("tmp")).unwrap();
+    dir
+}
+
+fn config(dir: &Path) -> Config {
+    Config {
+        tmp_dir: Some(dir.join("tmp")),
+        api_keys: vec!["one".to_string(), "two".to_string()],
+        ..router::config(dir)
+    }
+}
+
+fn runs(dir: &Path) -> usize {
+    std::fs::read_to_string(dir.join("runs")).unwrap_or_default().lines().count()
+}
+
+async fn post(app: &Router, path: &str, headers: &[(&str, &str)], body: &'static [u8]) -> Response {
+    let mut request = Request::post(path);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let mut request = request.body(Body::from(body)).unwrap();
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+    request.extensions_mut().insert(ConnectInfo(Connection::from(peer)));
+    app.clone().oneshot(request).await.unwrap()
+}
+
+// Submit `job` with the API key `key` and the `Idempotency-Key`
+// `idempotency_key`, returning the status, whether it was replayed, and the
+// job's ID
+async fn submit(app: &Router, key: &str, idempotency_key: &str, job: &'static str) -> (u16, bool, Option<String>) {
+    let authorization = format!("Bearer {}", key);
+    let headers = [("authorization", authorization.as_str()), ("idempotency-key", idempotency_key), ("content-type", "application/json")];
+    let response = post(app, "/jobs", &headers, job.as_bytes()).await;
+    let status = response.status().as_u16();
+    let replayed = response.headers().get("idempotent-replayed").is_some_and(|value| value == "true");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let id = serde_json::from_slice::<Value>(&body).ok().and_then(|created| created["id"].as_str().map(str::to_string));
+    (status, replayed, id)
+}
+
+#[tokio::test]
+async fn retried_jobs_are_only_made_once() {
+    let dir = setup("jobs");
+    let app = router::app(Config { idempotency_ttl: 1, ..config(&dir) }).await;
+
+    let (status, replayed, first) = submit(&app, "one", "retry-me", r#"{"path": "abc.gif"}"#).await;
+    assert_eq!((status, replayed), (202, false));
+    let (status, replayed, again) = submit(&app, "one", "retry-me", r#"{"path": "abc.gif"}"#).await;
+    assert_eq!((status, replayed), (202, true));
+    assert_eq!(again, first);
+
+    // The same key for something else is a mistake
+    let (status, _, _) = submit(&app, "one", "retry-me", r#"{"path": "abc.gif", "params": {"width": 100}}"#).await;
+    assert_eq!(status, 422);
+    // but it's only the same key for the same API key
+    let (status, replayed, theirs) = submit(&app, "two", "retry-me", r#"{"path": "abc.gif"}"#).await;
+    assert_eq!((status, replayed), (202, false));
+    assert_ne!(theirs, first);
+    let (status, _, _) = submit(&app, "one", &"k".repeat(256), r#"{"path": "abc.gif"}"#).await;
+    assert_eq!(status, 400);
+
+    // and it's forgotten after IDEMPOTENCY_TTL
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let (status, replayed, later) = submit(&app, "one", "retry-me", r#"{"path": "abc.gif", "params": {"width": 100}}"#).await;
+    assert_eq!((status, replayed), (202, false));
+    assert_ne!(later, first);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn uploads_retried_while_converting_wait_for_the_first() {
+    let dir = setup("convert");
+    let app = router::app(config(&dir)).await;
+    let convert = |idempotency_key: &'static str| {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let headers = [("authorization", "Bearer one"), ("idempotency-key", idempotency_key), ("content-type", "video/mp4")];
+            let response = post(&app, "/convert?width=100", &headers, VIDEO).await;
+            let status = response.status().as_u16();
+            let replayed = response.headers().get("idempotent-replayed").is_some();
+            (status, replayed, to_bytes(response.into_body(), usize::MAX).await.unwrap())
+        })
+    };
+
+    std::fs::write(dir.join("hang"), "").unwrap();
+    let first = convert("upload-1");
+    let started = Instant::now();
+    while runs(&dir) == 0 {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let retry = convert("upload-1");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    std::fs::remove_file(dir.join("hang")).unwrap();
+
+    let (status, replayed, body) = first.await.unwrap();
+    assert_eq!((status, replayed, body.as_ref()), (200, false, VIDEO));
+    let (status, replayed, body) = retry.await.unwrap();
+    assert_eq!((status, replayed, body.as_ref()), (200, true, VIDEO));
+    assert_eq!(runs(&dir), 1);
+
+    // Without a key, it's converted again
+    let headers = [("authorization", "Bearer one"), ("content-type", "video/mp4")];
+    assert_eq!(post(&app, "/convert?width=100", &headers, VIDEO).await.status(), 200);
+    assert_eq!(runs(&dir), 2);
+    let headers = [("authorization", "Bearer one"), ("idempotency-key", "upload-1"), ("content-type", "video/mp4")];
+    assert_eq!(post(&app, "/convert?width=200", &headers, VIDEO).await.status(), 422);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,93 @@
+//! Converts with an ffmpeg that makes whatever the test says, and checks
+//! what isn't a whole GIF is refused as an encode error, counted, and never
+//! cached, however the output was kept: in memory, spilled to disk or
+//! streamed.
+#![cfg(unix)]
+
+use axum::body::to_bytes;
+use axum::Router;
+use fastgif::config::Config;
+use std::path::{Path, PathBuf};
+use support::router::{self, get};
+
+mod support;
+
+// A whole GIF, of one pixel
+const PIXEL: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff\
+    !\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+
+// What encoders have been seen to make instead, and what's wrong with it
+const BROKEN: &[(&str, &[u8], &str)] = &[
+    ("empty", b"", "it's empty"),
+    ("zeroes", &[0; 64], "it doesn't start with GIF89a or GIF87a"),
+    ("truncated", PIXEL.split_at(30).0, "it doesn't end with the GIF trailer"),
+    ("no frames", b"GIF89a\x01\x00\x01\x00\x00\x00\x00;", "it has no frames"),
+];
+
+// A directory of the test's own, with `abc.mp4`, and an ffmpeg in it that
+// makes what's in `made`
+fn setup(test: &str) -> PathBuf {
+    let dir = router::setup(test, "cat > /dev/null\nexec cat \"$(dirname \"$0\")/made\"");
+    std::fs::write(dir.join("abc.mp4"), b"not really a video").unwrap();
+    dir
+}
+
+fn config(dir: &Path) -> Config {
+    Config {
+        cache_max_bytes: Some(1024 * 1024),
+        ..router::config(dir)
+    }
+}
+
+async fn invalid_outputs(app: &Router) -> usize {
+    let response = get(app, "/metrics").await;
+    let metrics = String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix("fastgif_invalid_outputs_total{format=\"gif\"} "))
+        .and_then(|count| count.parse().ok())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn broken_output_is_refused_and_never_cached() {
+    // In memory, and spilled to disk
+    for (test, spill_threshold_bytes) in [("memory", None), ("spilled", Some(16))] {
+        let dir = setup(test);
+        let app = router::app(Config { spill_threshold_bytes, ..config(&dir) }).await;
+        for (count, (name, made, reason)) in BROKEN.iter().enumerate() {
+            std::fs::write(dir.join("made"), made).unwrap();
+            let response = get(&app, "/tweet_video/abc.gif").await;
+            assert_eq!(response.status(), 500, "{}: {}", test, name);
+            let body = String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+            assert!(body.contains("\"error\":\"gifski_encode_error\""), "{}: {}", name, body);
+            assert!(body.contains(&format!("ffmpeg made an invalid gif ({})", reason)), "{}: {}: {}", test, name, body);
+            assert_eq!(invalid_outputs(&app).await, count + 1);
+        }
+
+        // What's made once the encoder behaves is a miss, made afresh
+        std::fs::write(dir.join("made"), PIXEL).unwrap();
+        let response = get(&app, "/tweet_video/abc.gif").await;
+        assert_eq!(response.status(), 200, "{}", test);
+        assert_eq!(response.headers()["x-cache"], "MISS", "{}", test);
+        assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), PIXEL);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
+
+#[tokio::test]
+async fn broken_streamed_output_ends_the_body_in_an_error() {
+    let dir = setup("streamed");
+    let app = router::app(Config { stream_response: true, ..config(&dir) }).await;
+    std::fs::write(dir.join("made"), PIXEL.split_at(30).0).unwrap();
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.status(), 200);
+    assert!(to_bytes(response.into_body(), usize::MAX).await.is_err());
+    assert_eq!(invalid_outputs(&app).await, 1);
+
+    std::fs::write(dir.join("made"), PIXEL).unwrap();
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.headers()["x-cache"], "MISS");
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), PIXEL);
+    let _ = std::fs::remove_dir_all(&dir);
+}
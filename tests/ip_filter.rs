@@ -0,0 +1,79 @@
+//! Sends requests as clients in and out of `ALLOW_CIDRS` and `DENY_CIDRS`,
+//! written in both IPv4 and IPv4-mapped IPv6, and checks which get answered,
+//! before and after the lists are changed by a reload.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use support::server::spawn_server;
+
+mod support;
+
+const TOKEN: &str = "let-me-in";
+
+/// Each client, and whether it gets past `ALLOW`/`DENY`.
+const CLIENTS: &[(&str, bool)] = &[
+    ("203.0.113.7", true),
+    ("::ffff:203.0.113.7", true),
+    // Allowed, but denied too
+    ("203.0.113.66", false),
+    ("::ffff:203.0.113.66", false),
+    ("198.51.100.1", false),
+    ("::ffff:198.51.100.1", false),
+    ("2001:db8::17", true),
+    ("2001:db8:1::17", false),
+    // Not there at all
+    ("192.0.2.1", false),
+];
+
+const ALLOW: &str = "[\"203.0.113.0/24\", \"2001:db8::/48\"]";
+const DENY: &str = "[\"::ffff:203.0.113.64/122\"]";
+
+// Send a request forwarded for `client` by our proxy on 127.0.0.1, and
+// return the status code and body
+fn request(port: u16, method: &str, path: &str, client: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: {}\r\nAuthorization: Bearer {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        method, path, client, TOKEN
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default();
+    (status, body)
+}
+
+fn write_config(path: &PathBuf, lists: &str) {
+    std::fs::write(path, format!("trust_proxy = \"127.0.0.0/8\"\n{}", lists)).unwrap();
+}
+
+#[test]
+fn clients_are_filtered_by_network() {
+    let config = std::env::temp_dir().join(format!("fastgif-ip-filter-{}.toml", std::process::id()));
+    write_config(&config, &format!("allow_cidrs = {}\ndeny_cidrs = {}\n", ALLOW, DENY));
+    let (_server, port) = spawn_server(&[("FASTGIF_CONFIG", config.to_str().unwrap()), ("ADMIN_TOKEN", TOKEN)]);
+
+    for (client, allowed) in CLIENTS {
+        let (status, body) = request(port, "GET", "/version", client);
+        if *allowed {
+            assert_eq!(status, 200, "{}: {}", client, body);
+        } else {
+            assert_eq!(status, 403, "{}: {}", client, body);
+            assert_eq!(body, "", "{}", client);
+        }
+    }
+
+    // Without the allow list, only the denied are kept out
+    write_config(&config, &format!("deny_cidrs = {}\n", DENY));
+    let (status, body) = request(port, "POST", "/admin/reload", "203.0.113.7");
+    assert_eq!(status, 200, "{}", body);
+    assert!(body.contains("allow_cidrs"), "{}", body);
+    for (client, expected) in [("192.0.2.1", 200), ("::ffff:192.0.2.1", 200), ("::ffff:203.0.113.66", 403)] {
+        let (status, _) = request(port, "GET", "/version", client);
+        assert_eq!(status, expected, "{}", client);
+    }
+    let _ = std::fs::remove_file(config);
+}
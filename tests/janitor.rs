@@ -0,0 +1,94 @@
+//! Leaves temp files and finished jobs lying around, then has the janitor
+//! sweep them up with `POST /admin/janitor/run`.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use support::router::{self, send, PASSES_ON};
+
+mod support;
+
+// A directory of the test's own, with an ffmpeg in it that passes on what
+// it's fed, `abc.mp4`, and `tmp` for temp files
+fn setup(test: &str) -> PathBuf {
+    let dir = router::setup(test, PASSES_ON);
+    std::fs::create_dir_all(dir.join("tmp")).unwrap();
+    dir
+}
+
+fn config(dir: &Path) -> Config {
+    Config {
+        tmp_dir: Some(dir.join("tmp")),
+        admin_token: Some("admin".to_string()),
+        job_ttl: 1,
+        janitor_interval: 0,
+        ..router::config(dir)
+    }
+}
+
+async fn json(response: Response) -> Value {
+    serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+}
+
+async fn sweep(app: &Router) -> Value {
+    let response = send(app, Request::post("/admin/janitor/run").header("authorization", "Bearer admin").body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 200);
+    json(response).await
+}
+
+// `name` in `tmp`, last written to `age` ago
+fn leftover(dir: &Path, name: &str, age: Duration) -> PathBuf {
+    let path = dir.join("tmp").join(name);
+    std::fs::write(&path, vec![0u8; 1000]).unwrap();
+    std::fs::File::options().write(true).open(&path).unwrap().set_modified(SystemTime::now() - age).unwrap();
+    path
+}
+
+#[tokio::test]
+async fn leftovers_are_swept_up() {
+    let dir = setup("leftovers");
+    let app = router::app(config(&dir)).await;
+    let hour = Duration::from_secs(3600);
+    let crashed = leftover(&dir, "fastgif-1-0.gif", hour * 2);
+    let running = leftover(&dir, "fastgif-1-1.gif", Duration::from_secs(60));
+    let someone_elses = leftover(&dir, "other.gif", hour * 2);
+
+    // A job that's over, and stays that way for longer than JOB_TTL
+    let request = Request::post("/jobs").header("content-type", "application/json").body(Body::from(r#"{"path": "abc.gif"}"#)).unwrap();
+    let id = json(send(&app, request).await).await["id"].as_str().unwrap().to_string();
+    let started = Instant::now();
+    loop {
+        let status = json(send(&app, Request::get(format!("/jobs/{}", id)).body(Body::empty()).unwrap()).await).await;
+        if status["state"] == "done" {
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "{}", status);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    let reclaimed = sweep(&app).await;
+    assert_eq!((reclaimed["temp_files"].as_u64(), reclaimed["temp_bytes"].as_u64(), reclaimed["jobs"].as_u64()), (Some(1), Some(1000), Some(1)), "{}", reclaimed);
+    assert!(!crashed.exists());
+    assert!(running.exists() && someone_elses.exists());
+    let response = send(&app, Request::get(format!("/jobs/{}", id)).body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 404);
+
+    let metrics = String::from_utf8(to_bytes(send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+    assert!(metrics.contains("fastgif_janitor_removed_total{kind=\"temp_file\"} 1"), "{}", metrics);
+    assert!(metrics.contains("fastgif_janitor_removed_total{kind=\"job\"} 1"), "{}", metrics);
+    assert!(metrics.contains("fastgif_janitor_reclaimed_bytes_total 1000"), "{}", metrics);
+
+    // and there's nothing left the second time
+    let reclaimed = sweep(&app).await;
+    assert_eq!((reclaimed["temp_files"].as_u64(), reclaimed["jobs"].as_u64()), (Some(0), Some(0)), "{}", reclaimed);
+    let response = send(&app, Request::post("/admin/janitor/run").body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 401);
+    let _ = std::fs::remove_dir_all(&dir);
+}
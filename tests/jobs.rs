@@ -0,0 +1,538 @@
+//! Submits jobs to `POST /jobs` through the router, converting videos from a
+//! `SOURCE_DIR` with an ffmpeg that passes on what it's fed, and follows
+//! them from queued to done, failed or cancelled, then to their GIFs, across
+//! restarts too.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use fastgif::Connection;
+use fastgif_core::Encoder;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use support::router::{self, send, setup, VIDEO};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message;
+
+mod support;
+
+async fn json(response: Response) -> Value {
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap_or_else(|_| panic!("not JSON: {}", String::from_utf8_lossy(&body)))
+}
+
+// Submit `job`, returning the status and what came back
+async fn submit(app: &Router, job: &str) -> (u16, Response) {
+    let request = Request::post("/jobs").header("content-type", "application/json").body(Body::from(job.to_string())).unwrap();
+    let response = send(app, request).await;
+    (response.status().as_u16(), response)
+}
+
+async fn status(app: &Router, id: &str) -> (u16, Value) {
+    let response = send(app, Request::get(format!("/jobs/{}", id)).body(Body::empty()).unwrap()).await;
+    let status = response.status().as_u16();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+// What the job's status is once it's no longer queued or running `state`
+async fn wait_for(app: &Router, id: &str, state: &str) -> Value {
+    let started = Instant::now();
+    loop {
+        let (code, status) = status(app, id).await;
+        assert_eq!(code, 200);
+        if status["state"] == state {
+            return status;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "still {}", status);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn jobs_make_gifs_to_be_fetched_later() {
+    let dir = setup("done", "exec cat");
+    let app = router::app(router::config(&dir)).await;
+
+    let (code, response) = submit(&app, r#"{"path": "abc.gif", "params": {"width": 480}}"#).await;
+    assert_eq!(code, 202);
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    let created = json(response).await;
+    let id = created["id"].as_str().unwrap().to_string();
+    assert_eq!(id.len(), 32);
+    assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_eq!(location, format!("/jobs/{}", id));
+    assert_eq!(created["status_url"], location);
+
+    let done = wait_for(&app, &id, "done").await;
+    assert_eq!(done["bytes"], VIDEO.len());
+    assert_eq!(done["content_type"], "image/gif");
+    assert!(done["etag"].as_str().unwrap().starts_with("W/"));
+    let result_url = done["result_url"].as_str().unwrap();
+    assert_eq!(result_url, format!("/jobs/{}/result", id));
+    let response = send(&app, Request::get(result_url).body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "image/gif");
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+
+    // Nobody gets at a job without its ID
+    assert_eq!(status(&app, "0123456789abcdef0123456789abcdef").await.0, 404);
+    let (code, _) = submit(&app, r#"{"path": "abc.gif", "what": 1}"#).await;
+    assert_eq!(code, 400);
+    let (code, _) = submit(&app, r#"{"path": "../abc.gif"}"#).await;
+    assert_eq!(code, 400);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn failed_jobs_say_why() {
+    let dir = setup("failed", "exec cat");
+    let app = router::app(router::config(&dir)).await;
+
+    let (_, response) = submit(&app, r#"{"path": "missing.gif"}"#).await;
+    let id = json(response).await["id"].as_str().unwrap().to_string();
+    let failed = wait_for(&app, &id, "failed").await;
+    assert_eq!(failed["status"], 404);
+    assert_eq!(failed["error"], "upstream_not_found");
+    let response = send(&app, Request::get(format!("/jobs/{}/result", id)).body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 409);
+    assert_eq!(json(response).await["state"], "failed");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn jobs_wait_their_turn_and_expire() {
+    let dir = setup("queued", "sleep 1\nexec cat");
+    let config = Config {
+        max_concurrent_conversions: Some(1),
+        max_jobs: 2,
+        job_ttl: 0,
+        ..router::config(&dir)
+    };
+    let app = router::app(config).await;
+
+    let (_, first) = submit(&app, r#"{"path": "abc.gif"}"#).await;
+    let first = json(first).await["id"].as_str().unwrap().to_string();
+    let (_, second) = submit(&app, r#"{"path": "abc.gif", "params": {"fps": 10}}"#).await;
+    let second = json(second).await["id"].as_str().unwrap().to_string();
+    let running = wait_for(&app, &first, "running").await;
+    assert!(running["running_ms"].is_u64());
+    assert_eq!(status(&app, &second).await.1["state"], "queued");
+    // No more than MAX_JOBS at once
+    assert_eq!(submit(&app, r#"{"path": "abc.gif"}"#).await.0, 503);
+
+    // With no TTL, a job's gone as soon as it's over
+    let started = Instant::now();
+    while status(&app, &second).await.0 != 404 {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(status(&app, &first).await.0, 404);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn higher_priorities_go_first() {
+    let dir = setup("priority", "sleep 1\nexec cat");
+    let app = router::app(Config { max_concurrent_conversions: Some(1), ..router::config(&dir) }).await;
+    let id = |response| async { json(response).await["id"].as_str().unwrap().to_string() };
+
+    let first = id(submit(&app, r#"{"path": "abc.gif"}"#).await.1).await;
+    wait_for(&app, &first, "running").await;
+    let low = id(submit(&app, r#"{"path": "abc.gif", "priority": "low"}"#).await.1).await;
+    let high = id(submit(&app, r#"{"path": "abc.gif", "params": {"fps": 10}, "priority": "high"}"#).await.1).await;
+    assert_eq!(submit(&app, r#"{"path": "abc.gif", "priority": "urgent"}"#).await.0, 400);
+
+    // Both of them queued, once their tasks have got that far
+    let started = Instant::now();
+    loop {
+        let response = send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+        let metrics = String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        let queued = |priority| metrics.contains(&format!("fastgif_conversions_queued{{priority=\"{}\"}} 1", priority));
+        if queued("high") && queued("low") {
+            assert!(metrics.contains("fastgif_conversions_queued{priority=\"normal\"} 0"), "{}", metrics);
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "{}", metrics);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // The high one was submitted last, but it's let in first
+    wait_for(&app, &high, "running").await;
+    assert_eq!(status(&app, &low).await.1["state"], "queued");
+    wait_for(&app, &low, "done").await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// A y4m stream of five 4x2 frames, a little apart
+const Y4M_FFMPEG: &str = "printf 'YUV4MPEG2 W4 H2 F10:1 C444\\n'
+for frame in 1 2 3 4 5; do
+    printf 'FRAME\\n%024d' 0
+    sleep 0.3
+done";
+
+// The events sent on `response`, as (event, data) pairs, once it's ended
+async fn events(response: Response) -> Vec<(String, Value)> {
+    let body = tokio::time::timeout(Duration::from_secs(10), to_bytes(response.into_body(), usize::MAX)).await.unwrap().unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    body.split("\n\n")
+        .filter_map(|event| {
+            let field = |name: &str| event.lines().find_map(|line| line.strip_prefix(name));
+            let data = serde_json::from_str(field("data: ")?).unwrap();
+            Some((field("event: ")?.to_string(), data))
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn events_follow_jobs_to_the_end() {
+    let dir = setup("events", Y4M_FFMPEG);
+    // gifski makes the video, which is a GIF, of the frames
+    let gifski = "cat > /dev/null\nexec cat \"$(dirname \"$0\")/abc.mp4\"";
+    for (tool, script) in [("gifski", gifski), ("ffprobe", "printf 'codec_name=h264\\nduration=0.5\\n'")] {
+        std::fs::write(dir.join(tool), format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(dir.join(tool), std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let config = Config {
+        encoder: Some(Encoder::Subprocess),
+        ..router::config(&dir)
+    };
+    let app = router::app(config).await;
+
+    let (_, response) = submit(&app, r#"{"path": "abc.gif"}"#).await;
+    let id = json(response).await["id"].as_str().unwrap().to_string();
+    let watch = || send(&app, Request::get(format!("/jobs/{}/events", id)).body(Body::empty()).unwrap());
+    let response = watch().await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+    // A few at once, but not too many, and hanging up doesn't bother the job
+    let mut others = Vec::new();
+    for _ in 0..3 {
+        others.push(watch().await);
+    }
+    assert_eq!(watch().await.status(), 429);
+    drop(others.pop());
+    let hung_up = watch().await;
+    assert_eq!(hung_up.status(), 200);
+    drop(hung_up);
+    assert_eq!(send(&app, Request::get("/jobs/0123456789abcdef0123456789abcdef/events").body(Body::empty()).unwrap()).await.status(), 404);
+
+    let sent = events(response).await;
+    assert_eq!(sent[0].0, "state", "{:?}", sent);
+    let (last, status) = sent.last().unwrap();
+    assert_eq!(last, "done", "{:?}", sent);
+    assert_eq!(status["bytes"], VIDEO.len());
+    let progress: Vec<&Value> = sent.iter().filter(|(event, _)| event == "progress").map(|(_, data)| data).collect();
+    assert!(!progress.is_empty(), "{:?}", sent);
+    for progress in &progress {
+        assert_eq!(progress["expected_frames"], 5);
+        assert!(progress["frames"].as_u64().unwrap() <= 5);
+        assert!(progress["percent"].as_u64().unwrap() < 100);
+    }
+    assert!(progress.iter().any(|progress| progress["frames"].as_u64() > Some(0)), "{:?}", progress);
+    assert!(progress.windows(2).all(|pair| pair[0]["bytes"].as_u64() <= pair[1]["bytes"].as_u64()));
+
+    // Once it's over, there's nothing to wait for
+    let again = events(watch().await).await;
+    assert_eq!(again.len(), 1);
+    assert_eq!(again[0].0, "done");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// Submit `job` with the API key `key`, returning its ID
+async fn submit_with(app: &Router, key: &str, job: &str) -> String {
+    let request = Request::post("/jobs").header("authorization", format!("Bearer {}", key)).body(Body::from(job.to_string())).unwrap();
+    let response = send(app, request).await;
+    assert_eq!(response.status(), 202);
+    json(response).await["id"].as_str().unwrap().to_string()
+}
+
+// Ask for the job `id` to be cancelled, with `token` if there is one
+async fn cancel(app: &Router, id: &str, token: Option<&str>) -> Response {
+    let mut request = Request::delete(format!("/jobs/{}", id));
+    if let Some(token) = token {
+        request = request.header("authorization", format!("Bearer {}", token));
+    }
+    send(app, request.body(Body::empty()).unwrap()).await
+}
+
+#[tokio::test]
+async fn jobs_are_cancelled_by_whoever_submitted_them() {
+    let dir = setup("cancel", "sleep 2\nexec cat");
+    let config = Config {
+        max_concurrent_conversions: Some(1),
+        api_keys: vec!["first".to_string(), "second".to_string()],
+        admin_token: Some("root".to_string()),
+        ..router::config(&dir)
+    };
+    let app = router::app(config).await;
+    let status = |id: String| {
+        let request = Request::get(format!("/jobs/{}", id)).header("authorization", "Bearer first").body(Body::empty()).unwrap();
+        let app = app.clone();
+        async move { json(send(&app, request).await).await }
+    };
+    let state_of = |id: &str| {
+        let status = status(id.to_string());
+        async move { status.await["state"].as_str().unwrap().to_string() }
+    };
+
+    let running = submit_with(&app, "first", r#"{"path": "abc.gif"}"#).await;
+    let queued = submit_with(&app, "first", r#"{"path": "abc.gif", "params": {"fps": 10}}"#).await;
+    let started = Instant::now();
+    while state_of(&running).await != "running" {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(state_of(&queued).await, "queued");
+
+    // Only by the same key, or an admin
+    assert_eq!(cancel(&app, &queued, None).await.status(), 401);
+    assert_eq!(cancel(&app, &queued, Some("second")).await.status(), 403);
+    assert_eq!(cancel(&app, "0123456789abcdef0123456789abcdef", Some("first")).await.status(), 404);
+
+    // A queued job is cancelled there and then
+    let response = cancel(&app, &queued, Some("first")).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(json(response).await["state"], "cancelled");
+    assert_eq!(state_of(&queued).await, "cancelled");
+
+    // and a running one once its ffmpeg has been stopped, well before it'd finish
+    let cancelled_at = Instant::now();
+    let response = cancel(&app, &running, Some("root")).await;
+    assert_eq!(response.status(), 202);
+    assert_eq!(json(response).await["state"], "running");
+    while state_of(&running).await != "cancelled" {
+        assert!(cancelled_at.elapsed() < Duration::from_millis(1500), "{}", status(running.clone()).await);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(cancel(&app, &running, Some("first")).await.status(), 409);
+
+    // What's done stays done
+    let done = submit_with(&app, "first", r#"{"path": "abc.gif"}"#).await;
+    let started = Instant::now();
+    while state_of(&done).await != "done" {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let response = cancel(&app, &done, Some("first")).await;
+    assert_eq!(response.status(), 409);
+    assert_eq!(json(response).await["state"], "done");
+    assert_eq!(state_of(&done).await, "done");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// `app` on a port of its own, for clients that need a real connection
+async fn listen(app: Router) -> u16 {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let app = app.layer(middleware::from_fn(|mut request: Request<Body>, next: Next| async move {
+        let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+        request.extensions_mut().insert(ConnectInfo(Connection::from(peer)));
+        next.run(request).await
+    }));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    port
+}
+
+#[tokio::test]
+async fn sockets_follow_and_cancel_jobs() {
+    let dir = setup("socket", "sleep 3\nexec cat");
+    let app = router::app(Config { job_socket_idle_timeout: 1, ..router::config(&dir) }).await;
+    let port = listen(app.clone()).await;
+    let connect = |id: String| async move {
+        let url = format!("ws://127.0.0.1:{}/ws/jobs/{}", port, id);
+        tokio_tungstenite::connect_async(url).await.unwrap().0
+    };
+    let next_event = |message: Option<Result<Message, _>>| -> Value {
+        match message.unwrap().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("not an event: {:?}", other),
+        }
+    };
+
+    // Hanging up doesn't stop a job, and one nobody hears from is hung up on
+    let (_, response) = submit(&app, r#"{"path": "abc.gif"}"#).await;
+    let left = json(response).await["id"].as_str().unwrap().to_string();
+    drop(connect(left.clone()).await);
+    let mut idle = connect(left.clone()).await;
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(next_event(idle.next().await)["event"], "state");
+    // The pings it sent went unanswered, so its goodbye may not get here
+    let hung_up = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(Ok(message)) = idle.next().await {
+            if let Message::Close(Some(frame)) = message {
+                assert_eq!(frame.code, CloseCode::Away);
+            }
+        }
+    });
+    hung_up.await.expect("the idle socket is still open");
+    assert_eq!(status(&app, &left).await.1["state"], "running");
+
+    let (_, response) = submit(&app, r#"{"path": "abc.gif", "params": {"fps": 5}}"#).await;
+    let cancelled = json(response).await["id"].as_str().unwrap().to_string();
+    let mut socket = connect(cancelled.clone()).await;
+    let first = next_event(socket.next().await);
+    assert_eq!((&first["event"], &first["data"]["id"]), (&Value::from("state"), &Value::from(cancelled.as_str())));
+    socket.send(Message::text(r#"{"op": "explode"}"#)).await.unwrap();
+    assert_eq!(next_event(socket.next().await)["data"]["error"], "invalid_message");
+    socket.send(Message::text(r#"{"op": "cancel"}"#)).await.unwrap();
+    let mut events = Vec::new();
+    while let Some(Ok(message)) = socket.next().await {
+        if let Message::Text(text) = message {
+            events.push(serde_json::from_str::<Value>(&text).unwrap());
+        }
+    }
+    let cancel = events.iter().find(|event| event["event"] == "cancel").unwrap();
+    assert_eq!(cancel["data"]["cancelled"], true);
+    assert_eq!(events.last().unwrap()["event"], "cancelled");
+    assert_eq!(status(&app, &cancelled).await.1["state"], "cancelled");
+
+    wait_for(&app, &left, "done").await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// The server restarting with a JOB_DB_PATH in `dir`, whose ffmpeg hangs
+// while there's a `hang` file there, so jobs stay running
+async fn restarted(dir: &Path, interrupted: &str) -> Router {
+    let config = Config {
+        job_db_path: Some(dir.join("jobs.db")),
+        interrupted_jobs: interrupted.parse().unwrap(),
+        max_concurrent_conversions: Some(1),
+        ..router::config(dir)
+    };
+    router::app(config).await
+}
+
+// Only conversions hang, not the checks made on ffmpeg at startup
+const HANGS: &str = "case \"$*\" in *pipe:0*) while [ -e \"$(dirname \"$0\")/hang\" ]; do sleep 0.05; done;; esac\nexec cat";
+
+#[tokio::test]
+async fn jobs_outlast_restarts() {
+    let dir = setup("restarted", HANGS);
+    let before = restarted(&dir, "fail").await;
+    let (_, done) = submit(&before, r#"{"path": "abc.gif"}"#).await;
+    let done = json(done).await["id"].as_str().unwrap().to_string();
+    wait_for(&before, &done, "done").await;
+    std::fs::write(dir.join("hang"), "").unwrap();
+    let (_, running) = submit(&before, r#"{"path": "abc.gif", "params": {"fps": 10}}"#).await;
+    let running = json(running).await["id"].as_str().unwrap().to_string();
+    let (_, queued) = submit(&before, r#"{"path": "abc.gif", "params": {"fps": 5}}"#).await;
+    let queued = json(queued).await["id"].as_str().unwrap().to_string();
+    wait_for(&before, &running, "running").await;
+    // Written behind the requests' backs
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // The GIF that was made is still there, the job that was running when
+    // the server went away fails, and the one that was waiting is converted
+    let after = restarted(&dir, "fail").await;
+    std::fs::remove_file(dir.join("hang")).unwrap();
+    let response = send(&after, Request::get(format!("/jobs/{}/result", done)).body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "image/gif");
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    let interrupted = wait_for(&after, &running, "failed").await;
+    assert_eq!(interrupted["status"], 503);
+    assert_eq!(interrupted["error"], "interrupted");
+    assert_eq!(wait_for(&after, &queued, "done").await["bytes"], VIDEO.len());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn interrupted_jobs_can_be_retried() {
+    let dir = setup("retried", HANGS);
+    let before = restarted(&dir, "retry").await;
+    std::fs::write(dir.join("hang"), "").unwrap();
+    let (_, running) = submit(&before, r#"{"path": "abc.gif"}"#).await;
+    let running = json(running).await["id"].as_str().unwrap().to_string();
+    wait_for(&before, &running, "running").await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let after = restarted(&dir, "retry").await;
+    std::fs::remove_file(dir.join("hang")).unwrap();
+    assert_eq!(wait_for(&after, &running, "done").await["bytes"], VIDEO.len());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+async fn get_async(app: &Router, uri: &str) -> Response {
+    send(app, Request::get(uri).header("prefer", "wait=5, respond-async").body(Body::empty()).unwrap()).await
+}
+
+#[tokio::test]
+async fn gets_can_be_answered_with_a_job() {
+    let dir = setup("respond-async", "exec cat");
+    let app = router::app(Config { cache_max_bytes: Some(1 << 20), ..router::config(&dir) }).await;
+
+    // Not made yet, so it's a job
+    let response = get_async(&app, "/tweet_video/abc.gif?width=480").await;
+    assert_eq!(response.status(), 202);
+    assert_eq!(response.headers()["preference-applied"], "respond-async");
+    assert_eq!(response.headers()["cache-control"], "no-store");
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    let id = json(response).await["id"].as_str().unwrap().to_string();
+    assert_eq!(location, format!("/jobs/{}", id));
+    let done = wait_for(&app, &id, "done").await;
+    let response = send(&app, Request::get(done["result_url"].as_str().unwrap()).body(Body::empty()).unwrap()).await;
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+
+    // Once it's cached, there's no need
+    let response = get_async(&app, "/tweet_video/abc.gif?width=480").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-cache"], "HIT");
+    assert!(response.headers().get("preference-applied").is_none());
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+
+    // and without asking, it's waited for as ever
+    let response = send(&app, Request::get("/tweet_video/abc.gif").body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    let response = get_async(&app, "/tweet_video/abc.gif?width=wide").await;
+    assert_eq!(response.status(), 400);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn a_job_behind_the_same_gif_is_answered_from_the_cache() {
+    // Each conversion leaves a line in `runs`
+    let script = "case \"$*\" in *pipe:0*) echo >> \"$(dirname \"$0\")/runs\"; while [ -e \"$(dirname \"$0\")/hang\" ]; do sleep 0.05; done;; esac\nexec cat";
+    let dir = setup("respond-async-in-flight", script);
+    let config = Config { cache_max_bytes: Some(1 << 20), max_concurrent_conversions: Some(1), ..router::config(&dir) };
+    let app = router::app(config).await;
+    let runs = || std::fs::read_to_string(dir.join("runs")).unwrap_or_default().lines().count();
+
+    std::fs::write(dir.join("hang"), "").unwrap();
+    let waiting = {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let response = send(&app, Request::get("/tweet_video/abc.gif").body(Body::empty()).unwrap()).await;
+            (response.status(), to_bytes(response.into_body(), usize::MAX).await.unwrap())
+        })
+    };
+    let started = Instant::now();
+    while runs() == 0 {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let response = get_async(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.status(), 202);
+    let id = json(response).await["id"].as_str().unwrap().to_string();
+    assert_eq!(status(&app, &id).await.1["state"], "queued");
+
+    std::fs::remove_file(dir.join("hang")).unwrap();
+    let (code, body) = waiting.await.unwrap();
+    assert_eq!((code.as_u16(), body.as_ref()), (200, VIDEO));
+    let done = wait_for(&app, &id, "done").await;
+    assert_eq!(done["bytes"], VIDEO.len());
+    assert_eq!(runs(), 1);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,101 @@
+//! Converts a video end to end with the libav decoder, no ffmpeg or gifski
+//! binaries involved, and a rotated one made with the ffmpeg binary, which
+//! has to be installed for that. Only built with `cargo test --features libav`.
+#![cfg(feature = "libav")]
+
+use fastgif_core::gif;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use support::server::{free_port, server_command, start};
+
+mod support;
+
+// A tiny y4m video (libav reads those as happily as mp4): 32x32, 10 fps, 1 s
+fn fixture() -> Vec<u8> {
+    let (width, height, frames) = (32, 32, 10);
+    let mut video = format!("YUV4MPEG2 W{} H{} F10:1 Ip A1:1 C420jpeg\n", width, height).into_bytes();
+    for frame in 0..frames {
+        video.extend_from_slice(b"FRAME\n");
+        video.extend((0..width * height).map(|i| ((i + frame * 20) % 220 + 16) as u8));
+        video.extend(std::iter::repeat_n(128u8, width * height / 2));
+    }
+    video
+}
+
+// 2 s of 64x48 MPEG-4 at 5 fps, tagged to be shown turned a quarter turn,
+// so 48x64, with its index first so it can be read front to back
+fn rotated() -> Vec<u8> {
+    let path = |name: &str| std::env::temp_dir().join(format!("fastgif-libav-{}-{}.mp4", name, std::process::id()));
+    let (upright, rotated) = (path("upright"), path("rotated"));
+    let ffmpeg = |args: &[&str]| {
+        Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-y"])
+            .args(args)
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    };
+    let (upright_path, rotated_path) = (upright.to_str().unwrap(), rotated.to_str().unwrap());
+    let made = ffmpeg(&["-f", "lavfi", "-i", "testsrc=duration=2:size=64x48:rate=5", "-c:v", "mpeg4", upright_path])
+        && (ffmpeg(&["-display_rotation", "-90", "-i", upright_path, "-c", "copy", "-movflags", "+faststart", rotated_path])
+            || ffmpeg(&["-i", upright_path, "-c", "copy", "-metadata:s:v:0", "rotate=90", "-movflags", "+faststart", rotated_path]));
+    assert!(made, "ffmpeg couldn't make the rotated fixture; is it installed?");
+    let video = std::fs::read(&rotated).unwrap();
+    let _ = std::fs::remove_file(&upright);
+    let _ = std::fs::remove_file(&rotated);
+    video
+}
+
+// Answers every request with `video`
+fn serve(video: Vec<u8>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let mut request = [0u8; 4096];
+            let _ = stream.read(&mut request);
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                video.len()
+            );
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(&video);
+        }
+    });
+    port
+}
+
+// The GIF a server decoding with libav makes of `video`
+fn convert(video: Vec<u8>) -> Vec<u8> {
+    let upstream = serve(video);
+    let port = free_port();
+    let base_url = format!("http://127.0.0.1:{}", upstream);
+    // No ffmpeg, gifski or ffprobe at all, so nothing to check for
+    let mut command = server_command(port, &[("VIDEO_BASE_URL", &base_url), ("DECODER", "libav"), ("ENCODER", "native")]);
+    command.stderr(Stdio::inherit());
+    let _server = start(command, port);
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET /tweet_video/fixture.gif HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let (head, body) = (String::from_utf8_lossy(&response[..split]), &response[split + 4..]);
+    assert!(head.starts_with("HTTP/1.1 200"), "unexpected response: {}", head);
+    assert!(head.to_lowercase().contains("content-type: image/gif"));
+    assert!(body.starts_with(b"GIF89a"), "body isn't a GIF");
+    body.to_vec()
+}
+
+#[test]
+fn converts_with_libav() {
+    convert(fixture());
+}
+
+#[test]
+fn rotated_videos_are_turned_upright() {
+    let summary = gif::summary(&convert(rotated())).unwrap();
+    assert_eq!((summary.width, summary.height), (48, 64), "{:?}", summary);
+}
@@ -0,0 +1,87 @@
+//! Changes the log filter of a running server through `/admin/log-level` and
+//! checks which lines it writes before and after.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use support::server::{free_port, server_command, start};
+
+mod support;
+
+const TOKEN: &str = "let-me-in";
+
+// Send a request and return the status code and body
+fn request(port: u16, method: &str, path: &str, token: Option<&str>, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let auth = token.map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        auth,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default();
+    (status, body)
+}
+
+// Wait a moment for `needle` to show up in the log, since lines are read on another thread
+fn logged(lines: &Mutex<Vec<String>>, needle: &str) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(2) {
+        if lines.lock().unwrap().iter().any(|line| line.contains(needle)) {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+#[test]
+fn log_level_changes_at_runtime() {
+    let port = free_port();
+    let mut command = server_command(port, &[("RUST_LOG", "warn"), ("ADMIN_TOKEN", TOKEN)]);
+    command.stdout(Stdio::piped());
+    let mut server = start(command, port);
+    let stdout = server.0.stdout.take().unwrap();
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let collected = lines.clone();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else { break };
+            collected.lock().unwrap().push(line);
+        }
+    });
+
+    // At `warn`, starting a conversion isn't worth a line. Whatever happens to
+    // the conversion after that (there may be no ffmpeg) doesn't matter here.
+    request(port, "GET", "/tweet_video/first.gif", None, "");
+    assert!(!logged(&lines, "Processing video: first.gif"));
+
+    assert_eq!(request(port, "PUT", "/admin/log-level", None, "info").0, 401);
+    assert_eq!(request(port, "PUT", "/admin/log-level", Some("wrong"), "info").0, 401);
+    assert_eq!(request(port, "GET", "/admin/log-level", Some(TOKEN), "").1, "warn");
+
+    let (status, body) = request(port, "PUT", "/admin/log-level", Some(TOKEN), "info,fastgif::pipeline=debug");
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(request(port, "GET", "/admin/log-level", Some(TOKEN), "").1, "fastgif::pipeline=debug,info");
+    request(port, "GET", "/tweet_video/second.gif", None, "");
+    assert!(logged(&lines, "Processing video: second.gif"));
+
+    // A filter that doesn't parse leaves the current one alone
+    let (status, _) = request(port, "PUT", "/admin/log-level", Some(TOKEN), "info,fastgif=loud");
+    assert_eq!(status, 400);
+    assert_eq!(request(port, "GET", "/admin/log-level", Some(TOKEN), "").1, "fastgif::pipeline=debug,info");
+
+    assert_eq!(request(port, "PUT", "/admin/log-level", Some(TOKEN), "error").0, 200);
+    request(port, "GET", "/tweet_video/third.gif", None, "");
+    assert!(!logged(&lines, "Processing video: third.gif"));
+}
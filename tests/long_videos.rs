@@ -0,0 +1,118 @@
+//! Asks for GIFs of a video longer than `MAX_INPUT_DURATION` with an ffmpeg
+//! that writes down its command line, and checks `LONG_VIDEO_POLICY`
+//! refuses or trims it, says so, and lets `?start=` and `?duration=` ask for
+//! less of it, but never more than the limit.
+#![cfg(unix)]
+
+use axum::body::to_bytes;
+use axum::Router;
+use fastgif::clip::LongVideoPolicy;
+use fastgif::config::Config;
+use fastgif_core::Encoder;
+use std::path::{Path, PathBuf};
+use support::router::{self, get, tool, PASSES_ON};
+
+mod support;
+
+// A minute long, with the limit at 10 s
+const PROBED: &str = "codec_name=h264\\npix_fmt=yuv420p\\nduration=60.000000";
+const MAX_INPUT_DURATION: f64 = 10.0;
+
+// One pixel of GIF, for ffmpeg to pass on as what it made
+const GIF: &str = r"printf 'GIF89a\001\000\001\000\000\000\000,\000\000\000\000\001\000\001\000\000\002\002D\001\000;'";
+
+// A directory of the test's own, with `abc.mp4`, an ffmpeg in it that writes
+// its command line to `said`, a gifski that passes on what it makes, and an
+// ffprobe that says the video is a minute long
+fn setup(test: &str) -> PathBuf {
+    let dir = router::setup(test, &format!("cat > /dev/null\nprintf '%s\\n' \"$*\" > \"$(dirname \"$0\")/said\"\n{}", GIF));
+    tool(&dir, "gifski", PASSES_ON);
+    tool(&dir, "ffprobe", &format!("printf '{}\\n'", PROBED));
+    std::fs::write(dir.join("abc.mp4"), b"not really a video").unwrap();
+    dir
+}
+
+fn config(dir: &Path) -> Config {
+    Config {
+        encoder: Some(Encoder::Subprocess),
+        max_input_duration: MAX_INPUT_DURATION,
+        ..router::config(dir)
+    }
+}
+
+// What ffmpeg in `dir` was told to do for `uri`, and the response's headers
+// about trimming
+async fn convert(app: &Router, dir: &Path, uri: &str) -> (String, Option<String>, Option<String>) {
+    let response = get(app, uri).await;
+    assert_eq!(response.status(), 200, "{}", uri);
+    let header = |name: &str| response.headers().get(name).map(|value| value.to_str().unwrap().to_string());
+    let (trimmed, duration) = (header("x-fastgif-trimmed"), header("x-fastgif-source-duration-ms"));
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(body.starts_with(b"GIF89a"), "{:?}", body);
+    (std::fs::read_to_string(dir.join("said")).unwrap(), trimmed, duration)
+}
+
+#[tokio::test]
+async fn long_videos_are_refused_unless_less_is_asked_for() {
+    let dir = setup("reject");
+    let policy = Config { long_video_policy: Some(LongVideoPolicy::Reject), ..config(&dir) };
+    let app = router::app(policy).await;
+    for uri in ["/tweet_video/abc.gif", "/tweet_video/abc.gif?start=5&duration=30", "/tweet_video/abc.gif?start=45"] {
+        let response = get(&app, uri).await;
+        assert_eq!(response.status(), 422, "{}", uri);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("input_too_long"), "{}: {:?}", uri, body);
+    }
+
+    // What's left from where it starts is short enough
+    let (args, trimmed, _) = convert(&app, &dir, "/tweet_video/abc.gif?start=2.5&duration=4").await;
+    assert!(args.contains("-ss 2.500 -t 4.000"), "{}", args);
+    assert_eq!(trimmed, None);
+    let (args, _, _) = convert(&app, &dir, "/tweet_video/abc.gif?start=55").await;
+    assert!(args.contains("-ss 55.000") && !args.contains("-t "), "{}", args);
+
+    for uri in ["/tweet_video/abc.gif?start=-1", "/tweet_video/abc.gif?duration=soon"] {
+        assert_eq!(get(&app, uri).await.status(), 400, "{}", uri);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn long_videos_are_trimmed_and_say_so() {
+    let dir = setup("trim");
+    let policy = Config { long_video_policy: Some(LongVideoPolicy::Trim), cache_max_bytes: Some(1024 * 1024), ..config(&dir) };
+    let app = router::app(policy).await;
+    let (args, trimmed, duration) = convert(&app, &dir, "/tweet_video/abc.gif").await;
+    assert!(args.contains("-t 10.000") && !args.contains("-ss "), "{}", args);
+    assert_eq!((trimmed.as_deref(), duration.as_deref()), (Some("true"), Some("60000")));
+
+    // and from the cache, just the same
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.headers()["x-cache"], "HIT");
+    assert_eq!(response.headers()["x-fastgif-trimmed"], "true");
+    assert_eq!(response.headers()["x-fastgif-source-duration-ms"], "60000");
+
+    // Asking for more than the limit gets the limit
+    let (args, trimmed, _) = convert(&app, &dir, "/tweet_video/abc.gif?start=20&duration=30").await;
+    assert!(args.contains("-ss 20.000 -t 10.000"), "{}", args);
+    assert_eq!(trimmed.as_deref(), Some("true"));
+    // and less, what was asked for
+    let (args, trimmed, duration) = convert(&app, &dir, "/tweet_video/abc.gif?duration=3").await;
+    assert!(args.contains("-t 3.000"), "{}", args);
+    assert_eq!((trimmed, duration), (None, None));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn auto_trim_still_picks_the_policy() {
+    let dir = setup("auto");
+    let app = router::app(Config { auto_trim: true, ..config(&dir) }).await;
+    let (_, trimmed, _) = convert(&app, &dir, "/tweet_video/abc.gif").await;
+    assert_eq!(trimmed.as_deref(), Some("true"));
+
+    // but not over LONG_VIDEO_POLICY
+    let policy = Config { auto_trim: true, long_video_policy: Some(LongVideoPolicy::Reject), ..config(&dir) };
+    let app = router::app(policy).await;
+    assert_eq!(get(&app, "/tweet_video/abc.gif").await.status(), 422);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,90 @@
+//! Starts the server in maintenance mode and checks conversions are refused
+//! until it's turned off through `/admin/maintenance`, except for GIFs that
+//! are sent from the cache.
+
+use axum::body::Body;
+use axum::http::Request;
+use fastgif::config::Config;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use support::router::{self, get, send, setup, PASSES_ON, VIDEO};
+use support::server::spawn_server;
+
+mod support;
+
+const TOKEN: &str = "let-me-in";
+
+// Send a request and return the whole response
+fn request(port: u16, method: &str, path: &str, token: Option<&str>, body: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let auth = token.map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        auth,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn status(response: &str) -> u16 {
+    response[9..12].parse().unwrap()
+}
+
+#[test]
+fn maintenance_mode_refuses_conversions() {
+    let (_server, port) = spawn_server(&[
+        ("MAINTENANCE", "true"),
+        ("MAINTENANCE_MESSAGE", "Back soon"),
+        ("MAINTENANCE_RETRY_AFTER", "60"),
+        ("ADMIN_TOKEN", TOKEN),
+    ]);
+
+    let refused = request(port, "GET", "/tweet_video/abc.gif", None, "");
+    assert_eq!(status(&refused), 503);
+    assert!(refused.to_ascii_lowercase().contains("retry-after: 60\r\n"), "{}", refused);
+    assert!(refused.contains(r#"{"error":"maintenance","message":"Back soon"}"#), "{}", refused);
+    // Everything that isn't a conversion carries on
+    assert_eq!(status(&request(port, "GET", "/version", None, "")), 200);
+
+    assert_eq!(status(&request(port, "GET", "/admin/maintenance", None, "")), 401);
+    let current = request(port, "GET", "/admin/maintenance", Some(TOKEN), "");
+    assert!(current.contains(r#""enabled":true"#), "{}", current);
+    assert_eq!(status(&request(port, "POST", "/admin/maintenance", Some(TOKEN), "maybe")), 400);
+
+    let turned_off = request(port, "POST", "/admin/maintenance", Some(TOKEN), "off");
+    assert!(turned_off.ends_with(r#"{"enabled":false}"#), "{}", turned_off);
+    // Whatever happens to the conversion now (there may be no ffmpeg), it isn't refused for maintenance
+    let converted = request(port, "GET", "/tweet_video/abc.gif", None, "");
+    assert!(!converted.contains(r#""error":"maintenance""#), "{}", converted);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn cached_gifs_are_sent_in_maintenance_mode() {
+    let dir = setup("cached", PASSES_ON);
+    std::fs::write(dir.join("def.mp4"), VIDEO).unwrap();
+    let config = Config { cache_max_bytes: Some(1024 * 1024), admin_token: Some(TOKEN.to_string()), ..router::config(&dir) };
+    let app = router::app(config).await;
+    assert_eq!(get(&app, "/tweet_video/abc.gif").await.status(), 200);
+
+    let request = Request::post("/admin/maintenance").header("authorization", format!("Bearer {}", TOKEN)).body(Body::from("on")).unwrap();
+    assert_eq!(send(&app, request).await.status(), 200);
+    let hit = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(hit.status(), 200);
+    assert_eq!(hit.headers()["x-cache"], "HIT");
+    // but one that would have to be converted isn't
+    let refused = get(&app, "/tweet_video/def.gif").await;
+    assert_eq!(refused.status(), 503);
+    assert_eq!(refused.headers()["cache-control"], "no-store");
+    let request = Request::get("/tweet_video/def.gif").header("prefer", "respond-async").body(Body::empty()).unwrap();
+    assert_eq!(send(&app, request).await.status(), 503);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,63 @@
+//! Runs the server with `ENCODER=native` and `tests/support/fake-tool.sh` as
+//! ffmpeg, writing out a y4m video made here, so that the gifski library does
+//! the encoding for real. Checks the GIF is one the server would send, as big
+//! as the video and with a frame for each of its. Needs neither ffmpeg nor
+//! gifski installed.
+#![cfg(all(feature = "native-encoder", target_os = "linux"))]
+
+use fastgif_core::backend::Format;
+use fastgif_core::{gif, integrity};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use support::server::spawn_server;
+
+mod support;
+
+// Ten frames of 64x48 at 5 fps, in the yuv444p the native encoder asks
+// ffmpeg for: a gradient moving along, turning from blue to red
+fn y4m() -> Vec<u8> {
+    let (width, height) = (64, 48);
+    let mut video = format!("YUV4MPEG2 W{} H{} F5:1 Ip A1:1 C444\n", width, height).into_bytes();
+    for frame in 0..10 {
+        video.extend_from_slice(b"FRAME\n");
+        video.extend((0..width * height).map(|i| ((i % width * 3 + frame * 20) % 220 + 16) as u8));
+        video.extend(std::iter::repeat_n(200 - frame as u8 * 15, width * height));
+        video.extend(std::iter::repeat_n(56 + frame as u8 * 15, width * height));
+    }
+    video
+}
+
+#[test]
+fn the_library_encodes_what_ffmpeg_decodes() {
+    let dir = std::env::temp_dir().join(format!("fastgif-native-encoder-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let tool = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/support/fake-tool.sh");
+    let ffmpeg = dir.join("ffmpeg");
+    let _ = std::fs::remove_file(&ffmpeg);
+    std::os::unix::fs::symlink(&tool, &ffmpeg).unwrap();
+    let frames = dir.join("frames.y4m");
+    std::fs::write(&frames, y4m()).unwrap();
+
+    let (_server, port) = spawn_server(&[
+        ("FFMPEG_PATH", ffmpeg.to_str().unwrap()),
+        ("FAKE_FFMPEG_OUTPUT", frames.to_str().unwrap()),
+        ("ENCODER", "native"),
+        ("ERROR_DETAIL", "true"),
+    ]);
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET /tweet_video/abc.gif HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let (head, body) = (String::from_utf8_lossy(&response[..split]), &response[split + 4..]);
+    assert!(head.starts_with("HTTP/1.1 200"), "{}\n{}", head, String::from_utf8_lossy(body));
+
+    if let Err(e) = integrity::check(body, Format::Gif) {
+        panic!("body isn't a GIF the server would send: {}", e);
+    }
+    let summary = gif::summary(body).unwrap();
+    assert_eq!((summary.width, summary.height, summary.frames), (64, 48, 10), "{:?}", summary);
+    assert_eq!(summary.loop_count, Some(0));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,168 @@
+//! Throws random paths and query strings at what parses them, since that's
+//! where requests stop being trusted: the path for the upstream URL, and the
+//! conversion's query parameters. Inputs come from a seeded generator, so a
+//! failure says which seed to run again with `FASTGIF_FUZZ_SEED`, and
+//! `FASTGIF_FUZZ_CASES` runs more of them. Inputs that have caused trouble are
+//! checked on their own too.
+
+use axum::body::to_bytes;
+use axum::Router;
+use fastgif::config::Config;
+use fastgif_core::fetch::{SourceFetcher, SourceRef, SourceStream};
+use fastgif::source_path::canonicalize;
+use fastgif::{AppState, LogFilter};
+use fastgif_core::ConversionError;
+use futures_util::future::BoxFuture;
+use std::sync::Arc;
+use support::router::get;
+use support::{cases, Rng};
+
+mod support;
+
+// Paths that must never make it to the upstream
+const BAD_PATHS: &[&str] = &[
+    "",
+    ".",
+    "..",
+    "../etc/passwd",
+    "..\\..\\windows",
+    "a/b.mp4",
+    "a..b.mp4",
+    ".hidden.mp4",
+    "a\0b.mp4",
+    "a\nb.mp4",
+    "a\rb.mp4",
+    "a b.mp4",
+    "abc.mp4?x=1",
+    "abc.mp4#x",
+    "%2e%2e",
+    "abc%00.mp4",
+    "é.mp4",
+    "abc.mp4\u{202e}",
+    "https://example.com/x.mp4",
+];
+
+// What has to hold for any path that's let through
+fn check_path(raw: &str) -> Result<(), String> {
+    let Ok(path) = canonicalize(raw) else { return Ok(()) };
+    if path.is_empty() || path.contains('/') || path.contains("..") || path.starts_with('.') {
+        return Err(format!("{:?} became {:?}", raw, path));
+    }
+    if !path.bytes().all(|b| b.is_ascii_graphic() && !matches!(b, b'?' | b'#' | b'%' | b'\\')) {
+        return Err(format!("{:?} became {:?}, which has a byte it shouldn't", raw, path));
+    }
+    if canonicalize(&path).as_ref() != Ok(&path) {
+        return Err(format!("{:?} became {:?}, which doesn't come out the same again", raw, path));
+    }
+    Ok(())
+}
+
+#[test]
+fn bad_paths_are_refused() {
+    for raw in BAD_PATHS {
+        assert!(canonicalize(raw).is_err(), "{:?} was let through", raw);
+    }
+    assert!(canonicalize(&"a".repeat(201)).is_err());
+    assert_eq!(canonicalize("FfyEjQ_WIAAd7rg.gif").unwrap(), "FfyEjQ_WIAAd7rg.mp4");
+    assert_eq!(canonicalize("FfyEjQ_WIAAd7rg.mp4").unwrap(), "FfyEjQ_WIAAd7rg.mp4");
+}
+
+#[test]
+fn random_paths_come_out_safe() {
+    let (mut rng, seed) = Rng::seeded();
+    for _ in 0..cases(20_000) {
+        let raw = rng.string(12);
+        if let Err(e) = check_path(&raw) {
+            panic!("{} (FASTGIF_FUZZ_SEED={})", e, seed);
+        }
+    }
+    // Names the way the upstream has them are all let through
+    let alphabet: Vec<char> = ('a'..='z').chain('A'..='Z').chain('0'..='9').chain(['_', '-']).collect();
+    for _ in 0..cases(20_000) {
+        let len = 1 + rng.below(30);
+        let name: String = (0..len).map(|_| *rng.pick(&alphabet)).collect();
+        let name = format!("{}{}", name, rng.pick(&[".gif", ".mp4"]));
+        assert!(canonicalize(&name).is_ok(), "{:?} was refused (FASTGIF_FUZZ_SEED={})", name, seed);
+    }
+}
+
+// Every source is missing, so a request that gets past parsing is a 404
+// without anything being converted
+#[derive(Debug)]
+struct Missing;
+
+impl SourceFetcher for Missing {
+    fn fetch<'a>(&'a self, source: &'a SourceRef) -> BoxFuture<'a, Result<SourceStream, ConversionError>> {
+        Box::pin(async move {
+            Err(ConversionError::UpstreamStatus { status: reqwest::StatusCode::NOT_FOUND, url: source.url.clone() })
+        })
+    }
+}
+
+async fn app() -> Router {
+    let config = Config {
+        skip_binary_check: true,
+        max_input_duration: 0.0,
+        ..Config::default()
+    };
+    let mut state = AppState::new(&config, LogFilter::default(), false).await.unwrap();
+    state.set_source_fetcher(Arc::new(Missing));
+    fastgif::app(&config, state)
+}
+
+async fn status(app: &Router, uri: &str) -> u16 {
+    let response = get(app, uri).await;
+    let status = response.status().as_u16();
+    to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    status
+}
+
+// Every byte escaped, so anything at all can be sent
+fn escape(value: &str) -> String {
+    value.bytes().map(|b| format!("%{:02X}", b)).collect()
+}
+
+#[tokio::test]
+async fn requests_are_parsed_without_panicking() {
+    let app = app().await;
+    assert_eq!(status(&app, "/tweet_video/..%2F..%2Fetc%2Fpasswd").await, 400);
+    assert_eq!(status(&app, "/tweet_video/abc.gif?repeat=65536").await, 400);
+    assert_eq!(status(&app, "/tweet_video/abc.gif?repeat=-1").await, 400);
+    assert_eq!(status(&app, "/tweet_video/abc.gif?repeat=65535").await, 404);
+
+    let (mut rng, seed) = Rng::seeded();
+    const REPEATS: &[&str] = &["0", "1", "3", "65535", "65536", "-1", "+2", "", "1.5", "1e3", "99999999999999999999"];
+    const FORMATS: &[&str] = &["gif", "avif", "", "GIF", "gif\0"];
+    for _ in 0..cases(300) {
+        let raw = if rng.below(2) == 0 { rng.string(12) } else { format!("{}.gif", rng.string(4)) };
+        let mut query = Vec::new();
+        let mut expected = if canonicalize(&raw).is_ok() { 404 } else { 400 };
+        if rng.below(2) == 0 {
+            let repeat = rng.pick(REPEATS);
+            if repeat.parse::<u16>().is_err() {
+                expected = 400;
+            }
+            query.push(format!("repeat={}", escape(repeat)));
+        }
+        if rng.below(2) == 0 {
+            let format = rng.pick(FORMATS);
+            if *format != "gif" {
+                expected = 400;
+            }
+            query.push(format!("format={}", escape(format)));
+        }
+        if rng.below(2) == 0 {
+            query.push(format!("optimize={}", escape(&rng.string(3))));
+        }
+        // Keys it doesn't know are ignored. These can't spell any it does.
+        if rng.below(2) == 0 {
+            query.push(format!("{}={}", escape(&rng.string(3)), escape(&rng.string(3))));
+        }
+        // Empty paths don't reach the route at all
+        if raw.is_empty() {
+            continue;
+        }
+        let uri = format!("/tweet_video/{}?{}", escape(&raw), query.join("&"));
+        assert_eq!(status(&app, &uri).await, expected, "{} (FASTGIF_FUZZ_SEED={})", uri, seed);
+    }
+}
@@ -0,0 +1,123 @@
+//! Runs `fastgif pipe` with videos on stdin, and checks what comes out on
+//! stdout, how ffmpeg was given the video, and the exit codes failures get.
+#![cfg(target_os = "linux")]
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use support::router::{fake_tools, temp_dir, tool};
+
+mod support;
+
+// An ffmpeg that copies the video to `read` next to it, from wherever it was
+// told to read it, noting where that was in `input`, and makes `pixel.gif`
+// of it
+const PASS_THROUGH: &str = r#"while [ $# -gt 0 ]; do [ "$1" = "-i" ] && input=$2; shift; done
+[ -n "$input" ] || exit 0
+dir=$(dirname "$0")
+echo "$input" > "$dir/input"
+if [ "$input" = pipe:0 ]; then cat > "$dir/read"; else cat "$input" > "$dir/read"; fi
+exec cat "$dir/pixel.gif"
+"#;
+
+// A directory of the test's own with that ffmpeg, and the fake tools as
+// `fake/ffmpeg` and `fake/gifski`
+fn setup(test: &str) -> PathBuf {
+    let dir = temp_dir(test);
+    tool(&dir, "ffmpeg", PASS_THROUGH);
+    std::fs::copy(pixel(), dir.join("pixel.gif")).unwrap();
+    std::fs::create_dir_all(dir.join("fake")).unwrap();
+    fake_tools(&dir.join("fake"));
+    dir
+}
+
+// A whole GIF, for what's made to be
+fn pixel() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/pixel.gif")
+}
+
+type Env<'a> = &'a [(&'a str, &'a str)];
+
+fn pipe(env: Env, video: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fastgif"))
+        .arg("pipe")
+        .envs(env.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let _ = stdin.write_all(video);
+    drop(stdin);
+    child.wait_with_output().unwrap()
+}
+
+// The smallest MP4 boxes there are: a size, a type and `body` bytes
+fn mp4_box(kind: &[u8; 4], body: usize) -> Vec<u8> {
+    let mut bytes = ((8 + body) as u32).to_be_bytes().to_vec();
+    bytes.extend(kind);
+    bytes.extend(vec![0; body]);
+    bytes
+}
+
+#[test]
+fn videos_stream_through_unless_they_need_seeking() {
+    let dir = setup("seeking");
+    let ffmpeg = dir.join("ffmpeg");
+    let env = [
+        ("FFMPEG_PATH", ffmpeg.to_str().unwrap()),
+        ("ENCODER", "ffmpeg"),
+        ("SKIP_BINARY_CHECK", "true"),
+        ("TMP_DIR", dir.to_str().unwrap()),
+    ];
+    let fast_start = [mp4_box(b"ftyp", 16), mp4_box(b"moov", 100), mp4_box(b"mdat", 1000)].concat();
+    let index_last = [mp4_box(b"ftyp", 16), mp4_box(b"mdat", 100_000), mp4_box(b"moov", 100)].concat();
+    for (video, streamed) in [(b"not an mp4".to_vec(), true), (fast_start, true), (index_last, false)] {
+        let output = pipe(&env, &video);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(output.stdout, std::fs::read(pixel()).unwrap());
+        assert_eq!(std::fs::read(dir.join("read")).unwrap(), video);
+        let input = std::fs::read_to_string(dir.join("input")).unwrap();
+        assert_eq!(input.trim() == "pipe:0", streamed, "{}", input);
+        if !streamed {
+            assert!(!Path::new(input.trim()).exists(), "{} was left behind", input);
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn failures_exit_with_their_kind() {
+    let dir = setup("failures");
+    let (ffmpeg, gifski) = (dir.join("fake/ffmpeg"), dir.join("fake/gifski"));
+    let tools = [
+        ("FFMPEG_PATH", ffmpeg.to_str().unwrap()),
+        ("GIFSKI_PATH", gifski.to_str().unwrap()),
+        ("ENCODER", "subprocess"),
+    ];
+    let index_last = [mp4_box(b"ftyp", 16), mp4_box(b"mdat", 1000)].concat();
+    let cases: &[(Env, &[u8], i32, &str)] = &[
+        (&[("FAKE_FFMPEG_EXIT", "1")], b"video", 4, "ffmpeg_decode_error"),
+        (&[("FAKE_FFMPEG_BYTES", "100"), ("FAKE_GIFSKI_EXIT", "1")], b"video", 5, "gifski_encode_error"),
+        (&[("FAKE_GIFSKI_BYTES", "1000"), ("MAX_OUTPUT_BYTES", "100")], b"video", 6, "output_too_large"),
+        (&[("MAX_INPUT_BYTES", "100")], &index_last, 6, "input_too_large"),
+    ];
+    for (env, video, code, class) in cases {
+        let env: Vec<(&str, &str)> = tools.iter().chain(env.iter()).copied().collect();
+        let output = pipe(&env, video);
+        let said = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(*code), "{:?}: {}", env, said);
+        assert!(said.starts_with(class), "{:?}: {}", env, said);
+        assert!(output.stdout.is_empty(), "{:?}", env);
+    }
+
+    // and when it works, only the GIF is on stdout
+    let pixel = pixel();
+    let env: Vec<(&str, &str)> = tools.iter().copied().chain([("FAKE_GIFSKI_OUTPUT", pixel.to_str().unwrap())]).collect();
+    let output = pipe(&env, b"video");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, std::fs::read(&pixel).unwrap());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("43 bytes in "));
+    let _ = std::fs::remove_dir_all(&dir);
+}
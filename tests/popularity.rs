@@ -0,0 +1,108 @@
+//! Asks for a few GIFs, and checks `GET /admin/top` lists their paths by how
+//! often they were asked for, with what was converted, sent from the cache,
+//! and how many bytes went out, for no more paths than it's allowed.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use support::router::{self, send, READS_INPUT, VIDEO};
+
+mod support;
+
+// A directory of the test's own, with `abc.mp4`, `def.mp4` and `ghi.mp4`,
+// and an ffmpeg in it that passes on what it's given to read
+fn setup(test: &str) -> PathBuf {
+    let dir = router::setup(test, READS_INPUT);
+    for name in ["def", "ghi"] {
+        std::fs::write(dir.join(format!("{}.mp4", name)), VIDEO).unwrap();
+    }
+    dir
+}
+
+fn config(dir: &Path, max_paths: usize) -> Config {
+    Config {
+        cache_max_bytes: Some(1024 * 1024),
+        popularity_max_paths: max_paths,
+        admin_token: Some("admin".to_string()),
+        ..router::config(dir)
+    }
+}
+
+// Read all of it, as only what's sent is counted
+async fn get(app: &Router, name: &str) {
+    let response = send(app, Request::get(format!("/tweet_video/{}.gif", name)).body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 200);
+    to_bytes(response.into_body(), usize::MAX).await.unwrap();
+}
+
+async fn admin(app: &Router, method: &str, uri: &str) -> (u16, Value) {
+    let request = Request::builder().method(method).uri(uri).header("authorization", "Bearer admin").body(Body::empty()).unwrap();
+    let response = send(app, request).await;
+    let status = response.status().as_u16();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned())))
+}
+
+#[tokio::test]
+async fn the_paths_asked_for_most_are_listed_first() {
+    let dir = setup("top");
+    let app = router::app(config(&dir, 100)).await;
+    for name in ["abc", "abc", "abc", "def"] {
+        get(&app, name).await;
+    }
+
+    let (status, top) = admin(&app, "GET", "/admin/top?window=1h&n=50").await;
+    assert_eq!(status, 200, "{}", top);
+    let bytes = VIDEO.len() as u64;
+    assert_eq!(
+        top,
+        json!({
+            "window": "1h",
+            "tracked": 2,
+            "max_paths": 100,
+            "paths": [
+                {"path": "abc.mp4", "hits": 3, "conversions": 1, "cache_hit_ratio": 2.0 / 3.0, "bytes": 3 * bytes},
+                {"path": "def.mp4", "hits": 1, "conversions": 1, "cache_hit_ratio": 0.0, "bytes": bytes},
+            ],
+        })
+    );
+    let (_, top) = admin(&app, "GET", "/admin/top?window=all&n=1").await;
+    assert_eq!(top["paths"].as_array().unwrap().len(), 1);
+    assert_eq!(top["paths"][0]["path"], "abc.mp4");
+
+    for window in ["2h", "soon", "0m"] {
+        let (status, _) = admin(&app, "GET", &format!("/admin/top?window={}", window)).await;
+        assert_eq!(status, 400, "{}", window);
+    }
+    let response = send(&app, Request::get("/admin/top").body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 401);
+
+    // until it's reset
+    assert_eq!(admin(&app, "DELETE", "/admin/top").await, (200, json!({"reset": 2})));
+    let (_, top) = admin(&app, "GET", "/admin/top").await;
+    assert_eq!(top["paths"], json!([]));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn no_more_paths_are_tracked_than_allowed() {
+    let dir = setup("bounded");
+    let app = router::app(config(&dir, 2)).await;
+    for name in ["abc", "def", "ghi"] {
+        get(&app, name).await;
+    }
+    let (_, top) = admin(&app, "GET", "/admin/top").await;
+    assert_eq!(top["tracked"], 2);
+    // The one asked for longest ago went first
+    let paths: Vec<&str> = top["paths"].as_array().unwrap().iter().map(|path| path["path"].as_str().unwrap()).collect();
+    assert_eq!(paths, ["def.mp4", "ghi.mp4"]);
+
+    let app = router::app(config(&dir, 0)).await;
+    get(&app, "abc").await;
+    assert_eq!(admin(&app, "GET", "/admin/top").await.0, 404);
+    let _ = std::fs::remove_dir_all(&dir);
+}
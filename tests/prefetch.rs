@@ -0,0 +1,164 @@
+//! Prefetches GIFs with `POST /prefetch`, and checks they end up in the cache
+//! without getting in the way of anyone actually waiting for a GIF.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::Value;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use support::router::{self, send, setup, HANGS, VIDEO};
+
+mod support;
+
+fn config(dir: &Path) -> Config {
+    Config {
+        admin_token: Some("admin".to_string()),
+        max_concurrent_per_client: 0,
+        ..router::config(dir)
+    }
+}
+
+fn cached(dir: &Path) -> Config {
+    Config { cache_max_bytes: Some(1 << 20), ..config(dir) }
+}
+
+async fn prefetch(app: &Router, item: &str) -> Response {
+    let request = Request::post("/prefetch").header("content-type", "application/json").body(Body::from(item.to_string())).unwrap();
+    send(app, request).await
+}
+
+// A conversion as `request_id`, which comes back with its status
+fn convert(app: &Router, uri: &'static str, request_id: &'static str) -> tokio::task::JoinHandle<u16> {
+    let app = app.clone();
+    tokio::spawn(async move {
+        let request = Request::get(uri).header("x-request-id", request_id).header("x-fastgif-priority", "low").body(Body::empty()).unwrap();
+        let response = send(&app, request).await;
+        let status = response.status().as_u16();
+        let _ = to_bytes(response.into_body(), usize::MAX).await;
+        status
+    })
+}
+
+async fn json(app: &Router, request: Request<Body>) -> Value {
+    let response = send(app, request).await;
+    assert_eq!(response.status(), 200);
+    serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+}
+
+async fn running(app: &Router) -> Vec<String> {
+    let request = Request::get("/admin/conversions").header("authorization", "Bearer admin").body(Body::empty()).unwrap();
+    let conversions = json(app, request).await;
+    conversions.as_array().unwrap().iter().map(|conversion| conversion["request_id"].as_str().unwrap().to_string()).collect()
+}
+
+async fn metrics(app: &Router) -> String {
+    let response = send(app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+    String::from_utf8(to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap()
+}
+
+async fn wait_for_metric(app: &Router, line: &str) {
+    let started = Instant::now();
+    while !metrics(app).await.contains(line) {
+        assert!(started.elapsed() < Duration::from_secs(10), "{}", metrics(app).await);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn prefetched_gifs_are_sent_from_the_cache() {
+    let dir = setup("cached", HANGS);
+    let app = router::app(cached(&dir)).await;
+    let response = prefetch(&app, r#"{"path": "abc.gif"}"#).await;
+    assert_eq!(response.status(), 202);
+    assert!(to_bytes(response.into_body(), usize::MAX).await.unwrap().is_empty());
+    wait_for_metric(&app, "fastgif_prefetches_total{outcome=\"converted\"} 1").await;
+
+    // There's nothing more to do for it
+    assert_eq!(prefetch(&app, r#"{"path": "/tweet_video/abc.gif"}"#).await.status(), 202);
+    assert!(metrics(&app).await.contains("fastgif_prefetches_total{outcome=\"cached\"} 1"));
+
+    // and whoever asks for it first is what it was for
+    for _ in 0..2 {
+        let response = send(&app, Request::get("/tweet_video/abc.gif").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    }
+    let metrics = metrics(&app).await;
+    assert!(metrics.contains("fastgif_prefetch_hits_total 1"), "{}", metrics);
+    assert!(metrics.contains("fastgif_prefetches_total{outcome=\"converted\"} 1"), "{}", metrics);
+
+    assert_eq!(prefetch(&app, r#"{"path": "../abc.gif"}"#).await.status(), 400);
+    assert_eq!(prefetch(&app, r#"{"path": "abc.gif", "params": {"width": "wide"}}"#).await.status(), 400);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn prefetches_wait_behind_everyone_else() {
+    let dir = setup("behind", HANGS);
+    let config = Config { max_concurrent_conversions: Some(1), max_queue_depth: Some(1), queue_wait_timeout: 30.0, ..cached(&dir) };
+    let app = router::app(config).await;
+    let kill = |request_id: &str| {
+        let request = Request::delete(format!("/admin/conversions/{}", request_id)).header("authorization", "Bearer admin");
+        send(&app, request.body(Body::empty()).unwrap())
+    };
+    let running_is = |expected: &'static str| {
+        let app = app.clone();
+        async move {
+            let started = Instant::now();
+            while running(&app).await != [expected] {
+                assert!(started.elapsed() < Duration::from_secs(10), "{:?}", running(&app).await);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+    };
+
+    std::fs::write(dir.join("hang"), "").unwrap();
+    let first = convert(&app, "/tweet_video/abc.gif?width=100", "first");
+    running_is("first").await;
+
+    // Waiting for a permit, but not in the queue, which still has room
+    let request = Request::post("/prefetch").header("x-request-id", "prefetch").body(Body::from(r#"{"path": "abc.gif"}"#)).unwrap();
+    assert_eq!(send(&app, request).await.status(), 202);
+    assert_eq!(prefetch(&app, r#"{"path": "abc.gif"}"#).await.status(), 202);
+    assert!(metrics(&app).await.contains("fastgif_prefetches_total{outcome=\"in_flight\"} 1"));
+    let started = Instant::now();
+    loop {
+        let status = json(&app, Request::get("/queue").body(Body::empty()).unwrap()).await;
+        if status["prefetches_queued"] == 1 {
+            assert_eq!(status["queued"], 0, "{}", status);
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "{}", status);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // Someone who came later, at the lowest priority there is, still goes first
+    let second = convert(&app, "/tweet_video/abc.gif?width=200", "second");
+    let started = Instant::now();
+    while json(&app, Request::get("/queue").body(Body::empty()).unwrap()).await["queued"] != 1 {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(kill("first").await.status(), 200);
+    assert_eq!(first.await.unwrap(), 503);
+    running_is("second").await;
+    assert_eq!(kill("second").await.status(), 200);
+    assert_eq!(second.await.unwrap(), 503);
+    running_is("prefetch").await;
+
+    std::fs::remove_file(dir.join("hang")).unwrap();
+    wait_for_metric(&app, "fastgif_prefetches_total{outcome=\"converted\"} 1").await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn prefetching_needs_a_cache() {
+    let dir = setup("uncached", HANGS);
+    let app = router::app(config(&dir)).await;
+    assert_eq!(prefetch(&app, r#"{"path": "abc.gif"}"#).await.status(), 404);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,346 @@
+//! Runs the server with `tests/support/fake-tool.sh` as both ffmpeg and
+//! gifski, each told through `FAKE_FFMPEG_*` and `FAKE_GIFSKI_*` to misbehave
+//! in some way, and checks what the process management makes of it: how exit
+//! statuses and signals are answered, what's kept of their stderr, what comes
+//! of either failing partway through, that a conversion that times out or
+//! whose pipe fails midway leaves nothing running, that a gifski crashing
+//! once is tried again, and that shutting down lets a conversion finish. None of it needs ffmpeg or gifski installed.
+#![cfg(target_os = "linux")]
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+use support::server::{spawn_server, Server};
+
+mod support;
+
+// What the children are told to do, and the status and error code that
+// should come of it
+type Case = (&'static [(&'static str, &'static str)], u16, &'static str);
+
+const CASES: &[Case] = &[
+    (&[("FAKE_GIFSKI_BYTES", "64"), ("FAKE_GIFSKI_EXIT", "1")], 500, "gifski_encode_error"),
+    (&[("FAKE_GIFSKI_SIGNAL", "SEGV")], 500, "gifski_encode_error"),
+    (&[("FAKE_GIFSKI_SIGNAL", "KILL")], 500, "resource_limit"),
+    (&[("FAKE_FFMPEG_EXIT", "1")], 500, "ffmpeg_decode_error"),
+    (&[("FAKE_FFMPEG_SIGNAL", "SEGV")], 500, "ffmpeg_decode_error"),
+    (&[("FAKE_FFMPEG_SIGNAL", "XCPU")], 500, "resource_limit"),
+    (&[("FAKE_FFMPEG_STDERR", "Server returned 404 Not Found"), ("FAKE_FFMPEG_EXIT", "1")], 404, "upstream_not_found"),
+    (&[("FAKE_GIFSKI_BYTES", "1000"), ("MAX_OUTPUT_BYTES", "100")], 413, "output_too_large"),
+    // gifski says it's fine, but what it wrote isn't a GIF
+    (&[("FAKE_GIFSKI_BYTES", "64")], 500, "gifski_encode_error"),
+];
+
+// A directory of the test's own with `ffmpeg` and `gifski` in it, both the
+// fake tool
+fn fake_tools(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fastgif-processes-{}-{}", test, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let tool = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/support/fake-tool.sh");
+    for name in ["ffmpeg", "gifski"] {
+        let _ = std::fs::remove_file(dir.join(name));
+        std::os::unix::fs::symlink(&tool, dir.join(name)).unwrap();
+    }
+    dir
+}
+
+// Start the server with the fake tools and `env`, which says how they behave
+fn start(dir: &Path, env: &[(&str, &str)]) -> (Server, u16) {
+    let (ffmpeg, gifski) = (dir.join("ffmpeg"), dir.join("gifski"));
+    let tools = [
+        ("FFMPEG_PATH", ffmpeg.to_str().unwrap()),
+        ("GIFSKI_PATH", gifski.to_str().unwrap()),
+        ("ENCODER", "subprocess"),
+        ("ERROR_DETAIL", "true"),
+        // The fake tools say what they are when asked
+        ("SKIP_BINARY_CHECK", "false"),
+    ];
+    spawn_server(&[&tools, env].concat())
+}
+
+// Convert a video and return the response's head and body as they were
+// sent, for as much of them as was. A broken-off response is cut short.
+fn request(port: u16) -> (String, Vec<u8>) {
+    fetch(port, "/tweet_video/abc.gif")
+}
+
+// Ask for `path`, the same way
+fn fetch(port: u16, path: &str) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&response[..split]).to_ascii_lowercase();
+    (head, response[split + 4..].to_vec())
+}
+
+// Convert a video and return the status code and body
+fn convert(port: u16) -> (u16, Vec<u8>) {
+    let (head, body) = request(port);
+    (head[9..12].parse().unwrap(), body)
+}
+
+// The error code in a failed conversion's body
+fn error_code(body: &[u8]) -> String {
+    let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+    body["error"].as_str().unwrap_or_default().to_string()
+}
+
+// The pid the fake tool wrote to `path`
+fn pid(path: &Path) -> u32 {
+    std::fs::read_to_string(path).unwrap().trim().parse().unwrap()
+}
+
+// Whether `pid` is still running, as opposed to gone or a zombie
+fn running(pid: u32) -> bool {
+    std::fs::read_to_string(format!("/proc/{}/stat", pid))
+        .ok()
+        .and_then(|stat| stat.rsplit_once(") ")?.1.chars().next())
+        .is_some_and(|state| state != 'Z')
+}
+
+#[test]
+fn exit_statuses_are_answered() {
+    let dir = fake_tools("statuses");
+    for (env, status, code) in CASES {
+        let (_server, port) = start(&dir, env);
+        let (got, body) = convert(port);
+        assert_eq!(got, *status, "{:?}: {}", env, String::from_utf8_lossy(&body));
+        assert_eq!(error_code(&body), *code, "{:?}", env);
+    }
+
+    // and when they behave, the GIF is whatever gifski wrote
+    let pixel = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/pixel.gif");
+    let (_server, port) = start(&dir, &[("FAKE_FFMPEG_BYTES", "100000"), ("FAKE_GIFSKI_OUTPUT", pixel.to_str().unwrap())]);
+    assert_eq!(convert(port), (200, std::fs::read(&pixel).unwrap()));
+    // with what each of them used measured as they exited
+    let metrics = String::from_utf8(fetch(port, "/metrics").1).unwrap();
+    for histogram in ["child_user_cpu_seconds", "child_system_cpu_seconds", "child_max_rss_bytes"] {
+        for process in ["ffmpeg", "gifski"] {
+            let series = format!("fastgif_{}_count{{process=\"{}\"}} 1", histogram, process);
+            assert!(metrics.lines().any(|line| line == series), "no {} in {}", series, metrics);
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn stderr_is_kept_to_its_tail() {
+    let dir = fake_tools("stderr");
+    let (_server, port) = start(
+        &dir,
+        &[
+            ("FAKE_FFMPEG_STDERR_LINES", "5000"),
+            ("FAKE_FFMPEG_STDERR", "Invalid data found when processing input"),
+            ("FAKE_FFMPEG_EXIT", "1"),
+            ("FAKE_GIFSKI_STDERR", "gifski: too few frames"),
+        ],
+    );
+    let (status, body) = convert(port);
+    assert_eq!(status, 422, "{}", String::from_utf8_lossy(&body));
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"], "unsupported_source");
+    assert_eq!(body["message"], "Failed to process video: Source has nothing to convert: it isn't a video, or is too damaged to read");
+    let ffmpeg: Vec<&str> = body["stderr"]["ffmpeg"].as_array().unwrap().iter().filter_map(|line| line.as_str()).collect();
+    assert_eq!(ffmpeg.len(), 20, "{:?}", ffmpeg);
+    assert_eq!(ffmpeg[0], "ffmpeg: line 4982");
+    assert_eq!(ffmpeg[19], "Invalid data found when processing input");
+    assert_eq!(body["stderr"]["gifski"], serde_json::json!(["gifski: too few frames"]));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn timed_out_conversions_leave_nothing_running() {
+    let dir = fake_tools("timeout");
+    let pidfile = dir.join("ffmpeg.pid");
+    // ffmpeg never finishes and shrugs off SIGTERM, as does its child
+    let (_server, port) = start(
+        &dir,
+        &[
+            ("FAKE_FFMPEG_PIDFILE", pidfile.to_str().unwrap()),
+            ("FAKE_FFMPEG_IGNORE_TERM", "1"),
+            ("FAKE_FFMPEG_SLEEP", "forever"),
+            ("CONVERSION_TIMEOUT", "1"),
+        ],
+    );
+    let started = Instant::now();
+    let (status, body) = convert(port);
+    assert_eq!(status, 504, "{}", String::from_utf8_lossy(&body));
+    assert_eq!(error_code(&body), "timeout");
+    // The timeout, then the grace after SIGTERM, then SIGKILL
+    assert!(started.elapsed() < Duration::from_secs(8), "took {:?}", started.elapsed());
+
+    let (ffmpeg, child) = (pid(&pidfile), pid(&dir.join("ffmpeg.pid.child")));
+    let started = Instant::now();
+    while running(ffmpeg) || running(child) {
+        assert!(started.elapsed() < Duration::from_secs(2), "ffmpeg or its child is still running");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn a_pipe_failing_midway_leaves_nothing_running() {
+    let dir = fake_tools("midway");
+    let (ffmpeg_pidfile, gifski_pidfile) = (dir.join("ffmpeg.pid"), dir.join("gifski.pid"));
+    // gifski gives up after the first frames, while ffmpeg, and a child of
+    // its, would carry on for an hour
+    let (_server, port) = start(
+        &dir,
+        &[
+            ("FAKE_FFMPEG_PIDFILE", ffmpeg_pidfile.to_str().unwrap()),
+            ("FAKE_FFMPEG_BYTES", "1000"),
+            ("FAKE_FFMPEG_SLEEP", "forever"),
+            ("FAKE_GIFSKI_PIDFILE", gifski_pidfile.to_str().unwrap()),
+            ("FAKE_GIFSKI_READ_BYTES", "1000"),
+            ("FAKE_GIFSKI_EXIT", "1"),
+        ],
+    );
+    let started = Instant::now();
+    let (status, body) = convert(port);
+    assert_eq!(status, 500, "{}", String::from_utf8_lossy(&body));
+    assert_eq!(error_code(&body), "gifski_encode_error");
+    assert!(started.elapsed() < Duration::from_secs(10), "took {:?}", started.elapsed());
+
+    let pids = [pid(&ffmpeg_pidfile), pid(&dir.join("ffmpeg.pid.child")), pid(&gifski_pidfile)];
+    let started = Instant::now();
+    while let Some(pid) = pids.iter().find(|pid| running(**pid)) {
+        assert!(started.elapsed() < Duration::from_secs(2), "{} of {:?} is still running", pid, pids);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// How the children fail partway through, and what should come of it
+const PARTWAY: &[Case] = &[
+    // ffmpeg gets some frames out, then gives up, having read only the start
+    // of the video if it's being fed it
+    (
+        &[("FAKE_FFMPEG_READ_BYTES", "1000"), ("FAKE_FFMPEG_BYTES", "100000"), ("FAKE_FFMPEG_EXIT", "1"), ("FAKE_GIFSKI_BYTES", "64")],
+        500,
+        "ffmpeg_decode_error",
+    ),
+    // gifski goes away without reading anything, so ffmpeg dies of SIGPIPE
+    (&[("FAKE_FFMPEG_BYTES", "50000000"), ("FAKE_GIFSKI_READ_BYTES", "0"), ("FAKE_GIFSKI_EXIT", "1")], 500, "gifski_encode_error"),
+    // Both say they're fine, but there's no GIF
+    (&[], 500, "gifski_encode_error"),
+];
+
+#[test]
+fn children_failing_partway_through_fail_the_conversion() {
+    let dir = fake_tools("partway");
+    // A video big enough that nothing reads all of it at once
+    let videos = dir.join("videos");
+    std::fs::create_dir_all(&videos).unwrap();
+    std::fs::write(videos.join("abc.mp4"), vec![0u8; 8 << 20]).unwrap();
+
+    for (env, status, code) in PARTWAY {
+        // With ffmpeg fetching the video, and then fed it from `SOURCE_DIR`
+        for fed in [&[][..], &[("SOURCE_DIR", videos.to_str().unwrap())]] {
+            let env: Vec<(&str, &str)> = env.iter().chain(fed).copied().collect();
+            let (_server, port) = start(&dir, &env);
+            let started = Instant::now();
+            let (head, body) = request(port);
+            assert_eq!(head[9..12].parse::<u16>().unwrap(), *status, "{:?}: {}", env, String::from_utf8_lossy(&body));
+            assert_eq!(error_code(&body), *code, "{:?}", env);
+            assert!(!head.contains("\r\netag:"), "{:?} was tagged for caching", env);
+            assert!(started.elapsed() < Duration::from_secs(10), "{:?} took {:?}", env, started.elapsed());
+
+            // Streamed, the client either gets the error, or a body that breaks
+            // off rather than ending
+            let streamed: Vec<(&str, &str)> = env.iter().copied().chain([("STREAM_RESPONSE", "true")]).collect();
+            let (_server, port) = start(&dir, &streamed);
+            let (head, body) = request(port);
+            if head.starts_with("http/1.1 200") {
+                assert!(!body.ends_with(b"0\r\n\r\n"), "{:?} was streamed as if it had worked", streamed);
+            } else {
+                assert_eq!(error_code(&body), *code, "{:?}", streamed);
+            }
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn a_gifski_that_crashes_once_is_retried() {
+    let dir = fake_tools("retry");
+    let pixel = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/pixel.gif");
+    let crashed = dir.join("crashed");
+    let gifski = [
+        ("FAKE_FFMPEG_BYTES", "100000"),
+        ("FAKE_GIFSKI_OUTPUT", pixel.to_str().unwrap()),
+        ("FAKE_GIFSKI_ONCE", crashed.to_str().unwrap()),
+    ];
+    for (signal, limits, expected) in [
+        ("SEGV", &[][..], (200, "succeeded")),
+        // Killed the way the OOM killer would
+        ("KILL", &[][..], (200, "succeeded")),
+        // but with a memory limit of ours, that's what killed it, and would again
+        ("KILL", &[("CHILD_MAX_MEMORY_MB", "4096")][..], (500, "none")),
+    ] {
+        let _ = std::fs::remove_file(&crashed);
+        let (_server, port) = start(&dir, &[&gifski[..], &[("FAKE_GIFSKI_SIGNAL", signal)], limits].concat());
+        let (status, body) = convert(port);
+        assert_eq!(status, expected.0, "{} {:?}: {}", signal, limits, String::from_utf8_lossy(&body));
+        if status == 200 {
+            assert_eq!(body, std::fs::read(&pixel).unwrap());
+        } else {
+            assert_eq!(error_code(&body), "resource_limit");
+        }
+        assert!(crashed.exists(), "gifski never crashed");
+
+        let metrics = String::from_utf8(fetch(port, "/metrics").1).unwrap();
+        for outcome in ["succeeded", "failed"] {
+            let count = if outcome == expected.1 { 1 } else { 0 };
+            let series = format!("fastgif_encoder_retries_total{{outcome=\"{}\"}} {}", outcome, count);
+            assert!(metrics.lines().any(|line| line == series), "no {} for {} {:?} in {}", series, signal, limits, metrics);
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn shutting_down_finishes_what_was_started_and_takes_nothing_new() {
+    let dir = fake_tools("drain");
+    let pidfile = dir.join("ffmpeg.pid");
+    let pixel = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/pixel.gif");
+    // ffmpeg takes a couple of seconds over the video
+    let (mut server, port) = start(
+        &dir,
+        &[
+            ("FAKE_FFMPEG_PIDFILE", pidfile.to_str().unwrap()),
+            ("FAKE_FFMPEG_SLEEP", "2"),
+            ("FAKE_GIFSKI_OUTPUT", pixel.to_str().unwrap()),
+        ],
+    );
+    let converting = std::thread::spawn(move || convert(port));
+    let started = Instant::now();
+    while !pidfile.exists() {
+        assert!(started.elapsed() < Duration::from_secs(5), "the conversion didn't start");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    let terminated = Command::new("kill").args(["-TERM", &server.0.id().to_string()]).status().unwrap();
+    assert!(terminated.success());
+    let started = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_ok() {
+        assert!(started.elapsed() < Duration::from_secs(1), "new connections are still being taken");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!converting.is_finished(), "the conversion was over before the listener closed");
+
+    assert_eq!(converting.join().unwrap(), (200, std::fs::read(&pixel).unwrap()));
+    let started = Instant::now();
+    let exited = loop {
+        if let Some(status) = server.0.try_wait().unwrap() {
+            break status;
+        }
+        assert!(started.elapsed() < Duration::from_secs(5), "the server didn't exit once it was drained");
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    assert!(exited.success(), "the server exited with {}", exited);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,126 @@
+//! Purges cached GIFs with `POST /admin/purge`, and checks a hard purge has
+//! the next request convert it again, while after a soft one the old GIF's
+//! sent as stale until a single conversion in the background replaces it.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use support::router::{self, send, setup, HANGS, VIDEO};
+
+mod support;
+
+// The same, of another colour, for the video once it's changed
+const CHANGED: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\x00\xff\x00\
+    !\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+
+fn config(dir: &Path) -> Config {
+    Config {
+        cache_max_bytes: Some(1024 * 1024),
+        admin_token: Some("admin".to_string()),
+        ..router::config(dir)
+    }
+}
+
+// A GIF, and what its `X-Cache` said
+async fn get(app: &Router) -> (String, Response) {
+    let response = send(app, Request::get("/tweet_video/abc.gif").body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 200);
+    let cache = response.headers().get("x-cache").unwrap().to_str().unwrap().to_string();
+    (cache, response)
+}
+
+async fn body(response: Response) -> Vec<u8> {
+    to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()
+}
+
+async fn purge(app: &Router, mode: &str) -> Value {
+    let request = Request::post(format!("/admin/purge{}", mode))
+        .header("authorization", "Bearer admin")
+        .body(Body::from(r#"{"path": "abc.gif"}"#))
+        .unwrap();
+    let response = send(app, request).await;
+    assert_eq!(response.status(), 200);
+    serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+}
+
+async fn conversions(app: &Router) -> usize {
+    let request = Request::get("/admin/conversions").header("authorization", "Bearer admin").body(Body::empty()).unwrap();
+    let response = send(app, request).await;
+    let listed: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+    listed.as_array().unwrap().len()
+}
+
+#[tokio::test]
+async fn a_hard_purge_has_the_gif_converted_again() {
+    let dir = setup("hard", HANGS);
+    let app = router::app(config(&dir)).await;
+    assert_eq!(get(&app).await.0, "MISS");
+    assert_eq!(get(&app).await.0, "HIT");
+    std::fs::write(dir.join("abc.mp4"), CHANGED).unwrap();
+
+    assert_eq!(purge(&app, "").await, json!({"mode": "hard", "purged": ["memory"]}));
+    let (cache, response) = get(&app).await;
+    assert_eq!(cache, "MISS");
+    assert_eq!(body(response).await, CHANGED);
+    // and again, once it's gone already
+    purge(&app, "?mode=hard").await;
+    assert_eq!(purge(&app, "?mode=hard").await, json!({"mode": "hard", "purged": []}));
+
+    let request = Request::post("/admin/purge").body(Body::from(r#"{"path": "abc.gif"}"#)).unwrap();
+    assert_eq!(send(&app, request).await.status(), 401);
+    let request = Request::post("/admin/purge").header("authorization", "Bearer admin").body(Body::from("abc.gif")).unwrap();
+    assert_eq!(send(&app, request).await.status(), 400);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn a_soft_purged_gif_is_sent_stale_while_its_made_again_once() {
+    let dir = setup("soft", HANGS);
+    let app = router::app(config(&dir)).await;
+    assert_eq!(get(&app).await.0, "MISS");
+    std::fs::write(dir.join("abc.mp4"), CHANGED).unwrap();
+    std::fs::write(dir.join("hang"), "").unwrap();
+
+    assert_eq!(purge(&app, "?mode=soft").await, json!({"mode": "soft", "purged": ["memory"]}));
+    // Everyone's sent the old GIF straight away, without it being made
+    // again for each of them
+    let (cache, response) = get(&app).await;
+    assert_eq!(cache, "STALE");
+    assert_eq!(response.headers()["warning"], "110 - \"Response is Stale\"");
+    assert_eq!(response.headers()["cache-control"], "no-store");
+    assert_eq!(body(response).await, VIDEO);
+    let started = Instant::now();
+    while conversions(&app).await == 0 {
+        assert!(started.elapsed() < Duration::from_secs(10), "never made again");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    for _ in 0..3 {
+        let (cache, response) = get(&app).await;
+        assert_eq!(cache, "STALE");
+        assert_eq!(body(response).await, VIDEO);
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(conversions(&app).await, 1);
+
+    // until it's been replaced
+    std::fs::remove_file(dir.join("hang")).unwrap();
+    let started = Instant::now();
+    loop {
+        let (cache, response) = get(&app).await;
+        if cache == "HIT" {
+            assert_eq!(body(response).await, CHANGED);
+            break;
+        }
+        assert_eq!(cache, "STALE");
+        assert!(started.elapsed() < Duration::from_secs(10), "never replaced");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(conversions(&app).await, 0);
+    let _ = std::fs::remove_dir_all(&dir);
+}
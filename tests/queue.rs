@@ -0,0 +1,181 @@
+//! Fills the conversion queue with conversions that wait for the test to let
+//! them go, and checks what `GET /queue` says about it, and whose turn comes
+//! first.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::Value;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use support::router::{self, send, setup, TAKES_A_WHILE, VIDEO};
+
+mod support;
+
+fn config(dir: &Path) -> Config {
+    Config {
+        max_concurrent_conversions: Some(1),
+        max_queue_depth: Some(5),
+        queue_wait_timeout: 30.0,
+        // They all come from the one client
+        max_concurrent_per_client: 0,
+        ..router::config(dir)
+    }
+}
+
+// A conversion with `priority`, left to wait its turn
+fn convert(app: &Router, priority: &'static str) -> tokio::task::JoinHandle<u16> {
+    let app = app.clone();
+    tokio::spawn(async move {
+        let request = Request::get("/tweet_video/abc.gif").header("x-fastgif-priority", priority).body(Body::empty()).unwrap();
+        let response = send(&app, request).await;
+        let status = response.status().as_u16();
+        let _ = to_bytes(response.into_body(), usize::MAX).await;
+        status
+    })
+}
+
+async fn queue(app: &Router) -> Value {
+    let response = send(app, Request::get("/queue").body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 200);
+    serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn the_queue_says_how_busy_it_is() {
+    let dir = setup("busy", TAKES_A_WHILE);
+    let app = router::app(config(&dir)).await;
+
+    // Nothing's been converted, so there's nothing to go by
+    let idle = queue(&app).await;
+    assert_eq!((idle["in_flight"].as_u64(), idle["running"].as_u64(), idle["queued"].as_u64()), (Some(0), Some(0), Some(0)), "{}", idle);
+    assert!(idle["average_conversion_ms"].is_null(), "{}", idle);
+    assert_eq!(idle["estimated_wait_secs"], 0.0);
+    assert_eq!(idle["shedding"], false);
+    assert_eq!((idle["max_concurrent"].as_u64(), idle["max_queue_depth"].as_u64()), (Some(1), Some(5)));
+    assert_eq!(idle["queue_wait_timeout_secs"], 30.0);
+
+    assert_eq!(convert(&app, "high").await.unwrap(), 200);
+    let average = queue(&app).await["average_conversion_ms"].as_u64().unwrap();
+    assert!((200..5000).contains(&average), "{}", average);
+
+    // One running, and three waiting behind it
+    std::fs::write(dir.join("hang"), "").unwrap();
+    let conversions = [convert(&app, "normal"), convert(&app, "high"), convert(&app, "high"), convert(&app, "low")];
+    let started = Instant::now();
+    let busy = loop {
+        let busy = queue(&app).await;
+        if busy["queued"] == 3 && busy["running"] == 1 {
+            break busy;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "{}", busy);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert_eq!(busy["in_flight"], 1);
+    let by_priority = &busy["queued_by_priority"];
+    let queued: u64 = ["high", "normal", "low"].iter().map(|priority| by_priority[priority].as_u64().unwrap()).sum();
+    assert_eq!(queued, 3, "{}", busy);
+    assert_eq!(by_priority["low"], 1, "{}", busy);
+    let estimated = busy["estimated_wait_secs"].as_f64().unwrap();
+    let expected = average as f64 * 3.0 / 1000.0;
+    assert!((estimated - expected).abs() < 0.01, "{} for an average of {}ms", estimated, average);
+
+    std::fs::remove_file(dir.join("hang")).unwrap();
+    for conversion in conversions {
+        assert_eq!(conversion.await.unwrap(), 200);
+    }
+    let after = queue(&app).await;
+    assert_eq!((after["running"].as_u64(), after["queued"].as_u64()), (Some(0), Some(0)), "{}", after);
+    assert_eq!(after["queued_by_priority"]["high"], 0);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn the_queue_can_be_kept_to_callers() {
+    let dir = setup("private", TAKES_A_WHILE);
+    let app = router::app(Config {
+        api_keys: vec!["key".to_string()],
+        admin_token: Some("admin".to_string()),
+        queue_status_public: false,
+        ..config(&dir)
+    })
+    .await;
+    let ask = |token: Option<&str>| {
+        let mut request = Request::get("/queue");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        send(&app, request.body(Body::empty()).unwrap())
+    };
+    assert_eq!(ask(None).await.status(), 401);
+    assert_eq!(ask(Some("key")).await.status(), 200);
+    assert_eq!(ask(Some("admin")).await.status(), 200);
+
+    // and is anyone's by default, keys or not
+    let app = router::app(Config { api_keys: vec!["key".to_string()], ..config(&dir) }).await;
+    assert_eq!(send(&app, Request::get("/queue").body(Body::empty()).unwrap()).await.status(), 200);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// A conversion of `path`, and when it was done
+fn convert_path(app: &Router, path: &'static str) -> tokio::task::JoinHandle<(u16, Instant)> {
+    let app = app.clone();
+    tokio::spawn(async move {
+        let response = send(&app, Request::get(format!("/tweet_video/{}", path)).body(Body::empty()).unwrap()).await;
+        let status = response.status().as_u16();
+        let _ = to_bytes(response.into_body(), usize::MAX).await;
+        (status, Instant::now())
+    })
+}
+
+// Once `queued` are waiting for a permit
+async fn queued(app: &Router, queued: u64) {
+    let started = Instant::now();
+    while queue(app).await["queued"] != queued {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn every_video_waiting_gets_a_turn_before_any_gets_another() {
+    let dir = setup("fair", TAKES_A_WHILE);
+    std::fs::write(dir.join("def.mp4"), VIDEO).unwrap();
+    let app = router::app(Config { admin_token: Some("admin".to_string()), ..config(&dir) }).await;
+
+    // One running, three more for the same video waiting behind it, and
+    // then one for another
+    std::fs::write(dir.join("hang"), "").unwrap();
+    let running = convert_path(&app, "abc.gif");
+    let mut same = Vec::new();
+    for count in 0..3 {
+        queued(&app, count).await;
+        same.push(convert_path(&app, "abc.gif"));
+    }
+    queued(&app, 3).await;
+    let other = convert_path(&app, "def.gif");
+    queued(&app, 4).await;
+
+    let request = Request::get("/admin/conversions").header("authorization", "Bearer admin").body(Body::empty()).unwrap();
+    let response = send(&app, request).await;
+    let conversions: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+    assert_eq!(conversions[0]["path"], "abc.gif", "{}", conversions);
+    assert_eq!(conversions[0]["queued_for_source"], 3, "{}", conversions);
+
+    std::fs::remove_file(dir.join("hang")).unwrap();
+    assert_eq!(running.await.unwrap().0, 200);
+    let (status, other) = other.await.unwrap();
+    assert_eq!(status, 200);
+    let mut done = Vec::new();
+    for conversion in same {
+        let (status, at) = conversion.await.unwrap();
+        assert_eq!(status, 200);
+        done.push(at);
+    }
+    // The first of the same video's turn came before it, and the rest after
+    assert!(done[0] < other);
+    assert!(done[1..].iter().all(|at| *at > other));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,75 @@
+//! Checks a client's token bucket over a simulated window: what's left goes
+//! down with each request, a refusal says how long until the next, and the
+//! bucket fills back up as the window goes by.
+
+use fastgif::rate_limit::{Checked, Rate, RateLimiter};
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+
+// To the millisecond, since the bucket's arithmetic isn't exact
+fn ms(took: Duration) -> u64 {
+    (took.as_secs_f64() * 1000.0).round() as u64
+}
+
+fn header(checked: &Checked, name: &str, legacy: bool) -> Option<String> {
+    let headers = checked.headers(legacy);
+    let (_, value) = headers.iter().find(|(header, _)| header.as_str() == name)?;
+    Some(value.to_str().unwrap().to_string())
+}
+
+#[test]
+fn whats_left_goes_down_and_fills_back_up_over_the_window() {
+    let limiter = RateLimiter::new(NonZeroUsize::new(16).unwrap());
+    let rate: Rate = "3/minute".parse().unwrap();
+    let start = Instant::now();
+
+    // A token every 20 seconds, and a new client starts with all three
+    let remaining: Vec<_> = (0..3).map(|_| limiter.check_at(CLIENT, rate, start)).collect();
+    assert!(remaining.iter().all(|checked| checked.allowed && checked.limit == 3));
+    assert_eq!(remaining.iter().map(|checked| checked.remaining).collect::<Vec<_>>(), [2, 1, 0]);
+    assert_eq!(ms(remaining[0].reset), 20_000);
+    assert_eq!(ms(remaining[2].reset), 60_000);
+
+    let refused = limiter.check_at(CLIENT, rate, start + Duration::from_secs(5));
+    assert!(!refused.allowed);
+    assert_eq!((refused.remaining, ms(refused.retry_after), ms(refused.reset)), (0, 15_000, 55_000));
+    assert_eq!(header(&refused, "ratelimit-reset", false).as_deref(), Some("55"));
+
+    // Once one's come back it can be taken
+    let later = limiter.check_at(CLIENT, rate, start + Duration::from_secs(20));
+    assert!(later.allowed);
+    assert_eq!((later.remaining, ms(later.reset)), (0, 60_000));
+
+    // and after the whole window the bucket's full again
+    let full = limiter.check_at(CLIENT, rate, start + Duration::from_secs(90));
+    assert!(full.allowed);
+    assert_eq!((full.remaining, ms(full.reset)), (2, 20_000));
+
+    // Other clients have buckets of their own
+    let other = limiter.check_at(IpAddr::from([192, 0, 2, 2]), rate, start + Duration::from_secs(5));
+    assert_eq!(other.remaining, 2);
+}
+
+#[test]
+fn the_headers_say_what_the_bucket_does() {
+    let limiter = RateLimiter::new(NonZeroUsize::new(16).unwrap());
+    let rate: Rate = "10/second".parse().unwrap();
+    let checked = limiter.check(CLIENT, rate);
+    assert_eq!(header(&checked, "ratelimit-limit", false).as_deref(), Some("10"));
+    assert_eq!(header(&checked, "ratelimit-remaining", false).as_deref(), Some("9"));
+    // Part of a second is rounded up
+    assert_eq!(header(&checked, "ratelimit-reset", false).as_deref(), Some("1"));
+    assert_eq!(header(&checked, "x-ratelimit-remaining", false), None);
+    assert_eq!(checked.headers(true).len(), 6);
+    assert_eq!(header(&checked, "x-ratelimit-remaining", true).as_deref(), Some("9"));
+
+    // Of two checks, the one that leaves less is what's said
+    let refused = Checked { allowed: false, remaining: 0, ..checked };
+    let fewer = Checked { remaining: 3, ..checked };
+    assert_eq!(checked.tighter(refused), refused);
+    assert_eq!(refused.tighter(fewer), refused);
+    assert_eq!(checked.tighter(fewer), fewer);
+}
@@ -0,0 +1,97 @@
+//! Runs the server with `REVALIDATE_AFTER` set, and checks a GIF whose video
+//! has since been taken down is forgotten by both caches, while one whose
+//! video is still there is kept.
+#![cfg(unix)]
+
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use support::router::{self, READS_INPUT, VIDEO};
+use support::server::spawn_server;
+
+mod support;
+
+// A directory of the test's own, with `abc.mp4`, `def.mp4`, a `cache`, and
+// an ffmpeg in it that passes on what it's given to read
+fn setup(test: &str) -> PathBuf {
+    let dir = router::setup(test, READS_INPUT);
+    std::fs::create_dir_all(dir.join("cache")).unwrap();
+    std::fs::write(dir.join("def.mp4"), VIDEO).unwrap();
+    dir
+}
+
+// The whole response to a GET of `path`, once the server's answering
+fn get(port: u16, path: &str) -> String {
+    let started = Instant::now();
+    loop {
+        if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)) {
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer admin\r\nConnection: close\r\n\r\n", path).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            return String::from_utf8_lossy(&response).into_owned();
+        }
+        assert!(started.elapsed() < Duration::from_secs(20), "the server never started");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// A GIF's status, and what its `X-Cache` said
+fn gif(port: u16, name: &str) -> (u16, String) {
+    let response = get(port, &format!("/tweet_video/{}.gif", name));
+    let status = response.get(9..12).and_then(|status| status.parse().ok()).unwrap_or(0);
+    let cache = response
+        .lines()
+        .find_map(|line| line.strip_prefix("x-cache: "))
+        .unwrap_or("")
+        .to_string();
+    (status, cache)
+}
+
+// How many GIFs are kept in memory and on disk
+fn kept(port: u16) -> (u64, u64) {
+    let response = get(port, "/stats");
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    let stats: Value = serde_json::from_str(body).unwrap();
+    (
+        stats["gif_cache"]["entries"].as_u64().unwrap(),
+        stats["gif_disk_cache"]["entries"].as_u64().unwrap(),
+    )
+}
+
+fn wait_until_kept(port: u16, wanted: (u64, u64), what: &str) {
+    let started = Instant::now();
+    while kept(port) != wanted {
+        assert!(started.elapsed() < Duration::from_secs(10), "{}: {:?}", what, kept(port));
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn a_gif_whose_video_has_gone_is_forgotten() {
+    let dir = setup("gone");
+    let (ffmpeg, cache) = (dir.join("ffmpeg"), dir.join("cache"));
+    let (_server, port) = spawn_server(&[
+        ("FFMPEG_PATH", ffmpeg.to_str().unwrap()),
+        ("SOURCE_DIR", dir.to_str().unwrap()),
+        ("TMP_DIR", dir.to_str().unwrap()),
+        ("CACHE_MAX_BYTES", "1048576"),
+        ("CACHE_DIR", cache.to_str().unwrap()),
+        ("JANITOR_INTERVAL", "1"),
+        ("REVALIDATE_AFTER", "1"),
+        ("ADMIN_TOKEN", "admin"),
+    ]);
+    assert_eq!(gif(port, "abc"), (200, "MISS".to_string()));
+    assert_eq!(gif(port, "def"), (200, "MISS".to_string()));
+    wait_until_kept(port, (2, 2), "never written to the cache");
+
+    std::fs::remove_file(dir.join("abc.mp4")).unwrap();
+    wait_until_kept(port, (1, 1), "never forgotten");
+    assert_eq!(gif(port, "abc").0, 404);
+
+    // while the other's still kept, a few rounds later
+    std::thread::sleep(Duration::from_millis(2500));
+    assert_eq!(gif(port, "def"), (200, "HIT".to_string()));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,202 @@
+//! Sends requests straight to the router, with no listener in between, and
+//! checks it answers the way the server does over a socket. Then converts
+//! through the pipeline on its own, and with a backend and source fetchers
+//! put in from outside.
+
+use axum::body::to_bytes;
+use axum::Router;
+use bytes::Bytes;
+use fastgif::config::Config;
+use fastgif::{AppState, LogFilter};
+use fastgif_core::backend::{Backend, Encoded, Format};
+use fastgif_core::fetch::{SourceFetcher, SourceRef, SourceStream};
+use fastgif_core::pipeline::{PipelineConfig, RunningEncoder};
+use fastgif_core::{ConversionError, ConversionOptions, Encoder, Source};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use futures_util::future::BoxFuture;
+use futures_util::stream::{self, StreamExt};
+use std::sync::Arc;
+use support::router;
+use support::server::{free_port, spawn_server};
+use tokio::io::AsyncWriteExt;
+use tokio::process::ChildStdout;
+use tokio_util::sync::CancellationToken;
+
+mod support;
+
+const PATHS: &[&str] = &["/version", "/livez", "/nowhere", "/tweet_video"];
+
+// The settings both the router and the server get: no binary check, ffmpeg
+// as the encoder, and no environment to pick anything else up from
+fn config() -> Config {
+    Config {
+        skip_binary_check: true,
+        max_input_duration: 0.0,
+        encoder: Some(Encoder::Ffmpeg),
+        ..Config::default()
+    }
+}
+
+// Send a GET through the router and return the status code and body
+async fn oneshot(app: &Router, path: &str) -> (u16, String) {
+    let response = router::get(app, path).await;
+    let status = response.status().as_u16();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+// Send a GET over a socket and return the status code and body
+fn get(port: u16, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default();
+    (status, body)
+}
+
+#[tokio::test]
+async fn router_answers_like_the_server() {
+    let config = config();
+    let state = AppState::new(&config, LogFilter::default(), false).await.unwrap();
+    let app = fastgif::app(&config, state);
+
+    let (_server, port) = spawn_server(&[]);
+
+    for path in PATHS {
+        assert_eq!(oneshot(&app, path).await, get(port, path), "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn pipeline_converts_on_its_own() {
+    let state = AppState::new(&config(), LogFilter::default(), false).await.unwrap();
+    // Nothing listens here, so whichever way the video is fetched, it isn't
+    let source = Source::Url(format!("http://127.0.0.1:{}/tweet_video/abc.mp4", free_port()));
+    let result = state.converter().convert(source, &ConversionOptions::default()).await;
+    assert!(result.is_err());
+}
+
+// The smallest GIF there is: one transparent pixel
+const PIXEL: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff\
+    !\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+
+// And the smallest WebP: one lossless pixel
+const WEBP: &[u8] = b"RIFF\x1a\x00\x00\x00WEBPVP8L\x0d\x00\x00\x00\x2f\x00\x00\x00\x10\x07\x10\x11\x11\x88\x88\xfe\x07\x00";
+
+// A backend that reads whatever ffmpeg writes and answers with `WEBP`
+#[derive(Debug)]
+struct Mock;
+
+impl Backend for Mock {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn format(&self) -> Format {
+        Format::Webp
+    }
+
+    fn start(
+        &self,
+        mut frames: ChildStdout,
+        _: &PipelineConfig,
+        _: &ConversionOptions,
+        _: &CancellationToken,
+    ) -> Result<(RunningEncoder, Encoded), ConversionError> {
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let encode = tokio::spawn(async move {
+            tokio::io::copy(&mut frames, &mut tokio::io::sink()).await.map_err(ConversionError::pipe("Failed to read frames"))?;
+            writer.write_all(WEBP).await.map_err(ConversionError::pipe("Failed to write the WebP"))
+        });
+        Ok((RunningEncoder::Task(encode), Box::new(reader)))
+    }
+}
+
+#[tokio::test]
+async fn registered_backend_makes_what_is_asked_for() {
+    // Writes a few bytes of "frames"
+    let dir = router::setup("backend", "printf frames");
+    let config = router::config(&dir);
+    let mut state = AppState::new(&config, LogFilter::default(), false).await.unwrap();
+    state.register_backend(Arc::new(Mock));
+    let app = fastgif::app(&config, state);
+
+    let response = router::get(&app, "/tweet_video/abc.gif?format=webp").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "image/webp");
+    assert_eq!(response.headers()["x-fastgif-encoder"], "mock");
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), WEBP);
+
+    let (status, body) = oneshot(&app, "/tweet_video/abc.gif?format=avif").await;
+    assert_eq!(status, 400);
+    assert!(body.contains("unsupported_format") && body.contains("gif, webp"), "{}", body);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+// Sources made up on the spot: `gone.mp4` isn't there, and anything else is
+// `PIXEL`
+#[derive(Debug)]
+struct Canned;
+
+impl SourceFetcher for Canned {
+    fn fetch<'a>(&'a self, source: &'a SourceRef) -> BoxFuture<'a, Result<SourceStream, ConversionError>> {
+        Box::pin(async move {
+            match source.path.as_str() {
+                "gone.mp4" => Err(ConversionError::UpstreamStatus { status: reqwest::StatusCode::NOT_FOUND, url: source.url.clone() }),
+                _ => Ok(stream::iter([Ok(Bytes::from_static(PIXEL))]).boxed()),
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn injected_fetcher_feeds_conversions() {
+    // With ffmpeg making GIFs, which here means passing on what it's fed
+    let dir = router::setup("fetcher", "exec cat");
+    let config = Config { source_dir: None, ..router::config(&dir) };
+    let mut state = AppState::new(&config, LogFilter::default(), false).await.unwrap();
+    state.set_source_fetcher(Arc::new(Canned));
+    let app = fastgif::app(&config, state);
+
+    let response = router::get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.status(), 200);
+    let etag = response.headers()["etag"].clone();
+    assert!(etag.to_str().unwrap().starts_with("W/\""), "{:?}", etag);
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), PIXEL);
+    // The same variant asked for another way is tagged the same
+    assert_eq!(router::get(&app, "/tweet_video/abc.gif?strict=1&x=y").await.headers()["etag"], etag);
+    assert_ne!(router::get(&app, "/tweet_video/abc.gif?repeat=2").await.headers()["etag"], etag);
+    let (status, body) = oneshot(&app, "/tweet_video/gone.gif").await;
+    assert_eq!(status, 404, "{}", body);
+
+    // Counted against the limit as it's fed, since it didn't say how big it is
+    let config = Config {
+        max_input_bytes: Some(10),
+        ..config
+    };
+    let mut state = AppState::new(&config, LogFilter::default(), false).await.unwrap();
+    state.set_source_fetcher(Arc::new(Canned));
+    let (status, body) = oneshot(&fastgif::app(&config, state), "/tweet_video/abc.gif").await;
+    assert_eq!(status, 413, "{}", body);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn source_dir_stands_in_for_the_upstream() {
+    let dir = router::setup("source-dir", "exec cat");
+    std::fs::write(dir.join("abc.mp4"), PIXEL).unwrap();
+    let config = router::config(&dir);
+    let state = AppState::new(&config, LogFilter::default(), false).await.unwrap();
+    let app = fastgif::app(&config, state);
+
+    let response = router::get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), PIXEL);
+    assert_eq!(oneshot(&app, "/tweet_video/missing.gif").await.0, 404);
+    // Nothing outside it, however it's asked for
+    assert_eq!(oneshot(&app, "/tweet_video/..%2F..%2Fetc%2Fpasswd").await.0, 400);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,165 @@
+//! Converts with an `S3_BUCKET` at a stand-in for S3, and checks that GIFs
+//! are uploaded there and handed out as presigned URLs, and that they're
+//! sent directly when an upload fails.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use support::router::{self, send, setup, PASSES_ON, VIDEO};
+
+mod support;
+
+// A request as it arrived: its request line, headers, lowercased, and body
+type Received = Arc<Mutex<Vec<(String, Vec<(String, String)>, Vec<u8>)>>>;
+
+// Answers every request with `status`, recording them
+fn bucket(status: u16) -> (u16, Received) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let received = Received::default();
+    let recorded = received.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            let _ = reader.read_line(&mut request_line);
+            let mut headers = Vec::new();
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).unwrap_or(0) <= 2 {
+                    break;
+                }
+                if let Some((name, value)) = line.trim().split_once(':') {
+                    headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+                }
+            }
+            let len = headers.iter().find(|(name, _)| name == "content-length").map_or(0, |(_, len)| len.parse().unwrap());
+            let mut body = vec![0; len];
+            let _ = reader.read_exact(&mut body);
+            recorded.lock().unwrap().push((request_line.trim().to_string(), headers, body));
+            let _ = write!(writer, "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+        }
+    });
+    (port, received)
+}
+
+fn config(dir: &Path, port: u16) -> Config {
+    Config {
+        s3_bucket: Some("gifs".to_string()),
+        s3_endpoint: Some(format!("http://127.0.0.1:{}", port)),
+        s3_access_key_id: Some("AKIDEXAMPLE".to_string()),
+        s3_secret_access_key: Some("secret".to_string()),
+        ..router::config(dir)
+    }
+}
+
+async fn get(app: &Router, uri: &str) -> Response {
+    send(app, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+async fn json(response: Response) -> Value {
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap_or_else(|_| panic!("not JSON: {}", String::from_utf8_lossy(&body)))
+}
+
+// The job `job`'s status, once it's done
+async fn finished_job(app: &Router, job: Value) -> Value {
+    let request = Request::post("/jobs").body(Body::from(job.to_string())).unwrap();
+    let id = json(send(app, request).await).await["id"].as_str().unwrap().to_string();
+    let started = Instant::now();
+    loop {
+        let status = json(get(app, &format!("/jobs/{}", id)).await).await;
+        if status["state"] == "done" {
+            return status;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "still {}", status);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+// Checks `url` is a presigned GET for an object the bucket was sent, and
+// that it's the GIF
+fn check_presigned(url: &str, port: u16, received: &Received) {
+    let object = url.strip_prefix(&format!("http://127.0.0.1:{}", port)).unwrap().split('?').next().unwrap();
+    let parts: Vec<&str> = object.split('/').collect();
+    // /gifs/fastgif/<year>/<month>/<day>/<name>.gif
+    assert_eq!(&parts[..3], ["", "gifs", "fastgif"], "{}", url);
+    assert_eq!([parts[3].len(), parts[4].len(), parts[5].len()], [4, 2, 2], "{}", url);
+    assert!(parts[6].ends_with(".gif"), "{}", url);
+    for param in ["X-Amz-Algorithm=AWS4-HMAC-SHA256", "X-Amz-Credential=AKIDEXAMPLE%2F", "X-Amz-Expires=3600", "X-Amz-SignedHeaders=host", "X-Amz-Signature="] {
+        assert!(url.contains(param), "{} isn't in {}", param, url);
+    }
+    let received = received.lock().unwrap();
+    let (_, headers, body) = received
+        .iter()
+        .find(|(line, ..)| line.starts_with(&format!("PUT {}?", object)))
+        .unwrap_or_else(|| panic!("{} wasn't uploaded", object));
+    assert_eq!(body, VIDEO);
+    assert!(headers.contains(&("content-type".to_string(), "image/gif".to_string())), "{:?}", headers);
+}
+
+#[tokio::test]
+async fn gifs_are_handed_out_from_the_bucket() {
+    let dir = setup("uploaded", PASSES_ON);
+    let (port, received) = bucket(200);
+    let app = router::app(config(&dir, port)).await;
+
+    // Only when it's asked for on the GET route
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    assert!(received.lock().unwrap().is_empty());
+    let response = get(&app, "/tweet_video/abc.gif?delivery=redirect").await;
+    assert_eq!(response.status(), 307);
+    assert_eq!(response.headers()["cache-control"], "no-store");
+    check_presigned(response.headers()["location"].to_str().unwrap(), port, &received);
+
+    // but always for batches and jobs
+    let request = Request::post("/batch").body(Body::from(json!([{"path": "abc.gif", "params": {"fps": 10}}]).to_string())).unwrap();
+    let outcomes = json(send(&app, request).await).await;
+    assert_eq!(outcomes[0]["status"], 200);
+    check_presigned(outcomes[0]["url"].as_str().unwrap(), port, &received);
+
+    let done = finished_job(&app, json!({"path": "abc.gif", "params": {"fps": 5}})).await;
+    check_presigned(done["download_url"].as_str().unwrap(), port, &received);
+    assert_eq!(done["bytes"], VIDEO.len());
+    let response = get(&app, done["result_url"].as_str().unwrap()).await;
+    assert_eq!(response.status(), 307);
+    check_presigned(response.headers()["location"].to_str().unwrap(), port, &received);
+    assert_eq!(received.lock().unwrap().len(), 3);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn gifs_are_sent_directly_when_uploads_fail() {
+    let dir = setup("failed", PASSES_ON);
+    let (port, received) = bucket(503);
+    let app = router::app(config(&dir, port)).await;
+
+    let response = get(&app, "/tweet_video/abc.gif?delivery=redirect").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+
+    let request = Request::post("/batch").body(Body::from(json!([{"path": "abc.gif"}]).to_string())).unwrap();
+    assert_eq!(json(send(&app, request).await).await[0]["url"], "/tweet_video/abc.gif");
+
+    let done = finished_job(&app, json!({"path": "abc.gif"})).await;
+    assert!(done.get("download_url").is_none(), "{}", done);
+    let response = get(&app, done["result_url"].as_str().unwrap()).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    assert_eq!(received.lock().unwrap().len(), 3);
+    let _ = std::fs::remove_dir_all(&dir);
+}
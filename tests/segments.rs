@@ -0,0 +1,136 @@
+//! Converts the same video in one go and with `PARALLEL_SEGMENTS`, and checks
+//! the joined GIF plays for as long. Needs real ffmpeg, ffprobe and gifski
+//! binaries, and passes without checking anything when they aren't installed.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use support::server::{free_port, server_command, start};
+
+mod support;
+
+// A small y4m video: 80x48, 10 fps, 9 s, so it splits into three segments
+fn fixture() -> Vec<u8> {
+    let (width, height, frames) = (80, 48, 90);
+    let mut video = format!("YUV4MPEG2 W{} H{} F10:1 Ip A1:1 C420jpeg\n", width, height).into_bytes();
+    for frame in 0..frames {
+        video.extend_from_slice(b"FRAME\n");
+        video.extend((0..width * height).map(|i| ((i + frame * 7) % 220 + 16) as u8));
+        video.extend(std::iter::repeat_n((frame * 2 % 256) as u8, width * height / 2));
+    }
+    video
+}
+
+// Answers every request with the fixture, honouring `Range: bytes=N-` so
+// ffmpeg can seek in it
+fn serve_fixture() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let video = fixture();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let mut request = [0u8; 4096];
+            let read = stream.read(&mut request).unwrap_or(0);
+            let request = String::from_utf8_lossy(&request[..read]).to_lowercase();
+            let from = request
+                .lines()
+                .find_map(|line| line.strip_prefix("range: bytes="))
+                .and_then(|range| range.split('-').next()?.trim().parse::<usize>().ok())
+                .filter(|from| *from < video.len());
+            let head = match from {
+                Some(from) => format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    from,
+                    video.len() - 1,
+                    video.len(),
+                    video.len() - from
+                ),
+                None => format!(
+                    "HTTP/1.1 200 OK\r\nAccept-Ranges: bytes\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    video.len()
+                ),
+            };
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(&video[from.unwrap_or(0)..]);
+        }
+    });
+    port
+}
+
+fn is_installed(name: &str, version_flag: &str, expected: &str) -> bool {
+    Command::new(name)
+        .arg(version_flag)
+        .stderr(Stdio::null())
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains(expected))
+}
+
+// Convert the fixture with a server started with `env`, returning the GIF
+fn convert(upstream: u16, env: &[(&str, &str)]) -> Vec<u8> {
+    let port = free_port();
+    let base_url = format!("http://127.0.0.1:{}", upstream);
+    let mut command = server_command(port, &[&[("VIDEO_BASE_URL", base_url.as_str()), ("ENCODER", "subprocess")], env].concat());
+    // With the real tools, so checked for, what they say shown, and the
+    // videos probed as they would be
+    command.env("SKIP_BINARY_CHECK", "false").env_remove("MAX_INPUT_DURATION").stdout(Stdio::inherit()).stderr(Stdio::inherit());
+    let _server = start(command, port);
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET /tweet_video/fixture.gif HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&response[..split]);
+    assert!(head.starts_with("HTTP/1.1 200"), "unexpected response: {}", head);
+    response[split + 4..].to_vec()
+}
+
+// How long a GIF plays for, in hundredths of a second, checking its structure
+// on the way
+fn play_time(gif: &[u8]) -> u32 {
+    assert!(gif.starts_with(b"GIF89a"), "body isn't a GIF");
+    let palette_len = |packed: u8| if packed & 0x80 != 0 { 3 << ((packed & 0x07) + 1) } else { 0 };
+    let skip_sub_blocks = |mut pos: usize| loop {
+        let len = gif[pos] as usize;
+        pos += 1 + len;
+        if len == 0 {
+            return pos;
+        }
+    };
+    let mut pos = 13 + palette_len(gif[10]);
+    let mut time = 0;
+    loop {
+        match gif[pos] {
+            0x21 => {
+                if gif[pos + 1] == 0xF9 {
+                    time += u16::from_le_bytes([gif[pos + 4], gif[pos + 5]]) as u32;
+                }
+                pos = skip_sub_blocks(pos + 2);
+            }
+            0x2C => pos = skip_sub_blocks(pos + 10 + palette_len(gif[pos + 9]) + 1),
+            0x3B => return time,
+            other => panic!("unexpected block 0x{:02x} at byte {}", other, pos),
+        }
+    }
+}
+
+#[test]
+fn segmented_conversion_has_every_frame() {
+    if !is_installed("ffmpeg", "-version", "ffmpeg version")
+        || !is_installed("ffprobe", "-version", "ffprobe version")
+        || !is_installed("gifski", "--version", "gifski")
+    {
+        eprintln!("ffmpeg, ffprobe or gifski isn't installed, skipping");
+        return;
+    }
+    let upstream = serve_fixture();
+
+    let serial = convert(upstream, &[]);
+    let segmented = convert(upstream, &[("PARALLEL_SEGMENTS", "3")]);
+
+    // gifski merges frames that look alike, which it does a little
+    // differently each side of a join, so it's how long they play for that
+    // has to match, not how many frames they have
+    assert_eq!(play_time(&segmented), play_time(&serial));
+}
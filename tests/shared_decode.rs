@@ -0,0 +1,150 @@
+//! Asks `POST /batch` and `POST /prefetch` for a video's `default` and
+//! `thumb` variants, with an ffmpeg that passes on what it's given to read,
+//! and checks the video is decoded once for both, each is converted and
+//! cached under its own key, and one failing leaves the other to be made.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use support::router::{self, send};
+
+mod support;
+
+// A directory of the test's own, with `abc.mp4`, and an ffmpeg in it that
+// passes on what it's given to read, leaving the decode's arguments as a
+// line in `decodes` and each conversion's in `runs`. With `thumb_fails`,
+// the conversion at 10 frames a second fails.
+fn setup(test: &str, thumb_fails: bool) -> PathBuf {
+    let failing = match thumb_fails {
+        true => "case \"$*\" in *fps=10,*) cat > /dev/null; echo 'Conversion failed!' >&2; exit 1;; esac",
+        false => "",
+    };
+    let ffmpeg = format!(
+        "here=\"$(dirname \"$0\")\"\n\
+         case \"$*\" in\n\
+         *yuv4mpegpipe*) echo \"$*\" >> \"$here/decodes\";;\n\
+         *-i*) echo \"$*\" >> \"$here/runs\";;\n\
+         esac\n\
+         {}\n\
+         while [ $# -gt 0 ]; do [ \"$1\" = \"-i\" ] && [ \"$2\" != pipe:0 ] && exec cat \"$2\"; shift; done\n\
+         exec cat",
+        failing
+    );
+    router::setup(test, &ffmpeg)
+}
+
+fn config(dir: &Path) -> Config {
+    Config {
+        // A slot for each variant, or they can't all be let in together
+        max_concurrent_conversions: Some(4),
+        max_concurrent_per_client: 0,
+        cache_max_bytes: Some(1 << 20),
+        ..router::config(dir)
+    }
+}
+
+async fn post(app: &Router, uri: &str, body: Value) -> (u16, String) {
+    let request = Request::post(uri).header("content-type", "application/json").body(Body::from(body.to_string())).unwrap();
+    let response = send(app, request).await;
+    let status = response.status().as_u16();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+async fn cache_status(app: &Router, uri: &str) -> String {
+    let response = send(app, Request::get(uri).body(Body::empty()).unwrap()).await;
+    response.headers().get("x-cache").map_or("", |value| value.to_str().unwrap()).to_string()
+}
+
+fn lines(dir: &Path, file: &str) -> Vec<String> {
+    std::fs::read_to_string(dir.join(file)).unwrap_or_default().lines().map(str::to_string).collect()
+}
+
+#[tokio::test]
+async fn both_variants_come_from_one_decode() {
+    let dir = setup("batch", false);
+    let app = router::app(config(&dir)).await;
+
+    let item = json!([{"path": "abc.gif", "params": {"width": 480}, "variants": ["default", "thumb"]}]);
+    let (status, body) = post(&app, "/batch", item).await;
+    assert_eq!(status, 200, "{}", body);
+    let outcomes: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(outcomes.as_array().unwrap().len(), 2, "{}", body);
+    assert_eq!(outcomes[0]["variant"], "default");
+    assert_eq!(outcomes[0]["status"], 200);
+    assert_eq!(outcomes[0]["url"], "/tweet_video/abc.gif?width=480");
+    assert_eq!(outcomes[1]["variant"], "thumb");
+    assert_eq!(outcomes[1]["status"], 200);
+    assert_eq!(outcomes[1]["url"], "/tweet_video/abc.gif?width=160&fps=10");
+
+    let decodes = lines(&dir, "decodes");
+    assert_eq!(decodes.len(), 1, "{:?}", decodes);
+    let runs = lines(&dir, "runs");
+    assert_eq!(runs.len(), 2, "{:?}", runs);
+    // Each converts what was decoded, not the video
+    assert!(runs.iter().all(|run| run.contains("-i pipe:0") && !run.contains("abc.mp4")), "{:?}", runs);
+    assert!(runs.iter().any(|run| run.contains("min(iw,480)") && run.contains(",fps=20,")), "{:?}", runs);
+    assert!(runs.iter().any(|run| run.contains("min(iw,160)") && run.contains(",fps=10,")), "{:?}", runs);
+
+    // and each is cached under its own key
+    assert_eq!(cache_status(&app, "/tweet_video/abc.gif?width=480").await, "HIT");
+    assert_eq!(cache_status(&app, "/tweet_video/abc.gif?width=160&fps=10").await, "HIT");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn one_variant_failing_leaves_the_other() {
+    let dir = setup("failing", true);
+    let app = router::app(config(&dir)).await;
+
+    let item = json!([{"path": "abc.gif", "variants": ["default", "thumb"]}]);
+    let (status, body) = post(&app, "/batch", item).await;
+    assert_eq!(status, 200, "{}", body);
+    let outcomes: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(outcomes[0]["status"], 200, "{}", body);
+    assert_ne!(outcomes[1]["status"], 200, "{}", body);
+    assert_eq!(outcomes[1]["variant"], "thumb");
+    assert_eq!(lines(&dir, "decodes").len(), 1);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn unknown_variants_are_refused() {
+    let dir = setup("unknown", false);
+    let app = router::app(config(&dir)).await;
+
+    let (status, body) = post(&app, "/batch", json!([{"path": "abc.gif", "variants": ["default", "huge"]}])).await;
+    assert_eq!(status, 200);
+    let outcomes: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(outcomes.as_array().unwrap().len(), 1, "{}", body);
+    assert_eq!(outcomes[0]["status"], 400);
+    assert!(outcomes[0]["error"].as_str().unwrap().starts_with("invalid_variant"), "{}", body);
+
+    let (status, body) = post(&app, "/prefetch", json!({"path": "abc.gif", "variants": ["thumb", "thumb"]})).await;
+    assert_eq!(status, 400);
+    assert!(body.contains("invalid_variant"), "{}", body);
+    assert!(lines(&dir, "runs").is_empty());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn prefetched_variants_share_a_decode_too() {
+    let dir = setup("prefetch", false);
+    let app = router::app(config(&dir)).await;
+
+    let (status, _) = post(&app, "/prefetch", json!({"path": "abc.gif", "variants": ["default", "thumb"]})).await;
+    assert_eq!(status, 202);
+    let started = Instant::now();
+    while lines(&dir, "runs").len() < 2 || cache_status(&app, "/tweet_video/abc.gif?width=160&fps=10").await != "HIT" {
+        assert!(started.elapsed() < Duration::from_secs(10), "the variants weren't prefetched");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(cache_status(&app, "/tweet_video/abc.gif").await, "HIT");
+    assert_eq!(lines(&dir, "decodes").len(), 1);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,87 @@
+//! Starts the server the way systemd does for socket activation, with
+//! listening sockets already open as fd 3 onwards, and checks it answers on
+//! those instead of binding `BIND` itself.
+#![cfg(unix)]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use support::server::{free_port, Server};
+
+mod support;
+
+// Send a GET and return the status code
+fn get(mut stream: impl Read + Write, path: &str) -> u16 {
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response[9..12].parse().unwrap()
+}
+
+#[test]
+fn serves_on_the_sockets_it_was_passed() {
+    let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+    let tcp_port = tcp.local_addr().unwrap().port();
+    let socket = std::env::temp_dir().join(format!("fastgif-activation-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket);
+    let unix = UnixListener::bind(&socket).unwrap();
+    let fds = [tcp.as_raw_fd(), unix.as_raw_fd()];
+    // What BIND would have had it listen on
+    let port = free_port();
+
+    // LISTEN_PID has to be the server's own, which the shell knows once it's
+    // about to become the server
+    let mut command = Command::new("/bin/sh");
+    command
+        .args(["-c", "LISTEN_PID=$$ exec \"$0\"", env!("CARGO_BIN_EXE_fastgif")])
+        .env("LISTEN_FDS", "2")
+        .env("LISTEN_FDNAMES", "web:local")
+        .env("PORT", port.to_string())
+        .env("ADMIN_LISTENERS", format!("unix:{}", socket.display()))
+        .env("MAX_INPUT_DURATION", "0")
+        .env("ENCODER", "ffmpeg")
+        .env("SKIP_BINARY_CHECK", "true")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // Put the sockets where systemd would, as fds 3 and 4 without close-on-exec
+    unsafe {
+        command.pre_exec(move || {
+            // Out of the way first, since either could be on 3 or 4 already.
+            // Nothing may allocate here, between fork and exec.
+            let mut moved = [0; 2];
+            for (moved, fd) in moved.iter_mut().zip(fds) {
+                *moved = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 100);
+                if *moved == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            for (target, fd) in (3..).zip(moved) {
+                if libc::dup2(fd, target) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let _server = Server(command.spawn().unwrap());
+    drop((tcp, unix));
+
+    // The sockets were listening all along, so connections just wait for it
+    let started = Instant::now();
+    let stream = TcpStream::connect(("127.0.0.1", tcp_port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    assert_eq!(get(stream, "/version"), 200);
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert_eq!(get(UnixStream::connect(&socket).unwrap(), "/version"), 200);
+
+    // Listeners are told apart by address, for ADMIN_LISTENERS
+    assert_eq!(get(UnixStream::connect(&socket).unwrap(), "/metrics"), 200);
+    assert_eq!(get(TcpStream::connect(("127.0.0.1", tcp_port)).unwrap(), "/metrics"), 404);
+
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err(), "BIND was listened on too");
+    let _ = std::fs::remove_file(socket);
+}
@@ -0,0 +1,115 @@
+//! Asks `GET /tweet_video/{path}/sprite` for sprite sheets, and checks
+//! ffmpeg is asked for the frames and grid that were, that the headers say
+//! where the frames are, that a sheet is only made once, and that ffmpeg
+//! failing is answered with why. With ffmpeg installed, it checks one it
+//! made really is the size asked for.
+#![cfg(unix)]
+
+use axum::body::to_bytes;
+use fastgif::config::Config;
+use std::path::{Path, PathBuf};
+use support::router::{self, get, header};
+use support::stills::{self, runs};
+use support::is_installed;
+
+mod support;
+
+const PROBED: &str = r#"{"streams": [{"width": 1280, "height": 720, "avg_frame_rate": "30/1"}], "format": {"duration": "8.000000"}}"#;
+
+// What the fake ffmpeg makes, passed on as the sheet
+const IMAGE: &[u8] = b"\x89PNG\r\n\x1a\nnot really";
+
+// A directory of the test's own, with the image and probe above faked
+fn setup(test: &str) -> PathBuf {
+    stills::setup(test, "tile=", IMAGE, PROBED)
+}
+
+#[tokio::test]
+async fn frames_are_taken_evenly_and_tiled() {
+    let dir = setup("tiled");
+    let app = router::app(router::config(&dir)).await;
+
+    let response = get(&app, "/tweet_video/abc.mp4/sprite").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(header(&response, "content-type"), "image/png");
+    assert_eq!(header(&response, "x-cache"), "MISS");
+    assert_eq!(header(&response, "x-fastgif-sprite-grid"), "5x4");
+    // 160 wide keeps 16:9 at 90 high, and 20 frames over 8 seconds are 0.4 apart
+    assert_eq!(header(&response, "x-fastgif-sprite-cell"), "160x90");
+    assert_eq!(header(&response, "x-fastgif-sprite-interval"), "0.400");
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), IMAGE);
+    let runs = runs(&dir);
+    assert_eq!(runs.len(), 1);
+    assert!(runs[0].contains("-vf fps=20/8.000,scale=160:90,setsar=1,tile=5x4 -frames:v 1 -c:v png"), "{}", runs[0]);
+
+    // and once it's made, it's kept
+    let response = get(&app, "/tweet_video/abc.mp4/sprite?cols=5&rows=4&width=160").await;
+    assert_eq!(header(&response, "x-cache"), "HIT");
+    assert_eq!(self::runs(&dir).len(), 1);
+
+    // A grid of its own is a sheet of its own
+    let response = get(&app, "/tweet_video/abc.mp4/sprite?cols=2&rows=3&width=101&format=jpeg").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(header(&response, "content-type"), "image/jpeg");
+    assert_eq!(header(&response, "x-cache"), "MISS");
+    assert_eq!(header(&response, "x-fastgif-sprite-cell"), "101x56");
+    assert_eq!(header(&response, "x-fastgif-sprite-interval"), "1.333");
+    let runs = self::runs(&dir);
+    assert_eq!(runs.len(), 2);
+    assert!(runs[1].contains("-vf fps=6/8.000,scale=101:56,setsar=1,tile=2x3 -frames:v 1 -c:v mjpeg"), "{}", runs[1]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn grids_past_the_caps_are_refused() {
+    let dir = setup("caps");
+    let app = router::app(router::config(&dir)).await;
+    for query in ["cols=11", "rows=0", "width=321", "width=1", "format=gif"] {
+        let response = get(&app, &format!("/tweet_video/abc.mp4/sprite?{}", query)).await;
+        assert_eq!(response.status(), 400, "{}", query);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("invalid_grid"), "{}", query);
+    }
+    assert!(runs(&dir).is_empty());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn ffmpeg_failing_says_why() {
+    let dir = setup("unsupported");
+    router::tool(&dir, "ffmpeg", "echo 'abc.mp4: moov atom not found' >&2\nexit 1");
+    let app = router::app(router::config(&dir)).await;
+    let response = get(&app, "/tweet_video/abc.mp4/sprite").await;
+    assert_eq!(response.status(), 422);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("unsupported_source"), "{}", String::from_utf8_lossy(&body));
+    // and nothing's kept, so it's tried again
+    assert_eq!(header(&get(&app, "/tweet_video/abc.mp4/sprite").await, "x-cache"), "");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn the_sheet_is_as_big_as_its_cells() {
+    if !is_installed("ffmpeg") || !is_installed("ffprobe") {
+        eprintln!("ffmpeg or ffprobe isn't installed, skipping");
+        return;
+    }
+    let dir = router::tools_dir("fixture");
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/clip.y4m");
+    std::fs::copy(fixture, dir.join("clip.mp4")).unwrap();
+    // with the real ffmpeg and ffprobe
+    let config = Config { ffmpeg_path: None, ffprobe_path: None, ..router::config(&dir) };
+    let app = router::app(config).await;
+
+    let response = get(&app, "/tweet_video/clip.mp4/sprite?cols=3&rows=2&width=16").await;
+    assert_eq!(response.status(), 200);
+    // The clip is square
+    assert_eq!(header(&response, "x-fastgif-sprite-cell"), "16x16");
+    let png = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    // The IHDR chunk comes first, with the width and height
+    let width = u32::from_be_bytes(png[16..20].try_into().unwrap());
+    let height = u32::from_be_bytes(png[20..24].try_into().unwrap());
+    assert_eq!((width, height), (3 * 16, 2 * 16));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,88 @@
+//! Converts a GIF and sends it again from the cache, and checks `/stats`
+//! sums that up, and only says as much as `STATS_ACCESS` lets it.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::Value;
+use std::path::Path;
+use support::router::{self, send, setup, READS_INPUT, VIDEO};
+
+mod support;
+
+fn config(dir: &Path, stats_access: &str) -> Config {
+    Config {
+        cache_max_bytes: Some(1024 * 1024),
+        admin_token: Some("admin".to_string()),
+        stats_access: stats_access.parse().unwrap(),
+        ..router::config(dir)
+    }
+}
+
+async fn get(app: &Router) {
+    let response = send(app, Request::get("/tweet_video/abc.gif").body(Body::empty()).unwrap()).await;
+    assert_eq!(response.status(), 200);
+    to_bytes(response.into_body(), usize::MAX).await.unwrap();
+}
+
+async fn stats(app: &Router, token: Option<&str>) -> (u16, Value) {
+    let mut request = Request::get("/stats");
+    if let Some(token) = token {
+        request = request.header("authorization", format!("Bearer {}", token));
+    }
+    let response = send(app, request.body(Body::empty()).unwrap()).await;
+    let status = response.status().as_u16();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn the_summary_sums_up_what_happened() {
+    let dir = setup("summary", READS_INPUT);
+    let app = router::app(config(&dir, "public")).await;
+    get(&app).await;
+    get(&app).await;
+
+    let (status, stats) = stats(&app, None).await;
+    assert_eq!(status, 200);
+    assert!(stats["uptime_secs"].is_u64(), "{}", stats);
+    assert_eq!(stats["requests"], 2, "{}", stats);
+    assert_eq!(stats["conversions"]["succeeded"], 1, "{}", stats);
+    assert_eq!(stats["conversions"]["failed"], 0, "{}", stats);
+    assert_eq!(stats["conversions"]["failed_by_class"], serde_json::json!({}));
+    assert_eq!(stats["in_flight"], 0);
+    assert_eq!(stats["queued"], 0);
+    assert_eq!(stats["cache_hit_ratio"], 0.5);
+    assert_eq!(stats["bytes_served"], 2 * VIDEO.len());
+    let latency = &stats["latency"];
+    assert_eq!((latency["window_secs"].as_u64(), latency["conversions"].as_u64()), (Some(300), Some(1)), "{}", latency);
+    let (p50, p99) = (latency["p50_ms"].as_u64().unwrap(), latency["p99_ms"].as_u64().unwrap());
+    assert!(p50 <= p99, "{}", latency);
+    // with everything else, since it's public
+    assert!(stats["admission"].is_object(), "{}", stats);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn the_details_can_be_kept_for_the_admin_token() {
+    let dir = setup("access", READS_INPUT);
+    let app = router::app(config(&dir, "summary")).await;
+    let (status, summary) = stats(&app, None).await;
+    assert_eq!(status, 200);
+    assert!(summary["requests"].is_u64(), "{}", summary);
+    assert!(summary.get("admission").is_none(), "{}", summary);
+    let (_, detailed) = stats(&app, Some("admin")).await;
+    assert!(detailed["admission"].is_object(), "{}", detailed);
+
+    let app = router::app(config(&dir, "admin")).await;
+    assert_eq!(stats(&app, None).await.0, 401);
+    assert_eq!(stats(&app, Some("wrong")).await.0, 401);
+    assert!(stats(&app, Some("admin")).await.1["admission"].is_object());
+
+    // and there has to be one
+    let config = Config { admin_token: None, ..config(&dir, "summary") };
+    assert!(config.problems().iter().any(|problem| problem.contains("STATS_ACCESS")), "{:?}", config.problems());
+    let _ = std::fs::remove_dir_all(&dir);
+}
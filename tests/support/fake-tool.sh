@@ -0,0 +1,85 @@
+#!/bin/sh
+# Stands in for ffmpeg or gifski, whichever it's run as (through a link of
+# that name), so tests can have the server's children misbehave without
+# either being installed. Asked what it is or what it can do, it answers like
+# a recent release. Converting, it does what the environment says, with
+# FAKE_FFMPEG_* or FAKE_GIFSKI_* depending on which it is:
+#
+#   _PIDFILE       a file to write its pid to, and its sleep's to <file>.child
+#   _IGNORE_TERM   1 to ignore SIGTERM, along with anything it starts
+#   _STDERR_LINES  how many numbered lines to spam stderr with
+#   _STDERR        a last line for stderr, like ffmpeg's reason for failing
+#   _READ_BYTES    how much of its input to read before going on (all of it)
+#   _BYTES         how many bytes to write to stdout after that, dying of
+#                  SIGPIPE like ffmpeg does if nothing reads them
+#   _OUTPUT        a file to write to stdout instead, like a whole GIF
+#   _SLEEP         seconds to sleep after that, or `forever`, in a child
+#   _SIGNAL        a signal to kill itself with after that, like SEGV
+#   _EXIT          the status to exit with otherwise (0)
+#   _ONCE          a file it makes the first time it's run, only doing what
+#                  _SIGNAL and _EXIT say while that file isn't there yet
+
+name=$(basename "$0")
+case "$name" in
+    ffmpeg) prefix=FAKE_FFMPEG ;;
+    gifski) prefix=FAKE_GIFSKI ;;
+    *) echo "fake-tool.sh has to be run as ffmpeg or gifski, not $name" >&2; exit 2 ;;
+esac
+
+case "$name $*" in
+    "ffmpeg -version") echo "ffmpeg version 6.1-fake Copyright (c) 2000-2023 the FFmpeg developers"; exit 0 ;;
+    "gifski --version") echo "gifski 1.32.0"; exit 0 ;;
+    "gifski --help") printf '      --fast\n      --repeat <num>\n'; exit 0 ;;
+    "ffmpeg -hide_banner -muxers"|"ffmpeg -hide_banner -encoders")
+        printf ' E = Muxing supported\n --\n E yuv4mpegpipe    YUV4MPEG pipe\n V..... gif    GIF\n'
+        exit 0
+        ;;
+esac
+
+setting() {
+    eval "printf '%s' \"\${${prefix}_$1:-$2}\""
+}
+
+pidfile=$(setting PIDFILE)
+[ -n "$pidfile" ] && echo $$ > "$pidfile"
+[ "$(setting IGNORE_TERM)" = 1 ] && trap '' TERM
+
+i=1
+while [ "$i" -le "$(setting STDERR_LINES 0)" ]; do
+    echo "$name: line $i" >&2
+    i=$((i + 1))
+done
+[ -n "$(setting STDERR)" ] && setting STDERR >&2 && echo >&2
+
+# Reading frames or the video from a pipe, rather than fetching it
+case " $* " in
+    *" pipe:0 "*|*" - ")
+        read=$(setting READ_BYTES)
+        if [ -n "$read" ]; then head -c "$read" > /dev/null; else cat > /dev/null; fi
+        ;;
+esac
+
+output=$(setting OUTPUT)
+if [ -n "$output" ]; then
+    cat "$output" || kill -s PIPE $$
+else
+    head -c "$(setting BYTES 0)" /dev/zero || kill -s PIPE $$
+fi
+
+sleep=$(setting SLEEP)
+if [ -n "$sleep" ]; then
+    [ "$sleep" = forever ] && sleep=3600
+    sleep "$sleep" &
+    [ -n "$pidfile" ] && echo $! > "$pidfile.child"
+    wait $!
+fi
+
+once=$(setting ONCE)
+if [ -n "$once" ]; then
+    [ -e "$once" ] && exit 0
+    : > "$once"
+fi
+
+signal=$(setting SIGNAL)
+[ -n "$signal" ] && kill -s "$signal" $$
+exit "$(setting EXIT 0)"
@@ -0,0 +1,70 @@
+//! What the tests have in common: a seeded generator for the ones that throw
+//! random inputs at things, whether a real tool is installed for the ones
+//! that need it, in `router`, the stand-in tools and the router the ones that
+//! convert something go through, in `server`, the server run as its own
+//! process, and in `stills`, the stand-ins for sprite sheets and frames. A
+//! fuzz failure says which seed to run again with `FASTGIF_FUZZ_SEED`, and
+//! `FASTGIF_FUZZ_CASES` runs more cases. (`fake-tool.sh` beside it is run by
+//! the tests rather than compiled into them.)
+
+// Every test has all of it, and uses only some
+#![allow(dead_code)]
+
+pub mod router;
+pub mod server;
+pub mod stills;
+
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// xorshift64*: plenty for picking inputs, and the same every run for a seed
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn seeded() -> (Self, u64) {
+        let seed = std::env::var("FASTGIF_FUZZ_SEED")
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64);
+        (Rng(seed.max(1)), seed)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+
+    // Mostly what names look like, with the characters that matter mixed in
+    pub fn string(&mut self, max_len: usize) -> String {
+        const PIECES: &[&str] = &[
+            "a", "Z", "0", "9", "_", "-", ".", "..", "/", "\\", "%", "%2F", "?", "#", "&", "=", "+", " ", "\0", "\n",
+            "\r", "\t", "\u{7f}", "é", "\u{202e}", "\u{fffd}", ".gif", ".mp4", "gif", "~", ":",
+        ];
+        let len = self.below(max_len + 1);
+        (0..len).map(|_| *self.pick(PIECES)).collect()
+    }
+}
+
+pub fn cases(default: usize) -> usize {
+    std::env::var("FASTGIF_FUZZ_CASES").ok().and_then(|cases| cases.parse().ok()).unwrap_or(default)
+}
+
+/// Whether `name` is on the `PATH` and says it's `name` when asked its
+/// version, as ffmpeg and ffprobe do.
+pub fn is_installed(name: &str) -> bool {
+    Command::new(name)
+        .arg("-version")
+        .stderr(Stdio::null())
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains(&format!("{} version", name)))
+}
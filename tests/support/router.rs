@@ -0,0 +1,134 @@
+//! A server's router, without a listener, reading its videos from a
+//! directory of the test's own with stand-in tools in it, and sending it
+//! requests as one client would.
+
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use fastgif_core::Encoder;
+use fastgif::{AppState, Connection, LogFilter};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tower::ServiceExt;
+
+/// A whole GIF, of one pixel, for an ffmpeg that passes on what it's fed to
+/// make.
+pub const VIDEO: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff\
+    !\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+
+/// An ffmpeg that passes on what it's fed.
+pub const PASSES_ON: &str = "exec cat";
+
+/// An ffmpeg that passes on what it's given to read, piped or a file.
+pub const READS_INPUT: &str =
+    "while [ $# -gt 0 ]; do [ \"$1\" = \"-i\" ] && [ \"$2\" != pipe:0 ] && exec cat \"$2\"; shift; done\nexec cat";
+
+/// An ffmpeg that waits for as long as there's a `hang` file beside it before
+/// passing on what it's fed.
+pub const HANGS: &str =
+    "case \"$*\" in *pipe:0*) while [ -e \"$(dirname \"$0\")/hang\" ]; do sleep 0.05; done;; esac\nexec cat";
+
+/// An ffmpeg like `HANGS` that takes a little while even without a `hang`.
+pub const TAKES_A_WHILE: &str =
+    "case \"$*\" in *pipe:0*) sleep 0.2; while [ -e \"$(dirname \"$0\")/hang\" ]; do sleep 0.05; done;; esac\nexec cat";
+
+/// What a tool the test hasn't faked does when it's run: fails, saying so,
+/// rather than whatever's on this machine's `PATH` being run in its place.
+pub const NOT_FAKED: &str = "echo \"$(basename \"$0\") isn't faked for this test\" >&2\nexit 127";
+
+/// An empty directory of the test's own, named after its file and `test`.
+pub fn temp_dir(test: &str) -> PathBuf {
+    let suite = env!("CARGO_CRATE_NAME").replace('_', "-");
+    let dir = std::env::temp_dir().join(format!("fastgif-{}-{}-{}", suite, test, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A directory of the test's own with each of the tools `config` points at
+/// in it, all `NOT_FAKED` until the test fakes them with `tool`.
+#[cfg(unix)]
+pub fn tools_dir(test: &str) -> PathBuf {
+    let dir = temp_dir(test);
+    for name in ["ffmpeg", "ffprobe", "gifski"] {
+        tool(&dir, name, NOT_FAKED);
+    }
+    dir
+}
+
+/// A directory of the test's own, with an ffmpeg in it that runs `script`,
+/// the other tools `NOT_FAKED`, and `abc.mp4` to convert.
+#[cfg(unix)]
+pub fn setup(test: &str, script: &str) -> PathBuf {
+    let dir = tools_dir(test);
+    tool(&dir, "ffmpeg", script);
+    std::fs::write(dir.join("abc.mp4"), VIDEO).unwrap();
+    dir
+}
+
+/// `name` in `dir`, a shell script that runs `script`.
+#[cfg(unix)]
+pub fn tool(dir: &Path, name: &str, script: &str) {
+    let path = dir.join(name);
+    std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// `ffmpeg` and `gifski` in `dir`, both `fake-tool.sh`, told how to behave
+/// by `FAKE_FFMPEG_*` and `FAKE_GIFSKI_*`.
+#[cfg(unix)]
+pub fn fake_tools(dir: &Path) {
+    let tool = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/support/fake-tool.sh");
+    for name in ["ffmpeg", "gifski"] {
+        let _ = std::fs::remove_file(dir.join(name));
+        std::os::unix::fs::symlink(&tool, dir.join(name)).unwrap();
+    }
+}
+
+/// How a test's server is set up unless the test says otherwise: reading its
+/// videos from `dir` and converting them with the ffmpeg there alone, without
+/// probing them first or checking what the binaries can do. Every tool is the
+/// one in `dir`, never one from the `PATH`, so one the test hasn't faked fails
+/// loudly, as does one that isn't there. Tests change what they need with
+/// `Config { .., ..config(&dir) }`.
+pub fn config(dir: &Path) -> Config {
+    Config {
+        ffmpeg_path: Some(dir.join("ffmpeg")),
+        ffprobe_path: Some(dir.join("ffprobe")),
+        gifski_path: Some(dir.join("gifski")),
+        encoder: Some(Encoder::Ffmpeg),
+        source_dir: Some(dir.to_path_buf()),
+        skip_binary_check: true,
+        max_input_duration: 0.0,
+        ..Config::default()
+    }
+}
+
+/// The router a server set up with `config` serves: `config(dir)` changed as
+/// the test needs, or for the few tests that want the real binaries, built
+/// on `Config::default()`.
+pub async fn app(config: Config) -> Router {
+    let state = AppState::new(&config, LogFilter::default(), false).await.unwrap();
+    fastgif::app(&config, state)
+}
+
+/// What `app` answers `request` with, as if it came from 127.0.0.1.
+pub async fn send(app: &Router, mut request: Request<Body>) -> Response {
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+    request.extensions_mut().insert(ConnectInfo(Connection::from(peer)));
+    app.clone().oneshot(request).await.unwrap()
+}
+
+/// What `app` answers a `GET` of `uri` with.
+pub async fn get(app: &Router, uri: &str) -> Response {
+    send(app, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+/// `response`'s `name` header, or `""` without one.
+pub fn header<'a>(response: &'a Response, name: &str) -> &'a str {
+    response.headers().get(name).map_or("", |value| value.to_str().unwrap())
+}
@@ -0,0 +1,61 @@
+//! The server itself, run as its own process on a port of its own, for the
+//! tests that need what only a listener or the binary does.
+
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// A running server, killed when it's dropped.
+pub struct Server(pub Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A port nothing's listening on, for now.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// The server on a port of its own, with `env`, once it's listening.
+pub fn spawn_server(env: &[(&str, &str)]) -> (Server, u16) {
+    let port = free_port();
+    (start(server_command(port, env), port), port)
+}
+
+/// The server's command, to listen on `port` with the binary check skipped,
+/// videos of any length converted by ffmpeg alone whatever gifski this
+/// machine has, nothing read or written on its standard streams, and `env`
+/// on top of that.
+pub fn server_command(port: u16, env: &[(&str, &str)]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_fastgif"));
+    command
+        .env("PORT", port.to_string())
+        .env("SKIP_BINARY_CHECK", "true")
+        .env("MAX_INPUT_DURATION", "0")
+        .env("ENCODER", "ffmpeg")
+        .envs(env.iter().copied())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    command
+}
+
+/// `command` run, once it's listening on `port`.
+pub fn start(mut command: Command, port: u16) -> Server {
+    let server = Server(command.spawn().unwrap());
+    wait_for(port);
+    server
+}
+
+/// Once something's listening on `port`.
+pub fn wait_for(port: u16) {
+    let started = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(started.elapsed() < Duration::from_secs(20), "the server never started");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,34 @@
+//! The stand-ins the sprite sheet and frame routes are tested with: an
+//! ffmpeg that answers with an image of the test's choosing and notes each
+//! time it's asked for one, and an ffprobe that says what the video is.
+
+use super::router;
+use std::path::{Path, PathBuf};
+
+/// An ffmpeg that answers with the `image` beside it, leaving its arguments
+/// as a line in `runs` when they have `marker` in them, which tells a still
+/// being made from ffmpeg being asked what it can do.
+pub fn ffmpeg(marker: &str) -> String {
+    format!(
+        "case \"$*\" in *{}*) echo \"$*\" >> \"$(dirname \"$0\")/runs\";; esac\nexec cat \"$(dirname \"$0\")/image\"",
+        marker
+    )
+}
+
+/// A directory of the test's own with `abc.mp4`, the `ffmpeg` for `marker`
+/// making `image`, an ffprobe that answers with `probed`, and the other tools
+/// `NOT_FAKED`.
+#[cfg(unix)]
+pub fn setup(test: &str, marker: &str, image: &[u8], probed: &str) -> PathBuf {
+    let dir = router::tools_dir(test);
+    std::fs::write(dir.join("image"), image).unwrap();
+    router::tool(&dir, "ffmpeg", &ffmpeg(marker));
+    router::tool(&dir, "ffprobe", &format!("echo '{}'", probed));
+    std::fs::write(dir.join("abc.mp4"), b"a video").unwrap();
+    dir
+}
+
+/// The arguments of each still ffmpeg was asked for, in order.
+pub fn runs(dir: &Path) -> Vec<String> {
+    std::fs::read_to_string(dir.join("runs")).unwrap_or_default().lines().map(str::to_string).collect()
+}
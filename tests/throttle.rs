@@ -0,0 +1,78 @@
+//! Checks that `THROTTLE_BYTES_PER_SEC` paces a big response. A stand-in
+//! ffmpeg script makes the GIF, so this only runs on Unix.
+#![cfg(unix)]
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::time::Instant;
+use support::server::spawn_server;
+
+mod support;
+
+const BODY_BYTES: usize = 1024 * 1024;
+const BYTES_PER_SEC: usize = 256 * 1024;
+
+// 1 MiB of GIF: a pixel, after a comment of zeroes that makes up the rest
+fn big_gif() -> Vec<u8> {
+    let mut gif = b"GIF89a\x01\x00\x01\x00\x00\x00\x00!\xfe".to_vec();
+    let image = b",\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+    // Sub-blocks of up to 255 bytes, leaving one for the end of them
+    while BODY_BYTES - gif.len() - image.len() > 1 {
+        let len = (BODY_BYTES - gif.len() - image.len() - 2).min(255);
+        gif.push(len as u8);
+        gif.extend(vec![0; len]);
+    }
+    gif.push(0);
+    gif.extend(image);
+    assert_eq!(gif.len(), BODY_BYTES);
+    gif
+}
+
+// An ffmpeg that ignores its arguments and writes that
+fn fake_ffmpeg() -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("throttle-bin");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("big.gif"), big_gif()).unwrap();
+    let ffmpeg = dir.join("ffmpeg");
+    std::fs::write(&ffmpeg, format!("#!/bin/sh\nexec cat {}\n", dir.join("big.gif").display())).unwrap();
+    std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+    dir
+}
+
+#[test]
+fn big_response_is_paced() {
+    let bin = fake_ffmpeg();
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default());
+    let (_server, port) = spawn_server(&[
+        ("PATH", &path),
+        ("THROTTLE_BYTES_PER_SEC", &BYTES_PER_SEC.to_string()),
+        ("THROTTLE_MIN_BYTES", "0"),
+    ]);
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET /tweet_video/video.gif HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    // Timed from the first byte, so only sending the body counts
+    let mut first = [0u8; 1];
+    stream.read_exact(&mut first).unwrap();
+    let sending = Instant::now();
+    let mut response = first.to_vec();
+    stream.read_to_end(&mut response).unwrap();
+    let took = sending.elapsed();
+
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&response[..split]);
+    assert!(head.starts_with("HTTP/1.1 200"), "unexpected response: {}", head);
+    assert_eq!(response.len() - split - 4, BODY_BYTES);
+
+    // 4 s at this rate, give or take the first piece and a slow machine
+    let expected = BODY_BYTES as f64 / BYTES_PER_SEC as f64;
+    assert!(
+        took.as_secs_f64() > expected * 0.8 && took.as_secs_f64() < expected * 1.5,
+        "sending {} bytes at {} bytes/s took {:?}",
+        BODY_BYTES,
+        BYTES_PER_SEC,
+        took
+    );
+}
@@ -0,0 +1,128 @@
+//! Converts videos ffprobe says have an alpha channel with an ffmpeg that
+//! writes down its command line, and checks their transparency is carried
+//! through to the encoder, or flattened onto `?bg=`.
+#![cfg(unix)]
+
+use axum::body::to_bytes;
+use axum::Router;
+use fastgif::config::Config;
+use fastgif_core::Encoder;
+use std::path::{Path, PathBuf};
+use support::router::{self, get, tool, PASSES_ON};
+
+mod support;
+
+const OPAQUE: &str = "codec_name=h264\npix_fmt=yuv420p\nduration=2.000000";
+const IN_PIXELS: &str = "codec_name=ffv1\npix_fmt=yuva420p\nduration=2.000000";
+// As sticker WebMs come: the alpha channel's beside the frames, not in them
+const STICKER: &str = "codec_name=vp9\npix_fmt=yuv420p\nTAG:alpha_mode=1\nduration=2.000000";
+
+// One pixel of GIF, for ffmpeg to pass on as what it made
+const GIF: &str = r"printf 'GIF89a\001\000\001\000\000\000\000,\000\000\000\000\001\000\001\000\000\002\002D\001\000;'";
+// All a PNG needs to be read as one, for ffmpeg to write a frame of
+const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\0IEND\xaeB`\x82";
+const PRINT_PNG: &str = r"printf '\211PNG\r\n\032\n\000\000\000\000IEND\256B`\202'";
+
+// A directory of the test's own, with `abc.mp4`, an ffmpeg in it that
+// writes its command line to `said` and two PNGs when asked for them, a
+// gifski that passes on what it makes or, given frames, copies them to
+// `frames`, and an ffprobe that answers with `probed`
+fn setup(test: &str, probed: &str) -> PathBuf {
+    let dir = router::setup(
+        test,
+        &format!(
+            "cat > /dev/null\nprintf '%s\\n' \"$*\" > \"$(dirname \"$0\")/said\"\ncase \"$*\" in *image2pipe*) {png}; {png} ;; *) {gif} ;; esac",
+            png = PRINT_PNG,
+            gif = GIF
+        ),
+    );
+    tool(&dir, "gifski", &format!("case \"$*\" in *.png*) ;; *) {} ;; esac\nfor arg; do case \"$arg\" in *.png) cat \"$arg\" >> \"$(dirname \"$0\")/frames\" ;; esac; done\n{}", PASSES_ON, GIF));
+    tool(&dir, "ffprobe", &format!("printf '{}\\n'", probed.replace('\n', "\\n")));
+    std::fs::write(dir.join("abc.mp4"), b"not really a video").unwrap();
+    dir
+}
+
+fn config(dir: &Path, encoder: Encoder) -> Config {
+    Config {
+        encoder: Some(encoder),
+        // Probed, for ffprobe to say whether there's an alpha channel
+        max_input_duration: 60.0,
+        ..router::config(dir)
+    }
+}
+
+// What ffmpeg was told to do for `uri`
+async fn ffmpeg_args(app: &Router, dir: &Path, uri: &str) -> String {
+    let response = get(app, uri).await;
+    assert_eq!(response.status(), 200);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(body.starts_with(b"GIF89a"), "{:?}", body);
+    std::fs::read_to_string(dir.join("said")).unwrap()
+}
+
+#[tokio::test]
+async fn gifski_is_handed_pngs_of_transparent_videos() {
+    let dir = setup("pngs", IN_PIXELS);
+    let app = router::app(config(&dir, Encoder::Subprocess)).await;
+    let args = ffmpeg_args(&app, &dir, "/tweet_video/abc.gif").await;
+    assert!(args.contains("-c:v png -pix_fmt rgba -f image2pipe"), "{}", args);
+    assert!(!args.contains("yuv4mpegpipe"), "{}", args);
+    // which it can only read as files, all there by the time it runs
+    assert_eq!(std::fs::read(dir.join("frames")).unwrap(), [PNG, PNG].concat());
+
+    // and y4m, flattened onto the background, when there is one
+    let args = ffmpeg_args(&app, &dir, "/tweet_video/abc.gif?bg=%23FF8800").await;
+    assert!(args.contains("drawbox=c=0xff8800@1:replace=1:t=fill"), "{}", args);
+    assert!(args.contains("-f yuv4mpegpipe"), "{}", args);
+
+    assert_eq!(get(&app, "/tweet_video/abc.gif?bg=orange").await.status(), 400);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn gifski_is_handed_more_frames_than_their_paths_would_fit_in_argv() {
+    // ffmpeg writes `FRAMES` PNGs, and gifski counts the ones it's given
+    const FRAMES: usize = 50_000;
+    let dir = setup("many_pngs", IN_PIXELS);
+    tool(
+        &dir,
+        "ffmpeg",
+        &format!("cat > /dev/null\ncase \"$*\" in *image2pipe*) i=0; while [ $i -lt {} ]; do {}; i=$((i + 1)); done ;; *) {} ;; esac", FRAMES, PRINT_PNG, GIF),
+    );
+    tool(&dir, "gifski", &format!("n=0\nfor arg; do case \"$arg\" in *.png) [ -f \"$arg\" ] && n=$((n + 1)) ;; esac; done\necho $n > \"$(dirname \"$0\")/frames\"\n{}", GIF));
+    let config = config(&dir, Encoder::Subprocess);
+    // Each frame's full path would take up far more than `ARG_MAX` between them
+    let path = std::env::temp_dir().join("fastgif-instance-0000000-0000000000000000/frame-00000.png");
+    assert!(FRAMES * (path.as_os_str().len() + 1 + std::mem::size_of::<usize>()) > 2 * 1024 * 1024);
+    let app = router::app(config).await;
+
+    let response = get(&app, "/tweet_video/abc.gif").await;
+    assert_eq!(response.status(), 200);
+    let frames = std::fs::read_to_string(dir.join("frames")).unwrap();
+    assert_eq!(frames.trim(), FRAMES.to_string());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn stickers_are_decoded_with_their_alpha_channel() {
+    let dir = setup("sticker", STICKER);
+    let app = router::app(config(&dir, Encoder::Ffmpeg)).await;
+    let args = ffmpeg_args(&app, &dir, "/tweet_video/abc.gif").await;
+    // libvpx is the only VP9 decoder that reads it, as an input option
+    let decoder = args.find("-c:v libvpx-vp9").expect(&args);
+    assert!(decoder < args.find("-i ").unwrap(), "{}", args);
+    assert!(args.contains("reserve_transparent=1") && args.contains("alpha_threshold=128"), "{}", args);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn opaque_videos_are_converted_as_ever() {
+    let dir = setup("opaque", OPAQUE);
+    let app = router::app(config(&dir, Encoder::Subprocess)).await;
+    for uri in ["/tweet_video/abc.gif", "/tweet_video/abc.gif?bg=ffffff"] {
+        let args = ffmpeg_args(&app, &dir, uri).await;
+        assert!(args.contains("-f yuv4mpegpipe"), "{}", args);
+        assert!(!args.contains("libvpx") && !args.contains("drawbox"), "{}", args);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,143 @@
+//! Asks for GIFs of sources there's nothing in to convert, an audio-only M4A
+//! and an MP4 cut short before its index, and checks they're refused with a
+//! `422` saying why, without gifski ever being started when the probe has
+//! already said so. The fixtures themselves need real ffmpeg and ffprobe
+//! binaries, and pass without checking anything when they aren't installed.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::Router;
+use fastgif::config::Config;
+use fastgif::Connection;
+use fastgif_core::Encoder;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use support::router;
+use tower::ServiceExt;
+
+mod support;
+
+const TRUNCATED: &[u8] = include_bytes!("fixtures/truncated.mp4");
+
+// What ffprobe says of each, as real ones do
+const AUDIO_ONLY_PROBED: &str = "echo duration=4.021000";
+const TRUNCATED_PROBED: &str = "echo '[mov,mp4,m4a,3gp,3g2,mj2 @ 0x55b8f0a6f900] moov atom not found' >&2\n\
+    echo 'abc.mp4: Invalid data found when processing input' >&2\n\
+    exit 1";
+
+// A directory of the test's own with `tools` in it, as scripts, the rest not
+// faked, and an `abc.mp4`. Each of ffmpeg and gifski leaves a line in `spawned` when it's
+// run to convert something, rather than to say what it can do, before doing
+// what it's told.
+fn setup(test: &str, tools: &[(&str, &str)]) -> PathBuf {
+    let dir = router::tools_dir(test);
+    for (tool, script) in tools {
+        let spawned = match *tool {
+            "ffmpeg" | "gifski" => {
+                "case \"$*\" in *-version|*--help|*-muxers|*-encoders) ;; *) basename \"$0\" >> \"$(dirname \"$0\")/spawned\";; esac\n"
+            }
+            _ => "",
+        };
+        router::tool(&dir, tool, &format!("{}{}", spawned, script));
+    }
+    std::fs::write(dir.join("abc.mp4"), b"not really a video").unwrap();
+    dir
+}
+
+fn config(dir: &Path) -> Config {
+    Config {
+        encoder: Some(Encoder::Subprocess),
+        ..router::config(dir)
+    }
+}
+
+// The status and error body of converting `abc.mp4`
+async fn convert(app: &Router) -> (u16, Value) {
+    let mut request = Request::get("/tweet_video/abc.gif").body(Body::empty()).unwrap();
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+    request.extensions_mut().insert(ConnectInfo(Connection::from(peer)));
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status().as_u16();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+fn spawned(dir: &Path) -> Vec<String> {
+    std::fs::read_to_string(dir.join("spawned")).unwrap_or_default().lines().map(str::to_string).collect()
+}
+
+fn is_installed(name: &str) -> bool {
+    Command::new(name)
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+#[tokio::test]
+async fn nothing_is_started_for_what_the_probe_says_cant_be_converted() {
+    for (test, ffprobe, reason) in [
+        ("audio", AUDIO_ONLY_PROBED, "it has no video stream"),
+        ("truncated", TRUNCATED_PROBED, "it's an MP4 that was cut short, or never finished being written"),
+    ] {
+        let dir = setup(test, &[("ffmpeg", "exec cat"), ("gifski", "exec cat"), ("ffprobe", ffprobe)]);
+        let app = router::app(Config { max_input_duration: 60.0, ..config(&dir) }).await;
+        let (status, body) = convert(&app).await;
+        assert_eq!(status, 422, "{}: {}", test, body);
+        assert_eq!(body["error"], "unsupported_source", "{}", test);
+        assert_eq!(body["message"], format!("Failed to process video: Source has nothing to convert: {}", reason));
+        assert!(spawned(&dir).is_empty(), "{}: {:?}", test, spawned(&dir));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
+
+#[tokio::test]
+async fn ffmpeg_finding_no_video_stream_is_unsupported_too() {
+    // Nothing needs the video's duration, so it isn't probed
+    let ffmpeg = "cat > /dev/null\n\
+        echo '[out#0/yuv4mpegpipe @ 0x55d5e1b7a2c0] Output file does not contain any stream' >&2\n\
+        exit 1";
+    let dir = setup("ffmpeg", &[("ffmpeg", ffmpeg), ("gifski", "cat > /dev/null")]);
+    let app = router::app(Config { max_input_duration: 0.0, ..config(&dir) }).await;
+    let (status, body) = convert(&app).await;
+    assert_eq!(status, 422, "{}", body);
+    assert_eq!(body["error"], "unsupported_source");
+    assert_eq!(body["message"], "Failed to process video: Source has nothing to convert: it has no video stream");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn fixtures_are_refused_with_or_without_a_probe() {
+    if !is_installed("ffmpeg") || !is_installed("ffprobe") {
+        eprintln!("ffmpeg or ffprobe isn't installed, skipping");
+        return;
+    }
+    // 1 s of a tone, and nothing else
+    let audio_only = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-f", "lavfi", "-i", "sine=frequency=440:duration=1"])
+        .args(["-c:a", "aac", "-f", "ipod", "-movflags", "frag_keyframe+empty_moov", "-"])
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap();
+    assert!(audio_only.status.success(), "ffmpeg couldn't make the audio-only fixture");
+
+    for (test, video) in [("real-audio", audio_only.stdout.as_slice()), ("real-truncated", TRUNCATED)] {
+        for max_input_duration in [60.0, 0.0] {
+            let dir = setup(test, &[("gifski", "exec cat")]);
+            std::fs::write(dir.join("abc.mp4"), video).unwrap();
+            // The real ffmpeg and ffprobe, from the `PATH`
+            let app = router::app(Config { max_input_duration, ffmpeg_path: None, ffprobe_path: None, ..config(&dir) }).await;
+            let (status, body) = convert(&app).await;
+            assert_eq!((status, &body["error"]), (422, &Value::from("unsupported_source")), "{}: {}", test, body);
+            if max_input_duration > 0.0 {
+                assert!(spawned(&dir).is_empty(), "{}: {:?}", test, spawned(&dir));
+            }
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+}
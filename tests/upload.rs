@@ -0,0 +1,197 @@
+//! Uploads videos to `POST /convert` through the router, with an ffmpeg that
+//! passes on the file it's told to read, and checks what's sent back, what
+//! may be cached, the size limit and that the uploads don't outlive their
+//! requests.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use bytes::Bytes;
+use fastgif::config::Config;
+use fastgif::Connection;
+use futures_util::stream;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use support::router;
+use tower::ServiceExt;
+
+mod support;
+
+// Not really an mp4, but a whole GIF, which an ffmpeg that passes on what
+// it reads makes a GIF of
+const VIDEO: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff\
+    !\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+
+const BOUNDARY: &str = "fastgif-test-boundary";
+
+// A directory of the test's own, with an ffmpeg in it that writes out the
+// file it's given and a `tmp` for the uploads
+fn setup(test: &str) -> PathBuf {
+    let dir = router::setup(test, "while [ $# -gt 0 ]; do [ \"$1\" = \"-i\" ] && exec cat \"$2\"; shift; done");
+    std::fs::create_dir_all(dir.join("tmp")).unwrap();
+    dir
+}
+
+fn config(dir: &Path) -> Config {
+    Config {
+        tmp_dir: Some(dir.join("tmp")),
+        ..router::config(dir)
+    }
+}
+
+async fn post(app: &Router, path: &str, headers: &[(&str, &str)], body: Body) -> Response {
+    let mut request = Request::post(path);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let mut request = request.body(body).unwrap();
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+    request.extensions_mut().insert(ConnectInfo(Connection::from(peer)));
+    app.clone().oneshot(request).await.unwrap()
+}
+
+// A form with `video` as its `file` part, after `fields`
+fn form(fields: &[(&str, &str)], video: &[u8]) -> Vec<u8> {
+    let mut form = Vec::new();
+    for (name, value) in fields {
+        form.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", BOUNDARY, name, value).bytes());
+    }
+    form.extend(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"clip.mp4\"\r\nContent-Type: video/mp4\r\n\r\n",
+            BOUNDARY
+        )
+        .bytes(),
+    );
+    form.extend(video);
+    form.extend(format!("\r\n--{}--\r\n", BOUNDARY).bytes());
+    form
+}
+
+fn multipart() -> String {
+    format!("multipart/form-data; boundary={}", BOUNDARY)
+}
+
+// Whether every upload in `dir` has been removed, given a moment for it.
+// The instance's own directory in `tmp` stays until it stops.
+async fn cleaned_up(dir: &Path) -> bool {
+    let files = || {
+        let instances = std::fs::read_dir(dir.join("tmp")).unwrap().flatten();
+        instances.flat_map(|instance| std::fs::read_dir(instance.path()).into_iter().flatten()).count()
+    };
+    let started = Instant::now();
+    while files() > 0 {
+        if started.elapsed() > Duration::from_secs(5) {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    true
+}
+
+#[tokio::test]
+async fn uploads_are_converted_and_not_cached() {
+    let dir = setup("raw");
+    let app = router::app(config(&dir)).await;
+
+    let response = post(&app, "/convert", &[("content-type", "video/mp4")], Body::from(VIDEO)).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "image/gif");
+    assert_eq!(response.headers()["cache-control"], "no-store");
+    assert!(response.headers().get("etag").is_none());
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+
+    let response = post(&app, "/convert?width=480", &[("content-type", &multipart())], Body::from(form(&[("repeat", "2")], VIDEO))).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), VIDEO);
+    assert!(cleaned_up(&dir).await);
+
+    let response = post(&app, "/convert", &[("content-type", "text/plain")], Body::from(VIDEO)).await;
+    assert_eq!(response.status(), 400);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("invalid_upload"));
+    let response = post(&app, "/convert", &[("content-type", &multipart())], Body::from(format!("--{}--\r\n", BOUNDARY))).await;
+    assert_eq!(response.status(), 400);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn idempotency_keys_make_uploads_cacheable() {
+    let dir = setup("idempotent");
+    // Replaying responses is off, so a key can be sent with other parameters
+    let app = router::app(Config { idempotency_ttl: 0, ..config(&dir) }).await;
+    let upload = |key: &'static str, query: &'static str| {
+        let app = app.clone();
+        async move {
+            let path = format!("/convert{}", query);
+            let headers = [("content-type", "video/mp4"), ("idempotency-key", key)];
+            post(&app, &path, &headers, Body::from(VIDEO)).await
+        }
+    };
+
+    let response = upload("abc", "").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["cache-control"], "public, max-age=31536000");
+    let etag = response.headers()["etag"].clone();
+    assert_eq!(upload("abc", "?strict=1").await.headers()["etag"], etag);
+    assert_ne!(upload("abc", "?fps=10").await.headers()["etag"], etag);
+    assert_ne!(upload("def", "").await.headers()["etag"], etag);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn uploads_over_the_limit_are_refused_as_they_arrive() {
+    let dir = setup("limit");
+    let app = router::app(Config { max_upload_bytes: 16, ..config(&dir) }).await;
+
+    // Said up front
+    let response = post(&app, "/convert", &[("content-type", "video/mp4")], Body::from(VIDEO)).await;
+    assert_eq!(response.status(), 413);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("input_too_large"));
+
+    // Found out while reading, from a body that would go on for ever
+    let endless = stream::repeat_with(|| Ok::<_, std::io::Error>(Bytes::from_static(VIDEO)));
+    let response = post(&app, "/convert", &[("content-type", "video/mp4")], Body::from_stream(endless)).await;
+    assert_eq!(response.status(), 413);
+
+    let response = post(&app, "/convert", &[("content-type", &multipart())], Body::from(form(&[], VIDEO))).await;
+    assert_eq!(response.status(), 413);
+    assert!(cleaned_up(&dir).await);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn uploads_past_what_temp_files_are_allowed_are_refused() {
+    let dir = setup("temp-space");
+    let app = router::app(Config { temp_max_bytes: Some(16), ..config(&dir) }).await;
+
+    let response = post(&app, "/convert", &[("content-type", "video/mp4")], Body::from(VIDEO)).await;
+    assert_eq!(response.status(), 507);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("more than the 16 bytes allowed"), "{}", String::from_utf8_lossy(&body));
+    assert!(cleaned_up(&dir).await);
+
+    // and with room for it, it's taken
+    let app = router::app(Config { temp_max_bytes: Some(1024), ..config(&dir) }).await;
+    let response = post(&app, "/convert", &[("content-type", "video/mp4")], Body::from(VIDEO)).await;
+    assert_eq!(response.status(), 200);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn uploads_need_an_api_key_when_conversions_do() {
+    let dir = setup("keys");
+    let app = router::app(Config { api_keys: vec!["secret".to_string()], ..config(&dir) }).await;
+
+    let response = post(&app, "/convert", &[("content-type", "video/mp4")], Body::from(VIDEO)).await;
+    assert_eq!(response.status(), 401);
+    let headers = [("content-type", "video/mp4"), ("authorization", "Bearer secret")];
+    let response = post(&app, "/convert", &headers, Body::from(VIDEO)).await;
+    assert_eq!(response.status(), 200);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,83 @@
+//! Checks that upstream requests share a pooled connection instead of each
+//! opening their own.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use support::server::spawn_server;
+
+mod support;
+
+// Answers HEAD requests with a small Content-Length on kept-alive connections,
+// recording how many requests each connection carried. Anything else gets a 404.
+fn serve_upstream() -> (u16, Arc<Mutex<Vec<usize>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(Mutex::new(Vec::new()));
+    let recorded = connections.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let connections = connections.clone();
+            std::thread::spawn(move || {
+                let index = {
+                    let mut connections = connections.lock().unwrap();
+                    connections.push(0);
+                    connections.len() - 1
+                };
+                let mut writer = stream.try_clone().unwrap();
+                let mut reader = BufReader::new(stream);
+                loop {
+                    let mut request_line = String::new();
+                    if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                        return;
+                    }
+                    // Skip the headers; none of these requests have a body
+                    let mut header = String::new();
+                    while reader.read_line(&mut header).unwrap_or(0) > 2 {
+                        header.clear();
+                    }
+                    connections.lock().unwrap()[index] += 1;
+                    let response: &[u8] = if request_line.starts_with("HEAD ") {
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n"
+                    } else {
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+                    };
+                    if writer.write_all(response).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (port, recorded)
+}
+
+fn get(port: u16, path: &str) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+}
+
+#[test]
+fn sequential_requests_reuse_a_connection() {
+    let (upstream, connections) = serve_upstream();
+    // MAX_INPUT_BYTES makes every conversion start with a HEAD request from
+    // the shared client. Whatever happens after that (there may be no ffmpeg
+    // to run) doesn't matter here.
+    let base_url = format!("http://127.0.0.1:{}", upstream);
+    let (_server, port) = spawn_server(&[("VIDEO_BASE_URL", &base_url), ("MAX_INPUT_BYTES", "1000000")]);
+
+    get(port, "/tweet_video/first.gif");
+    get(port, "/tweet_video/second.gif");
+
+    // An ffmpeg that's installed fetches the video itself on connections of
+    // its own, but both HEAD requests have to have gone over the same one
+    let connections = connections.lock().unwrap();
+    assert!(
+        connections.iter().any(|requests| *requests >= 2),
+        "no connection was reused: {:?}",
+        *connections
+    );
+}
@@ -0,0 +1,116 @@
+//! Converts with API keys held to quotas, and checks what each key used is
+//! counted, reported with `GET /usage` and `GET /admin/keys/{name}/usage`,
+//! and kept across a restart with a `JOB_DB_PATH`.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use fastgif::config::Config;
+use serde_json::Value;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use support::router::{self, setup, PASSES_ON, VIDEO};
+
+mod support;
+
+fn config(dir: &Path) -> Config {
+    Config {
+        admin_token: Some("admin".to_string()),
+        api_keys: vec!["one".to_string(), "two".to_string()],
+        ..router::config(dir)
+    }
+}
+
+async fn send(app: &Router, uri: &str, authorization: Option<&str>) -> Response {
+    let mut request = Request::get(uri);
+    if let Some(authorization) = authorization {
+        request = request.header("authorization", format!("Bearer {}", authorization));
+    }
+    router::send(app, request.body(Body::empty()).unwrap()).await
+}
+
+// Convert with `key`, returning the status and the body
+async fn convert(app: &Router, key: &str, width: u32) -> (u16, Vec<u8>) {
+    let response = send(app, &format!("/tweet_video/abc.gif?width={}", width), Some(key)).await;
+    let status = response.status().as_u16();
+    (status, to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec())
+}
+
+async fn usage(app: &Router, uri: &str, authorization: &str) -> Value {
+    let response = send(app, uri, Some(authorization)).await;
+    assert_eq!(response.status(), 200);
+    serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+}
+
+#[tokio::test]
+async fn keys_are_held_to_their_quotas() {
+    let dir = setup("quotas", PASSES_ON);
+    let app = router::app(Config { api_key_quotas: Some("key-1=2/-".to_string()), quota_cpu_seconds_per_day: 3600, ..config(&dir) }).await;
+    assert_eq!(convert(&app, "one", 100).await, (200, VIDEO.to_vec()));
+    assert_eq!(convert(&app, "one", 200).await, (200, VIDEO.to_vec()));
+
+    let response = send(&app, "/tweet_video/abc.gif?width=300", Some("one")).await;
+    assert_eq!(response.status(), 429);
+    let retry_after: u64 = response.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+    assert!((1..=86_400).contains(&retry_after));
+    let refused: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+    assert_eq!((refused["error"].as_str(), refused["quota"].as_str(), refused["limit"].as_u64()), (Some("quota_exceeded"), Some("conversions"), Some(2)));
+    assert!(refused["resets_at"].as_i64().unwrap() > now_ms());
+    // and it's the key that's out, not anyone else
+    assert_eq!(convert(&app, "two", 300).await.0, 200);
+
+    let used = usage(&app, "/usage", "one").await;
+    assert_eq!(used["key"], "key-1");
+    assert_eq!(used["today"]["conversions"], 2);
+    assert_eq!(used["today"]["output_bytes"], 2 * VIDEO.len());
+    assert_eq!(used["quota"]["conversions_per_day"], 2);
+    assert_eq!(used["quota"]["cpu_seconds_per_day"], Value::Null);
+    assert_eq!(used["days"].as_array().unwrap().len(), 1);
+    assert_eq!(used["resets_at"], refused["resets_at"]);
+
+    // An admin can ask about any key
+    let theirs = usage(&app, "/admin/keys/key-2/usage", "admin").await;
+    assert_eq!(theirs["today"]["conversions"], 1);
+    assert_eq!(theirs["quota"]["cpu_seconds_per_day"], 3600);
+    assert_eq!(send(&app, "/admin/keys/key-3/usage", Some("admin")).await.status(), 404);
+    assert_eq!(send(&app, "/admin/keys/key-1/usage", Some("one")).await.status(), 401);
+    assert_eq!(send(&app, "/usage", None).await.status(), 401);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn usage_outlasts_restarts() {
+    let dir = setup("restarted", PASSES_ON);
+    let restartable = || Config { job_db_path: Some(dir.join("jobs.db")), quota_conversions_per_day: 1, ..config(&dir) };
+    let before = router::app(restartable()).await;
+    assert_eq!(convert(&before, "one", 100).await.0, 200);
+
+    // Written behind the request's back
+    let started = Instant::now();
+    loop {
+        let after = router::app(restartable()).await;
+        if usage(&after, "/usage", "one").await["today"]["conversions"] == 1 {
+            assert_eq!(convert(&after, "one", 200).await.0, 429);
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn quotas_have_to_make_sense() {
+    for spec in ["key-1=lots/-", "key-1", "key-1=5", "key-1=5/-,key-1=6/-"] {
+        let config = Config { api_key_quotas: Some(spec.to_string()), ..Config::default() };
+        assert!(config.problems().iter().any(|problem| problem.contains("API_KEY_QUOTAS")), "{}", spec);
+    }
+    let config = Config { api_key_quotas: Some("partner-a=1000/3600, partner-b=-/600".to_string()), ..Config::default() };
+    assert!(config.problems().is_empty(), "{:?}", config.problems());
+}
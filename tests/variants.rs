@@ -0,0 +1,244 @@
+//! Derives the keys (`VariantKey`) of random requests under random
+//! configurations, and checks what caches rely on them for: a key changes
+//! with everything that changes what's made, and with nothing else. The order
+//! of the query parameters, the ones that only say how to answer a failure or
+//! who's asking, and settings that only change how the server runs all leave
+//! it as it was. Like `parsing.rs`, this says which seed found a failure.
+
+use axum::extract::Query;
+use axum::http::Uri;
+use fastgif_core::auto_quality::Bucket;
+use fastgif_core::backend::FfmpegWebp;
+use fastgif::clip::LongVideoPolicy;
+use fastgif::config::Config;
+use fastgif::variant::{ConversionQuery, VariantKey};
+use fastgif::{AppState, LogFilter};
+use fastgif_core::Encoder;
+use std::sync::Arc;
+use std::time::Duration;
+use support::{cases, Rng};
+
+mod support;
+
+const PATHS: &[&str] = &["FfyEjQ_WIAAd7rg.mp4", "abc.mp4", "a-b_c.mp4"];
+const OPTIMIZE: &[&str] = &["1", "true", "yes", "0", "no", ""];
+const FORMATS: &[&str] = &["gif", "webp"];
+const STRICT: &[&str] = &["1", "0", ""];
+const FPS: &[f32] = &[5.0, 10.0, 12.5, 20.0, 30.0];
+const DURATIONS: &[f64] = &[0.0, 5.0, 60.0];
+const POLICIES: &[Option<LongVideoPolicy>] = &[None, Some(LongVideoPolicy::Reject), Some(LongVideoPolicy::Trim)];
+const CLIP_SECONDS: &[&str] = &["0", "1.5", "4", "90"];
+const SEGMENTS: &[Option<usize>] = &[None, Some(2), Some(4)];
+
+// The settings that go into a key, and some that mustn't
+#[derive(Debug, Clone)]
+struct Setup {
+    quality: Option<u8>,
+    fast: Option<bool>,
+    fps: Option<f32>,
+    encoder: Encoder,
+    max_input_duration: f64,
+    auto_trim: bool,
+    long_video_policy: Option<LongVideoPolicy>,
+    parallel_segments: Option<usize>,
+    stream_response: bool,
+    // None of these change what's made
+    conversion_timeout: f64,
+    max_output_bytes: Option<u64>,
+    error_detail: bool,
+    strict_params: bool,
+}
+
+impl Setup {
+    fn random(rng: &mut Rng) -> Self {
+        Setup {
+            quality: (rng.below(2) == 0).then(|| 1 + rng.below(100) as u8),
+            fast: *rng.pick(&[None, Some(false), Some(true)]),
+            fps: (rng.below(2) == 0).then(|| *rng.pick(FPS)),
+            encoder: *rng.pick(&[Encoder::Subprocess, Encoder::Ffmpeg]),
+            max_input_duration: *rng.pick(DURATIONS),
+            auto_trim: rng.below(2) == 0,
+            long_video_policy: *rng.pick(POLICIES),
+            parallel_segments: *rng.pick(SEGMENTS),
+            stream_response: rng.below(2) == 0,
+            conversion_timeout: 1.0 + rng.below(120) as f64,
+            max_output_bytes: (rng.below(2) == 0).then(|| 1 + rng.next() % 100_000_000),
+            error_detail: rng.below(2) == 0,
+            strict_params: rng.below(2) == 0,
+        }
+    }
+
+    async fn state(&self) -> AppState {
+        let config = Config {
+            gif_quality: self.quality,
+            gif_fast: self.fast,
+            gif_fps: self.fps,
+            encoder: Some(self.encoder),
+            max_input_duration: self.max_input_duration,
+            auto_trim: self.auto_trim,
+            long_video_policy: self.long_video_policy,
+            parallel_segments: self.parallel_segments,
+            stream_response: self.stream_response,
+            conversion_timeout: self.conversion_timeout,
+            max_output_bytes: self.max_output_bytes,
+            error_detail: self.error_detail,
+            strict_params: self.strict_params,
+            skip_binary_check: true,
+            ..Config::default()
+        };
+        let mut state = AppState::new(&config, LogFilter::default(), false).await.unwrap();
+        // So WebP is there to ask for, whatever ffmpeg is installed
+        state.register_backend(Arc::new(FfmpegWebp));
+        state
+    }
+}
+
+// Every byte escaped, so anything at all can be sent
+fn escape(value: &str) -> String {
+    value.bytes().map(|b| format!("%{:02X}", b)).collect()
+}
+
+fn derive(state: &AppState, path: &str, params: &[(String, String)]) -> VariantKey {
+    derive_held(state, path, params, None)
+}
+
+// held to the profile of `auto`, as `AUTO_QUALITY` would have it
+fn derive_held(state: &AppState, path: &str, params: &[(String, String)], auto: Option<Bucket>) -> VariantKey {
+    let query: Vec<String> = params.iter().map(|(name, value)| format!("{}={}", name, escape(value))).collect();
+    let uri: Uri = format!("/tweet_video/{}?{}", path, query.join("&")).parse().unwrap();
+    let Query(query) = Query::<ConversionQuery>::try_from_uri(&uri).unwrap();
+    VariantKey::from(path, &query, auto, state).unwrap_or_else(|| panic!("no key for {}", uri))
+}
+
+fn shuffle<T>(rng: &mut Rng, items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        items.swap(i, rng.below(i + 1));
+    }
+}
+
+fn set(params: &mut Vec<(String, String)>, name: &str, value: &str) {
+    params.retain(|(other, _)| other != name);
+    params.push((name.to_string(), value.to_string()));
+}
+
+fn get<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params.iter().find(|(other, _)| other == name).map(|(_, value)| value.as_str())
+}
+
+#[tokio::test]
+async fn keys_change_with_the_output_and_nothing_else() {
+    let (mut rng, seed) = Rng::seeded();
+    for _ in 0..cases(100) {
+        let setup = Setup::random(&mut rng);
+        let state = setup.state().await;
+        let path = *rng.pick(PATHS);
+        let mut params = Vec::new();
+        if rng.below(2) == 0 {
+            let optimize = rng.pick(OPTIMIZE);
+            set(&mut params, "optimize", optimize);
+        }
+        if rng.below(2) == 0 {
+            set(&mut params, "repeat", &rng.below(5).to_string());
+        }
+        if rng.below(2) == 0 {
+            let format = rng.pick(FORMATS);
+            set(&mut params, "format", format);
+        }
+        for clip in ["start", "duration"] {
+            if rng.below(2) == 0 {
+                let seconds = rng.pick(CLIP_SECONDS);
+                set(&mut params, clip, seconds);
+            }
+        }
+        let key = derive(&state, path, &params);
+        let context = format!("{:?} {:?} (FASTGIF_FUZZ_SEED={})", setup, params, seed);
+
+        // The same in any order, and with parameters that aren't about the output
+        let mut extra = params.clone();
+        let strict = rng.pick(STRICT);
+        set(&mut extra, "strict", strict);
+        set(&mut extra, "key", &rng.string(8));
+        set(&mut extra, &format!("x{}", escape(&rng.string(4))), &rng.string(4));
+        shuffle(&mut rng, &mut extra);
+        assert_eq!(derive(&state, path, &extra), key, "{} vs {:?}", context, extra);
+        let unrelated = Setup {
+            conversion_timeout: setup.conversion_timeout + 1.0,
+            max_output_bytes: setup.max_output_bytes.map_or(Some(1000), |_| None),
+            error_detail: !setup.error_detail,
+            strict_params: !setup.strict_params,
+            ..setup.clone()
+        };
+        assert_eq!(derive(&unrelated.state().await, path, &params), key, "{} vs {:?}", context, unrelated);
+
+        // What the output depends on, as the server resolves it
+        let gif = get(&params, "format") != Some("webp");
+        let flags = &state.pipeline().gifski_flags;
+        let gifski = setup.encoder == Encoder::Subprocess && gif;
+        let post_optimize = state.pipeline().post_optimize.as_ref();
+        let optimized = post_optimize.is_some_and(|post_optimize| post_optimize.always || asks_to_optimize(&params));
+        let repeat = get(&params, "repeat").and_then(|repeat| repeat.parse::<u16>().ok());
+        let fast = setup.fast.unwrap_or(true);
+        let fps = setup.fps.unwrap_or(20.0);
+
+        let mut changes: Vec<(&str, VariantKey, bool)> = Vec::new();
+        let other_path = PATHS.iter().find(|other| **other != path).unwrap();
+        changes.push(("path", derive(&state, other_path, &params), true));
+        let mut changed = params.clone();
+        set(&mut changed, "format", if gif { "webp" } else { "gif" });
+        changes.push(("format", derive(&state, path, &changed), true));
+        let mut changed = params.clone();
+        set(&mut changed, "optimize", if asks_to_optimize(&params) { "0" } else { "1" });
+        let matters = post_optimize.is_some_and(|post_optimize| !post_optimize.always) && gif && !setup.stream_response;
+        changes.push(("optimize", derive(&state, path, &changed), matters));
+        if !gifski || flags.has("--repeat") {
+            let mut changed = params.clone();
+            set(&mut changed, "repeat", &(repeat.map_or(0, |repeat| repeat + 1)).to_string());
+            changes.push(("repeat", derive(&state, path, &changed), true));
+        }
+        for clip in ["start", "duration"] {
+            let mut changed = params.clone();
+            let seconds = get(&params, clip).and_then(|seconds| seconds.parse::<f64>().ok()).unwrap_or(0.0);
+            set(&mut changed, clip, &(seconds + 0.5).to_string());
+            changes.push((clip, derive(&state, path, &changed), true));
+        }
+        // and so does a bucket, even one whose profile comes to what's
+        // configured
+        let bucket = Bucket::of(Duration::from_secs(*rng.pick(&[5, 20, 60])), 1280, 720, None);
+        changes.push(("auto", derive_held(&state, path, &params, Some(bucket)), true));
+
+        let mut changed_setups: Vec<(&str, Setup, bool)> = vec![
+            ("quality", Setup { quality: Some(setup.quality.unwrap_or(90) % 100 + 1), ..setup.clone() }, true),
+            ("fps", Setup { fps: FPS.iter().copied().find(|other| *other != fps), ..setup.clone() }, true),
+            ("fast", Setup { fast: Some(!fast), ..setup.clone() }, !gifski || flags.has("--fast")),
+        ];
+        let encoder = if setup.encoder == Encoder::Subprocess { Encoder::Ffmpeg } else { Encoder::Subprocess };
+        changed_setups.push(("encoder", Setup { encoder, ..setup.clone() }, gif));
+        let max_input_duration = *DURATIONS.iter().find(|other| **other != setup.max_input_duration).unwrap();
+        let trims = setup.long_video_policy.unwrap_or(if setup.auto_trim { LongVideoPolicy::Trim } else { LongVideoPolicy::Reject });
+        let trims = trims == LongVideoPolicy::Trim;
+        changed_setups.push(("max_input_duration", Setup { max_input_duration, ..setup.clone() }, trims));
+        let auto_trim = !setup.auto_trim;
+        let matters = setup.long_video_policy.is_none() && setup.max_input_duration > 0.0;
+        changed_setups.push(("auto_trim", Setup { auto_trim, ..setup.clone() }, matters));
+        let long_video_policy = Some(if trims { LongVideoPolicy::Reject } else { LongVideoPolicy::Trim });
+        changed_setups.push(("long_video_policy", Setup { long_video_policy, ..setup.clone() }, setup.max_input_duration > 0.0));
+        let parallel_segments = *SEGMENTS.iter().find(|other| **other != setup.parallel_segments).unwrap();
+        let splittable = gif && !setup.stream_response;
+        changed_setups.push(("parallel_segments", Setup { parallel_segments, ..setup.clone() }, splittable));
+        let stream_response = !setup.stream_response;
+        let matters = gif && (setup.parallel_segments.is_some() || optimized);
+        changed_setups.push(("stream_response", Setup { stream_response, ..setup.clone() }, matters));
+        for (what, changed, matters) in changed_setups {
+            changes.push((what, derive(&changed.state().await, path, &params), matters));
+        }
+
+        for (what, changed, matters) in changes {
+            assert_eq!(changed != key, matters, "changing {} for {}: {} vs {}", what, context, key, changed);
+            assert_eq!(changed.etag() != key.etag(), matters, "changing {} for {}: {} vs {}", what, context, key, changed);
+        }
+    }
+}
+
+fn asks_to_optimize(params: &[(String, String)]) -> bool {
+    matches!(get(params, "optimize"), Some("1" | "true" | "yes"))
+}
@@ -0,0 +1,117 @@
+//! Runs `fastgif warm` against a stand-in server, and checks what it asks
+//! for, what it retries, and how it sums up what it got back.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+
+// Answers each request by its path, one request per connection, recording
+// the request lines and Authorization headers. `flaky.gif` is unavailable
+// the first time it's asked for.
+fn serve() -> (u16, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = requests.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            let _ = reader.read_line(&mut request);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                if line.to_ascii_lowercase().starts_with("authorization:") {
+                    request.push_str(line.trim());
+                }
+                line.clear();
+            }
+            let path = request.split(' ').nth(1).unwrap_or_default().to_string();
+            let flaky_before = requests.lock().unwrap().iter().any(|earlier: &String| earlier.contains("flaky.gif"));
+            requests.lock().unwrap().push(request.trim().to_string());
+            let response: &str = if path.contains("hit.gif") {
+                "200 OK\r\nAge: 30\r\nContent-Length: 4\r\n\r\nGIF8"
+            } else if path.contains("flaky.gif") && !flaky_before {
+                "503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\n\r\n"
+            } else if path.contains("broken.gif") {
+                "500 Internal Server Error\r\nX-FastGIF-Error: ffmpeg_decode_error\r\nContent-Length: 0\r\n\r\n"
+            } else {
+                "200 OK\r\nContent-Length: 6\r\n\r\nGIF89a"
+            };
+            let _ = write!(writer, "HTTP/1.1 {}", response.replace("\r\n\r\n", "\r\nConnection: close\r\n\r\n"));
+        }
+    });
+    (port, recorded)
+}
+
+fn list(test: &str, entries: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("fastgif-warm-{}-{}.txt", test, std::process::id()));
+    std::fs::write(&path, entries).unwrap();
+    path
+}
+
+fn warm(port: u16, list: &PathBuf, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fastgif"))
+        .arg("warm")
+        .args(["--server", &format!("http://127.0.0.1:{}", port)])
+        .arg("--list")
+        .arg(list)
+        .args(args)
+        .env_remove("FASTGIF_API_KEY")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn warms_everything_listed_and_sums_it_up() {
+    let (port, requests) = serve();
+    let entries = "# hot paths\nabc.gif\n\n/tweet_video/hit.gif\nflaky.gif\n";
+    let list = list("ok", entries);
+    let output = warm(port, &list, &["--params", "width=480", "--api-key", "secret", "--concurrency", "2"]);
+    let summary = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", summary);
+    assert!(summary.contains("converted          2\n"), "{}", summary);
+    assert!(summary.contains("cached             1\n"), "{}", summary);
+    assert!(summary.contains("failed             0\n"), "{}", summary);
+    assert!(summary.contains("bytes             16"), "{}", summary);
+
+    let requests = requests.lock().unwrap().clone();
+    // flaky.gif twice, having been retried
+    assert_eq!(requests.len(), 4, "{:?}", requests);
+    for request in &requests {
+        assert!(request.starts_with("GET /tweet_video/"), "{}", request);
+        assert!(request.contains(".gif?width=480 "), "{}", request);
+        assert!(request.ends_with("Bearer secret"), "{}", request);
+    }
+    let _ = std::fs::remove_file(&list);
+}
+
+#[test]
+fn failures_are_listed_and_exit_nonzero() {
+    let (port, requests) = serve();
+    let list = list("failed", "abc.gif\nbroken.gif\n");
+    let output = warm(port, &list, &["--retries", "3"]);
+    let summary = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{}", summary);
+    assert!(summary.contains("failed             1\n"), "{}", summary);
+    assert!(summary.contains("broken.gif: 500 Internal Server Error (ffmpeg_decode_error)"), "{}", summary);
+    // A 500 isn't something to try again
+    assert_eq!(requests.lock().unwrap().len(), 2);
+    let _ = std::fs::remove_file(&list);
+}
+
+#[test]
+fn dry_runs_only_send_heads() {
+    let (port, requests) = serve();
+    let list = list("dry", &format!("abc.gif\nhttp://127.0.0.1:{}/elsewhere.gif\n", port));
+    let output = warm(port, &list, &["--dry-run"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+    let requests = requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().all(|request| request.starts_with("HEAD ")), "{:?}", requests);
+    assert!(requests.iter().any(|request| request.starts_with("HEAD /elsewhere.gif ")), "{:?}", requests);
+    let _ = std::fs::remove_file(&list);
+}
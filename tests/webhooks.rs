@@ -0,0 +1,165 @@
+//! Submits jobs with callbacks through the router, to a stand-in receiver,
+//! and checks what it's sent, how it's signed, that failed deliveries are
+//! tried again, and which callback URLs are refused.
+#![cfg(unix)]
+
+use axum::body::{to_bytes, Body};
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::Router;
+use fastgif::config::Config;
+use fastgif::Connection;
+use ring::hmac;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use support::router::{self, setup, PASSES_ON, VIDEO};
+use tower::ServiceExt;
+
+mod support;
+
+const SECRET: &str = "shh";
+
+// A callback as it arrived: its headers, lowercased, and body
+type Received = Arc<Mutex<Vec<(Vec<(String, String)>, Vec<u8>)>>>;
+
+// Answers callbacks with `statuses` in turn, then 200s, recording them
+fn receiver(statuses: &'static [u16]) -> (u16, Received) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let received = Received::default();
+    let recorded = received.clone();
+    std::thread::spawn(move || {
+        for (n, stream) in listener.incoming().enumerate() {
+            let Ok(stream) = stream else { continue };
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            let _ = reader.read_line(&mut line);
+            let mut headers = Vec::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).unwrap_or(0) <= 2 {
+                    break;
+                }
+                if let Some((name, value)) = line.trim().split_once(':') {
+                    headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+                }
+            }
+            let len = headers.iter().find(|(name, _)| name == "content-length").map_or(0, |(_, len)| len.parse().unwrap());
+            let mut body = vec![0; len];
+            let _ = reader.read_exact(&mut body);
+            recorded.lock().unwrap().push((headers, body));
+            let status = statuses.get(n).copied().unwrap_or(200);
+            let _ = write!(writer, "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+        }
+    });
+    (port, received)
+}
+
+async fn submit(app: &Router, job: Value) -> (u16, String) {
+    let mut request = Request::post("/jobs").body(Body::from(job.to_string())).unwrap();
+    let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+    request.extensions_mut().insert(ConnectInfo(Connection::from(peer)));
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status().as_u16();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+// The callbacks `received` has had, once there are `count` of them
+async fn wait_for(received: &Received, count: usize) -> Vec<(Vec<(String, String)>, Vec<u8>)> {
+    let started = Instant::now();
+    while received.lock().unwrap().len() < count {
+        assert!(started.elapsed() < Duration::from_secs(10), "only {} callbacks", received.lock().unwrap().len());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    received.lock().unwrap().clone()
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> &'a str {
+    headers.iter().find(|(other, _)| other == name).map(|(_, value)| value.as_str()).unwrap_or_default()
+}
+
+fn webhook_config(dir: &Path) -> Config {
+    Config {
+        webhook_secret: Some(SECRET.to_string()),
+        webhook_allow_cidrs: vec!["127.0.0.0/8".parse().unwrap()],
+        ..router::config(dir)
+    }
+}
+
+#[tokio::test]
+async fn callbacks_are_signed_and_retried() {
+    let dir = setup("signed", PASSES_ON);
+    let app = router::app(webhook_config(&dir)).await;
+    let (port, received) = receiver(&[503]);
+
+    let callback = format!("http://127.0.0.1:{}/done", port);
+    let (status, body) = submit(&app, json!({"path": "abc.gif", "callback_url": callback})).await;
+    assert_eq!(status, 202, "{}", body);
+    let id = serde_json::from_str::<Value>(&body).unwrap()["id"].as_str().unwrap().to_string();
+
+    // The first one's answered with a 503, so it's sent again
+    let callbacks = wait_for(&received, 2).await;
+    assert_eq!(callbacks[0].1, callbacks[1].1);
+    let (headers, body) = &callbacks[1];
+    let payload: Value = serde_json::from_slice(body).unwrap();
+    assert_eq!(payload["id"], id);
+    assert_eq!(payload["state"], "done");
+    assert_eq!(payload["bytes"], VIDEO.len());
+    assert_eq!(payload["result_url"], format!("/jobs/{}/result", id));
+    assert!(payload["queued_ms"].is_u64() && payload["running_ms"].is_u64(), "{}", payload);
+    assert_eq!(header(headers, "content-type"), "application/json");
+
+    let timestamp = header(headers, "x-fastgif-timestamp");
+    let signature = header(headers, "x-fastgif-signature").strip_prefix("sha256=").unwrap();
+    let signature: Vec<u8> = (0..signature.len()).step_by(2).map(|i| u8::from_str_radix(&signature[i..i + 2], 16).unwrap()).collect();
+    let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
+    let signed = [format!("{}.", timestamp).as_bytes(), body].concat();
+    hmac::verify(&key, &signed, &signature).expect("the signature doesn't match");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn failures_are_called_back_too_and_refusals_are_final() {
+    let dir = setup("failed", PASSES_ON);
+    let app = router::app(webhook_config(&dir)).await;
+    let (port, received) = receiver(&[404]);
+
+    let callback = format!("http://127.0.0.1:{}/done", port);
+    let (status, _) = submit(&app, json!({"path": "missing.gif", "callback_url": callback})).await;
+    assert_eq!(status, 202);
+    let callbacks = wait_for(&received, 1).await;
+    let payload: Value = serde_json::from_slice(&callbacks[0].1).unwrap();
+    assert_eq!(payload["state"], "failed");
+    assert_eq!(payload["error"], "upstream_not_found");
+    // A 404 from the receiver isn't worth another try
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(received.lock().unwrap().len(), 1);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn callbacks_only_go_where_they_may() {
+    let dir = setup("refused", PASSES_ON);
+    let (port, received) = receiver(&[]);
+    let callback = format!("http://127.0.0.1:{}/done", port);
+
+    // Not anywhere internal unless it's allowed
+    let config = Config { webhook_secret: Some(SECRET.to_string()), ..router::config(&dir) };
+    let signed = router::app(config).await;
+    for url in [callback.as_str(), "http://169.254.169.254/latest", "http://[::ffff:10.0.0.1]/", "ftp://example.com/", "nonsense"] {
+        let (status, body) = submit(&signed, json!({"path": "abc.gif", "callback_url": url})).await;
+        assert_eq!(status, 400, "{}: {}", url, body);
+        assert!(body.contains("invalid_callback"), "{}", body);
+    }
+    // and not at all without a secret to sign them with
+    let unsigned = router::app(Config { webhook_allow_cidrs: vec!["127.0.0.0/8".parse().unwrap()], ..router::config(&dir) }).await;
+    assert_eq!(submit(&unsigned, json!({"path": "abc.gif", "callback_url": callback})).await.0, 400);
+    assert!(received.lock().unwrap().is_empty());
+    let _ = std::fs::remove_dir_all(&dir);
+}
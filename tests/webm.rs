@@ -0,0 +1,101 @@
+//! Asks for `?format=webm` from servers whose ffmpeg has VP9, AV1 as well,
+//! or neither, and checks it's only made when it can be, with the encoder
+//! that's there, silent and no wider than it's capped at, and only sent
+//! when it's a WebM with a frame in it.
+#![cfg(unix)]
+
+use axum::body::to_bytes;
+use std::path::{Path, PathBuf};
+use support::router::{self, get};
+
+mod support;
+
+// The smallest WebM the server will send: an empty EBML header, and a
+// segment of unknown size, as ffmpeg writes one to a pipe, with a cluster of
+// one block in it
+const WEBM: &[u8] = b"\x1a\x45\xdf\xa3\x80\x18\x53\x80\x67\x01\xff\xff\xff\xff\xff\xff\xff\x1f\x43\xb6\x75\x86\xa3\x84\x81\x00\x00\x80";
+
+// And one with no frames in it at all
+const EMPTY: &[u8] = b"\x1a\x45\xdf\xa3\x80\x18\x53\x80\x67\x01\xff\xff\xff\xff\xff\xff\xff";
+
+// A directory of the test's own, with `abc.mp4`, and an ffmpeg in it that
+// lists `encoders` as the ones it has, and answers a conversion to WebM with
+// `made`, leaving its arguments as a line in `runs`
+fn setup(test: &str, encoders: &[&str], made: &[u8]) -> PathBuf {
+    let listed: String = encoders.iter().map(|encoder| format!(" V..... {} A video encoder\\n", encoder)).collect();
+    let ffmpeg = format!(
+        "here=\"$(dirname \"$0\")\"\n\
+         case \"$*\" in\n\
+         *-encoders*) printf 'Encoders:\\n ------\\n{}'; exit 0;;\n\
+         *'-f webm'*) echo \"$*\" >> \"$here/runs\"; exec cat \"$here/made.webm\";;\n\
+         esac\n\
+         exec cat",
+        listed
+    );
+    let dir = router::setup(test, &ffmpeg);
+    std::fs::write(dir.join("made.webm"), made).unwrap();
+    dir
+}
+
+fn runs(dir: &Path) -> Vec<String> {
+    std::fs::read_to_string(dir.join("runs")).unwrap_or_default().lines().map(str::to_string).collect()
+}
+
+#[tokio::test]
+async fn vp9_makes_a_silent_capped_webm() {
+    let dir = setup("vp9", &["libvpx-vp9"], WEBM);
+    let app = router::app(router::config(&dir)).await;
+
+    let response = get(&app, "/tweet_video/abc.gif?format=webm").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "video/webm");
+    assert_eq!(response.headers()["x-fastgif-encoder"], "ffmpeg-vp9");
+    assert_eq!(to_bytes(response.into_body(), usize::MAX).await.unwrap(), WEBM);
+    let runs = runs(&dir);
+    assert!(runs[0].contains("-an -sn "), "{}", runs[0]);
+    assert!(runs[0].contains("scale='trunc(min(iw,720)/2)*2':-2"), "{}", runs[0]);
+    // GIF quality 90 by default
+    assert!(runs[0].contains("-c:v libvpx-vp9 -crf 18 -b:v 0 "), "{}", runs[0]);
+
+    // and narrower when it's asked to be, but no wider
+    get(&app, "/tweet_video/abc.gif?format=webm&width=320").await;
+    get(&app, "/tweet_video/abc.gif?format=webm&width=1920").await;
+    let runs = self::runs(&dir);
+    assert!(runs[1].contains("scale='trunc(min(iw,320)/2)*2':-2"), "{}", runs[1]);
+    assert!(runs[2].contains("scale='trunc(min(iw,720)/2)*2':-2"), "{}", runs[2]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn av1_is_preferred_where_there_is_an_encoder_for_it() {
+    let dir = setup("av1", &["libvpx-vp9", "libsvtav1"], WEBM);
+    let app = router::app(router::config(&dir)).await;
+    let response = get(&app, "/tweet_video/abc.gif?format=webm").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-fastgif-encoder"], "ffmpeg-av1");
+    assert!(runs(&dir)[0].contains("-c:v libsvtav1 -crf 18 "), "{}", runs(&dir)[0]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn webm_needs_an_encoder_for_it() {
+    let dir = setup("none", &["libx264"], WEBM);
+    let app = router::app(router::config(&dir)).await;
+    let response = get(&app, "/tweet_video/abc.gif?format=webm").await;
+    assert_eq!(response.status(), 400);
+    let body = String::from_utf8_lossy(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).into_owned();
+    assert!(body.contains("unsupported_format") && body.contains("this server makes gif)"), "{}", body);
+    assert!(runs(&dir).is_empty());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn a_webm_with_no_frames_isnt_sent() {
+    let dir = setup("empty", &["libvpx-vp9"], EMPTY);
+    let app = router::app(router::config(&dir)).await;
+    let response = get(&app, "/tweet_video/abc.gif?format=webm").await;
+    assert_eq!(response.status(), 500);
+    let body = String::from_utf8_lossy(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).into_owned();
+    assert!(body.contains("ffmpeg-vp9 made an invalid webm (it has no frames)"), "{}", body);
+    let _ = std::fs::remove_dir_all(&dir);
+}